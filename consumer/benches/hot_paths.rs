@@ -0,0 +1,192 @@
+//! Benchmarks for the per-message hot path: hashing, envelope decode, post-processing
+//! filters and `_bulk` serialization.
+//!
+//! Run with `cargo bench --bench hot_paths`.
+
+use chrono::Utc;
+use consumer::contamination::ContaminationChecker;
+use consumer::corpus_dedup::{self, CorpusIndex};
+use consumer::db;
+use consumer::difficulty;
+use consumer::llm_wrapper;
+use consumer::schemas::envelope::{TaskMessage, TaskPayload};
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::task_status::TaskStatus;
+use consumer::settings::{ContaminationSettings, CorpusDedupSettings};
+use consumer::text;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+const COMPLETION: &str = "To solve the equation, first isolate x:\n\
+1. Subtract 3 from both sides: 2x = 8\n\
+2. Divide both sides by 2: x = 4\n\
+\n\
+```python\n\
+def solve(a, b, c):\n    return (c - b) / a\n\
+```\n\
+The answer is x = 4, which you can verify by substituting back: 2 * 4 + 3 = 11.";
+
+fn body() -> Value {
+    json!({
+        "model": "openai/gpt-4o-mini",
+        "temperature": 0.7,
+        "max_tokens": 1024,
+        "messages": [
+            { "role": "system", "content": "You are a patient math tutor. Explain each step." },
+            { "role": "user", "content": "Solve 2x + 3 = 11 and show a Python helper that solves ax + b = c." }
+        ]
+    })
+}
+
+fn message() -> Vec<u8> {
+    serde_json::to_vec(&json!({
+        "message_id": "6f1c2a7e-94b4-4f0e-9d5e-3c1b2a9f8e71",
+        "timestamp": "2025-01-01T00:00:00Z",
+        "batch_id": "b3f0c6de-1a2b-4c3d-8e9f-0a1b2c3d4e5f",
+        "body_hash": "n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=",
+        "payload": {
+            "custom_id": "row-000042",
+            "method": "POST",
+            "url": "https://openrouter.ai/api/v1/chat/completions",
+            "api_key": "sk-or-redacted",
+            "body": body(),
+            "dataset": "math-tutor",
+            "source": {},
+            "use_cache": true,
+            "track_progress": true
+        }
+    }))
+    .unwrap()
+}
+
+fn response() -> LLMResponse {
+    let now = Utc::now();
+    let mut annotations = serde_json::Map::new();
+    annotations.insert("difficulty".to_string(), json!("medium"));
+    annotations.insert("difficulty_score".to_string(), json!(0.42));
+    annotations.insert("topic_labels".to_string(), json!(["math", "code"]));
+    let completions = json!({
+        "id": "gen-1736000000-abc",
+        "model": "openai/gpt-4o-mini",
+        "choices": [{ "index": 0, "finish_reason": "stop", "message": { "role": "assistant", "content": COMPLETION }}],
+        "usage": { "prompt_tokens": 48, "completion_tokens": 96, "total_tokens": 144 }
+    });
+    LLMResponse {
+        usage: llm_wrapper::parse_usage(&completions),
+        completions,
+        cached: false,
+        attempt: 1,
+        started_at: now,
+        completed_at: now,
+        annotations,
+        cost: Some(0.0000648),
+    }
+}
+
+fn fixture(name: &str, contents: &str) -> String {
+    let path: PathBuf = std::env::temp_dir().join(format!("synthgen-bench-{}", name));
+    std::fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+fn bench_hashing(c: &mut Criterion) {
+    let body = body();
+    let mut group = c.benchmark_group("hashing");
+    group.bench_function("body_sha256", |b| {
+        b.iter(|| Sha256::digest(serde_json::to_vec(black_box(&body)).unwrap()))
+    });
+    group.bench_function("completion_digest", |b| {
+        b.iter(|| corpus_dedup::digest(black_box(COMPLETION)))
+    });
+    group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let data = message();
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("envelope", |b| {
+        b.iter(|| TaskMessage::parse(black_box(&data)).unwrap())
+    });
+    group.bench_function("payload", |b| {
+        let message = TaskMessage::parse(&data).unwrap();
+        b.iter(|| TaskPayload::from_value(black_box(&message.payload)).unwrap())
+    });
+    group.bench_function("prompt_text", |b| {
+        let body = body();
+        b.iter(|| text::prompt_text(black_box(&body)))
+    });
+    group.finish();
+}
+
+fn bench_postprocess(c: &mut Criterion) {
+    let body = body();
+    let prompt = text::prompt_text(&body);
+
+    let eval_set: String = (0..2000)
+        .map(|i| format!("{{\"question\": \"Question {} about solving linear equations with integer coefficients and checking the answer\"}}\n", i))
+        .collect();
+    let contamination = ContaminationChecker::load(&ContaminationSettings {
+        eval_sets: vec![fixture("eval.jsonl", &eval_set)],
+        ngram_size: 13,
+        threshold: 0.1,
+    })
+    .unwrap();
+
+    let hashes: String = (0..10_000)
+        .map(|i| format!("{:064x}\n", i as u128 * 0x9e37_79b9_7f4a_7c15))
+        .collect();
+    let corpus = CorpusIndex::load(&CorpusDedupSettings {
+        hash_files: vec![fixture("corpus.txt", &hashes)],
+    })
+    .unwrap();
+
+    let mut group = c.benchmark_group("postprocess");
+    group.bench_function("words", |b| b.iter(|| text::words(black_box(COMPLETION))));
+    group.bench_function("difficulty_heuristic", |b| {
+        b.iter(|| difficulty::heuristic_score(black_box(&prompt), black_box(COMPLETION)))
+    });
+    group.bench_function("contamination_check", |b| {
+        b.iter(|| contamination.check(black_box(&body), black_box(COMPLETION)))
+    });
+    group.bench_function("corpus_check", |b| {
+        b.iter(|| corpus.check(black_box(COMPLETION)))
+    });
+    group.finish();
+}
+
+fn bench_bulk(c: &mut Criterion) {
+    const BATCH: usize = 500;
+    let response = response();
+    let started_at = Utc::now();
+    let ids: Vec<String> = (0..BATCH).map(|i| format!("message-{:06}", i)).collect();
+
+    let mut group = c.benchmark_group("bulk");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function("serialize_updates", |b| {
+        b.iter_batched(
+            || Vec::with_capacity(BATCH * 1024),
+            |mut buf| {
+                for id in &ids {
+                    let fields =
+                        db::event_update_fields(TaskStatus::Completed, &response, started_at);
+                    db::write_bulk_update(&mut buf, "events", id, &fields).unwrap();
+                }
+                buf
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_hashing,
+    bench_decode,
+    bench_postprocess,
+    bench_bulk
+);
+criterion_main!(benches);
@@ -0,0 +1,316 @@
+//! Python bindings of task submission (the `synthgen_client` wheel), so notebooks build
+//! and check tasks with the consumer's own body hashing, payload checks and message
+//! format instead of reimplementing the contract.
+//!
+//! Built with `maturin build --release` (or `maturin develop`) from this directory, and
+//! tested in an embedded interpreter with the rest of the workspace.
+//! `Client` reads the consumer's settings from the environment: it records the events
+//! of submitted tasks in the configured store and publishes them to the task queue,
+//! like `synthgen-api`, reads batch counts kept by `BATCH_PROGRESS` and writes exports
+//! like `consumer export`. With `PAYLOAD_RECIPIENTS`, payloads are encrypted to the
+//! consumer's age keys before they leave the notebook.
+//!
+//! ```python
+//! import synthgen_client
+//!
+//! client = synthgen_client.Client()
+//! submitted = client.submit([{"method": "POST", "url": "...", "body": {...}}])
+//! client.status(submitted["batch_id"])
+//! client.export(submitted["batch_id"], sink="exports", format="parquet")
+//! ```
+
+use consumer::broker::{self, MessageBroker};
+use consumer::db::{self, TaskStore};
+use consumer::export;
+use consumer::payload_encryption;
+use consumer::producer::{self, PreparedTask};
+use consumer::schema_registry::SchemaRegistry;
+use consumer::schemas::envelope::{self, TaskPayload};
+use consumer::settings::{ExportFormat, Settings};
+use consumer::webhook::Webhooks;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Events are created in chunks of this many tasks before their messages are published.
+const CHUNK_SIZE: usize = 1000;
+
+/// `value` as JSON, through Python's `json` module.
+fn to_json(value: &Bound<'_, PyAny>) -> PyResult<Value> {
+    let text: String = value
+        .py()
+        .import("json")?
+        .call_method1("dumps", (value,))?
+        .extract()?;
+    serde_json::from_str(&text).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+/// `value` as Python objects, through Python's `json` module.
+fn to_python(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    Ok(py
+        .import("json")?
+        .call_method1("loads", (value.to_string(),))?
+        .unbind())
+}
+
+fn runtime_error(error: impl std::fmt::Display) -> PyErr {
+    PyRuntimeError::new_err(error.to_string())
+}
+
+/// Checks a task's payload and builds its event and message, as `synthgen-api` does.
+/// With `schemas`, the payload is also checked against the schema of its `task_type`.
+/// The payload is encrypted to `recipients` when there are any.
+fn prepare_task(
+    batch_id: &str,
+    task: Value,
+    max_priority: u8,
+    schemas: Option<&SchemaRegistry>,
+    recipients: &[age::x25519::Recipient],
+) -> Result<PreparedTask, String> {
+    TaskPayload::from_value(&task)?;
+    if let Some(Err(errors)) = schemas.map(|schemas| schemas.check(&task)) {
+        return Err(format!(
+            "Payload violates its schema: {}",
+            errors.join("; ")
+        ));
+    }
+    producer::prepare_encrypted(batch_id, task, max_priority, recipients)
+}
+
+/// Hash the cache and deduplication key a task body on.
+#[pyfunction]
+fn body_hash(body: &Bound<'_, PyAny>) -> PyResult<String> {
+    Ok(producer::body_hash(&to_json(body)?))
+}
+
+/// Raises `ValueError` when the task breaks the message contract. Payload schemas of
+/// task types are only checked by `Client.submit`, which reads them from the settings.
+#[pyfunction]
+fn check_task(task: &Bound<'_, PyAny>) -> PyResult<()> {
+    prepare_task("check", to_json(task)?, 0, None, &[])
+        .map(|_| ())
+        .map_err(PyValueError::new_err)
+}
+
+/// JSON Schema of task messages, the submitted task being their `payload`.
+#[pyfunction]
+fn task_schema(py: Python<'_>) -> PyResult<PyObject> {
+    to_python(py, &envelope::json_schema())
+}
+
+/// The event document and the message a task of `batch_id` is submitted as, its payload
+/// encrypted to the age `recipients` when given.
+#[pyfunction]
+#[pyo3(signature = (batch_id, task, max_priority = 0, recipients = Vec::new()))]
+fn prepare(
+    py: Python<'_>,
+    batch_id: &str,
+    task: &Bound<'_, PyAny>,
+    max_priority: u8,
+    recipients: Vec<String>,
+) -> PyResult<PyObject> {
+    let recipients =
+        payload_encryption::parse_recipients(&recipients).map_err(PyValueError::new_err)?;
+    let task = prepare_task(batch_id, to_json(task)?, max_priority, None, &recipients)
+        .map_err(PyValueError::new_err)?;
+    let message: Value =
+        serde_json::from_slice(&task.message).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    to_python(
+        py,
+        &json!({
+            "message_id": task.message_id,
+            "body_hash": task.body_hash,
+            "event": task.event,
+            "message": message,
+            "priority": task.priority,
+        }),
+    )
+}
+
+/// Submits tasks, reads batch counts and exports results, with the consumer's settings.
+#[pyclass]
+struct Client {
+    runtime: tokio::runtime::Runtime,
+    settings: Settings,
+    /// Configured payload schemas (`PAYLOAD_SCHEMAS`), without the ones of an
+    /// Elasticsearch document.
+    schemas: SchemaRegistry,
+    /// Consumer keys payloads are encrypted to (`PAYLOAD_RECIPIENTS`).
+    recipients: Vec<age::x25519::Recipient>,
+    store: Arc<dyn TaskStore>,
+    /// Connected on the first submission.
+    broker: Mutex<Option<Arc<dyn MessageBroker>>>,
+}
+
+impl Client {
+    async fn broker(&self) -> broker::BrokerResult<Arc<dyn MessageBroker>> {
+        let mut broker = self.broker.lock().await;
+        match broker.as_ref() {
+            Some(connected) if connected.is_connected() => Ok(connected.clone()),
+            _ => {
+                let connected =
+                    broker::connect(&self.settings.broker, &self.settings.network).await?;
+                *broker = Some(connected.clone());
+                Ok(connected)
+            }
+        }
+    }
+
+    /// Creates the events of `tasks`, then publishes their messages, chunk by chunk.
+    async fn submit_tasks(&self, tasks: &[PreparedTask]) -> Result<(), String> {
+        let broker = self.broker().await.map_err(|e| e.to_string())?;
+        let mut published = 0;
+        for chunk in tasks.chunks(CHUNK_SIZE) {
+            let events: Vec<Value> = chunk.iter().map(|task| task.event.clone()).collect();
+            self.store.create_events(&events).await.map_err(|e| {
+                format!(
+                    "Failed to create events after {} published tasks: {}",
+                    published, e
+                )
+            })?;
+            for task in chunk {
+                broker
+                    .publish_task(&task.message, task.priority, &BTreeMap::new())
+                    .await
+                    .map_err(|e| format!("Failed to publish after {} tasks: {}", published, e))?;
+                published += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl Client {
+    #[new]
+    fn new(py: Python<'_>) -> PyResult<Self> {
+        let settings = Settings::new().map_err(runtime_error)?;
+        let recipients =
+            payload_encryption::parse_recipients(&settings.payload_encryption.recipients)
+                .map_err(PyValueError::new_err)?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        let store = py
+            .allow_threads(|| {
+                runtime.block_on(db::connect(
+                    &settings.storage,
+                    &Webhooks::new(&settings.webhooks),
+                ))
+            })
+            .map_err(runtime_error)?;
+        Ok(Self {
+            runtime,
+            schemas: SchemaRegistry::new(&settings.schema_registry),
+            recipients,
+            settings,
+            store,
+            broker: Mutex::new(None),
+        })
+    }
+
+    /// Submits `tasks` to `batch_id`, or to a new batch. Tasks breaking the contract
+    /// are reported by position and skipped; the others are submitted.
+    #[pyo3(signature = (tasks, batch_id = None))]
+    fn submit(
+        &self,
+        py: Python<'_>,
+        tasks: Vec<Bound<'_, PyAny>>,
+        batch_id: Option<String>,
+    ) -> PyResult<PyObject> {
+        let batch_id = batch_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let max_priority = self.settings.broker.max_priority;
+        let mut prepared = Vec::with_capacity(tasks.len());
+        let mut rejected = Vec::new();
+        for (index, task) in tasks.iter().enumerate() {
+            match prepare_task(
+                &batch_id,
+                to_json(task)?,
+                max_priority,
+                Some(&self.schemas),
+                &self.recipients,
+            ) {
+                Ok(task) => prepared.push(task),
+                Err(error) => rejected.push(json!({ "index": index, "error": error })),
+            }
+        }
+
+        py.allow_threads(|| self.runtime.block_on(self.submit_tasks(&prepared)))
+            .map_err(runtime_error)?;
+        let message_ids: Vec<&str> = prepared
+            .iter()
+            .map(|task| task.message_id.as_str())
+            .collect();
+        to_python(
+            py,
+            &json!({
+                "batch_id": batch_id,
+                "message_ids": message_ids,
+                "rejected": rejected,
+            }),
+        )
+    }
+
+    /// Task counts of `batch_id` by status, or `None` when none are kept for it.
+    fn status(&self, py: Python<'_>, batch_id: &str) -> PyResult<PyObject> {
+        let counts = py
+            .allow_threads(|| self.runtime.block_on(self.store.batch_counts(batch_id)))
+            .map_err(runtime_error)?;
+        match counts {
+            Some(counts) => {
+                let mut status = serde_json::to_value(&counts).map_err(runtime_error)?;
+                status["finished"] = json!(counts.is_finished());
+                to_python(py, &status)
+            }
+            None => Ok(py.None()),
+        }
+    }
+
+    /// Writes the completed tasks of `batch_id` to `sink` (defaults to `EXPORT_SINK`)
+    /// as `format` (defaults to `EXPORT_FORMAT`).
+    #[pyo3(signature = (batch_id, sink = None, format = None))]
+    fn export(
+        &self,
+        py: Python<'_>,
+        batch_id: &str,
+        sink: Option<String>,
+        format: Option<&str>,
+    ) -> PyResult<PyObject> {
+        let mut export_settings = self.settings.export.clone();
+        if let Some(format) = format {
+            export_settings.format = ExportFormat::from_name(format).ok_or_else(|| {
+                PyValueError::new_err(format!("Unknown export format {}", format))
+            })?;
+        }
+        let sink = export::Sink::parse(
+            sink.as_deref().unwrap_or(&export_settings.sink),
+            self.settings.stored_sizes.archive.as_ref(),
+        )
+        .map_err(runtime_error)?;
+        let report = py
+            .allow_threads(|| {
+                self.runtime.block_on(export::export_batch(
+                    self.store.as_ref(),
+                    batch_id,
+                    &export_settings,
+                    &sink,
+                ))
+            })
+            .map_err(runtime_error)?;
+        to_python(
+            py,
+            &json!({ "rows": report.rows, "location": report.location }),
+        )
+    }
+}
+
+#[pymodule]
+pub fn synthgen_client(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(body_hash, module)?)?;
+    module.add_function(wrap_pyfunction!(check_task, module)?)?;
+    module.add_function(wrap_pyfunction!(task_schema, module)?)?;
+    module.add_function(wrap_pyfunction!(prepare, module)?)?;
+    module.add_class::<Client>()?;
+    Ok(())
+}
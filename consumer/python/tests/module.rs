@@ -0,0 +1,56 @@
+//! The `synthgen_client` module imports into an embedded interpreter and its functions
+//! answer like the consumer's own.
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use serde_json::json;
+use synthgen_client::synthgen_client as client_module;
+
+#[test]
+fn the_module_hashes_checks_and_prepares_tasks() {
+    pyo3::append_to_inittab!(client_module);
+    Python::with_gil(|py| {
+        let module = py.import("synthgen_client").unwrap();
+        let json = py.import("json").unwrap();
+        let task = json!({
+            "method": "POST",
+            "url": "https://api.openai.com/v1/chat/completions",
+            "body": { "model": "gpt-4o", "messages": [] },
+        });
+        let task: Bound<'_, PyDict> = json
+            .call_method1("loads", (task.to_string(),))
+            .unwrap()
+            .downcast_into()
+            .unwrap();
+
+        let body_hash: String = module
+            .call_method1("body_hash", (task.get_item("body").unwrap(),))
+            .unwrap()
+            .extract()
+            .unwrap();
+        assert_eq!(
+            body_hash,
+            consumer::producer::body_hash(&json!({ "model": "gpt-4o", "messages": [] }))
+        );
+
+        module.call_method1("check_task", (&task,)).unwrap();
+        let malformed = PyDict::new(py);
+        malformed.set_item("url", "u").unwrap();
+        let error = module.call_method1("check_task", (malformed,)).unwrap_err();
+        assert!(error.is_instance_of::<PyValueError>(py));
+
+        let prepared = module.call_method1("prepare", ("batch-1", &task)).unwrap();
+        let batch_id: String = prepared
+            .get_item("message")
+            .and_then(|message| message.get_item("batch_id"))
+            .and_then(|batch_id| batch_id.extract())
+            .unwrap();
+        assert_eq!(batch_id, "batch-1");
+        let prepared_hash: String = prepared
+            .get_item("body_hash")
+            .and_then(|hash| hash.extract())
+            .unwrap();
+        assert_eq!(prepared_hash, body_hash);
+    });
+}
@@ -0,0 +1,682 @@
+//! Queue and index operations behind the `synthgen-admin` binary, over the RabbitMQ
+//! queues and the Elasticsearch `events` index of the consumer's settings.
+//!
+//! Failed tasks of a batch are published again from the dead-letter queue, their
+//! events set back to PENDING. The dead-letter queue is walked a message at a time, and
+//! those of other batches are put back at its end. Cancelling a batch fails its PENDING
+//! events with a `cancelled` annotation, and consumers ack the deliveries of cancelled
+//! events without processing them (unless `DEDUP_MODE=off`), wherever their messages
+//! wait; tasks already PROCESSING run to the end. Reindexing the cache writes the completed responses of the events to the Redis
+//! response cache (`CACHE_BACKEND=redis`), e.g. after Redis lost them.
+//!
+//! Bulk operations apply to the events matching an Elasticsearch query, narrowed by
+//! batch or message ids: cancelling PENDING tasks like a batch cancellation,
+//! publishing the messages of PENDING tasks again at another priority, walking the task
+//! queue like queue aging does, and adding or removing `tags`. They page through the events by message id, `CHUNK_SIZE` at a time,
+//! logging their progress, and can be dry-run to count the events first.
+//!
+//! Batch counts follow the events reset or failed when `BATCH_PROGRESS` keeps them, but
+//! no webhook is told of batches finished by a cancellation.
+//!
+//! With `ELASTICSEARCH_SNAPSHOT_REPOSITORY`, the operations rewriting events, requeues,
+//! cancellations and retags, snapshot the indexes first and don't run when the snapshot
+//! fails. Snapshots are recorded in the audit index (`AUDIT_INDEX`), and `restore`
+//! rolls the indexes back to one of them.
+//!
+//! Migrating the events index moves its events to a new index with the current mapping,
+//! optionally moving fields (e.g. flat ones into the objects of the event schema), and
+//! leaves the old name as an alias of it. Writes to the index are blocked meanwhile, so
+//! stop the consumers first. Under rollover each dated index is migrated on its own.
+//! Migrations are snapshotted first too.
+//!
+//! Aging the task queue raises the priority of its aged tasks, see `queue_aging`. It
+//! takes a lock in the audit index first, so walks started on a schedule don't overlap.
+//!
+//! The fleet lists the consumer processes from their heartbeats, see `workers`.
+
+use crate::broker::amqp::AmqpBroker;
+use crate::broker::{MessageBroker, ATTEMPT_HEADER, PARTITION_HEADER};
+use crate::db::elastic::{ElasticStore, FieldMove, IndexMigration};
+use crate::db::redis_cache::RedisCacheStore;
+use crate::db::{DbResult, TaskStore};
+use crate::queue_aging::{self, AgingReport, QueueAging};
+use crate::schemas::envelope::TaskMessage;
+use crate::schemas::task_status::TaskStatus;
+use crate::telemetry;
+use crate::workers;
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, info_span, warn, Instrument};
+
+/// Annotation of the events failed by a cancellation.
+pub const CANCELLED: &str = "cancelled";
+
+/// Events updated by query at a time.
+const CHUNK_SIZE: usize = 1000;
+
+/// Lock of the queue aging walks.
+const AGE_QUEUE_LOCK: &str = "age-queue";
+
+/// How long a queue aging walk holds its lock at most, should it not release it.
+const AGE_QUEUE_LOCK_TTL: Duration = Duration::from_secs(3600);
+
+/// Cacheable responses looked up at a time.
+const PAGE_SIZE: usize = 500;
+
+/// Headers a dead-lettered message gets, and the broker adds to a redelivery.
+const FAILURE_HEADERS: [&str; 6] = [
+    ATTEMPT_HEADER,
+    PARTITION_HEADER,
+    "x-delivery-count",
+    "x-failure-reason",
+    "x-failed-at",
+    "x-original-queue",
+];
+
+/// Whether `data` is a task message of `batch_id`.
+pub fn in_batch(data: &[u8], batch_id: &str) -> bool {
+    TaskMessage::parse(data).is_ok_and(|message| message.batch_id == batch_id)
+}
+
+/// Headers of a dead-lettered message published again: without its failure metadata
+/// and attempt count, so it gets every attempt again.
+pub fn requeue_headers(headers: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .filter(|(key, _)| !FAILURE_HEADERS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RequeueReport {
+    /// Messages published to the task queue again.
+    pub requeued: usize,
+    /// Events set back from FAILED to PENDING.
+    pub reset: u64,
+    /// Snapshot taken first.
+    pub snapshot: Option<String>,
+}
+
+/// Publishes the dead-lettered tasks of `batch_id` to the task queue again.
+pub async fn requeue_failed(
+    broker: &Arc<AmqpBroker>,
+    store: &ElasticStore,
+    batch_id: &str,
+    keep_counts: bool,
+) -> DbResult<RequeueReport> {
+    let Some(dead_letter_queue) = broker.dead_letter_queue() else {
+        return Err("No dead-letter queue to requeue from (DEAD_LETTER_QUEUE)".into());
+    };
+    let snapshot = store
+        .snapshot_before("requeue-failed", json!({ "batch_id": batch_id }))
+        .await?;
+    let mut report = RequeueReport {
+        snapshot,
+        ..RequeueReport::default()
+    };
+    let mut walk = broker.walk(dead_letter_queue).await?;
+    while let Some(message) = walk.next().await? {
+        let task = TaskMessage::parse(&message.data)
+            .ok()
+            .filter(|task| task.batch_id == batch_id);
+        let Some(task) = task else {
+            walk.put_back(&message, message.priority).await?;
+            continue;
+        };
+
+        // The event is reset first: a task published while its event is still FAILED
+        // would have its counts moved from the wrong status.
+        let query = json!({
+            "bool": {
+                "filter": [
+                    { "term": { "batch_id": batch_id }},
+                    { "term": { "status": TaskStatus::Failed.as_str() }},
+                    { "term": { "message_id": task.message_id }}
+                ]
+            }
+        });
+        let fields = json!({
+            "status": TaskStatus::Pending.as_str(),
+            "started_at": null,
+            "completed_at": null,
+        });
+        let reset = store.update_events_by_query(query, &fields).await?;
+        if keep_counts && reset > 0 {
+            store
+                .update_batch_counts(
+                    batch_id,
+                    Some(TaskStatus::Failed),
+                    TaskStatus::Pending,
+                    reset,
+                )
+                .await?;
+        }
+        report.reset += reset;
+
+        let span = info_span!("requeue_failed", batch_id = %batch_id);
+        let mut headers = requeue_headers(&message.headers);
+        telemetry::inject(&span, &mut headers);
+        broker
+            .publish_task(&message.data, message.priority, &headers)
+            .instrument(span)
+            .await?;
+        message.ack().await?;
+        report.requeued += 1;
+    }
+    info!(
+        "Requeued {} failed tasks of batch {} ({} events reset)",
+        report.requeued, batch_id, report.reset
+    );
+    Ok(report)
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CancelReport {
+    /// PENDING events failed as cancelled.
+    pub cancelled: u64,
+    /// Snapshot taken first.
+    pub snapshot: Option<String>,
+}
+
+/// Fails the PENDING tasks of `batch_id`, so their messages are dropped when delivered.
+pub async fn cancel_batch(
+    store: &ElasticStore,
+    batch_id: &str,
+    keep_counts: bool,
+) -> DbResult<CancelReport> {
+    let snapshot = store
+        .snapshot_before("cancel-batch", json!({ "batch_id": batch_id }))
+        .await?;
+    let query = json!({
+        "bool": {
+            "filter": [
+                { "term": { "batch_id": batch_id }},
+                { "term": { "status": TaskStatus::Pending.as_str() }}
+            ]
+        }
+    });
+    let fields = json!({
+        "status": TaskStatus::Failed.as_str(),
+        "completed_at": Utc::now(),
+        CANCELLED: true,
+    });
+    let cancelled = store.update_events_by_query(query, &fields).await?;
+    if keep_counts && cancelled > 0 {
+        let progress = store
+            .update_batch_counts(
+                batch_id,
+                Some(TaskStatus::Pending),
+                TaskStatus::Failed,
+                cancelled,
+            )
+            .await?;
+        if progress.just_finished {
+            info!("Batch {} finished with its cancellation", batch_id);
+        }
+    }
+    info!(
+        "Cancelled batch {}: {} pending events failed",
+        batch_id, cancelled
+    );
+    Ok(CancelReport {
+        cancelled,
+        snapshot,
+    })
+}
+
+/// Walks the task queue raising the priority of its aged tasks, or returns `None` when
+/// another walk holds the lock.
+pub async fn age_queue(
+    broker: &Arc<AmqpBroker>,
+    store: &ElasticStore,
+    aging: &QueueAging,
+    max_priority: u8,
+) -> DbResult<Option<AgingReport>> {
+    if !store
+        .acquire_lock(AGE_QUEUE_LOCK, AGE_QUEUE_LOCK_TTL)
+        .await?
+    {
+        return Ok(None);
+    }
+    let report = queue_aging::age_queue(broker, aging, max_priority).await;
+    store.release_lock(AGE_QUEUE_LOCK).await?;
+    Ok(Some(report?))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    pub migration: IndexMigration,
+    /// Snapshot taken first.
+    pub snapshot: Option<String>,
+}
+
+/// Migrates the events index `index` to the current mapping with `moves` applied.
+pub async fn migrate_events(
+    store: &ElasticStore,
+    index: &str,
+    moves: &[FieldMove],
+) -> DbResult<MigrationReport> {
+    let snapshot = store
+        .snapshot_before("migrate", json!({ "index": index }))
+        .await?;
+    let migration = store.migrate_events(index, moves).await?;
+    Ok(MigrationReport {
+        migration,
+        snapshot,
+    })
+}
+
+/// Query of the events a bulk operation applies to: those matching the Elasticsearch
+/// `query` (JSON), of `batch_id` and among `message_ids`, whichever are given.
+pub fn selection(
+    query: Option<&str>,
+    batch_id: Option<&str>,
+    message_ids: &[String],
+) -> Result<Value, String> {
+    let mut filter = Vec::new();
+    if let Some(query) = query {
+        let query: Value =
+            serde_json::from_str(query).map_err(|e| format!("Invalid query: {}", e))?;
+        if !query.is_object() {
+            return Err("The query has to be a JSON object".to_string());
+        }
+        filter.push(query);
+    }
+    if let Some(batch_id) = batch_id {
+        filter.push(json!({ "term": { "batch_id": batch_id }}));
+    }
+    if !message_ids.is_empty() {
+        filter.push(json!({ "terms": { "message_id": message_ids }}));
+    }
+    if filter.is_empty() {
+        return Err("Select the events with a query, a batch or message ids".to_string());
+    }
+    Ok(json!({ "bool": { "filter": filter }}))
+}
+
+/// `selection` narrowed to the events in `status`.
+fn in_status(selection: &Value, status: TaskStatus) -> Value {
+    json!({
+        "bool": {
+            "filter": [selection, { "term": { "status": status.as_str() }}]
+        }
+    })
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BulkReport {
+    /// Events the operation applied to.
+    pub matched: u64,
+    /// Events changed.
+    pub updated: u64,
+    /// Messages published to the task queue again at another priority.
+    pub messages: usize,
+    /// Snapshot taken first.
+    pub snapshot: Option<String>,
+}
+
+/// Events matching a query, paged by message id.
+struct Pages<'a> {
+    store: &'a ElasticStore,
+    query: &'a Value,
+    operation: &'static str,
+    total: u64,
+    seen: u64,
+    after: Option<String>,
+}
+
+impl<'a> Pages<'a> {
+    async fn new(
+        store: &'a ElasticStore,
+        query: &'a Value,
+        operation: &'static str,
+    ) -> DbResult<Pages<'a>> {
+        let total = store.count_events(query).await?;
+        Ok(Self {
+            store,
+            query,
+            operation,
+            total,
+            seen: 0,
+            after: None,
+        })
+    }
+
+    /// Message ids and batch ids of the next events, logging the progress.
+    async fn next(&mut self) -> DbResult<Option<Vec<(String, String)>>> {
+        if self.seen > 0 {
+            info!("{}: {} of {} events", self.operation, self.seen, self.total);
+        }
+        let page = self
+            .store
+            .matching_events(self.query, self.after.as_deref(), CHUNK_SIZE)
+            .await?;
+        let Some((last, _)) = page.last() else {
+            return Ok(None);
+        };
+        self.after = Some(last.clone());
+        self.seen += page.len() as u64;
+        Ok(Some(page))
+    }
+}
+
+/// Whether `data` is the task message of one of `message_ids`.
+fn is_selected(data: &[u8], message_ids: &HashSet<String>) -> bool {
+    TaskMessage::parse(data).is_ok_and(|message| message_ids.contains(message.message_id.as_ref()))
+}
+
+/// Fails the PENDING tasks among the events of `selection` as cancelled, so their
+/// messages are dropped when delivered, or only counts them with `dry_run`.
+pub async fn cancel_tasks(
+    store: &ElasticStore,
+    selection: &Value,
+    keep_counts: bool,
+    dry_run: bool,
+) -> DbResult<BulkReport> {
+    let query = in_status(selection, TaskStatus::Pending);
+    let mut pages = Pages::new(store, &query, "cancel-tasks").await?;
+    let mut report = BulkReport {
+        matched: pages.total,
+        ..BulkReport::default()
+    };
+    if dry_run || report.matched == 0 {
+        return Ok(report);
+    }
+    report.snapshot = store
+        .snapshot_before("cancel-tasks", json!({ "query": selection }))
+        .await?;
+
+    let fields = json!({
+        "status": TaskStatus::Failed.as_str(),
+        "completed_at": Utc::now(),
+        CANCELLED: true,
+    });
+    while let Some(page) = pages.next().await? {
+        let mut batches: HashMap<String, Vec<String>> = HashMap::new();
+        for (message_id, batch_id) in page {
+            batches.entry(batch_id).or_default().push(message_id);
+        }
+        for (batch_id, message_ids) in batches {
+            let query = in_status(
+                &json!({ "terms": { "message_id": message_ids }}),
+                TaskStatus::Pending,
+            );
+            let updated = store.update_events_by_query(query, &fields).await?;
+            if keep_counts && updated > 0 {
+                store
+                    .update_batch_counts(
+                        &batch_id,
+                        Some(TaskStatus::Pending),
+                        TaskStatus::Failed,
+                        updated,
+                    )
+                    .await?;
+            }
+            report.updated += updated;
+        }
+    }
+    info!("Cancelled {} pending tasks", report.updated);
+    Ok(report)
+}
+
+/// Publishes the messages of the PENDING tasks among the events of `selection` again
+/// at `priority`, or only counts the tasks with `dry_run`.
+pub async fn reprioritize(
+    broker: &Arc<AmqpBroker>,
+    store: &ElasticStore,
+    selection: &Value,
+    priority: u8,
+    dry_run: bool,
+) -> DbResult<BulkReport> {
+    let query = in_status(selection, TaskStatus::Pending);
+    let mut pages = Pages::new(store, &query, "reprioritize").await?;
+    let mut report = BulkReport {
+        matched: pages.total,
+        ..BulkReport::default()
+    };
+    if dry_run || report.matched == 0 {
+        return Ok(report);
+    }
+    let mut selected = HashSet::new();
+    while let Some(page) = pages.next().await? {
+        selected.extend(page.into_iter().map(|(message_id, _)| message_id));
+    }
+
+    // Messages of other tasks are put back at their priority, in the order they came.
+    let mut walk = broker.walk(broker.queue()).await?;
+    while let Some(message) = walk.next().await? {
+        if is_selected(&message.data, &selected) {
+            walk.put_back(&message, Some(priority)).await?;
+            report.messages += 1;
+        } else {
+            walk.put_back(&message, message.priority).await?;
+        }
+    }
+    info!(
+        "Published {} pending tasks again at priority {}",
+        report.messages, priority
+    );
+    Ok(report)
+}
+
+/// Adds the tags `add` to the events of `selection` and removes the tags `remove`, or
+/// only counts the events with `dry_run`.
+pub async fn retag(
+    store: &ElasticStore,
+    selection: &Value,
+    add: &[String],
+    remove: &[String],
+    dry_run: bool,
+) -> DbResult<BulkReport> {
+    if add.is_empty() && remove.is_empty() {
+        return Err("No tags to add or remove".into());
+    }
+    let mut pages = Pages::new(store, selection, "retag").await?;
+    let mut report = BulkReport {
+        matched: pages.total,
+        ..BulkReport::default()
+    };
+    if dry_run || report.matched == 0 {
+        return Ok(report);
+    }
+    report.snapshot = store
+        .snapshot_before(
+            "retag",
+            json!({ "query": selection, "add": add, "remove": remove }),
+        )
+        .await?;
+    while let Some(page) = pages.next().await? {
+        let message_ids: Vec<String> = page.into_iter().map(|(message_id, _)| message_id).collect();
+        report.updated += store
+            .retag_events(
+                json!({ "terms": { "message_id": message_ids }}),
+                add,
+                remove,
+            )
+            .await?;
+    }
+    info!("Retagged {} of {} events", report.updated, report.matched);
+    Ok(report)
+}
+
+/// Messages ready in a queue and its consumers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueStats {
+    pub name: String,
+    pub messages: u32,
+    pub consumers: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub queues: Vec<QueueStats>,
+    /// Events by status, of one batch or of all of them.
+    pub events: BTreeMap<String, u64>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<40} {:>10} {:>10}", "queue", "messages", "consumers")?;
+        for queue in &self.queues {
+            writeln!(
+                f,
+                "{:<40} {:>10} {:>10}",
+                queue.name, queue.messages, queue.consumers
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:<40} {:>10}", "status", "events")?;
+        for (status, count) in &self.events {
+            writeln!(f, "{:<40} {:>10}", status, count)?;
+        }
+        writeln!(
+            f,
+            "{:<40} {:>10}",
+            "total",
+            self.events.values().sum::<u64>()
+        )
+    }
+}
+
+/// Counts of the task and dead-letter queues, and of the events of `batch_id` or of
+/// every batch.
+pub async fn stats(
+    broker: &AmqpBroker,
+    store: &ElasticStore,
+    batch_id: Option<&str>,
+) -> DbResult<Stats> {
+    let mut queues = Vec::new();
+    for name in std::iter::once(broker.queue()).chain(broker.dead_letter_queue()) {
+        let (messages, consumers) = broker.queue_counts(name).await?;
+        queues.push(QueueStats {
+            name: name.to_string(),
+            messages,
+            consumers,
+        });
+    }
+    Ok(Stats {
+        queues,
+        events: store.status_counts(batch_id).await?,
+    })
+}
+
+/// Writes the completed responses of `batch_id`, or of every batch, to the response
+/// cache and returns how many were written.
+pub async fn reindex_cache(
+    store: &ElasticStore,
+    cache: &RedisCacheStore,
+    batch_id: Option<&str>,
+) -> DbResult<usize> {
+    let mut after: Option<String> = None;
+    let mut written = 0;
+    loop {
+        let page = store
+            .cacheable_responses(batch_id, after.as_deref(), PAGE_SIZE)
+            .await?;
+        let Some((_, last)) = page.last() else {
+            break;
+        };
+        after = Some(last.message_id.clone());
+        for (body_hash, result) in &page {
+            match cache.put(body_hash, &result.response).await {
+                Ok(()) => written += 1,
+                Err(e) => warn!(
+                    "Failed to cache the response of {}: {}",
+                    result.message_id, e
+                ),
+            }
+        }
+    }
+    info!(
+        "Wrote {} completed responses to the response cache",
+        written
+    );
+    Ok(written)
+}
+
+/// A consumer process as its latest heartbeat describes it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerStatus {
+    pub worker_id: String,
+    pub hostname: String,
+    pub version: String,
+    /// `running`, or `stopped` once shut down cleanly.
+    pub state: String,
+    pub in_flight: u64,
+    pub processed: u64,
+    pub uptime_secs: u64,
+    pub last_heartbeat: String,
+    /// Running but silent for longer than `WORKER_STALE_AFTER_SECS`.
+    pub stale: bool,
+}
+
+impl WorkerStatus {
+    pub fn from_heartbeat(heartbeat: &serde_json::Value, stale_after: Duration) -> Self {
+        let text = |field: &str| heartbeat[field].as_str().unwrap_or_default().to_string();
+        let count = |field: &str| heartbeat[field].as_u64().unwrap_or_default();
+        Self {
+            worker_id: text("worker_id"),
+            hostname: text("hostname"),
+            version: text("version"),
+            state: text("state"),
+            in_flight: count("in_flight"),
+            processed: count("processed"),
+            uptime_secs: count("uptime_secs"),
+            last_heartbeat: text("last_heartbeat"),
+            stale: workers::is_stale(heartbeat, Utc::now(), stale_after),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Fleet {
+    pub workers: Vec<WorkerStatus>,
+}
+
+impl fmt::Display for Fleet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<40} {:<24} {:<10} {:<8} {:>9} {:>10} {:>10}  last heartbeat",
+            "worker", "hostname", "version", "state", "in flight", "processed", "uptime"
+        )?;
+        for worker in &self.workers {
+            let state = if worker.stale { "stale" } else { &worker.state };
+            writeln!(
+                f,
+                "{:<40} {:<24} {:<10} {:<8} {:>9} {:>10} {:>9}s  {}",
+                worker.worker_id,
+                worker.hostname,
+                worker.version,
+                state,
+                worker.in_flight,
+                worker.processed,
+                worker.uptime_secs,
+                worker.last_heartbeat
+            )?;
+        }
+        let stale = self.workers.iter().filter(|worker| worker.stale).count();
+        writeln!(f, "{} workers, {} stale", self.workers.len(), stale)
+    }
+}
+
+/// Consumer processes with a heartbeat, only the stale ones with `stale_only`.
+pub async fn fleet(
+    store: &ElasticStore,
+    stale_after: Duration,
+    stale_only: bool,
+) -> DbResult<Fleet> {
+    let workers = store
+        .worker_heartbeats()
+        .await?
+        .iter()
+        .map(|heartbeat| WorkerStatus::from_heartbeat(heartbeat, stale_after))
+        .filter(|worker| worker.stale || !stale_only)
+        .collect();
+    Ok(Fleet { workers })
+}
@@ -0,0 +1,126 @@
+//! Anthropic's Messages API, spoken by providers configured with `provider: anthropic`.
+//!
+//! Requests authenticate with `x-api-key` and name the API version in
+//! `anthropic-version` rather than sending a bearer token. Bodies written for the
+//! OpenAI-compatible chat API, or legacy prompts, are converted: system messages move
+//! to `system`, `stop` becomes `stop_sequences`, the required `max_tokens` defaults to
+//! `DEFAULT_MAX_TOKENS` and parameters the Messages API rejects are dropped. Native
+//! bodies go through unchanged.
+//!
+//! Responses are stored as returned, `provider_response` reading text, stop reason and
+//! usage out of the Messages shape and mapping its error types onto the retry classes.
+//! A 429 without `retry-after` waits for the reset of the limits it exhausted, and
+//! `x-should-retry` overrides whether an error status is retried.
+
+use crate::legacy_completions;
+use crate::schemas::provider_response;
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderMap;
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+pub const API_VERSION: &str = "2023-06-01";
+
+/// `max_tokens` of converted bodies that set no token limit.
+pub const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+/// OpenAI chat parameters the Messages API has no equivalent for.
+const DROPPED_PARAMS: &[&str] = &[
+    "n",
+    "frequency_penalty",
+    "presence_penalty",
+    "logprobs",
+    "top_logprobs",
+    "logit_bias",
+    "seed",
+    "response_format",
+    "stream_options",
+    "parallel_tool_calls",
+];
+
+/// Rate limits reported in `anthropic-ratelimit-<limit>-remaining` and `-reset`.
+const RATE_LIMITS: &[&str] = &["requests", "tokens", "input-tokens", "output-tokens"];
+
+/// The Messages API equivalent of `body`.
+pub fn to_messages(body: Value) -> Value {
+    let body = if legacy_completions::is_legacy(&body) {
+        legacy_completions::to_chat(&body).unwrap_or(body)
+    } else {
+        body
+    };
+    let Value::Object(mut body) = body else {
+        return body;
+    };
+
+    if let Some(Value::Array(messages)) = body.remove("messages") {
+        let mut system: Vec<String> = body
+            .remove("system")
+            .and_then(|system| system_text(&system))
+            .into_iter()
+            .collect();
+        let mut turns = Vec::with_capacity(messages.len());
+        for message in messages {
+            match message["role"].as_str() {
+                Some("system" | "developer") => system.extend(system_text(&message["content"])),
+                _ => turns.push(message),
+            }
+        }
+        if !system.is_empty() {
+            body.insert("system".to_string(), Value::from(system.join("\n\n")));
+        }
+        body.insert("messages".to_string(), Value::Array(turns));
+    }
+
+    for param in DROPPED_PARAMS {
+        body.remove(*param);
+    }
+    let max_completion_tokens = body.remove("max_completion_tokens");
+    if !body.contains_key("max_tokens") {
+        let max_tokens = max_completion_tokens.unwrap_or(Value::from(DEFAULT_MAX_TOKENS));
+        body.insert("max_tokens".to_string(), max_tokens);
+    }
+    if let Some(stop) = body.remove("stop") {
+        let stop = match stop {
+            Value::String(stop) => Value::from(vec![stop]),
+            stop => stop,
+        };
+        body.entry("stop_sequences").or_insert(stop);
+    }
+    if let Some(user) = body.remove("user") {
+        let mut metadata = Map::new();
+        metadata.insert("user_id".to_string(), user);
+        body.entry("metadata").or_insert(Value::Object(metadata));
+    }
+    Value::Object(body)
+}
+
+/// Text of a system prompt: a string or text blocks.
+fn system_text(content: &Value) -> Option<String> {
+    match content {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(blocks) => provider_response::text_blocks(blocks.iter()).map(Into::into),
+        _ => None,
+    }
+}
+
+/// Delay until the latest reset of the rate limits a response reports exhausted.
+pub fn rate_limit_reset(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let header = |name: String| headers.get(name).and_then(|value| value.to_str().ok());
+    RATE_LIMITS
+        .iter()
+        .filter(|limit| header(format!("anthropic-ratelimit-{}-remaining", limit)) == Some("0"))
+        .filter_map(|limit| header(format!("anthropic-ratelimit-{}-reset", limit)))
+        .filter_map(|reset| DateTime::parse_from_rfc3339(reset).ok())
+        .map(|reset| reset.with_timezone(&Utc) - now)
+        .max()
+        .map(|delay| delay.to_std().unwrap_or_default())
+}
+
+/// Whether the provider asks for the request to be retried, if it says so.
+pub fn should_retry(headers: &HeaderMap) -> Option<bool> {
+    match headers.get("x-should-retry")?.to_str().ok()? {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,188 @@
+//! Uploads to an S3-compatible object store, e.g. the MinIO bucket the API keeps batch
+//! files in, for bodies too large to keep in events.
+//!
+//! Objects are written with a single path-style `PUT {endpoint}/{bucket}/{key}` signed
+//! with AWS Signature Version 4, so any S3 implementation accepts them without an SDK.
+
+use crate::settings::ArchiveSettings;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+type ArchiveResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const UPLOAD_TIMEOUT_SECS: u64 = 30;
+
+#[derive(Clone)]
+pub struct Archive {
+    client: reqwest::Client,
+    settings: ArchiveSettings,
+}
+
+impl Archive {
+    pub fn new(settings: &ArchiveSettings) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(UPLOAD_TIMEOUT_SECS))
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            settings: settings.clone(),
+        }
+    }
+
+    /// Stores `body` under the configured prefix followed by `key`, and returns its
+    /// `s3://bucket/key` location.
+    pub async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> ArchiveResult<String> {
+        let key = format!("{}{}", self.settings.prefix, key);
+        let url = Url::parse(&format!(
+            "{}/{}/{}",
+            self.settings.endpoint.trim_end_matches('/'),
+            self.settings.bucket,
+            uri_encode(&key)
+        ))?;
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let now = Utc::now();
+        let authorization = authorization(&self.settings, &url, &payload_hash, now)?;
+
+        let response = self
+            .client
+            .put(url)
+            .header("x-amz-date", amz_date(now))
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("authorization", authorization)
+            .header("content-type", content_type)
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Archive upload failed ({}): {}", status, error).into());
+        }
+        Ok(format!("s3://{}/{}", self.settings.bucket, key))
+    }
+
+    /// Stores the file at `path` like `put`, streaming it rather than reading it whole.
+    pub async fn put_file(
+        &self,
+        key: &str,
+        path: &Path,
+        content_type: &str,
+    ) -> ArchiveResult<String> {
+        let key = format!("{}{}", self.settings.prefix, key);
+        let url = Url::parse(&format!(
+            "{}/{}/{}",
+            self.settings.endpoint.trim_end_matches('/'),
+            self.settings.bucket,
+            uri_encode(&key)
+        ))?;
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut hasher = Sha256::new();
+        let mut chunk = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&chunk[..read]);
+        }
+        let payload_hash = hex::encode(hasher.finalize());
+        let length = file.metadata().await?.len();
+        file.rewind().await?;
+        let now = Utc::now();
+        let authorization = authorization(&self.settings, &url, &payload_hash, now)?;
+
+        let response = self
+            .client
+            .put(url)
+            .header("x-amz-date", amz_date(now))
+            .header("x-amz-content-sha256", &payload_hash)
+            .header("authorization", authorization)
+            .header("content-type", content_type)
+            .header("content-length", length)
+            .body(file)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Archive upload failed ({}): {}", status, error).into());
+        }
+        Ok(format!("s3://{}/{}", self.settings.bucket, key))
+    }
+}
+
+fn amz_date(at: DateTime<Utc>) -> String {
+    at.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// `Authorization` header of a `PUT` to `url` of a body hashing to `payload_hash`.
+fn authorization(
+    settings: &ArchiveSettings,
+    url: &Url,
+    payload_hash: &str,
+    at: DateTime<Utc>,
+) -> ArchiveResult<String> {
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err(format!("Archive endpoint {} has no host", url).into()),
+    };
+    let amz_date = amz_date(at);
+    let date = at.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/s3/aws4_request", date, settings.region);
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+        "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+        url.path(),
+        host,
+        payload_hash,
+        amz_date,
+        signed_headers,
+        payload_hash
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let mut key = format!("AWS4{}", settings.secret_key).into_bytes();
+    for part in [
+        date.as_str(),
+        settings.region.as_str(),
+        "s3",
+        "aws4_request",
+    ] {
+        key = hmac(&key, part.as_bytes())?;
+    }
+    let signature = hex::encode(hmac(&key, string_to_sign.as_bytes())?);
+    Ok(format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        settings.access_key, scope, signed_headers, signature
+    ))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> ArchiveResult<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+/// Percent-encodes an object key as SigV4 expects, keeping its `/` separators.
+fn uri_encode(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
@@ -0,0 +1,66 @@
+//! Arrow IPC streams of export rows, which `synthgen-api` serves so Python/Polars
+//! readers pull the completed events of a batch as record batches rather than pages of
+//! JSON (`pyarrow.ipc.open_stream`, `polars.read_ipc_stream`).
+//!
+//! A stream is the schema message, a record batch message per page of rows, then the
+//! end-of-stream marker. Columns are the nullable UTF-8 columns of `EXPORT_FIELDS`,
+//! with the values of the Parquet export: values that aren't strings are written as
+//! JSON.
+
+use crate::export::cell;
+use crate::settings::ExportField;
+use arrow_array::{ArrayRef, RecordBatch, StringArray};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+
+/// Media type of Arrow IPC streams.
+pub const CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+
+/// Arrow IPC stream of export rows, whose messages are taken out as they are written.
+pub struct ResultStream {
+    writer: StreamWriter<Vec<u8>>,
+    schema: SchemaRef,
+}
+
+impl ResultStream {
+    /// Stream of `fields`, starting with its schema message.
+    pub fn new(fields: &[ExportField]) -> Result<Self, ArrowError> {
+        let schema = Arc::new(Schema::new(
+            fields
+                .iter()
+                .map(|f| Field::new(&f.name, DataType::Utf8, true))
+                .collect::<Vec<_>>(),
+        ));
+        Ok(Self {
+            writer: StreamWriter::try_new(Vec::new(), &schema)?,
+            schema,
+        })
+    }
+
+    /// Writes a record batch message of `rows`, with a column per field.
+    pub fn write(&mut self, rows: &[Map<String, Value>]) -> Result<(), ArrowError> {
+        let columns = self
+            .schema
+            .fields()
+            .iter()
+            .map(|f| {
+                let values = rows.iter().map(|row| cell(row.get(f.name())));
+                Arc::new(values.collect::<StringArray>()) as ArrayRef
+            })
+            .collect();
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        self.writer.write(&batch)
+    }
+
+    /// Writes the end-of-stream marker.
+    pub fn finish(&mut self) -> Result<(), ArrowError> {
+        self.writer.finish()
+    }
+
+    /// Bytes of the messages written since the last call.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(self.writer.get_mut())
+    }
+}
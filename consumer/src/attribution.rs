@@ -0,0 +1,80 @@
+//! Organization, project and user a task is run for, so a shared cluster can serve
+//! many teams with clean attribution.
+//!
+//! Producers put `{"org": ..., "project": ..., "user": ...}` under the message's
+//! `attribution`, next to the payload rather than in it, so it stays readable when the
+//! payload is encrypted. It is copied onto the task's event, labels the task's spans,
+//! and selects the quotas the task's LLM calls are charged to: `QUOTA_<ORG>` and
+//! `TOKEN_QUOTA_<ORG>` for a whole organization, `QUOTA_<ORG>__<PROJECT>` and
+//! `TOKEN_QUOTA_<ORG>__<PROJECT>` for one of its projects, see `rate_limit`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Field of messages and events holding the attribution.
+pub const FIELD: &str = "attribution";
+
+/// Who a task is run for. A project belongs to an organization, and a user to the
+/// organization or to one of its projects.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Attribution {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub org: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl Attribution {
+    /// The attribution of a submitted task, from its `attribution` field.
+    pub fn from_task(task: &Value) -> Result<Option<Self>, String> {
+        match &task[FIELD] {
+            Value::Null => Ok(None),
+            value => {
+                let attribution: Self = serde_json::from_value(value.clone())
+                    .map_err(|e| format!("Task field `{}`: {}", FIELD, e))?;
+                attribution
+                    .check()
+                    .map_err(|e| format!("Task field `{}`: {}", FIELD, e))?;
+                Ok(Some(attribution).filter(|a| !a.is_empty()))
+            }
+        }
+    }
+
+    /// Checks the hierarchy: projects and users need their organization.
+    pub fn check(&self) -> Result<(), String> {
+        for (field, value) in [
+            ("org", &self.org),
+            ("project", &self.project),
+            ("user", &self.user),
+        ] {
+            if value.as_deref().is_some_and(str::is_empty) {
+                return Err(format!("{}: must not be empty", field));
+            }
+        }
+        if self.org.is_none() && (self.project.is_some() || self.user.is_some()) {
+            return Err("org: required with a project or a user".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.org.is_none() && self.project.is_none() && self.user.is_none()
+    }
+
+    /// Names of the quotas the task is charged to, lowercased: its organization's
+    /// (`acme`) and its project's (`acme/search`).
+    pub fn quota_scopes(&self) -> Vec<String> {
+        let Some(org) = &self.org else {
+            return Vec::new();
+        };
+        let org = org.to_lowercase();
+        let mut scopes = vec![org.clone()];
+        if let Some(project) = &self.project {
+            scopes.push(format!("{}/{}", org, project.to_lowercase()));
+        }
+        scopes
+    }
+}
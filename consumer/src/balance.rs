@@ -0,0 +1,81 @@
+use crate::db::TaskStore;
+use crate::settings::BalanceSettings;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Tracks accepted label counts per batch and decides whether a new row would push
+/// its label above the declared target share.
+///
+/// Counts are seeded from Elasticsearch the first time a batch is seen by this process
+/// and kept in memory afterwards, counting rows once they are written, so replicas
+/// converge on the target proportions rather than enforcing them exactly.
+pub struct BalanceTracker {
+    settings: BalanceSettings,
+    counts: Mutex<HashMap<String, HashMap<String, u64>>>,
+}
+
+impl BalanceTracker {
+    pub fn new(settings: BalanceSettings) -> Self {
+        Self {
+            settings,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn ensure_seeded(&self, db_client: &dyn TaskStore, batch_id: &str) {
+        if self.counts.lock().unwrap().contains_key(batch_id) {
+            return;
+        }
+        let seeded = match db_client.label_counts(batch_id).await {
+            Ok(counts) => counts,
+            Err(e) => {
+                tracing::warn!("Failed to seed label counts for batch {}: {}", batch_id, e);
+                HashMap::new()
+            }
+        };
+        self.counts
+            .lock()
+            .unwrap()
+            .entry(batch_id.to_string())
+            .or_insert(seeded);
+    }
+
+    /// Returns `false` when a row with the given label is surplus. Labels without a
+    /// declared target are accepted. The row isn't counted until `record`.
+    pub async fn admit(&self, db_client: &dyn TaskStore, batch_id: &str, label: &str) -> bool {
+        let Some(target) = self.settings.targets.get(label).copied() else {
+            return true;
+        };
+        self.ensure_seeded(db_client, batch_id).await;
+
+        let counts = self.counts.lock().unwrap();
+        let Some(batch_counts) = counts.get(batch_id) else {
+            return true;
+        };
+        let total: u64 = batch_counts.values().sum();
+        let current = batch_counts.get(label).copied().unwrap_or(0);
+
+        if total >= self.settings.min_samples {
+            let share = (current + 1) as f64 / (total + 1) as f64;
+            if share > target + self.settings.tolerance {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Counts an admitted row once it has been written.
+    pub fn record(&self, batch_id: &str, label: &str) {
+        if !self.settings.targets.contains_key(label) {
+            return;
+        }
+        *self
+            .counts
+            .lock()
+            .unwrap()
+            .entry(batch_id.to_string())
+            .or_default()
+            .entry(label.to_string())
+            .or_insert(0) += 1;
+    }
+}
@@ -0,0 +1,260 @@
+//! Near-duplicate detection of completions across a batch.
+//!
+//! Each completion gets a MinHash signature of its word n-grams, split into bands for
+//! locality-sensitive hashing, so it's only compared with the earlier completions of
+//! its batch sharing a band with it. A completion whose estimated Jaccard similarity to
+//! one of them reaches `BATCH_DEDUP_THRESHOLD` is a `batch_duplicate` and joins that
+//! one's `dedup_cluster`, the message id of the first completion of the cluster; other
+//! completions start a cluster of their own. With `BATCH_DEDUP_REGENERATE_ATTEMPTS`,
+//! duplicates are generated again and the least similar attempt is kept; those still
+//! duplicates are rejected by the quality gates.
+//!
+//! As with label balancing, a batch's index is seeded from its completed events the
+//! first time this process sees the batch and kept in memory afterwards, so replicas
+//! generating near duplicates at the same time may not catch each other.
+
+use crate::db::TaskStore;
+use crate::schemas::provider_response::content;
+use crate::settings::BatchDedupSettings;
+use crate::text;
+use serde_json::{json, Map, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+const NUM_HASHES: usize = 64;
+/// Signatures sharing all the rows of a band are compared; 16 bands of 4 rows find
+/// most pairs from about 0.5 similar.
+const BAND_ROWS: usize = 4;
+const SEED_PAGE_SIZE: usize = 500;
+
+/// MinHash signature of a text's word n-grams.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature([u32; NUM_HASHES]);
+
+impl Signature {
+    /// `None` for texts without words.
+    pub fn of(completion: &str, ngram_size: usize) -> Option<Self> {
+        let words = text::words(completion);
+        if words.is_empty() {
+            return None;
+        }
+        // Texts too short for a whole n-gram are hashed as one.
+        let n = ngram_size.max(1).min(words.len());
+        let mut minimums = [u32::MAX; NUM_HASHES];
+        for hash in text::ngram_hashes(&words, n) {
+            for (i, minimum) in minimums.iter_mut().enumerate() {
+                *minimum = (*minimum).min(permute(hash, i));
+            }
+        }
+        Some(Self(minimums))
+    }
+
+    /// Estimated Jaccard similarity of the n-grams of both texts.
+    pub fn similarity(&self, other: &Signature) -> f64 {
+        let shared = self.0.iter().zip(&other.0).filter(|(a, b)| a == b).count();
+        shared as f64 / NUM_HASHES as f64
+    }
+
+    fn bands(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.0.chunks(BAND_ROWS).enumerate().map(|(band, rows)| {
+            let mut hasher = DefaultHasher::new();
+            rows.hash(&mut hasher);
+            (band, hasher.finish())
+        })
+    }
+}
+
+/// `hash` under the `i`th of the signature's hash functions (splitmix64 of a seeded
+/// value).
+fn permute(hash: u64, i: usize) -> u32 {
+    let mut z = hash.wrapping_add((i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) as u32
+}
+
+struct Entry {
+    message_id: String,
+    signature: Signature,
+    cluster: String,
+}
+
+#[derive(Default)]
+struct BatchIndex {
+    entries: Vec<Entry>,
+    buckets: HashMap<(usize, u64), Vec<usize>>,
+}
+
+impl BatchIndex {
+    /// Most similar earlier completion of another task, with its similarity.
+    fn closest(&self, message_id: &str, signature: &Signature) -> Option<(&Entry, f64)> {
+        let mut candidates: Vec<usize> = signature
+            .bands()
+            .filter_map(|band| self.buckets.get(&band))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+            .into_iter()
+            .map(|i| &self.entries[i])
+            // A redelivered task isn't a duplicate of its own earlier generation.
+            .filter(|entry| entry.message_id != message_id)
+            .map(|entry| (entry, entry.signature.similarity(signature)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+    }
+
+    fn insert(&mut self, message_id: &str, signature: Signature, cluster: String) {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.message_id == message_id) {
+            entry.cluster = cluster;
+            return;
+        }
+        let index = self.entries.len();
+        for band in signature.bands() {
+            self.buckets.entry(band).or_default().push(index);
+        }
+        self.entries.push(Entry {
+            message_id: message_id.to_string(),
+            signature,
+            cluster,
+        });
+    }
+}
+
+/// Where a completion stands among the earlier completions of its batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assessment {
+    signature: Option<Signature>,
+    /// Message id of the first completion of the completion's cluster.
+    pub cluster: String,
+    /// Highest similarity to an earlier completion sharing a band with it, 0 without.
+    pub similarity: f64,
+    /// Whether `similarity` reaches the threshold.
+    pub duplicate: bool,
+}
+
+impl Assessment {
+    pub fn annotations(&self) -> Map<String, Value> {
+        let mut annotations = Map::new();
+        annotations.insert("dedup_cluster".to_string(), json!(self.cluster));
+        annotations.insert("batch_duplicate".to_string(), json!(self.duplicate));
+        annotations.insert(
+            "batch_duplicate_similarity".to_string(),
+            json!(self.similarity),
+        );
+        annotations
+    }
+}
+
+/// Signatures of the completions of each batch seen by this process.
+pub struct BatchDedup {
+    settings: BatchDedupSettings,
+    batches: Mutex<HashMap<String, BatchIndex>>,
+}
+
+impl BatchDedup {
+    pub fn new(settings: BatchDedupSettings) -> Self {
+        Self {
+            settings,
+            batches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn regenerate_attempts(&self) -> u32 {
+        self.settings.regenerate_attempts
+    }
+
+    /// Indexes the completed events of `batch_id` the first time it's seen.
+    pub async fn seed(&self, db_client: &dyn TaskStore, batch_id: &str) {
+        if self.batches.lock().unwrap().contains_key(batch_id) {
+            return;
+        }
+        let mut index = BatchIndex::default();
+        let mut after: Option<String> = None;
+        loop {
+            let page = match db_client
+                .completed_events(batch_id, after.as_deref(), SEED_PAGE_SIZE)
+                .await
+            {
+                Ok(page) => page,
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to seed the completions of batch {}: {}",
+                        batch_id,
+                        e
+                    );
+                    break;
+                }
+            };
+            for event in &page {
+                let Some(message_id) = event["message_id"].as_str() else {
+                    continue;
+                };
+                let Some(signature) = content(&event["completions"])
+                    .and_then(|completion| Signature::of(&completion, self.settings.ngram_size))
+                else {
+                    continue;
+                };
+                let cluster = event["dedup_cluster"].as_str().unwrap_or(message_id);
+                index.insert(message_id, signature, cluster.to_string());
+            }
+            after = match page.last() {
+                Some(last) if page.len() == SEED_PAGE_SIZE => {
+                    last["message_id"].as_str().map(str::to_string)
+                }
+                _ => None,
+            };
+            if after.is_none() {
+                break;
+            }
+        }
+        self.batches
+            .lock()
+            .unwrap()
+            .entry(batch_id.to_string())
+            .or_insert(index);
+    }
+
+    /// Compares the completion of `message_id` with the earlier ones of `batch_id`,
+    /// without recording it.
+    pub fn assess(&self, batch_id: &str, message_id: &str, completion: &str) -> Assessment {
+        let signature = Signature::of(completion, self.settings.ngram_size);
+        let batches = self.batches.lock().unwrap();
+        let closest = signature.as_ref().and_then(|signature| {
+            batches
+                .get(batch_id)
+                .and_then(|index| index.closest(message_id, signature))
+        });
+        match closest {
+            Some((entry, similarity)) if similarity >= self.settings.threshold => Assessment {
+                signature,
+                cluster: entry.cluster.clone(),
+                similarity,
+                duplicate: true,
+            },
+            closest => Assessment {
+                signature,
+                cluster: message_id.to_string(),
+                similarity: closest.map_or(0.0, |(_, similarity)| similarity),
+                duplicate: false,
+            },
+        }
+    }
+
+    /// Records the assessed completion of `message_id` for later completions of
+    /// `batch_id` to be compared with.
+    pub fn record(&self, batch_id: &str, message_id: &str, assessment: &Assessment) {
+        let Some(signature) = &assessment.signature else {
+            return;
+        };
+        self.batches
+            .lock()
+            .unwrap()
+            .entry(batch_id.to_string())
+            .or_default()
+            .insert(message_id, signature.clone(), assessment.cluster.clone());
+    }
+}
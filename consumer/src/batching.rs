@@ -0,0 +1,219 @@
+//! Client for OpenAI-compatible batch APIs (`/files` + `/batches`), which process
+//! requests asynchronously within a completion window at a discount.
+
+use crate::llm_wrapper::{self, LLMClient};
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+type BatchResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Endpoints the batch API accepts, relative to the API base URL.
+pub const BATCH_ENDPOINTS: &[&str] = &["/chat/completions", "/completions", "/embeddings"];
+
+/// Tasks sharing a key can go into the same batch.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BatchKey {
+    /// API base, e.g. `https://api.openai.com/v1`.
+    pub base_url: String,
+    /// Request path as the batch API expects it, e.g. `/v1/chat/completions`.
+    pub endpoint: String,
+    pub api_key: String,
+    pub model: String,
+}
+
+impl BatchKey {
+    /// Key for a task, or `None` when its URL isn't a batchable endpoint.
+    pub fn for_task(url: &str, api_key: &str, body: &Value) -> Option<Self> {
+        let parsed = reqwest::Url::parse(url).ok()?;
+        if parsed.query().is_some() {
+            return None;
+        }
+        let path = parsed.path().trim_end_matches('/');
+        let suffix = BATCH_ENDPOINTS.iter().find(|e| path.ends_with(*e))?;
+        let base_url = url.trim_end_matches('/').strip_suffix(suffix)?.to_string();
+        Some(Self {
+            base_url,
+            endpoint: path.to_string(),
+            api_key: api_key.to_string(),
+            model: body["model"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+}
+
+/// Appends one request to a batch input file.
+pub fn write_request_line(
+    buf: &mut Vec<u8>,
+    custom_id: &str,
+    endpoint: &str,
+    body: &Value,
+) -> serde_json::Result<()> {
+    serde_json::to_writer(
+        &mut *buf,
+        &json!({ "custom_id": custom_id, "method": "POST", "url": endpoint, "body": body }),
+    )?;
+    buf.push(b'\n');
+    Ok(())
+}
+
+/// Result of one request in a batch output or error file.
+#[derive(Debug)]
+pub struct RequestResult {
+    pub custom_id: String,
+    pub outcome: Result<Value, String>,
+}
+
+/// Parses a batch output or error file.
+pub fn parse_results(jsonl: &str) -> Vec<RequestResult> {
+    jsonl
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| {
+            let record: Value = match serde_json::from_str(line) {
+                Ok(record) => record,
+                Err(e) => {
+                    tracing::warn!("Skipping unparseable batch result line: {}", e);
+                    return None;
+                }
+            };
+            let custom_id = record["custom_id"].as_str()?.to_string();
+            let outcome = if !record["error"].is_null() {
+                Err(record["error"]["message"]
+                    .as_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| record["error"].to_string()))
+            } else {
+                let response = &record["response"];
+                let status = response["status_code"].as_u64().unwrap_or(0);
+                let body = response["body"].clone();
+                if !(200..300).contains(&status) {
+                    Err(format!(
+                        "Request failed ({}): {}",
+                        status,
+                        body["error"]["message"].as_str().unwrap_or("unknown error")
+                    ))
+                } else if let Some(failure) = llm_wrapper::classify_body(&body, 0) {
+                    Err(failure.message().to_string())
+                } else {
+                    Ok(body)
+                }
+            };
+            Some(RequestResult { custom_id, outcome })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BatchStatus {
+    pub id: String,
+    pub status: String,
+    pub output_file_id: Option<String>,
+    pub error_file_id: Option<String>,
+    #[serde(default)]
+    pub request_counts: Value,
+}
+
+impl BatchStatus {
+    /// Whether the batch stopped changing.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self.status.as_str(),
+            "completed" | "failed" | "expired" | "cancelled"
+        )
+    }
+}
+
+pub struct BatchApiClient {
+    client: LLMClient,
+    base_url: String,
+    authorization: String,
+}
+
+impl BatchApiClient {
+    pub fn new(client: LLMClient, key: &BatchKey) -> Self {
+        Self {
+            client,
+            base_url: key.base_url.clone(),
+            authorization: format!("Bearer {}", key.api_key),
+        }
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> BatchResult<reqwest::Response> {
+        let response = request
+            .header("Authorization", &self.authorization)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("Batch API error ({}): {}", status, body).into());
+        }
+        Ok(response)
+    }
+
+    /// Uploads a JSONL input file and returns its id.
+    pub async fn upload(&self, jsonl: Vec<u8>) -> BatchResult<String> {
+        let form = Form::new().text("purpose", "batch").part(
+            "file",
+            Part::bytes(jsonl)
+                .file_name("batch.jsonl")
+                .mime_str("application/jsonl")?,
+        );
+        let response = self
+            .send(
+                self.client
+                    .for_url(&self.base_url)
+                    .post(format!("{}/files", self.base_url))
+                    .multipart(form),
+            )
+            .await?;
+        let file: Value = response.json().await?;
+        file["id"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| "File upload response has no id".into())
+    }
+
+    pub async fn create(
+        &self,
+        input_file_id: &str,
+        endpoint: &str,
+        completion_window: &str,
+    ) -> BatchResult<BatchStatus> {
+        let response = self
+            .send(
+                self.client
+                    .for_url(&self.base_url)
+                    .post(format!("{}/batches", self.base_url))
+                    .json(&json!({
+                        "input_file_id": input_file_id,
+                        "endpoint": endpoint,
+                        "completion_window": completion_window,
+                    })),
+            )
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn status(&self, batch_id: &str) -> BatchResult<BatchStatus> {
+        let response = self
+            .send(
+                self.client
+                    .for_url(&self.base_url)
+                    .get(format!("{}/batches/{}", self.base_url, batch_id)),
+            )
+            .await?;
+        Ok(response.json().await?)
+    }
+
+    pub async fn download(&self, file_id: &str) -> BatchResult<String> {
+        let response = self
+            .send(
+                self.client
+                    .for_url(&self.base_url)
+                    .get(format!("{}/files/{}/content", self.base_url, file_id)),
+            )
+            .await?;
+        Ok(response.text().await?)
+    }
+}
@@ -0,0 +1,311 @@
+//! Operator commands over the task queues in RabbitMQ and the events in
+//! Elasticsearch, with the consumer's settings:
+//!
+//! - `requeue-failed --batch <id>` publishes the dead-lettered tasks of a batch again
+//! - `purge-queue` drops every message ready in the task queue (`--dead-letter` for
+//!   the dead-letter queue)
+//! - `stats` prints the messages and consumers of both queues and the events by status
+//! - `cancel-batch --batch <id>` fails the batch's pending tasks, whose messages consumers
+//!   then drop
+//! - `reindex-cache` writes completed responses to the Redis response cache
+//! - `age-queue` publishes aged tasks again at their raised priority, one run at a time,
+//!   see `consumer::queue_aging`
+//! - `workers` lists the consumer processes from their heartbeats (`--stale` for those
+//!   presumed crashed), see `consumer::workers`
+//! - `restore --snapshot <name>` rolls the indexes back to a snapshot taken before a
+//!   requeue, cancellation or migration
+//! - `cancel-tasks`, `reprioritize --priority <n>` and `retag --add <tag> --remove <tag>`
+//!   apply to the events selected by `--query <json>`, `--batch <id>` and
+//!   `--message-id <id>`, counting them only with `--dry-run`
+//! - `migrate [--index <name>] [--move <from>=<to>]...` reindexes an events index into
+//!   the current mapping, moving fields, behind an alias of its name
+//!
+//! See `consumer::admin` for what each one touches.
+
+use clap::{Args, Parser, Subcommand};
+use consumer::admin;
+use consumer::broker::amqp::AmqpBroker;
+use consumer::db::elastic::{ElasticStore, FieldMove};
+use consumer::db::redis_cache::RedisCacheStore;
+use consumer::queue_aging::QueueAging;
+use consumer::settings::Settings;
+use consumer::telemetry;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(about = "Operates the task queues and the events index of synthgen")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Events a bulk operation applies to, those matching every option given.
+#[derive(Args)]
+struct Selection {
+    /// Elasticsearch query of the events, as JSON
+    #[arg(long)]
+    query: Option<String>,
+    #[arg(long)]
+    batch: Option<String>,
+    /// May be repeated
+    #[arg(long = "message-id")]
+    message_ids: Vec<String>,
+    /// Only count the events the operation would apply to
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl Selection {
+    fn query(&self) -> Result<serde_json::Value, String> {
+        admin::selection(
+            self.query.as_deref(),
+            self.batch.as_deref(),
+            &self.message_ids,
+        )
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Publish the dead-lettered tasks of a batch again and set their events back to
+    /// PENDING
+    RequeueFailed {
+        #[arg(long)]
+        batch: String,
+    },
+    /// Drop every message ready in the task queue
+    PurgeQueue {
+        /// Purge the dead-letter queue instead
+        #[arg(long)]
+        dead_letter: bool,
+    },
+    /// Print queue depths and event counts by status
+    Stats {
+        /// Count the events of this batch only
+        #[arg(long)]
+        batch: Option<String>,
+    },
+    /// Fail the pending tasks of a batch, so consumers drop their messages
+    CancelBatch {
+        #[arg(long)]
+        batch: String,
+    },
+    /// Write completed responses to the Redis response cache (CACHE_BACKEND=redis)
+    ReindexCache {
+        /// Write the responses of this batch only
+        #[arg(long)]
+        batch: Option<String>,
+    },
+    /// Publish the aged tasks of the task queue again at their raised priority
+    /// (TASK_STALE_AFTER_SECS)
+    AgeQueue,
+    /// List the consumer processes and their latest heartbeat
+    Workers {
+        /// Only list the workers silent for WORKER_STALE_AFTER_SECS
+        #[arg(long)]
+        stale: bool,
+    },
+    /// Roll the indexes back to a snapshot taken before an operation
+    /// (ELASTICSEARCH_SNAPSHOT_REPOSITORY); stop the consumers first
+    Restore {
+        #[arg(long)]
+        snapshot: String,
+    },
+    /// Fail the selected pending tasks, so consumers drop their messages
+    CancelTasks {
+        #[command(flatten)]
+        selection: Selection,
+    },
+    /// Publish the messages of the selected pending tasks again at another priority
+    Reprioritize {
+        #[command(flatten)]
+        selection: Selection,
+        #[arg(long)]
+        priority: u8,
+    },
+    /// Add tags to the selected events or remove tags from them
+    Retag {
+        #[command(flatten)]
+        selection: Selection,
+        /// May be repeated
+        #[arg(long)]
+        add: Vec<String>,
+        /// May be repeated
+        #[arg(long)]
+        remove: Vec<String>,
+    },
+    /// Reindex the events index into a new one with the current mapping and swap its
+    /// name over to it as an alias; stop the consumers first
+    Migrate {
+        /// Events index to migrate, by default the one events are written to; under
+        /// rollover, migrate each dated index in turn
+        #[arg(long)]
+        index: Option<String>,
+        /// Move a field of the events, as `from=to` dotted paths; may be repeated
+        #[arg(long = "move", value_name = "FROM=TO")]
+        moves: Vec<String>,
+    },
+}
+
+fn print_snapshot(snapshot: &Option<String>) {
+    if let Some(snapshot) = snapshot {
+        println!(
+            "Snapshot {} taken first, `restore --snapshot {}` to roll back",
+            snapshot, snapshot
+        );
+    }
+}
+
+fn print_bulk_report(report: &admin::BulkReport, dry_run: bool, matched: &str) {
+    if dry_run {
+        println!("Would apply to {} {}", report.matched, matched);
+    } else {
+        print_snapshot(&report.snapshot);
+    }
+}
+
+async fn connect_broker(
+    settings: &Settings,
+) -> Result<Arc<AmqpBroker>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(Arc::new(
+        AmqpBroker::connect(&settings.broker, &settings.network).await?,
+    ))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    dotenv::dotenv().ok();
+    let _telemetry = telemetry::init("synthgen-admin");
+
+    let settings = Settings::new().expect("Failed to load settings");
+    let cli = Cli::parse();
+    let store = ElasticStore::new(&settings.storage.elasticsearch).await?;
+    let keep_counts = settings.storage.batch_progress.is_some();
+
+    match cli.command {
+        Command::RequeueFailed { batch } => {
+            let broker = connect_broker(&settings).await?;
+            let report = admin::requeue_failed(&broker, &store, &batch, keep_counts).await?;
+            print_snapshot(&report.snapshot);
+            println!(
+                "Requeued {} tasks of batch {}, {} events set back to PENDING",
+                report.requeued, batch, report.reset
+            );
+        }
+        Command::PurgeQueue { dead_letter } => {
+            let broker = connect_broker(&settings).await?;
+            let queue = if dead_letter {
+                broker
+                    .dead_letter_queue()
+                    .ok_or("No dead-letter queue configured (DEAD_LETTER_QUEUE)")?
+            } else {
+                broker.queue()
+            };
+            let purged = broker.purge(queue).await?;
+            println!("Purged {} messages from {}", purged, queue);
+        }
+        Command::Stats { batch } => {
+            let broker = connect_broker(&settings).await?;
+            print!("{}", admin::stats(&broker, &store, batch.as_deref()).await?);
+        }
+        Command::CancelBatch { batch } => {
+            let report = admin::cancel_batch(&store, &batch, keep_counts).await?;
+            print_snapshot(&report.snapshot);
+            println!(
+                "Cancelled {} pending tasks of batch {}",
+                report.cancelled, batch
+            );
+        }
+        Command::ReindexCache { batch } => {
+            let Some(response_cache) = &settings.storage.response_cache else {
+                return Err("No response cache configured (CACHE_BACKEND=redis)".into());
+            };
+            let cache = RedisCacheStore::new(Arc::new(store.clone()), response_cache)?;
+            let written = admin::reindex_cache(&store, &cache, batch.as_deref()).await?;
+            println!("Wrote {} responses to the response cache", written);
+        }
+        Command::AgeQueue => {
+            let Some(aging) = QueueAging::new(&settings.queue_aging) else {
+                return Err("Tasks don't age without TASK_STALE_AFTER_SECS".into());
+            };
+            let broker = connect_broker(&settings).await?;
+            let report =
+                admin::age_queue(&broker, &store, &aging, settings.broker.max_priority).await?;
+            match report {
+                Some(report) => println!(
+                    "Raised the priority of {} aged tasks, {} left at theirs",
+                    report.boosted, report.kept
+                ),
+                None => println!("Another age-queue run holds the lock; nothing done"),
+            }
+        }
+        Command::Workers { stale } => {
+            let stale_after = Duration::from_secs(settings.worker.stale_after_secs);
+            print!("{}", admin::fleet(&store, stale_after, stale).await?);
+        }
+        Command::Restore { snapshot } => {
+            let indices = store.restore_snapshot(&snapshot).await?;
+            println!("Restored {} from snapshot {}", indices.join(", "), snapshot);
+        }
+        Command::CancelTasks { selection } => {
+            let query = selection.query()?;
+            let report =
+                admin::cancel_tasks(&store, &query, keep_counts, selection.dry_run).await?;
+            print_bulk_report(&report, selection.dry_run, "pending tasks");
+            if !selection.dry_run {
+                println!("Cancelled {} pending tasks", report.updated);
+            }
+        }
+        Command::Reprioritize {
+            selection,
+            priority,
+        } => {
+            let max_priority = settings.broker.max_priority;
+            if max_priority == 0 {
+                return Err("The task queue has no priorities (TASK_QUEUE_MAX_PRIORITY)".into());
+            }
+            if priority > max_priority {
+                return Err(format!("Priorities go up to {}", max_priority).into());
+            }
+            let query = selection.query()?;
+            let broker = connect_broker(&settings).await?;
+            let report =
+                admin::reprioritize(&broker, &store, &query, priority, selection.dry_run).await?;
+            print_bulk_report(&report, selection.dry_run, "pending tasks");
+            if !selection.dry_run {
+                println!(
+                    "Published {} tasks again at priority {}",
+                    report.messages, priority
+                );
+            }
+        }
+        Command::Retag {
+            selection,
+            add,
+            remove,
+        } => {
+            let query = selection.query()?;
+            let report = admin::retag(&store, &query, &add, &remove, selection.dry_run).await?;
+            print_bulk_report(&report, selection.dry_run, "events");
+            if !selection.dry_run {
+                println!("Retagged {} events", report.updated);
+            }
+        }
+        Command::Migrate { index, moves } => {
+            let moves = moves
+                .iter()
+                .map(|spec| FieldMove::parse(spec))
+                .collect::<Result<Vec<_>, _>>()?;
+            let index = index.unwrap_or_else(|| store.events_index());
+            let report = admin::migrate_events(&store, &index, &moves).await?;
+            print_snapshot(&report.snapshot);
+            let migration = &report.migration;
+            println!(
+                "Migrated {} events of {} to {}, now behind the alias {}",
+                migration.documents, migration.source, migration.target, migration.alias
+            );
+        }
+    }
+    Ok(())
+}
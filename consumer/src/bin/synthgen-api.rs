@@ -0,0 +1,747 @@
+//! HTTP API submitting generation tasks: records each task's PENDING event and
+//! publishes it to the task queue for the consumer, like the Python API's batch worker.
+//!
+//! A batch resubmitted with `previous_batch_id` only regenerates the tasks whose body
+//! changed since that run: the others get the previous completion copied into their
+//! event, with `copied_from` pointing at the event it came from.
+//!
+//! A batch submitted with `callback_url` gets a signed `batch.finished` webhook once
+//! all its tasks have completed or failed; a task's own `callback_url` is told when
+//! that task does.
+//!
+//! Producers fetch the envelope and the payload schema of each task type from
+//! `/api/v1/schemas`; tasks breaking the schema of their `task_type` are rejected.
+//!
+//! The OpenAPI document of these endpoints is served at `/openapi.json`, and printed
+//! by `synthgen-api openapi` without connecting to anything, for generating clients.
+//! Builds with the `swagger-ui` feature also serve Swagger UI at `/docs`.
+//!
+//! `/api/v1/batches/{batch_id}/arrow` streams the completed events of a batch as Arrow
+//! record batches, with the columns of the exports, for dataframe readers pulling far
+//! more rows than JSON pages carry.
+//!
+//! With `PAYLOAD_RECIPIENTS`, payloads are encrypted to the consumer's age keys before
+//! they are published, and events only hold the routing fields of their task.
+//!
+//! Settings are the consumer's, overridden by `SYNTHGEN_API__...` variables.
+
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use bytes::Bytes;
+use chrono::Utc;
+use consumer::arrow_ipc;
+use consumer::broker::{self, MessageBroker};
+use consumer::db::{self, TaskStore};
+use consumer::export;
+use consumer::payload_encryption;
+use consumer::producer::{self, PreparedTask};
+use consumer::schema_registry::SchemaRegistry;
+use consumer::schemas::envelope;
+use consumer::schemas::task_status::TaskStatus;
+use consumer::settings::Settings;
+use consumer::telemetry;
+use consumer::webhook::Webhooks;
+use futures_lite::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info, info_span, Instrument};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+
+/// Events are created in chunks of this many tasks before their messages are published.
+const CHUNK_SIZE: usize = 1000;
+
+/// Completed events read at a time when streaming results, the rows of each record
+/// batch.
+const RESULT_PAGE_SIZE: usize = 2000;
+
+struct ApiState {
+    settings: Settings,
+    store: Arc<dyn TaskStore>,
+    schemas: Arc<SchemaRegistry>,
+    /// Consumer keys payloads are encrypted to, if any.
+    recipients: Vec<age::x25519::Recipient>,
+    /// Connected lazily and replaced once the connection is lost.
+    broker: Mutex<Option<Arc<dyn MessageBroker>>>,
+}
+
+impl ApiState {
+    async fn broker(&self) -> broker::BrokerResult<Arc<dyn MessageBroker>> {
+        let mut broker = self.broker.lock().await;
+        match broker.as_ref() {
+            Some(connected) if connected.is_connected() => Ok(connected.clone()),
+            _ => {
+                let connected =
+                    broker::connect(&self.settings.broker, &self.settings.network).await?;
+                *broker = Some(connected.clone());
+                Ok(connected)
+            }
+        }
+    }
+}
+
+struct ApiError(StatusCode, String);
+
+/// Body of error responses.
+#[derive(Serialize, ToSchema)]
+struct ErrorDetail {
+    detail: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(ErrorDetail { detail: self.1 })).into_response()
+    }
+}
+
+/// A task as submitted, the `payload` of its message. Its schema in the OpenAPI
+/// document is the envelope's, see `openapi_document`.
+#[derive(ToSchema)]
+#[schema(value_type = Object)]
+#[allow(dead_code)]
+struct Task(Value);
+
+/// Arrow IPC stream of the completed events of a batch.
+#[derive(ToSchema)]
+#[schema(value_type = String, format = Binary)]
+#[allow(dead_code)]
+struct ArrowStream(Vec<u8>);
+
+/// A batch submitted as JSON rather than JSONL.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+struct BatchRequest {
+    tasks: Vec<Task>,
+    /// Webhook told when the batch finishes, unless given as a query parameter.
+    callback_url: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct TaskSubmitted {
+    batch_id: String,
+    message_id: String,
+    body_hash: String,
+    /// Whether the task was completed with the result of the previous run.
+    copied: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BatchSubmitted {
+    batch_id: String,
+    total_tasks: usize,
+    /// Tasks published for generation.
+    regenerated: usize,
+    /// Tasks completed with the result of the previous run.
+    copied: usize,
+    rejected: Vec<RejectedTask>,
+}
+
+/// A task of a batch that wasn't submitted.
+#[derive(Serialize, ToSchema)]
+struct RejectedTask {
+    /// 1-based position of the task in the batch.
+    line: usize,
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SchemaList {
+    /// JSON Schema of task messages.
+    #[schema(value_type = Object)]
+    envelope: Value,
+    /// JSON Schema of the payload of each task type.
+    #[schema(value_type = HashMap<String, Object>)]
+    task_types: BTreeMap<String, Value>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct TaskTypeSchema {
+    task_type: String,
+    #[schema(value_type = Object)]
+    schema: Value,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SubmitParams {
+    /// Batch the tasks join; a new one by default.
+    batch_id: Option<String>,
+    /// Earlier run whose completed results are copied to unchanged tasks.
+    previous_batch_id: Option<String>,
+    /// Webhook told when the batch finishes.
+    callback_url: Option<String>,
+}
+
+#[derive(Default)]
+struct Submitted {
+    published: usize,
+    copied: usize,
+}
+
+fn authorize(state: &ApiState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(secret_key) = &state.settings.api.secret_key else {
+        return Ok(());
+    };
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if token == Some(secret_key.as_str()) {
+        Ok(())
+    } else {
+        Err(ApiError(
+            StatusCode::UNAUTHORIZED,
+            "Invalid authentication credentials".to_string(),
+        ))
+    }
+}
+
+/// Checks the task against the payload schema of its `task_type`.
+fn check_contract(state: &ApiState, task: Value) -> Result<Value, String> {
+    match state.schemas.check(&task) {
+        Ok(()) => Ok(task),
+        Err(errors) => Err(format!(
+            "Payload violates its schema: {}",
+            errors.join("; ")
+        )),
+    }
+}
+
+/// Builds the event and message of a task, with its payload encrypted to the configured
+/// recipients.
+fn prepare(state: &ApiState, batch_id: &str, task: Value) -> Result<PreparedTask, String> {
+    producer::prepare_encrypted(
+        batch_id,
+        task,
+        state.settings.broker.max_priority,
+        &state.recipients,
+    )
+}
+
+fn check_callback_url(callback_url: &str) -> Result<(), ApiError> {
+    match reqwest::Url::parse(callback_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => Err(ApiError(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Invalid callback URL `{}`", callback_url),
+        )),
+    }
+}
+
+/// Creates the events of `tasks`, then publishes their messages, chunk by chunk. Tasks
+/// with a completed result of the same body in `previous_batch_id` are completed with a
+/// copy of it instead of being published.
+async fn submit(
+    state: &ApiState,
+    batch_id: &str,
+    previous_batch_id: Option<&str>,
+    callback_url: Option<&str>,
+    tasks: &[PreparedTask],
+) -> Result<Submitted, ApiError> {
+    let broker = state.broker().await.map_err(|e| {
+        error!("Failed to connect to the broker: {}", e);
+        ApiError(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Broker unavailable: {}", e),
+        )
+    })?;
+    // Recorded first, so a batch finishing before the submission returns is announced.
+    if let Some(callback_url) = callback_url {
+        if let Err(e) = state
+            .store
+            .set_batch_callback_url(batch_id, callback_url)
+            .await
+        {
+            error!("Failed to record the callback of batch {}: {}", batch_id, e);
+            return Err(ApiError(
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to record the batch callback: {}", e),
+            ));
+        }
+    }
+
+    let mut submitted = Submitted::default();
+    for chunk in tasks.chunks(CHUNK_SIZE) {
+        let previous = match previous_batch_id {
+            Some(previous_batch_id) => {
+                let body_hashes: Vec<&str> =
+                    chunk.iter().map(|task| task.body_hash.as_str()).collect();
+                state
+                    .store
+                    .completed_results(previous_batch_id, &body_hashes)
+                    .await
+                    .map_err(|e| {
+                        error!("Failed to look up results of {}: {}", previous_batch_id, e);
+                        ApiError(
+                            StatusCode::BAD_GATEWAY,
+                            format!("Failed to look up the previous run: {}", e),
+                        )
+                    })?
+            }
+            None => HashMap::new(),
+        };
+
+        let events: Vec<Value> = chunk.iter().map(|task| task.event.clone()).collect();
+        if let Err(e) = state.store.create_events(&events).await {
+            error!("Failed to create events: {}", e);
+            return Err(ApiError(
+                StatusCode::BAD_GATEWAY,
+                format!(
+                    "Failed to create events after {} published tasks: {}",
+                    submitted.published, e
+                ),
+            ));
+        }
+        for task in chunk {
+            if let Some(result) = previous.get(&task.body_hash) {
+                let mut response = result.response.clone();
+                response
+                    .annotations
+                    .insert("copied_from".to_string(), json!(result.message_id));
+                response
+                    .annotations
+                    .insert("copied_from_batch_id".to_string(), json!(previous_batch_id));
+                let event = db::EventKey {
+                    message_id: &task.message_id,
+                    batch_id,
+                    body_hash: &task.body_hash,
+                };
+                if let Err(e) = state
+                    .store
+                    .update_event_status(&event, TaskStatus::Completed, &response, Utc::now())
+                    .await
+                {
+                    error!("Failed to copy result to task {}: {}", task.message_id, e);
+                    return Err(ApiError(
+                        StatusCode::BAD_GATEWAY,
+                        format!(
+                            "Failed to copy results after {} published tasks: {}",
+                            submitted.published, e
+                        ),
+                    ));
+                }
+                submitted.copied += 1;
+                continue;
+            }
+            let span = info_span!("publish_task", message_id = %task.message_id, batch_id);
+            let mut headers = BTreeMap::new();
+            telemetry::inject(&span, &mut headers);
+            if let Err(e) = broker
+                .publish_task(&task.message, task.priority, &headers)
+                .instrument(span)
+                .await
+            {
+                error!("Failed to publish task {}: {}", task.message_id, e);
+                return Err(ApiError(
+                    StatusCode::BAD_GATEWAY,
+                    format!(
+                        "Failed to publish after {} tasks: {}",
+                        submitted.published, e
+                    ),
+                ));
+            }
+            submitted.published += 1;
+        }
+    }
+    Ok(submitted)
+}
+
+#[utoipa::path(get, path = "/health", responses((status = 200, description = "Up", body = String)))]
+async fn health() -> &'static str {
+    "ok"
+}
+
+/// Submits a single task, given as the JSON body.
+#[utoipa::path(
+    post,
+    path = "/api/v1/tasks",
+    params(SubmitParams),
+    request_body = Task,
+    responses(
+        (status = 201, description = "Task submitted", body = TaskSubmitted),
+        (status = 401, description = "Invalid bearer token", body = ErrorDetail),
+        (status = 422, description = "Invalid task", body = ErrorDetail),
+        (status = 502, description = "Storage or broker failure", body = ErrorDetail),
+        (status = 503, description = "Broker unavailable", body = ErrorDetail),
+    ),
+    security((), ("bearer" = []))
+)]
+async fn submit_task(
+    State(state): State<Arc<ApiState>>,
+    Query(params): Query<SubmitParams>,
+    headers: HeaderMap,
+    Json(task): Json<Value>,
+) -> Result<(StatusCode, Json<TaskSubmitted>), ApiError> {
+    authorize(&state, &headers)?;
+    let batch_id = params
+        .batch_id
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    if let Some(callback_url) = &params.callback_url {
+        check_callback_url(callback_url)?;
+    }
+    let task = check_contract(&state, task)
+        .and_then(|task| prepare(&state, &batch_id, task))
+        .map_err(|e| ApiError(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+
+    let submitted = submit(
+        &state,
+        &batch_id,
+        params.previous_batch_id.as_deref(),
+        params.callback_url.as_deref(),
+        std::slice::from_ref(&task),
+    )
+    .await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(TaskSubmitted {
+            batch_id,
+            message_id: task.message_id,
+            body_hash: task.body_hash,
+            copied: submitted.copied > 0,
+        }),
+    ))
+}
+
+/// Submits a batch, given either as `{"tasks": [...]}` or as JSONL with one task per
+/// line. Invalid tasks are reported and skipped; the others are submitted.
+#[utoipa::path(
+    post,
+    path = "/api/v1/batches",
+    params(SubmitParams),
+    request_body(
+        content((BatchRequest = "application/json"), (String = "application/x-ndjson")),
+        description = "The tasks as a JSON object, or as JSONL with one task per line"
+    ),
+    responses(
+        (status = 200, description = "Batch submitted", body = BatchSubmitted),
+        (status = 401, description = "Invalid bearer token", body = ErrorDetail),
+        (status = 413, description = "Too many tasks", body = ErrorDetail),
+        (status = 422, description = "Invalid batch", body = ErrorDetail),
+        (status = 502, description = "Storage or broker failure", body = ErrorDetail),
+        (status = 503, description = "Broker unavailable", body = ErrorDetail),
+    ),
+    security((), ("bearer" = []))
+)]
+async fn submit_batch(
+    State(state): State<Arc<ApiState>>,
+    Query(params): Query<SubmitParams>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<BatchSubmitted>, ApiError> {
+    authorize(&state, &headers)?;
+    let batch_id = params
+        .batch_id
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let mut callback_url = params.callback_url;
+    let entries: Vec<Result<Value, String>> = match serde_json::from_str::<Value>(&body) {
+        Ok(Value::Object(mut list)) if list.contains_key("tasks") => match list.remove("tasks") {
+            Some(Value::Array(tasks)) => {
+                if let Some(Value::String(url)) = list.remove("callback_url") {
+                    callback_url.get_or_insert(url);
+                }
+                tasks.into_iter().map(Ok).collect()
+            }
+            _ => {
+                return Err(ApiError(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "`tasks` must be a list".to_string(),
+                ))
+            }
+        },
+        _ => body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(|e| format!("Invalid JSON: {}", e)))
+            .collect(),
+    };
+    if let Some(callback_url) = &callback_url {
+        check_callback_url(callback_url)?;
+    }
+    if entries.len() > state.settings.api.max_batch_tasks {
+        return Err(ApiError(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!(
+                "Batches are limited to {} tasks",
+                state.settings.api.max_batch_tasks
+            ),
+        ));
+    }
+
+    let mut tasks = Vec::with_capacity(entries.len());
+    let mut rejected = Vec::new();
+    for (index, entry) in entries.into_iter().enumerate() {
+        match entry
+            .and_then(|task| check_contract(&state, task))
+            .and_then(|task| prepare(&state, &batch_id, task))
+        {
+            Ok(task) => tasks.push(task),
+            Err(error) => rejected.push(RejectedTask {
+                line: index + 1,
+                error,
+            }),
+        }
+    }
+
+    let submitted = submit(
+        &state,
+        &batch_id,
+        params.previous_batch_id.as_deref(),
+        callback_url.as_deref(),
+        &tasks,
+    )
+    .await?;
+    info!(
+        "Submitted {} tasks for batch {} ({} copied, {} rejected)",
+        submitted.published + submitted.copied,
+        batch_id,
+        submitted.copied,
+        rejected.len()
+    );
+    Ok(Json(BatchSubmitted {
+        batch_id,
+        total_tasks: submitted.published + submitted.copied,
+        regenerated: submitted.published,
+        copied: submitted.copied,
+        rejected,
+    }))
+}
+
+/// The envelope schema and the payload schemas of all registered task types.
+#[utoipa::path(
+    get,
+    path = "/api/v1/schemas",
+    responses((status = 200, description = "Registered schemas", body = SchemaList), (status = 401, description = "Invalid bearer token", body = ErrorDetail)),
+    security((), ("bearer" = []))
+)]
+async fn list_schemas(
+    State(state): State<Arc<ApiState>>,
+    headers: HeaderMap,
+) -> Result<Json<SchemaList>, ApiError> {
+    authorize(&state, &headers)?;
+    Ok(Json(SchemaList {
+        envelope: envelope::json_schema(),
+        task_types: state.schemas.schemas(),
+    }))
+}
+
+/// Payload schema of one task type.
+#[utoipa::path(
+    get,
+    path = "/api/v1/schemas/{task_type}",
+    params(("task_type" = String, Path)),
+    responses(
+        (status = 200, description = "Payload schema", body = TaskTypeSchema),
+        (status = 401, description = "Invalid bearer token", body = ErrorDetail),
+        (status = 404, description = "Unknown task type", body = ErrorDetail),
+    ),
+    security((), ("bearer" = []))
+)]
+async fn get_schema(
+    State(state): State<Arc<ApiState>>,
+    Path(task_type): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<TaskTypeSchema>, ApiError> {
+    authorize(&state, &headers)?;
+    match state.schemas.schema(&task_type) {
+        Some(schema) => Ok(Json(TaskTypeSchema { task_type, schema })),
+        None => Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("Unknown task type `{}`", task_type),
+        )),
+    }
+}
+
+/// State of an Arrow stream of results between pages of completed events.
+struct ResultPages {
+    state: Arc<ApiState>,
+    batch_id: String,
+    /// Message id of the last event streamed.
+    after: Option<String>,
+    done: bool,
+    stream: arrow_ipc::ResultStream,
+}
+
+impl ResultPages {
+    /// The record batch of the next page, followed by the end-of-stream marker after
+    /// the last page.
+    async fn next(mut self) -> Option<(db::DbResult<Bytes>, Self)> {
+        if self.done {
+            return None;
+        }
+        let page = self
+            .state
+            .store
+            .completed_events(&self.batch_id, self.after.as_deref(), RESULT_PAGE_SIZE)
+            .await;
+        let events = match page {
+            Ok(events) => events,
+            Err(e) => {
+                error!("Failed to stream results of batch {}: {}", self.batch_id, e);
+                self.done = true;
+                return Some((Err(e), self));
+            }
+        };
+        let fields = &self.state.settings.export.fields;
+        self.after = match events.last() {
+            Some(last) if events.len() == RESULT_PAGE_SIZE => {
+                last["message_id"].as_str().map(str::to_string)
+            }
+            _ => None,
+        };
+        let mut written = Ok(());
+        if !events.is_empty() {
+            let rows: Vec<_> = events
+                .iter()
+                .map(|event| export::row(event, fields))
+                .collect();
+            written = self.stream.write(&rows);
+        }
+        if self.after.is_none() {
+            written = written.and_then(|()| self.stream.finish());
+            self.done = true;
+        }
+        if let Err(e) = written {
+            error!("Failed to stream results of batch {}: {}", self.batch_id, e);
+            self.done = true;
+            return Some((Err(e.into()), self));
+        }
+        let chunk = self.stream.take();
+        Some((Ok(Bytes::from(chunk)), self))
+    }
+}
+
+/// Completed events of a batch as an Arrow IPC stream, with the columns of the
+/// exports (`EXPORT_FIELDS`) and a record batch per page of events.
+#[utoipa::path(
+    get,
+    path = "/api/v1/batches/{batch_id}/arrow",
+    params(("batch_id" = String, Path)),
+    responses(
+        (status = 200, description = "Arrow IPC stream of the completed events", content_type = "application/vnd.apache.arrow.stream", body = ArrowStream),
+        (status = 401, description = "Invalid bearer token", body = ErrorDetail),
+    ),
+    security((), ("bearer" = []))
+)]
+async fn stream_results(
+    State(state): State<Arc<ApiState>>,
+    Path(batch_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    authorize(&state, &headers)?;
+    let mut results = arrow_ipc::ResultStream::new(&state.settings.export.fields)
+        .map_err(|e| ApiError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let schema = results.take();
+    let pages = ResultPages {
+        state,
+        batch_id,
+        after: None,
+        done: false,
+        stream: results,
+    };
+    let stream =
+        stream::once(Ok(Bytes::from(schema))).chain(stream::unfold(pages, ResultPages::next));
+    Ok((
+        [(header::CONTENT_TYPE, arrow_ipc::CONTENT_TYPE)],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "synthgen API", description = "Submission of data generation tasks"),
+    paths(health, submit_task, submit_batch, stream_results, list_schemas, get_schema),
+    modifiers(&BearerAuth)
+)]
+struct ApiDoc;
+
+/// `API_SECRET_KEY` as a bearer token, required when it is set.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+/// The OpenAPI document, with the `Task` schema being the envelope's payload schema.
+fn openapi_document() -> Value {
+    let mut openapi = ApiDoc::openapi();
+    openapi.info.license = None;
+    let mut document = serde_json::to_value(openapi).unwrap_or_default();
+    document["components"]["schemas"]["Task"] =
+        envelope::json_schema()["properties"]["payload"].clone();
+    document
+}
+
+async fn openapi() -> Json<Value> {
+    Json(openapi_document())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if std::env::args().nth(1).as_deref() == Some("openapi") {
+        println!("{}", serde_json::to_string_pretty(&openapi_document())?);
+        return Ok(());
+    }
+    let _telemetry = telemetry::init("synthgen-api");
+
+    let settings = Settings::with_prefix("SYNTHGEN_API").expect("Failed to load settings");
+    let store = db::connect(&settings.storage, &Webhooks::new(&settings.webhooks))
+        .await
+        .expect("Failed to connect to database");
+    let listener = tokio::net::TcpListener::bind(&settings.api.listen_addr).await?;
+    info!("Listening on {}", settings.api.listen_addr);
+
+    let schemas = Arc::new(SchemaRegistry::new(&settings.schema_registry));
+    if let Some(document) = settings.schema_registry.document.clone() {
+        let store = db::elastic::ElasticStore::new(&settings.storage.elasticsearch)
+            .await
+            .expect("Failed to connect to Elasticsearch for payload schemas");
+        let schemas = schemas.clone();
+        tokio::spawn(async move { schemas.run(store, &document).await });
+    }
+
+    let recipients = payload_encryption::parse_recipients(&settings.payload_encryption.recipients)
+        .expect("Invalid PAYLOAD_RECIPIENTS");
+    let state = Arc::new(ApiState {
+        settings,
+        store,
+        schemas,
+        recipients,
+        broker: Mutex::new(None),
+    });
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/openapi.json", get(openapi))
+        .route("/api/v1/tasks", post(submit_task))
+        .route("/api/v1/batches", post(submit_batch))
+        .route("/api/v1/batches/{batch_id}/arrow", get(stream_results))
+        .route("/api/v1/schemas", get(list_schemas))
+        .route("/api/v1/schemas/{task_type}", get(get_schema));
+    #[cfg(feature = "swagger-ui")]
+    let app = app.merge(
+        utoipa_swagger_ui::SwaggerUi::new("/docs")
+            .config(utoipa_swagger_ui::Config::from("/openapi.json")),
+    );
+    let app = app.with_state(state);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
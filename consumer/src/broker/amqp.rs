@@ -0,0 +1,710 @@
+use super::{
+    defer_step, requeue_delay, BrokerMessage, BrokerResult, MessageBroker, MessageStream, Receipt,
+    ATTEMPT_HEADER, PARTITION_HEADER,
+};
+use crate::resolver::Resolver;
+use crate::settings::{AmqpSettings, AmqpTlsSettings, BrokerSettings, NetworkSettings};
+use async_trait::async_trait;
+use futures_lite::StreamExt;
+use lapin::{
+    options::*,
+    tcp::{OwnedIdentity, OwnedTLSConfig},
+    types::{AMQPValue, FieldTable, LongString, ShortString},
+    BasicProperties, Channel, Connection, ConnectionProperties,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info};
+
+pub struct AmqpBroker {
+    connection: Connection,
+    channel: Channel,
+    queue: String,
+    dead_letter_queue: Option<String>,
+    requeue_delays_ms: Vec<u64>,
+    /// Delays of the holding queues, sorted.
+    delay_steps_ms: Vec<u64>,
+    /// Queue of the tasks this replica owns under work partitioning.
+    partition_queue: Option<String>,
+    lease_duration_secs: u64,
+    max_priority: u8,
+    /// Partition queues already declared on this channel.
+    declared_partitions: Mutex<HashSet<String>>,
+    /// Whether consumption was already cancelled ahead of the shutdown.
+    cancelled: AtomicBool,
+}
+
+/// Arguments of queues holding tasks, matching the API's declaration of the task queue.
+/// Priorities must be enabled when a queue is first declared.
+fn task_queue_arguments(max_priority: u8) -> FieldTable {
+    let mut arguments = FieldTable::default();
+    arguments.insert(
+        ShortString::from("x-queue-type"),
+        AMQPValue::LongString(LongString::from("classic")),
+    );
+    if max_priority > 0 {
+        arguments.insert(
+            ShortString::from("x-max-priority"),
+            AMQPValue::ShortShortUInt(max_priority),
+        );
+    }
+    arguments
+}
+
+/// Queue receiving the tasks forwarded to partition `member`.
+fn partition_queue_name(queue: &str, member: &str) -> String {
+    format!("{}.partition.{}", queue, member)
+}
+
+fn partition_consumer_tag(consumer_tag: &str) -> String {
+    format!("{}-partition", consumer_tag)
+}
+
+/// Holding queue for requeued messages waiting `delay_ms`.
+fn delay_queue_name(queue: &str, delay_ms: u64) -> String {
+    format!("{}.delay.{}ms", queue, delay_ms)
+}
+
+/// Percent-encodes a userinfo or path component of an AMQP URI.
+fn uri_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// URI of the broker at `host`: `amqps://` when TLS is configured, with the vhost
+/// (`/` included) and credentials percent-encoded.
+pub fn amqp_uri(settings: &AmqpSettings, host: &str) -> String {
+    format!(
+        "{}://{}:{}@{}:{}/{}",
+        if settings.tls.is_some() {
+            "amqps"
+        } else {
+            "amqp"
+        },
+        uri_component(&settings.user),
+        uri_component(&settings.password),
+        host,
+        settings.port,
+        uri_component(&settings.vhost)
+    )
+}
+
+/// Client identity and trusted CA certificates of the TLS connection, read once from
+/// their files. `OwnedTLSConfig` is consumed by each connection attempt, so it is
+/// built again from these for every one.
+struct TlsMaterial {
+    identity: Option<(Vec<u8>, String)>,
+    cert_chain: Option<String>,
+}
+
+impl TlsMaterial {
+    fn read(settings: &AmqpTlsSettings) -> BrokerResult<Self> {
+        let read =
+            |path: &str| std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e));
+        let identity = match &settings.client_identity {
+            Some(path) => Some((
+                read(path)?,
+                settings
+                    .client_identity_password
+                    .clone()
+                    .unwrap_or_default(),
+            )),
+            None => None,
+        };
+        let cert_chain = match &settings.ca_cert {
+            Some(path) => Some(String::from_utf8(read(path)?)?),
+            None => None,
+        };
+        Ok(TlsMaterial {
+            identity,
+            cert_chain,
+        })
+    }
+
+    fn config(&self) -> OwnedTLSConfig {
+        OwnedTLSConfig {
+            identity: self.identity.as_ref().map(|(der, password)| OwnedIdentity {
+                der: der.clone(),
+                password: password.clone(),
+            }),
+            cert_chain: self.cert_chain.clone(),
+        }
+    }
+}
+
+/// Connects to RabbitMQ, retrying until the broker is reachable. Only unreadable TLS
+/// certificates fail.
+async fn establish_rabbitmq_connection(
+    settings: &BrokerSettings,
+    network: &NetworkSettings,
+) -> BrokerResult<Connection> {
+    let tls = settings
+        .amqp
+        .tls
+        .as_ref()
+        .map(TlsMaterial::read)
+        .transpose()?;
+    // lapin resolves through the system, so the host is resolved here when the
+    // network settings say otherwise.
+    let resolver = (!network.is_system_default())
+        .then(|| Resolver::new(network, 16, std::time::Duration::ZERO));
+    loop {
+        let host = match &resolver {
+            // The certificate is checked against the host name, which the resolved
+            // address would replace.
+            Some(resolver) if tls.is_none() => match resolver.uri_host(&settings.amqp.host).await {
+                Ok(host) => host,
+                Err(e) => {
+                    error!(
+                        "Failed to resolve RabbitMQ host {}: {}, retrying in 5s...",
+                        settings.amqp.host, e
+                    );
+                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    continue;
+                }
+            },
+            _ => settings.amqp.host.clone(),
+        };
+        let uri = amqp_uri(&settings.amqp, &host);
+
+        let connected = match &tls {
+            Some(tls) => {
+                Connection::connect_with_config(&uri, ConnectionProperties::default(), tls.config())
+                    .await
+            }
+            None => Connection::connect(&uri, ConnectionProperties::default()).await,
+        };
+        match connected {
+            Ok(conn) => {
+                info!("RabbitMQ connection established");
+                return Ok(conn);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to connect to RabbitMQ: {} with connection uri: {}, retrying in 5s...",
+                    e, uri
+                );
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+fn header_value(value: &AMQPValue) -> Option<String> {
+    match value {
+        AMQPValue::LongString(s) => Some(s.to_string()),
+        AMQPValue::ShortString(s) => Some(s.to_string()),
+        AMQPValue::Boolean(b) => Some(b.to_string()),
+        AMQPValue::ShortShortInt(i) => Some(i.to_string()),
+        AMQPValue::ShortShortUInt(i) => Some(i.to_string()),
+        AMQPValue::ShortInt(i) => Some(i.to_string()),
+        AMQPValue::ShortUInt(i) => Some(i.to_string()),
+        AMQPValue::LongInt(i) => Some(i.to_string()),
+        AMQPValue::LongUInt(i) => Some(i.to_string()),
+        AMQPValue::LongLongInt(i) => Some(i.to_string()),
+        AMQPValue::Timestamp(t) => Some(t.to_string()),
+        _ => None,
+    }
+}
+
+pub fn headers_to_map(headers: Option<&FieldTable>) -> BTreeMap<String, String> {
+    headers
+        .map(|table| {
+            table
+                .inner()
+                .iter()
+                .filter_map(|(k, v)| Some((k.to_string(), header_value(v)?)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn map_to_headers(headers: &BTreeMap<String, String>) -> FieldTable {
+    let mut table = FieldTable::default();
+    for (key, value) in headers {
+        table.insert(
+            ShortString::from(key.clone()),
+            AMQPValue::LongString(LongString::from(value.clone())),
+        );
+    }
+    table
+}
+
+impl AmqpBroker {
+    pub async fn connect(
+        settings: &BrokerSettings,
+        network: &NetworkSettings,
+    ) -> BrokerResult<Self> {
+        info!("Attempting to establish RabbitMQ connection...");
+        let connection = establish_rabbitmq_connection(settings, network).await?;
+        let channel = connection.create_channel().await?;
+        info!("RabbitMQ channel created successfully");
+
+        // Set QoS (prefetch)
+        channel
+            .basic_qos(settings.prefetch, BasicQosOptions::default())
+            .await?;
+
+        channel
+            .queue_declare(
+                &settings.queue,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                task_queue_arguments(settings.max_priority),
+            )
+            .await?;
+
+        if let Some(dead_letter_queue) = &settings.dead_letter_queue {
+            channel
+                .queue_declare(
+                    dead_letter_queue,
+                    QueueDeclareOptions {
+                        durable: true,
+                        ..QueueDeclareOptions::default()
+                    },
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+
+        // Each delay step gets its own holding queue without consumers: messages expire
+        // after the queue's TTL and are dead-lettered back to the task queue. A single
+        // queue with per-message TTLs would only expire messages at its head.
+        let deferral_delays_ms = match settings.defer_after_ms {
+            Some(_) => settings.defer_delays_ms.as_slice(),
+            None => &[],
+        };
+        let mut delay_steps_ms: Vec<u64> = settings
+            .requeue_delays_ms
+            .iter()
+            .chain(deferral_delays_ms)
+            .copied()
+            .filter(|&delay_ms| delay_ms > 0)
+            .collect();
+        delay_steps_ms.sort_unstable();
+        delay_steps_ms.dedup();
+        for &delay_ms in &delay_steps_ms {
+            let mut arguments = FieldTable::default();
+            arguments.insert(
+                ShortString::from("x-message-ttl"),
+                AMQPValue::LongLongInt(delay_ms as i64),
+            );
+            arguments.insert(
+                ShortString::from("x-dead-letter-exchange"),
+                AMQPValue::LongString(LongString::from("")),
+            );
+            arguments.insert(
+                ShortString::from("x-dead-letter-routing-key"),
+                AMQPValue::LongString(LongString::from(settings.queue.clone())),
+            );
+            channel
+                .queue_declare(
+                    &delay_queue_name(&settings.queue, delay_ms),
+                    QueueDeclareOptions {
+                        durable: true,
+                        ..QueueDeclareOptions::default()
+                    },
+                    arguments,
+                )
+                .await?;
+        }
+
+        let partition_queue = settings
+            .partitioning
+            .as_ref()
+            .map(|partitioning| partition_queue_name(&settings.queue, &partitioning.identity));
+
+        let broker = Self {
+            connection,
+            channel,
+            queue: settings.queue.clone(),
+            dead_letter_queue: settings.dead_letter_queue.clone(),
+            requeue_delays_ms: settings.requeue_delays_ms.clone(),
+            delay_steps_ms,
+            partition_queue,
+            lease_duration_secs: settings
+                .partitioning
+                .as_ref()
+                .map_or(0, |partitioning| partitioning.lease_duration_secs),
+            max_priority: settings.max_priority,
+            declared_partitions: Mutex::new(HashSet::new()),
+            cancelled: AtomicBool::new(false),
+        };
+        if let Some(partition_queue) = &broker.partition_queue {
+            broker.declare_partition_queue(partition_queue).await?;
+        }
+        Ok(broker)
+    }
+
+    // Messages left in a partition queue, e.g. because its replica went away, expire
+    // back into the task queue to be routed under the current membership (consuming
+    // them from the task queue drops their partition header), and the queue itself is
+    // deleted once unused for a while. Forwarding declares the queue
+    // too, as its replica may not have connected yet.
+    async fn declare_partition_queue(&self, name: &str) -> BrokerResult<()> {
+        if self.declared_partitions.lock().unwrap().contains(name) {
+            return Ok(());
+        }
+        let lease_ms = self.lease_duration_secs as i64 * 1000;
+        let mut arguments = task_queue_arguments(self.max_priority);
+        arguments.insert(
+            ShortString::from("x-message-ttl"),
+            AMQPValue::LongLongInt(lease_ms * 2),
+        );
+        arguments.insert(
+            ShortString::from("x-expires"),
+            AMQPValue::LongLongInt(lease_ms * 20),
+        );
+        arguments.insert(
+            ShortString::from("x-dead-letter-exchange"),
+            AMQPValue::LongString(LongString::from("")),
+        );
+        arguments.insert(
+            ShortString::from("x-dead-letter-routing-key"),
+            AMQPValue::LongString(LongString::from(self.queue.clone())),
+        );
+        self.channel
+            .queue_declare(
+                name,
+                QueueDeclareOptions {
+                    durable: true,
+                    ..QueueDeclareOptions::default()
+                },
+                arguments,
+            )
+            .await?;
+        self.declared_partitions
+            .lock()
+            .unwrap()
+            .insert(name.to_string());
+        Ok(())
+    }
+
+    pub fn channel(&self) -> &Channel {
+        &self.channel
+    }
+
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    pub fn dead_letter_queue(&self) -> Option<&str> {
+        self.dead_letter_queue.as_deref()
+    }
+
+    /// Messages ready in `queue` and consumers of it.
+    pub async fn queue_counts(&self, queue: &str) -> BrokerResult<(u32, u32)> {
+        let queue = self
+            .channel
+            .queue_declare(
+                queue,
+                QueueDeclareOptions {
+                    passive: true,
+                    ..QueueDeclareOptions::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        Ok((queue.message_count(), queue.consumer_count()))
+    }
+
+    /// Drops every message ready in `queue` and returns how many there were.
+    pub async fn purge(&self, queue: &str) -> BrokerResult<u32> {
+        Ok(self
+            .channel
+            .queue_purge(queue, QueuePurgeOptions::default())
+            .await?)
+    }
+
+    /// Walks the messages ready in `queue`. They're got one at a time, up to as many as
+    /// were ready when the walk started, and the caller settles each before getting the
+    /// next, so no more than one is held.
+    pub async fn walk(self: &Arc<Self>, queue: &str) -> BrokerResult<QueueWalk> {
+        let (remaining, _) = self.queue_counts(queue).await?;
+        Ok(QueueWalk {
+            broker: self.clone(),
+            queue: queue.to_string(),
+            remaining,
+        })
+    }
+
+    /// Publishes a persistent copy of `message` to `queue` through the default exchange,
+    /// keeping its priority, and waits for the broker to take it.
+    async fn publish(
+        &self,
+        queue: &str,
+        message: &BrokerMessage,
+        headers: &BTreeMap<String, String>,
+    ) -> BrokerResult<()> {
+        self.publish_data(queue, &message.data, message.priority, headers)
+            .await
+    }
+
+    async fn publish_data(
+        &self,
+        queue: &str,
+        data: &[u8],
+        priority: Option<u8>,
+        headers: &BTreeMap<String, String>,
+    ) -> BrokerResult<()> {
+        let mut properties = BasicProperties::default()
+            .with_delivery_mode(2)
+            .with_headers(map_to_headers(headers));
+        if let Some(priority) = priority {
+            properties = properties.with_priority(priority);
+        }
+        self.channel
+            .basic_publish("", queue, BasicPublishOptions::default(), data, properties)
+            .await?
+            .await?;
+        Ok(())
+    }
+
+    fn acker(message: &BrokerMessage) -> BrokerResult<&lapin::acker::Acker> {
+        match &message.receipt {
+            Receipt::Amqp(acker) => Ok(acker),
+            #[allow(unreachable_patterns)]
+            _ => Err("Message was not delivered by RabbitMQ".into()),
+        }
+    }
+}
+
+/// Messages of a queue got one at a time, see `AmqpBroker::walk`.
+pub struct QueueWalk {
+    broker: Arc<AmqpBroker>,
+    queue: String,
+    /// Messages left of those ready when the walk started.
+    remaining: u32,
+}
+
+impl QueueWalk {
+    pub async fn next(&mut self) -> BrokerResult<Option<BrokerMessage>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let message = self
+            .broker
+            .channel
+            .basic_get(&self.queue, BasicGetOptions::default())
+            .await?;
+        let Some(message) = message else {
+            self.remaining = 0;
+            return Ok(None);
+        };
+        let delivery = message.delivery;
+        Ok(Some(BrokerMessage::new(
+            delivery.data,
+            delivery.redelivered,
+            headers_to_map(delivery.properties.headers().as_ref()),
+            *delivery.properties.priority(),
+            Receipt::Amqp(delivery.acker),
+            self.broker.clone(),
+        )))
+    }
+
+    /// Publishes `message` to the end of the walked queue again at `priority` and acks
+    /// it. Handing it back with a reject instead would have the walk get it again, and
+    /// mark it redelivered as if a consumer had tried it. A message that was redelivered
+    /// keeps its attempt in the attempt header.
+    pub async fn put_back(
+        &self,
+        message: &BrokerMessage,
+        priority: Option<u8>,
+    ) -> BrokerResult<()> {
+        let mut headers = message.headers.clone();
+        let attempt = message.delivery_attempt();
+        if attempt > 1 {
+            headers.insert(ATTEMPT_HEADER.to_string(), attempt.to_string());
+        }
+        self.broker
+            .publish_data(&self.queue, &message.data, priority, &headers)
+            .await?;
+        message.ack().await
+    }
+}
+
+#[async_trait]
+impl MessageBroker for AmqpBroker {
+    async fn consume(self: Arc<Self>, consumer_tag: &str) -> BrokerResult<MessageStream> {
+        let consumer = self
+            .channel
+            .basic_consume(
+                &self.queue,
+                consumer_tag,
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+
+        let broker: Arc<dyn MessageBroker> = self.clone();
+        let to_message = move |forwarded: bool| {
+            let broker = broker.clone();
+            move |delivery: lapin::Result<lapin::message::Delivery>| {
+                let delivery = delivery?;
+                let mut headers = headers_to_map(delivery.properties.headers().as_ref());
+                // A forwarded task back in the task queue, expired from its partition
+                // queue or requeued, is routed under the current membership again.
+                if !forwarded {
+                    headers.remove(PARTITION_HEADER);
+                }
+                Ok(BrokerMessage::new(
+                    delivery.data,
+                    delivery.redelivered,
+                    headers,
+                    *delivery.properties.priority(),
+                    Receipt::Amqp(delivery.acker),
+                    broker.clone(),
+                ))
+            }
+        };
+
+        let Some(partition_queue) = &self.partition_queue else {
+            return Ok(Box::pin(consumer.map(to_message(false))));
+        };
+        let partition_consumer = self
+            .channel
+            .basic_consume(
+                partition_queue,
+                &partition_consumer_tag(consumer_tag),
+                BasicConsumeOptions::default(),
+                FieldTable::default(),
+            )
+            .await?;
+        // Forwarded tasks go first; they already waited in another replica's queue.
+        Ok(Box::pin(
+            partition_consumer
+                .map(to_message(true))
+                .or(consumer.map(to_message(false))),
+        ))
+    }
+
+    async fn ack(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        Ok(Self::acker(message)?
+            .ack(BasicAckOptions::default())
+            .await?)
+    }
+
+    async fn nack(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        Ok(Self::acker(message)?
+            .reject(BasicRejectOptions { requeue: false })
+            .await?)
+    }
+
+    // A plain reject-with-requeue doesn't count deliveries on classic queues, so the
+    // message is re-published with its attempt number and the original acked. Delayed
+    // messages go through the holding queue of their delay step.
+    async fn requeue(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        let attempt = message.delivery_attempt();
+        let mut headers = message.headers.clone();
+        headers.insert(ATTEMPT_HEADER.to_string(), (attempt + 1).to_string());
+        let queue = match requeue_delay(&self.requeue_delays_ms, attempt) {
+            Some(delay) => delay_queue_name(&self.queue, delay.as_millis() as u64),
+            None => self.queue.clone(),
+        };
+        self.publish(&queue, message, &headers).await?;
+        self.ack(message).await
+    }
+
+    async fn dead_letter(&self, message: &BrokerMessage, reason: &str) -> BrokerResult<()> {
+        match &self.dead_letter_queue {
+            Some(dead_letter_queue) => {
+                let headers = message.failure_headers(&self.queue, reason);
+                self.publish(dead_letter_queue, message, &headers).await?;
+                self.ack(message).await
+            }
+            None => self.nack(message).await,
+        }
+    }
+
+    // Priorities above the queue's maximum are treated as the maximum by RabbitMQ.
+    async fn publish_task(
+        &self,
+        data: &[u8],
+        priority: Option<u8>,
+        headers: &BTreeMap<String, String>,
+    ) -> BrokerResult<()> {
+        let priority = priority.filter(|_| self.max_priority > 0);
+        self.publish_data(&self.queue, data, priority, headers)
+            .await
+    }
+
+    async fn forward(&self, message: &BrokerMessage, member: &str) -> BrokerResult<()> {
+        let queue = partition_queue_name(&self.queue, member);
+        self.declare_partition_queue(&queue).await?;
+        let mut headers = message.headers.clone();
+        headers.insert(PARTITION_HEADER.to_string(), member.to_string());
+        self.publish(&queue, message, &headers).await?;
+        self.ack(message).await
+    }
+
+    async fn cancel(&self, consumer_tag: &str) -> BrokerResult<()> {
+        if self.cancelled.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.channel
+            .basic_cancel(consumer_tag, BasicCancelOptions::default())
+            .await?;
+        if self.partition_queue.is_some() {
+            self.channel
+                .basic_cancel(
+                    &partition_consumer_tag(consumer_tag),
+                    BasicCancelOptions::default(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Re-published rather than rejected: RabbitMQ flags a requeued reject as
+    // redelivered, which would count as a failed attempt. The copy goes to the back of
+    // the queue.
+    async fn release(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        self.publish(&self.queue, message, &message.release_headers())
+            .await?;
+        self.ack(message).await
+    }
+
+    // Deferring isn't a failed attempt, so the attempt header is kept as it is.
+    async fn defer(&self, message: &BrokerMessage, delay: Duration) -> BrokerResult<()> {
+        let queue = match defer_step(&self.delay_steps_ms, delay) {
+            Some(delay_ms) => delay_queue_name(&self.queue, delay_ms),
+            None => self.queue.clone(),
+        };
+        self.publish(&queue, message, &message.headers).await?;
+        self.ack(message).await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.status().connected() && self.channel.status().connected()
+    }
+
+    async fn shutdown(&self, consumer_tag: &str) -> BrokerResult<()> {
+        self.cancel(consumer_tag).await?;
+        // Delivery tag 0 with `multiple` covers every delivery still unacked on the channel.
+        self.channel
+            .basic_nack(
+                0,
+                BasicNackOptions {
+                    multiple: true,
+                    requeue: true,
+                },
+            )
+            .await?;
+        self.channel.close(200, "Consumer shutting down").await?;
+        self.connection.close(200, "Consumer shutting down").await?;
+        info!("RabbitMQ channel and connection closed");
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+//! Kafka backend, built with the `kafka` feature.
+//!
+//! Kafka has no per-message acknowledgement, so settling a message stores its offset
+//! for the next auto-commit. Offsets are only advanced past messages that are fully
+//! settled, keeping at-least-once delivery when tasks finish out of order. Requeued and
+//! dead-lettered messages are re-published to the end of the task or dead-letter topic
+//! before being settled.
+
+use super::{
+    requeue_delay, BrokerMessage, BrokerResult, MessageBroker, MessageStream, Receipt,
+    ATTEMPT_HEADER,
+};
+use crate::resolver::Resolver;
+use crate::settings::{BrokerSettings, IpFamily, NetworkSettings};
+use async_trait::async_trait;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use rdkafka::ClientConfig;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Offsets of a partition's messages still in flight, and the offset to commit for it.
+#[derive(Default)]
+pub struct PartitionOffsets {
+    in_flight: BTreeSet<i64>,
+    highest_settled: Option<i64>,
+}
+
+impl PartitionOffsets {
+    pub fn track(&mut self, offset: i64) {
+        self.in_flight.insert(offset);
+    }
+
+    /// Marks an offset as settled and returns the offset to commit: that of the next
+    /// message to read, which is the lowest one still in flight, or the one after the
+    /// highest settled when none is.
+    pub fn settle(&mut self, offset: i64) -> i64 {
+        self.in_flight.remove(&offset);
+        let highest_settled = self.highest_settled.map_or(offset, |o| o.max(offset));
+        self.highest_settled = Some(highest_settled);
+        match self.in_flight.first() {
+            Some(lowest) => *lowest,
+            None => highest_settled + 1,
+        }
+    }
+}
+
+/// `host:port` bootstrap list with the hosts resolved as `network` says. librdkafka
+/// resolves the listeners advertised by the brokers itself, through the system
+/// resolver, so those must still resolve there (or be advertised as addresses).
+async fn bootstrap_servers(brokers: &str, network: &NetworkSettings) -> BrokerResult<String> {
+    if network.dns_servers.is_empty() && network.host_overrides.is_empty() {
+        return Ok(brokers.to_string());
+    }
+    let resolver = Resolver::new(network, 16, Duration::ZERO);
+    let mut resolved = Vec::new();
+    for server in brokers.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (host, port) = server.rsplit_once(':').unwrap_or((server, "9092"));
+        resolved.push(format!("{}:{}", resolver.uri_host(host).await?, port));
+    }
+    Ok(resolved.join(","))
+}
+
+pub struct KafkaBroker {
+    consumer: Arc<StreamConsumer>,
+    producer: FutureProducer,
+    topic: String,
+    dead_letter_topic: Option<String>,
+    requeue_delays_ms: Vec<u64>,
+    prefetch: usize,
+    offsets: Mutex<HashMap<(String, i32), PartitionOffsets>>,
+}
+
+impl KafkaBroker {
+    pub async fn connect(
+        settings: &BrokerSettings,
+        network: &NetworkSettings,
+    ) -> BrokerResult<Self> {
+        let brokers = bootstrap_servers(&settings.kafka.brokers, network).await?;
+        let address_family = match network.ip_family {
+            IpFamily::Any => "any",
+            IpFamily::V4 => "v4",
+            IpFamily::V6 => "v6",
+        };
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &brokers)
+            .set("broker.address.family", address_family)
+            .set("group.id", &settings.kafka.group_id)
+            .set("enable.auto.commit", "true")
+            .set("enable.auto.offset.store", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()?;
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", &brokers)
+            .set("broker.address.family", address_family)
+            .create()?;
+
+        Ok(Self {
+            consumer: Arc::new(consumer),
+            producer,
+            topic: settings.queue.clone(),
+            dead_letter_topic: settings.dead_letter_queue.clone(),
+            requeue_delays_ms: settings.requeue_delays_ms.clone(),
+            prefetch: settings.prefetch.max(1) as usize,
+            offsets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    async fn publish(
+        &self,
+        topic: &str,
+        data: &[u8],
+        headers: &BTreeMap<String, String>,
+    ) -> BrokerResult<()> {
+        let mut owned = OwnedHeaders::new();
+        for (key, value) in headers {
+            owned = owned.insert(Header {
+                key,
+                value: Some(value.as_bytes()),
+            });
+        }
+        self.producer
+            .send(
+                FutureRecord::<(), [u8]>::to(topic)
+                    .payload(data)
+                    .headers(owned),
+                Duration::from_secs(30),
+            )
+            .await
+            .map_err(|(e, _)| e)?;
+        Ok(())
+    }
+
+    fn track(&self, topic: &str, partition: i32, offset: i64) {
+        self.offsets
+            .lock()
+            .unwrap()
+            .entry((topic.to_string(), partition))
+            .or_default()
+            .track(offset);
+    }
+
+    /// Marks an offset as settled and stores the offset below which every message of
+    /// the partition is settled.
+    fn settle(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        let Receipt::Kafka {
+            topic,
+            partition,
+            offset,
+        } = &message.receipt
+        else {
+            return Err("Message was not delivered by Kafka".into());
+        };
+
+        let committable = self
+            .offsets
+            .lock()
+            .unwrap()
+            .entry((topic.clone(), *partition))
+            .or_default()
+            .settle(*offset);
+        self.consumer.store_offset(topic, *partition, committable)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MessageBroker for KafkaBroker {
+    async fn consume(self: Arc<Self>, _consumer_tag: &str) -> BrokerResult<MessageStream> {
+        self.consumer.subscribe(&[&self.topic])?;
+
+        // BorrowedMessage can't outlive the consumer borrow, so messages are copied
+        // out by a reader task; the bounded channel plays the role of the prefetch.
+        let (sender, receiver) = mpsc::channel(self.prefetch);
+        let broker = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let result = match broker.consumer.recv().await {
+                    Ok(m) => {
+                        let headers: BTreeMap<String, String> = m
+                            .headers()
+                            .map(|h| {
+                                h.iter()
+                                    .filter_map(|header| {
+                                        Some((
+                                            header.key.to_string(),
+                                            String::from_utf8_lossy(header.value?).into_owned(),
+                                        ))
+                                    })
+                                    .collect()
+                            })
+                            .unwrap_or_default();
+                        broker.track(m.topic(), m.partition(), m.offset());
+                        Ok(BrokerMessage::new(
+                            m.payload().map(<[u8]>::to_vec).unwrap_or_default(),
+                            headers.contains_key(ATTEMPT_HEADER),
+                            headers,
+                            None,
+                            Receipt::Kafka {
+                                topic: m.topic().to_string(),
+                                partition: m.partition(),
+                                offset: m.offset(),
+                            },
+                            broker.clone(),
+                        ))
+                    }
+                    Err(e) => Err(Box::new(e) as Box<dyn std::error::Error + Send + Sync>),
+                };
+                if sender.send(result).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Box::pin(futures_lite::stream::unfold(
+            receiver,
+            |mut receiver| async move { receiver.recv().await.map(|m| (m, receiver)) },
+        )))
+    }
+
+    async fn ack(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        self.settle(message)
+    }
+
+    async fn nack(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        self.settle(message)
+    }
+
+    // Kafka has no per-message delay, so the message is held before it is re-published.
+    // Its offset stays unsettled meanwhile, which blocks no other message of the
+    // partition since offsets are tracked per message.
+    async fn requeue(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        let attempt = message.delivery_attempt();
+        if let Some(delay) = requeue_delay(&self.requeue_delays_ms, attempt) {
+            tokio::time::sleep(delay).await;
+        }
+        let mut headers = message.headers.clone();
+        headers.insert(ATTEMPT_HEADER.to_string(), (attempt + 1).to_string());
+        self.publish(&self.topic, &message.data, &headers).await?;
+        self.settle(message)
+    }
+
+    async fn dead_letter(&self, message: &BrokerMessage, reason: &str) -> BrokerResult<()> {
+        if let Some(dead_letter_topic) = &self.dead_letter_topic {
+            let headers = message.failure_headers(&self.topic, reason);
+            self.publish(dead_letter_topic, &message.data, &headers)
+                .await?;
+        }
+        self.settle(message)
+    }
+
+    // Kafka topics have no priorities.
+    async fn publish_task(
+        &self,
+        data: &[u8],
+        _priority: Option<u8>,
+        headers: &BTreeMap<String, String>,
+    ) -> BrokerResult<()> {
+        self.publish(&self.topic, data, headers).await
+    }
+
+    // Kafka already partitions a topic between the members of a consumer group.
+    async fn forward(&self, _message: &BrokerMessage, _member: &str) -> BrokerResult<()> {
+        Err("Work partitioning is not supported with Kafka".into())
+    }
+
+    // Unsubscribing would revoke the partitions of the tasks still in flight, so the
+    // reader keeps running until shutdown; the consumer just stops taking messages.
+    async fn cancel(&self, _consumer_tag: &str) -> BrokerResult<()> {
+        Ok(())
+    }
+
+    // The offset stays unsettled, so the message is redelivered to the member of the
+    // group that takes over the partition.
+    async fn release(&self, _message: &BrokerMessage) -> BrokerResult<()> {
+        Ok(())
+    }
+
+    // Topics have no holding queues: the delay is waited out before re-publishing, as
+    // requeues are, so deferral is only enabled on RabbitMQ.
+    async fn defer(&self, message: &BrokerMessage, delay: Duration) -> BrokerResult<()> {
+        tokio::time::sleep(delay).await;
+        self.publish(&self.topic, &message.data, &message.headers)
+            .await?;
+        self.settle(message)
+    }
+
+    // librdkafka reconnects to brokers on its own; only a fatal error (e.g. a fenced
+    // producer or an unrecoverable consumer state) needs a fresh client.
+    fn is_connected(&self) -> bool {
+        self.consumer.client().fatal_error().is_none()
+    }
+
+    // Unsettled offsets were never stored, so those messages are redelivered to the
+    // next member of the group; only the settled progress needs committing.
+    async fn shutdown(&self, _consumer_tag: &str) -> BrokerResult<()> {
+        if let Err(e) = self.consumer.commit_consumer_state(CommitMode::Sync) {
+            tracing::warn!("Failed to commit offsets on shutdown: {}", e);
+        }
+        self.consumer.unsubscribe();
+        self.producer.flush(Duration::from_secs(10))?;
+        Ok(())
+    }
+}
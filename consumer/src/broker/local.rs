@@ -0,0 +1,203 @@
+//! In-process broker of standalone mode, see `standalone`.
+//!
+//! Tasks sent to it are delivered in order, those handed back (requeued, deferred or
+//! released) ahead of new ones. Like Kafka, it has no per-message delay, so requeues
+//! and deferrals wait theirs out before handing the message back. Its stream ends once
+//! the input is closed and every delivery is settled, so a standalone run stops when
+//! its last task finishes. Tasks have no priorities, and dead-lettered ones are dropped:
+//! their failure is on their event.
+
+use super::ATTEMPT_HEADER;
+use super::{requeue_delay, BrokerMessage, BrokerResult, MessageBroker, MessageStream, Receipt};
+use async_trait::async_trait;
+use futures_lite::StreamExt;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+
+/// Data and headers of a message handed back.
+type HandedBack = (Vec<u8>, BTreeMap<String, String>);
+
+pub struct LocalBroker {
+    input: Mutex<Option<mpsc::Receiver<Vec<u8>>>>,
+    handed_back: Mutex<VecDeque<HandedBack>>,
+    unsettled: AtomicUsize,
+    cancelled: AtomicBool,
+    /// Woken when a message is handed back or settled, or consumption is cancelled.
+    changed: Notify,
+    requeue_delays_ms: Vec<u64>,
+}
+
+impl LocalBroker {
+    /// Broker delivering the task messages sent on the returned sender, holding up to
+    /// `capacity` of them until they're delivered. Dropping the sender closes the input.
+    pub fn new(capacity: usize, requeue_delays_ms: Vec<u64>) -> (Self, mpsc::Sender<Vec<u8>>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let broker = Self {
+            input: Mutex::new(Some(receiver)),
+            handed_back: Mutex::new(VecDeque::new()),
+            unsettled: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false),
+            changed: Notify::new(),
+            requeue_delays_ms,
+        };
+        (broker, sender)
+    }
+
+    /// Deliveries not settled yet.
+    pub fn unsettled(&self) -> usize {
+        self.unsettled.load(Ordering::SeqCst)
+    }
+
+    fn hand_back(&self, data: Vec<u8>, headers: BTreeMap<String, String>) {
+        self.handed_back.lock().unwrap().push_back((data, headers));
+        self.changed.notify_one();
+    }
+
+    fn settle(&self) {
+        self.unsettled.fetch_sub(1, Ordering::SeqCst);
+        self.changed.notify_one();
+    }
+
+    fn deliver(
+        self: &Arc<Self>,
+        data: Vec<u8>,
+        headers: BTreeMap<String, String>,
+    ) -> BrokerMessage {
+        self.unsettled.fetch_add(1, Ordering::SeqCst);
+        BrokerMessage::new(
+            data,
+            headers.contains_key(ATTEMPT_HEADER),
+            headers,
+            None,
+            Receipt::Local,
+            self.clone(),
+        )
+    }
+
+    /// Next message to deliver, or `None` once there's nothing left to wait for.
+    async fn next(
+        self: &Arc<Self>,
+        input: &mut Option<mpsc::Receiver<Vec<u8>>>,
+    ) -> Option<BrokerMessage> {
+        loop {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return None;
+            }
+            let handed_back = self.handed_back.lock().unwrap().pop_front();
+            if let Some((data, headers)) = handed_back {
+                return Some(self.deliver(data, headers));
+            }
+            let Some(receiver) = input.as_mut() else {
+                // Settling a delivery may hand it back, so the stream waits for all of
+                // them.
+                if self.unsettled() == 0 {
+                    return None;
+                }
+                self.changed.notified().await;
+                continue;
+            };
+            tokio::select! {
+                data = receiver.recv() => match data {
+                    Some(data) => return Some(self.deliver(data, BTreeMap::new())),
+                    None => *input = None,
+                },
+                _ = self.changed.notified() => {}
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBroker for LocalBroker {
+    async fn consume(self: Arc<Self>, _consumer_tag: &str) -> BrokerResult<MessageStream> {
+        let input = self
+            .input
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or("Standalone tasks can only be consumed once")?;
+        // Fused, as the consumer may still poll the stream on shutdown after it ended.
+        let stream =
+            futures_lite::stream::unfold((self, Some(input)), |(broker, mut input)| async move {
+                let message = broker.next(&mut input).await?;
+                Some((Ok(message), (broker, input)))
+            });
+        Ok(Box::pin(stream.fuse()))
+    }
+
+    async fn ack(&self, _message: &BrokerMessage) -> BrokerResult<()> {
+        self.settle();
+        Ok(())
+    }
+
+    async fn nack(&self, _message: &BrokerMessage) -> BrokerResult<()> {
+        self.settle();
+        Ok(())
+    }
+
+    async fn requeue(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        let attempt = message.delivery_attempt();
+        if let Some(delay) = requeue_delay(&self.requeue_delays_ms, attempt) {
+            tokio::time::sleep(delay).await;
+        }
+        let mut headers = message.headers.clone();
+        headers.insert(ATTEMPT_HEADER.to_string(), (attempt + 1).to_string());
+        self.hand_back(message.data.clone(), headers);
+        self.settle();
+        Ok(())
+    }
+
+    async fn dead_letter(&self, _message: &BrokerMessage, _reason: &str) -> BrokerResult<()> {
+        self.settle();
+        Ok(())
+    }
+
+    async fn publish_task(
+        &self,
+        data: &[u8],
+        _priority: Option<u8>,
+        headers: &BTreeMap<String, String>,
+    ) -> BrokerResult<()> {
+        self.hand_back(data.to_vec(), headers.clone());
+        Ok(())
+    }
+
+    async fn forward(&self, _message: &BrokerMessage, _member: &str) -> BrokerResult<()> {
+        Err("Work partitioning is not supported in standalone mode".into())
+    }
+
+    // Tasks not delivered yet are left out of the run.
+    async fn cancel(&self, _consumer_tag: &str) -> BrokerResult<()> {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    async fn release(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        self.hand_back(message.data.clone(), message.headers.clone());
+        self.settle();
+        Ok(())
+    }
+
+    async fn defer(&self, message: &BrokerMessage, delay: Duration) -> BrokerResult<()> {
+        tokio::time::sleep(delay).await;
+        self.hand_back(message.data.clone(), message.headers.clone());
+        self.settle();
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    async fn shutdown(&self, _consumer_tag: &str) -> BrokerResult<()> {
+        let left = self.unsettled() + self.handed_back.lock().unwrap().len();
+        if left > 0 {
+            tracing::warn!("Stopped with {} standalone tasks unfinished", left);
+        }
+        Ok(())
+    }
+}
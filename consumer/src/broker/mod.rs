@@ -0,0 +1,229 @@
+//! Message broker abstraction, so the same worker can consume from RabbitMQ or Kafka,
+//! or from tasks read in-process in standalone mode.
+
+pub mod amqp;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod local;
+
+use crate::settings::{BrokerKind, BrokerSettings, NetworkSettings};
+use async_trait::async_trait;
+use futures_lite::Stream;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Header carrying the delivery attempt of a re-published message.
+pub const ATTEMPT_HEADER: &str = "x-delivery-attempt";
+
+/// Header naming the replica a message was forwarded to by work partitioning.
+pub const PARTITION_HEADER: &str = "x-partition-member";
+
+/// Delay before redelivering a message whose `attempt` failed, from the escalating
+/// `REQUEUE_DELAYS_MS` steps.
+pub fn requeue_delay(delays_ms: &[u64], attempt: u32) -> Option<Duration> {
+    let index = (attempt.max(1) as usize - 1).min(delays_ms.len().checked_sub(1)?);
+    Some(Duration::from_millis(delays_ms[index])).filter(|delay| !delay.is_zero())
+}
+
+/// Delay step of the holding queue a message deferred for `delay` goes through: the
+/// shortest of `steps_ms` covering it, or else the longest. `steps_ms` is sorted.
+pub fn defer_step(steps_ms: &[u64], delay: Duration) -> Option<u64> {
+    let delay_ms = delay.as_millis() as u64;
+    steps_ms
+        .iter()
+        .copied()
+        .find(|&step| step >= delay_ms)
+        .or_else(|| steps_ms.last().copied())
+}
+
+pub type BrokerResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub type MessageStream = Pin<Box<dyn Stream<Item = BrokerResult<BrokerMessage>> + Send>>;
+
+/// Backend-specific handle needed to settle a message.
+pub enum Receipt {
+    Amqp(lapin::acker::Acker),
+    #[cfg(feature = "kafka")]
+    Kafka {
+        topic: String,
+        partition: i32,
+        offset: i64,
+    },
+    /// Delivered by the in-process broker of standalone mode.
+    Local,
+}
+
+/// A task message, independent of the broker it came from.
+pub struct BrokerMessage {
+    pub data: Vec<u8>,
+    /// Whether the broker already delivered this message before.
+    pub redelivered: bool,
+    pub headers: BTreeMap<String, String>,
+    /// Queue priority the message was published with, kept when it is re-published.
+    pub priority: Option<u8>,
+    pub receipt: Receipt,
+    broker: Arc<dyn MessageBroker>,
+}
+
+impl BrokerMessage {
+    pub fn new(
+        data: Vec<u8>,
+        redelivered: bool,
+        headers: BTreeMap<String, String>,
+        priority: Option<u8>,
+        receipt: Receipt,
+        broker: Arc<dyn MessageBroker>,
+    ) -> Self {
+        Self {
+            data,
+            redelivered,
+            headers,
+            priority,
+            receipt,
+            broker,
+        }
+    }
+
+    /// 1-based delivery attempt, from our own re-publish header, RabbitMQ quorum
+    /// queues' `x-delivery-count`, or the redelivered flag, whichever is highest.
+    pub fn delivery_attempt(&self) -> u32 {
+        let published = self
+            .headers
+            .get(ATTEMPT_HEADER)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        let quorum = self
+            .headers
+            .get("x-delivery-count")
+            .and_then(|v| v.parse::<u32>().ok())
+            .map_or(1, |count| count + 1);
+        let redelivered = if self.redelivered { 2 } else { 1 };
+        published.max(quorum).max(redelivered)
+    }
+
+    /// Headers of a copy of this message published back to its queue without counting
+    /// a delivery attempt. The copy isn't flagged as redelivered, so the attempt it is
+    /// on goes in the attempt header.
+    pub fn release_headers(&self) -> BTreeMap<String, String> {
+        let mut headers = self.headers.clone();
+        headers.insert(
+            ATTEMPT_HEADER.to_string(),
+            self.delivery_attempt().to_string(),
+        );
+        headers
+    }
+
+    /// Headers attached to a dead-lettered copy of this message.
+    pub fn failure_headers(&self, queue: &str, reason: &str) -> BTreeMap<String, String> {
+        let mut headers = self.headers.clone();
+        headers.insert(
+            ATTEMPT_HEADER.to_string(),
+            self.delivery_attempt().to_string(),
+        );
+        headers.insert("x-failure-reason".to_string(), reason.to_string());
+        headers.insert("x-failed-at".to_string(), chrono::Utc::now().to_rfc3339());
+        headers.insert("x-original-queue".to_string(), queue.to_string());
+        headers
+    }
+
+    pub async fn ack(&self) -> BrokerResult<()> {
+        self.broker.ack(self).await
+    }
+
+    pub async fn nack(&self) -> BrokerResult<()> {
+        self.broker.nack(self).await
+    }
+
+    pub async fn requeue(&self) -> BrokerResult<()> {
+        self.broker.requeue(self).await
+    }
+
+    pub async fn dead_letter(&self, reason: &str) -> BrokerResult<()> {
+        self.broker.dead_letter(self, reason).await
+    }
+
+    pub async fn forward(&self, member: &str) -> BrokerResult<()> {
+        self.broker.forward(self, member).await
+    }
+
+    pub async fn release(&self) -> BrokerResult<()> {
+        self.broker.release(self).await
+    }
+
+    pub async fn defer(&self, delay: Duration) -> BrokerResult<()> {
+        self.broker.defer(self, delay).await
+    }
+}
+
+#[async_trait]
+pub trait MessageBroker: Send + Sync {
+    /// Starts consuming the task queue/topic.
+    async fn consume(self: Arc<Self>, consumer_tag: &str) -> BrokerResult<MessageStream>;
+
+    /// Marks the message as processed.
+    async fn ack(&self, message: &BrokerMessage) -> BrokerResult<()>;
+
+    /// Drops the message without redelivery.
+    async fn nack(&self, message: &BrokerMessage) -> BrokerResult<()>;
+
+    /// Hands the message back to the broker for redelivery, counting the attempt and
+    /// holding it back for the attempt's requeue delay.
+    async fn requeue(&self, message: &BrokerMessage) -> BrokerResult<()>;
+
+    /// Moves the message to the dead-letter queue with failure metadata, or drops it
+    /// when no dead-letter queue is configured.
+    async fn dead_letter(&self, message: &BrokerMessage, reason: &str) -> BrokerResult<()>;
+
+    /// Publishes a new task message to the task queue/topic, with `headers` such as the
+    /// trace context of the submission.
+    async fn publish_task(
+        &self,
+        data: &[u8],
+        priority: Option<u8>,
+        headers: &BTreeMap<String, String>,
+    ) -> BrokerResult<()>;
+
+    /// Hands the message over to the partition of another replica.
+    async fn forward(&self, message: &BrokerMessage, member: &str) -> BrokerResult<()>;
+
+    /// Stops new deliveries to `consumer_tag`. Messages delivered before can still be
+    /// settled, and the stream ends once it has yielded them.
+    async fn cancel(&self, consumer_tag: &str) -> BrokerResult<()>;
+
+    /// Hands a message no task started on back to the broker as it is, without counting
+    /// a delivery attempt, so another consumer can take it right away.
+    async fn release(&self, message: &BrokerMessage) -> BrokerResult<()>;
+
+    /// Hands the message back to be delivered again after about `delay`, without
+    /// counting a delivery attempt, e.g. once a rate limit has passed.
+    async fn defer(&self, message: &BrokerMessage, delay: Duration) -> BrokerResult<()>;
+
+    /// Whether the underlying connection is still usable. A stream error while this
+    /// holds concerns a single delivery and can be skipped; otherwise the consumer has
+    /// to reconnect.
+    fn is_connected(&self) -> bool;
+
+    /// Stops consuming, hands every unsettled message back for redelivery and closes
+    /// the connection.
+    async fn shutdown(&self, consumer_tag: &str) -> BrokerResult<()>;
+}
+
+/// Connects to the broker selected by `BROKER_KIND`.
+pub async fn connect(
+    settings: &BrokerSettings,
+    network: &NetworkSettings,
+) -> BrokerResult<Arc<dyn MessageBroker>> {
+    match settings.kind {
+        BrokerKind::RabbitMq => Ok(Arc::new(
+            amqp::AmqpBroker::connect(settings, network).await?,
+        )),
+        #[cfg(feature = "kafka")]
+        BrokerKind::Kafka => Ok(Arc::new(
+            kafka::KafkaBroker::connect(settings, network).await?,
+        )),
+        #[cfg(not(feature = "kafka"))]
+        BrokerKind::Kafka => Err("Kafka support requires building with the `kafka` feature".into()),
+    }
+}
@@ -0,0 +1,179 @@
+//! Keys tasks are cached and deduplicated on.
+//!
+//! Producers send a `body_hash` with each task: base64 of the SHA-256 of the canonical
+//! JSON of what its completion is generated from, see `producer::body_hash`. By
+//! default the consumer trusts it (`CACHE_KEY_MODE=trust`). With `verify` it
+//! recomputes the key and dead-letters tasks whose hash doesn't match, so a buggy or
+//! hostile producer can't serve one task's completion to another; with `replace` it
+//! ignores the producer's hash and keys tasks on its own.
+//!
+//! `CACHE_KEY_EXCLUDED_FIELDS` (e.g. `user,metadata`) leaves fields of the body, or of
+//! the variables of a templated task, out of the key, so tasks differing only in them
+//! share cached completions. Under `verify`, producers have to leave them out too.
+//!
+//! With `CACHE_KEY_SEMANTIC_TEMPLATES=true`, templated tasks are keyed on the
+//! `semantic_hash` of their template and their canonical variables rather than on the
+//! template as sent, so reformatting a template doesn't invalidate its cached
+//! completions while editing it does, named templates included. Producers can't
+//! compute that key, so it takes `replace`.
+//!
+//! Recomputed keys are hashed with `HASH_ALGORITHM`, see `hashing`; producers only
+//! compute SHA-256 keys, so BLAKE3 takes `replace` too.
+
+use crate::producer;
+use crate::settings::{CacheKeyMode, CacheKeySettings, HashAlgorithm};
+use crate::templates::PromptTemplates;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Computes the key of a task from its payload.
+pub trait CacheKeyStrategy: Send + Sync {
+    fn key(&self, payload: &Value) -> String;
+}
+
+/// What a task's completion is generated from: its body, or its template and
+/// variables, along with its conversation plan if any. Producers hash this.
+pub fn hashed_value(payload: &Value) -> Value {
+    let hashed = if payload["template"].is_null() {
+        payload["body"].clone()
+    } else {
+        json!({ "template": payload["template"], "variables": payload["variables"] })
+    };
+    // Conversations with other plans give other transcripts
+    if payload["conversation"].is_null() {
+        hashed
+    } else {
+        json!({ "body": hashed, "conversation": payload["conversation"] })
+    }
+}
+
+/// The producers' hash, optionally without some fields of the body or variables.
+pub struct CanonicalHash {
+    excluded_fields: Vec<String>,
+    algorithm: HashAlgorithm,
+}
+
+impl CanonicalHash {
+    pub fn new(excluded_fields: Vec<String>) -> Self {
+        Self::with_algorithm(excluded_fields, HashAlgorithm::Sha256)
+    }
+
+    pub fn with_algorithm(excluded_fields: Vec<String>, algorithm: HashAlgorithm) -> Self {
+        Self {
+            excluded_fields,
+            algorithm,
+        }
+    }
+}
+
+impl CacheKeyStrategy for CanonicalHash {
+    fn key(&self, payload: &Value) -> String {
+        if self.excluded_fields.is_empty() {
+            return producer::body_hash_with(self.algorithm, &hashed_value(payload));
+        }
+        let mut payload = payload.clone();
+        for field in ["body", "variables"] {
+            if let Some(fields) = payload[field].as_object_mut() {
+                fields.retain(|name, _| !self.excluded_fields.contains(name));
+            }
+        }
+        producer::body_hash_with(self.algorithm, &hashed_value(&payload))
+    }
+}
+
+/// `CanonicalHash` with the templates of templated tasks replaced by their
+/// `semantic_hash`.
+pub struct SemanticTemplateKey {
+    canonical: CanonicalHash,
+    templates: Arc<PromptTemplates>,
+}
+
+impl SemanticTemplateKey {
+    pub fn new(canonical: CanonicalHash, templates: Arc<PromptTemplates>) -> Self {
+        Self {
+            canonical,
+            templates,
+        }
+    }
+}
+
+impl CacheKeyStrategy for SemanticTemplateKey {
+    fn key(&self, payload: &Value) -> String {
+        // Tasks whose template doesn't resolve fail to render; any key does.
+        let Some(semantic_hash) = payload
+            .get("template")
+            .filter(|template| !template.is_null())
+            .and_then(|template| self.templates.semantic_hash(template).ok())
+        else {
+            return self.canonical.key(payload);
+        };
+        let mut payload = payload.clone();
+        payload["template"] = json!({ "semantic_hash": semantic_hash });
+        self.canonical.key(&payload)
+    }
+}
+
+/// `CanonicalHash` of the settings. Keys verified against the producers' stay SHA-256.
+fn canonical_hash(settings: &CacheKeySettings) -> CanonicalHash {
+    let algorithm = match settings.mode {
+        CacheKeyMode::Replace => settings.hash,
+        _ => HashAlgorithm::Sha256,
+    };
+    CanonicalHash::with_algorithm(settings.excluded_fields.clone(), algorithm)
+}
+
+/// Resolves the key of each delivered task according to `CACHE_KEY_MODE`.
+pub struct CacheKeys {
+    mode: CacheKeyMode,
+    strategy: Box<dyn CacheKeyStrategy>,
+}
+
+impl CacheKeys {
+    pub fn new(settings: &CacheKeySettings) -> Self {
+        if settings.hash != HashAlgorithm::Sha256 && settings.mode != CacheKeyMode::Replace {
+            tracing::warn!("HASH_ALGORITHM only applies to task keys with CACHE_KEY_MODE=replace");
+        }
+        Self::with_strategy(settings.mode, Box::new(canonical_hash(settings)))
+    }
+
+    /// Like `new`, with templated tasks keyed on the semantic hash of their template
+    /// under `semantic_templates`.
+    pub fn with_templates(settings: &CacheKeySettings, templates: Arc<PromptTemplates>) -> Self {
+        if !settings.semantic_templates {
+            return Self::new(settings);
+        }
+        if settings.mode != CacheKeyMode::Replace {
+            tracing::warn!("CACHE_KEY_SEMANTIC_TEMPLATES only applies with CACHE_KEY_MODE=replace");
+        }
+        Self::with_strategy(
+            settings.mode,
+            Box::new(SemanticTemplateKey::new(
+                canonical_hash(settings),
+                templates,
+            )),
+        )
+    }
+
+    pub fn with_strategy(mode: CacheKeyMode, strategy: Box<dyn CacheKeyStrategy>) -> Self {
+        Self { mode, strategy }
+    }
+
+    /// Key of the task with `payload` whose producer sent `body_hash`, or why the
+    /// task is rejected.
+    pub fn resolve(&self, payload: &Value, body_hash: &str) -> Result<String, String> {
+        match self.mode {
+            CacheKeyMode::Trust => Ok(body_hash.to_string()),
+            CacheKeyMode::Verify => {
+                let key = self.strategy.key(payload);
+                if key != body_hash {
+                    return Err(format!(
+                        "body_hash {} does not match the payload, whose key is {}",
+                        body_hash, key
+                    ));
+                }
+                Ok(key)
+            }
+            CacheKeyMode::Replace => Ok(self.strategy.key(payload)),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Response cache of stochastic generations.
+//!
+//! A body sampled with a temperature above zero is asked for diverse answers, so
+//! serving every duplicate the first cached response defeats its purpose. Such bodies
+//! instead collect up to `CACHE_STOCHASTIC_SAMPLES` generated responses (or the task's
+//! `cache_samples`): duplicates are generated again until that many are stored, and
+//! later ones reuse one of them, picked by message id so redeliveries get the same
+//! sample. Zero, the default, keeps stochastic bodies out of the cache.
+
+use crate::schemas::llm_response::LLMResponse;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Whether `body` samples with a temperature above zero. Bodies without one are
+/// treated as deterministic, whatever the provider's default.
+pub fn is_stochastic(body: &Value) -> bool {
+    body["temperature"].as_f64().is_some_and(|t| t > 0.0)
+}
+
+/// Samples collected for the body of a task: its `cache_samples`, or `default`.
+pub fn sample_limit(payload: &Value, default: usize) -> usize {
+    payload["cache_samples"]
+        .as_u64()
+        .map(|n| n as usize)
+        .unwrap_or(default)
+}
+
+/// Sample served to the task `key` out of the stored `samples`, or `None` while fewer
+/// than `limit` were collected and a new one should be generated.
+pub fn pick(mut samples: Vec<LLMResponse>, limit: usize, key: &str) -> Option<LLMResponse> {
+    if limit == 0 || samples.len() < limit {
+        return None;
+    }
+    samples.truncate(limit);
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let index = (hasher.finish() % limit as u64) as usize;
+    Some(samples.swap_remove(index))
+}
@@ -0,0 +1,168 @@
+//! Circuit breakers shared by every LLM call of the run, one per provider URL, so an
+//! outage doesn't have every task retrying against the provider in lockstep.
+//!
+//! After `CIRCUIT_BREAKER_FAILURES` consecutive attempts failed on the provider's side
+//! (connection errors, timeouts, 5xx, 401/403, rate limits), the circuit opens for
+//! `CIRCUIT_BREAKER_OPEN_SECS`. Attempts wait while it's open, holding their in-flight
+//! slot, which pauses consumption. Once the cooldown is over a single probe is let
+//! through: its success closes the circuit, its failure reopens it for twice as long,
+//! up to `CIRCUIT_BREAKER_MAX_OPEN_SECS`. Responses the request itself is to blame for
+//! (other 4xx, empty or oversized completions) show the provider is up.
+
+use crate::settings::CircuitBreakerSettings;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+#[derive(Default)]
+pub struct CircuitBreakers {
+    settings: CircuitBreakerSettings,
+    circuits: Mutex<HashMap<String, Arc<Circuit>>>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    state: Mutex<CircuitState>,
+    /// Woken when a probe settles.
+    probed: Notify,
+}
+
+#[derive(Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    /// Set while the circuit is open.
+    open_until: Option<Instant>,
+    /// How long the circuit opens for next.
+    cooldown: Duration,
+    probing: bool,
+}
+
+/// Admission of an attempt, to settle with its result. A probe dropped unsettled, e.g.
+/// when its task timed out, lets another attempt probe.
+pub struct Permit {
+    circuit: Option<Arc<Circuit>>,
+    url: String,
+    probe: bool,
+    settings: CircuitBreakerSettings,
+}
+
+impl CircuitBreakers {
+    pub fn new(settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            circuits: Mutex::default(),
+        }
+    }
+
+    /// Waits until the circuit of `url` lets an attempt through. Gives up with the time
+    /// the circuit reopens when that's past `deadline`.
+    pub async fn admit(&self, url: &str, deadline: Option<Instant>) -> Result<Permit, Instant> {
+        let url = circuit_key(url);
+        let mut permit = Permit {
+            circuit: None,
+            url: url.to_string(),
+            probe: false,
+            settings: self.settings.clone(),
+        };
+        if self.settings.failure_threshold == 0 {
+            return Ok(permit);
+        }
+        let circuit = self
+            .circuits
+            .lock()
+            .unwrap()
+            .entry(url.to_string())
+            .or_default()
+            .clone();
+        permit.circuit = Some(circuit.clone());
+        loop {
+            let probed = circuit.probed.notified();
+            tokio::pin!(probed);
+            // Registered before the state is read, so a probe settling in between
+            // still wakes this attempt.
+            probed.as_mut().enable();
+            let wait_until = {
+                let mut state = circuit.state.lock().unwrap();
+                match state.open_until {
+                    None => return Ok(permit),
+                    Some(open_until) if !state.probing && Instant::now() >= open_until => {
+                        state.probing = true;
+                        permit.probe = true;
+                        tracing::info!("Probing the circuit of {}", url);
+                        return Ok(permit);
+                    }
+                    Some(open_until) => open_until,
+                }
+            };
+            if deadline.is_some_and(|deadline| wait_until >= deadline) {
+                return Err(wait_until);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep_until(wait_until) => {}
+                _ = probed => {}
+            }
+        }
+    }
+}
+
+impl Permit {
+    /// Records the attempt's result: `false` when it failed on the provider's side.
+    pub fn settle(mut self, provider_healthy: bool) {
+        let Some(circuit) = self.circuit.take() else {
+            return;
+        };
+        let mut state = circuit.state.lock().unwrap();
+        let base = Duration::from_secs(self.settings.open_secs);
+        if provider_healthy {
+            if state.open_until.is_some() && self.probe {
+                tracing::info!("Circuit of {} closed", self.url);
+            }
+            if state.open_until.is_none() || self.probe {
+                *state = CircuitState::default();
+            }
+        } else {
+            state.consecutive_failures += 1;
+            if self.probe {
+                let max = Duration::from_secs(self.settings.max_open_secs);
+                state.cooldown = (state.cooldown * 2).min(max).max(base);
+                state.open_until = Some(Instant::now() + state.cooldown);
+                tracing::warn!(
+                    "Probe of {} failed, circuit reopened for {}s",
+                    self.url,
+                    state.cooldown.as_secs()
+                );
+            } else if state.open_until.is_none()
+                && state.consecutive_failures >= self.settings.failure_threshold
+            {
+                state.cooldown = base;
+                state.open_until = Some(Instant::now() + base);
+                tracing::warn!(
+                    "Circuit of {} opened for {}s after {} consecutive failures",
+                    self.url,
+                    base.as_secs(),
+                    state.consecutive_failures
+                );
+            }
+        }
+        if self.probe {
+            state.probing = false;
+            circuit.probed.notify_waiters();
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let (Some(circuit), true) = (&self.circuit, self.probe) {
+            circuit.state.lock().unwrap().probing = false;
+            circuit.probed.notify_waiters();
+        }
+    }
+}
+
+/// URL a circuit is kept for: the request URL without its query.
+fn circuit_key(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
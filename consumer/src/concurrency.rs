@@ -0,0 +1,50 @@
+//! Concurrency limits per provider or model, so the global `MAX_PARALLEL_TASKS` doesn't
+//! have to be tuned for the slowest endpoint. `CONCURRENCY_<NAME>=<n>` caps the
+//! requests in flight to endpoints whose host or model contains the name; each limit
+//! is itself capped by the global one.
+//!
+//! Tasks take their global slot before their route is known, so tasks queued on a
+//! saturated route still count against `MAX_PARALLEL_TASKS` while they wait.
+
+use crate::rate_limit::RateLimitTarget;
+use crate::settings::ConcurrencyLimitSettings;
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub struct ConcurrencyLimiter {
+    limits: Vec<(String, Arc<Semaphore>)>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(limits: &[ConcurrencyLimitSettings], max_parallel_tasks: usize) -> Self {
+        Self {
+            limits: limits
+                .iter()
+                .map(|limit| {
+                    let permits = limit.limit.clamp(1, max_parallel_tasks.max(1));
+                    (
+                        limit.pattern.to_lowercase(),
+                        Arc::new(Semaphore::new(permits)),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Waits for a slot under every limit matching `target`, held until the returned
+    /// permits are dropped. Limits are always taken in the same order, so requests
+    /// matching several of them can't deadlock.
+    pub async fn acquire(&self, target: &RateLimitTarget<'_>) -> Vec<OwnedSemaphorePermit> {
+        let matches = target.matcher();
+        let mut permits = Vec::new();
+        for (pattern, semaphore) in &self.limits {
+            if matches(pattern) {
+                // The semaphores are never closed.
+                if let Ok(permit) = semaphore.clone().acquire_owned().await {
+                    permits.push(permit);
+                }
+            }
+        }
+        permits
+    }
+}
@@ -0,0 +1,83 @@
+use crate::settings::ContaminationSettings;
+use crate::text;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::path::Path;
+
+struct EvalSet {
+    name: String,
+    ngrams: HashSet<u64>,
+}
+
+/// N-gram index over the configured evaluation sets.
+pub struct ContaminationChecker {
+    sets: Vec<EvalSet>,
+    ngram_size: usize,
+    threshold: f64,
+}
+
+fn line_text(line: &str) -> String {
+    match serde_json::from_str::<Value>(line) {
+        Ok(Value::Object(record)) => record
+            .values()
+            .filter_map(|v| v.as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => line.to_string(),
+    }
+}
+
+impl ContaminationChecker {
+    pub fn load(settings: &ContaminationSettings) -> Result<Self, std::io::Error> {
+        let mut sets = Vec::new();
+        for path in &settings.eval_sets {
+            let content = std::fs::read_to_string(path)?;
+            let mut ngrams = HashSet::new();
+            for line in content.lines().filter(|l| !l.trim().is_empty()) {
+                let words = text::words(&line_text(line));
+                ngrams.extend(text::ngram_hashes(&words, settings.ngram_size));
+            }
+            let name = Path::new(path)
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.clone());
+            tracing::info!("Loaded eval set {} with {} n-grams", name, ngrams.len());
+            sets.push(EvalSet { name, ngrams });
+        }
+
+        Ok(Self {
+            sets,
+            ngram_size: settings.ngram_size,
+            threshold: settings.threshold,
+        })
+    }
+
+    /// Returns the highest overlap ratio with any eval set and that set's name.
+    pub fn overlap(&self, example: &str) -> Option<(f64, &str)> {
+        let words = text::words(example);
+        let hashes: HashSet<u64> = text::ngram_hashes(&words, self.ngram_size).collect();
+        if hashes.is_empty() {
+            return None;
+        }
+        self.sets
+            .iter()
+            .map(|set| {
+                let hits = hashes.iter().filter(|h| set.ngrams.contains(h)).count();
+                (hits as f64 / hashes.len() as f64, set.name.as_str())
+            })
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+    }
+
+    pub fn check(&self, body: &Value, completion: &str) -> Map<String, Value> {
+        let example = format!("{}\n{}", text::prompt_text(body), completion);
+        let mut annotations = Map::new();
+        let (score, source) = self.overlap(&example).unwrap_or((0.0, ""));
+        let contaminated = score > 0.0 && score >= self.threshold;
+        annotations.insert("contaminated".to_string(), json!(contaminated));
+        annotations.insert("contamination_score".to_string(), json!(score));
+        if contaminated {
+            annotations.insert("contamination_source".to_string(), json!(source));
+        }
+        annotations
+    }
+}
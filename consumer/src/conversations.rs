@@ -0,0 +1,137 @@
+//! Tasks of kind `conversation`, which generate a whole dialogue instead of a single
+//! reply, for synthetic dialogue datasets.
+//!
+//! The body opens the conversation with its `messages`. After each reply the next user
+//! message comes from the task's `conversation` plan: the next of its fixed `turns`
+//! (strings or whole messages), or its `follow_up` template rendered with the task's
+//! `variables`, the `transcript` so far, the last `reply` and the `turn` number, for up
+//! to `max_turns` replies. A follow-up rendered empty ends the conversation early. The
+//! last reply is kept as the task's completions and the whole dialogue in the
+//! `transcript` annotation.
+
+use crate::templates::PromptTemplates;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+pub const KIND: &str = "conversation";
+
+pub fn is_conversation_task(payload: &Value) -> bool {
+    payload["kind"].as_str() == Some(KIND)
+}
+
+/// How a conversation goes on after its opening request, as submitted.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConversationPlan {
+    /// User messages sent after each reply in turn.
+    #[serde(default)]
+    pub turns: Vec<Value>,
+    /// Template name, or an inline template, of the next user message.
+    #[serde(default)]
+    pub follow_up: Option<Value>,
+    /// Replies generated with a `follow_up`, the first one included.
+    #[serde(default)]
+    pub max_turns: Option<usize>,
+}
+
+impl ConversationPlan {
+    pub fn check(&self) -> Result<(), String> {
+        match (&self.follow_up, self.max_turns) {
+            (Some(_), _) if !self.turns.is_empty() => {
+                Err("conversation: either turns or follow_up, not both".to_string())
+            }
+            (Some(_), None) => Err("conversation.max_turns: required with follow_up".to_string()),
+            (Some(template), _) if !template.is_string() && !template.is_object() => {
+                Err("conversation.follow_up: must be a template name or an object".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A conversation under way: the messages exchanged so far.
+pub struct Conversation {
+    plan: ConversationPlan,
+    body: Value,
+    messages: Vec<Value>,
+    replies: usize,
+}
+
+impl Conversation {
+    /// Conversation of a task, opened by the `messages` of its body.
+    pub fn start(payload: &Value) -> Result<Self, String> {
+        let plan: ConversationPlan = serde_json::from_value(payload["conversation"].clone())
+            .map_err(|e| format!("conversation: {}", e))?;
+        plan.check()?;
+        let body = payload["body"].clone();
+        let Some(messages) = body["messages"].as_array().cloned() else {
+            return Err("Conversation tasks need a body with messages".to_string());
+        };
+        Ok(Self {
+            plan,
+            body,
+            messages,
+            replies: 0,
+        })
+    }
+
+    /// Body of the next request, with every message so far.
+    pub fn body(&self) -> Value {
+        let mut body = self.body.clone();
+        body["messages"] = Value::from(self.messages.clone());
+        body
+    }
+
+    /// Records `reply` and returns the body of the next request, or `None` once the
+    /// conversation is over.
+    pub fn next_turn(
+        &mut self,
+        reply: &str,
+        templates: &PromptTemplates,
+        variables: &Value,
+    ) -> Result<Option<Value>, String> {
+        self.messages
+            .push(json!({ "role": "assistant", "content": reply }));
+        self.replies += 1;
+
+        let message = match &self.plan.follow_up {
+            None => match self.plan.turns.get(self.replies - 1) {
+                Some(Value::String(content)) => json!({ "role": "user", "content": content }),
+                Some(message) => message.clone(),
+                None => return Ok(None),
+            },
+            Some(template) => {
+                if self.replies >= self.plan.max_turns.unwrap_or(0) {
+                    return Ok(None);
+                }
+                let mut context = variables.as_object().cloned().unwrap_or_default();
+                context.insert("transcript".to_string(), Value::from(self.messages.clone()));
+                context.insert("reply".to_string(), Value::from(reply));
+                context.insert("turn".to_string(), Value::from(self.replies));
+                match templates.render(template, &Value::Object(context))? {
+                    Value::String(content) if content.trim().is_empty() => return Ok(None),
+                    Value::String(content) => json!({ "role": "user", "content": content }),
+                    Value::Object(message) if is_empty(&message) => return Ok(None),
+                    message => message,
+                }
+            }
+        };
+        self.messages.push(message);
+        Ok(Some(self.body()))
+    }
+
+    pub fn annotations(&self) -> Map<String, Value> {
+        let mut annotations = Map::new();
+        annotations.insert("transcript".to_string(), Value::from(self.messages.clone()));
+        annotations.insert("conversation_turns".to_string(), json!(self.replies));
+        annotations
+    }
+}
+
+/// Whether a rendered message has nothing to say.
+fn is_empty(message: &Map<String, Value>) -> bool {
+    match message.get("content") {
+        Some(Value::String(content)) => content.trim().is_empty(),
+        Some(Value::Null) | None => true,
+        _ => false,
+    }
+}
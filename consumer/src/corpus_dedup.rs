@@ -0,0 +1,113 @@
+//! Exact-match lookup of generated completions against existing training corpora.
+//!
+//! Corpora are described by the SHA-256 of their normalized text (whitespace collapsed,
+//! trimmed). Two file formats are accepted:
+//! - plain text: one hex digest per line;
+//! - `.bloom`: the magic `SGBLOOM1`, a little-endian `u32` hash count, a little-endian
+//!   `u64` bit count, then the bit array. Bit positions are derived by double hashing
+//!   the first two 64-bit words of the digest.
+
+use crate::settings::CorpusDedupSettings;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+
+const BLOOM_MAGIC: &[u8; 8] = b"SGBLOOM1";
+
+pub struct BloomFilter {
+    num_hashes: u32,
+    num_bits: u64,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 20 || &bytes[..8] != BLOOM_MAGIC {
+            return Err("not a synthgen bloom filter".to_string());
+        }
+        let num_hashes = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+        let num_bits = u64::from_le_bytes(bytes[12..20].try_into().unwrap());
+        let bits = bytes[20..].to_vec();
+        // Without hashes every digest would be found, and without bits none can be.
+        if num_hashes == 0 || num_bits == 0 {
+            return Err("bloom filter has no hashes or no bits".to_string());
+        }
+        if (bits.len() as u64) * 8 < num_bits {
+            return Err("bloom filter bit array is truncated".to_string());
+        }
+        Ok(Self {
+            num_hashes,
+            num_bits,
+            bits,
+        })
+    }
+
+    fn positions<'a>(&'a self, digest: &'a [u8; 32]) -> impl Iterator<Item = u64> + 'a {
+        let h1 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap());
+        (0..self.num_hashes as u64)
+            .map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits)
+    }
+
+    pub fn contains(&self, digest: &[u8; 32]) -> bool {
+        self.positions(digest)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+}
+
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+pub fn digest(text: &str) -> [u8; 32] {
+    Sha256::digest(normalize(text).as_bytes()).into()
+}
+
+fn from_hex(s: &str) -> Option<[u8; 32]> {
+    let mut out = [0u8; 32];
+    hex::decode_to_slice(s, &mut out).ok()?;
+    Some(out)
+}
+
+pub struct CorpusIndex {
+    hashes: HashSet<[u8; 32]>,
+    blooms: Vec<BloomFilter>,
+}
+
+impl CorpusIndex {
+    pub fn load(
+        settings: &CorpusDedupSettings,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut hashes = HashSet::new();
+        let mut blooms = Vec::new();
+        for path in &settings.hash_files {
+            if path.ends_with(".bloom") {
+                blooms.push(BloomFilter::from_bytes(&std::fs::read(path)?)?);
+            } else {
+                let content = std::fs::read_to_string(path)?;
+                hashes.extend(content.lines().filter_map(|l| from_hex(l.trim())));
+            }
+        }
+        tracing::info!(
+            "Loaded {} corpus hashes and {} bloom filters",
+            hashes.len(),
+            blooms.len()
+        );
+        Ok(Self { hashes, blooms })
+    }
+
+    pub fn contains(&self, digest: &[u8; 32]) -> bool {
+        self.hashes.contains(digest) || self.blooms.iter().any(|b| b.contains(digest))
+    }
+
+    pub fn check(&self, completion: &str) -> Map<String, Value> {
+        let digest = digest(completion);
+        let mut annotations = Map::new();
+        annotations.insert("completion_hash".to_string(), json!(hex::encode(digest)));
+        annotations.insert(
+            "corpus_duplicate".to_string(),
+            json!(self.contains(&digest)),
+        );
+        annotations
+    }
+}
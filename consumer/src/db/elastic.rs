@@ -0,0 +1,2179 @@
+//! Elasticsearch store, writing events, batch counts and leaderboards to the indexes
+//! named in the settings (`events`, `batches` and `leaderboard` by default).
+//!
+//! With `EVENTS_INDEX_ROLLOVER`, new events go to an index of the current day or month
+//! (`events-2026.10.18`) and searches cover `events-*`; updates look up the index
+//! holding their event. `bootstrap` creates the index templates holding the mappings
+//! of these indexes, so dated indexes get them as they are created.
+//!
+//! Status updates are either sent one `_update` call each or, with a bulk writer,
+//! queued and flushed together through `_bulk` once enough are waiting or the flush
+//! interval passes. Each writer still waits for the outcome of its own document, and
+//! a full queue holds writers back while Elasticsearch is slow.
+//!
+//! With `CONTENT_ADDRESSED_COMPLETIONS=true`, completions are written once per content
+//! to the `completions` index, under their hash (see `hashing`), and events reference
+//! them by `completions_ref` instead of holding them, so cached and repeated
+//! completions are stored once. Reads of completions resolve the references. Stored
+//! completions are kept when their events are deleted.
+
+use super::{
+    checkpoint_document, event_update_fields, parse_checkpoint, write_bulk_update, BatchCounts,
+    BatchProgress, DbResult, EmbeddingDocument, EventKey, EventState, PreviousResult,
+    ScoredEvaluation, StaleTask, TaskStore, TASK_MESSAGE,
+};
+use crate::hashing;
+use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::task_status::TaskStatus;
+use crate::settings::{BulkWriterSettings, DatabaseSettings, HashAlgorithm, IndexSettings};
+use crate::truncation::TRUNCATED_ANNOTATION;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use elasticsearch::{
+    auth::Credentials,
+    cert::{Certificate, CertificateValidation},
+    http::transport::{CloudConnectionPool, SingleNodeConnectionPool, Transport, TransportBuilder},
+    http::Url,
+    indices::{
+        IndicesCloseParts, IndicesCreateParts, IndicesExistsIndexTemplateParts, IndicesExistsParts,
+        IndicesGetAliasParts, IndicesPutIndexTemplateParts, IndicesPutSettingsParts,
+        IndicesRefreshParts,
+    },
+    params::{Conflicts, OpType, Refresh},
+    snapshot::{SnapshotCreateParts, SnapshotGetParts, SnapshotRestoreParts},
+    tasks::TasksGetParts,
+    BulkParts, CountParts, DeleteParts, Elasticsearch, GetParts, IndexParts, SearchParts,
+    UpdateByQueryParts, UpdateParts,
+};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Applies a status change to a `batches` document, like `BatchCounts::apply`, and
+/// stamps `finished_at` on the change that leaves no pending or processing tasks.
+const BATCH_COUNTS_SCRIPT: &str = "
+    for (String field : ['total', 'pending', 'processing', 'completed', 'failed']) {
+        if (ctx._source[field] == null) { ctx._source[field] = 0 }
+    }
+    if (params.from == null) {
+        ctx._source.total += params.count;
+    } else {
+        ctx._source[params.from] = Math.max(0, ctx._source[params.from] - params.count);
+    }
+    ctx._source[params.to] += params.count;
+    ctx._source.updated_at = params.now;
+    if (ctx._source.total > 0 && ctx._source.pending == 0 && ctx._source.processing == 0) {
+        if (ctx._source.finished_at == null) { ctx._source.finished_at = params.now }
+    } else {
+        ctx._source.finished_at = null;
+    }
+";
+
+/// Sets a PROCESSING event started before `params.started_before` (epoch millis) back
+/// to PENDING, and leaves any other event untouched.
+const RESET_STALE_SCRIPT: &str = "
+    if (ctx._source.status == 'PROCESSING' && ctx._source.started_at != null
+        && ZonedDateTime.parse(ctx._source.started_at).toInstant().toEpochMilli()
+            < params.started_before) {
+        ctx._source.status = 'PENDING';
+        ctx._source.started_at = null;
+    } else {
+        ctx.op = 'noop';
+    }
+";
+
+/// Adds a scored task to a `leaderboard` document and recomputes its accuracy (in
+/// percent) and mean score.
+const LEADERBOARD_SCRIPT: &str = "
+    for (String field : ['scored_tasks', 'correct_tasks']) {
+        if (ctx._source[field] == null) { ctx._source[field] = 0 }
+    }
+    if (ctx._source.score_sum == null) { ctx._source.score_sum = 0.0 }
+    ctx._source.scored_tasks += 1;
+    if (params.correct) { ctx._source.correct_tasks += 1 }
+    ctx._source.score_sum += params.score;
+    ctx._source.accuracy = ctx._source.correct_tasks * 100.0 / ctx._source.scored_tasks;
+    ctx._source.mean_score = ctx._source.score_sum / ctx._source.scored_tasks;
+    ctx._source.updated_at = params.now;
+";
+
+/// Sets each of `params.fields` on an event.
+const SET_FIELDS_SCRIPT: &str = "
+    for (entry in params.fields.entrySet()) {
+        ctx._source[entry.getKey()] = entry.getValue();
+    }
+";
+
+/// Adds the tags of `params.add` to an event's `tags` and takes those of
+/// `params.remove` out, leaving events whose tags don't change untouched.
+const RETAG_SCRIPT: &str = "
+    List tags = new ArrayList();
+    if (ctx._source.tags instanceof List) {
+        tags.addAll(ctx._source.tags);
+    } else if (ctx._source.tags != null) {
+        tags.add(ctx._source.tags);
+    }
+    List retagged = new ArrayList(tags);
+    retagged.removeAll(params.remove);
+    for (tag in params.add) {
+        if (!retagged.contains(tag)) {
+            retagged.add(tag);
+        }
+    }
+    if (retagged.equals(tags)) {
+        ctx.op = 'noop';
+    } else {
+        ctx._source.tags = retagged;
+    }
+";
+
+/// Moves each of `params.moves` within an event, `from` and `to` being lists of keys,
+/// creating the objects on the way to `to`. Events without the `from` field are left
+/// as they are.
+const MOVE_FIELDS_SCRIPT: &str = "
+    for (move in params.moves) {
+        def parent = ctx._source;
+        int last = move.from.size() - 1;
+        for (int i = 0; i < last && parent instanceof Map; i++) {
+            parent = parent.get(move.from[i]);
+        }
+        if (!(parent instanceof Map) || !parent.containsKey(move.from[last])) {
+            continue;
+        }
+        def value = parent.remove(move.from[last]);
+        def target = ctx._source;
+        for (int i = 0; i < move.to.size() - 1; i++) {
+            if (!(target.get(move.to[i]) instanceof Map)) {
+                target.put(move.to[i], new HashMap());
+            }
+            target = target.get(move.to[i]);
+        }
+        target.put(move.to[move.to.size() - 1], value);
+    }
+";
+
+/// Field of the events referencing their stored completions.
+pub const COMPLETIONS_REF: &str = "completions_ref";
+
+/// How often the reindex task of a migration is checked on.
+const MIGRATION_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Indexes of events kept by `ElasticStore::event_index` under rollover; the cache is
+/// emptied when it reaches this size.
+const EVENT_INDEX_CACHE_SIZE: usize = 100_000;
+
+/// Retries of a `batches` or `leaderboard` update that lost a race with a concurrent
+/// one.
+const BATCH_UPDATE_RETRIES: i64 = 10;
+
+/// Field of the events moved to another path by a migration, e.g. a flat field into
+/// an object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldMove {
+    pub from: String,
+    pub to: String,
+}
+
+impl FieldMove {
+    /// Parses `from=to`, both dotted paths.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (from, to) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("Field move `{}` is not `from=to`", spec))?;
+        let (from, to) = (from.trim(), to.trim());
+        for path in [from, to] {
+            if path.split('.').any(str::is_empty) {
+                return Err(format!("Invalid field path `{}` in `{}`", path, spec));
+            }
+        }
+        if from == to || to.starts_with(&format!("{}.", from)) {
+            return Err(format!("Field `{}` can't be moved into itself", from));
+        }
+        Ok(Self {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+
+    /// Parameter of `MOVE_FIELDS_SCRIPT`.
+    pub fn script_param(&self) -> Value {
+        json!({
+            "from": self.from.split('.').collect::<Vec<_>>(),
+            "to": self.to.split('.').collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Outcome of migrating an events index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexMigration {
+    /// Alias the events are read and written through.
+    pub alias: String,
+    /// Index the events were migrated from, now deleted.
+    pub source: String,
+    /// Index created with the current mappings, which the alias now points to.
+    pub target: String,
+    /// Events reindexed.
+    pub documents: u64,
+}
+
+struct PendingUpdate {
+    index: String,
+    id: String,
+    fields: Value,
+    done: oneshot::Sender<DbResult<()>>,
+}
+
+/// Transport to the cluster of `settings`: its Elastic Cloud deployment or its node,
+/// authenticated with the API key, the bearer token or else the user and password.
+fn transport(
+    settings: &DatabaseSettings,
+) -> Result<Transport, Box<dyn std::error::Error + Send + Sync>> {
+    let credentials = match (&settings.api_key, &settings.bearer_token) {
+        (Some(api_key), _) => Credentials::EncodedApiKey(api_key.clone()),
+        (None, Some(token)) => Credentials::Bearer(token.clone()),
+        (None, None) => Credentials::Basic(settings.user.clone(), settings.password.clone()),
+    };
+    let builder = match &settings.cloud_id {
+        Some(cloud_id) => TransportBuilder::new(CloudConnectionPool::new(cloud_id)?),
+        None => TransportBuilder::new(SingleNodeConnectionPool::new(Url::parse(&format!(
+            "{}://{}:{}",
+            settings.scheme, settings.host, settings.port
+        ))?)),
+    };
+    let validation = match &settings.ca_cert {
+        _ if !settings.verify_certs => CertificateValidation::None,
+        Some(path) => {
+            let pem = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            CertificateValidation::Full(Certificate::from_pem(&pem)?)
+        }
+        None => CertificateValidation::Default,
+    };
+    Ok(builder
+        .auth(credentials)
+        .cert_validation(validation)
+        .build()?)
+}
+
+/// Mappings of the fields the store filters, sorts and aggregates events on.
+fn events_mappings() -> Value {
+    json!({
+        "properties": {
+            "message_id": { "type": "keyword" },
+            "batch_id": { "type": "keyword" },
+            "custom_id": { "type": "keyword" },
+            "status": { "type": "keyword" },
+            "body_hash": { "type": "keyword" },
+            COMPLETIONS_REF: { "type": "keyword" },
+            "tags": { "type": "keyword" },
+            "provider_batch_id": { "type": "keyword" },
+            "balance_label": { "type": "keyword" },
+            "balance_excess": { "type": "boolean" },
+            "dedup_cluster": { "type": "keyword" },
+            "cached": { "type": "boolean" },
+            "created_at": { "type": "date" },
+            "started_at": { "type": "date" },
+            "completed_at": { "type": "date" },
+            "attribution": {
+                "properties": {
+                    "org": { "type": "keyword" },
+                    "project": { "type": "keyword" },
+                    "user": { "type": "keyword" },
+                }
+            },
+            "checkpoint": { "type": "object", "enabled": false },
+            TASK_MESSAGE: { "type": "object", "enabled": false },
+        }
+    })
+}
+
+fn batches_mappings() -> Value {
+    json!({
+        "properties": {
+            "batch_id": { "type": "keyword" },
+            "total": { "type": "long" },
+            "pending": { "type": "long" },
+            "processing": { "type": "long" },
+            "completed": { "type": "long" },
+            "failed": { "type": "long" },
+            "callback_url": { "type": "keyword", "index": false },
+            "updated_at": { "type": "date" },
+            "finished_at": { "type": "date" },
+        }
+    })
+}
+
+fn leaderboard_mappings() -> Value {
+    json!({
+        "properties": {
+            "suite": { "type": "keyword" },
+            "model": { "type": "keyword" },
+            "scored_tasks": { "type": "long" },
+            "correct_tasks": { "type": "long" },
+            "score_sum": { "type": "double" },
+            "accuracy": { "type": "double" },
+            "mean_score": { "type": "double" },
+            "updated_at": { "type": "date" },
+        }
+    })
+}
+
+fn completions_mappings() -> Value {
+    json!({
+        "properties": {
+            "completions": { "type": "object", "enabled": false },
+            "created_at": { "type": "date" },
+        }
+    })
+}
+
+fn workers_mappings() -> Value {
+    json!({
+        "properties": {
+            "worker_id": { "type": "keyword" },
+            "hostname": { "type": "keyword" },
+            "version": { "type": "keyword" },
+            "state": { "type": "keyword" },
+            "started_at": { "type": "date" },
+            "last_heartbeat": { "type": "date" },
+        }
+    })
+}
+
+pub struct ElasticStore {
+    client: Elasticsearch,
+    writer: Option<mpsc::Sender<PendingUpdate>>,
+    indices: IndexSettings,
+    snapshot_repository: Option<String>,
+    /// Vector indexes known to exist, created with their mapping on first write.
+    vector_indices: Arc<Mutex<HashSet<String>>>,
+    /// Index of each event looked up under rollover, by message id.
+    event_indices: Arc<Mutex<HashMap<String, String>>>,
+    /// Hash of the completions stored content-addressably.
+    content_addressed: Option<HashAlgorithm>,
+}
+
+impl ElasticStore {
+    pub async fn new(
+        db_settings: &DatabaseSettings,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let client = Elasticsearch::new(transport(db_settings)?);
+
+        Ok(ElasticStore {
+            client,
+            writer: None,
+            indices: db_settings.indices.clone(),
+            snapshot_repository: db_settings.snapshot_repository.clone(),
+            vector_indices: Arc::default(),
+            event_indices: Arc::default(),
+            content_addressed: db_settings.content_addressed_completions,
+        })
+    }
+
+    /// Index new events are written to: the events index, or under rollover the one of
+    /// the current day or month.
+    pub fn events_index(&self) -> String {
+        events_index(&self.indices, Utc::now())
+    }
+
+    /// Index, alias or pattern searches of events go to.
+    fn events_pattern(&self) -> String {
+        match self.indices.rollover {
+            Some(_) => format!("{}-*", self.indices.events),
+            None => self.indices.events.clone(),
+        }
+    }
+
+    /// Index holding the event `message_id`. Under rollover it is looked up across the
+    /// dated indexes, defaulting to the current one for events not found.
+    pub async fn event_index(&self, message_id: &str) -> DbResult<String> {
+        if self.indices.rollover.is_none() {
+            return Ok(self.indices.events.clone());
+        }
+        if let Some(index) = self.event_indices.lock().unwrap().get(message_id) {
+            return Ok(index.clone());
+        }
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(json!({
+                "query": { "ids": { "values": [message_id] }},
+                "size": 1,
+                "_source": false,
+            }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+        let response_body = response.json::<Value>().await?;
+        let Some(index) = response_body["hits"]["hits"][0]["_index"].as_str() else {
+            return Ok(self.events_index());
+        };
+        let mut event_indices = self.event_indices.lock().unwrap();
+        if event_indices.len() >= EVENT_INDEX_CACHE_SIZE {
+            event_indices.clear();
+        }
+        event_indices.insert(message_id.to_string(), index.to_string());
+        Ok(index.to_string())
+    }
+
+    pub fn workers_index(&self) -> &str {
+        &self.indices.workers
+    }
+
+    /// Creates the index templates of the events, batches, leaderboard and workers
+    /// indexes, then those indexes, where missing. Existing templates and mappings are
+    /// left as they are.
+    pub async fn bootstrap(&self) -> DbResult<()> {
+        let events = &self.indices.events;
+        let mut templates = vec![
+            (
+                events,
+                vec![events.clone(), format!("{}-*", events)],
+                events_mappings(),
+            ),
+            (
+                &self.indices.batches,
+                vec![self.indices.batches.clone()],
+                batches_mappings(),
+            ),
+            (
+                &self.indices.leaderboard,
+                vec![self.indices.leaderboard.clone()],
+                leaderboard_mappings(),
+            ),
+            (
+                &self.indices.workers,
+                vec![self.indices.workers.clone()],
+                workers_mappings(),
+            ),
+        ];
+        if self.content_addressed.is_some() {
+            templates.push((
+                &self.indices.completions,
+                vec![self.indices.completions.clone()],
+                completions_mappings(),
+            ));
+        }
+        for (name, patterns, mappings) in templates {
+            let template = format!("synthgen-{}", name);
+            let response = self
+                .client
+                .indices()
+                .exists_index_template(IndicesExistsIndexTemplateParts::Name(&template))
+                .send()
+                .await?;
+            if response.status_code().as_u16() == 404 {
+                let response = self
+                    .client
+                    .indices()
+                    .put_index_template(IndicesPutIndexTemplateParts::Name(&template))
+                    .body(json!({
+                        "index_patterns": patterns,
+                        "template": { "mappings": mappings },
+                    }))
+                    .send()
+                    .await?;
+                let status = response.status_code();
+                if !status.is_success() {
+                    let error = response.text().await.unwrap_or_default();
+                    return Err(format!(
+                        "Failed to create index template {} ({}): {}",
+                        template, status, error
+                    )
+                    .into());
+                }
+                tracing::info!("Created index template {}", template);
+            }
+        }
+
+        let events_index = self.events_index();
+        let mut indices = vec![
+            &events_index,
+            &self.indices.batches,
+            &self.indices.leaderboard,
+            &self.indices.workers,
+        ];
+        if self.content_addressed.is_some() {
+            indices.push(&self.indices.completions);
+        }
+        for index in indices {
+            let response = self
+                .client
+                .indices()
+                .exists(IndicesExistsParts::Index(&[index]))
+                .send()
+                .await?;
+            if response.status_code().as_u16() != 404 {
+                continue;
+            }
+            let response = self
+                .client
+                .indices()
+                .create(IndicesCreateParts::Index(index))
+                .send()
+                .await?;
+            let status = response.status_code();
+            if !status.is_success() {
+                let error = response.text().await.unwrap_or_default();
+                if !error.contains("resource_already_exists_exception") {
+                    return Err(format!(
+                        "Failed to create index {} ({}): {}",
+                        index, status, error
+                    )
+                    .into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Snapshots the events, batches and leaderboard indexes before `operation` and
+    /// records the snapshot with `details` in the audit index. Returns its name, or
+    /// `None` without a snapshot repository.
+    pub async fn snapshot_before(
+        &self,
+        operation: &str,
+        details: Value,
+    ) -> DbResult<Option<String>> {
+        let Some(repository) = &self.snapshot_repository else {
+            return Ok(None);
+        };
+        let snapshot = snapshot_name(operation, Utc::now());
+        let indices = [
+            self.events_pattern(),
+            self.indices.batches.clone(),
+            self.indices.leaderboard.clone(),
+        ];
+        let response = self
+            .client
+            .snapshot()
+            .create(SnapshotCreateParts::RepositorySnapshot(
+                repository, &snapshot,
+            ))
+            .wait_for_completion(true)
+            .body(json!({
+                "indices": indices.join(","),
+                "ignore_unavailable": true,
+                "include_global_state": false,
+            }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Failed to snapshot before {} ({}): {}",
+                operation, status, error
+            )
+            .into());
+        }
+        let response_body = response.json::<Value>().await?;
+        let state = &response_body["snapshot"]["state"];
+        if state != "SUCCESS" {
+            return Err(
+                format!("Snapshot {} before {} ended {}", snapshot, operation, state).into(),
+            );
+        }
+        self.audit(json!({
+            "action": "snapshot",
+            "operation": operation,
+            "repository": repository,
+            "snapshot": snapshot,
+            "indices": response_body["snapshot"]["indices"],
+            "details": details,
+        }))
+        .await?;
+        tracing::info!(
+            "Took snapshot {} in {} before {}",
+            snapshot,
+            repository,
+            operation
+        );
+        Ok(Some(snapshot))
+    }
+
+    /// Restores the indexes of `snapshot`, closing them first: their events are rolled
+    /// back to when it was taken. Indexes created since are left as they are. Returns
+    /// the indexes restored.
+    pub async fn restore_snapshot(&self, snapshot: &str) -> DbResult<Vec<String>> {
+        let Some(repository) = &self.snapshot_repository else {
+            return Err(
+                "No snapshot repository configured (ELASTICSEARCH_SNAPSHOT_REPOSITORY)".into(),
+            );
+        };
+        let response = self
+            .client
+            .snapshot()
+            .get(SnapshotGetParts::RepositorySnapshot(
+                repository,
+                &[snapshot],
+            ))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Failed to look up snapshot {} ({}): {}",
+                snapshot, status, error
+            )
+            .into());
+        }
+        let response_body = response.json::<Value>().await?;
+        let indices: Vec<String> = response_body["snapshots"][0]["indices"]
+            .as_array()
+            .map(|indices| {
+                indices
+                    .iter()
+                    .filter_map(|index| index.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if indices.is_empty() {
+            return Err(format!("Snapshot {} has no indexes to restore", snapshot).into());
+        }
+        let names: Vec<&str> = indices.iter().map(String::as_str).collect();
+
+        // Open indexes can't be restored over; those deleted since are restored anew.
+        let response = self
+            .client
+            .indices()
+            .close(IndicesCloseParts::Index(&names))
+            .ignore_unavailable(true)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Failed to close {} ({}): {}",
+                indices.join(","),
+                status,
+                error
+            )
+            .into());
+        }
+        let response = self
+            .client
+            .snapshot()
+            .restore(SnapshotRestoreParts::RepositorySnapshot(
+                repository, snapshot,
+            ))
+            .wait_for_completion(true)
+            .body(json!({
+                "indices": indices.join(","),
+                "include_global_state": false,
+            }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Failed to restore snapshot {} ({}): {}",
+                snapshot, status, error
+            )
+            .into());
+        }
+        self.event_indices.lock().unwrap().clear();
+        self.audit(json!({
+            "action": "restore",
+            "repository": repository,
+            "snapshot": snapshot,
+            "indices": indices,
+        }))
+        .await?;
+        tracing::info!("Restored {} from snapshot {}", indices.join(", "), snapshot);
+        Ok(indices)
+    }
+
+    /// Adds `record` to the audit index with the time it happened.
+    async fn audit(&self, mut record: Value) -> DbResult<()> {
+        record["at"] = json!(Utc::now());
+        let response = self
+            .client
+            .index(IndexParts::Index(&self.indices.audit))
+            .body(record)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Failed to write to {} ({}): {}",
+                self.indices.audit, status, error
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Takes the lock `name` for `ttl`, a document of the audit index, so that one
+    /// admin operation of its kind runs at a time. A lock held past its expiry is taken
+    /// over. Returns whether it was taken.
+    pub async fn acquire_lock(&self, name: &str, ttl: Duration) -> DbResult<bool> {
+        let id = format!("lock-{}", name);
+        let now = Utc::now();
+        let lock = json!({
+            "lock": name,
+            "locked_at": now,
+            "expires_at": now + chrono::Duration::from_std(ttl)?,
+        });
+        let response = self
+            .client
+            .index(IndexParts::IndexId(&self.indices.audit, &id))
+            .op_type(OpType::Create)
+            .body(lock.clone())
+            .send()
+            .await?;
+        let status = response.status_code();
+        if status.is_success() {
+            return Ok(true);
+        }
+        if status.as_u16() != 409 {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Failed to take lock {} ({}): {}", name, status, error).into());
+        }
+
+        let response = self
+            .client
+            .get(GetParts::IndexId(&self.indices.audit, &id))
+            .send()
+            .await?;
+        let held = response.json::<Value>().await?;
+        let expires_at = held["_source"]["expires_at"]
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+        if expires_at.is_some_and(|t| t > now) {
+            return Ok(false);
+        }
+        // Only replaced if no other run took it over since it was read.
+        let (Some(seq_no), Some(primary_term)) =
+            (held["_seq_no"].as_i64(), held["_primary_term"].as_i64())
+        else {
+            return Ok(false);
+        };
+        let response = self
+            .client
+            .index(IndexParts::IndexId(&self.indices.audit, &id))
+            .if_seq_no(seq_no)
+            .if_primary_term(primary_term)
+            .body(lock)
+            .send()
+            .await?;
+        Ok(response.status_code().is_success())
+    }
+
+    /// Releases the lock `name` taken with `acquire_lock`.
+    pub async fn release_lock(&self, name: &str) -> DbResult<()> {
+        let id = format!("lock-{}", name);
+        let response = self
+            .client
+            .delete(DeleteParts::IndexId(&self.indices.audit, &id))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() && status.as_u16() != 404 {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Failed to release lock {} ({}): {}", name, status, error).into());
+        }
+        Ok(())
+    }
+
+    /// Migrates the events index (or alias) `name` to a new index with the current
+    /// mappings: writes to its index are blocked, its events are reindexed with `moves`
+    /// applied, and in one atomic step `name` becomes an alias of the new index and its
+    /// index is deleted. On failure the source is writable again and the alias left as
+    /// it was, the new index being kept for inspection.
+    pub async fn migrate_events(
+        &self,
+        name: &str,
+        moves: &[FieldMove],
+    ) -> DbResult<IndexMigration> {
+        let events = &self.indices.events;
+        if name != events && !name.starts_with(&format!("{}-", events)) {
+            return Err(format!("{} is not an events index", name).into());
+        }
+        let response = self
+            .client
+            .indices()
+            .get_alias(IndicesGetAliasParts::Name(&[name]))
+            .send()
+            .await?;
+        let status = response.status_code();
+        let source = if status.as_u16() == 404 {
+            name.to_string()
+        } else if status.is_success() {
+            let response_body = response.json::<Value>().await?;
+            let indices: Vec<&String> = response_body
+                .as_object()
+                .map(|indices| indices.keys().collect())
+                .unwrap_or_default();
+            match indices.as_slice() {
+                [index] => index.to_string(),
+                _ => {
+                    return Err(format!(
+                        "Alias {} has to point to a single index to be migrated",
+                        name
+                    )
+                    .into())
+                }
+            }
+        } else {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Failed to look up alias {} ({}): {}", name, status, error).into());
+        };
+
+        let target = migrated_index_name(name, Utc::now());
+        let response = self
+            .client
+            .indices()
+            .create(IndicesCreateParts::Index(&target))
+            .body(json!({ "mappings": events_mappings() }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(
+                format!("Failed to create index {} ({}): {}", target, status, error).into(),
+            );
+        }
+
+        self.block_writes(&source, true).await?;
+        let documents = match self.reindex(&source, &target, moves).await {
+            Ok(documents) => documents,
+            Err(e) => {
+                if let Err(unblock) = self.block_writes(&source, false).await {
+                    tracing::error!("Failed to unblock writes to {}: {}", source, unblock);
+                }
+                return Err(format!(
+                    "Migration of {} to {} failed, {} is left for inspection: {}",
+                    source, target, target, e
+                )
+                .into());
+            }
+        };
+
+        // The source is dropped rather than kept read-only: its events would otherwise
+        // show up twice in searches over the rollover pattern.
+        let actions = json!([
+            { "add": { "index": target, "alias": name }},
+            { "remove_index": { "index": source }},
+        ]);
+        let response = self
+            .client
+            .indices()
+            .update_aliases()
+            .body(json!({ "actions": actions }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            if let Err(unblock) = self.block_writes(&source, false).await {
+                tracing::error!("Failed to unblock writes to {}: {}", source, unblock);
+            }
+            return Err(format!(
+                "Failed to swap alias {} to {} ({}): {}",
+                name, target, status, error
+            )
+            .into());
+        }
+        self.event_indices.lock().unwrap().clear();
+        let moves: Vec<String> = moves
+            .iter()
+            .map(|m| format!("{}={}", m.from, m.to))
+            .collect();
+        self.audit(json!({
+            "action": "migrate",
+            "alias": name,
+            "source": source,
+            "target": target,
+            "documents": documents,
+            "moves": moves,
+        }))
+        .await?;
+        tracing::info!("Migrated {} events of {} to {}", documents, source, target);
+        Ok(IndexMigration {
+            alias: name.to_string(),
+            source,
+            target,
+            documents,
+        })
+    }
+
+    /// Sets or lifts the write block of `index`.
+    async fn block_writes(&self, index: &str, blocked: bool) -> DbResult<()> {
+        let response = self
+            .client
+            .indices()
+            .put_settings(IndicesPutSettingsParts::Index(&[index]))
+            .body(json!({ "index.blocks.write": blocked }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Failed to set the write block of {} ({}): {}",
+                index, status, error
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Copies the events of `source` to `target` with `moves` applied, as a background
+    /// task waited on, and checks both hold as many. Returns that number.
+    async fn reindex(&self, source: &str, target: &str, moves: &[FieldMove]) -> DbResult<u64> {
+        let mut body = json!({
+            "source": { "index": source },
+            "dest": { "index": target, "op_type": "create" },
+        });
+        if !moves.is_empty() {
+            let moves: Vec<Value> = moves.iter().map(FieldMove::script_param).collect();
+            body["script"] = json!({ "source": MOVE_FIELDS_SCRIPT, "params": { "moves": moves }});
+        }
+        let response = self
+            .client
+            .reindex()
+            .body(body)
+            .wait_for_completion(false)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Reindex request failed ({}): {}", status, error).into());
+        }
+        let response_body = response.json::<Value>().await?;
+        let task = response_body["task"]
+            .as_str()
+            .ok_or("Reindex request returned no task")?
+            .to_string();
+
+        let outcome = loop {
+            tokio::time::sleep(MIGRATION_POLL_INTERVAL).await;
+            let response = self
+                .client
+                .tasks()
+                .get(TasksGetParts::TaskId(&task))
+                .send()
+                .await?;
+            let status = response.status_code();
+            if !status.is_success() {
+                let error = response.text().await.unwrap_or_default();
+                return Err(
+                    format!("Failed to check on task {} ({}): {}", task, status, error).into(),
+                );
+            }
+            let response_body = response.json::<Value>().await?;
+            if response_body["completed"] == true {
+                break response_body;
+            }
+            let progress = &response_body["task"]["status"];
+            tracing::info!(
+                "Reindexed {} of {} events into {}",
+                progress["created"],
+                progress["total"],
+                target
+            );
+        };
+        if !outcome["error"].is_null() {
+            return Err(format!("Reindex task {} failed: {}", task, outcome["error"]).into());
+        }
+        let failures = &outcome["response"]["failures"];
+        if failures
+            .as_array()
+            .is_some_and(|failures| !failures.is_empty())
+        {
+            return Err(format!("Reindex task {} had failures: {}", task, failures).into());
+        }
+
+        let response = self
+            .client
+            .indices()
+            .refresh(IndicesRefreshParts::Index(&[target]))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Failed to refresh {} ({}): {}", target, status, error).into());
+        }
+        let (copied, expected) = (self.count(target).await?, self.count(source).await?);
+        if copied != expected {
+            return Err(format!(
+                "{} holds {} events where {} holds {}",
+                target, copied, source, expected
+            )
+            .into());
+        }
+        Ok(copied)
+    }
+
+    /// Number of documents of `index`.
+    async fn count(&self, index: &str) -> DbResult<u64> {
+        let response = self
+            .client
+            .count(CountParts::Index(&[index]))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Count request failed ({}): {}", status, error).into());
+        }
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body["count"].as_u64().unwrap_or_default())
+    }
+
+    /// Routes status updates through a bulk writer flushing in the background.
+    pub fn with_bulk_writer(mut self, settings: &BulkWriterSettings) -> Self {
+        let (writer, updates) = mpsc::channel(settings.queue_capacity.max(1));
+        tokio::spawn(flush_updates(
+            self.client.clone(),
+            updates,
+            settings.max_actions.max(1),
+            Duration::from_millis(settings.flush_interval_ms),
+        ));
+        self.writer = Some(writer);
+        self
+    }
+
+    /// Nearest completions to `vector` by cosine similarity on `completion_embedding`.
+    pub async fn similar_completions(
+        &self,
+        vector: &[f32],
+        k: usize,
+        batch_id: Option<&str>,
+    ) -> Result<Vec<(String, f64)>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut knn = json!({
+            "field": "completion_embedding",
+            "query_vector": vector,
+            "k": k,
+            "num_candidates": (k * 10).max(100),
+        });
+        if let Some(batch_id) = batch_id {
+            knn["filter"] = json!({ "term": { "batch_id": batch_id }});
+        }
+
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(json!({ "knn": knn, "_source": ["message_id"] }))
+            .send()
+            .await?;
+
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|h| Some((h["_id"].as_str()?.to_string(), h["_score"].as_f64()?)))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Creates `index` with the mapping of vector indexes unless it already exists.
+    async fn ensure_vector_index(&self, index: &str) -> DbResult<()> {
+        if self.vector_indices.lock().unwrap().contains(index) {
+            return Ok(());
+        }
+        let response = self
+            .client
+            .indices()
+            .create(IndicesCreateParts::Index(index))
+            .body(json!({
+                "mappings": {
+                    "properties": {
+                        "message_id": { "type": "keyword" },
+                        "batch_id": { "type": "keyword" },
+                        "position": { "type": "integer" },
+                        "input": { "type": "text" },
+                        "model": { "type": "keyword" },
+                        "dimensions": { "type": "integer" },
+                        "vector": { "type": "dense_vector", "index": true, "similarity": "cosine" },
+                        "created_at": { "type": "date" },
+                    }
+                }
+            }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            if !error.contains("resource_already_exists_exception") {
+                return Err(
+                    format!("Failed to create index {} ({}): {}", index, status, error).into(),
+                );
+            }
+        }
+        self.vector_indices
+            .lock()
+            .unwrap()
+            .insert(index.to_string());
+        Ok(())
+    }
+
+    /// Partial update of an events document, through the bulk writer when enabled.
+    async fn update_event(&self, message_id: &str, fields: Value) -> DbResult<()> {
+        let index = self.event_index(message_id).await?;
+        if let Some(writer) = &self.writer {
+            let (done, outcome) = oneshot::channel();
+            writer
+                .send(PendingUpdate {
+                    index,
+                    id: message_id.to_string(),
+                    fields,
+                    done,
+                })
+                .await
+                .map_err(|_| "Bulk writer has stopped")?;
+            return outcome
+                .await
+                .map_err(|_| "Bulk writer dropped the update")?;
+        }
+        let doc = json!({ "doc": fields });
+
+        let response = self
+            .client
+            .update(UpdateParts::IndexId(&index, message_id))
+            .body(doc)
+            .refresh(Refresh::False)
+            .send()
+            .await?;
+
+        if let Some(exception) = response.exception().await? {
+            return Err(format!("Failed to update document: {:?}", exception).into());
+        }
+        Ok(())
+    }
+
+    /// Source of a document, or `None` when the index or the document doesn't exist.
+    pub async fn get_document(&self, index: &str, id: &str) -> DbResult<Option<Value>> {
+        let response = self.client.get(GetParts::IndexId(index, id)).send().await?;
+        let status = response.status_code();
+        if status.as_u16() == 404 {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Get request failed ({}): {}", status, error).into());
+        }
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body.get("_source").cloned())
+    }
+
+    /// Writes `document` as `id` of `index`, replacing it.
+    pub async fn put_document(&self, index: &str, id: &str, document: &Value) -> DbResult<()> {
+        let response = self
+            .client
+            .index(IndexParts::IndexId(index, id))
+            .body(document)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Index request failed ({}): {}", status, error).into());
+        }
+        Ok(())
+    }
+
+    /// Moves the content of the completions of event `fields` to the completion store,
+    /// leaving its address in `completions_ref` and the rest of the response on the
+    /// event. Content already stored isn't written again.
+    async fn store_completions(&self, fields: &mut Value) -> DbResult<()> {
+        let Some(algorithm) = self.content_addressed else {
+            return Ok(());
+        };
+        let Some(fields) = fields.as_object_mut() else {
+            return Ok(());
+        };
+        let Some(completions) = fields.remove("completions").filter(|c| !c.is_null()) else {
+            return Ok(());
+        };
+        let (content, rest) = hashing::split_content(completions);
+        let address = hashing::content_hash(algorithm, &content);
+        let response = self
+            .client
+            .index(IndexParts::IndexId(&self.indices.completions, &address))
+            .op_type(OpType::Create)
+            .body(json!({ "completions": content, "created_at": Utc::now() }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        // A conflict means the same content is stored already.
+        if !status.is_success() && status.as_u16() != 409 {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!(
+                "Failed to store completions {} ({}): {}",
+                address, status, error
+            )
+            .into());
+        }
+        if !rest.is_null() {
+            fields.insert("completions".to_string(), rest);
+        }
+        fields.insert(COMPLETIONS_REF.to_string(), json!(address));
+        Ok(())
+    }
+
+    /// Sources of the hits of a search of events, with their stored completions.
+    async fn hit_sources(&self, mut response_body: Value) -> DbResult<Vec<Value>> {
+        let mut sources: Vec<Value> = response_body["hits"]["hits"]
+            .as_array_mut()
+            .map(|hits| hits.iter_mut().map(|hit| hit["_source"].take()).collect())
+            .unwrap_or_default();
+        self.resolve_completions(&mut sources).await?;
+        Ok(sources)
+    }
+
+    /// Fills in the completions of the events referencing stored content, from it and
+    /// the rest of the response kept on the event.
+    async fn resolve_completions(&self, events: &mut [Value]) -> DbResult<()> {
+        let mut addresses: Vec<&str> = events
+            .iter()
+            .filter_map(|event| event[COMPLETIONS_REF].as_str())
+            .collect();
+        if addresses.is_empty() {
+            return Ok(());
+        }
+        addresses.sort_unstable();
+        addresses.dedup();
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.indices.completions]))
+            .body(json!({
+                "query": { "ids": { "values": addresses }},
+                "size": addresses.len(),
+            }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+        let mut response_body = response.json::<Value>().await?;
+        let stored: HashMap<String, Value> = response_body["hits"]["hits"]
+            .as_array_mut()
+            .map(|hits| {
+                hits.iter_mut()
+                    .filter_map(|hit| {
+                        let address = hit["_id"].as_str()?.to_string();
+                        Some((address, hit["_source"]["completions"].take()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        for event in events.iter_mut() {
+            let Some(content) = event[COMPLETIONS_REF].as_str().and_then(|a| stored.get(a)) else {
+                continue;
+            };
+            event["completions"] = hashing::join_content(content.clone(), &event["completions"]);
+        }
+        Ok(())
+    }
+
+    /// Latest heartbeat of every worker, by worker id.
+    pub async fn worker_heartbeats(&self) -> DbResult<Vec<Value>> {
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.indices.workers]))
+            .body(json!({
+                "query": { "match_all": {} },
+                "sort": [{ "worker_id": "asc" }],
+                "size": 10000,
+            }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if status.as_u16() == 404 {
+            return Ok(Vec::new());
+        }
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+        let mut response_body = response.json::<Value>().await?;
+        Ok(response_body["hits"]["hits"]
+            .as_array_mut()
+            .map(|hits| hits.iter_mut().map(|hit| hit["_source"].take()).collect())
+            .unwrap_or_default())
+    }
+
+    /// Sends a `_bulk` NDJSON body and returns the number of actions that failed.
+    pub async fn bulk(&self, ndjson: Vec<u8>) -> DbResult<usize> {
+        let errors = bulk_request(&self.client, &self.events_index(), ndjson).await?;
+        Ok(errors.iter().filter(|error| error.is_some()).count())
+    }
+
+    /// Sets `fields` on every event matching `query` and returns how many were updated.
+    /// Events changing meanwhile are left out rather than failing the whole update.
+    pub async fn update_events_by_query(&self, query: Value, fields: &Value) -> DbResult<u64> {
+        self.update_events_by_script(query, SET_FIELDS_SCRIPT, json!({ "fields": fields }))
+            .await
+    }
+
+    /// Adds the tags `add` to the events matching `query` and removes the tags
+    /// `remove`, returning how many events changed.
+    pub async fn retag_events(
+        &self,
+        query: Value,
+        add: &[String],
+        remove: &[String],
+    ) -> DbResult<u64> {
+        self.update_events_by_script(query, RETAG_SCRIPT, json!({ "add": add, "remove": remove }))
+            .await
+    }
+
+    async fn update_events_by_script(
+        &self,
+        query: Value,
+        script: &str,
+        params: Value,
+    ) -> DbResult<u64> {
+        let response = self
+            .client
+            .update_by_query(UpdateByQueryParts::Index(&[&self.events_pattern()]))
+            .body(json!({
+                "query": query,
+                "script": { "source": script, "params": params },
+            }))
+            .conflicts(Conflicts::Proceed)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Update by query failed ({}): {}", status, error).into());
+        }
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body["updated"].as_u64().unwrap_or_default())
+    }
+
+    /// Number of events matching `query`.
+    pub async fn count_events(&self, query: &Value) -> DbResult<u64> {
+        let response = self
+            .client
+            .count(CountParts::Index(&[&self.events_pattern()]))
+            .body(json!({ "query": query }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Count request failed ({}): {}", status, error).into());
+        }
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body["count"].as_u64().unwrap_or_default())
+    }
+
+    /// Message ids and batch ids of up to `limit` events matching `query`, by message id
+    /// starting after the message id `after`.
+    pub async fn matching_events(
+        &self,
+        query: &Value,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<(String, String)>> {
+        let mut body = json!({
+            "query": query,
+            "sort": [{ "message_id": "asc" }],
+            "size": limit,
+            "_source": ["message_id", "batch_id"],
+        });
+        if let Some(after) = after {
+            body["search_after"] = json!([after]);
+        }
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|hit| {
+                        let source = &hit["_source"];
+                        Some((
+                            source["message_id"].as_str()?.to_string(),
+                            source["batch_id"].as_str().unwrap_or_default().to_string(),
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Number of events by status, of `batch_id` or of every batch.
+    pub async fn status_counts(&self, batch_id: Option<&str>) -> DbResult<BTreeMap<String, u64>> {
+        let query = match batch_id {
+            Some(batch_id) => json!({ "term": { "batch_id": batch_id }}),
+            None => json!({ "match_all": {} }),
+        };
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(json!({
+                "size": 0,
+                "query": query,
+                "aggs": { "statuses": { "terms": { "field": "status", "size": 10 }}}
+            }))
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body["aggregations"]["statuses"]["buckets"]
+            .as_array()
+            .map(|buckets| {
+                buckets
+                    .iter()
+                    .filter_map(|b| {
+                        Some((b["key"].as_str()?.to_string(), b["doc_count"].as_u64()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Up to `limit` completed responses the cache may serve, of `batch_id` or of every
+    /// batch, by message id starting after the message id `after`. Each comes with the
+    /// body hash it answers.
+    pub async fn cacheable_responses(
+        &self,
+        batch_id: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<(String, PreviousResult)>> {
+        let mut filter = vec![
+            json!({ "term": { "status": TaskStatus::Completed.as_str() }}),
+            json!({ "term": { "cached": false }}),
+            json!({
+                "bool": {
+                    "should": [
+                        { "exists": { "field": "completions" }},
+                        { "exists": { "field": COMPLETIONS_REF }}
+                    ]
+                }
+            }),
+        ];
+        if let Some(batch_id) = batch_id {
+            filter.push(json!({ "term": { "batch_id": batch_id }}));
+        }
+        let mut query = json!({
+            "query": {
+                "bool": {
+                    "filter": filter,
+                    "must_not": [{ "exists": { "field": TRUNCATED_ANNOTATION }}]
+                }
+            },
+            "sort": [{ "message_id": "asc" }],
+            "size": limit,
+            "_source": [
+                "message_id",
+                "body_hash",
+                "completions",
+                COMPLETIONS_REF,
+                "started_at",
+                "completed_at"
+            ],
+        });
+        if let Some(after) = after {
+            query["search_after"] = json!([after]);
+        }
+
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(query)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+
+        let response_body = response.json::<Value>().await?;
+        Ok(self
+            .hit_sources(response_body)
+            .await?
+            .iter()
+            .filter_map(|source| {
+                let result = PreviousResult {
+                    message_id: source["message_id"].as_str()?.to_string(),
+                    response: cached_response(source)?,
+                };
+                Some((source["body_hash"].as_str()?.to_string(), result))
+            })
+            .collect())
+    }
+}
+
+/// Sends a `_bulk` NDJSON body, whose actions default to `index`, and returns the
+/// error of each action, in order.
+async fn bulk_request(
+    client: &Elasticsearch,
+    index: &str,
+    ndjson: Vec<u8>,
+) -> DbResult<Vec<Option<String>>> {
+    let response = client
+        .bulk(BulkParts::Index(index))
+        .body(vec![ndjson])
+        .refresh(Refresh::False)
+        .send()
+        .await?;
+
+    let status = response.status_code();
+    if !status.is_success() {
+        let error = response.text().await.unwrap_or_default();
+        return Err(format!("Bulk request failed ({}): {}", status, error).into());
+    }
+    let response_body = response.json::<Value>().await?;
+    Ok(response_body["items"]
+        .as_array()
+        .map(|items| {
+            items
+                .iter()
+                .map(|item| {
+                    // Each item is keyed by its action: `update`, `create`, ...
+                    let error = item.as_object()?.values().next()?.get("error")?;
+                    Some(
+                        error["reason"]
+                            .as_str()
+                            .map_or_else(|| error.to_string(), str::to_string),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// Collects queued updates into `_bulk` requests of up to `max_actions`, waiting at
+/// most `flush_interval` for a batch to fill, and reports each document's outcome.
+/// Requests go out one at a time, so updates of a document are applied in order.
+async fn flush_updates(
+    client: Elasticsearch,
+    mut updates: mpsc::Receiver<PendingUpdate>,
+    max_actions: usize,
+    flush_interval: Duration,
+) {
+    let mut batch = Vec::with_capacity(max_actions);
+    loop {
+        if updates.recv_many(&mut batch, max_actions).await == 0 {
+            return;
+        }
+        let deadline = tokio::time::Instant::now() + flush_interval;
+        while batch.len() < max_actions {
+            let limit = max_actions - batch.len();
+            match tokio::time::timeout_at(deadline, updates.recv_many(&mut batch, limit)).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+        }
+
+        let mut body = Vec::new();
+        let serialized = batch.iter().try_for_each(|update| {
+            write_bulk_update(&mut body, &update.index, &update.id, &update.fields)
+        });
+        let result = match serialized {
+            Ok(()) => bulk_request(&client, &batch[0].index, body).await,
+            Err(e) => Err(e.into()),
+        };
+
+        match result {
+            Ok(errors) => {
+                let mut errors = errors.into_iter();
+                for update in batch.drain(..) {
+                    let outcome = match errors.next() {
+                        Some(None) => Ok(()),
+                        Some(Some(error)) => Err(format!(
+                            "Failed to update document {}: {}",
+                            update.id, error
+                        )
+                        .into()),
+                        None => Err(format!("No bulk result for document {}", update.id).into()),
+                    };
+                    let _ = update.done.send(outcome);
+                }
+            }
+            Err(e) => {
+                tracing::error!("Bulk update of {} documents failed: {}", batch.len(), e);
+                for update in batch.drain(..) {
+                    let _ = update
+                        .done
+                        .send(Err(format!("Bulk update failed: {}", e).into()));
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TaskStore for ElasticStore {
+    async fn update_event_status(
+        &self,
+        event: &EventKey<'_>,
+        status: TaskStatus,
+        llm_response: &LLMResponse,
+        started_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        let mut fields = event_update_fields(status, llm_response, started_at);
+        self.store_completions(&mut fields).await?;
+        self.update_event(event.message_id, fields).await
+    }
+
+    async fn save_checkpoint(&self, event: &EventKey<'_>, response: &LLMResponse) -> DbResult<()> {
+        let fields = json!({ "checkpoint": checkpoint_document(response) });
+        self.update_event(event.message_id, fields).await
+    }
+
+    async fn load_checkpoint(&self, message_id: &str) -> DbResult<Option<LLMResponse>> {
+        let index = self.event_index(message_id).await?;
+        let source = self.get_document(&index, message_id).await?;
+        Ok(source.and_then(|source| parse_checkpoint(&source["checkpoint"])))
+    }
+
+    async fn create_events(&self, documents: &[Value]) -> DbResult<HashMap<String, u64>> {
+        let index = self.events_index();
+        let mut body = Vec::new();
+        for document in documents {
+            serde_json::to_writer(
+                &mut body,
+                &json!({ "create": { "_index": index, "_id": document["message_id"] }}),
+            )?;
+            body.push(b'\n');
+            serde_json::to_writer(&mut body, document)?;
+            body.push(b'\n');
+        }
+        let errors = bulk_request(&self.client, &index, body).await?;
+        let mut created = HashMap::new();
+        for (document, error) in documents.iter().zip(&errors) {
+            if error.is_none() {
+                let batch_id = document["batch_id"].as_str().unwrap_or_default();
+                *created.entry(batch_id.to_string()).or_insert(0) += 1;
+            }
+        }
+        // A conflict means the event already exists, e.g. from a retried submission.
+        let failed: Vec<_> = errors
+            .into_iter()
+            .flatten()
+            .filter(|error| !error.contains("version conflict"))
+            .collect();
+        if let Some(error) = failed.first() {
+            return Err(format!("Failed to create {} events: {}", failed.len(), error).into());
+        }
+        Ok(created)
+    }
+
+    async fn stale_tasks(
+        &self,
+        started_before: DateTime<Utc>,
+        limit: usize,
+    ) -> DbResult<Vec<StaleTask>> {
+        let query = json!({
+            "query": {
+                "bool": {
+                    "filter": [
+                        { "term": { "status": TaskStatus::Processing.as_str() }},
+                        { "range": { "started_at": { "lt": started_before }}}
+                    ],
+                    "must_not": [{ "exists": { "field": "provider_batch_id" }}]
+                }
+            },
+            "sort": [{ "started_at": "desc" }],
+            "size": limit,
+            "_source": ["message_id", "batch_id", "body_hash", "started_at", TASK_MESSAGE],
+        });
+
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(query)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body["hits"]["hits"]
+            .as_array()
+            .map(|hits| {
+                hits.iter()
+                    .filter_map(|hit| {
+                        let source = &hit["_source"];
+                        let field = |name: &str| Some(source[name].as_str()?.to_string());
+                        Some(StaleTask {
+                            message_id: field("message_id")?,
+                            batch_id: field("batch_id").unwrap_or_default(),
+                            body_hash: field("body_hash").unwrap_or_default(),
+                            started_at: field("started_at")?.parse().ok()?,
+                            message: Some(source[TASK_MESSAGE].clone()).filter(|m| !m.is_null()),
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn reset_stale_task(
+        &self,
+        event: &EventKey<'_>,
+        started_before: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        let index = self.event_index(event.message_id).await?;
+        let response = self
+            .client
+            .update(UpdateParts::IndexId(&index, event.message_id))
+            .body(json!({
+                "script": {
+                    "source": RESET_STALE_SCRIPT,
+                    "params": { "started_before": started_before.timestamp_millis() }
+                }
+            }))
+            .refresh(Refresh::False)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if status.as_u16() == 404 {
+            return Ok(false);
+        }
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Event reset failed ({}): {}", status, error).into());
+        }
+        let response_body = response.json::<Value>().await?;
+        Ok(response_body["result"] == "updated")
+    }
+
+    async fn event_status(&self, message_id: &str) -> DbResult<Option<TaskStatus>> {
+        let index = self.event_index(message_id).await?;
+        let source = self.get_document(&index, message_id).await?;
+        Ok(source
+            .as_ref()
+            .and_then(|source| source["status"].as_str())
+            .and_then(TaskStatus::parse))
+    }
+
+    async fn event_state(&self, message_id: &str) -> DbResult<Option<EventState>> {
+        let index = self.event_index(message_id).await?;
+        let source = self.get_document(&index, message_id).await?;
+        Ok(source.as_ref().and_then(EventState::of))
+    }
+
+    async fn get_cached_completion(&self, body_hash: &str) -> DbResult<Option<LLMResponse>> {
+        let query = json!({
+            "query": {
+                "bool": {
+                    "must": [
+                        { "term": { "status": TaskStatus::Completed.as_str() }},
+                        { "term": { "body_hash": body_hash }}
+                    ],
+                    "must_not": [
+                        { "exists": { "field": TRUNCATED_ANNOTATION }}
+                    ]
+                }
+            },
+            "size": 1,
+        });
+
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(query)
+            .send()
+            .await?;
+
+        let response_body = response.json::<Value>().await?;
+
+        Ok(self
+            .hit_sources(response_body)
+            .await?
+            .first()
+            .and_then(cached_response))
+    }
+
+    async fn cached_samples(&self, body_hash: &str, limit: usize) -> DbResult<Vec<LLMResponse>> {
+        let query = json!({
+            "query": {
+                "bool": {
+                    "filter": [
+                        { "term": { "status": TaskStatus::Completed.as_str() }},
+                        { "term": { "body_hash": body_hash }},
+                        { "term": { "cached": false }}
+                    ],
+                    "must_not": [
+                        { "exists": { "field": TRUNCATED_ANNOTATION }}
+                    ]
+                }
+            },
+            "sort": [{ "completed_at": "asc" }],
+            "size": limit,
+        });
+
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(query)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+
+        let response_body = response.json::<Value>().await?;
+        Ok(self
+            .hit_sources(response_body)
+            .await?
+            .iter()
+            .filter_map(cached_response)
+            .collect())
+    }
+
+    async fn completed_results(
+        &self,
+        batch_id: &str,
+        body_hashes: &[&str],
+    ) -> DbResult<HashMap<String, PreviousResult>> {
+        if body_hashes.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let query = json!({
+            "query": {
+                "bool": {
+                    "filter": [
+                        { "term": { "batch_id": batch_id }},
+                        { "term": { "status": TaskStatus::Completed.as_str() }},
+                        { "terms": { "body_hash": body_hashes }}
+                    ]
+                }
+            },
+            "collapse": { "field": "body_hash" },
+            "size": body_hashes.len(),
+        });
+
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(query)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+
+        let response_body = response.json::<Value>().await?;
+        Ok(self
+            .hit_sources(response_body)
+            .await?
+            .iter()
+            .filter_map(|source| {
+                let result = PreviousResult {
+                    message_id: source["message_id"].as_str()?.to_string(),
+                    response: cached_response(source)?,
+                };
+                Some((source["body_hash"].as_str()?.to_string(), result))
+            })
+            .collect())
+    }
+
+    async fn completed_events(
+        &self,
+        batch_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Value>> {
+        let mut query = json!({
+            "query": {
+                "bool": {
+                    "filter": [
+                        { "term": { "batch_id": batch_id }},
+                        { "term": { "status": TaskStatus::Completed.as_str() }}
+                    ]
+                }
+            },
+            "sort": [{ "message_id": "asc" }],
+            "size": limit,
+            "_source": { "excludes": ["checkpoint", TASK_MESSAGE] },
+        });
+        if let Some(after) = after {
+            query["search_after"] = json!([after]);
+        }
+
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(query)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Search request failed ({}): {}", status, error).into());
+        }
+
+        let response_body = response.json::<Value>().await?;
+        self.hit_sources(response_body).await
+    }
+
+    async fn label_counts(&self, batch_id: &str) -> DbResult<HashMap<String, u64>> {
+        let query = json!({
+            "size": 0,
+            "query": {
+                "bool": {
+                    "must": [{ "term": { "batch_id": batch_id }}],
+                    "must_not": [{ "term": { "balance_excess": true }}]
+                }
+            },
+            "aggs": {
+                "labels": { "terms": { "field": "balance_label", "size": 1000 }}
+            }
+        });
+
+        let response = self
+            .client
+            .search(SearchParts::Index(&[&self.events_pattern()]))
+            .body(query)
+            .send()
+            .await?;
+
+        let response_body = response.json::<Value>().await?;
+        let counts = response_body["aggregations"]["labels"]["buckets"]
+            .as_array()
+            .map(|buckets| {
+                buckets
+                    .iter()
+                    .filter_map(|b| {
+                        Some((b["key"].as_str()?.to_string(), b["doc_count"].as_u64()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(counts)
+    }
+
+    async fn update_batch_counts(
+        &self,
+        batch_id: &str,
+        from: Option<TaskStatus>,
+        to: TaskStatus,
+        count: u64,
+    ) -> DbResult<BatchProgress> {
+        let now = Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true);
+        let response = self
+            .client
+            .update(UpdateParts::IndexId(&self.indices.batches, batch_id))
+            .body(json!({
+                "script": {
+                    "source": BATCH_COUNTS_SCRIPT,
+                    "params": {
+                        "from": from.map(|from| from.as_str().to_lowercase()),
+                        "to": to.as_str().to_lowercase(),
+                        "count": count,
+                        "now": now,
+                    }
+                },
+                "scripted_upsert": true,
+                "upsert": { "batch_id": batch_id },
+            }))
+            .retry_on_conflict(BATCH_UPDATE_RETRIES)
+            ._source(&["true"])
+            .refresh(Refresh::False)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Batch counts update failed ({}): {}", status, error).into());
+        }
+
+        let response_body = response.json::<Value>().await?;
+        let source = &response_body["get"]["_source"];
+        let counts: BatchCounts = serde_json::from_value(source.clone())?;
+        // `finished_at` is only stamped by the update that finished the batch.
+        let just_finished = source["finished_at"].as_str() == Some(now.as_str());
+        Ok(BatchProgress {
+            counts,
+            just_finished,
+            callback_url: source["callback_url"].as_str().map(str::to_string),
+        })
+    }
+
+    async fn batch_counts(&self, batch_id: &str) -> DbResult<Option<BatchCounts>> {
+        match self.get_document(&self.indices.batches, batch_id).await? {
+            Some(source) => Ok(Some(serde_json::from_value(source)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn set_batch_callback_url(&self, batch_id: &str, callback_url: &str) -> DbResult<()> {
+        let response = self
+            .client
+            .update(UpdateParts::IndexId(&self.indices.batches, batch_id))
+            .body(json!({
+                "doc": { "batch_id": batch_id, "callback_url": callback_url },
+                "doc_as_upsert": true,
+            }))
+            .retry_on_conflict(BATCH_UPDATE_RETRIES)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Batch callback update failed ({}): {}", status, error).into());
+        }
+        Ok(())
+    }
+
+    async fn record_evaluation(&self, evaluation: &ScoredEvaluation<'_>) -> DbResult<()> {
+        let id = format!("{}:{}", evaluation.suite, evaluation.model);
+        let response = self
+            .client
+            .update(UpdateParts::IndexId(&self.indices.leaderboard, &id))
+            .body(json!({
+                "script": {
+                    "source": LEADERBOARD_SCRIPT,
+                    "params": {
+                        "correct": evaluation.correct,
+                        "score": evaluation.score,
+                        "now": Utc::now(),
+                    }
+                },
+                "scripted_upsert": true,
+                "upsert": { "suite": evaluation.suite, "model": evaluation.model },
+            }))
+            .retry_on_conflict(BATCH_UPDATE_RETRIES)
+            .send()
+            .await?;
+        let status = response.status_code();
+        if !status.is_success() {
+            let error = response.text().await.unwrap_or_default();
+            return Err(format!("Leaderboard update failed ({}): {}", status, error).into());
+        }
+        Ok(())
+    }
+
+    async fn index_embeddings(
+        &self,
+        index: &str,
+        documents: &[EmbeddingDocument<'_>],
+    ) -> DbResult<()> {
+        self.ensure_vector_index(index).await?;
+        let now = Utc::now();
+        let mut body = Vec::new();
+        for document in documents {
+            let id = format!("{}:{}", document.message_id, document.position);
+            serde_json::to_writer(
+                &mut body,
+                &json!({ "index": { "_index": index, "_id": id }}),
+            )?;
+            body.push(b'\n');
+            serde_json::to_writer(
+                &mut body,
+                &json!({
+                    "message_id": document.message_id,
+                    "batch_id": document.batch_id,
+                    "position": document.position,
+                    "input": document.input,
+                    "model": document.model,
+                    "dimensions": document.vector.len(),
+                    "vector": document.vector,
+                    "created_at": now,
+                }),
+            )?;
+            body.push(b'\n');
+        }
+        let errors = bulk_request(&self.client, index, body).await?;
+        let failed: Vec<_> = errors.into_iter().flatten().collect();
+        if let Some(error) = failed.first() {
+            return Err(format!("Failed to index {} vectors: {}", failed.len(), error).into());
+        }
+        Ok(())
+    }
+}
+
+/// Name of the snapshot taken before `operation` at `now`, lowercase as snapshot
+/// names must be.
+pub fn snapshot_name(operation: &str, now: DateTime<Utc>) -> String {
+    format!("synthgen-{}-{}", operation, now.format("%Y.%m.%d-%H.%M.%S")).to_lowercase()
+}
+
+/// Index an events index (or alias) `name` is migrated to at `now`.
+pub fn migrated_index_name(name: &str, now: DateTime<Utc>) -> String {
+    format!("{}-migrated-{}", name, now.format("%Y%m%d%H%M%S"))
+}
+
+/// Index events created at `now` are written to, under the rollover of `indices`.
+pub fn events_index(indices: &IndexSettings, now: DateTime<Utc>) -> String {
+    match indices.rollover {
+        Some(rollover) => format!("{}-{}", indices.events, now.format(rollover.date_format())),
+        None => indices.events.clone(),
+    }
+}
+
+/// Response of a completed event, as served from the cache.
+pub(super) fn cached_response(source: &Value) -> Option<LLMResponse> {
+    let completions = source["completions"].as_object()?;
+    Some(LLMResponse {
+        completions: Value::Object(completions.clone()),
+        cached: true,
+        attempt: 0,
+        started_at: source["started_at"]
+            .as_str()
+            .and_then(|s| s.parse::<DateTime<Utc>>().ok())
+            .unwrap_or_else(Utc::now),
+        completed_at: source["completed_at"]
+            .as_str()
+            .and_then(|s| s.parse::<DateTime<Utc>>().ok())
+            .unwrap_or_else(Utc::now),
+        annotations: Default::default(),
+        usage: None,
+        cost: None,
+    })
+}
+
+impl Clone for ElasticStore {
+    fn clone(&self) -> Self {
+        ElasticStore {
+            client: self.client.clone(),
+            writer: self.writer.clone(),
+            indices: self.indices.clone(),
+            snapshot_repository: self.snapshot_repository.clone(),
+            vector_indices: self.vector_indices.clone(),
+            event_indices: self.event_indices.clone(),
+            content_addressed: self.content_addressed,
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Store of standalone runs without Elasticsearch or PostgreSQL
+//! (`STORAGE_BACKEND=jsonl`). Events are kept in memory for the run, in the shape of
+//! `events` documents, and appended to `JSONL_OUTPUT` as a line each time a task reaches
+//! a final status. The file is truncated when the store is opened. Leaderboards aren't
+//! kept, and `embedding` tasks fail for lack of a vector index.
+
+use super::elastic::cached_response;
+use super::{
+    checkpoint_document, event_update_fields, parse_checkpoint, BatchCounts, BatchProgress,
+    DbResult, EmbeddingDocument, EventKey, PreviousResult, ScoredEvaluation, StaleTask, TaskStore,
+    TASK_MESSAGE,
+};
+use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::task_status::TaskStatus;
+use crate::truncation::TRUNCATED_ANNOTATION;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::sync::Mutex;
+
+#[derive(Default)]
+struct BatchEntry {
+    counts: BatchCounts,
+    finished: bool,
+    callback_url: Option<String>,
+}
+
+pub struct JsonlStore {
+    output: Mutex<LineWriter<File>>,
+    events: Mutex<HashMap<String, Value>>,
+    batches: Mutex<HashMap<String, BatchEntry>>,
+}
+
+impl JsonlStore {
+    pub fn create(path: &str) -> DbResult<Self> {
+        Ok(Self {
+            output: Mutex::new(LineWriter::new(File::create(path)?)),
+            events: Mutex::new(HashMap::new()),
+            batches: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn write(&self, event: &Value) -> DbResult<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.output.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+
+    /// Events matching `filter`, in no particular order.
+    fn find(&self, filter: impl Fn(&Value) -> bool) -> Vec<Value> {
+        self.events
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|event| filter(event))
+            .cloned()
+            .collect()
+    }
+}
+
+fn status_of(event: &Value) -> Option<TaskStatus> {
+    event["status"].as_str().and_then(TaskStatus::parse)
+}
+
+fn timestamp(event: &Value, field: &str) -> Option<DateTime<Utc>> {
+    event[field].as_str()?.parse().ok()
+}
+
+/// Completed event whose completions can be served from the cache.
+fn is_cacheable(event: &Value, body_hash: &str) -> bool {
+    status_of(event) == Some(TaskStatus::Completed)
+        && event["body_hash"] == body_hash
+        && event.get(TRUNCATED_ANNOTATION).is_none()
+}
+
+#[async_trait]
+impl TaskStore for JsonlStore {
+    async fn update_event_status(
+        &self,
+        event: &EventKey<'_>,
+        status: TaskStatus,
+        llm_response: &LLMResponse,
+        started_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        let fields = event_update_fields(status, llm_response, started_at);
+        let finished = {
+            let mut events = self.events.lock().unwrap();
+            let document = events
+                .entry(event.message_id.to_string())
+                .or_insert_with(|| {
+                    json!({
+                        "message_id": event.message_id,
+                        "batch_id": event.batch_id,
+                        "body_hash": event.body_hash,
+                    })
+                });
+            let previous = status_of(document);
+            if let (Some(document), Some(fields)) = (document.as_object_mut(), fields.as_object()) {
+                for (field, value) in fields {
+                    document.insert(field.clone(), value.clone());
+                }
+            }
+            // A redelivered task finishing again isn't written twice.
+            (matches!(status, TaskStatus::Completed | TaskStatus::Failed)
+                && previous != Some(status))
+            .then(|| document.clone())
+        };
+        match finished {
+            Some(document) => self.write(&document),
+            None => Ok(()),
+        }
+    }
+
+    async fn create_events(&self, documents: &[Value]) -> DbResult<HashMap<String, u64>> {
+        let mut events = self.events.lock().unwrap();
+        let mut created = HashMap::new();
+        for document in documents {
+            let message_id = document["message_id"].as_str().unwrap_or_default();
+            if events.contains_key(message_id) {
+                continue;
+            }
+            events.insert(message_id.to_string(), document.clone());
+            let batch_id = document["batch_id"].as_str().unwrap_or_default();
+            *created.entry(batch_id.to_string()).or_insert(0) += 1;
+        }
+        Ok(created)
+    }
+
+    async fn save_checkpoint(&self, event: &EventKey<'_>, response: &LLMResponse) -> DbResult<()> {
+        if let Some(document) = self.events.lock().unwrap().get_mut(event.message_id) {
+            document["checkpoint"] = checkpoint_document(response);
+        }
+        Ok(())
+    }
+
+    async fn load_checkpoint(&self, message_id: &str) -> DbResult<Option<LLMResponse>> {
+        Ok(self
+            .events
+            .lock()
+            .unwrap()
+            .get(message_id)
+            .and_then(|document| parse_checkpoint(&document["checkpoint"])))
+    }
+
+    async fn stale_tasks(
+        &self,
+        started_before: DateTime<Utc>,
+        limit: usize,
+    ) -> DbResult<Vec<StaleTask>> {
+        let mut stale: Vec<StaleTask> = self
+            .find(|event| {
+                status_of(event) == Some(TaskStatus::Processing)
+                    && event.get("provider_batch_id").is_none()
+            })
+            .into_iter()
+            .filter_map(|event| {
+                Some(StaleTask {
+                    message_id: event["message_id"].as_str()?.to_string(),
+                    batch_id: event["batch_id"].as_str()?.to_string(),
+                    body_hash: event["body_hash"].as_str()?.to_string(),
+                    started_at: timestamp(&event, "started_at")
+                        .filter(|started_at| *started_at < started_before)?,
+                    message: Some(event[TASK_MESSAGE].clone()).filter(|m| !m.is_null()),
+                })
+            })
+            .collect();
+        stale.sort_by_key(|task| std::cmp::Reverse(task.started_at));
+        stale.truncate(limit);
+        Ok(stale)
+    }
+
+    async fn reset_stale_task(
+        &self,
+        event: &EventKey<'_>,
+        started_before: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        let mut events = self.events.lock().unwrap();
+        let Some(document) = events.get_mut(event.message_id) else {
+            return Ok(false);
+        };
+        let stale = status_of(document) == Some(TaskStatus::Processing)
+            && timestamp(document, "started_at").is_some_and(|at| at < started_before);
+        if stale {
+            document["status"] = json!(TaskStatus::Pending.as_str());
+            document["started_at"] = Value::Null;
+        }
+        Ok(stale)
+    }
+
+    async fn event_status(&self, message_id: &str) -> DbResult<Option<TaskStatus>> {
+        Ok(self
+            .events
+            .lock()
+            .unwrap()
+            .get(message_id)
+            .and_then(status_of))
+    }
+
+    async fn get_cached_completion(&self, body_hash: &str) -> DbResult<Option<LLMResponse>> {
+        Ok(self
+            .events
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|event| is_cacheable(event, body_hash))
+            .find_map(cached_response))
+    }
+
+    async fn cached_samples(&self, body_hash: &str, limit: usize) -> DbResult<Vec<LLMResponse>> {
+        let mut samples =
+            self.find(|event| is_cacheable(event, body_hash) && event["cached"] == false);
+        samples.sort_by_key(|event| timestamp(event, "completed_at"));
+        Ok(samples
+            .iter()
+            .filter_map(cached_response)
+            .take(limit)
+            .collect())
+    }
+
+    async fn completed_results(
+        &self,
+        batch_id: &str,
+        body_hashes: &[&str],
+    ) -> DbResult<HashMap<String, PreviousResult>> {
+        let mut results = HashMap::new();
+        for event in self.find(|event| event["batch_id"] == batch_id) {
+            let Some(body_hash) = event["body_hash"].as_str() else {
+                continue;
+            };
+            if !body_hashes.contains(&body_hash)
+                || status_of(&event) != Some(TaskStatus::Completed)
+                || results.contains_key(body_hash)
+            {
+                continue;
+            }
+            let (Some(message_id), Some(response)) =
+                (event["message_id"].as_str(), cached_response(&event))
+            else {
+                continue;
+            };
+            results.insert(
+                body_hash.to_string(),
+                PreviousResult {
+                    message_id: message_id.to_string(),
+                    response,
+                },
+            );
+        }
+        Ok(results)
+    }
+
+    async fn completed_events(
+        &self,
+        batch_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Value>> {
+        let after = after.unwrap_or_default();
+        let mut events = self.find(|event| {
+            event["batch_id"] == batch_id
+                && status_of(event) == Some(TaskStatus::Completed)
+                && event["message_id"].as_str().is_some_and(|id| id > after)
+        });
+        events.sort_by(|a, b| a["message_id"].as_str().cmp(&b["message_id"].as_str()));
+        events.truncate(limit);
+        Ok(events)
+    }
+
+    async fn label_counts(&self, batch_id: &str) -> DbResult<HashMap<String, u64>> {
+        let mut counts = HashMap::new();
+        for event in self.find(|event| event["batch_id"] == batch_id) {
+            if event["balance_excess"] == true {
+                continue;
+            }
+            if let Some(label) = event["balance_label"].as_str() {
+                *counts.entry(label.to_string()).or_insert(0) += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn update_batch_counts(
+        &self,
+        batch_id: &str,
+        from: Option<TaskStatus>,
+        to: TaskStatus,
+        count: u64,
+    ) -> DbResult<BatchProgress> {
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches.entry(batch_id.to_string()).or_default();
+        let was_finished = batch.finished;
+        batch.counts.apply(from, to, count);
+        batch.finished = batch.counts.is_finished();
+        Ok(BatchProgress {
+            counts: batch.counts.clone(),
+            just_finished: batch.finished && !was_finished,
+            callback_url: batch.callback_url.clone(),
+        })
+    }
+
+    async fn batch_counts(&self, batch_id: &str) -> DbResult<Option<BatchCounts>> {
+        Ok(self
+            .batches
+            .lock()
+            .unwrap()
+            .get(batch_id)
+            .map(|batch| batch.counts.clone()))
+    }
+
+    async fn set_batch_callback_url(&self, batch_id: &str, callback_url: &str) -> DbResult<()> {
+        self.batches
+            .lock()
+            .unwrap()
+            .entry(batch_id.to_string())
+            .or_default()
+            .callback_url = Some(callback_url.to_string());
+        Ok(())
+    }
+
+    // The evaluation is on the task's event in the output.
+    async fn record_evaluation(&self, _evaluation: &ScoredEvaluation<'_>) -> DbResult<()> {
+        Ok(())
+    }
+
+    async fn index_embeddings(
+        &self,
+        _index: &str,
+        _documents: &[EmbeddingDocument<'_>],
+    ) -> DbResult<()> {
+        Err("The JSONL store has no vector index for embeddings".into())
+    }
+}
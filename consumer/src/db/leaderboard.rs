@@ -0,0 +1,172 @@
+//! Per-model accuracy on each eval suite, kept in the `leaderboard` index (or table) as
+//! the benchmark-style tasks of eval runs finish, so models can be compared on a suite
+//! across batches with one lookup rather than an aggregation over their events.
+//!
+//! A task counts once, on its first final status, when its `evaluation` annotation
+//! names a suite and was scored; redeliveries of a finished task aren't counted again.
+//! The events stay the source of truth, so a failed update is logged rather than
+//! failing the write.
+
+use super::{
+    scored_evaluation, BatchCounts, BatchProgress, DbResult, EmbeddingDocument, EventKey,
+    EventState, PreviousResult, ScoredEvaluation, StaleTask, TaskStore,
+};
+use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::task_status::TaskStatus;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Keeps the leaderboards of the evaluations written through `inner`.
+pub struct LeaderboardStore {
+    inner: Arc<dyn TaskStore>,
+}
+
+impl LeaderboardStore {
+    pub fn new(inner: Arc<dyn TaskStore>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl TaskStore for LeaderboardStore {
+    async fn update_event_status(
+        &self,
+        event: &EventKey<'_>,
+        status: TaskStatus,
+        llm_response: &LLMResponse,
+        started_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        let Some(evaluation) = scored_evaluation(status, llm_response) else {
+            return self
+                .inner
+                .update_event_status(event, status, llm_response, started_at)
+                .await;
+        };
+        let previous = self.inner.event_status(event.message_id).await;
+        self.inner
+            .update_event_status(event, status, llm_response, started_at)
+            .await?;
+        match previous {
+            Ok(Some(TaskStatus::Completed | TaskStatus::Failed)) => {}
+            Ok(_) => {
+                if let Err(e) = self.inner.record_evaluation(&evaluation).await {
+                    tracing::error!(
+                        "Failed to update the {} leaderboard of suite {}: {}",
+                        evaluation.model,
+                        evaluation.suite,
+                        e
+                    );
+                }
+            }
+            Err(e) => tracing::warn!(
+                "Leaderboard of suite {} not updated, failed to read the status of {}: {}",
+                evaluation.suite,
+                event.message_id,
+                e
+            ),
+        }
+        Ok(())
+    }
+
+    async fn create_events(&self, documents: &[Value]) -> DbResult<HashMap<String, u64>> {
+        self.inner.create_events(documents).await
+    }
+
+    async fn save_checkpoint(&self, event: &EventKey<'_>, response: &LLMResponse) -> DbResult<()> {
+        self.inner.save_checkpoint(event, response).await
+    }
+
+    async fn load_checkpoint(&self, message_id: &str) -> DbResult<Option<LLMResponse>> {
+        self.inner.load_checkpoint(message_id).await
+    }
+
+    async fn stale_tasks(
+        &self,
+        started_before: DateTime<Utc>,
+        limit: usize,
+    ) -> DbResult<Vec<StaleTask>> {
+        self.inner.stale_tasks(started_before, limit).await
+    }
+
+    async fn reset_stale_task(
+        &self,
+        event: &EventKey<'_>,
+        started_before: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        self.inner.reset_stale_task(event, started_before).await
+    }
+
+    async fn event_status(&self, message_id: &str) -> DbResult<Option<TaskStatus>> {
+        self.inner.event_status(message_id).await
+    }
+
+    async fn event_state(&self, message_id: &str) -> DbResult<Option<EventState>> {
+        self.inner.event_state(message_id).await
+    }
+
+    async fn get_cached_completion(&self, body_hash: &str) -> DbResult<Option<LLMResponse>> {
+        self.inner.get_cached_completion(body_hash).await
+    }
+
+    async fn cached_samples(&self, body_hash: &str, limit: usize) -> DbResult<Vec<LLMResponse>> {
+        self.inner.cached_samples(body_hash, limit).await
+    }
+
+    async fn completed_results(
+        &self,
+        batch_id: &str,
+        body_hashes: &[&str],
+    ) -> DbResult<HashMap<String, PreviousResult>> {
+        self.inner.completed_results(batch_id, body_hashes).await
+    }
+
+    async fn completed_events(
+        &self,
+        batch_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Value>> {
+        self.inner.completed_events(batch_id, after, limit).await
+    }
+
+    async fn label_counts(&self, batch_id: &str) -> DbResult<HashMap<String, u64>> {
+        self.inner.label_counts(batch_id).await
+    }
+
+    async fn update_batch_counts(
+        &self,
+        batch_id: &str,
+        from: Option<TaskStatus>,
+        to: TaskStatus,
+        count: u64,
+    ) -> DbResult<BatchProgress> {
+        self.inner
+            .update_batch_counts(batch_id, from, to, count)
+            .await
+    }
+
+    async fn batch_counts(&self, batch_id: &str) -> DbResult<Option<BatchCounts>> {
+        self.inner.batch_counts(batch_id).await
+    }
+
+    async fn set_batch_callback_url(&self, batch_id: &str, callback_url: &str) -> DbResult<()> {
+        self.inner
+            .set_batch_callback_url(batch_id, callback_url)
+            .await
+    }
+
+    async fn record_evaluation(&self, evaluation: &ScoredEvaluation<'_>) -> DbResult<()> {
+        self.inner.record_evaluation(evaluation).await
+    }
+
+    async fn index_embeddings(
+        &self,
+        index: &str,
+        documents: &[EmbeddingDocument<'_>],
+    ) -> DbResult<()> {
+        self.inner.index_embeddings(index, documents).await
+    }
+}
@@ -0,0 +1,364 @@
+//! Asynchronous replication of event writes to a secondary Elasticsearch cluster,
+//! so generated datasets survive the loss of the primary's region.
+//!
+//! Writes go to the primary first and are then queued for the secondary; a full
+//! queue drops the mirrored copy rather than slowing the pipeline down. The
+//! secondary's index templates and indexes are created on connecting, like the
+//! primary's, unless `ELASTICSEARCH_BOOTSTRAP=false`.
+
+use super::elastic::ElasticStore;
+use super::{
+    event_update_fields, BatchCounts, BatchProgress, DbResult, EmbeddingDocument, EventKey,
+    EventState, PreviousResult, ScoredEvaluation, StaleTask, TaskStore,
+};
+use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::task_status::TaskStatus;
+use crate::settings::MirrorSettings;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const WRITE_ATTEMPTS: u32 = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+const STATS_INTERVAL: Duration = Duration::from_secs(30);
+
+struct MirrorWrite {
+    message_id: String,
+    fields: Value,
+    enqueued_at: Instant,
+}
+
+/// Replication counters, logged periodically by the mirror worker.
+#[derive(Default)]
+pub struct MirrorStats {
+    queued: AtomicU64,
+    replicated: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+    lag_ms: AtomicU64,
+}
+
+impl MirrorStats {
+    /// Writes queued but not yet replicated or given up on.
+    pub fn pending(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed).saturating_sub(
+            self.replicated.load(Ordering::Relaxed) + self.failed.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Writes successfully applied to the secondary.
+    pub fn replicated(&self) -> u64 {
+        self.replicated.load(Ordering::Relaxed)
+    }
+
+    /// Writes skipped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes the secondary rejected or that failed every attempt.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Time the oldest write of the last replicated batch spent in the queue.
+    pub fn lag(&self) -> Duration {
+        Duration::from_millis(self.lag_ms.load(Ordering::Relaxed))
+    }
+}
+
+/// Writes to `primary` and mirrors every event update to a secondary cluster.
+/// Reads are served by the primary.
+pub struct MirroredStore {
+    primary: Arc<dyn TaskStore>,
+    queue: mpsc::Sender<MirrorWrite>,
+    stats: Arc<MirrorStats>,
+}
+
+impl MirroredStore {
+    pub async fn new(primary: Arc<dyn TaskStore>, settings: &MirrorSettings) -> DbResult<Self> {
+        let secondary = ElasticStore::new(&settings.elasticsearch).await?;
+        if settings.elasticsearch.indices.bootstrap {
+            if let Err(e) = secondary.bootstrap().await {
+                tracing::warn!("Failed to bootstrap the secondary's indexes: {}", e);
+            }
+        }
+        let (queue, receiver) = mpsc::channel(settings.queue_capacity.max(1));
+        let stats = Arc::new(MirrorStats::default());
+
+        tokio::spawn(replicate(
+            secondary,
+            receiver,
+            settings.batch_size.max(1),
+            stats.clone(),
+        ));
+        tracing::info!(
+            "Mirroring event writes to Elasticsearch at {}:{}",
+            settings.elasticsearch.host,
+            settings.elasticsearch.port
+        );
+
+        Ok(Self {
+            primary,
+            queue,
+            stats,
+        })
+    }
+
+    pub fn stats(&self) -> &MirrorStats {
+        &self.stats
+    }
+
+    /// Queues an upsert of `fields` on the secondary, or drops it when the queue is full.
+    fn mirror(&self, message_id: &str, fields: Value) {
+        let write = MirrorWrite {
+            message_id: message_id.to_string(),
+            fields,
+            enqueued_at: Instant::now(),
+        };
+        match self.queue.try_send(write) {
+            Ok(()) => {
+                self.stats.queued.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!("Mirror queue full, event {} not replicated", message_id);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TaskStore for MirroredStore {
+    async fn update_event_status(
+        &self,
+        event: &EventKey<'_>,
+        status: TaskStatus,
+        llm_response: &LLMResponse,
+        started_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        self.primary
+            .update_event_status(event, status, llm_response, started_at)
+            .await?;
+
+        let mut fields = event_update_fields(status, llm_response, started_at);
+        // The secondary never sees the API's initial write, so carry the identifying
+        // fields along for the upsert.
+        if let Some(fields) = fields.as_object_mut() {
+            fields.insert("message_id".to_string(), json!(event.message_id));
+            fields.insert("batch_id".to_string(), json!(event.batch_id));
+            fields.insert("body_hash".to_string(), json!(event.body_hash));
+        }
+        self.mirror(event.message_id, fields);
+        Ok(())
+    }
+
+    async fn create_events(&self, documents: &[Value]) -> DbResult<HashMap<String, u64>> {
+        let created = self.primary.create_events(documents).await?;
+        for document in documents {
+            self.mirror(
+                document["message_id"].as_str().unwrap_or_default(),
+                document.clone(),
+            );
+        }
+        Ok(created)
+    }
+
+    // Checkpoints are recovery state of the primary and aren't replicated.
+    async fn save_checkpoint(&self, event: &EventKey<'_>, response: &LLMResponse) -> DbResult<()> {
+        self.primary.save_checkpoint(event, response).await
+    }
+
+    async fn load_checkpoint(&self, message_id: &str) -> DbResult<Option<LLMResponse>> {
+        self.primary.load_checkpoint(message_id).await
+    }
+
+    // Tasks are resumed off the primary; the mirror catches up with their next status.
+    async fn stale_tasks(
+        &self,
+        started_before: DateTime<Utc>,
+        limit: usize,
+    ) -> DbResult<Vec<StaleTask>> {
+        self.primary.stale_tasks(started_before, limit).await
+    }
+
+    async fn reset_stale_task(
+        &self,
+        event: &EventKey<'_>,
+        started_before: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        self.primary.reset_stale_task(event, started_before).await
+    }
+
+    async fn event_status(&self, message_id: &str) -> DbResult<Option<TaskStatus>> {
+        self.primary.event_status(message_id).await
+    }
+
+    async fn event_state(&self, message_id: &str) -> DbResult<Option<EventState>> {
+        self.primary.event_state(message_id).await
+    }
+
+    async fn get_cached_completion(&self, body_hash: &str) -> DbResult<Option<LLMResponse>> {
+        self.primary.get_cached_completion(body_hash).await
+    }
+
+    async fn cached_samples(&self, body_hash: &str, limit: usize) -> DbResult<Vec<LLMResponse>> {
+        self.primary.cached_samples(body_hash, limit).await
+    }
+
+    async fn completed_results(
+        &self,
+        batch_id: &str,
+        body_hashes: &[&str],
+    ) -> DbResult<HashMap<String, PreviousResult>> {
+        self.primary.completed_results(batch_id, body_hashes).await
+    }
+
+    async fn completed_events(
+        &self,
+        batch_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Value>> {
+        self.primary.completed_events(batch_id, after, limit).await
+    }
+
+    async fn label_counts(&self, batch_id: &str) -> DbResult<HashMap<String, u64>> {
+        self.primary.label_counts(batch_id).await
+    }
+
+    // Batch counts describe the primary's events and aren't replicated.
+    async fn update_batch_counts(
+        &self,
+        batch_id: &str,
+        from: Option<TaskStatus>,
+        to: TaskStatus,
+        count: u64,
+    ) -> DbResult<BatchProgress> {
+        self.primary
+            .update_batch_counts(batch_id, from, to, count)
+            .await
+    }
+
+    async fn batch_counts(&self, batch_id: &str) -> DbResult<Option<BatchCounts>> {
+        self.primary.batch_counts(batch_id).await
+    }
+
+    async fn set_batch_callback_url(&self, batch_id: &str, callback_url: &str) -> DbResult<()> {
+        self.primary
+            .set_batch_callback_url(batch_id, callback_url)
+            .await
+    }
+
+    // Leaderboards describe the primary's events and aren't replicated.
+    async fn record_evaluation(&self, evaluation: &ScoredEvaluation<'_>) -> DbResult<()> {
+        self.primary.record_evaluation(evaluation).await
+    }
+
+    // Vector indexes are only kept on the primary.
+    async fn index_embeddings(
+        &self,
+        index: &str,
+        documents: &[EmbeddingDocument<'_>],
+    ) -> DbResult<()> {
+        self.primary.index_embeddings(index, documents).await
+    }
+}
+
+/// Drains the queue into `_bulk` upserts on the secondary, in enqueue order.
+async fn replicate(
+    secondary: ElasticStore,
+    mut receiver: mpsc::Receiver<MirrorWrite>,
+    batch_size: usize,
+    stats: Arc<MirrorStats>,
+) {
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut report = tokio::time::interval(STATS_INTERVAL);
+
+    loop {
+        tokio::select! {
+            received = receiver.recv_many(&mut batch, batch_size) => {
+                if received == 0 {
+                    break;
+                }
+            }
+            _ = report.tick() => {
+                tracing::info!(
+                    pending = stats.pending(),
+                    replicated = stats.replicated(),
+                    dropped = stats.dropped(),
+                    failed = stats.failed(),
+                    lag_ms = stats.lag().as_millis() as u64,
+                    "Elasticsearch mirror replication"
+                );
+                continue;
+            }
+        }
+
+        let count = batch.len() as u64;
+        let oldest = batch.iter().map(|w| w.enqueued_at).min();
+        match write_batch(&secondary, &batch).await {
+            Ok(failed) => {
+                stats
+                    .replicated
+                    .fetch_add(count - failed as u64, Ordering::Relaxed);
+                stats.failed.fetch_add(failed as u64, Ordering::Relaxed);
+                if failed > 0 {
+                    tracing::warn!("{} mirrored event writes rejected by the secondary", failed);
+                }
+            }
+            Err(e) => {
+                stats.failed.fetch_add(count, Ordering::Relaxed);
+                tracing::error!("Failed to mirror {} event writes: {}", count, e);
+            }
+        }
+        if let Some(oldest) = oldest {
+            stats
+                .lag_ms
+                .store(oldest.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+        batch.clear();
+    }
+}
+
+async fn write_batch(secondary: &ElasticStore, batch: &[MirrorWrite]) -> DbResult<usize> {
+    let mut body = Vec::new();
+    for write in batch {
+        let index = secondary.event_index(&write.message_id).await?;
+        serde_json::to_writer(
+            &mut body,
+            &json!({ "update": { "_index": index, "_id": write.message_id }}),
+        )?;
+        body.push(b'\n');
+        serde_json::to_writer(
+            &mut body,
+            &json!({ "doc": write.fields, "doc_as_upsert": true }),
+        )?;
+        body.push(b'\n');
+    }
+
+    let mut delay = RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        match secondary.bulk(body.clone()).await {
+            Ok(failed) => return Ok(failed),
+            Err(e) if attempt < WRITE_ATTEMPTS => {
+                tracing::warn!(
+                    "Mirror write attempt {}/{} failed: {}",
+                    attempt,
+                    WRITE_ATTEMPTS,
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,438 @@
+//! Task event storage, so the consumer can record results in Elasticsearch or
+//! PostgreSQL, or in a JSONL file in standalone mode.
+
+pub mod elastic;
+pub mod jsonl;
+pub mod leaderboard;
+pub mod mirror;
+pub mod postgres;
+pub mod progress;
+pub mod redis_cache;
+
+use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::task_status::TaskStatus;
+use crate::settings::{StorageBackend, StorageSettings};
+use crate::webhook::Webhooks;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub type DbResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Identifies the event of a task. Stores that don't get their rows created by the
+/// API use the batch and body hash to create them on first write.
+pub struct EventKey<'a> {
+    pub message_id: &'a str,
+    pub batch_id: &'a str,
+    pub body_hash: &'a str,
+}
+
+/// Completed event of an earlier run whose result can be carried over to a task with
+/// the same body.
+pub struct PreviousResult {
+    pub message_id: String,
+    pub response: LLMResponse,
+}
+
+/// Status of a task's event as its deliveries are checked against it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventState {
+    pub status: TaskStatus,
+    /// Cancelled by `synthgen-admin`, see `admin::CANCELLED`.
+    pub cancelled: bool,
+}
+
+impl EventState {
+    /// State of an `events` document.
+    pub fn of(document: &Value) -> Option<Self> {
+        Some(Self {
+            status: document["status"].as_str().and_then(TaskStatus::parse)?,
+            cancelled: document[crate::admin::CANCELLED] == true,
+        })
+    }
+
+    /// Whether a delivery of the task is acked without processing it: it completed
+    /// already, or was cancelled while its message waited in a queue.
+    pub fn skips_delivery(&self) -> bool {
+        self.status == TaskStatus::Completed || self.cancelled
+    }
+}
+
+/// Task counts of a batch by the status of their events, kept in the `batches` index
+/// (or table) as tasks change status.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchCounts {
+    #[serde(default)]
+    pub total: u64,
+    #[serde(default)]
+    pub pending: u64,
+    #[serde(default)]
+    pub processing: u64,
+    #[serde(default)]
+    pub completed: u64,
+    #[serde(default)]
+    pub failed: u64,
+}
+
+impl BatchCounts {
+    /// Moves `count` tasks from `from` to `to`; tasks without a previous status join
+    /// the batch. Counts never go below zero.
+    pub fn apply(&mut self, from: Option<TaskStatus>, to: TaskStatus, count: u64) {
+        match from {
+            Some(from) => {
+                let from = self.count_mut(from);
+                *from = from.saturating_sub(count);
+            }
+            None => self.total += count,
+        }
+        *self.count_mut(to) += count;
+    }
+
+    /// Whether every task of the batch reached a final status.
+    pub fn is_finished(&self) -> bool {
+        self.total > 0 && self.pending == 0 && self.processing == 0
+    }
+
+    fn count_mut(&mut self, status: TaskStatus) -> &mut u64 {
+        match status {
+            TaskStatus::Pending => &mut self.pending,
+            TaskStatus::Processing => &mut self.processing,
+            TaskStatus::Completed => &mut self.completed,
+            TaskStatus::Failed => &mut self.failed,
+        }
+    }
+}
+
+/// Task stuck in PROCESSING, e.g. after its consumer crashed.
+pub struct StaleTask {
+    pub message_id: String,
+    pub batch_id: String,
+    pub body_hash: String,
+    pub started_at: DateTime<Utc>,
+    /// Message the task can be published again from, unless its event predates
+    /// `TASK_MESSAGE`.
+    pub message: Option<Value>,
+}
+
+impl StaleTask {
+    pub fn event_key(&self) -> EventKey<'_> {
+        EventKey {
+            message_id: &self.message_id,
+            batch_id: &self.batch_id,
+            body_hash: &self.body_hash,
+        }
+    }
+}
+
+/// Annotation of a PROCESSING status update holding the task's message, kept on the
+/// event until its final status so the task can be resumed if its consumer dies.
+pub const TASK_MESSAGE: &str = "task_message";
+
+/// Scored evaluation of a task belonging to an eval suite, counted on the suite's
+/// leaderboard under the model that answered it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoredEvaluation<'a> {
+    pub suite: &'a str,
+    pub model: &'a str,
+    pub correct: bool,
+    pub score: f64,
+}
+
+/// Leaderboard entry of a task reaching `status`: only final statuses of tasks whose
+/// `evaluation` annotation names a suite and a model and could be scored count.
+pub fn scored_evaluation(
+    status: TaskStatus,
+    llm_response: &LLMResponse,
+) -> Option<ScoredEvaluation<'_>> {
+    if !matches!(status, TaskStatus::Completed | TaskStatus::Failed) {
+        return None;
+    }
+    let evaluation = llm_response.annotations.get("evaluation")?;
+    Some(ScoredEvaluation {
+        suite: evaluation["suite"].as_str()?,
+        model: evaluation["model"].as_str()?,
+        correct: evaluation["correct"].as_bool()?,
+        score: evaluation["score"].as_f64()?,
+    })
+}
+
+/// Vector of one input of an `embedding` task, written to a vector index under
+/// `{message_id}:{position}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbeddingDocument<'a> {
+    pub message_id: &'a str,
+    pub batch_id: &'a str,
+    /// Position of the input in the task's `input` list.
+    pub position: usize,
+    /// The embedded text, unless the input was given as tokens.
+    pub input: Option<&'a str>,
+    pub model: Option<&'a str>,
+    pub vector: Vec<f32>,
+}
+
+/// Counts of a batch after an update, and whether that update finished the batch.
+pub struct BatchProgress {
+    pub counts: BatchCounts,
+    pub just_finished: bool,
+    /// Webhook the batch was submitted with, told when it finishes.
+    pub callback_url: Option<String>,
+}
+
+/// Fields written to the events document when a task changes status.
+pub fn event_update_fields(
+    status: TaskStatus,
+    llm_response: &LLMResponse,
+    started_at: DateTime<Utc>,
+) -> Value {
+    let completed_at = Utc::now();
+    let duration = if llm_response.completions != Value::Null {
+        llm_response
+            .completed_at
+            .signed_duration_since(llm_response.started_at)
+            .num_milliseconds() as i32
+    } else {
+        completed_at
+            .signed_duration_since(started_at)
+            .num_milliseconds() as i32
+    };
+
+    let mut fields = json!({
+        "completed_at": completed_at,
+        "started_at": started_at,
+        "status": status.as_str(),
+        "duration": duration,
+        "cached": llm_response.cached,
+        "attempt": llm_response.attempt,
+        "completions": llm_response.completions
+    });
+    if let Some(fields) = fields.as_object_mut() {
+        if matches!(status, TaskStatus::Completed | TaskStatus::Failed) {
+            fields.insert("checkpoint".to_string(), Value::Null);
+            fields.insert(TASK_MESSAGE.to_string(), Value::Null);
+        }
+        if let Some(usage) = &llm_response.usage {
+            fields.insert("prompt_tokens".to_string(), json!(usage.prompt_tokens));
+            fields.insert(
+                "completion_tokens".to_string(),
+                json!(usage.completion_tokens),
+            );
+            fields.insert("total_tokens".to_string(), json!(usage.total_tokens));
+        }
+        if let Some(cost) = llm_response.cost {
+            fields.insert("cost".to_string(), json!(cost));
+        }
+        for (key, value) in &llm_response.annotations {
+            fields.insert(key.clone(), value.clone());
+        }
+    }
+    fields
+}
+
+/// Checkpoint of a task's generation, kept on its event until the final status is
+/// written so a redelivery resumes after the generation instead of paying for it again.
+pub fn checkpoint_document(response: &LLMResponse) -> Value {
+    json!({
+        "completions": response.completions,
+        "annotations": response.annotations,
+        "usage": response.usage,
+        "attempt": response.attempt,
+        "started_at": response.started_at,
+        "completed_at": response.completed_at,
+    })
+}
+
+/// Response saved by `checkpoint_document`.
+pub fn parse_checkpoint(checkpoint: &Value) -> Option<LLMResponse> {
+    let timestamp = |field: &str| checkpoint[field].as_str()?.parse::<DateTime<Utc>>().ok();
+    Some(LLMResponse {
+        completions: checkpoint
+            .get("completions")
+            .filter(|c| !c.is_null())?
+            .clone(),
+        cached: false,
+        attempt: checkpoint["attempt"].as_u64().unwrap_or_default() as u32,
+        started_at: timestamp("started_at")?,
+        completed_at: timestamp("completed_at")?,
+        annotations: checkpoint["annotations"]
+            .as_object()
+            .cloned()
+            .unwrap_or_default(),
+        usage: serde_json::from_value(checkpoint["usage"].clone()).ok(),
+        cost: None,
+    })
+}
+
+/// Appends a partial-update action for `id` to a `_bulk` NDJSON body.
+pub fn write_bulk_update(
+    buf: &mut Vec<u8>,
+    index: &str,
+    id: &str,
+    fields: &Value,
+) -> serde_json::Result<()> {
+    serde_json::to_writer(
+        &mut *buf,
+        &json!({ "update": { "_index": index, "_id": id }}),
+    )?;
+    buf.push(b'\n');
+    serde_json::to_writer(&mut *buf, &json!({ "doc": fields }))?;
+    buf.push(b'\n');
+    Ok(())
+}
+
+#[async_trait]
+pub trait TaskStore: Send + Sync {
+    /// Records a status change of the task, with its completions and annotations.
+    async fn update_event_status(
+        &self,
+        event: &EventKey<'_>,
+        status: TaskStatus,
+        llm_response: &LLMResponse,
+        started_at: DateTime<Utc>,
+    ) -> DbResult<()>;
+
+    /// Creates the PENDING events of newly submitted tasks. Each document carries the
+    /// task's `message_id`, `batch_id` and `body_hash`; existing events are kept.
+    /// Returns the number of events created per batch.
+    async fn create_events(&self, documents: &[Value]) -> DbResult<HashMap<String, u64>>;
+
+    /// Keeps the response of the task's generation on its event, until its final
+    /// status clears it.
+    async fn save_checkpoint(&self, event: &EventKey<'_>, response: &LLMResponse) -> DbResult<()>;
+
+    /// Generation checkpoint of a task whose processing was interrupted after it.
+    async fn load_checkpoint(&self, message_id: &str) -> DbResult<Option<LLMResponse>>;
+
+    /// Up to `limit` tasks PROCESSING since before `started_before`, latest first. Tasks
+    /// handed to a provider's batch API are left out.
+    async fn stale_tasks(
+        &self,
+        started_before: DateTime<Utc>,
+        limit: usize,
+    ) -> DbResult<Vec<StaleTask>>;
+
+    /// Sets the task back to PENDING, unless it's no longer PROCESSING since before
+    /// `started_before`. Returns whether it was reset.
+    async fn reset_stale_task(
+        &self,
+        event: &EventKey<'_>,
+        started_before: DateTime<Utc>,
+    ) -> DbResult<bool>;
+
+    /// Current status of the task's event, if it exists.
+    async fn event_status(&self, message_id: &str) -> DbResult<Option<TaskStatus>>;
+
+    /// Current state of the task's event, if it exists. Stores that can't cancel tasks
+    /// only have its status.
+    async fn event_state(&self, message_id: &str) -> DbResult<Option<EventState>> {
+        Ok(self
+            .event_status(message_id)
+            .await?
+            .map(|status| EventState {
+                status,
+                cancelled: false,
+            }))
+    }
+
+    /// A completed response for an identical request body, if any.
+    async fn get_cached_completion(&self, body_hash: &str) -> DbResult<Option<LLMResponse>>;
+
+    /// Up to `limit` completed responses generated for an identical request body,
+    /// rather than served from the cache, oldest first.
+    async fn cached_samples(&self, body_hash: &str, limit: usize) -> DbResult<Vec<LLMResponse>>;
+
+    /// One completed event of `batch_id` per body hash among `body_hashes`, keyed by
+    /// body hash.
+    async fn completed_results(
+        &self,
+        batch_id: &str,
+        body_hashes: &[&str],
+    ) -> DbResult<HashMap<String, PreviousResult>>;
+
+    /// Up to `limit` COMPLETED events of `batch_id` by message id, starting after the
+    /// message id `after`. Each is a document with its annotations as top-level fields,
+    /// as in the `events` index.
+    async fn completed_events(
+        &self,
+        batch_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Value>>;
+
+    /// Number of accepted (non-excess) rows per topic label in a batch.
+    async fn label_counts(&self, batch_id: &str) -> DbResult<HashMap<String, u64>>;
+
+    /// Atomically moves `count` tasks of `batch_id` from `from` to `to` in the batch's
+    /// counts, adding them to the batch when `from` is `None`.
+    async fn update_batch_counts(
+        &self,
+        batch_id: &str,
+        from: Option<TaskStatus>,
+        to: TaskStatus,
+        count: u64,
+    ) -> DbResult<BatchProgress>;
+
+    /// Counts of `batch_id`, unless none were kept for it.
+    async fn batch_counts(&self, batch_id: &str) -> DbResult<Option<BatchCounts>>;
+
+    /// Records the webhook told when `batch_id` finishes.
+    async fn set_batch_callback_url(&self, batch_id: &str, callback_url: &str) -> DbResult<()>;
+
+    /// Atomically adds a scored task to the leaderboard entry of its suite and model.
+    async fn record_evaluation(&self, evaluation: &ScoredEvaluation<'_>) -> DbResult<()>;
+
+    /// Writes the vectors of an `embedding` task to `index`, replacing earlier ones.
+    async fn index_embeddings(
+        &self,
+        index: &str,
+        documents: &[EmbeddingDocument<'_>],
+    ) -> DbResult<()>;
+}
+
+/// Connects to the store selected by `STORAGE_BACKEND`, mirrored to the secondary
+/// Elasticsearch cluster when one is configured, with cached responses looked up in
+/// Redis first when `CACHE_BACKEND=redis`, keeping the leaderboards of eval suites, and
+/// keeping batch counts unless disabled. Missing Elasticsearch index templates and
+/// indexes are created first, unless `ELASTICSEARCH_BOOTSTRAP=false`.
+pub async fn connect(
+    settings: &StorageSettings,
+    webhooks: &Webhooks,
+) -> DbResult<Arc<dyn TaskStore>> {
+    let primary: Arc<dyn TaskStore> = match settings.backend {
+        StorageBackend::Elasticsearch => {
+            let store = elastic::ElasticStore::new(&settings.elasticsearch).await?;
+            if settings.elasticsearch.indices.bootstrap {
+                if let Err(e) = store.bootstrap().await {
+                    tracing::warn!("Failed to bootstrap the Elasticsearch indexes: {}", e);
+                }
+            }
+            match &settings.bulk_writer {
+                Some(bulk_writer) => Arc::new(store.with_bulk_writer(bulk_writer)),
+                None => Arc::new(store),
+            }
+        }
+        StorageBackend::Postgres => {
+            Arc::new(postgres::PostgresStore::connect(&settings.postgres).await?)
+        }
+        StorageBackend::Jsonl => Arc::new(jsonl::JsonlStore::create(&settings.jsonl_output)?),
+    };
+    let store: Arc<dyn TaskStore> = match &settings.mirror {
+        Some(mirror) => Arc::new(mirror::MirroredStore::new(primary, mirror).await?),
+        None => primary,
+    };
+    let store: Arc<dyn TaskStore> = match &settings.response_cache {
+        Some(response_cache) => Arc::new(redis_cache::RedisCacheStore::new(store, response_cache)?),
+        None => store,
+    };
+    let store: Arc<dyn TaskStore> = Arc::new(leaderboard::LeaderboardStore::new(store));
+    match &settings.batch_progress {
+        Some(batch_progress) => Ok(Arc::new(progress::ProgressStore::new(
+            store,
+            batch_progress,
+            webhooks.clone(),
+        ))),
+        None => Ok(store),
+    }
+}
@@ -0,0 +1,623 @@
+//! PostgreSQL store for deployments without Elasticsearch. The API doesn't create rows
+//! here, so the first status update of a task inserts its event.
+
+use super::{
+    checkpoint_document, event_update_fields, parse_checkpoint, BatchCounts, BatchProgress,
+    DbResult, EmbeddingDocument, EventKey, PreviousResult, ScoredEvaluation, StaleTask, TaskStore,
+    TASK_MESSAGE,
+};
+use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::task_status::TaskStatus;
+use crate::settings::PostgresSettings;
+use crate::truncation::TRUNCATED_ANNOTATION;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+use sqlx::postgres::{PgPool, PgPoolOptions, PgRow};
+use sqlx::types::Json;
+use sqlx::Row;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+const SCHEMA: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS events (
+        message_id TEXT PRIMARY KEY,
+        batch_id TEXT NOT NULL,
+        body_hash TEXT NOT NULL,
+        status TEXT NOT NULL,
+        started_at TIMESTAMPTZ,
+        completed_at TIMESTAMPTZ,
+        duration INTEGER,
+        cached BOOLEAN NOT NULL DEFAULT FALSE,
+        attempt INTEGER NOT NULL DEFAULT 0,
+        completions JSONB,
+        prompt_tokens BIGINT,
+        completion_tokens BIGINT,
+        total_tokens BIGINT,
+        cost DOUBLE PRECISION,
+        annotations JSONB NOT NULL DEFAULT '{}'
+    )",
+    "CREATE INDEX IF NOT EXISTS events_body_hash_status_idx ON events (body_hash, status)",
+    "CREATE INDEX IF NOT EXISTS events_batch_id_idx ON events (batch_id)",
+    "CREATE TABLE IF NOT EXISTS batches (
+        batch_id TEXT PRIMARY KEY,
+        total BIGINT NOT NULL DEFAULT 0,
+        pending BIGINT NOT NULL DEFAULT 0,
+        processing BIGINT NOT NULL DEFAULT 0,
+        completed BIGINT NOT NULL DEFAULT 0,
+        failed BIGINT NOT NULL DEFAULT 0,
+        updated_at TIMESTAMPTZ,
+        finished_at TIMESTAMPTZ
+    )",
+    "ALTER TABLE batches ADD COLUMN IF NOT EXISTS callback_url TEXT",
+    "ALTER TABLE events ADD COLUMN IF NOT EXISTS checkpoint JSONB",
+    "ALTER TABLE events ADD COLUMN IF NOT EXISTS task_message JSONB",
+    "ALTER TABLE events ADD COLUMN IF NOT EXISTS body JSONB",
+    "CREATE TABLE IF NOT EXISTS leaderboard (
+        suite TEXT NOT NULL,
+        model TEXT NOT NULL,
+        scored_tasks BIGINT NOT NULL DEFAULT 0,
+        correct_tasks BIGINT NOT NULL DEFAULT 0,
+        score_sum DOUBLE PRECISION NOT NULL DEFAULT 0,
+        updated_at TIMESTAMPTZ,
+        PRIMARY KEY (suite, model)
+    )",
+    "CREATE TABLE IF NOT EXISTS embeddings (
+        index_name TEXT NOT NULL,
+        message_id TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        batch_id TEXT NOT NULL,
+        input TEXT,
+        model TEXT,
+        dimensions INTEGER NOT NULL,
+        vector REAL[] NOT NULL,
+        created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+        PRIMARY KEY (index_name, message_id, position)
+    )",
+];
+
+pub struct PostgresStore {
+    pool: PgPool,
+}
+
+impl PostgresStore {
+    pub async fn connect(settings: &PostgresSettings) -> DbResult<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(settings.max_connections)
+            .connect(&settings.url)
+            .await?;
+        for statement in SCHEMA {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+        Ok(PostgresStore { pool })
+    }
+}
+
+/// Cached response read from a row selecting `completions`, `started_at` and
+/// `completed_at`.
+fn cached_response(row: &PgRow) -> DbResult<LLMResponse> {
+    let Json(completions): Json<Value> = row.try_get("completions")?;
+    Ok(LLMResponse {
+        completions,
+        cached: true,
+        attempt: 0,
+        started_at: row
+            .try_get::<Option<DateTime<Utc>>, _>("started_at")?
+            .unwrap_or_else(Utc::now),
+        completed_at: row
+            .try_get::<Option<DateTime<Utc>>, _>("completed_at")?
+            .unwrap_or_else(Utc::now),
+        annotations: Default::default(),
+        usage: None,
+        cost: None,
+    })
+}
+
+#[async_trait]
+impl TaskStore for PostgresStore {
+    async fn update_event_status(
+        &self,
+        event: &EventKey<'_>,
+        status: TaskStatus,
+        llm_response: &LLMResponse,
+        started_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        let status_name = status.as_str();
+        let usage = llm_response.usage.as_ref();
+        let fields = event_update_fields(status, llm_response, started_at);
+        // The task message is cleared with the final status.
+        let (annotations, task_message) = split_task_message(&llm_response.annotations);
+
+        // Annotations accumulate across updates, like fields of a partial document
+        // update in Elasticsearch.
+        sqlx::query(
+            "INSERT INTO events (message_id, batch_id, body_hash, status, started_at,
+                completed_at, duration, cached, attempt, completions, prompt_tokens,
+                completion_tokens, total_tokens, cost, annotations, task_message)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+             ON CONFLICT (message_id) DO UPDATE SET
+                status = EXCLUDED.status,
+                started_at = EXCLUDED.started_at,
+                completed_at = EXCLUDED.completed_at,
+                duration = EXCLUDED.duration,
+                cached = EXCLUDED.cached,
+                attempt = EXCLUDED.attempt,
+                completions = EXCLUDED.completions,
+                prompt_tokens = EXCLUDED.prompt_tokens,
+                completion_tokens = EXCLUDED.completion_tokens,
+                total_tokens = EXCLUDED.total_tokens,
+                cost = EXCLUDED.cost,
+                annotations = events.annotations || EXCLUDED.annotations,
+                checkpoint = CASE WHEN EXCLUDED.status IN ('COMPLETED', 'FAILED')
+                    THEN NULL ELSE events.checkpoint END,
+                task_message = CASE WHEN EXCLUDED.status IN ('COMPLETED', 'FAILED')
+                    THEN NULL ELSE COALESCE(EXCLUDED.task_message, events.task_message) END",
+        )
+        .bind(event.message_id)
+        .bind(event.batch_id)
+        .bind(event.body_hash)
+        .bind(status_name)
+        .bind(started_at)
+        .bind(Utc::now())
+        .bind(fields["duration"].as_i64().map(|d| d as i32))
+        .bind(llm_response.cached)
+        .bind(llm_response.attempt as i32)
+        .bind(Json(&llm_response.completions))
+        .bind(usage.map(|u| u.prompt_tokens as i64))
+        .bind(usage.map(|u| u.completion_tokens as i64))
+        .bind(usage.map(|u| u.total_tokens as i64))
+        .bind(llm_response.cost)
+        .bind(Json(annotations.as_ref()))
+        .bind(task_message.map(Json))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn save_checkpoint(&self, event: &EventKey<'_>, response: &LLMResponse) -> DbResult<()> {
+        sqlx::query("UPDATE events SET checkpoint = $2 WHERE message_id = $1")
+            .bind(event.message_id)
+            .bind(Json(checkpoint_document(response)))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn load_checkpoint(&self, message_id: &str) -> DbResult<Option<LLMResponse>> {
+        let checkpoint: Option<Option<Json<Value>>> =
+            sqlx::query_scalar("SELECT checkpoint FROM events WHERE message_id = $1")
+                .bind(message_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(checkpoint
+            .flatten()
+            .and_then(|Json(checkpoint)| parse_checkpoint(&checkpoint)))
+    }
+
+    async fn create_events(&self, documents: &[Value]) -> DbResult<HashMap<String, u64>> {
+        let mut created = HashMap::new();
+        let mut transaction = self.pool.begin().await?;
+        for document in documents {
+            let annotations = submission_annotations(document);
+            let batch_id = document["batch_id"].as_str().unwrap_or_default();
+            let inserted = sqlx::query(
+                "INSERT INTO events (message_id, batch_id, body_hash, status, annotations, body)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (message_id) DO NOTHING",
+            )
+            .bind(document["message_id"].as_str().unwrap_or_default())
+            .bind(batch_id)
+            .bind(document["body_hash"].as_str().unwrap_or_default())
+            .bind(TaskStatus::Pending.as_str())
+            .bind(Json(&annotations))
+            .bind(
+                Some(&document["body"])
+                    .filter(|body| !body.is_null())
+                    .map(Json),
+            )
+            .execute(&mut *transaction)
+            .await?
+            .rows_affected();
+            *created.entry(batch_id.to_string()).or_insert(0) += inserted;
+        }
+        transaction.commit().await?;
+        created.retain(|_, count| *count > 0);
+        Ok(created)
+    }
+
+    async fn stale_tasks(
+        &self,
+        started_before: DateTime<Utc>,
+        limit: usize,
+    ) -> DbResult<Vec<StaleTask>> {
+        let rows = sqlx::query(
+            "SELECT message_id, batch_id, body_hash, started_at, task_message FROM events
+             WHERE status = $1 AND started_at < $2 AND NOT annotations ? 'provider_batch_id'
+             ORDER BY started_at DESC
+             LIMIT $3",
+        )
+        .bind(TaskStatus::Processing.as_str())
+        .bind(started_before)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                let message: Option<Json<Value>> = row.try_get("task_message")?;
+                Ok(StaleTask {
+                    message_id: row.try_get("message_id")?,
+                    batch_id: row.try_get("batch_id")?,
+                    body_hash: row.try_get("body_hash")?,
+                    started_at: row.try_get("started_at")?,
+                    message: message.map(|Json(message)| message),
+                })
+            })
+            .collect()
+    }
+
+    async fn reset_stale_task(
+        &self,
+        event: &EventKey<'_>,
+        started_before: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        let reset = sqlx::query(
+            "UPDATE events SET status = $2, started_at = NULL
+             WHERE message_id = $1 AND status = $3 AND started_at < $4",
+        )
+        .bind(event.message_id)
+        .bind(TaskStatus::Pending.as_str())
+        .bind(TaskStatus::Processing.as_str())
+        .bind(started_before)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(reset > 0)
+    }
+
+    async fn event_status(&self, message_id: &str) -> DbResult<Option<TaskStatus>> {
+        let status: Option<String> =
+            sqlx::query_scalar("SELECT status FROM events WHERE message_id = $1")
+                .bind(message_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(status.as_deref().and_then(TaskStatus::parse))
+    }
+
+    async fn get_cached_completion(&self, body_hash: &str) -> DbResult<Option<LLMResponse>> {
+        let row = sqlx::query(
+            "SELECT completions, started_at, completed_at FROM events
+             WHERE body_hash = $1 AND status = $2 AND jsonb_typeof(completions) = 'object'
+                AND NOT annotations ? $3
+             LIMIT 1",
+        )
+        .bind(body_hash)
+        .bind(TaskStatus::Completed.as_str())
+        .bind(TRUNCATED_ANNOTATION)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(cached_response).transpose()
+    }
+
+    async fn cached_samples(&self, body_hash: &str, limit: usize) -> DbResult<Vec<LLMResponse>> {
+        let rows = sqlx::query(
+            "SELECT completions, started_at, completed_at FROM events
+             WHERE body_hash = $1 AND status = $2 AND NOT cached
+                AND jsonb_typeof(completions) = 'object' AND NOT annotations ? $3
+             ORDER BY completed_at
+             LIMIT $4",
+        )
+        .bind(body_hash)
+        .bind(TaskStatus::Completed.as_str())
+        .bind(TRUNCATED_ANNOTATION)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter().map(cached_response).collect()
+    }
+
+    async fn completed_results(
+        &self,
+        batch_id: &str,
+        body_hashes: &[&str],
+    ) -> DbResult<HashMap<String, PreviousResult>> {
+        let rows = sqlx::query(
+            "SELECT DISTINCT ON (body_hash) message_id, body_hash, completions, started_at,
+                    completed_at
+             FROM events
+             WHERE batch_id = $1 AND status = $2 AND body_hash = ANY($3)
+               AND jsonb_typeof(completions) = 'object'",
+        )
+        .bind(batch_id)
+        .bind(TaskStatus::Completed.as_str())
+        .bind(body_hashes)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let Json(completions): Json<Value> = row.try_get("completions")?;
+                let result = PreviousResult {
+                    message_id: row.try_get("message_id")?,
+                    response: LLMResponse {
+                        completions,
+                        cached: true,
+                        attempt: 0,
+                        started_at: row
+                            .try_get::<Option<DateTime<Utc>>, _>("started_at")?
+                            .unwrap_or_else(Utc::now),
+                        completed_at: row
+                            .try_get::<Option<DateTime<Utc>>, _>("completed_at")?
+                            .unwrap_or_else(Utc::now),
+                        annotations: Default::default(),
+                        usage: None,
+                        cost: None,
+                    },
+                };
+                Ok((row.try_get("body_hash")?, result))
+            })
+            .collect()
+    }
+
+    // Events inserted by a status update, rather than created on submission, have no
+    // body.
+    async fn completed_events(
+        &self,
+        batch_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Value>> {
+        let rows = sqlx::query(
+            "SELECT message_id, batch_id, body_hash, status, started_at, completed_at,
+                    duration, cached, attempt, completions, prompt_tokens, completion_tokens,
+                    total_tokens, cost, annotations, body
+             FROM events
+             WHERE batch_id = $1 AND status = $2 AND message_id > $3
+             ORDER BY message_id
+             LIMIT $4",
+        )
+        .bind(batch_id)
+        .bind(TaskStatus::Completed.as_str())
+        .bind(after.unwrap_or_default())
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let Json(annotations): Json<Value> = row.try_get("annotations")?;
+                let mut fields = Map::new();
+                for column in ["message_id", "batch_id", "body_hash", "status"] {
+                    fields.insert(column.to_string(), json!(row.try_get::<String, _>(column)?));
+                }
+                for column in ["started_at", "completed_at"] {
+                    let at: Option<DateTime<Utc>> = row.try_get(column)?;
+                    fields.insert(column.to_string(), json!(at));
+                }
+                for column in ["duration", "attempt"] {
+                    fields.insert(
+                        column.to_string(),
+                        json!(row.try_get::<Option<i32>, _>(column)?),
+                    );
+                }
+                for column in ["prompt_tokens", "completion_tokens", "total_tokens"] {
+                    fields.insert(
+                        column.to_string(),
+                        json!(row.try_get::<Option<i64>, _>(column)?),
+                    );
+                }
+                fields.insert(
+                    "cached".to_string(),
+                    json!(row.try_get::<bool, _>("cached")?),
+                );
+                fields.insert(
+                    "cost".to_string(),
+                    json!(row.try_get::<Option<f64>, _>("cost")?),
+                );
+                for column in ["completions", "body"] {
+                    let value: Option<Json<Value>> = row.try_get(column)?;
+                    fields.insert(
+                        column.to_string(),
+                        value.map(|Json(v)| v).unwrap_or_default(),
+                    );
+                }
+                event_document(annotations, fields)
+            })
+            .collect()
+    }
+
+    async fn label_counts(&self, batch_id: &str) -> DbResult<HashMap<String, u64>> {
+        let rows = sqlx::query(
+            "SELECT annotations->>'balance_label' AS label, COUNT(*) AS count FROM events
+             WHERE batch_id = $1
+               AND annotations ? 'balance_label'
+               AND NOT COALESCE((annotations->>'balance_excess')::boolean, FALSE)
+             GROUP BY 1",
+        )
+        .bind(batch_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| {
+                let label: String = row.try_get("label")?;
+                let count: i64 = row.try_get("count")?;
+                Ok((label, count as u64))
+            })
+            .collect()
+    }
+
+    async fn update_batch_counts(
+        &self,
+        batch_id: &str,
+        from: Option<TaskStatus>,
+        to: TaskStatus,
+        count: u64,
+    ) -> DbResult<BatchProgress> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query("INSERT INTO batches (batch_id) VALUES ($1) ON CONFLICT DO NOTHING")
+            .bind(batch_id)
+            .execute(&mut *transaction)
+            .await?;
+        let row = sqlx::query(
+            "SELECT total, pending, processing, completed, failed, finished_at IS NOT NULL
+                    AS finished, callback_url
+             FROM batches WHERE batch_id = $1 FOR UPDATE",
+        )
+        .bind(batch_id)
+        .fetch_one(&mut *transaction)
+        .await?;
+        let column = |name: &str| -> DbResult<u64> { Ok(row.try_get::<i64, _>(name)? as u64) };
+        let mut counts = BatchCounts {
+            total: column("total")?,
+            pending: column("pending")?,
+            processing: column("processing")?,
+            completed: column("completed")?,
+            failed: column("failed")?,
+        };
+        let was_finished: bool = row.try_get("finished")?;
+        counts.apply(from, to, count);
+
+        sqlx::query(
+            "UPDATE batches SET total = $2, pending = $3, processing = $4, completed = $5,
+                failed = $6, updated_at = now(),
+                finished_at = CASE WHEN $7 THEN COALESCE(finished_at, now()) END
+             WHERE batch_id = $1",
+        )
+        .bind(batch_id)
+        .bind(counts.total as i64)
+        .bind(counts.pending as i64)
+        .bind(counts.processing as i64)
+        .bind(counts.completed as i64)
+        .bind(counts.failed as i64)
+        .bind(counts.is_finished())
+        .execute(&mut *transaction)
+        .await?;
+        transaction.commit().await?;
+
+        Ok(BatchProgress {
+            just_finished: counts.is_finished() && !was_finished,
+            counts,
+            callback_url: row.try_get("callback_url")?,
+        })
+    }
+
+    async fn batch_counts(&self, batch_id: &str) -> DbResult<Option<BatchCounts>> {
+        let row = sqlx::query(
+            "SELECT total, pending, processing, completed, failed FROM batches WHERE batch_id = $1",
+        )
+        .bind(batch_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let column = |name: &str| -> DbResult<u64> { Ok(row.try_get::<i64, _>(name)? as u64) };
+        Ok(Some(BatchCounts {
+            total: column("total")?,
+            pending: column("pending")?,
+            processing: column("processing")?,
+            completed: column("completed")?,
+            failed: column("failed")?,
+        }))
+    }
+
+    async fn set_batch_callback_url(&self, batch_id: &str, callback_url: &str) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO batches (batch_id, callback_url) VALUES ($1, $2)
+             ON CONFLICT (batch_id) DO UPDATE SET callback_url = EXCLUDED.callback_url",
+        )
+        .bind(batch_id)
+        .bind(callback_url)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn record_evaluation(&self, evaluation: &ScoredEvaluation<'_>) -> DbResult<()> {
+        sqlx::query(
+            "INSERT INTO leaderboard (suite, model, scored_tasks, correct_tasks, score_sum,
+                updated_at)
+             VALUES ($1, $2, 1, $3, $4, now())
+             ON CONFLICT (suite, model) DO UPDATE SET
+                scored_tasks = leaderboard.scored_tasks + 1,
+                correct_tasks = leaderboard.correct_tasks + EXCLUDED.correct_tasks,
+                score_sum = leaderboard.score_sum + EXCLUDED.score_sum,
+                updated_at = now()",
+        )
+        .bind(evaluation.suite)
+        .bind(evaluation.model)
+        .bind(evaluation.correct as i64)
+        .bind(evaluation.score)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The vector index is the `index_name` of rows in the `embeddings` table.
+    async fn index_embeddings(
+        &self,
+        index: &str,
+        documents: &[EmbeddingDocument<'_>],
+    ) -> DbResult<()> {
+        let mut transaction = self.pool.begin().await?;
+        for document in documents {
+            sqlx::query(
+                "INSERT INTO embeddings (index_name, message_id, position, batch_id, input,
+                    model, dimensions, vector)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                 ON CONFLICT (index_name, message_id, position) DO UPDATE SET
+                    input = EXCLUDED.input,
+                    model = EXCLUDED.model,
+                    dimensions = EXCLUDED.dimensions,
+                    vector = EXCLUDED.vector,
+                    created_at = now()",
+            )
+            .bind(index)
+            .bind(document.message_id)
+            .bind(document.position as i32)
+            .bind(document.batch_id)
+            .bind(document.input)
+            .bind(document.model)
+            .bind(document.vector.len() as i32)
+            .bind(&document.vector)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+}
+
+/// Annotations of a status update without its task message, and the task message,
+/// which has a column of its own.
+pub fn split_task_message(
+    annotations: &Map<String, Value>,
+) -> (Cow<'_, Map<String, Value>>, Option<Value>) {
+    let mut annotations = Cow::Borrowed(annotations);
+    let task_message = annotations.get(TASK_MESSAGE).cloned();
+    if task_message.is_some() {
+        annotations.to_mut().remove(TASK_MESSAGE);
+    }
+    (annotations, task_message)
+}
+
+/// Fields of a submitted event without a column of their own, kept with its
+/// annotations.
+pub fn submission_annotations(document: &Value) -> Map<String, Value> {
+    ["custom_id", "dataset", "source"]
+        .into_iter()
+        .filter(|field| !document[*field].is_null())
+        .map(|field| (field.to_string(), document[field].clone()))
+        .collect()
+}
+
+/// Exported event of a row: its annotations, overridden by its `columns`.
+pub fn event_document(mut annotations: Value, columns: Map<String, Value>) -> DbResult<Value> {
+    let Some(fields) = annotations.as_object_mut() else {
+        return Err("Event annotations are not an object".into());
+    };
+    fields.extend(columns);
+    Ok(annotations)
+}
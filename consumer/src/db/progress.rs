@@ -0,0 +1,220 @@
+//! Per-batch task counts kept next to the events, in the `batches` index (or table),
+//! so a batch's progress is one lookup rather than an aggregation over its events.
+//!
+//! Each status change reads the event's previous status and then moves the task
+//! between the counts of its batch in a single atomic update. The update that leaves
+//! a batch without pending or processing tasks is announced to the completion webhook
+//! and to the batch's own `callback_url`, if any.
+//! Batches submitted by the Python API to a PostgreSQL-backed consumer have no events
+//! there until their tasks report, so their counts only grow as tasks are processed.
+
+use super::{
+    BatchCounts, BatchProgress, DbResult, EmbeddingDocument, EventKey, EventState, PreviousResult,
+    ScoredEvaluation, StaleTask, TaskStore,
+};
+use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::task_status::TaskStatus;
+use crate::settings::BatchProgressSettings;
+use crate::webhook::Webhooks;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Keeps the batch counts of every event written through `inner`.
+pub struct ProgressStore {
+    inner: Arc<dyn TaskStore>,
+    completion_webhook_url: Option<String>,
+    webhooks: Webhooks,
+}
+
+impl ProgressStore {
+    pub fn new(
+        inner: Arc<dyn TaskStore>,
+        settings: &BatchProgressSettings,
+        webhooks: Webhooks,
+    ) -> Self {
+        Self {
+            inner,
+            completion_webhook_url: settings.completion_webhook_url.clone(),
+            webhooks,
+        }
+    }
+
+    /// Moves `count` tasks between the counts of `batch_id`. The events stay the
+    /// source of truth, so a failed update is logged rather than failing the write.
+    async fn count(&self, batch_id: &str, from: Option<TaskStatus>, to: TaskStatus, count: u64) {
+        match self
+            .inner
+            .update_batch_counts(batch_id, from, to, count)
+            .await
+        {
+            Ok(progress) if progress.just_finished => {
+                tracing::info!(
+                    "Batch {} finished: {} completed, {} failed",
+                    batch_id,
+                    progress.counts.completed,
+                    progress.counts.failed
+                );
+                self.notify(batch_id, progress.counts, progress.callback_url);
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Failed to update counts of batch {}: {}", batch_id, e),
+        }
+    }
+
+    /// Posts the final counts of a batch to the webhooks, without waiting for them.
+    fn notify(&self, batch_id: &str, counts: BatchCounts, callback_url: Option<String>) {
+        let event = json!({
+            "event": "batch.finished",
+            "batch_id": batch_id,
+            "finished_at": Utc::now(),
+            "counts": counts,
+        });
+        for url in self.completion_webhook_url.iter().chain(&callback_url) {
+            self.webhooks.send(url, event.clone());
+        }
+    }
+}
+
+#[async_trait]
+impl TaskStore for ProgressStore {
+    async fn update_event_status(
+        &self,
+        event: &EventKey<'_>,
+        status: TaskStatus,
+        llm_response: &LLMResponse,
+        started_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        let previous = self.inner.event_status(event.message_id).await;
+        self.inner
+            .update_event_status(event, status, llm_response, started_at)
+            .await?;
+        match previous {
+            Ok(previous) if previous == Some(status) => {}
+            Ok(previous) => self.count(event.batch_id, previous, status, 1).await,
+            Err(e) => tracing::warn!(
+                "Counts of batch {} not updated, failed to read the status of {}: {}",
+                event.batch_id,
+                event.message_id,
+                e
+            ),
+        }
+        Ok(())
+    }
+
+    async fn create_events(&self, documents: &[Value]) -> DbResult<HashMap<String, u64>> {
+        let created = self.inner.create_events(documents).await?;
+        for (batch_id, count) in &created {
+            self.count(batch_id, None, TaskStatus::Pending, *count)
+                .await;
+        }
+        Ok(created)
+    }
+
+    async fn save_checkpoint(&self, event: &EventKey<'_>, response: &LLMResponse) -> DbResult<()> {
+        self.inner.save_checkpoint(event, response).await
+    }
+
+    async fn load_checkpoint(&self, message_id: &str) -> DbResult<Option<LLMResponse>> {
+        self.inner.load_checkpoint(message_id).await
+    }
+
+    async fn stale_tasks(
+        &self,
+        started_before: DateTime<Utc>,
+        limit: usize,
+    ) -> DbResult<Vec<StaleTask>> {
+        self.inner.stale_tasks(started_before, limit).await
+    }
+
+    async fn reset_stale_task(
+        &self,
+        event: &EventKey<'_>,
+        started_before: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        let reset = self.inner.reset_stale_task(event, started_before).await?;
+        if reset {
+            self.count(
+                event.batch_id,
+                Some(TaskStatus::Processing),
+                TaskStatus::Pending,
+                1,
+            )
+            .await;
+        }
+        Ok(reset)
+    }
+
+    async fn event_status(&self, message_id: &str) -> DbResult<Option<TaskStatus>> {
+        self.inner.event_status(message_id).await
+    }
+
+    async fn event_state(&self, message_id: &str) -> DbResult<Option<EventState>> {
+        self.inner.event_state(message_id).await
+    }
+
+    async fn get_cached_completion(&self, body_hash: &str) -> DbResult<Option<LLMResponse>> {
+        self.inner.get_cached_completion(body_hash).await
+    }
+
+    async fn cached_samples(&self, body_hash: &str, limit: usize) -> DbResult<Vec<LLMResponse>> {
+        self.inner.cached_samples(body_hash, limit).await
+    }
+
+    async fn completed_results(
+        &self,
+        batch_id: &str,
+        body_hashes: &[&str],
+    ) -> DbResult<HashMap<String, PreviousResult>> {
+        self.inner.completed_results(batch_id, body_hashes).await
+    }
+
+    async fn completed_events(
+        &self,
+        batch_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Value>> {
+        self.inner.completed_events(batch_id, after, limit).await
+    }
+
+    async fn label_counts(&self, batch_id: &str) -> DbResult<HashMap<String, u64>> {
+        self.inner.label_counts(batch_id).await
+    }
+
+    async fn update_batch_counts(
+        &self,
+        batch_id: &str,
+        from: Option<TaskStatus>,
+        to: TaskStatus,
+        count: u64,
+    ) -> DbResult<BatchProgress> {
+        self.inner
+            .update_batch_counts(batch_id, from, to, count)
+            .await
+    }
+
+    async fn batch_counts(&self, batch_id: &str) -> DbResult<Option<BatchCounts>> {
+        self.inner.batch_counts(batch_id).await
+    }
+
+    async fn set_batch_callback_url(&self, batch_id: &str, callback_url: &str) -> DbResult<()> {
+        self.inner
+            .set_batch_callback_url(batch_id, callback_url)
+            .await
+    }
+
+    async fn record_evaluation(&self, evaluation: &ScoredEvaluation<'_>) -> DbResult<()> {
+        self.inner.record_evaluation(evaluation).await
+    }
+
+    async fn index_embeddings(
+        &self,
+        index: &str,
+        documents: &[EmbeddingDocument<'_>],
+    ) -> DbResult<()> {
+        self.inner.index_embeddings(index, documents).await
+    }
+}
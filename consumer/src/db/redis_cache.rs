@@ -0,0 +1,233 @@
+//! Redis cache of completed responses by body hash, in front of the store's own cache
+//! lookup, so hot duplicate bodies don't each cost an Elasticsearch query.
+//!
+//! Completed responses are written through as their events are, and store hits are
+//! copied into Redis on the way back. Redis being slow or down only costs the
+//! `CACHE_TIMEOUT_MS` spent waiting for it: lookups then fall back to the store and
+//! failed writes are logged. Entries expire after `CACHE_TTL_SECS`, so responses of
+//! deleted batches may still be served until then.
+
+use super::{
+    BatchCounts, BatchProgress, DbResult, EmbeddingDocument, EventKey, EventState, PreviousResult,
+    ScoredEvaluation, StaleTask, TaskStore,
+};
+use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::task_status::TaskStatus;
+use crate::settings::ResponseCacheSettings;
+use crate::truncation::TRUNCATED_ANNOTATION;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+pub struct RedisCacheStore {
+    inner: Arc<dyn TaskStore>,
+    client: redis::Client,
+    connection: OnceCell<redis::aio::ConnectionManager>,
+    key_prefix: String,
+    ttl_secs: u64,
+    timeout: Duration,
+}
+
+impl RedisCacheStore {
+    pub fn new(inner: Arc<dyn TaskStore>, settings: &ResponseCacheSettings) -> DbResult<Self> {
+        Ok(Self {
+            inner,
+            client: redis::Client::open(settings.redis_url.as_str())?,
+            connection: OnceCell::new(),
+            key_prefix: settings.key_prefix.clone(),
+            ttl_secs: settings.ttl_secs.max(1),
+            timeout: Duration::from_millis(settings.timeout_ms),
+        })
+    }
+
+    fn key(&self, body_hash: &str) -> String {
+        format!("{}{}", self.key_prefix, body_hash)
+    }
+
+    /// Runs `command` on the shared connection, giving up after the cache timeout.
+    async fn run<T, F, Fut>(&self, command: F) -> DbResult<T>
+    where
+        F: FnOnce(redis::aio::ConnectionManager) -> Fut,
+        Fut: Future<Output = redis::RedisResult<T>>,
+    {
+        let attempt = async {
+            let connection = self
+                .connection
+                .get_or_try_init(|| self.client.get_connection_manager())
+                .await?
+                .clone();
+            command(connection).await
+        };
+        Ok(tokio::time::timeout(self.timeout, attempt).await??)
+    }
+
+    async fn lookup(&self, body_hash: &str) -> DbResult<Option<LLMResponse>> {
+        let key = self.key(body_hash);
+        let cached: Option<String> = self
+            .run(|mut connection| async move { connection.get(key).await })
+            .await?;
+        Ok(match cached {
+            Some(cached) => super::elastic::cached_response(&serde_json::from_str(&cached)?),
+            None => None,
+        })
+    }
+
+    /// Caches `response` for `body_hash`, replacing any cached one.
+    pub async fn put(&self, body_hash: &str, response: &LLMResponse) -> DbResult<()> {
+        let key = self.key(body_hash);
+        let value = json!({
+            "completions": response.completions,
+            "started_at": response.started_at,
+            "completed_at": response.completed_at,
+        })
+        .to_string();
+        let ttl_secs = self.ttl_secs;
+        self.run(|mut connection| async move { connection.set_ex(key, value, ttl_secs).await })
+            .await
+    }
+
+    /// Caches `response` for `body_hash`, logging rather than returning failures.
+    async fn store(&self, body_hash: &str, response: &LLMResponse) {
+        if let Err(e) = self.put(body_hash, response).await {
+            tracing::warn!("Failed to cache the response for {}: {}", body_hash, e);
+        }
+    }
+}
+
+#[async_trait]
+impl TaskStore for RedisCacheStore {
+    async fn update_event_status(
+        &self,
+        event: &EventKey<'_>,
+        status: TaskStatus,
+        llm_response: &LLMResponse,
+        started_at: DateTime<Utc>,
+    ) -> DbResult<()> {
+        self.inner
+            .update_event_status(event, status, llm_response, started_at)
+            .await?;
+        // Cached responses came from the cache or were copied into it on lookup.
+        if status == TaskStatus::Completed
+            && !llm_response.cached
+            && llm_response.completions.is_object()
+            && !llm_response.annotations.contains_key(TRUNCATED_ANNOTATION)
+        {
+            self.store(event.body_hash, llm_response).await;
+        }
+        Ok(())
+    }
+
+    async fn create_events(&self, documents: &[Value]) -> DbResult<HashMap<String, u64>> {
+        self.inner.create_events(documents).await
+    }
+
+    async fn save_checkpoint(&self, event: &EventKey<'_>, response: &LLMResponse) -> DbResult<()> {
+        self.inner.save_checkpoint(event, response).await
+    }
+
+    async fn load_checkpoint(&self, message_id: &str) -> DbResult<Option<LLMResponse>> {
+        self.inner.load_checkpoint(message_id).await
+    }
+
+    async fn stale_tasks(
+        &self,
+        started_before: DateTime<Utc>,
+        limit: usize,
+    ) -> DbResult<Vec<StaleTask>> {
+        self.inner.stale_tasks(started_before, limit).await
+    }
+
+    async fn reset_stale_task(
+        &self,
+        event: &EventKey<'_>,
+        started_before: DateTime<Utc>,
+    ) -> DbResult<bool> {
+        self.inner.reset_stale_task(event, started_before).await
+    }
+
+    async fn event_status(&self, message_id: &str) -> DbResult<Option<TaskStatus>> {
+        self.inner.event_status(message_id).await
+    }
+
+    async fn event_state(&self, message_id: &str) -> DbResult<Option<EventState>> {
+        self.inner.event_state(message_id).await
+    }
+
+    async fn get_cached_completion(&self, body_hash: &str) -> DbResult<Option<LLMResponse>> {
+        match self.lookup(body_hash).await {
+            Ok(Some(response)) => return Ok(Some(response)),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Response cache lookup of {} failed: {}", body_hash, e),
+        }
+        let response = self.inner.get_cached_completion(body_hash).await?;
+        if let Some(response) = &response {
+            self.store(body_hash, response).await;
+        }
+        Ok(response)
+    }
+
+    async fn cached_samples(&self, body_hash: &str, limit: usize) -> DbResult<Vec<LLMResponse>> {
+        self.inner.cached_samples(body_hash, limit).await
+    }
+
+    async fn completed_results(
+        &self,
+        batch_id: &str,
+        body_hashes: &[&str],
+    ) -> DbResult<HashMap<String, PreviousResult>> {
+        self.inner.completed_results(batch_id, body_hashes).await
+    }
+
+    async fn completed_events(
+        &self,
+        batch_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> DbResult<Vec<Value>> {
+        self.inner.completed_events(batch_id, after, limit).await
+    }
+
+    async fn label_counts(&self, batch_id: &str) -> DbResult<HashMap<String, u64>> {
+        self.inner.label_counts(batch_id).await
+    }
+
+    async fn update_batch_counts(
+        &self,
+        batch_id: &str,
+        from: Option<TaskStatus>,
+        to: TaskStatus,
+        count: u64,
+    ) -> DbResult<BatchProgress> {
+        self.inner
+            .update_batch_counts(batch_id, from, to, count)
+            .await
+    }
+
+    async fn batch_counts(&self, batch_id: &str) -> DbResult<Option<BatchCounts>> {
+        self.inner.batch_counts(batch_id).await
+    }
+
+    async fn set_batch_callback_url(&self, batch_id: &str, callback_url: &str) -> DbResult<()> {
+        self.inner
+            .set_batch_callback_url(batch_id, callback_url)
+            .await
+    }
+
+    async fn record_evaluation(&self, evaluation: &ScoredEvaluation<'_>) -> DbResult<()> {
+        self.inner.record_evaluation(evaluation).await
+    }
+
+    async fn index_embeddings(
+        &self,
+        index: &str,
+        documents: &[EmbeddingDocument<'_>],
+    ) -> DbResult<()> {
+        self.inner.index_embeddings(index, documents).await
+    }
+}
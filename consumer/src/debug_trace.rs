@@ -0,0 +1,133 @@
+//! Per-task record of how the consumer handled a message, stored on its event as
+//! `debug_trace` so the API can reconstruct the timeline of a single task
+//! (`GET /tasks/{id}/debug`).
+//!
+//! The trace of the task being processed is reachable through a task-local, so
+//! `call_llm` records its attempts without being handed the trace. Secrets are
+//! redacted and long strings cut short before anything is recorded.
+
+use crate::settings::DebugTraceMode;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+tokio::task_local! {
+    static CURRENT: Trace;
+}
+
+/// Longest string kept in request and response snippets, in characters.
+const SNIPPET_CHARS: usize = 500;
+
+/// Keys whose values are never recorded, compared case-insensitively.
+const REDACTED_KEYS: [&str; 8] = [
+    "api_key",
+    "apikey",
+    "x-api-key",
+    "authorization",
+    "password",
+    "secret",
+    "secret_key",
+    "access_token",
+];
+
+#[derive(Serialize, Default)]
+struct TraceData {
+    message: Value,
+    settings: Value,
+    attempts: Vec<Value>,
+}
+
+#[derive(Clone)]
+pub struct Trace {
+    data: Arc<Mutex<TraceData>>,
+    /// Whether the trace is stored for completed tasks too, not only failed ones.
+    keep_completed: bool,
+}
+
+impl Trace {
+    /// Starts the trace of a task under `mode`, or `None` when it isn't traced. The
+    /// task's `debug` payload field traces it whatever the mode.
+    pub fn start(mode: DebugTraceMode, message: &Value) -> Option<Self> {
+        let forced = message["payload"]["debug"].as_bool().unwrap_or(false);
+        let keep_completed = match mode {
+            _ if forced => true,
+            DebugTraceMode::Off => return None,
+            DebugTraceMode::Failed => false,
+            DebugTraceMode::All => true,
+        };
+        Some(Self {
+            data: Arc::new(Mutex::new(TraceData {
+                message: redact(message),
+                ..Default::default()
+            })),
+            keep_completed,
+        })
+    }
+
+    /// Settings the task was processed with, after task overrides were applied.
+    pub fn set_settings(&self, settings: Value) {
+        self.data.lock().unwrap().settings = redact(&settings);
+    }
+
+    /// Runs `future` with this trace as the current one.
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        CURRENT.scope(self.clone(), future).await
+    }
+
+    /// The trace to store on the event, if one is kept for this outcome.
+    pub fn finish(&self, failed: bool) -> Option<Value> {
+        if !failed && !self.keep_completed {
+            return None;
+        }
+        serde_json::to_value(&*self.data.lock().unwrap()).ok()
+    }
+}
+
+/// Appends an LLM attempt to the current trace; does nothing outside of one.
+pub fn record_attempt(attempt: Value) {
+    let _ = CURRENT.try_with(|trace| trace.data.lock().unwrap().attempts.push(attempt));
+}
+
+/// Whether a trace is being recorded, so callers can skip building attempt records.
+pub fn is_recording() -> bool {
+    CURRENT.try_with(|_| ()).is_ok()
+}
+
+/// Copy of `value` with the values of secret-looking keys replaced.
+pub fn redact(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| {
+                    let value = if REDACTED_KEYS.contains(&key.to_ascii_lowercase().as_str()) {
+                        Value::from("[redacted]")
+                    } else {
+                        redact(value)
+                    };
+                    (key.clone(), value)
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(redact).collect()),
+        _ => value.clone(),
+    }
+}
+
+/// Redacted copy of `value` with strings longer than `SNIPPET_CHARS` cut short.
+pub fn snippet(value: &Value) -> Value {
+    fn truncate(value: Value) -> Value {
+        match value {
+            Value::String(s) if s.chars().count() > SNIPPET_CHARS => {
+                let cut: String = s.chars().take(SNIPPET_CHARS).collect();
+                Value::String(format!("{}… ({} chars)", cut, s.chars().count()))
+            }
+            Value::Array(items) => Value::Array(items.into_iter().map(truncate).collect()),
+            Value::Object(map) => {
+                Value::Object(map.into_iter().map(|(k, v)| (k, truncate(v))).collect())
+            }
+            value => value,
+        }
+    }
+    truncate(redact(value))
+}
@@ -0,0 +1,132 @@
+use crate::llm_wrapper::{self, LLMClient};
+use crate::settings::{DifficultyMode, DifficultySettings};
+use crate::text;
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DifficultyTag {
+    Easy,
+    Medium,
+    Hard,
+}
+
+impl DifficultyTag {
+    pub fn from_score(score: f64) -> Self {
+        if score < 0.34 {
+            DifficultyTag::Easy
+        } else if score < 0.67 {
+            DifficultyTag::Medium
+        } else {
+            DifficultyTag::Hard
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DifficultyTag::Easy => "easy",
+            DifficultyTag::Medium => "medium",
+            DifficultyTag::Hard => "hard",
+        }
+    }
+}
+
+/// Scores an example in [0, 1] from surface features: length, vocabulary,
+/// code, math and multi-step structure.
+pub fn heuristic_score(prompt: &str, completion: &str) -> f64 {
+    let words = text::words(&format!("{} {}", prompt, completion));
+    if words.is_empty() {
+        return 0.0;
+    }
+
+    let length = (words.len() as f64 / 800.0).min(1.0);
+    let avg_word_len = words.iter().map(|w| w.len()).sum::<usize>() as f64 / words.len() as f64;
+    let vocabulary = ((avg_word_len - 3.0) / 5.0).clamp(0.0, 1.0);
+    let code = if completion.contains("```") { 1.0 } else { 0.0 };
+    let math = (completion
+        .chars()
+        .filter(|c| matches!(c, '=' | '+' | '^' | '∑' | '∫' | '√'))
+        .count() as f64
+        / 20.0)
+        .min(1.0);
+    let steps = (completion
+        .lines()
+        .filter(|l| {
+            let l = l.trim_start();
+            l.starts_with("- ") || l.chars().next().is_some_and(|c| c.is_ascii_digit())
+        })
+        .count() as f64
+        / 10.0)
+        .min(1.0);
+
+    0.35 * length + 0.2 * vocabulary + 0.15 * code + 0.15 * math + 0.15 * steps
+}
+
+async fn model_score(
+    client: &LLMClient,
+    settings: &DifficultySettings,
+    prompt: &str,
+    completion: &str,
+) -> Result<f64, Box<dyn std::error::Error + Send + Sync>> {
+    let aux = settings
+        .model
+        .as_ref()
+        .ok_or("Difficulty model is not configured")?;
+    let question = format!(
+        "Rate how difficult the following task is for a student, from 1 (trivial) to 10 (expert). \
+         Reply with the number only.\n\nTask:\n{}\n\nAnswer:\n{}",
+        prompt, completion
+    );
+    let answer = llm_wrapper::complete_prompt(client, aux, &question).await?;
+    let rating: f64 = answer
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .find(|s| !s.is_empty())
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| format!("Unparseable difficulty rating: {}", answer))?;
+    Ok(((rating - 1.0) / 9.0).clamp(0.0, 1.0))
+}
+
+/// Difficulty score stored on an event by `tag`.
+pub fn score(event: &Value) -> Option<f64> {
+    event["difficulty_score"].as_f64()
+}
+
+/// Curriculum order of difficulty scores: easiest first, unscored examples last.
+pub fn curriculum_order(a: Option<f64>, b: Option<f64>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Computes the difficulty of an example and returns the annotations to store.
+/// Model scoring falls back to the heuristic when the model call fails.
+pub async fn tag(
+    client: &LLMClient,
+    settings: &DifficultySettings,
+    body: &serde_json::Value,
+    completion: &str,
+) -> serde_json::Map<String, serde_json::Value> {
+    let prompt = text::prompt_text(body);
+    let (score, method) = match settings.mode {
+        DifficultyMode::Heuristic => (heuristic_score(&prompt, completion), "heuristic"),
+        DifficultyMode::Model => match model_score(client, settings, &prompt, completion).await {
+            Ok(score) => (score, "model"),
+            Err(e) => {
+                tracing::warn!("Difficulty model failed, using heuristic: {}", e);
+                (heuristic_score(&prompt, completion), "heuristic")
+            }
+        },
+    };
+
+    let mut annotations = serde_json::Map::new();
+    annotations.insert("difficulty_score".to_string(), json!(score));
+    annotations.insert(
+        "difficulty".to_string(),
+        json!(DifficultyTag::from_score(score).as_str()),
+    );
+    annotations.insert("difficulty_method".to_string(), json!(method));
+    annotations
+}
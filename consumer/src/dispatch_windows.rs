@@ -0,0 +1,219 @@
+//! Daily dispatch windows of providers, for energy- and contention-aware scheduling:
+//! e.g. an on-prem GPU cluster only sent requests overnight, when it's otherwise idle.
+//!
+//! Before a task's providers are tried, the ones outside their window are left out. A
+//! window set to `skip` lets the task go on to its next provider right away; one set to
+//! `hold` (the default) makes the task wait when it's its first choice, holding its
+//! in-flight slot. A task whose every provider is skipped waits for the earliest window.
+//! Tasks whose deadline comes before the window opens give up with
+//! `DeadlineUnreachable`. Waits longer than `PROVIDER_WINDOW_MAX_HOLD_SECS` end with
+//! `WindowClosed` after that long, and the delivery is handed back to the broker to be
+//! held again, so it doesn't sit unacknowledged past the broker's delivery timeout.
+
+use crate::llm_wrapper::{self, DeadlineUnreachable};
+use crate::settings::{DispatchWindow, DispatchWindowSettings, OutsideWindow, ProviderSettings};
+use chrono::{DateTime, FixedOffset, Local, NaiveTime, Utc};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Returned when a task was held for `PROVIDER_WINDOW_MAX_HOLD_SECS` without any of its
+/// providers' windows opening.
+#[derive(Debug)]
+pub struct WindowClosed(pub String);
+
+impl std::fmt::Display for WindowClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for WindowClosed {}
+
+enum Zone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    fn parse(timezone: Option<&str>) -> Result<Self, String> {
+        match timezone.map(str::trim) {
+            None | Some("") => Ok(Zone::Utc),
+            Some(zone) if zone.eq_ignore_ascii_case("utc") || zone == "Z" => Ok(Zone::Utc),
+            Some(zone) if zone.eq_ignore_ascii_case("local") => Ok(Zone::Local),
+            Some(zone) => zone
+                .parse()
+                .map(Zone::Fixed)
+                .map_err(|_| format!("invalid timezone {}", zone)),
+        }
+    }
+
+    fn time_at(&self, now: DateTime<Utc>) -> NaiveTime {
+        match self {
+            Zone::Utc => now.time(),
+            Zone::Local => now.with_timezone(&Local).time(),
+            Zone::Fixed(offset) => now.with_timezone(offset).time(),
+        }
+    }
+}
+
+struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    zone: Zone,
+    outside: OutsideWindow,
+}
+
+impl Window {
+    fn new(window: &DispatchWindow) -> Result<Self, String> {
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+                .map_err(|_| format!("invalid time {}", time))
+        };
+        Ok(Self {
+            start: time(&window.start)?,
+            end: time(&window.end)?,
+            zone: Zone::parse(window.timezone.as_deref())?,
+            outside: window.outside,
+        })
+    }
+
+    /// How long until the window opens, or `None` while it's open.
+    fn opens_in(&self, now: DateTime<Utc>) -> Option<Duration> {
+        let time = self.zone.time_at(now);
+        let open = if self.start == self.end {
+            true
+        } else if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        };
+        if open {
+            return None;
+        }
+        let until_start = (self.start - time).num_milliseconds().rem_euclid(DAY_MS);
+        Some(Duration::from_millis(until_start as u64))
+    }
+}
+
+/// Provider a task waits for, and how long until its window opens.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closed {
+    pub provider: String,
+    pub opens_in: Duration,
+}
+
+#[derive(Default)]
+pub struct DispatchWindows {
+    windows: HashMap<String, Window>,
+    max_hold: Duration,
+}
+
+impl DispatchWindows {
+    /// Windows that can't be parsed are ignored, their provider being always open.
+    pub fn new(settings: &DispatchWindowSettings) -> Self {
+        let windows = settings
+            .windows
+            .iter()
+            .filter_map(|(provider, window)| match Window::new(window) {
+                Ok(window) => Some((provider.clone(), window)),
+                Err(e) => {
+                    tracing::warn!("Ignoring the dispatch window of {}: {}", provider, e);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            windows,
+            max_hold: Duration::from_secs(settings.max_hold_secs),
+        }
+    }
+
+    /// Providers a task can be sent to at `now`, in order, or the one it has to wait
+    /// for: its first choice when that holds, else the earliest to open when all of
+    /// them are skipped.
+    pub fn select(
+        &self,
+        providers: &[ProviderSettings],
+        now: DateTime<Utc>,
+    ) -> Result<Vec<ProviderSettings>, Closed> {
+        let mut open = Vec::with_capacity(providers.len());
+        let mut earliest: Option<Closed> = None;
+        for provider in providers {
+            let Some(window) = self.windows.get(&provider.name) else {
+                open.push(provider.clone());
+                continue;
+            };
+            let Some(opens_in) = window.opens_in(now) else {
+                open.push(provider.clone());
+                continue;
+            };
+            let closed = Closed {
+                provider: provider.name.clone(),
+                opens_in,
+            };
+            if window.outside == OutsideWindow::Hold && open.is_empty() {
+                return Err(closed);
+            }
+            if earliest
+                .as_ref()
+                .is_none_or(|earliest| closed.opens_in < earliest.opens_in)
+            {
+                earliest = Some(closed);
+            }
+        }
+        match earliest {
+            Some(closed) if open.is_empty() => Err(closed),
+            _ => Ok(open),
+        }
+    }
+
+    /// Waits until the task can be sent to one of `providers`, and returns the ones it
+    /// can. A task whose delivery can be handed back (`releasable`) is held for
+    /// `max_hold` at most before failing with `WindowClosed`; zero holds it until the
+    /// window opens.
+    pub async fn wait(
+        &self,
+        providers: Vec<ProviderSettings>,
+        releasable: bool,
+    ) -> Result<Vec<ProviderSettings>, Box<dyn std::error::Error + Send + Sync>> {
+        if self.windows.is_empty() {
+            return Ok(providers);
+        }
+        let held_since = Instant::now();
+        loop {
+            let closed = match self.select(&providers, Utc::now()) {
+                Ok(providers) => return Ok(providers),
+                Err(closed) => closed,
+            };
+            let opens_at = Instant::now() + closed.opens_in;
+            if llm_wrapper::current_deadline().is_some_and(|deadline| opens_at > deadline) {
+                return Err(Box::new(DeadlineUnreachable(format!(
+                    "Deadline unreachable: the dispatch window of {} opens in {}s",
+                    closed.provider,
+                    closed.opens_in.as_secs()
+                ))));
+            }
+            let release_at = held_since + self.max_hold;
+            if releasable && !self.max_hold.is_zero() && release_at < opens_at {
+                tokio::time::sleep_until(release_at).await;
+                return Err(Box::new(WindowClosed(format!(
+                    "Held for {}s, the dispatch window of {} opens in {}s",
+                    self.max_hold.as_secs(),
+                    closed.provider,
+                    opens_at.saturating_duration_since(Instant::now()).as_secs()
+                ))));
+            }
+            tracing::info!(
+                "Holding a task until the dispatch window of {} opens in {}s",
+                closed.provider,
+                closed.opens_in.as_secs()
+            );
+            tokio::time::sleep_until(opens_at).await;
+        }
+    }
+}
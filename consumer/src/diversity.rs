@@ -0,0 +1,131 @@
+//! Diversity of the samples generated for the same prompt.
+//!
+//! Samples are the choices of a response (`n` > 1) and the responses generated earlier
+//! for the same body hash, the prompt group. Each is compared with the others by the
+//! Jaccard similarity of their word n-grams; a sample at least `DIVERSITY_THRESHOLD`
+//! similar to another is a near duplicate. The group's diversity score is one minus the
+//! mean pairwise similarity, written on the event with the group size, so the latest
+//! event of a group carries its current score.
+
+use crate::schemas::provider_response::{content, text_blocks};
+use crate::settings::DiversitySettings;
+use crate::text;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+
+/// Texts of every choice (or candidate) of a response.
+pub fn sample_texts(completions: &Value) -> Vec<String> {
+    match (
+        completions["choices"].as_array(),
+        completions["candidates"].as_array(),
+    ) {
+        (Some(choices), _) => choices
+            .iter()
+            .filter_map(|choice| {
+                choice["message"]["content"]
+                    .as_str()
+                    .or_else(|| choice["text"].as_str())
+                    .map(str::to_string)
+            })
+            .collect(),
+        (None, Some(candidates)) => candidates
+            .iter()
+            .filter_map(|candidate| {
+                text_blocks(candidate["content"]["parts"].as_array()?.iter())
+                    .map(|text| text.into_owned())
+            })
+            .collect(),
+        (None, None) => content(completions)
+            .map(|text| text.into_owned())
+            .into_iter()
+            .collect(),
+    }
+}
+
+fn shingles(sample: &str, n: usize) -> HashSet<u64> {
+    let words = text::words(sample);
+    if words.len() < n {
+        // Too short for a whole n-gram, compared as one.
+        return text::ngram_hashes(&words, words.len()).collect();
+    }
+    text::ngram_hashes(&words, n).collect()
+}
+
+/// Jaccard similarity of two n-gram sets; two empty samples are identical.
+fn jaccard(a: &HashSet<u64>, b: &HashSet<u64>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let shared = a.intersection(b).count();
+    shared as f64 / (a.len() + b.len() - shared) as f64
+}
+
+/// Diversity of new samples within their prompt group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diversity {
+    /// One minus the mean pairwise similarity of the group, 1 with a single sample.
+    pub score: f64,
+    /// Highest similarity of a new sample to any other sample of the group.
+    pub max_similarity: f64,
+    /// New samples at least `threshold` similar to another sample.
+    pub near_duplicates: usize,
+    /// Samples of the group, earlier and new.
+    pub group_size: usize,
+}
+
+impl Diversity {
+    pub fn annotations(&self) -> Map<String, Value> {
+        let mut annotations = Map::new();
+        annotations.insert("diversity_score".to_string(), json!(self.score));
+        annotations.insert(
+            "diversity_max_similarity".to_string(),
+            json!(self.max_similarity),
+        );
+        annotations.insert("diversity_group_size".to_string(), json!(self.group_size));
+        annotations.insert(
+            "near_duplicate".to_string(),
+            json!(self.near_duplicates > 0),
+        );
+        annotations
+    }
+}
+
+/// Compares the `new` samples with each other and with the `earlier` ones of their
+/// prompt group.
+pub fn assess(settings: &DiversitySettings, earlier: &[String], new: &[String]) -> Diversity {
+    let n = settings.ngram_size.max(1);
+    let group: Vec<HashSet<u64>> = earlier
+        .iter()
+        .chain(new)
+        .map(|sample| shingles(sample, n))
+        .collect();
+    let first_new = earlier.len();
+
+    let mut total = 0.0;
+    let mut pairs = 0;
+    let mut closest = vec![0.0f64; group.len()];
+    for i in 0..group.len() {
+        for j in i + 1..group.len() {
+            let similarity = jaccard(&group[i], &group[j]);
+            total += similarity;
+            pairs += 1;
+            closest[i] = closest[i].max(similarity);
+            closest[j] = closest[j].max(similarity);
+        }
+    }
+
+    let closest_new = &closest[first_new..];
+    Diversity {
+        score: if pairs == 0 {
+            1.0
+        } else {
+            1.0 - total / pairs as f64
+        },
+        max_similarity: closest_new.iter().copied().fold(0.0, f64::max),
+        near_duplicates: closest_new
+            .iter()
+            .filter(|&&similarity| similarity >= settings.threshold)
+            .count(),
+        group_size: group.len(),
+    }
+}
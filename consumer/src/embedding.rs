@@ -0,0 +1,301 @@
+use crate::llm_wrapper::LLMClient;
+use crate::settings::{EmbeddingProviderKind, EmbeddingSettings};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+type EmbeddingResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Source of text embeddings shared by the semantic stages (vector indexing,
+/// dedup, contamination).
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Embeds `texts`, returning one vector per input in the same order.
+    async fn embed_batch(&self, texts: &[String]) -> EmbeddingResult<Vec<Vec<f32>>>;
+}
+
+async fn post_json(
+    client: &LLMClient,
+    url: &str,
+    api_key: &str,
+    body: &Value,
+) -> EmbeddingResult<Value> {
+    let response = client
+        .for_url(url)
+        .post(url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .json(body)
+        .send()
+        .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_body = response.text().await.unwrap_or_default();
+        return Err(format!("Embedding request failed ({}): {}", status, error_body).into());
+    }
+    Ok(response.json::<Value>().await?)
+}
+
+/// Floats of a JSON array, as `f32`.
+pub fn parse_vector(value: &Value) -> Option<Vec<f32>> {
+    value.as_array().map(|v| {
+        v.iter()
+            .filter_map(|f| f.as_f64().map(|f| f as f32))
+            .collect()
+    })
+}
+
+fn check_count(vectors: Vec<Vec<f32>>, expected: usize) -> EmbeddingResult<Vec<Vec<f32>>> {
+    if vectors.len() != expected || vectors.iter().any(|v| v.is_empty()) {
+        return Err(format!(
+            "Embedding provider returned {} vectors for {} inputs",
+            vectors.len(),
+            expected
+        )
+        .into());
+    }
+    Ok(vectors)
+}
+
+/// OpenAI-compatible `/v1/embeddings` endpoint (OpenAI, vLLM, LiteLLM, TEI, ...).
+pub struct OpenAiEmbeddingProvider {
+    client: LLMClient,
+    url: String,
+    api_key: String,
+    model: String,
+}
+
+impl OpenAiEmbeddingProvider {
+    pub fn new(client: LLMClient, url: String, api_key: String, model: String) -> Self {
+        Self {
+            client,
+            url,
+            api_key,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAiEmbeddingProvider {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> EmbeddingResult<Vec<Vec<f32>>> {
+        let body = json!({ "model": self.model, "input": texts });
+        let response = post_json(&self.client, &self.url, &self.api_key, &body).await?;
+        let mut data = response["data"]
+            .as_array()
+            .ok_or("Embedding response has no data array")?
+            .clone();
+        // Entries carry their input position; don't rely on response order.
+        data.sort_by_key(|d| d["index"].as_u64().unwrap_or(0));
+        let vectors = data
+            .iter()
+            .filter_map(|d| parse_vector(&d["embedding"]))
+            .collect();
+        check_count(vectors, texts.len())
+    }
+}
+
+/// Cohere `/v2/embed` endpoint.
+pub struct CohereEmbeddingProvider {
+    client: LLMClient,
+    url: String,
+    api_key: String,
+    model: String,
+}
+
+impl CohereEmbeddingProvider {
+    pub fn new(client: LLMClient, url: String, api_key: String, model: String) -> Self {
+        Self {
+            client,
+            url,
+            api_key,
+            model,
+        }
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for CohereEmbeddingProvider {
+    fn name(&self) -> &str {
+        "cohere"
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> EmbeddingResult<Vec<Vec<f32>>> {
+        let body = json!({
+            "model": self.model,
+            "texts": texts,
+            "input_type": "search_document",
+            "embedding_types": ["float"],
+        });
+        let response = post_json(&self.client, &self.url, &self.api_key, &body).await?;
+        let vectors = response["embeddings"]["float"]
+            .as_array()
+            .ok_or("Cohere response has no embeddings.float array")?
+            .iter()
+            .filter_map(parse_vector)
+            .collect();
+        check_count(vectors, texts.len())
+    }
+}
+
+#[cfg(feature = "candle")]
+pub use crate::local_models::LocalEmbeddingProvider;
+
+pub fn build_provider(
+    client: LLMClient,
+    settings: &EmbeddingSettings,
+) -> EmbeddingResult<Arc<dyn EmbeddingProvider>> {
+    match settings.provider {
+        EmbeddingProviderKind::OpenAi => Ok(Arc::new(OpenAiEmbeddingProvider::new(
+            client,
+            settings.url.clone(),
+            settings.api_key.clone(),
+            settings.model.clone(),
+        ))),
+        EmbeddingProviderKind::Cohere => Ok(Arc::new(CohereEmbeddingProvider::new(
+            client,
+            settings.url.clone(),
+            settings.api_key.clone(),
+            settings.model.clone(),
+        ))),
+        #[cfg(feature = "candle")]
+        EmbeddingProviderKind::Local => Ok(Arc::new(LocalEmbeddingProvider::load(&settings.url)?)),
+        #[cfg(not(feature = "candle"))]
+        EmbeddingProviderKind::Local => {
+            Err("Local embeddings require building with the `candle` feature".into())
+        }
+    }
+}
+
+struct EmbeddingCache {
+    capacity: usize,
+    entries: HashMap<[u8; 32], Vec<f32>>,
+    order: VecDeque<[u8; 32]>,
+}
+
+impl EmbeddingCache {
+    fn get(&self, key: &[u8; 32]) -> Option<Vec<f32>> {
+        self.entries.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: [u8; 32], vector: Vec<f32>) {
+        if self.capacity == 0 || self.entries.contains_key(&key) {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        self.entries.insert(key, vector);
+    }
+}
+
+struct PendingEmbedding {
+    text: String,
+    reply: oneshot::Sender<Result<Vec<f32>, String>>,
+}
+
+/// Front door for embedding calls: answers repeated texts from a bounded FIFO cache
+/// and coalesces concurrent requests into provider batches.
+pub struct Embedder {
+    provider_name: String,
+    sender: mpsc::Sender<PendingEmbedding>,
+    cache: Mutex<EmbeddingCache>,
+}
+
+impl Embedder {
+    pub fn new(provider: Arc<dyn EmbeddingProvider>, settings: &EmbeddingSettings) -> Self {
+        let (sender, receiver) = mpsc::channel(settings.batch_size.max(1) * 4);
+        tokio::spawn(run_batcher(
+            provider.clone(),
+            receiver,
+            settings.batch_size.max(1),
+            Duration::from_millis(settings.batch_wait_ms),
+        ));
+        Self {
+            provider_name: provider.name().to_string(),
+            sender,
+            cache: Mutex::new(EmbeddingCache {
+                capacity: settings.cache_capacity,
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn provider_name(&self) -> &str {
+        &self.provider_name
+    }
+
+    pub async fn embed(&self, text: &str) -> EmbeddingResult<Vec<f32>> {
+        let key: [u8; 32] = Sha256::digest(text.as_bytes()).into();
+        if let Some(vector) = self.cache.lock().unwrap().get(&key) {
+            return Ok(vector);
+        }
+
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(PendingEmbedding {
+                text: text.to_string(),
+                reply,
+            })
+            .await
+            .map_err(|_| "Embedding batcher has stopped")?;
+        let vector = response
+            .await
+            .map_err(|_| "Embedding batcher dropped the request")??;
+
+        self.cache.lock().unwrap().insert(key, vector.clone());
+        Ok(vector)
+    }
+}
+
+async fn run_batcher(
+    provider: Arc<dyn EmbeddingProvider>,
+    mut receiver: mpsc::Receiver<PendingEmbedding>,
+    batch_size: usize,
+    batch_wait: Duration,
+) {
+    while let Some(first) = receiver.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::Instant::now() + batch_wait;
+        while batch.len() < batch_size {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(pending)) => batch.push(pending),
+                _ => break,
+            }
+        }
+
+        let texts: Vec<String> = batch.iter().map(|p| p.text.clone()).collect();
+        match provider.embed_batch(&texts).await {
+            Ok(vectors) => {
+                for (pending, vector) in batch.into_iter().zip(vectors) {
+                    let _ = pending.reply.send(Ok(vector));
+                }
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Embedding batch of {} failed on {}: {}",
+                    texts.len(),
+                    provider.name(),
+                    e
+                );
+                for pending in batch {
+                    let _ = pending.reply.send(Err(e.to_string()));
+                }
+            }
+        }
+    }
+}
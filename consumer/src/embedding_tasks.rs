@@ -0,0 +1,120 @@
+//! Tasks of kind `embedding`, which send their body (`model`, `input` and optionally
+//! `dimensions`) to the provider's OpenAI-compatible embeddings endpoint instead of
+//! generating a completion, so retrieval corpora are built through the same pipeline.
+//!
+//! The provider's response is kept as the task's completions. Its vectors are also
+//! stored for search: by default on the event, in the `embedding` field (a
+//! `dense_vector`) for a single input or the unindexed `embeddings` field for several;
+//! with `EMBEDDING_TASK_INDEX` set, as one document per input in that index instead.
+//! Either way the event records the `embedding_model`, `embedding_dimensions` and
+//! `embedding_count`.
+
+use crate::db::EmbeddingDocument;
+use crate::embedding::parse_vector;
+use crate::schemas::provider_response::Usage;
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+
+pub const KIND: &str = "embedding";
+
+pub fn is_embedding_task(payload: &Value) -> bool {
+    payload["kind"].as_str() == Some(KIND)
+}
+
+/// Embeddings endpoint of a provider, which may be configured by its chat completions
+/// URL, e.g. a fallback provider.
+pub fn embeddings_url(url: &str) -> Cow<'_, str> {
+    match url.strip_suffix("/chat/completions") {
+        Some(base) => Cow::Owned(format!("{}/embeddings", base)),
+        None => Cow::Borrowed(url),
+    }
+}
+
+/// Vectors of an embeddings response, in input order.
+pub fn vectors(raw: &Value) -> Result<Vec<Vec<f32>>, String> {
+    let mut data: Vec<&Value> = raw["data"]
+        .as_array()
+        .ok_or("Embeddings response has no data array")?
+        .iter()
+        .collect();
+    // Entries carry their input position; don't rely on response order.
+    data.sort_by_key(|d| d["index"].as_u64().unwrap_or(0));
+    let vectors: Vec<Vec<f32>> = data
+        .iter()
+        .filter_map(|d| parse_vector(&d["embedding"]))
+        .filter(|v| !v.is_empty())
+        .collect();
+    if vectors.is_empty() || vectors.len() != data.len() {
+        return Err(format!(
+            "Embeddings response has {} vectors for {} entries",
+            vectors.len(),
+            data.len()
+        ));
+    }
+    Ok(vectors)
+}
+
+/// Token usage of an embeddings response, which only counts input tokens.
+pub fn usage(raw: &Value) -> Option<Usage> {
+    let prompt_tokens = raw["usage"]["prompt_tokens"].as_u64()?;
+    Some(Usage {
+        prompt_tokens,
+        completion_tokens: 0,
+        total_tokens: raw["usage"]["total_tokens"]
+            .as_u64()
+            .unwrap_or(prompt_tokens),
+    })
+}
+
+/// Documents of the vector index for the task's `body` and embeddings response `raw`.
+pub fn vector_documents<'a>(
+    message_id: &'a str,
+    batch_id: &'a str,
+    body: &'a Value,
+    raw: &'a Value,
+) -> Result<Vec<EmbeddingDocument<'a>>, String> {
+    let model = raw["model"].as_str().or(body["model"].as_str());
+    Ok(vectors(raw)?
+        .into_iter()
+        .enumerate()
+        .map(|(position, vector)| EmbeddingDocument {
+            message_id,
+            batch_id,
+            position,
+            input: match &body["input"] {
+                Value::String(input) if position == 0 => Some(input.as_str()),
+                Value::Array(inputs) => inputs.get(position).and_then(Value::as_str),
+                _ => None,
+            },
+            model,
+            vector,
+        })
+        .collect())
+}
+
+/// Annotations of the task's event; `index` is the vector index its vectors were
+/// written to, if any, otherwise they're kept on the event.
+pub fn annotations(
+    body: &Value,
+    raw: &Value,
+    index: Option<&str>,
+) -> Result<Map<String, Value>, String> {
+    let vectors = vectors(raw)?;
+    let mut annotations = Map::new();
+    let model = raw["model"].as_str().or(body["model"].as_str());
+    annotations.insert("embedding_model".to_string(), json!(model));
+    annotations.insert("embedding_dimensions".to_string(), json!(vectors[0].len()));
+    annotations.insert("embedding_count".to_string(), json!(vectors.len()));
+    match index {
+        Some(index) => {
+            annotations.insert("embedding_index".to_string(), json!(index));
+        }
+        None if vectors.len() == 1 => {
+            annotations.insert("embedding".to_string(), json!(vectors[0]));
+        }
+        None => {
+            annotations.insert("embeddings".to_string(), json!(vectors));
+        }
+    }
+    Ok(annotations)
+}
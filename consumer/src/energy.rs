@@ -0,0 +1,63 @@
+//! Per-model energy factors, used to estimate the energy use and carbon emissions of
+//! each completion for campaign reporting.
+//!
+//! Estimates are recorded in the `energy_wh` and `co2_grams` annotations of fresh
+//! completions, which the API sums per batch. Models are matched like prices.
+
+use crate::pricing::model_entry;
+use crate::schemas::provider_response::Usage;
+use crate::settings::{EnergySettings, ModelEnergy};
+use serde_json::{json, Map, Value};
+
+/// Estimated footprint of a completion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Footprint {
+    pub energy_wh: f64,
+    pub co2_grams: f64,
+}
+
+impl Footprint {
+    pub fn annotations(&self) -> Map<String, Value> {
+        let mut annotations = Map::new();
+        annotations.insert("energy_wh".to_string(), json!(self.energy_wh));
+        annotations.insert("co2_grams".to_string(), json!(self.co2_grams));
+        annotations
+    }
+}
+
+/// Footprint of `usage` with `energy`, at `grams_co2_per_kwh` unless the model has its
+/// own intensity.
+pub fn footprint(energy: &ModelEnergy, usage: &Usage, grams_co2_per_kwh: f64) -> Footprint {
+    let energy_wh = usage.prompt_tokens as f64 / 1000.0 * energy.input_wh_per_1k
+        + usage.completion_tokens as f64 / 1000.0 * energy.output_wh_per_1k;
+    let intensity = energy.grams_co2_per_kwh.unwrap_or(grams_co2_per_kwh);
+    Footprint {
+        energy_wh,
+        co2_grams: energy_wh / 1000.0 * intensity,
+    }
+}
+
+#[derive(Default)]
+pub struct EnergyTable {
+    settings: EnergySettings,
+}
+
+impl EnergyTable {
+    pub fn new(settings: &EnergySettings) -> Self {
+        Self {
+            settings: settings.clone(),
+        }
+    }
+
+    /// Footprint of `usage` under the first of `models` with known energy factors.
+    pub fn footprint<'a>(
+        &self,
+        models: impl IntoIterator<Item = &'a str>,
+        usage: &Usage,
+    ) -> Option<Footprint> {
+        models
+            .into_iter()
+            .find_map(|model| model_entry(&self.settings.models, model))
+            .map(|energy| footprint(energy, usage, self.settings.grams_co2_per_kwh))
+    }
+}
@@ -0,0 +1,212 @@
+//! Scoring of benchmark-style tasks, which carry a reference answer in `evaluation`, so
+//! a batch doubles as an eval run.
+//!
+//! The answer is the completion, or the first group of `answer_pattern` in it, and is
+//! checked against the reference (any entry of a list) by `method`:
+//! - `exact_match`: equal after trimming and collapsing whitespace, and ignoring case
+//!   unless `case_sensitive`;
+//! - `regex`: the reference pattern matches the answer;
+//! - `numeric`: the last number of the answer is within `tolerance` of the reference;
+//! - `judge`: the `EVAL_JUDGE` model deems the answer equivalent to the reference.
+//!
+//! The `evaluation` annotation holds the method, the extracted answer, whether it's
+//! `correct` and its `score`, or an `error` when it couldn't be scored. Tasks of an
+//! eval `suite` also name it, and the pipeline adds the `model` that answered, so
+//! their scores add up on the suite's leaderboard (see `db::leaderboard`).
+
+use crate::llm_wrapper::{self, LLMClient};
+use crate::settings::AuxModelSettings;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EvaluationMethod {
+    ExactMatch,
+    Regex,
+    Numeric,
+    Judge,
+}
+
+/// `evaluation` field of a task.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EvaluationSpec {
+    pub method: EvaluationMethod,
+    /// Expected answer, or a list of accepted ones.
+    pub reference: Value,
+    /// Regular expression whose first group is the answer within the completion.
+    #[serde(default)]
+    pub answer_pattern: Option<String>,
+    #[serde(default)]
+    pub case_sensitive: bool,
+    #[serde(default = "default_tolerance")]
+    pub tolerance: f64,
+    /// Eval suite the task belongs to, whose leaderboard it counts on.
+    #[serde(default)]
+    pub suite: Option<String>,
+}
+
+fn default_tolerance() -> f64 {
+    1e-6
+}
+
+/// Scores `completion` of the task whose prompt is `prompt` and returns the
+/// `evaluation` annotation.
+pub async fn evaluate(
+    client: &LLMClient,
+    judge: Option<&AuxModelSettings>,
+    spec: &Value,
+    prompt: &str,
+    completion: &str,
+) -> Value {
+    let spec: EvaluationSpec = match serde_json::from_value(spec.clone()) {
+        Ok(spec) => spec,
+        Err(e) => {
+            let error = format!("Invalid evaluation: {}", e);
+            return json!({ "correct": null, "error": error });
+        }
+    };
+    let method = serde_json::to_value(spec.method).unwrap_or_default();
+    let outcome = match extract_answer(&spec, completion) {
+        Ok(answer) => score(client, judge, &spec, prompt, &answer)
+            .await
+            .map(|correct| (answer, correct)),
+        Err(e) => Err(e),
+    };
+    let mut evaluation = match outcome {
+        Ok((answer, correct)) => json!({
+            "method": method,
+            "answer": answer,
+            "correct": correct,
+            "score": if correct { 1.0 } else { 0.0 },
+        }),
+        Err(error) => json!({ "method": method, "correct": null, "error": error }),
+    };
+    if let Some(suite) = spec.suite {
+        evaluation["suite"] = Value::from(suite);
+    }
+    evaluation
+}
+
+/// Whether `answer` matches the reference of `spec`.
+pub async fn score(
+    client: &LLMClient,
+    judge: Option<&AuxModelSettings>,
+    spec: &EvaluationSpec,
+    prompt: &str,
+    answer: &str,
+) -> Result<bool, String> {
+    let references = references(&spec.reference);
+    if references.is_empty() {
+        return Err("The evaluation has no reference answer".to_string());
+    }
+    match spec.method {
+        EvaluationMethod::ExactMatch => {
+            let answer = normalize(answer, spec.case_sensitive);
+            Ok(references
+                .iter()
+                .any(|reference| normalize(reference, spec.case_sensitive) == answer))
+        }
+        EvaluationMethod::Regex => {
+            for reference in &references {
+                let pattern = RegexBuilder::new(reference)
+                    .case_insensitive(!spec.case_sensitive)
+                    .build()
+                    .map_err(|e| format!("Invalid reference pattern: {}", e))?;
+                if pattern.is_match(answer) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        EvaluationMethod::Numeric => {
+            let Some(value) = last_number(answer) else {
+                return Ok(false);
+            };
+            let expected: Vec<f64> = references
+                .iter()
+                .map(|reference| {
+                    parse_number(reference)
+                        .ok_or_else(|| format!("Reference `{}` isn't a number", reference))
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(expected
+                .iter()
+                .any(|expected| (value - expected).abs() <= spec.tolerance))
+        }
+        EvaluationMethod::Judge => {
+            let judge = judge.ok_or("The judge model isn't configured (EVAL_JUDGE_URL)")?;
+            let question = format!(
+                "Grade whether the answer to the question below is correct, given the reference \
+                 answer. Minor differences in wording or formatting don't matter.\n\
+                 Reply with CORRECT or INCORRECT only.\n\nQuestion:\n{}\n\n\
+                 Reference answer:\n{}\n\nAnswer:\n{}",
+                prompt,
+                references.join("\nor\n"),
+                answer
+            );
+            let verdict = llm_wrapper::complete_prompt(client, judge, &question)
+                .await
+                .map_err(|e| format!("Judge model failed: {}", e))?
+                .to_uppercase();
+            if verdict.contains("INCORRECT") {
+                Ok(false)
+            } else if verdict.contains("CORRECT") {
+                Ok(true)
+            } else {
+                Err(format!("Unparseable judge verdict: {}", verdict))
+            }
+        }
+    }
+}
+
+/// Part of `completion` checked against the reference.
+pub fn extract_answer(spec: &EvaluationSpec, completion: &str) -> Result<String, String> {
+    let Some(pattern) = &spec.answer_pattern else {
+        return Ok(completion.trim().to_string());
+    };
+    let pattern = Regex::new(pattern).map_err(|e| format!("Invalid answer pattern: {}", e))?;
+    Ok(pattern
+        .captures(completion)
+        .and_then(|captures| captures.get(1).or_else(|| captures.get(0)))
+        .map(|answer| answer.as_str().trim().to_string())
+        .unwrap_or_default())
+}
+
+fn references(reference: &Value) -> Vec<String> {
+    let as_text = |value: &Value| match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(flag) => Some(flag.to_string()),
+        _ => None,
+    };
+    match reference {
+        Value::Array(references) => references.iter().filter_map(as_text).collect(),
+        reference => as_text(reference).into_iter().collect(),
+    }
+}
+
+/// `text` trimmed of whitespace and trailing periods, with inner whitespace collapsed.
+fn normalize(text: &str, case_sensitive: bool) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let text = text.trim_end_matches('.').trim();
+    if case_sensitive {
+        text.to_string()
+    } else {
+        text.to_lowercase()
+    }
+}
+
+/// Last number written in `text`, thousands separators allowed.
+fn last_number(text: &str) -> Option<f64> {
+    let number = Regex::new(r"-?\d[\d,]*(?:\.\d+)?(?:[eE][-+]?\d+)?").ok()?;
+    number
+        .find_iter(text)
+        .filter_map(|found| parse_number(found.as_str()))
+        .last()
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    text.trim().replace(',', "").parse().ok()
+}
@@ -0,0 +1,481 @@
+//! Exports of the prompt/completion pairs of a batch's completed events, as JSONL,
+//! Parquet, CSV or SQLite, to a local directory or an S3-compatible bucket
+//! (`EXPORT_SINK=s3://...`).
+//!
+//! Each row has the columns of `EXPORT_FIELDS`, read from the event: `prompt` is the text
+//! of the request body's messages (or legacy prompt), `messages` the messages
+//! themselves, `completion` the text of the first choice, and any other source the
+//! dotted path of an event field, e.g. `custom_id` or `body.model`. The export is
+//! written page by page to a temporary file, then stored as a single
+//! `{batch_id}.{jsonl,parquet,csv,sqlite}` file or object.
+//!
+//! Parquet files hold a Snappy-compressed row group per page of events, of optional
+//! UTF-8 columns with values that aren't strings written as JSON. CSV files have a
+//! header row and SQLite databases a `results` table, with the same text values (nulls
+//! are empty CSV cells), for analysts opening them in a spreadsheet or a SQLite browser.
+//!
+//! Rows are in message id order, or easiest first by difficulty score with
+//! `EXPORT_ORDER=curriculum`, which has to hold every row of the batch to sort them.
+//!
+//! Runs on demand with `consumer export --batch-id <id>`.
+
+use crate::archive::Archive;
+use crate::db::TaskStore;
+use crate::difficulty;
+use crate::schemas::provider_response;
+use crate::settings::{ArchiveSettings, ExportField, ExportFormat, ExportOrder, ExportSettings};
+use crate::text::prompt_text;
+use parquet::basic::{Compression, LogicalType, Repetition, Type as PhysicalType};
+use parquet::data_type::{ByteArray, ByteArrayType};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use serde_json::{Map, Value};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqliteJournalMode};
+use sqlx::Connection;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::info;
+
+type ExportResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Completed events looked up at a time.
+const PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportReport {
+    pub rows: usize,
+    /// Path or `s3://` URL of the written file.
+    pub location: String,
+}
+
+/// Value of the column read from `source` of `event`, null when the event has none.
+pub fn field(event: &Value, source: &str) -> Value {
+    match source {
+        "prompt" if event["body"].is_object() => Value::String(prompt_text(&event["body"])),
+        "prompt" => Value::Null,
+        "messages" => event["body"]["messages"].clone(),
+        "completion" => provider_response::content(&event["completions"])
+            .map(|content| Value::String(content.into_owned()))
+            .unwrap_or(Value::Null),
+        path => path
+            .split('.')
+            .fold(event, |value, key| &value[key])
+            .clone(),
+    }
+}
+
+/// Export row of `event`, with a column per field.
+pub fn row(event: &Value, fields: &[ExportField]) -> Map<String, Value> {
+    fields
+        .iter()
+        .map(|f| (f.name.clone(), field(event, &f.source)))
+        .collect()
+}
+
+pub fn jsonl(rows: &[Map<String, Value>]) -> ExportResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    for row in rows {
+        serde_json::to_writer(&mut buf, row)?;
+        buf.push(b'\n');
+    }
+    Ok(buf)
+}
+
+/// Text of a column value: strings as they are, other values as JSON, null as `None`.
+pub fn cell(value: Option<&Value>) -> Option<String> {
+    match value {
+        None | Some(Value::Null) => None,
+        Some(Value::String(value)) => Some(value.clone()),
+        Some(value) => Some(value.to_string()),
+    }
+}
+
+fn push_record<'a>(buf: &mut String, values: impl Iterator<Item = Option<&'a str>>) {
+    for (i, value) in values.enumerate() {
+        if i > 0 {
+            buf.push(',');
+        }
+        let value = value.unwrap_or_default();
+        if value.contains([',', '"', '\n', '\r']) {
+            buf.push('"');
+            buf.push_str(&value.replace('"', "\"\""));
+            buf.push('"');
+        } else {
+            buf.push_str(value);
+        }
+    }
+    buf.push_str("\r\n");
+}
+
+/// CSV header row of the field names.
+fn csv_header(fields: &[ExportField]) -> Vec<u8> {
+    let mut buf = String::new();
+    push_record(&mut buf, fields.iter().map(|f| Some(f.name.as_str())));
+    buf.into_bytes()
+}
+
+/// CSV records of `rows`, without the header row.
+fn csv_records(fields: &[ExportField], rows: &[Map<String, Value>]) -> Vec<u8> {
+    let mut buf = String::new();
+    for row in rows {
+        let values: Vec<Option<String>> = fields.iter().map(|f| cell(row.get(&f.name))).collect();
+        push_record(&mut buf, values.iter().map(Option::as_deref));
+    }
+    buf.into_bytes()
+}
+
+/// CSV file of `rows`, with a header row of the field names.
+pub fn csv(fields: &[ExportField], rows: &[Map<String, Value>]) -> Vec<u8> {
+    let mut buf = csv_header(fields);
+    buf.extend_from_slice(&csv_records(fields, rows));
+    buf
+}
+
+/// SQLite database of `rows`, with a `results` table of a TEXT column per field. The
+/// database is built in a temporary file, read back whole.
+pub async fn sqlite(fields: &[ExportField], rows: &[Map<String, Value>]) -> ExportResult<Vec<u8>> {
+    let path = temporary_file("sqlite");
+    let written = async {
+        let mut table = SqliteTable::create(&path, fields).await?;
+        table.insert(rows).await?;
+        table.close().await
+    }
+    .await;
+    let database = match written {
+        Ok(()) => tokio::fs::read(&path).await.map_err(Into::into),
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_file(&path).await;
+    database
+}
+
+/// `results` table of a SQLite database being written, a transaction per insert.
+struct SqliteTable {
+    connection: SqliteConnection,
+    insert: String,
+    fields: Vec<String>,
+}
+
+impl SqliteTable {
+    async fn create(path: &Path, fields: &[ExportField]) -> ExportResult<Self> {
+        if fields.is_empty() {
+            return Err("No export fields".into());
+        }
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Off);
+        let mut connection = SqliteConnection::connect_with(&options).await?;
+
+        let columns: Vec<String> = fields
+            .iter()
+            .map(|f| format!("\"{}\"", f.name.replace('"', "\"\"")))
+            .collect();
+        sqlx::query(&format!(
+            "CREATE TABLE results ({} TEXT)",
+            columns.join(" TEXT, ")
+        ))
+        .execute(&mut connection)
+        .await?;
+
+        let insert = format!(
+            "INSERT INTO results ({}) VALUES ({})",
+            columns.join(", "),
+            vec!["?"; fields.len()].join(", ")
+        );
+        Ok(Self {
+            connection,
+            insert,
+            fields: fields.iter().map(|f| f.name.clone()).collect(),
+        })
+    }
+
+    async fn insert(&mut self, rows: &[Map<String, Value>]) -> ExportResult<()> {
+        let mut transaction = self.connection.begin().await?;
+        for row in rows {
+            let mut query = sqlx::query(&self.insert);
+            for name in &self.fields {
+                query = query.bind(cell(row.get(name)));
+            }
+            query.execute(&mut *transaction).await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn close(self) -> ExportResult<()> {
+        self.connection.close().await?;
+        Ok(())
+    }
+}
+
+/// Parquet file being written to `W`, a row group per call to `write`, with an optional
+/// UTF-8 column per field.
+pub struct ParquetWriter<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    fields: Vec<String>,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    pub fn new(out: W, fields: &[ExportField]) -> ExportResult<Self> {
+        let columns = fields
+            .iter()
+            .map(|f| {
+                Type::primitive_type_builder(&f.name, PhysicalType::BYTE_ARRAY)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .with_logical_type(Some(LogicalType::String))
+                    .build()
+                    .map(Arc::new)
+            })
+            .collect::<Result<_, _>>()?;
+        let schema = Type::group_type_builder("schema")
+            .with_fields(columns)
+            .build()?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_created_by("synthgen-consumer".to_string())
+            .build();
+        Ok(Self {
+            writer: SerializedFileWriter::new(out, Arc::new(schema), Arc::new(properties))?,
+            fields: fields.iter().map(|f| f.name.clone()).collect(),
+        })
+    }
+
+    /// Writes `rows` as a row group; nothing when there are none.
+    pub fn write(&mut self, rows: &[Map<String, Value>]) -> ExportResult<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group()?;
+        for name in &self.fields {
+            let mut values = Vec::with_capacity(rows.len());
+            let mut levels = Vec::with_capacity(rows.len());
+            for row in rows {
+                match cell(row.get(name)) {
+                    Some(value) => {
+                        values.push(ByteArray::from(value.into_bytes()));
+                        levels.push(1);
+                    }
+                    None => levels.push(0),
+                }
+            }
+            let mut column = row_group
+                .next_column()?
+                .ok_or("Parquet schema has fewer columns than the export fields")?;
+            column
+                .typed::<ByteArrayType>()
+                .write_batch(&values, Some(&levels), None)?;
+            column.close()?;
+        }
+        row_group.close()?;
+        Ok(())
+    }
+
+    /// Writes the footer and returns the output.
+    pub fn finish(self) -> ExportResult<W> {
+        Ok(self.writer.into_inner()?)
+    }
+}
+
+/// Parquet file of `rows`, in a single row group.
+pub fn parquet(fields: &[ExportField], rows: &[Map<String, Value>]) -> ExportResult<Vec<u8>> {
+    let mut writer = ParquetWriter::new(Vec::new(), fields)?;
+    writer.write(rows)?;
+    writer.finish()
+}
+
+/// Export file being written page by page.
+enum ExportFile {
+    Jsonl(BufWriter<File>),
+    Csv(BufWriter<File>),
+    Parquet(ParquetWriter<BufWriter<File>>),
+    Sqlite(SqliteTable),
+}
+
+impl ExportFile {
+    async fn create(
+        path: &Path,
+        format: ExportFormat,
+        fields: &[ExportField],
+    ) -> ExportResult<Self> {
+        Ok(match format {
+            ExportFormat::Jsonl => ExportFile::Jsonl(BufWriter::new(File::create(path)?)),
+            ExportFormat::Csv => {
+                let mut file = BufWriter::new(File::create(path)?);
+                file.write_all(&csv_header(fields))?;
+                ExportFile::Csv(file)
+            }
+            ExportFormat::Parquet => ExportFile::Parquet(ParquetWriter::new(
+                BufWriter::new(File::create(path)?),
+                fields,
+            )?),
+            ExportFormat::Sqlite => ExportFile::Sqlite(SqliteTable::create(path, fields).await?),
+        })
+    }
+
+    async fn write(
+        &mut self,
+        fields: &[ExportField],
+        rows: &[Map<String, Value>],
+    ) -> ExportResult<()> {
+        match self {
+            ExportFile::Jsonl(file) => file.write_all(&jsonl(rows)?)?,
+            ExportFile::Csv(file) => file.write_all(&csv_records(fields, rows))?,
+            ExportFile::Parquet(writer) => writer.write(rows)?,
+            ExportFile::Sqlite(table) => table.insert(rows).await?,
+        }
+        Ok(())
+    }
+
+    async fn close(self) -> ExportResult<()> {
+        match self {
+            ExportFile::Jsonl(mut file) | ExportFile::Csv(mut file) => file.flush()?,
+            ExportFile::Parquet(writer) => writer.finish()?.flush()?,
+            ExportFile::Sqlite(table) => table.close().await?,
+        }
+        Ok(())
+    }
+}
+
+fn temporary_file(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "synthgen-export-{}.{}",
+        uuid::Uuid::new_v4(),
+        extension
+    ))
+}
+
+/// Where export files are written.
+pub enum Sink {
+    Directory(PathBuf),
+    Bucket(Archive),
+}
+
+impl Sink {
+    /// Sink of `EXPORT_SINK`; `s3://` URLs are written to with the endpoint and
+    /// credentials of `archive`.
+    pub fn parse(sink: &str, archive: Option<&ArchiveSettings>) -> ExportResult<Self> {
+        let Some(location) = sink.strip_prefix("s3://") else {
+            return Ok(Sink::Directory(PathBuf::from(sink)));
+        };
+        let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if bucket.is_empty() {
+            return Err(format!("No bucket in EXPORT_SINK {}", sink).into());
+        }
+        let archive =
+            archive.ok_or("EXPORT_SINK is an S3 URL but ARCHIVE_S3_ENDPOINT is not set")?;
+        let prefix = prefix.trim_matches('/');
+        Ok(Sink::Bucket(Archive::new(&ArchiveSettings {
+            bucket: bucket.to_string(),
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", prefix)
+            },
+            ..archive.clone()
+        })))
+    }
+
+    /// Stores the file at `path` as the file `name`, and returns its location.
+    pub async fn write_file(
+        &self,
+        name: &str,
+        path: &Path,
+        content_type: &str,
+    ) -> ExportResult<String> {
+        match self {
+            Sink::Directory(directory) => {
+                tokio::fs::create_dir_all(directory).await?;
+                let destination = directory.join(name);
+                tokio::fs::copy(path, &destination).await?;
+                Ok(destination.display().to_string())
+            }
+            Sink::Bucket(archive) => archive.put_file(name, path, content_type).await,
+        }
+    }
+}
+
+/// Writes the completed events of `batch_id` to `sink`.
+pub async fn export_batch(
+    store: &dyn TaskStore,
+    batch_id: &str,
+    settings: &ExportSettings,
+    sink: &Sink,
+) -> ExportResult<ExportReport> {
+    let (extension, content_type) = match settings.format {
+        ExportFormat::Jsonl => ("jsonl", "application/x-ndjson"),
+        ExportFormat::Parquet => ("parquet", "application/vnd.apache.parquet"),
+        ExportFormat::Csv => ("csv", "text/csv"),
+        ExportFormat::Sqlite => ("sqlite", "application/vnd.sqlite3"),
+    };
+    let path = temporary_file(extension);
+    let exported: ExportResult<ExportReport> = async {
+        let rows = write_rows(store, batch_id, settings, &path).await?;
+        let location = sink
+            .write_file(&format!("{}.{}", batch_id, extension), &path, content_type)
+            .await?;
+        Ok(ExportReport { rows, location })
+    }
+    .await;
+    let _ = tokio::fs::remove_file(&path).await;
+    let report = exported?;
+    info!(
+        "Exported {} tasks of batch {} to {}",
+        report.rows, batch_id, report.location
+    );
+    Ok(report)
+}
+
+/// Writes the rows of the completed events of `batch_id` to the file at `path`, a page
+/// at a time unless they have to be sorted, and returns how many there were.
+async fn write_rows(
+    store: &dyn TaskStore,
+    batch_id: &str,
+    settings: &ExportSettings,
+    path: &Path,
+) -> ExportResult<usize> {
+    let mut file = ExportFile::create(path, settings.format, &settings.fields).await?;
+    let mut sorted = Vec::new();
+    let mut rows = 0;
+    let mut after: Option<String> = None;
+    loop {
+        let page = store
+            .completed_events(batch_id, after.as_deref(), PAGE_SIZE)
+            .await?;
+        rows += page.len();
+        if settings.order == ExportOrder::Curriculum {
+            sorted.extend(
+                page.iter()
+                    .map(|event| (difficulty::score(event), row(event, &settings.fields))),
+            );
+        } else {
+            let page: Vec<_> = page
+                .iter()
+                .map(|event| row(event, &settings.fields))
+                .collect();
+            file.write(&settings.fields, &page).await?;
+        }
+        after = match page.last() {
+            Some(last) if page.len() == PAGE_SIZE => {
+                last["message_id"].as_str().map(str::to_string)
+            }
+            _ => None,
+        };
+        if after.is_none() {
+            break;
+        }
+    }
+    if settings.order == ExportOrder::Curriculum {
+        // Stable, so rows of equal difficulty stay in message id order.
+        sorted.sort_by(|(a, _), (b, _)| difficulty::curriculum_order(*a, *b));
+        let sorted: Vec<_> = sorted.into_iter().map(|(_, row)| row).collect();
+        for page in sorted.chunks(PAGE_SIZE) {
+            file.write(&settings.fields, page).await?;
+        }
+    }
+    file.close().await?;
+    Ok(rows)
+}
@@ -0,0 +1,115 @@
+//! Feature flags switching pipeline stages on per run, tenant or share of tasks without
+//! a redeploy.
+//!
+//! Rules come from `FEATURE_FLAGS` and, when `FEATURE_FLAGS_INDEX` is set, from an
+//! Elasticsearch document re-read periodically, whose rules take precedence over the
+//! configured ones of the same name. A stage without a rule runs whenever its own
+//! settings enable it.
+
+use crate::db::elastic::ElasticStore;
+use crate::db::DbResult;
+use crate::settings::{FeatureFlagDocument, FeatureFlagRule, FeatureFlagSettings};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// What a flag is evaluated for.
+pub struct FlagContext<'a> {
+    pub batch_id: &'a str,
+    pub tenant: Option<&'a str>,
+    /// Stable key of the task, so a percentage rollout keeps its decision on retries.
+    pub key: &'a str,
+}
+
+#[derive(Deserialize)]
+struct FlagDocument {
+    #[serde(default)]
+    flags: HashMap<String, FeatureFlagRule>,
+}
+
+pub struct FeatureFlags {
+    configured: HashMap<String, FeatureFlagRule>,
+    /// Rules of the Elasticsearch document, as of the last refresh.
+    stored: RwLock<HashMap<String, FeatureFlagRule>>,
+}
+
+impl FeatureFlags {
+    pub fn new(settings: &FeatureFlagSettings) -> Self {
+        Self {
+            configured: settings.rules.clone(),
+            stored: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `flag` is on for the context; flags without a rule are on.
+    pub fn is_enabled(&self, flag: &str, context: &FlagContext<'_>) -> bool {
+        let stored = self.stored.read().unwrap();
+        match stored.get(flag).or_else(|| self.configured.get(flag)) {
+            Some(rule) => rule_matches(flag, rule, context),
+            None => true,
+        }
+    }
+
+    /// Re-reads the rules from the document until the process exits. A failed read
+    /// keeps the rules of the last successful one.
+    pub async fn run(&self, store: ElasticStore, document: &FeatureFlagDocument) {
+        let mut interval = tokio::time::interval(Duration::from_secs(document.refresh_secs.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.refresh(&store, document).await {
+                tracing::warn!(
+                    "Failed to refresh feature flags from {}/{}: {}",
+                    document.index,
+                    document.id,
+                    e
+                );
+            }
+        }
+    }
+
+    async fn refresh(&self, store: &ElasticStore, document: &FeatureFlagDocument) -> DbResult<()> {
+        let flags = match store.get_document(&document.index, &document.id).await? {
+            Some(source) => serde_json::from_value::<FlagDocument>(source)?.flags,
+            None => HashMap::new(),
+        };
+        let mut stored = self.stored.write().unwrap();
+        if *stored != flags {
+            tracing::info!("Feature flags updated: {} stored rules", flags.len());
+            *stored = flags;
+        }
+        Ok(())
+    }
+}
+
+fn rule_matches(flag: &str, rule: &FeatureFlagRule, context: &FlagContext<'_>) -> bool {
+    if !rule.enabled {
+        return false;
+    }
+    if !rule.batches.is_empty() && !rule.batches.iter().any(|b| b == context.batch_id) {
+        return false;
+    }
+    if !rule.tenants.is_empty()
+        && !context
+            .tenant
+            .is_some_and(|tenant| rule.tenants.iter().any(|t| t == tenant))
+    {
+        return false;
+    }
+    match rule.percentage {
+        Some(percentage) => bucket(flag, context.key) < percentage,
+        None => true,
+    }
+}
+
+/// Position of `key` in [0, 100) for `flag`; each flag spreads the keys differently so
+/// rollouts of different flags don't hit the same tasks.
+fn bucket(flag: &str, key: &str) -> f64 {
+    let digest = Sha256::new()
+        .chain_update(flag.as_bytes())
+        .chain_update([0])
+        .chain_update(key.as_bytes())
+        .finalize();
+    (u64::from_be_bytes(digest[..8].try_into().unwrap()) % 10_000) as f64 / 100.0
+}
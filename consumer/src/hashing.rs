@@ -0,0 +1,99 @@
+//! Hash algorithms of task keys and stored completions (`HASH_ALGORITHM`).
+//!
+//! SHA-256, the default, keeps the keys producers compute: base64 of the digest.
+//! BLAKE3 is faster on large bodies and completions; its keys are prefixed with
+//! `blake3:` so they never collide with SHA-256 ones, and cached completions keyed on
+//! SHA-256 stop being served after switching over. Producers hash with SHA-256, so
+//! BLAKE3 task keys take `CACHE_KEY_MODE=replace`.
+//!
+//! Completions stored content-addressably (`CONTENT_ADDRESSED_COMPLETIONS`) are keyed on
+//! `<algorithm>:<hex digest>` of the JSON of their content: the model and what it
+//! generated. The rest of a response, its `id`, `created` time, `usage` and the like,
+//! differs between identical generations and stays on the event.
+
+use crate::producer;
+use crate::settings::HashAlgorithm;
+use base64::Engine;
+use serde_json::{Map, Value};
+
+/// Fields of a response its content address covers: the model, and the choices of an
+/// OpenAI-compatible response or the content of an Anthropic one.
+pub const CONTENT_FIELDS: [&str; 3] = ["model", "choices", "content"];
+use sha2::{Digest, Sha256};
+
+impl HashAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> [u8; 32] {
+        match self {
+            HashAlgorithm::Sha256 => Sha256::digest(data).into(),
+            HashAlgorithm::Blake3 => blake3::hash(data).into(),
+        }
+    }
+}
+
+/// Task key of the canonical JSON of what a completion is generated from.
+pub fn key(algorithm: HashAlgorithm, canonical: &str) -> String {
+    let digest =
+        base64::engine::general_purpose::STANDARD.encode(algorithm.digest(canonical.as_bytes()));
+    match algorithm {
+        HashAlgorithm::Sha256 => digest,
+        _ => format!("{}:{}", algorithm.as_str(), digest),
+    }
+}
+
+/// Splits `completions` into its content and the rest of the response, `Null` when
+/// there's none.
+pub fn split_content(completions: Value) -> (Value, Value) {
+    let Value::Object(fields) = completions else {
+        return (completions, Value::Null);
+    };
+    let (content, rest): (Map<String, Value>, Map<String, Value>) = fields
+        .into_iter()
+        .partition(|(field, _)| CONTENT_FIELDS.contains(&field.as_str()));
+    let rest = if rest.is_empty() {
+        Value::Null
+    } else {
+        Value::Object(rest)
+    };
+    (Value::Object(content), rest)
+}
+
+/// Completions of a response split with `split_content`, put back together.
+pub fn join_content(mut content: Value, rest: &Value) -> Value {
+    if let (Some(content), Some(rest)) = (content.as_object_mut(), rest.as_object()) {
+        for (field, value) in rest {
+            content.insert(field.clone(), value.clone());
+        }
+    }
+    content
+}
+
+/// Address in the completion store of the content of `completions`.
+pub fn content_hash(algorithm: HashAlgorithm, completions: &Value) -> String {
+    let content = match completions.as_object() {
+        Some(fields) => Value::Object(
+            fields
+                .iter()
+                .filter(|(field, _)| CONTENT_FIELDS.contains(&field.as_str()))
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect(),
+        ),
+        None => completions.clone(),
+    };
+    let digest = algorithm.digest(producer::canonical_json(&content).as_bytes());
+    format!("{}:{}", algorithm.as_str(), hex::encode(digest))
+}
@@ -0,0 +1,192 @@
+//! Tuning of the shared reqwest/hyper client used for all upstream calls, and of the
+//! clients of hosts reached over a configured transport (Unix socket, mTLS, proxy).
+//!
+//! The profile picks the pooling and keep-alive behavior; timeouts, the HTTP version,
+//! a proxy and an extra CA bundle are configured on top of it, for corporate networks
+//! and slow self-hosted inference servers. A transport's own proxy, or `none`, replaces
+//! the shared one and those of the environment for its host, so local inference servers
+//! aren't sent through a proxy meant for the cloud providers.
+
+use crate::resolver::Resolver;
+use crate::settings::{HttpProfile, HttpSettings, HttpVersion, NetworkSettings, TransportSettings};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Certificate, Client, ClientBuilder, Identity, NoProxy, Proxy, Url};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+type HttpResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Idle connections kept per host by the high-concurrency profile, unless configured.
+const HIGH_CONCURRENCY_POOL_MAX_IDLE_PER_HOST: usize = 1024;
+/// Connect timeout of the high-concurrency profile, unless configured.
+const HIGH_CONCURRENCY_CONNECT_TIMEOUT_MS: u64 = 10_000;
+
+/// Resolves through hickory with its own answer cache, so thousands of connections to
+/// the same few hosts don't each go through `getaddrinfo` on a blocking thread.
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addrs: Addrs = Box::new(
+                resolver
+                    .lookup(name.as_str())
+                    .await?
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, 0)),
+            );
+            Ok(addrs)
+        })
+    }
+}
+
+fn resolver(settings: &HttpSettings, network: &NetworkSettings) -> Arc<Resolver> {
+    Arc::new(Resolver::new(
+        network,
+        settings.dns_cache_size,
+        Duration::from_secs(settings.dns_min_ttl_secs),
+    ))
+}
+
+/// Builds the HTTP client for `settings.profile`, resolving names as `network` says.
+pub fn build_client(settings: &HttpSettings, network: &NetworkSettings) -> HttpResult<Client> {
+    Ok(client_builder(settings, network)?.build()?)
+}
+
+/// Builds the client of the requests carried by `transport`, tuned like the others.
+pub fn build_transport_client(
+    settings: &HttpSettings,
+    network: &NetworkSettings,
+    transport: &TransportSettings,
+) -> HttpResult<Client> {
+    let mut builder = client_builder(settings, network)?;
+    if let Some(path) = &transport.unix_socket {
+        #[cfg(unix)]
+        {
+            builder = builder.unix_socket(path.as_str());
+        }
+        #[cfg(not(unix))]
+        return Err(format!("Unix domain sockets are not supported here: {}", path).into());
+    }
+    match (&transport.client_cert, &transport.client_key) {
+        (Some(cert), Some(key)) => {
+            let identity = Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)?;
+            builder = builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => {
+            return Err(format!(
+                "Transport of {} needs both a client certificate and its key",
+                transport.host
+            )
+            .into())
+        }
+    }
+    if let Some(ca_cert) = &transport.ca_cert {
+        builder = add_root_certificates(builder, ca_cert)?;
+    }
+    match transport.proxy.as_deref() {
+        None => {}
+        Some("none") => builder = builder.no_proxy(),
+        Some(proxy) => builder = builder.no_proxy().proxy(transport_proxy(transport, proxy)?),
+    }
+    Ok(builder.build()?)
+}
+
+/// Proxy of a transport, with its credentials. The URL isn't part of the errors, as it
+/// may hold them.
+fn transport_proxy(transport: &TransportSettings, proxy: &str) -> HttpResult<Proxy> {
+    let invalid = || format!("Invalid proxy URL of the transport of {}", transport.host);
+    let mut url = Url::parse(proxy).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(format!(
+            "Unsupported proxy scheme {} of the transport of {}",
+            url.scheme(),
+            transport.host
+        )
+        .into());
+    }
+    if let Some(username) = &transport.proxy_username {
+        url.set_username(username).map_err(|_| invalid())?;
+    }
+    if let Some(password) = &transport.proxy_password {
+        url.set_password(Some(password)).map_err(|_| invalid())?;
+    }
+    Proxy::all(url).map_err(|_| invalid().into())
+}
+
+fn read(path: &str) -> HttpResult<Vec<u8>> {
+    std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e).into())
+}
+
+/// Trusts the certificates of the PEM bundle at `path`, besides the system roots.
+fn add_root_certificates(mut builder: ClientBuilder, path: &str) -> HttpResult<ClientBuilder> {
+    for certificate in Certificate::from_pem_bundle(&read(path)?)? {
+        builder = builder.add_root_certificate(certificate);
+    }
+    Ok(builder)
+}
+
+fn client_builder(settings: &HttpSettings, network: &NetworkSettings) -> HttpResult<ClientBuilder> {
+    let builder = profile_builder(settings, network);
+    configure(builder, settings)
+}
+
+fn profile_builder(settings: &HttpSettings, network: &NetworkSettings) -> ClientBuilder {
+    let custom_network = !network.is_system_default();
+    if settings.profile == HttpProfile::Default {
+        let mut builder = Client::builder();
+        if custom_network {
+            builder = builder.dns_resolver(resolver(settings, network));
+        }
+        if let Some(pool_max_idle_per_host) = settings.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+        if let Some(connect_timeout_ms) = settings.connect_timeout_ms {
+            builder = builder.connect_timeout(Duration::from_millis(connect_timeout_ms));
+        }
+        return builder;
+    }
+
+    let pool_max_idle_per_host = settings
+        .pool_max_idle_per_host
+        .unwrap_or(HIGH_CONCURRENCY_POOL_MAX_IDLE_PER_HOST);
+    let connect_timeout_ms = settings
+        .connect_timeout_ms
+        .unwrap_or(HIGH_CONCURRENCY_CONNECT_TIMEOUT_MS);
+    let mut builder = Client::builder()
+        .pool_max_idle_per_host(pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(settings.pool_idle_timeout_secs))
+        .tcp_keepalive(Duration::from_secs(settings.tcp_keepalive_secs))
+        .tcp_nodelay(true)
+        .http2_keep_alive_interval(Duration::from_secs(settings.http2_keep_alive_interval_secs))
+        .http2_keep_alive_while_idle(true)
+        .http2_adaptive_window(true)
+        .connect_timeout(Duration::from_millis(connect_timeout_ms));
+    if settings.dns_cache_size > 0 || custom_network {
+        builder = builder.dns_resolver(resolver(settings, network));
+    }
+    builder
+}
+
+/// Applies the settings that hold under any profile.
+fn configure(mut builder: ClientBuilder, settings: &HttpSettings) -> HttpResult<ClientBuilder> {
+    if let Some(read_timeout_secs) = settings.read_timeout_secs {
+        builder = builder.read_timeout(Duration::from_secs(read_timeout_secs));
+    }
+    builder = match settings.version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1Only => builder.http1_only(),
+        HttpVersion::Http2PriorKnowledge => builder.http2_prior_knowledge(),
+    };
+    if let Some(proxy_url) = &settings.proxy_url {
+        let proxy = Proxy::all(proxy_url)
+            .map_err(|e| format!("Invalid HTTP_PROXY_URL {}: {}", proxy_url, e))?
+            .no_proxy(settings.no_proxy.as_deref().and_then(NoProxy::from_string));
+        builder = builder.proxy(proxy);
+    }
+    if let Some(ca_bundle) = &settings.ca_bundle {
+        builder = add_root_certificates(builder, ca_bundle)?;
+    }
+    Ok(builder)
+}
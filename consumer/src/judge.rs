@@ -0,0 +1,93 @@
+//! Second-pass scoring of completions by a judge model (`QUALITY_JUDGE_URL`).
+//!
+//! The judge gets the prompt, the completion and a rubric (`QUALITY_JUDGE_RUBRIC`, or
+//! the default below) and answers with a rating from 1 to 10 and its rationale, as a
+//! JSON object or a bare number followed by the rationale. The rating is stored as
+//! `quality_score` in [0, 1], next to `judge_feedback`, so `ACCEPT_MIN_QUALITY_SCORE`
+//! filters completions the judge scored low. A judge that fails or answers something
+//! unparseable leaves a `judge_error` instead, and the completion unscored.
+
+use crate::llm_wrapper::{self, LLMClient};
+use crate::settings::JudgeSettings;
+use crate::text;
+use serde_json::{json, Map, Value};
+
+/// Rubric used unless `QUALITY_JUDGE_RUBRIC` is set.
+pub const DEFAULT_RUBRIC: &str = "Judge the answer as training data for an assistant: is it \
+     correct, complete, relevant to the request, clearly written and free of filler?";
+
+/// Question asked to the judge about `completion`.
+pub fn question(rubric: &str, prompt: &str, completion: &str) -> String {
+    format!(
+        "{}\n\nRate the answer below from 1 (unusable) to 10 (excellent). Reply with a JSON \
+         object only: {{\"score\": <1-10>, \"rationale\": \"<one or two sentences>\"}}\
+         \n\nRequest:\n{}\n\nAnswer:\n{}",
+        rubric, prompt, completion
+    )
+}
+
+/// Rating in [1, 10] and rationale of a judge's answer.
+pub fn parse_verdict(answer: &str) -> Result<(f64, String), String> {
+    let object = answer
+        .find('{')
+        .zip(answer.rfind('}'))
+        .and_then(|(start, end)| serde_json::from_str::<Value>(&answer[start..=end]).ok());
+    let (rating, rationale) = match object {
+        Some(verdict) => (
+            match &verdict["score"] {
+                Value::String(score) => score.trim().parse().ok(),
+                score => score.as_f64(),
+            },
+            verdict["rationale"]
+                .as_str()
+                .or(verdict["feedback"].as_str())
+                .unwrap_or_default()
+                .to_string(),
+        ),
+        None => {
+            let start = answer
+                .find(|c: char| c.is_ascii_digit())
+                .ok_or_else(|| format!("Unparseable judge verdict: {}", answer))?;
+            let end = answer[start..]
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .map_or(answer.len(), |i| start + i);
+            let rationale = answer[end..]
+                .trim_start_matches("/10")
+                .trim_start_matches(|c: char| !c.is_alphanumeric());
+            (
+                answer[start..end].trim_end_matches('.').parse().ok(),
+                rationale.trim().to_string(),
+            )
+        }
+    };
+    let rating = rating.ok_or_else(|| format!("Unparseable judge verdict: {}", answer))?;
+    Ok((rating.clamp(1.0, 10.0), rationale))
+}
+
+/// Has the judge score `completion` and returns the annotations to store.
+pub async fn score(
+    client: &LLMClient,
+    settings: &JudgeSettings,
+    body: &Value,
+    completion: &str,
+) -> Map<String, Value> {
+    let question = question(&settings.rubric, &text::prompt_text(body), completion);
+    let verdict = match llm_wrapper::complete_prompt(client, &settings.model, &question).await {
+        Ok(answer) => parse_verdict(&answer),
+        Err(e) => Err(format!("Judge model failed: {}", e)),
+    };
+
+    let mut annotations = Map::new();
+    match verdict {
+        Ok((rating, rationale)) => {
+            annotations.insert("quality_score".to_string(), json!((rating - 1.0) / 9.0));
+            annotations.insert("judge_feedback".to_string(), json!(rationale));
+            annotations.insert("judge_model".to_string(), json!(settings.model.model));
+        }
+        Err(e) => {
+            tracing::warn!("Quality judge failed: {}", e);
+            annotations.insert("judge_error".to_string(), json!(e));
+        }
+    }
+    annotations
+}
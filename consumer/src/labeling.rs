@@ -0,0 +1,46 @@
+use crate::llm_wrapper::{self, LLMClient};
+use crate::settings::LabelingSettings;
+use crate::text;
+
+/// Keeps only answers that belong to the configured label set, preserving the
+/// configured spelling and at most `max_labels` entries.
+pub fn parse_labels(answer: &str, settings: &LabelingSettings) -> Vec<String> {
+    let mut labels = Vec::new();
+    for candidate in answer.split([',', '\n']) {
+        let candidate = candidate
+            .trim()
+            .trim_matches(|c: char| !c.is_alphanumeric());
+        if let Some(label) = settings
+            .labels
+            .iter()
+            .find(|l| l.eq_ignore_ascii_case(candidate))
+        {
+            if !labels.contains(label) {
+                labels.push(label.clone());
+            }
+        }
+        if labels.len() >= settings.max_labels {
+            break;
+        }
+    }
+    labels
+}
+
+/// Asks the labeling model which topics from the configured set describe the example.
+pub async fn label(
+    client: &LLMClient,
+    settings: &LabelingSettings,
+    body: &serde_json::Value,
+    completion: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let question = format!(
+        "Classify the following example into at most {} of these topics: {}.\n\
+         Reply with a comma-separated list of topics only.\n\nPrompt:\n{}\n\nResponse:\n{}",
+        settings.max_labels,
+        settings.labels.join(", "),
+        text::prompt_text(body),
+        completion
+    );
+    let answer = llm_wrapper::complete_prompt(client, &settings.model, &question).await?;
+    Ok(parse_labels(&answer, settings))
+}
@@ -0,0 +1,109 @@
+//! Legacy `/v1/completions` request bodies (a `prompt` rather than `messages`) sent by
+//! older producers, adapted to the provider they're routed to instead of being
+//! rejected with a 400.
+//!
+//! Providers configured with a `completions_url` still serve the legacy API, so the
+//! body goes there unchanged. Otherwise a body bound for a chat completions endpoint is
+//! converted to a single user message; prompts with no chat equivalent (several
+//! prompts, token arrays, `suffix`, `echo`) fail with that provider so the next one is
+//! tried. Endpoints of other APIs get the body unchanged, as before.
+
+use crate::settings::ProviderSettings;
+use serde_json::{json, Value};
+
+/// Legacy parameters the chat API doesn't take and that don't change what is generated.
+const DROPPED_PARAMS: &[&str] = &["best_of", "echo", "suffix"];
+
+/// Endpoint and body a request is sent with after adaptation.
+pub struct Adapted {
+    pub url: String,
+    pub body: Value,
+    /// How a legacy body was adapted, recorded in the `legacy_payload` annotation.
+    pub adaptation: Option<&'static str>,
+}
+
+/// Whether `body` is a legacy completions request.
+pub fn is_legacy(body: &Value) -> bool {
+    body.get("prompt").is_some() && body.get("messages").is_none() && body.get("contents").is_none()
+}
+
+fn endpoint_path(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .map(|url| url.path().trim_end_matches('/').to_string())
+        .unwrap_or_default()
+}
+
+/// Whether `url` is a legacy completions endpoint, e.g. `/v1/completions`.
+pub fn is_legacy_endpoint(url: &str) -> bool {
+    let path = endpoint_path(url);
+    path.ends_with("/completions") && !path.ends_with("/chat/completions")
+}
+
+/// The chat completions equivalent of a legacy body, if it has one.
+pub fn to_chat(body: &Value) -> Option<Value> {
+    let prompt = match &body["prompt"] {
+        Value::String(prompt) => prompt.as_str(),
+        Value::Array(prompts) if prompts.len() == 1 => prompts[0].as_str()?,
+        _ => return None,
+    };
+    if !body["suffix"].as_str().unwrap_or_default().is_empty() || body["echo"] == true {
+        return None;
+    }
+
+    let mut chat = body.as_object()?.clone();
+    chat.remove("prompt");
+    for param in DROPPED_PARAMS {
+        chat.remove(*param);
+    }
+    // Legacy `logprobs` is the number of alternatives to return for each token.
+    if let Some(logprobs) = chat.remove("logprobs").and_then(|v| v.as_u64()) {
+        chat.insert("logprobs".to_string(), Value::from(true));
+        if logprobs > 0 {
+            chat.insert("top_logprobs".to_string(), Value::from(logprobs));
+        }
+    }
+    chat.insert(
+        "messages".to_string(),
+        json!([{ "role": "user", "content": prompt }]),
+    );
+    Some(Value::Object(chat))
+}
+
+/// Endpoint and body to send `body` to `provider` with.
+pub fn adapt(provider: &ProviderSettings, body: Value) -> Result<Adapted, String> {
+    let unchanged = |body| {
+        Ok(Adapted {
+            url: provider.url.clone(),
+            body,
+            adaptation: None,
+        })
+    };
+    if !is_legacy(&body) || is_legacy_endpoint(&provider.url) {
+        return unchanged(body);
+    }
+    if let Some(url) = provider
+        .completions_url
+        .as_ref()
+        .filter(|url| !url.is_empty())
+    {
+        return Ok(Adapted {
+            url: url.clone(),
+            body,
+            adaptation: Some("legacy_endpoint"),
+        });
+    }
+    if !endpoint_path(&provider.url).ends_with("/chat/completions") {
+        return unchanged(body);
+    }
+    match to_chat(&body) {
+        Some(chat) => Ok(Adapted {
+            url: provider.url.clone(),
+            body: chat,
+            adaptation: Some("converted_to_chat"),
+        }),
+        None => Err(format!(
+            "Legacy completions body can't be converted to chat for {}, which has no completions_url",
+            provider.name
+        )),
+    }
+}
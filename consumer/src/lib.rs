@@ -1,7 +1,65 @@
 pub mod llm_wrapper;
+pub mod admin;
+pub mod anthropic;
+pub mod archive;
+pub mod arrow_ipc;
+pub mod attribution;
+pub mod balance;
+pub mod batch_dedup;
+pub mod batching;
+pub mod broker;
+pub mod cache_key;
+pub mod cache_samples;
+pub mod circuit_breaker;
+pub mod concurrency;
+pub mod contamination;
+pub mod conversations;
+pub mod corpus_dedup;
 pub mod db;
+pub mod debug_trace;
+pub mod difficulty;
+pub mod diversity;
+pub mod dispatch_windows;
+pub mod embedding;
+pub mod embedding_tasks;
+pub mod energy;
+pub mod evaluation;
+pub mod export;
+pub mod feature_flags;
+pub mod hashing;
+pub mod http;
+pub mod judge;
+pub mod labeling;
+pub mod legacy_completions;
+#[cfg(feature = "candle")]
+pub mod local_models;
+pub mod mock_llm;
+pub mod parameter_policy;
+pub mod partition;
+pub mod payload;
+pub mod payload_encryption;
+pub mod postprocess;
+pub mod pricing;
+pub mod producer;
+pub mod quality_gates;
+pub mod queue_aging;
+pub mod rate_limit;
+pub mod resolver;
+pub mod resume;
+pub mod schema_registry;
 pub mod schemas {
+    pub mod envelope;
     pub mod task_status;
     pub mod llm_response;
+    pub mod provider_response;
 }
-pub mod settings;
\ No newline at end of file
+pub mod settings;
+pub mod simulation;
+pub mod standalone;
+pub mod telemetry;
+pub mod templates;
+pub mod text;
+pub mod truncation;
+pub mod validation;
+pub mod webhook;
+pub mod workers;
@@ -1,12 +1,29 @@
+use crate::anthropic;
+use crate::circuit_breaker::CircuitBreakers;
+use crate::debug_trace;
+use crate::mock_llm::MockBackend;
+use crate::payload::{self, Payloads};
 use crate::schemas::llm_response::LLMResponse;
+use crate::schemas::provider_response::{self, Provider, Usage};
+use crate::settings::{
+    AuxModelSettings, EmptyCompletionSettings, HttpSettings, IdempotencySettings, NetworkSettings,
+    ProviderApi, ProviderSettings, TransportSettings,
+};
+use crate::telemetry;
+use crate::truncation::Truncation;
+use bytes::Bytes;
 use reqwest::Client;
-use serde_json::Value;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::time::Instant;
 use tokio_retry2::strategy::jitter;
 use tokio_retry2::Retry;
 use tokio_retry2::RetryError;
+use tracing::{info_span, Instrument};
 use chrono::Utc;
 
 #[derive(Debug)]
@@ -20,40 +37,485 @@ impl std::fmt::Display for LLMError {
 
 impl std::error::Error for LLMError {}
 
+/// Returned instead of retrying when the next retry would start past the deadline the
+/// call runs under, so its result would be discarded anyway.
+#[derive(Debug)]
+pub struct DeadlineUnreachable(pub String);
+
+impl std::fmt::Display for DeadlineUnreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for DeadlineUnreachable {}
+
+/// Returned instead of waiting out a rate limit at least as long as the one the call
+/// runs under (`with_rate_limit_deferral`), so the task can be re-published for later
+/// rather than hold its worker slot.
+#[derive(Debug)]
+pub struct RateLimitDeferred {
+    pub message: String,
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for RateLimitDeferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for RateLimitDeferred {}
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+    static DEFER_AFTER: Duration;
+    static IDEMPOTENCY: IdempotencyScope;
+}
+
+/// Task the idempotency keys of LLM calls are derived from.
+struct IdempotencyScope {
+    message_id: String,
+    /// Calls made so far, so each distinct request gets its own key.
+    calls: AtomicU32,
+}
+
+/// Runs `future` with its LLM calls sending idempotency keys derived from `message_id`.
+///
+/// The key of an attempt is `<message_id>-<call>-<attempt>`, `attempt` counting the
+/// earlier attempts of the call the provider answered. A retry after a timeout or a
+/// connection error, which the provider may have received, keeps its key, so the
+/// provider can recognize it rather than charge for a second generation; a redelivered
+/// task is recognized the same way.
+pub async fn with_idempotency_key<F: Future>(message_id: &str, future: F) -> F::Output {
+    let scope = IdempotencyScope {
+        message_id: message_id.to_string(),
+        calls: AtomicU32::new(0),
+    };
+    IDEMPOTENCY.scope(scope, future).await
+}
+
+/// Runs `future` with the retries of its LLM calls bounded by `deadline`: a call gives
+/// up with `DeadlineUnreachable` rather than wait for a retry that can't start in time.
+pub async fn with_deadline<F: Future>(deadline: Instant, future: F) -> F::Output {
+    DEADLINE.scope(deadline, future).await
+}
+
+/// Runs `future` with its LLM calls giving up with `RateLimitDeferred` when a rate limit
+/// asks them to wait `defer_after` or longer; `None` waits every rate limit out.
+pub async fn with_rate_limit_deferral<F: Future>(
+    defer_after: Option<Duration>,
+    future: F,
+) -> F::Output {
+    match defer_after {
+        Some(defer_after) => DEFER_AFTER.scope(defer_after, future).await,
+        None => future.await,
+    }
+}
+
+/// Deadline the LLM calls of the current task run under, if any.
+pub fn current_deadline() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
 #[derive(Clone)]
 pub struct LLMClient {
     inner: Arc<Client>,
+    /// Clients of the hosts reached over a configured transport, by host.
+    transports: Arc<Vec<(String, Client)>>,
+    /// Payload sizes per model, and the budgets responses are read under.
+    payloads: Arc<Payloads>,
+    /// Answers `call_llm` instead of the providers when set.
+    mock: Option<Arc<MockBackend>>,
+    /// Headers idempotency keys are sent in.
+    idempotency: Arc<IdempotencySettings>,
+    /// Circuits of the provider URLs, shared by all calls.
+    circuit_breakers: Arc<CircuitBreakers>,
+    /// Size limits of the error bodies and completions kept.
+    truncation: Arc<Truncation>,
+}
+
+impl Default for LLMClient {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl LLMClient {
     pub fn new() -> Self {
         Self {
             inner: Arc::new(Client::new()),
+            transports: Arc::default(),
+            payloads: Arc::default(),
+            mock: None,
+            idempotency: Arc::default(),
+            circuit_breakers: Arc::default(),
+            truncation: Arc::default(),
+        }
+    }
+
+    /// Client built from the configured HTTP tuning profile and name resolution, with
+    /// its own connections to the hosts of `transports`.
+    pub fn with_settings(
+        settings: &HttpSettings,
+        network: &NetworkSettings,
+        transports: &[TransportSettings],
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let transports = transports
+            .iter()
+            .map(|transport| {
+                let client = crate::http::build_transport_client(settings, network, transport)?;
+                Ok((transport.host.to_lowercase(), client))
+            })
+            .collect::<Result<_, Box<dyn std::error::Error + Send + Sync>>>()?;
+        Ok(Self {
+            inner: Arc::new(crate::http::build_client(settings, network)?),
+            transports: Arc::new(transports),
+            payloads: Arc::default(),
+            mock: None,
+            idempotency: Arc::default(),
+            circuit_breakers: Arc::default(),
+            truncation: Arc::default(),
+        })
+    }
+
+    /// Records payload sizes in, and reads responses under the budgets of, `payloads`.
+    pub fn with_payloads(mut self, payloads: Arc<Payloads>) -> Self {
+        self.payloads = payloads;
+        self
+    }
+
+    /// Answers `call_llm` with `mock` rather than sending requests.
+    pub fn with_mock(mut self, mock: Arc<MockBackend>) -> Self {
+        self.mock = Some(mock);
+        self
+    }
+
+    /// Sends the idempotency keys of calls in `with_idempotency_key` in the configured
+    /// headers.
+    pub fn with_idempotency(mut self, idempotency: IdempotencySettings) -> Self {
+        self.idempotency = Arc::new(idempotency);
+        self
+    }
+
+    /// Makes calls go through `circuit_breakers`.
+    pub fn with_circuit_breakers(mut self, circuit_breakers: Arc<CircuitBreakers>) -> Self {
+        self.circuit_breakers = circuit_breakers;
+        self
+    }
+
+    /// Keeps error bodies and completions within the limits of `truncation`.
+    pub fn with_truncation(mut self, truncation: Arc<Truncation>) -> Self {
+        self.truncation = truncation;
+        self
+    }
+
+    /// Header the idempotency key of a request to `url` is sent in, if any.
+    pub fn idempotency_header(&self, url: &str) -> Option<&str> {
+        let host = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+            .unwrap_or_default();
+        self.idempotency
+            .hosts
+            .iter()
+            .find(|host_header| host.contains(&host_header.pattern))
+            .map_or(&self.idempotency.header, |host_header| &host_header.header)
+            .as_deref()
+    }
+
+    pub fn inner(&self) -> &Client {
+        &self.inner
+    }
+
+    pub fn payloads(&self) -> &Payloads {
+        &self.payloads
+    }
+
+    pub fn truncation(&self) -> &Truncation {
+        &self.truncation
+    }
+
+    /// Client for requests to `url`: the one of its host's transport, if configured.
+    pub fn for_url(&self, url: &str) -> &Client {
+        let Ok(url) = reqwest::Url::parse(url) else {
+            return &self.inner;
+        };
+        let Some(host) = url.host_str().map(str::to_lowercase) else {
+            return &self.inner;
+        };
+        let authority = url
+            .port_or_known_default()
+            .map(|port| format!("{}:{}", host, port));
+        self.transports
+            .iter()
+            .find(|(pattern, _)| *pattern == host || Some(pattern) == authority.as_ref())
+            .map_or(&self.inner, |(_, client)| client)
+    }
+}
+
+/// How a failed attempt is retried.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// Worth retrying with backoff (timeouts, connection errors, 5xx).
+    Transient(String),
+    /// Retrying can't help (auth, other 4xx, unparseable responses).
+    Permanent(String),
+    /// Retry after the provider-specified delay.
+    RateLimited { message: String, retry_after: Duration },
+}
+
+impl Failure {
+    pub fn message(&self) -> &str {
+        match self {
+            Failure::Transient(message)
+            | Failure::Permanent(message)
+            | Failure::RateLimited { message, .. } => message,
         }
     }
 }
 
+impl From<Failure> for RetryError<String> {
+    fn from(failure: Failure) -> Self {
+        match failure {
+            Failure::Transient(message) => RetryError::transient(message),
+            Failure::Permanent(message) => RetryError::permanent(message),
+            Failure::RateLimited {
+                message,
+                retry_after,
+            } => RetryError::retry_after(message, retry_after),
+        }
+    }
+}
+
+const DEFAULT_RATE_LIMIT_DELAY_SECS: u64 = 2;
+
+/// Classifies a non-success HTTP status. `retry_after` is the raw `Retry-After` header.
+pub fn classify_status(status: u16, retry_after: Option<&str>, error_body: &str) -> Failure {
+    match status {
+        401 => Failure::Permanent(format!("Authentication error: {}", error_body)),
+        429 => Failure::RateLimited {
+            message: "Rate limit exceeded".to_string(),
+            retry_after: Duration::from_secs(
+                retry_after
+                    .and_then(|s| s.trim().parse().ok())
+                    .unwrap_or(DEFAULT_RATE_LIMIT_DELAY_SECS),
+            ),
+        },
+        500..=599 => Failure::Transient(format!("Server error ({}): {}", status, error_body)),
+        _ => Failure::Permanent(format!("Client error ({}): {}", status, error_body)),
+    }
+}
+
+/// Detects an error embedded in a 200 response body (OpenRouter reports upstream
+/// failures this way). `now_ms` is the current Unix time in milliseconds, used to
+/// turn `X-RateLimit-Reset` into a delay.
+pub fn classify_body(raw_response: &Value, now_ms: u64) -> Option<Failure> {
+    let error = provider_response::detect_error(raw_response)?;
+    let metadata = raw_response
+        .get("error")
+        .or_else(|| raw_response.get("completions").and_then(|c| c.get("error")))
+        .and_then(|e| e.get("metadata"));
+
+    let Some(code) = error.code else {
+        // OpenAI, Anthropic and Gemini report symbolic error types instead
+        return Some(match error.kind.as_deref() {
+            Some("rate_limit_error" | "rate_limit_exceeded" | "RESOURCE_EXHAUSTED") => {
+                Failure::RateLimited {
+                    message: format!("Rate limit exceeded: {}", error.message),
+                    retry_after: Duration::from_secs(DEFAULT_RATE_LIMIT_DELAY_SECS),
+                }
+            }
+            Some(
+                kind @ ("overloaded_error" | "api_error" | "server_error" | "timeout"
+                | "UNAVAILABLE" | "INTERNAL" | "DEADLINE_EXCEEDED"),
+            ) => Failure::Transient(format!("Server error ({}): {}", kind, error.message)),
+            kind => Failure::Permanent(format!(
+                "Client error ({}): {}",
+                kind.unwrap_or("unknown"),
+                error.message
+            )),
+        });
+    };
+
+    if code == 429 {
+        // Extract rate limit information from error metadata if available
+        let delay = metadata
+            .and_then(|m| m.get("headers"))
+            .and_then(|h| h.get("X-RateLimit-Reset"))
+            .and_then(|r| r.as_str())
+            .and_then(|s| s.parse::<u64>().ok())
+            .map(|reset_time| {
+                if reset_time > now_ms {
+                    (reset_time - now_ms) / 1000 + 1 // Convert to seconds and add 1 for safety
+                } else {
+                    DEFAULT_RATE_LIMIT_DELAY_SECS // Reset time is in the past
+                }
+            })
+            .unwrap_or(DEFAULT_RATE_LIMIT_DELAY_SECS);
+
+        return Some(Failure::RateLimited {
+            message: format!("Rate limit exceeded: {}", error.message),
+            retry_after: Duration::from_secs(delay),
+        });
+    }
+
+    // Treat server errors (5xx) as transient, client errors (4xx) as permanent
+    Some(if (500..600).contains(&code) {
+        Failure::Transient(format!("Server error ({}): {}", code, error.message))
+    } else {
+        Failure::Permanent(format!("Client error ({}): {}", code, error.message))
+    })
+}
+
+/// Detects a successful response whose completion is missing or shorter than
+/// `min_chars`, which providers occasionally return with a 200. Tool calls count as a
+/// completion, and responses of an unknown shape are let through.
+pub fn classify_completion(raw_response: &Value, min_chars: usize) -> Option<Failure> {
+    if min_chars == 0
+        || provider_response::detect_provider(raw_response) == Provider::Unknown
+        || has_tool_calls(raw_response)
+    {
+        return None;
+    }
+    let chars = provider_response::content(raw_response)
+        .map_or(0, |content| content.trim().chars().count());
+    (chars < min_chars)
+        .then(|| Failure::Transient(format!("Empty completion ({} characters)", chars)))
+}
+
+fn has_tool_calls(raw_response: &Value) -> bool {
+    let message = &raw_response["choices"][0]["message"];
+    let non_empty = |v: &Value| v.as_array().is_some_and(|calls| !calls.is_empty());
+    non_empty(&message["tool_calls"])
+        || !message["function_call"].is_null()
+        || raw_response["content"]
+            .as_array()
+            .is_some_and(|blocks| blocks.iter().any(|b| b["type"] == "tool_use"))
+        || raw_response["candidates"][0]["content"]["parts"]
+            .as_array()
+            .is_some_and(|parts| parts.iter().any(|p| !p["functionCall"].is_null()))
+}
+
+/// Raises the body's `temperature` by `temperature_step` (from 1.0, the usual provider
+/// default, when unset) up to `max_temperature`, and scales its token limit by
+/// `max_tokens_factor`. Returns the parameters changed, with their new values.
+pub fn adjust_for_retry(
+    body: &mut Value,
+    settings: &EmptyCompletionSettings,
+) -> Map<String, Value> {
+    let mut changed = Map::new();
+    let Some(body) = body.as_object_mut() else {
+        return changed;
+    };
+
+    let temperature = body
+        .get("temperature")
+        .and_then(Value::as_f64)
+        .unwrap_or(1.0);
+    let raised = (temperature + settings.temperature_step).min(settings.max_temperature);
+    if raised > temperature {
+        // Rounded so repeated steps don't accumulate float noise like 1.4000000000000001.
+        let raised = Value::from((raised * 1000.0).round() / 1000.0);
+        body.insert("temperature".to_string(), raised.clone());
+        changed.insert("temperature".to_string(), raised);
+    }
+
+    for key in ["max_tokens", "max_completion_tokens"] {
+        if let Some(max_tokens) = body.get(key).and_then(Value::as_u64) {
+            let scaled = (max_tokens as f64 * settings.max_tokens_factor).ceil() as u64;
+            if scaled > max_tokens {
+                body.insert(key.to_string(), Value::from(scaled));
+                changed.insert(key.to_string(), Value::from(scaled));
+            }
+        }
+    }
+    changed
+}
+
+/// Body sent by the next attempt, replaced when retries of empty completions adjust
+/// its parameters.
+struct RetryRequest {
+    payload: Bytes,
+    adjusted_body: Option<Value>,
+    adjustments: Map<String, Value>,
+    empty_completions: u32,
+}
+
+/// Delays between attempts: exponential from `base_delay_ms` with jitter. The cap is
+/// applied after jitter, which can otherwise stretch a delay past `max_delay_secs`.
+pub fn retry_strategy(
+    base_delay_ms: u64,
+    max_delay_secs: u64,
+    retry_attempts: u32,
+) -> impl Iterator<Item = Duration> {
+    let max_delay = Duration::from_secs(max_delay_secs);
+    tokio_retry2::strategy::ExponentialFactorBackoff::from_millis(base_delay_ms, 2.0)
+        .max_delay(max_delay)
+        .map(jitter)
+        .map(move |delay| delay.min(max_delay))
+        .take(retry_attempts as usize)
+}
+
+/// Token usage of a successful response, whatever the provider's usage block looks like.
+pub fn parse_usage(raw_response: &Value) -> Option<Usage> {
+    provider_response::normalize(raw_response).ok()?.usage
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn call_llm(
     client: &LLMClient,
     url: &str,
     body: &Value,
-    api_key: String,
-    site_url: String,
-    site_name: String,
+    api_key: &str,
+    api: ProviderApi,
+    site_url: &str,
+    site_name: &str,
     retry_attempts: u32,
     base_delay_ms: u64,
     max_delay_secs: u64,
+    empty_completion: &EmptyCompletionSettings,
 ) -> Result<LLMResponse, Box<dyn std::error::Error + Send + Sync>> {
-    let retry_strategy =
-        tokio_retry2::strategy::ExponentialFactorBackoff::from_millis(base_delay_ms, 2.0)
-            .max_delay(Duration::from_secs(max_delay_secs))
-            .map(jitter)
-            .take(retry_attempts as usize);
+    if let Some(mock) = &client.mock {
+        let model = body["model"].as_str().unwrap_or_default();
+        let response = mock.respond(url, body).await;
+        let payloads = client.payloads();
+        payloads.record_request(model, serde_json::to_vec(body)?.len() as u64);
+        payloads.record_response(
+            model,
+            serde_json::to_vec(&response.completions)?.len() as u64,
+        );
+        return Ok(response);
+    }
+    let retry_strategy = retry_strategy(base_delay_ms, max_delay_secs, retry_attempts);
 
+    // Serialize once; every attempt reuses the same buffer until a retry adjusts it.
+    let request = Mutex::new(RetryRequest {
+        payload: Bytes::from(serde_json::to_vec(body)?),
+        adjusted_body: None,
+        adjustments: Map::new(),
+        empty_completions: 0,
+    });
+    let authorization = format!("Bearer {}", api_key);
     let attempt = AtomicU32::new(0);
+    let model = body["model"].as_str().unwrap_or_default();
+    let payloads = client.payloads();
+    let idempotency = client.idempotency_header(url).and_then(|header| {
+        IDEMPOTENCY
+            .try_with(|scope| {
+                let call = scope.calls.fetch_add(1, Ordering::SeqCst);
+                (header, format!("{}-{}", scope.message_id, call))
+            })
+            .ok()
+    });
+    let answered = AtomicU32::new(0);
+    // Set by attempts failing for reasons of their own, the provider being up.
+    let provider_healthy = AtomicBool::new(false);
 
-    let result = Retry::spawn(retry_strategy, || async {
+    let send_attempt = || async {
         let current_attempt = attempt.fetch_add(1, Ordering::SeqCst);
+        let payload = request.lock().unwrap().payload.clone();
+        payloads.record_request(model, payload.len() as u64);
         let attempt_started_at = Utc::now();
         
         tracing::debug!(
@@ -62,18 +524,42 @@ pub async fn call_llm(
             retry_attempts
         );
 
-        let response_result = client
-            .inner
+        let mut request_builder = client
+            .for_url(url)
             .post(url)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .header("HTTP-Referer", &site_url)
-            .header("X-Title", &site_name)
-            .json(&body)
-            .send()
-            .await;
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload);
+        request_builder = match api {
+            ProviderApi::OpenAiCompatible => request_builder
+                .header("Authorization", &authorization)
+                .header("HTTP-Referer", site_url)
+                .header("X-Title", site_name),
+            ProviderApi::Anthropic => request_builder
+                .header("x-api-key", api_key)
+                .header("anthropic-version", anthropic::API_VERSION),
+        };
+        let idempotency_key = idempotency.as_ref().map(|(header, key)| {
+            (
+                *header,
+                format!("{}-{}", key, answered.load(Ordering::SeqCst)),
+            )
+        });
+        if let Some((header, key)) = &idempotency_key {
+            request_builder = request_builder.header(*header, key);
+        }
+        // Lets a traced endpoint attach its spans to the attempt.
+        let mut trace_headers = BTreeMap::new();
+        telemetry::inject(&tracing::Span::current(), &mut trace_headers);
+        for (name, value) in &trace_headers {
+            request_builder = request_builder.header(name, value);
+        }
+        let response_result = request_builder.send().await;
 
         let response = match response_result {
-            Ok(resp) => resp,
+            Ok(resp) => {
+                answered.fetch_add(1, Ordering::SeqCst);
+                resp
+            }
             Err(e) => {
                 let error_type = if e.is_timeout() {
                     "timeout"
@@ -105,183 +591,455 @@ pub async fn call_llm(
             }
         };
 
-        match response.status() {
-            reqwest::StatusCode::UNAUTHORIZED => {
-                let error_body = response
+        let status = response.status();
+        if !status.is_success() {
+            let mut retry_after = response
+                .headers()
+                .get("Retry-After")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let mut should_retry = None;
+            if api == ProviderApi::Anthropic {
+                // Rounded up, `Retry-After` being in whole seconds.
+                retry_after = retry_after.or_else(|| {
+                    anthropic::rate_limit_reset(response.headers(), Utc::now())
+                        .map(|reset| (reset.as_secs() + 1).to_string())
+                });
+                should_retry = anthropic::should_retry(response.headers());
+            }
+            let error_body = if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                String::new()
+            } else {
+                let body = response
                     .text()
                     .await
-                    .unwrap_or_else(|_| "Failed to read error response body".to_string());
+                    .unwrap_or_else(|_| format!("HTTP error: {}", status));
+                client.truncation().error_body(body).await
+            };
 
-                tracing::error!(
-                    "Authentication failed on attempt {}/{}: {}",
+            let failure = match (
+                classify_status(status.as_u16(), retry_after.as_deref(), &error_body),
+                should_retry,
+            ) {
+                (Failure::Permanent(message), Some(true)) => Failure::Transient(message),
+                (Failure::Transient(message), Some(false)) => Failure::Permanent(message),
+                (failure, _) => failure,
+            };
+            if matches!(failure, Failure::Permanent(_)) && !matches!(status.as_u16(), 401 | 403) {
+                provider_healthy.store(true, Ordering::SeqCst);
+            }
+            match &failure {
+                Failure::RateLimited { retry_after, .. } => tracing::warn!(
+                    "Rate limit hit on attempt {}/{}. Waiting {} seconds before retry",
+                    current_attempt + 1,
+                    retry_attempts,
+                    retry_after.as_secs()
+                ),
+                _ => tracing::warn!(
+                    "LLM request failed with status {} on attempt {}/{}: {}",
+                    status,
                     current_attempt + 1,
                     retry_attempts,
                     error_body
-                );
+                ),
+            }
+            return Err(failure.into());
+        }
 
+        let budget = payloads.response_budget(model);
+        let (response_body, over_budget) =
+            match payload::read_body(response, budget, payloads.aborts_oversized()).await {
+                Ok(read) => read,
+                Err(e) => return Err(RetryError::permanent(format!("Response read error: {}", e))),
+            };
+        let response_bytes = response_body.len() as u64;
+        payloads.record_response(model, response_bytes);
+        if over_budget {
+            tracing::warn!(
+                "LLM response of {} exceeded its {} byte budget on attempt {}/{}",
+                model,
+                budget.unwrap_or_default(),
+                current_attempt + 1,
+                retry_attempts
+            );
+            if payloads.aborts_oversized() {
+                provider_healthy.store(true, Ordering::SeqCst);
                 return Err(RetryError::permanent(format!(
-                    "Authentication error: {}",
-                    error_body
+                    "Response exceeded the {} byte budget of {}",
+                    budget.unwrap_or_default(),
+                    model
                 )));
             }
-            reqwest::StatusCode::TOO_MANY_REQUESTS => {
-                let delay = response
-                    .headers()
-                    .get("Retry-After")
-                    .and_then(|v| v.to_str().ok())
-                    .and_then(|s| s.parse().ok())
-                    .unwrap_or(2);
-
-                tracing::warn!(
-                    "Rate limit hit on attempt {}/{}. Waiting {} seconds before retry",
-                    current_attempt + 1,
-                    retry_attempts,
-                    delay
-                );
+        }
 
-                return Err(RetryError::retry_after(
-                    "Rate limit exceeded".to_string(),
-                    Duration::from_secs(delay),
-                ));
+        let raw_response = match serde_json::from_slice::<Value>(&response_body) {
+            Ok(json) => json,
+            Err(e) => {
+                return Err(RetryError::permanent(format!("JSON parsing error: {}", e)))
             }
-            status if !status.is_success() => {
-                let error_body = response
-                    .text()
-                    .await
-                    .unwrap_or_else(|_| format!("HTTP error: {}", status));
+        };
 
-                tracing::warn!(
-                    "LLM request failed with status {} on attempt {}/{}: {}",
-                    status,
-                    current_attempt + 1,
-                    retry_attempts,
-                    error_body
-                );
+        // Check if the response contains an error in the completions field
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        if let Some(failure) = classify_body(&raw_response, now_ms) {
+            tracing::warn!(
+                "LLM request returned an error on attempt {}/{}: {:?}",
+                current_attempt + 1,
+                retry_attempts,
+                failure
+            );
+            return Err(failure.into());
+        }
 
-                if status.is_server_error() {
-                    return Err(RetryError::transient(format!(
-                        "Server error ({}): {}",
-                        status, error_body
-                    )));
-                } else {
-                    return Err(RetryError::permanent(format!(
-                        "Client error ({}): {}",
-                        status, error_body
-                    )));
+        if let Some(failure) = classify_completion(&raw_response, empty_completion.min_chars) {
+            tracing::warn!(
+                "LLM request returned an empty completion on attempt {}/{}: {:?}",
+                current_attempt + 1,
+                retry_attempts,
+                failure
+            );
+            provider_healthy.store(true, Ordering::SeqCst);
+            let mut request = request.lock().unwrap();
+            request.empty_completions += 1;
+            if empty_completion.adjust_params {
+                let adjusted = request.adjusted_body.get_or_insert_with(|| body.clone());
+                let changed = adjust_for_retry(adjusted, empty_completion);
+                if let Ok(payload) = serde_json::to_vec(adjusted) {
+                    request.payload = Bytes::from(payload);
+                    request.adjustments.extend(changed);
                 }
             }
-            _ => {
-                let raw_response = match response.json::<Value>().await {
-                    Ok(json) => json,
-                    Err(e) => {
-                        return Err(RetryError::permanent(format!("JSON parsing error: {}", e)))
-                    }
-                };
+            return Err(failure.into());
+        }
 
-                // Check if the response contains an error in the completions field
-                if let Some(error) = raw_response.get("error").or_else(|| raw_response.get("completions").and_then(|c| c.get("error"))) {
-                    // Check if it's a rate limit error (code 429)
-                    if let Some(code) = error.get("code").and_then(|c| c.as_u64()) {
-                        if code == 429 {
-                            // Extract rate limit information from error metadata if available
-                            let delay = error
-                                .get("metadata")
-                                .and_then(|m| m.get("headers"))
-                                .and_then(|h| h.get("X-RateLimit-Reset"))
-                                .and_then(|r| r.as_str())
-                                .and_then(|s| s.parse::<u64>().ok())
-                                .map(|reset_time| {
-                                    // Calculate delay from current time to reset time (in milliseconds)
-                                    let current_time = std::time::SystemTime::now()
-                                        .duration_since(std::time::UNIX_EPOCH)
-                                        .unwrap_or_default()
-                                        .as_millis() as u64;
-                                    
-                                    if reset_time > current_time {
-                                        (reset_time - current_time) / 1000 + 1 // Convert to seconds and add 1 for safety
-                                    } else {
-                                        2 // Default delay if reset time is in the past
-                                    }
-                                })
-                                .unwrap_or(2); // Default 2 seconds if we can't parse the reset time
-
-                            let message = error
-                                .get("message")
-                                .and_then(|m| m.as_str())
-                                .unwrap_or("Rate limit exceeded");
-
-                            tracing::warn!(
-                                "Rate limit hit on attempt {}/{}. Waiting {} seconds before retry. Message: {}",
-                                current_attempt + 1,
-                                retry_attempts,
-                                delay,
-                                message
-                            );
-
-                            return Err(RetryError::retry_after(
-                                format!("Rate limit exceeded: {}", message),
-                                Duration::from_secs(delay),
-                            ));
-                        } else {
-                            // Handle other error codes
-                            let message = error
-                                .get("message")
-                                .and_then(|m| m.as_str())
-                                .unwrap_or("Unknown error");
-
-                            tracing::warn!(
-                                "LLM request returned error code {} on attempt {}/{}: {}",
-                                code,
-                                current_attempt + 1,
-                                retry_attempts,
-                                message
-                            );
-
-                            // Treat server errors (5xx) as transient, client errors (4xx) as permanent
-                            if code >= 500 && code < 600 {
-                                return Err(RetryError::transient(format!(
-                                    "Server error ({}): {}",
-                                    code, message
-                                )));
-                            } else {
-                                return Err(RetryError::permanent(format!(
-                                    "Client error ({}): {}",
-                                    code, message
-                                )));
-                            }
-                        }
-                    }
-                }
+        let attempt_completed_at = Utc::now();
+        let duration_ms = attempt_completed_at.signed_duration_since(attempt_started_at).num_milliseconds();
+        
+        tracing::info!(
+            "LLM request attempt {}/{} completed successfully in {}ms",
+            current_attempt + 1,
+            retry_attempts,
+            duration_ms
+        );
 
-                let attempt_completed_at = Utc::now();
-                let duration_ms = attempt_completed_at.signed_duration_since(attempt_started_at).num_milliseconds();
-                
-                tracing::info!(
-                    "LLM request attempt {}/{} completed successfully in {}ms",
-                    current_attempt + 1,
-                    retry_attempts,
-                    duration_ms
-                );
+        // Record retries of empty completions and the parameters they were sent with
+        let mut annotations = Map::new();
+        let request = request.lock().unwrap();
+        if request.empty_completions > 0 {
+            annotations.insert(
+                "empty_completion_retries".to_string(),
+                Value::from(request.empty_completions),
+            );
+        }
+        if !request.adjustments.is_empty() {
+            annotations.insert(
+                "retry_adjustments".to_string(),
+                Value::Object(request.adjustments.clone()),
+            );
+        }
+        if let Some((_, key)) = idempotency_key {
+            annotations.insert("idempotency_key".to_string(), Value::from(key));
+        }
+        if over_budget {
+            annotations.insert(
+                "oversized_response".to_string(),
+                Value::from(response_bytes),
+            );
+        }
+
+        Ok(LLMResponse {
+            usage: parse_usage(&raw_response),
+            completions: raw_response,
+            cached: false,
+            attempt: current_attempt,
+            started_at: attempt_started_at,
+            completed_at: attempt_completed_at,
+            annotations,
+            cost: None,
+        })
+    };
+
+    // Drawn up front so each failure knows how long the next retry would wait.
+    let delays: Vec<Duration> = retry_strategy.collect();
+    let deadline = current_deadline();
+    let deadline_unreachable = AtomicBool::new(false);
+    let defer_after = DEFER_AFTER.try_with(|defer_after| *defer_after).ok();
+    // Rate limit the call was deferred for, in milliseconds.
+    let deferred_for = AtomicU64::new(0);
+
+    let result = Retry::spawn(delays.iter().copied(), || async {
+        let permit = match client.circuit_breakers.admit(url, deadline).await {
+            Ok(permit) => permit,
+            Err(reopens_at) => {
+                deadline_unreachable.store(true, Ordering::SeqCst);
+                return Err(RetryError::permanent(format!(
+                    "Deadline unreachable: the circuit of {} stays open for another {}s",
+                    url,
+                    reopens_at.saturating_duration_since(Instant::now()).as_secs()
+                )));
+            }
+        };
+        provider_healthy.store(false, Ordering::SeqCst);
+        let sent_body = debug_trace::is_recording()
+            .then(|| request.lock().unwrap().adjusted_body.clone());
+        let started_at = Utc::now();
+        let span = info_span!("llm_attempt", attempt = attempt.load(Ordering::SeqCst) + 1);
+        let outcome = send_attempt().instrument(span).await;
+        permit.settle(outcome.is_ok() || provider_healthy.load(Ordering::SeqCst));
+        let attempts_made = attempt.load(Ordering::SeqCst);
+        if let Some(sent_body) = sent_body {
+            record_attempt(
+                url,
+                attempts_made,
+                started_at,
+                sent_body.as_ref().unwrap_or(body),
+                &outcome,
+            );
+        }
 
-                Ok(LLMResponse {
-                    completions: raw_response,
-                    cached: false,
-                    attempt: current_attempt,
-                    started_at: attempt_started_at,
-                    completed_at: attempt_completed_at,
-                })
+        match outcome {
+            Err(RetryError::Transient { err, retry_after }) => {
+                let next_delay = delays
+                    .get(attempts_made as usize - 1)
+                    .map(|delay| (*delay).max(retry_after.unwrap_or_default()));
+                match (deadline, next_delay) {
+                    (Some(deadline), Some(delay)) if Instant::now() + delay >= deadline => {
+                        deadline_unreachable.store(true, Ordering::SeqCst);
+                        Err(RetryError::permanent(format!(
+                            "Deadline unreachable: retry {} would wait {}s, past the task deadline ({})",
+                            attempts_made + 1,
+                            delay.as_secs_f64(),
+                            err
+                        )))
+                    }
+                    _ => match (retry_after, defer_after) {
+                        (Some(retry_after), Some(defer_after)) if retry_after >= defer_after => {
+                            deferred_for.store(retry_after.as_millis() as u64, Ordering::SeqCst);
+                            Err(RetryError::permanent(format!(
+                                "Deferred: rate limited for {}s ({})",
+                                retry_after.as_secs_f64(),
+                                err
+                            )))
+                        }
+                        _ => Err(RetryError::Transient { err, retry_after }),
+                    },
+                }
             }
+            outcome => outcome,
         }
     })
+    .instrument(info_span!(
+        "call_llm",
+        url,
+        model = body["model"].as_str().unwrap_or_default(),
+    ))
     .await
     .map_err(|e| {
         tracing::error!(
             "LLM request failed after {} attempts. Final error: {}",
-            retry_attempts,
+            attempt.load(Ordering::SeqCst),
             e
         );
-        Box::new(LLMError(e.to_string())) as Box<dyn std::error::Error + Send + Sync>
+        let deferred_for = deferred_for.load(Ordering::SeqCst);
+        if deadline_unreachable.load(Ordering::SeqCst) {
+            Box::new(DeadlineUnreachable(e)) as Box<dyn std::error::Error + Send + Sync>
+        } else if deferred_for > 0 {
+            Box::new(RateLimitDeferred {
+                message: e,
+                retry_after: Duration::from_millis(deferred_for),
+            })
+        } else {
+            Box::new(LLMError(e)) as Box<dyn std::error::Error + Send + Sync>
+        }
     })?;
 
     Ok(result)
 }
+
+/// Appends an attempt of `call_llm` to the current debug trace.
+fn record_attempt(
+    url: &str,
+    attempt: u32,
+    started_at: chrono::DateTime<Utc>,
+    sent_body: &Value,
+    outcome: &Result<LLMResponse, RetryError<String>>,
+) {
+    let completed_at = Utc::now();
+    let mut record = serde_json::json!({
+        "url": url,
+        "attempt": attempt,
+        "started_at": started_at,
+        "completed_at": completed_at,
+        "duration_ms": completed_at.signed_duration_since(started_at).num_milliseconds(),
+        "request": debug_trace::snippet(sent_body),
+    });
+    match outcome {
+        Ok(response) => {
+            record["outcome"] = Value::from("success");
+            record["response"] = debug_trace::snippet(&response.completions);
+        }
+        Err(RetryError::Transient { err, .. }) => {
+            record["outcome"] = Value::from("transient_error");
+            record["error"] = debug_trace::snippet(&Value::from(err.as_str()));
+        }
+        Err(RetryError::Permanent(err)) => {
+            record["outcome"] = Value::from("permanent_error");
+            record["error"] = debug_trace::snippet(&Value::from(err.as_str()));
+        }
+    }
+    debug_trace::record_attempt(record);
+}
+
+/// Providers a task's completion is requested from, in order: the task's own
+/// `providers` list, or else its `url` and `api_key` followed by the configured
+/// fallbacks.
+pub fn task_providers(payload: &Value, fallbacks: &[ProviderSettings]) -> Vec<ProviderSettings> {
+    if let Some(providers) = payload.get("providers").filter(|p| !p.is_null()) {
+        match serde_json::from_value::<Vec<ProviderSettings>>(providers.clone()) {
+            Ok(providers) if !providers.is_empty() => {
+                return providers.into_iter().map(with_name).collect();
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Ignoring malformed task providers: {}", e),
+        }
+    }
+
+    let mut providers = Vec::with_capacity(fallbacks.len() + 1);
+    if let Some(url) = payload["url"].as_str().filter(|url| !url.is_empty()) {
+        providers.push(ProviderSettings {
+            name: String::new(),
+            url: url.to_string(),
+            api_key: payload["api_key"].as_str().unwrap_or_default().to_string(),
+            model: None,
+            completions_url: None,
+            api: serde_json::from_value(payload["provider"].clone()).unwrap_or_default(),
+        });
+    }
+    for fallback in fallbacks {
+        if !providers
+            .iter()
+            .any(|p| p.url == fallback.url && p.api_key == fallback.api_key)
+        {
+            providers.push(fallback.clone());
+        }
+    }
+    providers.into_iter().map(with_name).collect()
+}
+
+fn with_name(mut provider: ProviderSettings) -> ProviderSettings {
+    if provider.name.is_empty() {
+        provider.name = reqwest::Url::parse(&provider.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| provider.url.clone());
+    }
+    provider
+}
+
+/// Requests a completion from each provider in turn until one serves it. `call` sends
+/// the body, with the model renamed for the provider, and does its own retries, so a
+/// provider is given up on after a permanent error or once its retries are exhausted.
+/// The serving provider is recorded in the `provider` annotation. Fails with
+/// `DeadlineUnreachable` when the last provider gave up for lack of time, and with
+/// `RateLimitDeferred` when it was deferred.
+pub async fn route<'p, F, Fut>(
+    providers: &'p [ProviderSettings],
+    body: &Value,
+    mut call: F,
+) -> Result<LLMResponse, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: FnMut(&'p ProviderSettings, Value) -> Fut,
+    Fut: Future<Output = Result<LLMResponse, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let mut failures = Vec::new();
+    let mut deadline_unreachable = false;
+    let mut deferred_for = None;
+    for (index, provider) in providers.iter().enumerate() {
+        let mut body = body.clone();
+        if let Some(model) = &provider.model {
+            body["model"] = Value::from(model.as_str());
+        }
+        match call(provider, body).await {
+            Ok(mut response) => {
+                response
+                    .annotations
+                    .insert("provider".to_string(), Value::from(provider.name.as_str()));
+                return Ok(response);
+            }
+            Err(e) => {
+                if let Some(next) = providers.get(index + 1) {
+                    tracing::warn!(
+                        "Provider {} failed, falling back to {}: {}",
+                        provider.name,
+                        next.name,
+                        e
+                    );
+                }
+                // The next provider starts without a backoff, so it may still make it
+                deadline_unreachable = e.is::<DeadlineUnreachable>();
+                deferred_for = e
+                    .downcast_ref::<RateLimitDeferred>()
+                    .map(|deferred| deferred.retry_after);
+                failures.push(format!("{}: {}", provider.name, e));
+            }
+        }
+    }
+    if failures.is_empty() {
+        return Err(Box::new(LLMError(
+            "No LLM provider configured for the task".to_string(),
+        )));
+    }
+    let message = format!("All providers failed: {}", failures.join("; "));
+    if deadline_unreachable {
+        Err(Box::new(DeadlineUnreachable(message)))
+    } else if let Some(retry_after) = deferred_for {
+        Err(Box::new(RateLimitDeferred {
+            message,
+            retry_after,
+        }))
+    } else {
+        Err(Box::new(LLMError(message)))
+    }
+}
+
+const AUX_RETRY_ATTEMPTS: u32 = 3;
+const AUX_BASE_DELAY_MS: u64 = 1000;
+const AUX_MAX_DELAY_SECS: u64 = 30;
+/// Empty answers are retried as is; auxiliary prompts run at temperature 0 on purpose.
+const AUX_EMPTY_COMPLETION: EmptyCompletionSettings = EmptyCompletionSettings::UNADJUSTED;
+
+/// Sends a single user prompt to an auxiliary model and returns the text of its answer.
+pub async fn complete_prompt(
+    client: &LLMClient,
+    aux: &AuxModelSettings,
+    prompt: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let body = serde_json::json!({
+        "model": aux.model,
+        "messages": [{ "role": "user", "content": prompt }],
+        "temperature": 0,
+    });
+
+    let response = call_llm(
+        client,
+        &aux.url,
+        &body,
+        &aux.api_key,
+        ProviderApi::OpenAiCompatible,
+        "",
+        "",
+        AUX_RETRY_ATTEMPTS,
+        AUX_BASE_DELAY_MS,
+        AUX_MAX_DELAY_SECS,
+        &AUX_EMPTY_COMPLETION,
+    )
+    .await?;
+
+    response
+        .content()
+        .map(|s| s.trim().to_string())
+        .ok_or_else(|| Box::new(LLMError("Auxiliary model returned no content".to_string())) as _)
+}
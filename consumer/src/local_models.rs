@@ -0,0 +1,236 @@
+//! In-process inference for small auxiliary models, built with the `candle` feature.
+
+use crate::embedding::EmbeddingProvider;
+use crate::settings::LocalModelSettings;
+use async_trait::async_trait;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{Linear, Module, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use std::sync::Arc;
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer, TruncationParams};
+
+type LocalResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// BERT-family encoder loaded from a local directory containing `config.json`,
+/// `tokenizer.json` and `model.safetensors` (e.g. all-MiniLM-L6-v2).
+pub struct BertEncoder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    vb: VarBuilder<'static>,
+    config: Config,
+    raw_config: serde_json::Value,
+}
+
+impl BertEncoder {
+    pub fn load(model_dir: &str) -> LocalResult<Self> {
+        let dir = Path::new(model_dir);
+        let device = Device::Cpu;
+        let raw_config: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.join("config.json"))?)?;
+        let config: Config = serde_json::from_value(raw_config.clone())?;
+
+        let mut tokenizer = Tokenizer::from_file(dir.join("tokenizer.json"))?;
+        tokenizer.with_padding(Some(PaddingParams {
+            strategy: PaddingStrategy::BatchLongest,
+            ..Default::default()
+        }));
+        tokenizer.with_truncation(Some(TruncationParams {
+            max_length: config.max_position_embeddings,
+            ..Default::default()
+        }))?;
+
+        // SAFETY: the weights file is mapped read-only and not modified while loaded.
+        let vb = unsafe {
+            VarBuilder::from_mmaped_safetensors(&[dir.join("model.safetensors")], DTYPE, &device)?
+        };
+        let model = BertModel::load(vb.clone(), &config)?;
+
+        Ok(Self {
+            model,
+            tokenizer,
+            device,
+            vb,
+            config,
+            raw_config,
+        })
+    }
+
+    /// Last hidden states `(batch, seq, hidden)` and the attention mask `(batch, seq)`.
+    fn hidden_states(&self, texts: &[String]) -> LocalResult<(Tensor, Tensor)> {
+        let encodings = self.tokenizer.encode_batch(texts.to_vec(), true)?;
+        let ids = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_ids(), &self.device))
+            .collect::<Result<Vec<_>, _>>()?;
+        let masks = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), &self.device))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ids = Tensor::stack(&ids, 0)?;
+        let mask = Tensor::stack(&masks, 0)?;
+        let type_ids = ids.zeros_like()?;
+
+        let hidden = self.model.forward(&ids, &type_ids, Some(&mask))?;
+        Ok((hidden, mask))
+    }
+
+    /// Mean-pooled, L2-normalized sentence embeddings.
+    pub fn encode(&self, texts: &[String]) -> LocalResult<Vec<Vec<f32>>> {
+        let (hidden, mask) = self.hidden_states(texts)?;
+        let mask = mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+        let summed = hidden.broadcast_mul(&mask)?.sum(1)?;
+        let pooled = summed.broadcast_div(&mask.sum(1)?)?;
+        let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+        Ok(pooled.broadcast_div(&norm)?.to_vec2::<f32>()?)
+    }
+}
+
+pub struct LocalEmbeddingProvider {
+    encoder: Arc<BertEncoder>,
+}
+
+impl LocalEmbeddingProvider {
+    pub fn load(model_dir: &str) -> LocalResult<Self> {
+        Ok(Self {
+            encoder: Arc::new(BertEncoder::load(model_dir)?),
+        })
+    }
+}
+
+#[async_trait]
+impl EmbeddingProvider for LocalEmbeddingProvider {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    async fn embed_batch(&self, texts: &[String]) -> LocalResult<Vec<Vec<f32>>> {
+        let encoder = self.encoder.clone();
+        let texts = texts.to_vec();
+        tokio::task::spawn_blocking(move || encoder.encode(&texts)).await?
+    }
+}
+
+/// Sequence classifier on top of a BERT encoder (HF `BertForSequenceClassification`
+/// layout: `bert.pooler.dense` followed by `classifier`), used for language
+/// detection and quality scoring.
+pub struct TextClassifier {
+    encoder: BertEncoder,
+    pooler: Linear,
+    classifier: Linear,
+    labels: Vec<String>,
+}
+
+impl TextClassifier {
+    pub fn load(model_dir: &str) -> LocalResult<Self> {
+        let encoder = BertEncoder::load(model_dir)?;
+        let hidden = encoder.config.hidden_size;
+        let id2label = encoder.raw_config["id2label"]
+            .as_object()
+            .cloned()
+            .unwrap_or_default();
+        let mut labels = vec![String::new(); id2label.len().max(1)];
+        for (id, label) in &id2label {
+            if let (Ok(id), Some(label)) = (id.parse::<usize>(), label.as_str()) {
+                if id < labels.len() {
+                    labels[id] = label.to_string();
+                }
+            }
+        }
+
+        let pooler = candle_nn::linear(hidden, hidden, encoder.vb.pp("bert.pooler.dense"))?;
+        let classifier = candle_nn::linear(hidden, labels.len(), encoder.vb.pp("classifier"))?;
+        Ok(Self {
+            encoder,
+            pooler,
+            classifier,
+            labels,
+        })
+    }
+
+    /// Label probabilities for each text; a single-output head yields a sigmoid score.
+    pub fn classify(&self, text: &str) -> LocalResult<Vec<(String, f32)>> {
+        let (hidden, _) = self.encoder.hidden_states(&[text.to_string()])?;
+        let cls = hidden.narrow(1, 0, 1)?.squeeze(1)?;
+        let pooled = self.pooler.forward(&cls)?.tanh()?;
+        let logits = self.classifier.forward(&pooled)?.squeeze(0)?;
+        let scores = if self.labels.len() == 1 {
+            candle_nn::ops::sigmoid(&logits)?
+        } else {
+            candle_nn::ops::softmax(&logits, 0)?
+        };
+        Ok(self
+            .labels
+            .iter()
+            .cloned()
+            .zip(scores.to_vec1::<f32>()?)
+            .collect())
+    }
+
+    /// Probability of `label`, or of the last label when none is given.
+    pub fn score(&self, text: &str, label: Option<&str>) -> LocalResult<f32> {
+        let scores = self.classify(text)?;
+        let found = match label {
+            Some(label) => scores.iter().find(|(l, _)| l == label),
+            None => scores.last(),
+        };
+        found
+            .map(|(_, p)| *p)
+            .ok_or_else(|| format!("Classifier has no label {:?}", label).into())
+    }
+
+    /// Most probable label and its probability.
+    pub fn top(&self, text: &str) -> LocalResult<(String, f32)> {
+        self.classify(text)?
+            .into_iter()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .ok_or_else(|| "Classifier has no labels".into())
+    }
+}
+
+/// Per-event helper stages backed by local classifiers.
+pub struct LocalAnnotators {
+    language: Option<Arc<TextClassifier>>,
+    quality: Option<Arc<TextClassifier>>,
+    quality_positive_label: Option<String>,
+}
+
+impl LocalAnnotators {
+    pub fn load(settings: &LocalModelSettings) -> LocalResult<Option<Self>> {
+        if settings.language_model_dir.is_none() && settings.quality_model_dir.is_none() {
+            return Ok(None);
+        }
+        let load = |dir: &Option<String>| -> LocalResult<Option<Arc<TextClassifier>>> {
+            dir.as_deref()
+                .map(|d| TextClassifier::load(d).map(Arc::new))
+                .transpose()
+        };
+        Ok(Some(Self {
+            language: load(&settings.language_model_dir)?,
+            quality: load(&settings.quality_model_dir)?,
+            quality_positive_label: settings.quality_positive_label.clone(),
+        }))
+    }
+
+    pub async fn annotate(&self, text: &str) -> LocalResult<Map<String, Value>> {
+        let mut annotations = Map::new();
+        if let Some(language) = self.language.clone() {
+            let input = text.to_string();
+            let (label, confidence) =
+                tokio::task::spawn_blocking(move || language.top(&input)).await??;
+            annotations.insert("language".to_string(), json!(label));
+            annotations.insert("language_confidence".to_string(), json!(confidence));
+        }
+        if let Some(quality) = self.quality.clone() {
+            let input = text.to_string();
+            let label = self.quality_positive_label.clone();
+            let score =
+                tokio::task::spawn_blocking(move || quality.score(&input, label.as_deref()))
+                    .await??;
+            annotations.insert("local_quality_score".to_string(), json!(score));
+        }
+        Ok(annotations)
+    }
+}
@@ -1,371 +1,890 @@
-use chrono::Utc;
-use config::ConfigError;
+mod pipeline;
+#[cfg(test)]
+mod tests;
+
 use consumer::db;
 use consumer::llm_wrapper;
-use consumer::schemas;
-use consumer::settings::DatabaseSettings;
+use consumer::mock_llm;
+use consumer::balance;
+use consumer::batch_dedup;
+use consumer::broker::local::LocalBroker;
+use consumer::broker::{self, MessageBroker};
+use consumer::cache_key;
+use consumer::circuit_breaker;
+use consumer::concurrency;
+use consumer::contamination;
+use consumer::dispatch_windows;
+use consumer::partition;
+use consumer::payload;
+use consumer::payload_encryption;
+use consumer::postprocess;
+use consumer::pricing;
+use consumer::queue_aging;
+use consumer::rate_limit;
+use consumer::resume;
+use consumer::schema_registry;
+use consumer::simulation;
+use consumer::standalone;
+use consumer::telemetry;
+use consumer::templates;
+use consumer::truncation;
+use consumer::webhook;
+use consumer::workers;
+use clap::{Args, Parser, Subcommand};
+use consumer::corpus_dedup;
+use consumer::embedding;
+use consumer::energy;
+use consumer::export;
+use consumer::feature_flags;
+use consumer::settings::{
+    BrokerKind, ExportFormat, ExportOrder, ModelPrice, PipelineMode, Settings, StandaloneSettings,
+};
 use futures_lite::StreamExt;
-use lapin::{options::*, types::FieldTable, Connection, ConnectionProperties};
-use serde::Deserialize;
-use std::env;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
 use tracing::{error, info};
-use tracing_subscriber::EnvFilter;
-
-#[derive(Debug, Deserialize, Clone)]
-struct Settings {
-    site_url: String,
-    site_name: String,
-    retry_attempts: u32,
-    base_delay_ms: u64,
-    database: DatabaseSettings,
-    rabbitmq_host: String,
-    rabbitmq_port: u16,
-    rabbitmq_user: String,
-    rabbitmq_pass: String,
-    max_parallel_tasks: usize,
-    max_delay_secs: u64,
+
+/// How long the hand-off waits for the next prefetched delivery before it considers
+/// the buffer empty.
+const PREFETCH_HANDOFF_IDLE: Duration = Duration::from_millis(200);
+
+/// Process-wide state shared by all in-flight tasks and kept across reconnects.
+struct AppState {
+    llm_client: llm_wrapper::LLMClient,
+    balance: Option<balance::BalanceTracker>,
+    batch_dedup: Option<batch_dedup::BatchDedup>,
+    contamination: Option<contamination::ContaminationChecker>,
+    content_filter: Option<postprocess::ContentFilter>,
+    corpus: Option<corpus_dedup::CorpusIndex>,
+    embedder: Option<embedding::Embedder>,
+    dispatch_windows: dispatch_windows::DispatchWindows,
+    prices: pricing::PriceTable,
+    energy: energy::EnergyTable,
+    rate_limiter: rate_limit::RateLimiter,
+    concurrency: concurrency::ConcurrencyLimiter,
+    partitions: Option<Arc<partition::Membership>>,
+    feature_flags: Arc<feature_flags::FeatureFlags>,
+    schema_registry: Arc<schema_registry::SchemaRegistry>,
+    templates: Arc<templates::PromptTemplates>,
+    webhooks: webhook::Webhooks,
+    payload_keys: payload_encryption::PayloadKeys,
+    cache_keys: cache_key::CacheKeys,
+    queue_aging: Option<queue_aging::QueueAging>,
+    worker: Arc<workers::Worker>,
+    #[cfg(feature = "candle")]
+    local_annotators: Option<consumer::local_models::LocalAnnotators>,
+}
+
+#[derive(Parser)]
+#[command(about = "Consumes data generation tasks and calls the configured LLM endpoints")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Replay a recorded latency/error trace against retry and rate-limit policies
+    /// without touching the network
+    Simulate(SimulateArgs),
+    /// Publish tasks stranded in PROCESSING again and set them back to PENDING
+    Resume(ResumeArgs),
+    /// Write the prompt/completion pairs of a batch's completed tasks as JSONL, Parquet,
+    /// CSV or SQLite
+    Export(ExportArgs),
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// Batch whose completed tasks are exported
+    #[arg(long)]
+    batch_id: String,
+    /// `jsonl`, `parquet`, `csv` or `sqlite` (defaults to EXPORT_FORMAT)
+    #[arg(long)]
+    format: Option<String>,
+    /// Directory or `s3://bucket/prefix` URL written to (defaults to EXPORT_SINK)
+    #[arg(long)]
+    sink: Option<String>,
+    /// `message_id` or `curriculum` (defaults to EXPORT_ORDER)
+    #[arg(long)]
+    order: Option<String>,
+}
+
+#[derive(Args)]
+struct ResumeArgs {
+    /// How long a task has to be PROCESSING to be resumed (defaults to
+    /// RESUME_STALE_AFTER_SECS)
+    #[arg(long)]
+    stale_after_secs: Option<u64>,
+}
+
+#[derive(Args)]
+struct SimulateArgs {
+    /// JSONL trace, one recorded request attempt per line
+    #[arg(long)]
+    trace: String,
+    /// Number of tasks to simulate (defaults to the number of trace records)
+    #[arg(long)]
+    tasks: Option<usize>,
+    /// Concurrent tasks (defaults to MAX_PARALLEL_TASKS)
+    #[arg(long)]
+    concurrency: Option<usize>,
+    /// Comma-separated RETRY_ATTEMPTS values to compare (defaults to the current setting)
+    #[arg(long, value_delimiter = ',')]
+    retry_attempts: Vec<u32>,
+    /// Comma-separated BASE_DELAY_MS values to compare (defaults to the current setting)
+    #[arg(long, value_delimiter = ',')]
+    base_delay_ms: Vec<u64>,
+    /// Backoff cap in seconds (defaults to MAX_DELAY_SECS)
+    #[arg(long)]
+    max_delay_secs: Option<u64>,
+    /// Client-side cap on request starts per minute
+    #[arg(long)]
+    rate_limit_rpm: Option<u32>,
+    /// Price per million prompt tokens
+    #[arg(long, default_value_t = 0.0)]
+    input_price_per_1m: f64,
+    /// Price per million completion tokens
+    #[arg(long, default_value_t = 0.0)]
+    output_price_per_1m: f64,
+}
+
+fn run_simulation(
+    settings: &Settings,
+    args: SimulateArgs,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let trace = simulation::load_trace(&args.trace)?;
+    let tasks = args.tasks.unwrap_or(trace.len());
+    let retry_attempts = if args.retry_attempts.is_empty() {
+        vec![settings.retry_attempts]
+    } else {
+        args.retry_attempts
+    };
+    let base_delays = if args.base_delay_ms.is_empty() {
+        vec![settings.base_delay_ms]
+    } else {
+        args.base_delay_ms
+    };
+    let price = ModelPrice {
+        input_per_1m: args.input_price_per_1m,
+        output_per_1m: args.output_price_per_1m,
+    };
+
+    println!(
+        "{:>8} {:>10} {:>9} {:>7} {:>9} {:>11} {:>10} {:>9} {:>9} {:>10}",
+        "retries", "base_ms", "succeeded", "failed", "attempts", "makespan_s", "tasks/s", "p50_s", "p95_s", "cost"
+    );
+    for &attempts in &retry_attempts {
+        for &base_delay_ms in &base_delays {
+            let policy = simulation::Policy {
+                retry_attempts: attempts,
+                base_delay_ms,
+                max_delay_secs: args.max_delay_secs.unwrap_or(settings.max_delay_secs),
+                concurrency: args.concurrency.unwrap_or(settings.max_parallel_tasks),
+                rate_limit_rpm: args.rate_limit_rpm,
+            };
+            let report = simulation::simulate(&trace, tasks, &policy, price);
+            println!(
+                "{:>8} {:>10} {:>9} {:>7} {:>9} {:>11.1} {:>10.2} {:>9.2} {:>9.2} {:>10.4}",
+                attempts,
+                base_delay_ms,
+                report.succeeded,
+                report.failed,
+                report.attempts,
+                report.makespan.as_secs_f64(),
+                report.throughput(),
+                report.p50_latency.as_secs_f64(),
+                report.p95_latency.as_secs_f64(),
+                report.cost
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Publishes the tasks PROCESSING for longer than `stale_after_secs` again.
+async fn run_resume(
+    settings: &Settings,
+    webhooks: &webhook::Webhooks,
+    stale_after_secs: u64,
+) -> Result<resume::ResumeReport, Box<dyn std::error::Error + Send + Sync>> {
+    let store = db::connect(&settings.storage, webhooks).await?;
+    let broker = broker::connect(&settings.broker, &settings.network).await?;
+    resume::resume_stale_tasks(
+        store.as_ref(),
+        broker.as_ref(),
+        Duration::from_secs(stale_after_secs),
+        settings.broker.max_priority,
+    )
+    .await
 }
 
-impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        // Load environment variables
-        dotenv::dotenv().ok();
-
-        Ok(Settings {
-            site_url: env::var("SITE_URL").unwrap_or_else(|_| "https://your-site.com".to_string()),
-            site_name: env::var("SITE_NAME").unwrap_or_else(|_| "Your Site Name".to_string()),
-            retry_attempts: env::var("RETRY_ATTEMPTS")
-                .map(|v| v.parse().unwrap_or(10))
-                .unwrap_or(10),
-            base_delay_ms: env::var("BASE_DELAY_MS")
-                .map(|v| v.parse().unwrap_or(10000))
-                .unwrap_or(10000),
-            max_delay_secs: env::var("MAX_DELAY_SECS")
-                .map(|v| v.parse().unwrap_or(300))
-                .unwrap_or(300),
-            max_parallel_tasks: env::var("MAX_PARALLEL_TASKS")
-                .map(|v| v.parse().unwrap_or(10))
-                .unwrap_or(300),
-            rabbitmq_host: env::var("RABBITMQ_HOST").unwrap_or_else(|_| "localhost".to_string()),
-            rabbitmq_port: env::var("RABBITMQ_PORT")
-                .map(|v| v.parse().unwrap_or(5672))
-                .unwrap_or(5672),
-            rabbitmq_user: env::var("RABBITMQ_USER").unwrap_or_else(|_| "guest".to_string()),
-            rabbitmq_pass: env::var("RABBITMQ_PASS").unwrap_or_else(|_| "guest".to_string()),
-            database: DatabaseSettings {
-                host: env::var("ELASTICSEARCH_HOST").unwrap_or_else(|_| "localhost".to_string()),
-                port: env::var("ELASTICSEARCH_PORT")
-                    .map(|v| v.parse().unwrap_or(9200))
-                    .unwrap_or(9200),
-                user: env::var("ELASTICSEARCH_USER").unwrap_or_else(|_| "elastic".to_string()),
-                password: env::var("ELASTICSEARCH_PASSWORD")
-                    .unwrap_or_else(|_| "elastic".to_string()),
-            },
-        })
+/// Writes the completed tasks of the batch to the export sink.
+async fn run_export(
+    settings: &Settings,
+    webhooks: &webhook::Webhooks,
+    args: ExportArgs,
+) -> Result<export::ExportReport, Box<dyn std::error::Error + Send + Sync>> {
+    let mut export_settings = settings.export.clone();
+    if let Some(format) = args.format {
+        export_settings.format = ExportFormat::from_name(&format)
+            .ok_or_else(|| format!("Unknown export format {}", format))?;
+    }
+    if let Some(sink) = args.sink {
+        export_settings.sink = sink;
+    }
+    if let Some(order) = args.order {
+        export_settings.order = ExportOrder::from_name(&order)
+            .ok_or_else(|| format!("Unknown export order {}", order))?;
     }
+    let sink = export::Sink::parse(
+        &export_settings.sink,
+        settings.stored_sizes.archive.as_ref(),
+    )?;
+    let store = db::connect(&settings.storage, webhooks).await?;
+    export::export_batch(store.as_ref(), &args.batch_id, &export_settings, &sink).await
 }
 
-fn init_logging() {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env().add_directive(tracing::Level::INFO.into())) // Set default level to DEBUG
-        .with_target(true) // Include the target (module path) in the log output
-        .with_thread_ids(true) // Include thread IDs
-        .with_line_number(true) // Include line numbers
-        .with_file(true) // Include file names
-        .init(); // Initialize the subscriber
+/// State of the consumer as configured by `settings`. Panics on settings that fail to
+/// load, e.g. missing fixture or model files.
+fn app_state(settings: &Settings) -> AppState {
+    let llm_client = llm_wrapper::LLMClient::with_settings(
+        &settings.http,
+        &settings.network,
+        &settings.transports,
+    )
+        .expect("Failed to build HTTP client")
+        .with_payloads(Arc::new(payload::Payloads::new(&settings.payloads)))
+        .with_truncation(Arc::new(truncation::Truncation::new(&settings.stored_sizes)))
+        .with_idempotency(settings.idempotency.clone())
+        .with_circuit_breakers(Arc::new(circuit_breaker::CircuitBreakers::new(
+            &settings.circuit_breaker,
+        )));
+    let llm_client = match &settings.mock_llm {
+        Some(mock) => {
+            tracing::warn!("LLM_MODE=mock: LLM calls are answered by the mock backend");
+            llm_client.with_mock(Arc::new(
+                mock_llm::MockBackend::load(mock).expect("Failed to load mock LLM fixtures"),
+            ))
+        }
+        None => llm_client,
+    };
+    let prompt_templates = Arc::new(
+        templates::PromptTemplates::load(settings.prompt_templates_dir.as_deref())
+            .expect("Failed to load prompt templates"),
+    );
+    AppState {
+        llm_client: llm_client.clone(),
+        balance: settings.balance.clone().map(balance::BalanceTracker::new),
+        batch_dedup: settings.batch_dedup.clone().map(batch_dedup::BatchDedup::new),
+        contamination: settings.contamination.as_ref().map(|c| {
+            contamination::ContaminationChecker::load(c).expect("Failed to load eval sets")
+        }),
+        content_filter: settings.content_filter.as_ref().map(|c| {
+            postprocess::ContentFilter::new(c).expect("Invalid content filter settings")
+        }),
+        corpus: settings.corpus_dedup.as_ref().map(|c| {
+            corpus_dedup::CorpusIndex::load(c).expect("Failed to load corpus hashes")
+        }),
+        embedder: settings.embedding.as_ref().map(|e| {
+            let provider = embedding::build_provider(llm_client.clone(), e)
+                .expect("Failed to initialize embedding provider");
+            embedding::Embedder::new(provider, e)
+        }),
+        dispatch_windows: dispatch_windows::DispatchWindows::new(&settings.dispatch_windows),
+        prices: pricing::PriceTable::new(settings.model_prices.clone()),
+        energy: energy::EnergyTable::new(&settings.energy),
+        rate_limiter: rate_limit::RateLimiter::new(
+            &settings.rate_limits,
+            settings.redis_url.as_deref(),
+        )
+        .expect("Invalid REDIS_URL")
+        .with_quotas(&settings.quotas),
+        concurrency: concurrency::ConcurrencyLimiter::new(
+            &settings.concurrency_limits,
+            settings.max_parallel_tasks,
+        ),
+        partitions: match (&settings.broker.partitioning, settings.broker.kind) {
+            (Some(partitioning), BrokerKind::RabbitMq) => Some(Arc::new(
+                partition::Membership::in_cluster(partitioning)
+                    .expect("Failed to set up partition membership"),
+            )),
+            (Some(_), BrokerKind::Kafka) => {
+                tracing::warn!("Work partitioning only applies to RabbitMQ; ignoring PARTITION_GROUP");
+                None
+            }
+            (None, _) => None,
+        },
+        feature_flags: Arc::new(feature_flags::FeatureFlags::new(&settings.feature_flags)),
+        schema_registry: Arc::new(schema_registry::SchemaRegistry::new(
+            &settings.schema_registry,
+        )),
+        templates: prompt_templates.clone(),
+        webhooks: webhook::Webhooks::new(&settings.webhooks),
+        payload_keys: payload_encryption::PayloadKeys::load(&settings.payload_encryption)
+            .expect("Failed to load payload identities"),
+        cache_keys: cache_key::CacheKeys::with_templates(&settings.cache_key, prompt_templates),
+        queue_aging: queue_aging::QueueAging::new(&settings.queue_aging),
+        worker: Arc::new(workers::Worker::new(&settings.worker)),
+        #[cfg(feature = "candle")]
+        local_annotators: consumer::local_models::LocalAnnotators::load(&settings.local_models)
+            .expect("Failed to load local models"),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Initialize logging first
-    init_logging();
-
     dotenv::dotenv().ok();
+    // Initialize logging first; exported spans are flushed when this is dropped on
+    // return
+    let _telemetry = telemetry::init("synthgen-consumer");
+
     let settings = Arc::new(Settings::new().expect("Failed to load settings"));
+    match Cli::parse().command {
+        Some(Command::Simulate(args)) => return run_simulation(&settings, args),
+        Some(Command::Resume(args)) => {
+            let stale_after_secs = args
+                .stale_after_secs
+                .unwrap_or(settings.resume.stale_after_secs);
+            let webhooks = webhook::Webhooks::new(&settings.webhooks);
+            run_resume(&settings, &webhooks, stale_after_secs).await?;
+            return Ok(());
+        }
+        Some(Command::Export(args)) => {
+            let webhooks = webhook::Webhooks::new(&settings.webhooks);
+            run_export(&settings, &webhooks, args).await?;
+            return Ok(());
+        }
+        None => {}
+    }
+    #[cfg(not(feature = "candle"))]
+    if settings.local_models.language_model_dir.is_some()
+        || settings.local_models.quality_model_dir.is_some()
+    {
+        tracing::warn!("Local model directories are configured but the `candle` feature is disabled; ignoring them");
+    }
 
-    loop {
-        info!("Attempting to establish RabbitMQ connection...");
-        match establish_rabbitmq_connection(&settings).await {
-            Ok(conn) => {
-                info!("RabbitMQ connection established successfully");
-                match conn.create_channel().await {
-                    Ok(channel) => {
-                        info!("RabbitMQ channel created successfully");
-                        if let Err(e) = run_consumer(&settings, &channel).await {
-                            error!("Consumer error: {}. Reconnecting in 5s...", e);
-                            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                            continue;
+    let state = Arc::new(app_state(&settings));
+    if settings.payloads.report_interval_secs > 0 {
+        let llm_client = state.llm_client.clone();
+        let interval = Duration::from_secs(settings.payloads.report_interval_secs);
+        tokio::spawn(async move { llm_client.payloads().report(interval).await });
+    }
+
+    if let Some(partitions) = state.partitions.clone() {
+        let state = state.clone();
+        tokio::spawn(async move {
+            partitions
+                .run(|members| state.rate_limiter.set_cluster_size(members))
+                .await
+        });
+    }
+
+    if let Some(document) = settings.feature_flags.document.clone() {
+        let store = db::elastic::ElasticStore::new(&settings.storage.elasticsearch)
+            .await
+            .expect("Failed to connect to Elasticsearch for feature flags");
+        let flags = state.feature_flags.clone();
+        tokio::spawn(async move { flags.run(store, &document).await });
+    }
+
+    if let Some(document) = settings.schema_registry.document.clone() {
+        let store = db::elastic::ElasticStore::new(&settings.storage.elasticsearch)
+            .await
+            .expect("Failed to connect to Elasticsearch for payload schemas");
+        let registry = state.schema_registry.clone();
+        tokio::spawn(async move { registry.run(store, &document).await });
+    }
+
+    if let Some(standalone) = &settings.standalone {
+        run_standalone(&settings, &state, standalone).await?;
+        return Ok(());
+    }
+
+    let heartbeats = if settings.worker.heartbeat_secs > 0 {
+        let store = db::elastic::ElasticStore::new(&settings.storage.elasticsearch)
+            .await
+            .expect("Failed to connect to Elasticsearch for worker heartbeats");
+        let worker = state.worker.clone();
+        let heartbeat_store = store.clone();
+        tokio::spawn(async move { worker.run(heartbeat_store).await });
+        info!("Registered as worker {}", state.worker.id());
+        Some(store)
+    } else {
+        None
+    };
+
+    if settings.resume.on_startup {
+        if let Err(e) =
+            run_resume(&settings, &state.webhooks, settings.resume.stale_after_secs).await
+        {
+            error!("Failed to resume stranded tasks: {}", e);
+        }
+    }
+
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    let shutdown_tx = Arc::new(shutdown_tx);
+    let signal_shutdown = shutdown_tx.clone();
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutdown requested, draining in-flight tasks...");
+        let _ = signal_shutdown.send(true);
+    });
+
+    let (maintenance_tx, maintenance) = watch::channel(settings.maintenance_mode);
+    if settings.maintenance_mode {
+        info!("Starting in maintenance mode; send SIGUSR1 to resume consuming");
+    }
+    tokio::spawn(toggle_maintenance_on_signal(maintenance_tx));
+
+    if settings.worker_shards <= 1 {
+        let stopped = run_shard(
+            settings,
+            state.clone(),
+            shutdown,
+            maintenance,
+            "consumer".to_string(),
+        )
+        .await;
+        report_stopped(&state, heartbeats.as_ref()).await;
+        return stopped;
+    }
+
+    // Each shard gets its own single-threaded runtime, connection, channel and
+    // semaphore, optionally pinned to a core. A shard that fails shuts the others down
+    // so that the process exits rather than running on with fewer shards.
+    let core_ids = core_affinity::get_core_ids().unwrap_or_default();
+    let mut shards = Vec::with_capacity(settings.worker_shards);
+    for shard in 0..settings.worker_shards {
+        let settings = settings.clone();
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        let maintenance = maintenance.clone();
+        let shutdown_tx = shutdown_tx.clone();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let core = if settings.pin_shards && !core_ids.is_empty() {
+            Some(core_ids[shard % core_ids.len()])
+        } else {
+            None
+        };
+        shards.push(
+            std::thread::Builder::new()
+                .name(format!("consumer-shard-{}", shard))
+                .spawn(move || {
+                    if let Some(core) = core {
+                        if !core_affinity::set_for_current(core) {
+                            error!("Failed to pin shard {} to core {:?}", shard, core.id);
                         }
                     }
-                    Err(e) => {
-                        error!("Failed to create channel: {}. Reconnecting in 5s...", e);
-                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                        continue;
+                    let stopped = runtime.block_on(run_shard(
+                        settings,
+                        state,
+                        shutdown,
+                        maintenance,
+                        format!("consumer-{}", shard),
+                    ));
+                    if let Err(e) = &stopped {
+                        error!("Consumer shard {} failed: {}. Shutting down...", shard, e);
+                        let _ = shutdown_tx.send(true);
                     }
-                }
-            }
-            Err(e) => {
-                error!("Failed to connect to RabbitMQ: {}. Retrying in 5s...", e);
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
-                continue;
+                    stopped
+                })?,
+        );
+    }
+    info!("Started {} consumer shards", shards.len());
+
+    let stopped = tokio::task::spawn_blocking(move || {
+        let mut stopped = Ok(());
+        for shard in shards {
+            let joined = shard
+                .join()
+                .unwrap_or_else(|_| Err("A consumer shard panicked".into()));
+            if joined.is_err() && stopped.is_ok() {
+                stopped = joined;
             }
         }
-    }
+        stopped
+    })
+    .await?;
+    report_stopped(&state, heartbeats.as_ref()).await;
+    stopped
 }
 
-async fn run_consumer(
+/// Runs the tasks of the standalone input through the pipeline, see `standalone`, until
+/// they have all finished or shutdown is requested.
+async fn run_standalone(
     settings: &Arc<Settings>,
-    channel: &lapin::Channel,
+    state: &Arc<AppState>,
+    standalone: &StandaloneSettings,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Set QoS (prefetch)
-    channel
-        .basic_qos(
-            settings.max_parallel_tasks as u16,
-            BasicQosOptions::default(),
-        )
-        .await?;
-
-    channel
-        .queue_declare(
-            "data_generation_tasks",
-            QueueDeclareOptions {
-                durable: true,
-                ..QueueDeclareOptions::default()
-            },
-            FieldTable::default(),
-        )
-        .await?;
-
-    let db_client = Arc::new(
-        db::DatabaseClient::new(&settings.database)
-            .await
-            .expect("Failed to connect to database"),
+    let db_client = db::connect(&settings.storage, &state.webhooks).await?;
+    let input = standalone::open(standalone.input.as_deref()).await?;
+    let (broker, tasks) = LocalBroker::new(
+        settings.broker.prefetch as usize,
+        settings.broker.requeue_delays_ms.clone(),
     );
-
-    let semaphore = Arc::new(tokio::sync::Semaphore::new(settings.max_parallel_tasks));
-
-    let mut consumer = channel
-        .basic_consume(
-            "data_generation_tasks",
-            "consumer",
-            BasicConsumeOptions::default(),
-            FieldTable::default(),
+    let feeder = {
+        let db_client = db_client.clone();
+        let batch_id = standalone.batch_id.clone();
+        tokio::spawn(
+            async move { standalone::feed(input, &batch_id, db_client.as_ref(), tasks).await },
         )
-        .await?;
-
+    };
     info!(
-        "Started consuming messages with QoS {}",
-        settings.max_parallel_tasks
+        "Running the tasks of {} as batch {}",
+        standalone.input.as_deref().unwrap_or("stdin"),
+        standalone.batch_id
     );
 
-    while let Some(delivery) = consumer.next().await {
-        let delivery = delivery?;
-        let permit = semaphore.clone().acquire_owned().await?;
-        let settings = settings.clone();
-        let db_client = db_client.clone();
-
-        tokio::spawn(async move {
-            process_message(settings, db_client, delivery).await;
-            drop(permit);
-            Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
-        });
-    }
+    let (shutdown_tx, shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        info!("Shutdown requested, draining in-flight tasks...");
+        let _ = shutdown_tx.send(true);
+    });
+    // There's no maintenance mode; the sender is kept for the channel to stay open.
+    let (_maintenance_tx, maintenance) = watch::channel(false);
+    run_consumer(
+        settings,
+        state,
+        Arc::new(broker),
+        db_client,
+        shutdown,
+        maintenance,
+        "standalone",
+    )
+    .await?;
 
+    let report = feeder.await??;
+    info!(
+        "Ran {} tasks of batch {}, skipping {} invalid lines",
+        report.submitted, standalone.batch_id, report.rejected
+    );
     Ok(())
 }
 
-async fn establish_rabbitmq_connection(
-    settings: &Settings,
-) -> Result<Connection, Box<dyn std::error::Error + Send + Sync>> {
-    loop {
-        let uri = format!(
-            "amqp://{}:{}@{}:{}",
-            settings.rabbitmq_user,
-            settings.rabbitmq_pass,
-            settings.rabbitmq_host,
-            settings.rabbitmq_port
+/// Writes the last heartbeat of a worker shutting down cleanly, so it isn't taken for
+/// a crashed one.
+async fn report_stopped(state: &AppState, heartbeats: Option<&db::elastic::ElasticStore>) {
+    let Some(store) = heartbeats else {
+        return;
+    };
+    if let Err(e) = state.worker.report(store, workers::STOPPED).await {
+        error!(
+            "Failed to report worker {} stopped: {}",
+            state.worker.id(),
+            e
         );
+    }
+}
 
-        match Connection::connect(&uri, ConnectionProperties::default()).await {
-            Ok(conn) => {
-                info!("RabbitMQ connection established");
-                return Ok(conn);
-            }
-            Err(e) => {
-                error!(
-                    "Failed to connect to RabbitMQ: {} with connection uri: {}, retrying in 5s...",
-                    e, uri
-                );
-                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+/// Resolves on SIGINT, or SIGTERM on unix (what Kubernetes sends on a rolling deploy).
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+                return;
             }
+            Err(e) => error!("Failed to install SIGTERM handler: {}", e),
         }
     }
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-async fn process_message(
-    settings: Arc<Settings>,
-    db_client: Arc<db::DatabaseClient>,
-    delivery: lapin::message::Delivery,
-) {
-    let message_data: serde_json::Value = match serde_json::from_slice(&delivery.data) {
-        Ok(data) => data,
-        Err(e) => {
-            error!("Failed to parse message: {}", e);
-            // Reject the message and don't requeue since it's malformed
-            if let Err(reject_err) = delivery.reject(BasicRejectOptions { requeue: false }).await {
-                error!("Failed to reject malformed message: {}", reject_err);
+/// Flips maintenance mode on every SIGUSR1. In maintenance mode the shards stop
+/// consuming, let in-flight tasks finish and hand unsettled deliveries back, but the
+/// process keeps running until consumption is resumed.
+async fn toggle_maintenance_on_signal(maintenance: watch::Sender<bool>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::user_defined1()) {
+            Ok(mut sigusr1) => {
+                while sigusr1.recv().await.is_some() {
+                    let enabled = !*maintenance.borrow();
+                    if enabled {
+                        info!("Entering maintenance mode, pausing consumption");
+                    } else {
+                        info!("Leaving maintenance mode, resuming consumption");
+                    }
+                    maintenance.send_replace(enabled);
+                }
             }
-            return;
-        }
-    };
-
-    let message_id = message_data["message_id"].as_str().unwrap_or_default();
-    let payload = message_data["payload"].clone();
-    let body_hash = message_data["body_hash"].as_str().unwrap_or_default();
-    let processing_started_at = Utc::now();
-    let use_cache = payload["use_cache"].as_bool().unwrap_or(false);
-    let track_progress = payload["track_progress"].as_bool().unwrap_or(false);
-
-    info!("Processing message {}", message_id);
-
-    // Update status to PROCESSING only if track_progress is true
-    if track_progress {
-        if let Err(e) = db_client
-            .update_event_status(
-                message_id.to_string(),
-                schemas::task_status::TaskStatus::Processing,
-                &schemas::llm_response::LLMResponse {
-                    completions: serde_json::Value::Null,
-                    cached: false,
-                    attempt: 0,
-                    started_at: processing_started_at,
-                    completed_at: processing_started_at,
-                },
-                processing_started_at,
-            )
-            .await
-        {
-            error!("Failed to update status to PROCESSING: {}", e);
-            return;
+            Err(e) => error!("Failed to install SIGUSR1 handler: {}", e),
         }
     }
+    // The shards would see a dropped sender as a closed channel.
+    std::future::pending::<()>().await;
+    drop(maintenance);
+}
 
-    // Check cache only if use_cache is true
-    if use_cache {
-        if let Ok(Some(cached_response)) =
-            db_client.get_cached_completion(body_hash.to_string()).await
-        {
-            info!("Using cached response for message {}", message_id);
-            if let Err(e) = db_client
-                .update_event_status(
-                    message_id.to_string(),
-                    schemas::task_status::TaskStatus::Completed,
-                    &cached_response,
-                    processing_started_at,
+/// Connects to the broker and consumes until shutdown, reconnecting on broker failures.
+/// Fails when the database can't be connected to.
+async fn run_shard(
+    settings: Arc<Settings>,
+    state: Arc<AppState>,
+    mut shutdown: watch::Receiver<bool>,
+    mut maintenance: watch::Receiver<bool>,
+    consumer_tag: String,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    while !*shutdown.borrow() {
+        if *maintenance.borrow_and_update() {
+            tokio::select! {
+                _ = maintenance.wait_for(|enabled| !enabled) => continue,
+                _ = shutdown.changed() => return Ok(()),
+            }
+        }
+        let broker = tokio::select! {
+            broker = broker::connect(&settings.broker, &settings.network) => broker,
+            _ = shutdown.changed() => return Ok(()),
+        };
+        match broker {
+            Ok(broker) => {
+                let db_client = db::connect(&settings.storage, &state.webhooks).await?;
+                match run_consumer(
+                    &settings,
+                    &state,
+                    broker,
+                    db_client,
+                    shutdown.clone(),
+                    maintenance.clone(),
+                    &consumer_tag,
                 )
                 .await
-            {
-                error!("Failed to update cached status: {}", e);
-                // Requeue if db update fails
-                if let Err(reject_err) = delivery.reject(BasicRejectOptions { requeue: true }).await
                 {
-                    error!("Failed to requeue message: {}", reject_err);
+                    Ok(()) if *shutdown.borrow() => return Ok(()),
+                    Ok(()) if *maintenance.borrow() => continue,
+                    Ok(()) => error!("Consumer stream ended. Reconnecting in 5s..."),
+                    Err(e) => error!("Consumer error: {}. Reconnecting in 5s...", e),
                 }
-                return;
             }
-            // Acknowledge message for successful cache hit
-            if let Err(ack_err) = delivery.ack(BasicAckOptions::default()).await {
-                error!("Failed to acknowledge message: {}", ack_err);
+            Err(e) => {
+                error!("Failed to connect to broker: {}. Retrying in 5s...", e);
             }
-            return;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            _ = shutdown.changed() => return Ok(()),
         }
     }
+    Ok(())
+}
 
-    let url = payload["url"].as_str().unwrap_or_default().to_string();
-    let body = payload["body"].clone();
-    let api_key = payload["api_key"].as_str().unwrap_or_default().to_string();
-
-    match llm_wrapper::call_llm(
-        &llm_wrapper::LLMClient::new(),
-        &url,
-        &body,
-        api_key,
-        settings.site_url.clone(),
-        settings.site_name.clone(),
-        settings.retry_attempts,
-        settings.base_delay_ms,
-        settings.max_delay_secs,
-    )
-    .await
-    {
-        Ok(response) => {
-            match db_client
-                .update_event_status(
-                    message_id.to_string(),
-                    schemas::task_status::TaskStatus::Completed,
-                    &response,
-                    processing_started_at,
-                )
-                .await
-            {
-                Ok(_) => {
-                    // Use the timing information from the LLMResponse
-                    let llm_duration_ms = response.completed_at
-                        .signed_duration_since(response.started_at)
-                        .num_milliseconds();
-                    
-                    let total_duration_ms = Utc::now()
-                        .signed_duration_since(processing_started_at)
-                        .num_milliseconds();
-                    
-                    info!(
-                        "Successfully completed message {} - LLM call: {}ms, Total processing: {}ms",
-                        message_id,
-                        llm_duration_ms,
-                        total_duration_ms
-                    );
-                    
-                    // Acknowledge successful processing
-                    if let Err(ack_err) = delivery.ack(BasicAckOptions::default()).await {
-                        error!("Failed to acknowledge message: {}", ack_err);
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to update status to COMPLETED: {}", e);
-                    // Requeue the message if database update fails
-                    if let Err(reject_err) =
-                        delivery.reject(BasicRejectOptions { requeue: true }).await
-                    {
-                        error!("Failed to requeue message: {}", reject_err);
-                    }
-                }
+async fn run_consumer(
+    settings: &Arc<Settings>,
+    state: &Arc<AppState>,
+    broker: Arc<dyn MessageBroker>,
+    db_client: Arc<dyn db::TaskStore>,
+    mut shutdown: watch::Receiver<bool>,
+    mut maintenance: watch::Receiver<bool>,
+    consumer_tag: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(settings.max_parallel_tasks));
+
+    let mut consumer = broker.clone().consume(consumer_tag).await?;
+
+    info!(
+        "Started consuming messages from {} with prefetch {}",
+        settings.broker.queue, settings.broker.prefetch
+    );
+
+    // Deliveries waiting in the batcher stay unacked until their batch is submitted, so
+    // batch sizes are bounded by the prefetch.
+    let batcher = settings.batch_api.as_ref().map(|batch_api| {
+        pipeline::spawn_batcher(settings.clone(), state.clone(), db_client.clone(), batch_api)
+    });
+
+    let stages = (settings.pipeline.mode == PipelineMode::Staged).then(|| {
+        pipeline::spawn_staged(
+            settings.clone(),
+            state.clone(),
+            db_client.clone(),
+            batcher.clone(),
+            &settings.pipeline,
+        )
+    });
+
+    // Set when the loop stops for a reason that needs a fresh connection; the drain
+    // below still runs so in-flight tasks can settle their deliveries.
+    let mut failure: Option<Box<dyn std::error::Error + Send + Sync>> = None;
+    // Delivery that was waiting for a free slot when consumption stopped.
+    let mut waiting = None;
+
+    loop {
+        // Checked in order, so no delivery is taken once consumption is to stop.
+        let delivery = tokio::select! {
+            biased;
+            _ = shutdown.changed() => break,
+            _ = maintenance.wait_for(|enabled| *enabled) => break,
+            delivery = consumer.next() => delivery,
+        };
+        let delivery = match delivery {
+            Some(Ok(delivery)) => delivery,
+            Some(Err(e)) if broker.is_connected() => {
+                error!("Failed to receive delivery, skipping it: {}", e);
+                continue;
+            }
+            Some(Err(e)) => {
+                failure = Some(e);
+                break;
+            }
+            None => break,
+        };
+        if let Some(member) = state
+            .partitions
+            .as_ref()
+            .and_then(|partitions| partitions.forward_target(&delivery))
+        {
+            match delivery.forward(&member).await {
+                Ok(()) => continue,
+                Err(e) => error!(
+                    "Failed to forward delivery to partition {}, processing it here: {}",
+                    member, e
+                ),
             }
         }
-        Err(e) => {
-            error!("LLM request failed: {}", e);
-            let now = Utc::now();
-            if let Err(db_err) = db_client
-                .update_event_status(
-                    message_id.to_string(),
-                    schemas::task_status::TaskStatus::Failed,
-                    &schemas::llm_response::LLMResponse {
-                        completions: serde_json::json!({ "error": e.to_string() }),
-                        cached: false,
-                        attempt: 0,
-                        started_at: processing_started_at,
-                        completed_at: now,
-                    },
-                    processing_started_at,
-                )
-                .await
+        // The semaphore is owned by this loop and never closed on purpose; if it is,
+        // concurrency can no longer be bounded. The unsettled delivery goes back to
+        // the broker on shutdown below.
+        let permit = tokio::select! {
+            permit = semaphore.clone().acquire_owned() => permit,
+            _ = shutdown.changed() => {
+                waiting = Some(delivery);
+                break;
+            }
+            _ = maintenance.wait_for(|enabled| *enabled) => {
+                waiting = Some(delivery);
+                break;
+            }
+        };
+        let permit = match permit {
+            Ok(permit) => permit,
+            Err(_) => {
+                failure = Some("Task semaphore closed".into());
+                break;
+            }
+        };
+        let in_flight = state.worker.track();
+
+        if let Some(stages) = &stages {
+            if let Some(mut task) = pipeline::decode(
+                delivery,
+                settings.broker.max_delivery_attempts,
+                &state.payload_keys,
+                &state.cache_keys,
+            )
+            .await
             {
-                error!("Failed to update status to FAILED: {}", db_err);
+                task.permit = Some(permit);
+                task.in_flight = Some(in_flight);
+                if stages.send(task).await.is_err() {
+                    failure = Some("Pipeline stages have stopped".into());
+                    break;
+                }
             }
-            // Add acknowledgment for failed LLM requests
-            if let Err(ack_err) = delivery.ack(BasicAckOptions::default()).await {
-                error!("Failed to acknowledge failed message: {}", ack_err);
+            continue;
+        }
+
+        let settings = settings.clone();
+        let state = state.clone();
+        let db_client = db_client.clone();
+        let batcher = batcher.clone();
+
+        tokio::spawn(async move {
+            pipeline::process_message(settings, state, db_client, batcher, delivery).await;
+            drop(permit);
+            drop(in_flight);
+            Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+        });
+    }
+
+    if settings.handoff_prefetched && failure.is_none() {
+        hand_off_prefetched(
+            broker.as_ref(),
+            &mut consumer,
+            waiting,
+            consumer_tag,
+            settings.broker.prefetch as usize,
+        )
+        .await;
+    }
+
+    // Every in-flight task holds a permit until its delivery is settled, so getting
+    // all of them back means the drain is complete.
+    drop(consumer);
+    let drained = tokio::time::timeout(
+        Duration::from_secs(settings.shutdown_timeout_secs),
+        semaphore.acquire_many(settings.max_parallel_tasks as u32),
+    )
+    .await;
+    match drained {
+        Ok(Ok(_)) => info!("All in-flight tasks finished"),
+        Ok(Err(_)) => error!("Task semaphore closed; returning in-flight tasks to the broker"),
+        Err(_) => error!(
+            "{} tasks still in flight after {}s; returning them to the broker",
+            settings.max_parallel_tasks - semaphore.available_permits(),
+            settings.shutdown_timeout_secs
+        ),
+    }
+
+    if let Err(e) = broker.shutdown(consumer_tag).await {
+        error!("Failed to shut down broker cleanly: {}", e);
+    }
+    drop(db_client);
+    info!("Consumer {} stopped", consumer_tag);
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Stops consuming and hands the deliveries that were prefetched but not started back
+/// to the broker at once, so other replicas can take them while the in-flight tasks
+/// drain. Without this they stay unacked until the channel closes after the drain,
+/// which holds up rebalancing on scale-down by up to the shutdown timeout.
+async fn hand_off_prefetched(
+    broker: &dyn MessageBroker,
+    consumer: &mut broker::MessageStream,
+    waiting: Option<broker::BrokerMessage>,
+    consumer_tag: &str,
+    prefetch: usize,
+) {
+    if let Err(e) = broker.cancel(consumer_tag).await {
+        error!("Failed to cancel consumer {}: {}", consumer_tag, e);
+        return;
+    }
+
+    // The broker sends no more than the prefetch; a stream that keeps yielding past
+    // it or stalls is left to the shutdown.
+    let mut prefetched: Vec<_> = waiting.into_iter().collect();
+    while prefetched.len() < prefetch.max(1) {
+        match tokio::time::timeout(PREFETCH_HANDOFF_IDLE, consumer.next()).await {
+            Ok(Some(Ok(delivery))) => prefetched.push(delivery),
+            Ok(Some(Err(e))) => {
+                error!("Failed to receive prefetched delivery: {}", e);
+                break;
             }
+            Ok(None) | Err(_) => break,
+        }
+    }
+
+    let mut released = 0;
+    for delivery in &prefetched {
+        match delivery.release().await {
+            Ok(()) => released += 1,
+            Err(e) => error!("Failed to hand prefetched delivery back: {}", e),
         }
     }
+    if released > 0 {
+        info!("Handed {} prefetched deliveries back to the broker", released);
+    }
 }
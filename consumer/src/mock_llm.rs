@@ -0,0 +1,153 @@
+//! Mock LLM backend of `LLM_MODE=mock`, answering `call_llm` without network calls so
+//! the queue, pipeline and Elasticsearch path can be load-tested and integration-tested
+//! without spending tokens.
+//!
+//! A response is the fixture of the request's model, `<model>.json` in
+//! `LLM_MOCK_FIXTURES_DIR` with `/` written as `_`, or `default.json` when the model has
+//! none. Without a fixture, a canned response in the shape of the provider the request
+//! is sent to is built, its text derived from the request body so the same request
+//! always gets the same completion. Requests asking for JSON output get a JSON object.
+//! `LLM_MOCK_LATENCY_MS` delays each response to mimic a provider. Responses carry the
+//! `mock_response` annotation.
+
+use crate::schemas::llm_response::LLMResponse;
+use crate::settings::MockLlmSettings;
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Fixture used for models without their own.
+pub const DEFAULT_FIXTURE: &str = "default";
+
+pub struct MockBackend {
+    fixtures: HashMap<String, Value>,
+    latency: Duration,
+}
+
+impl MockBackend {
+    /// Backend answering with `fixtures`, by fixture name.
+    pub fn new(fixtures: HashMap<String, Value>, latency: Duration) -> Self {
+        Self { fixtures, latency }
+    }
+
+    /// Backend answering with the `*.json` fixtures of the configured directory.
+    pub fn load(
+        settings: &MockLlmSettings,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut fixtures = HashMap::new();
+        if let Some(dir) = &settings.fixtures_dir {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+                let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                    continue;
+                };
+                let fixture = serde_json::from_slice(&std::fs::read(&path)?)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                fixtures.insert(name.to_string(), fixture);
+            }
+            tracing::info!("Loaded {} mock LLM fixtures from {}", fixtures.len(), dir);
+        }
+        Ok(Self::new(
+            fixtures,
+            Duration::from_millis(settings.latency_ms),
+        ))
+    }
+
+    /// Response to `body` sent to `url`.
+    pub async fn respond(&self, url: &str, body: &Value) -> LLMResponse {
+        let started_at = Utc::now();
+        if !self.latency.is_zero() {
+            tokio::time::sleep(self.latency).await;
+        }
+        let model = body["model"].as_str().unwrap_or_default();
+        let completions = self
+            .fixtures
+            .get(&model.replace('/', "_"))
+            .or_else(|| self.fixtures.get(DEFAULT_FIXTURE))
+            .cloned()
+            .unwrap_or_else(|| canned_response(url, body));
+
+        let mut annotations = Map::new();
+        annotations.insert("mock_response".to_string(), Value::Bool(true));
+        LLMResponse {
+            usage: crate::llm_wrapper::parse_usage(&completions),
+            completions,
+            cached: false,
+            attempt: 0,
+            started_at,
+            completed_at: Utc::now(),
+            annotations,
+            cost: None,
+        }
+    }
+}
+
+/// Response in the shape of the provider behind `url`, with a text derived from `body`.
+fn canned_response(url: &str, body: &Value) -> Value {
+    let digest = hex::encode(Sha256::digest(body.to_string().as_bytes()));
+    let id = format!("mock-{}", &digest[..16]);
+    let wants_json = matches!(
+        body["response_format"]["type"].as_str(),
+        Some("json_object" | "json_schema")
+    ) || body["generationConfig"]["responseMimeType"] == "application/json";
+    let text = if wants_json {
+        json!({ "mock": id }).to_string()
+    } else {
+        format!("Mock completion {}.", id)
+    };
+    // Rough token counts, about four bytes per token.
+    let prompt_tokens = (body.to_string().len() as u64).div_ceil(4);
+    let completion_tokens = (text.len() as u64).div_ceil(4);
+    let model = body["model"].as_str().unwrap_or("mock");
+
+    if url.contains(":generateContent") {
+        json!({
+            "candidates": [{
+                "content": { "role": "model", "parts": [{ "text": text }] },
+                "finishReason": "STOP",
+            }],
+            "usageMetadata": {
+                "promptTokenCount": prompt_tokens,
+                "candidatesTokenCount": completion_tokens,
+                "totalTokenCount": prompt_tokens + completion_tokens,
+            },
+            "modelVersion": model,
+        })
+    } else if url.trim_end_matches('/').ends_with("/messages") {
+        json!({
+            "id": id,
+            "type": "message",
+            "role": "assistant",
+            "model": model,
+            "content": [{ "type": "text", "text": text }],
+            "stop_reason": "end_turn",
+            "usage": { "input_tokens": prompt_tokens, "output_tokens": completion_tokens },
+        })
+    } else {
+        let choice = if body.get("prompt").is_some() {
+            json!({ "index": 0, "text": text, "finish_reason": "stop" })
+        } else {
+            json!({
+                "index": 0,
+                "message": { "role": "assistant", "content": text },
+                "finish_reason": "stop",
+            })
+        };
+        json!({
+            "id": id,
+            "object": if body.get("prompt").is_some() { "text_completion" } else { "chat.completion" },
+            "model": model,
+            "choices": [choice],
+            "usage": {
+                "prompt_tokens": prompt_tokens,
+                "completion_tokens": completion_tokens,
+                "total_tokens": prompt_tokens + completion_tokens,
+            },
+        })
+    }
+}
@@ -0,0 +1,67 @@
+//! Request parameters operators set per task type, such as stop sequences, frequency
+//! and presence penalties or repetition penalties, merged into task bodies centrally so
+//! degenerate outputs are curbed without changing every producer.
+//!
+//! `PARAMETER_POLICIES` maps task types to the parameters merged into their bodies, `*`
+//! applying to every task after its type's own policy. Parameters the body already
+//! sets win and objects (e.g. Gemini's `generationConfig`) are merged recursively,
+//! except stop sequences, which are added to the body's own up to the four most
+//! providers accept. Parameters are sent as given, so a policy has to use the names
+//! of the API its task type is sent to.
+
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Policy applied to tasks of every type.
+pub const ALL_TASK_TYPES: &str = "*";
+/// Stop sequence lists of the OpenAI-compatible, Anthropic and Gemini APIs.
+const STOP_KEYS: &[&str] = &["stop", "stop_sequences", "stopSequences"];
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Merges the policy of `task_type`, then the one of all task types, into `body`.
+pub fn apply(policies: &HashMap<String, Value>, task_type: Option<&str>, body: &mut Value) {
+    let policies = [task_type, Some(ALL_TASK_TYPES)]
+        .into_iter()
+        .flatten()
+        .filter_map(|task_type| policies.get(task_type));
+    for policy in policies {
+        merge(body, policy);
+    }
+}
+
+fn merge(body: &mut Value, policy: &Value) {
+    let (Value::Object(body), Value::Object(policy)) = (body, policy) else {
+        return;
+    };
+    for (key, value) in policy {
+        match body.get_mut(key) {
+            None | Some(Value::Null) => {
+                body.insert(key.clone(), value.clone());
+            }
+            Some(existing) if STOP_KEYS.contains(&key.as_str()) => merge_stops(existing, value),
+            Some(existing) => merge(existing, value),
+        }
+    }
+}
+
+/// Adds the stop sequences of `policy` after the body's own, a single sequence being
+/// given as a string.
+fn merge_stops(existing: &mut Value, policy: &Value) {
+    let as_list = |value: &Value| match value {
+        Value::String(_) => Some(vec![value.clone()]),
+        Value::Array(stops) => Some(stops.clone()),
+        _ => None,
+    };
+    let (Some(mut stops), Some(additional)) = (as_list(existing), as_list(policy)) else {
+        return;
+    };
+    for stop in additional {
+        if stops.len() >= MAX_STOP_SEQUENCES {
+            break;
+        }
+        if !stops.contains(&stop) {
+            stops.push(stop);
+        }
+    }
+    *existing = Value::Array(stops);
+}
@@ -0,0 +1,229 @@
+//! Work partitioning between replicas. Every replica holds a Kubernetes Lease labelled
+//! with its partition group; the live leases form the membership, and each task is
+//! owned by one member picked by rendezvous hashing of its body hash. Replicas forward
+//! the tasks they don't own to the owner's partition queue, so identical requests meet
+//! on the same process and per-process limits can be sized as a share of the cluster's.
+
+use crate::broker::{BrokerMessage, PARTITION_HEADER};
+use crate::settings::PartitionSettings;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::sync::RwLock;
+use std::time::Duration;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+pub const SERVICE_ACCOUNT_NAMESPACE: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+const GROUP_LABEL: &str = "synthgen.io/partition-group";
+
+pub type PartitionResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Deserialize)]
+struct PartitionKey<'a> {
+    #[serde(default, borrow)]
+    message_id: Cow<'a, str>,
+    #[serde(default, borrow)]
+    body_hash: Cow<'a, str>,
+}
+
+/// Member owning `key`: the one with the highest hash of (member, key). Only the keys
+/// of a member that leaves or joins move.
+pub fn owner<'m>(members: &'m [String], key: &str) -> Option<&'m str> {
+    members
+        .iter()
+        .max_by_key(|member| {
+            let digest = Sha256::new()
+                .chain_update(member.as_bytes())
+                .chain_update([0])
+                .chain_update(key.as_bytes())
+                .finalize();
+            u64::from_be_bytes(digest[..8].try_into().unwrap())
+        })
+        .map(String::as_str)
+}
+
+/// Live members of the partition group, kept up to date from Kubernetes Leases.
+pub struct Membership {
+    settings: PartitionSettings,
+    client: reqwest::Client,
+    api_url: String,
+    /// Service account token, projected and rotated by the kubelet.
+    token_path: String,
+    members: RwLock<Vec<String>>,
+}
+
+impl Membership {
+    /// Talks to the API server with the pod's service account.
+    pub fn in_cluster(settings: &PartitionSettings) -> PartitionResult<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let token_path = format!("{}/token", SERVICE_ACCOUNT_DIR);
+        // Fail now rather than on the first renewal when there's no token.
+        std::fs::metadata(&token_path)?;
+        let ca = std::fs::read(format!("{}/ca.crt", SERVICE_ACCOUNT_DIR))?;
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+            .timeout(Duration::from_secs(10))
+            .build()?;
+
+        Ok(Self {
+            settings: settings.clone(),
+            client,
+            api_url: format!(
+                "https://{}:{}/apis/coordination.k8s.io/v1/namespaces/{}/leases",
+                host, port, settings.namespace
+            ),
+            token_path,
+            members: RwLock::new(vec![settings.identity.clone()]),
+        })
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.settings.identity
+    }
+
+    /// Member the message has to be forwarded to, or `None` when this replica owns it.
+    /// Messages delivered from a partition queue, which were already forwarded once,
+    /// stay where they are, so a membership change can't bounce them around.
+    pub fn forward_target(&self, message: &BrokerMessage) -> Option<String> {
+        if message.headers.contains_key(PARTITION_HEADER) {
+            return None;
+        }
+        let key: PartitionKey = serde_json::from_slice(&message.data).ok()?;
+        let key = if key.body_hash.is_empty() {
+            key.message_id
+        } else {
+            key.body_hash
+        };
+        let members = self.members.read().unwrap();
+        owner(&members, &key)
+            .filter(|owner| *owner != self.settings.identity)
+            .map(str::to_string)
+    }
+
+    /// Renews this replica's lease and refreshes the membership until the process
+    /// exits, calling `on_change` with the member count whenever it changes.
+    pub async fn run(&self, on_change: impl Fn(usize)) {
+        let interval = Duration::from_secs((self.settings.lease_duration_secs / 3).max(1));
+        loop {
+            if let Err(e) = self.renew().await {
+                tracing::error!("Failed to renew partition lease: {}", e);
+            }
+            match self.live_members().await {
+                Ok(mut live) => {
+                    // Our own lease may lag behind on its first renewal.
+                    if !live.contains(&self.settings.identity) {
+                        live.push(self.settings.identity.clone());
+                    }
+                    live.sort();
+                    let changed = *self.members.read().unwrap() != live;
+                    if changed {
+                        tracing::info!(
+                            "Partition group {} has {} members: {}",
+                            self.settings.group,
+                            live.len(),
+                            live.join(", ")
+                        );
+                        let count = live.len();
+                        *self.members.write().unwrap() = live;
+                        on_change(count);
+                    }
+                }
+                Err(e) => tracing::error!("Failed to list partition leases: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Current service account token; read for every request, as the kubelet rotates it.
+    fn token(&self) -> PartitionResult<String> {
+        Ok(std::fs::read_to_string(&self.token_path)?
+            .trim()
+            .to_string())
+    }
+
+    fn lease_name(&self) -> String {
+        format!("{}-{}", self.settings.group, self.settings.identity)
+    }
+
+    async fn renew(&self) -> PartitionResult<()> {
+        let token = self.token()?;
+        let now = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+        let spec = json!({
+            "holderIdentity": self.settings.identity,
+            "leaseDurationSeconds": self.settings.lease_duration_secs,
+            "renewTime": now,
+        });
+
+        let response = self
+            .client
+            .patch(format!("{}/{}", self.api_url, self.lease_name()))
+            .bearer_auth(&token)
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "application/merge-patch+json",
+            )
+            .body(json!({ "spec": spec }).to_string())
+            .send()
+            .await?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+            return Ok(());
+        }
+
+        self.client
+            .post(&self.api_url)
+            .bearer_auth(&token)
+            .json(&json!({
+                "apiVersion": "coordination.k8s.io/v1",
+                "kind": "Lease",
+                "metadata": {
+                    "name": self.lease_name(),
+                    "labels": { GROUP_LABEL: self.settings.group },
+                },
+                "spec": spec,
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Holders of the group's leases that were renewed within their duration.
+    async fn live_members(&self) -> PartitionResult<Vec<String>> {
+        let token = self.token()?;
+        let leases: Value = self
+            .client
+            .get(&self.api_url)
+            .bearer_auth(&token)
+            .query(&[(
+                "labelSelector",
+                format!("{}={}", GROUP_LABEL, self.settings.group),
+            )])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let now = Utc::now();
+        Ok(leases["items"]
+            .as_array()
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(|lease| {
+                        let spec = &lease["spec"];
+                        let renewed = spec["renewTime"].as_str()?.parse::<DateTime<Utc>>().ok()?;
+                        let duration = spec["leaseDurationSeconds"].as_i64()?;
+                        (renewed + chrono::Duration::seconds(duration) > now)
+                            .then(|| spec["holderIdentity"].as_str().map(str::to_string))?
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
@@ -0,0 +1,205 @@
+//! Request and response sizes of LLM calls per model, and per-model response budgets
+//! that keep pathological generations out of memory and the events index.
+//!
+//! Sizes are kept as histograms over power-of-four buckets from 1 KiB to 16 MiB and
+//! logged every `PAYLOAD_METRICS_INTERVAL_SECS`. `RESPONSE_BUDGET_<MODEL>=<bytes>`
+//! caps the responses of models whose name contains the pattern, the smallest budget
+//! winning when several match. Over budget, the response is no longer read and the
+//! attempt fails (`OVERSIZED_RESPONSE_ACTION=abort`, the default), or it's kept with
+//! its size in the `oversized_response` annotation (`flag`). Aborted responses are
+//! recorded with the size read before giving up.
+//!
+//! `MAX_REQUEST_BODY_BYTES` caps request bodies: a task whose body, rendered, is larger
+//! is rejected before any call.
+
+use crate::settings::{OversizedResponseAction, PayloadSettings};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in bytes; larger sizes go in a last bucket.
+pub const BUCKET_BOUNDS: [u64; 8] = [
+    1 << 10,
+    1 << 12,
+    1 << 14,
+    1 << 16,
+    1 << 18,
+    1 << 20,
+    1 << 22,
+    1 << 24,
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SizeHistogram {
+    buckets: [u64; BUCKET_BOUNDS.len() + 1],
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl SizeHistogram {
+    pub fn record(&mut self, bytes: u64) {
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| bytes <= *bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += bytes;
+        self.max = self.max.max(bytes);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.sum.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Upper bound of the bucket holding the `quantile` (0 to 1) of the sizes, capped
+    /// by the largest size seen.
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        let rank = (quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return BUCKET_BOUNDS
+                    .get(bucket)
+                    .map_or(self.max, |bound| (*bound).min(self.max));
+            }
+        }
+        self.max
+    }
+}
+
+/// Why a request `body` is refused under `max_bytes`, if it is. Zero lets any through.
+pub fn check_request_size(body: &Value, max_bytes: usize) -> Result<(), String> {
+    if max_bytes == 0 {
+        return Ok(());
+    }
+    let bytes = body.to_string().len();
+    if bytes > max_bytes {
+        return Err(format!(
+            "Request body of {} bytes is over the limit of {} bytes",
+            bytes, max_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Request and response sizes of one model.
+#[derive(Debug, Clone, Default)]
+pub struct ModelSizes {
+    pub requests: SizeHistogram,
+    pub responses: SizeHistogram,
+}
+
+#[derive(Default)]
+pub struct Payloads {
+    budgets: Vec<(String, u64)>,
+    oversized_response: OversizedResponseAction,
+    sizes: Mutex<HashMap<String, ModelSizes>>,
+}
+
+impl Payloads {
+    pub fn new(settings: &PayloadSettings) -> Self {
+        Self {
+            budgets: settings
+                .response_budgets
+                .iter()
+                .map(|budget| (budget.pattern.to_lowercase(), budget.max_bytes))
+                .collect(),
+            oversized_response: settings.oversized_response,
+            sizes: Mutex::default(),
+        }
+    }
+
+    /// Largest response accepted from `model`, if any budget applies to it.
+    pub fn response_budget(&self, model: &str) -> Option<u64> {
+        let model = model.to_lowercase();
+        self.budgets
+            .iter()
+            .filter(|(pattern, _)| model.contains(pattern.as_str()))
+            .map(|(_, max_bytes)| *max_bytes)
+            .min()
+    }
+
+    /// Whether responses over budget are given up on rather than flagged.
+    pub fn aborts_oversized(&self) -> bool {
+        self.oversized_response == OversizedResponseAction::Abort
+    }
+
+    pub fn record_request(&self, model: &str, bytes: u64) {
+        self.with_sizes(model, |sizes| sizes.requests.record(bytes));
+    }
+
+    pub fn record_response(&self, model: &str, bytes: u64) {
+        self.with_sizes(model, |sizes| sizes.responses.record(bytes));
+    }
+
+    /// Sizes recorded so far for `model`.
+    pub fn sizes(&self, model: &str) -> Option<ModelSizes> {
+        self.sizes.lock().unwrap().get(model).cloned()
+    }
+
+    fn with_sizes(&self, model: &str, update: impl FnOnce(&mut ModelSizes)) {
+        let mut sizes = self.sizes.lock().unwrap();
+        match sizes.get_mut(model) {
+            Some(model_sizes) => update(model_sizes),
+            None => update(sizes.entry(model.to_string()).or_default()),
+        }
+    }
+
+    /// Logs the sizes of every model each `interval`.
+    pub async fn report(&self, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            let sizes = self.sizes.lock().unwrap().clone();
+            for (model, sizes) in sizes {
+                tracing::info!(
+                    model,
+                    requests = sizes.requests.count(),
+                    request_bytes_mean = sizes.requests.mean(),
+                    request_bytes_p99 = sizes.requests.quantile(0.99),
+                    request_bytes_max = sizes.requests.max(),
+                    responses = sizes.responses.count(),
+                    response_bytes_mean = sizes.responses.mean(),
+                    response_bytes_p50 = sizes.responses.quantile(0.5),
+                    response_bytes_p99 = sizes.responses.quantile(0.99),
+                    response_bytes_max = sizes.responses.max(),
+                    "LLM payload sizes"
+                );
+            }
+        }
+    }
+}
+
+/// Reads the body of `response`, stopping as soon as it exceeds `budget` when `abort`
+/// is set. Returns the bytes read and whether the budget was exceeded.
+pub async fn read_body(
+    mut response: reqwest::Response,
+    budget: Option<u64>,
+    abort: bool,
+) -> reqwest::Result<(Vec<u8>, bool)> {
+    let mut body = Vec::new();
+    let mut over_budget = false;
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if budget.is_some_and(|budget| body.len() as u64 > budget) {
+            over_budget = true;
+            if abort {
+                break;
+            }
+        }
+    }
+    Ok((body, over_budget))
+}
@@ -0,0 +1,142 @@
+//! End-to-end encrypted task payloads, for regulated data synthesis on infrastructure
+//! that isn't trusted with the data.
+//!
+//! Producers encrypt the whole payload to the consumer's age X25519 recipients
+//! (`PAYLOAD_RECIPIENTS`) and send `{"age": "<base64 ciphertext>"}` in its place. The
+//! broker and the events index then only see the ciphertext and the routing fields of
+//! the message (`message_id`, `batch_id`, `body_hash`); the consumer decrypts the
+//! payload in memory with its identities (`PAYLOAD_IDENTITY_FILE`, as written by
+//! `age-keygen`). The body hash is left in the clear so the cache and deduplication keep
+//! working; it reveals which tasks share a body, not the body.
+//!
+//! With `PAYLOAD_ENCRYPTION_REQUIRED`, plaintext payloads are dead-lettered.
+//! Completions are stored as generated: results leave the consumer through the
+//! configured store and exports.
+
+use crate::settings::PayloadEncryptionSettings;
+use age::x25519::{Identity, Recipient};
+use base64::Engine;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+
+/// Field of an encrypted payload holding its ciphertext.
+pub const CIPHERTEXT: &str = "age";
+
+/// Whether `payload` is an encrypted payload.
+pub fn is_encrypted(payload: &Value) -> bool {
+    payload.as_object().is_some_and(|fields| {
+        fields.len() == 1 && fields.get(CIPHERTEXT).is_some_and(Value::is_string)
+    })
+}
+
+/// Parses `age1...` recipients.
+pub fn parse_recipients(recipients: &[String]) -> Result<Vec<Recipient>, String> {
+    recipients
+        .iter()
+        .map(|recipient| {
+            recipient
+                .parse()
+                .map_err(|e| format!("Invalid age recipient {}: {}", recipient, e))
+        })
+        .collect()
+}
+
+/// `payload` encrypted to `recipients`, any of whose identities decrypts it.
+pub fn encrypt(payload: &Value, recipients: &[Recipient]) -> Result<Value, String> {
+    let encryptor = age::Encryptor::with_recipients(
+        recipients
+            .iter()
+            .map(|recipient| recipient as &dyn age::Recipient),
+    )
+    .map_err(|e| e.to_string())?;
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut ciphertext)
+        .map_err(|e| e.to_string())?;
+    serde_json::to_writer(&mut writer, payload).map_err(|e| e.to_string())?;
+    writer.flush().map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(json!({
+        CIPHERTEXT: base64::engine::general_purpose::STANDARD.encode(ciphertext)
+    }))
+}
+
+/// The consumer's identities, decrypting the payloads of deliveries.
+pub struct PayloadKeys {
+    identities: Vec<Identity>,
+    required: bool,
+}
+
+impl PayloadKeys {
+    /// Reads the identities of `settings.identity_file`: `AGE-SECRET-KEY-1...` lines,
+    /// with `#` comments.
+    pub fn load(
+        settings: &PayloadEncryptionSettings,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let identities = match &settings.identity_file {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+                parse_identities(&contents).map_err(|e| format!("{}: {}", path, e))?
+            }
+            None => Vec::new(),
+        };
+        if settings.required && identities.is_empty() {
+            return Err("PAYLOAD_ENCRYPTION_REQUIRED needs a PAYLOAD_IDENTITY_FILE".into());
+        }
+        Ok(Self {
+            identities,
+            required: settings.required,
+        })
+    }
+
+    /// Keys of `identities`, requiring encrypted payloads when `required`.
+    pub fn new(identities: Vec<Identity>, required: bool) -> Self {
+        Self {
+            identities,
+            required,
+        }
+    }
+
+    /// The plaintext of `payload`, or `payload` itself when it isn't encrypted and
+    /// encryption isn't required.
+    pub fn decrypt(&self, payload: Value) -> Result<Value, String> {
+        if !is_encrypted(&payload) {
+            if self.required {
+                return Err("Payload must be encrypted".to_string());
+            }
+            return Ok(payload);
+        }
+        if self.identities.is_empty() {
+            return Err("Payload is encrypted but no PAYLOAD_IDENTITY_FILE is set".to_string());
+        }
+        let ciphertext = base64::engine::general_purpose::STANDARD
+            .decode(payload[CIPHERTEXT].as_str().unwrap_or_default())
+            .map_err(|e| format!("Invalid payload ciphertext: {}", e))?;
+        let decryptor = age::Decryptor::new_buffered(&ciphertext[..])
+            .map_err(|e| format!("Invalid payload ciphertext: {}", e))?;
+        let mut plaintext = Vec::new();
+        decryptor
+            .decrypt(
+                self.identities
+                    .iter()
+                    .map(|identity| identity as &dyn age::Identity),
+            )
+            .and_then(|mut reader| Ok(reader.read_to_end(&mut plaintext)?))
+            .map_err(|e| format!("Failed to decrypt payload: {}", e))?;
+        serde_json::from_slice(&plaintext).map_err(|e| format!("Decrypted payload: {}", e))
+    }
+}
+
+fn parse_identities(contents: &str) -> Result<Vec<Identity>, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            line.parse()
+                .map_err(|_| format!("line {} is not an age X25519 identity", i + 1))
+        })
+        .collect()
+}
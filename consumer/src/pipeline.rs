@@ -0,0 +1,2062 @@
+//! Message processing, split into stages: decode → cache → LLM → postprocess → persist.
+//!
+//! `process_message` runs the stages back to back for one delivery. In staged mode
+//! (`PIPELINE_MODE=staged`) each stage instead runs behind its own bounded channel with
+//! its own parallelism, so slow Elasticsearch writes don't hold LLM slots and vice versa.
+
+use crate::{AppState, Settings};
+use chrono::{DateTime, Utc};
+use consumer::anthropic;
+use consumer::attribution::Attribution;
+use consumer::batch_dedup::BatchDedup;
+use consumer::batching::{self, BatchApiClient, BatchKey};
+use consumer::broker::BrokerMessage;
+use consumer::cache_key::CacheKeys;
+use consumer::cache_samples;
+use consumer::conversations;
+use consumer::db;
+use consumer::debug_trace::Trace;
+use consumer::difficulty;
+use consumer::dispatch_windows;
+use consumer::diversity;
+use consumer::embedding_tasks;
+use consumer::evaluation;
+use consumer::feature_flags::FlagContext;
+use consumer::judge;
+use consumer::labeling;
+use consumer::legacy_completions;
+use consumer::llm_wrapper;
+use consumer::parameter_policy;
+use consumer::payload;
+use consumer::payload_encryption::PayloadKeys;
+use consumer::quality_gates;
+use consumer::queue_aging;
+use consumer::rate_limit;
+use consumer::schemas;
+use consumer::schemas::envelope::{TaskMessage, TaskPayload};
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::provider_response::Usage;
+use consumer::settings::{
+    AckPolicy, BatchApiSettings, BrokerKind, DedupMode, DiversitySettings, PipelineSettings,
+    ProviderApi,
+};
+use consumer::telemetry;
+use consumer::text;
+use consumer::truncation;
+use consumer::validation;
+use consumer::webhook::Webhooks;
+use consumer::workers::InFlight;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::{error, info, info_span, warn, Instrument, Span};
+
+pub struct Task {
+    /// `None` once the delivery was settled early, e.g. after a batch API submission.
+    pub delivery: Option<BrokerMessage>,
+    pub message_id: String,
+    pub batch_id: String,
+    pub body_hash: String,
+    pub payload: Value,
+    /// Who the task is run for, from the message's `attribution`.
+    pub attribution: Attribution,
+    /// When the producer submitted the task, see `queue_aging`.
+    pub enqueued_at: Option<DateTime<Utc>>,
+    pub processing_started_at: DateTime<Utc>,
+    /// In-flight slot held until the task is settled; shutdown drains these.
+    pub permit: Option<OwnedSemaphorePermit>,
+    /// Counts the task in the worker's heartbeats until it's settled.
+    pub in_flight: Option<InFlight>,
+    /// Set by `start_trace` when the task's processing is traced.
+    pub trace: Option<Trace>,
+    /// Parent of the task's spans, continuing the trace of its submission.
+    pub span: Span,
+}
+
+impl Task {
+    pub fn event_key(&self) -> db::EventKey<'_> {
+        db::EventKey {
+            message_id: &self.message_id,
+            batch_id: &self.batch_id,
+            body_hash: &self.body_hash,
+        }
+    }
+
+    pub fn flag_context(&self) -> FlagContext<'_> {
+        FlagContext {
+            batch_id: &self.batch_id,
+            tenant: self.payload["tenant"].as_str(),
+            key: &self.message_id,
+        }
+    }
+}
+
+pub enum Outcome {
+    Completed(LLMResponse),
+    Failed(String),
+    /// The task ran past its deadline; its LLM call was cancelled. Its delivery is
+    /// requeued until it runs out of attempts.
+    TimedOut,
+    /// The LLM call gave up early, its next retry being unable to start before the
+    /// task's deadline.
+    DeadlineUnreachable(String),
+    /// The payload breaks the registered schema of its `task_type`.
+    Rejected(String),
+    /// The payload's prompt template can't be rendered.
+    InvalidTemplate(String),
+    /// The payload is malformed, e.g. a field of the wrong type.
+    Quarantined(String),
+    /// The task went stale in the queue and `TASK_STALE_POLICY` expires stale tasks.
+    Expired(String),
+    /// The task was held for its providers' dispatch windows as long as its delivery
+    /// can be; it's handed back to the broker to wait again.
+    Held(String),
+    /// A rate limit asked for a wait of `RATE_LIMIT_DEFER_AFTER_MS` or longer; the task
+    /// is re-published through a delay queue rather than wait in its worker slot.
+    Deferred(String, Duration),
+}
+
+impl Outcome {
+    fn from_error(error: Box<dyn std::error::Error + Send + Sync>) -> Self {
+        if error.is::<llm_wrapper::DeadlineUnreachable>() {
+            Outcome::DeadlineUnreachable(error.to_string())
+        } else if error.is::<dispatch_windows::WindowClosed>() {
+            Outcome::Held(error.to_string())
+        } else if let Some(deferred) = error.downcast_ref::<llm_wrapper::RateLimitDeferred>() {
+            Outcome::Deferred(deferred.message.clone(), deferred.retry_after)
+        } else {
+            Outcome::Failed(error.to_string())
+        }
+    }
+}
+
+/// Parses the delivery, decrypts its payload and resolves its cache key; malformed
+/// messages, including those missing an identifier, whose payload can't be decrypted
+/// or whose `body_hash` fails verification, and messages past `max_delivery_attempts`
+/// are dead-lettered.
+pub async fn decode(
+    delivery: BrokerMessage,
+    max_delivery_attempts: u32,
+    payload_keys: &PayloadKeys,
+    cache_keys: &CacheKeys,
+) -> Option<Task> {
+    let envelope = match TaskMessage::parse(&delivery.data) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("Failed to parse message: {}", e);
+            // Don't requeue since it's malformed
+            if let Err(dlq_err) = delivery
+                .dead_letter(&format!("Malformed message: {}", e))
+                .await
+            {
+                error!("Failed to dead-letter malformed message: {}", dlq_err);
+            }
+            return None;
+        }
+    };
+
+    let attempt = delivery.delivery_attempt();
+    if attempt > max_delivery_attempts {
+        error!(
+            "Message {} exceeded {} delivery attempts",
+            envelope.message_id, max_delivery_attempts
+        );
+        if let Err(dlq_err) = delivery
+            .dead_letter(&format!(
+                "Exceeded {} delivery attempts",
+                max_delivery_attempts
+            ))
+            .await
+        {
+            error!("Failed to dead-letter message: {}", dlq_err);
+        }
+        return None;
+    }
+
+    let enqueued_at = queue_aging::enqueued_at(&envelope);
+    let payload = match payload_keys.decrypt(envelope.payload) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Message {}: {}", envelope.message_id, e);
+            if let Err(dlq_err) = delivery.dead_letter(&e).await {
+                error!("Failed to dead-letter message: {}", dlq_err);
+            }
+            return None;
+        }
+    };
+
+    let body_hash = match cache_keys.resolve(&payload, &envelope.body_hash) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Message {}: {}", envelope.message_id, e);
+            if let Err(dlq_err) = delivery.dead_letter(&e).await {
+                error!("Failed to dead-letter message: {}", dlq_err);
+            }
+            return None;
+        }
+    };
+    let message_id = envelope.message_id.into_owned();
+    let batch_id = envelope.batch_id.into_owned();
+    let attribution = envelope.attribution.unwrap_or_default();
+    let span = info_span!(
+        "process_message",
+        message_id = %message_id,
+        batch_id = %batch_id,
+        delivery_attempt = attempt,
+        org = attribution.org.as_deref(),
+        project = attribution.project.as_deref(),
+        user = attribution.user.as_deref(),
+    );
+    telemetry::set_parent(&span, &delivery.headers);
+    let task = Task {
+        message_id,
+        batch_id,
+        body_hash,
+        payload,
+        attribution,
+        enqueued_at,
+        processing_started_at: Utc::now(),
+        permit: None,
+        in_flight: None,
+        trace: None,
+        span,
+        delivery: Some(delivery),
+    };
+    info!("Processing message {}", task.message_id);
+    Some(task)
+}
+
+/// Starts the task's debug trace under `DEBUG_TRACE`, recording the message and the
+/// settings the task resolves to.
+pub fn start_trace(settings: &Settings, state: &AppState, task: &mut Task) {
+    let message = serde_json::json!({
+        "message_id": task.message_id,
+        "batch_id": task.batch_id,
+        "body_hash": task.body_hash,
+        "delivery_attempt": task.delivery.as_ref().map(|d| d.delivery_attempt()),
+        "received_at": task.processing_started_at,
+        "payload": task.payload,
+    });
+    let Some(trace) = Trace::start(settings.debug_trace, &message) else {
+        return;
+    };
+
+    let context = task.flag_context();
+    let enabled = |flag: &str| state.feature_flags.is_enabled(flag, &context);
+    trace.set_settings(serde_json::json!({
+        "providers": llm_wrapper::task_providers(&task.payload, &settings.fallback_providers),
+        "retry_attempts": settings.retry_attempts,
+        "base_delay_ms": settings.base_delay_ms,
+        "max_delay_secs": settings.max_delay_secs,
+        "timeout_secs": task.payload["timeout_secs"]
+            .as_u64()
+            .unwrap_or(settings.task_timeout_secs),
+        "ack_policy": ack_policy(settings, task),
+        "dedup_mode": settings.dedup_mode,
+        "empty_completion": settings.empty_completion,
+        "validation_repair_attempts": settings.validation_repair_attempts,
+        "min_quality_score": settings.min_quality_score,
+        "stages": {
+            "schema_repair": enabled("schema_repair"),
+            "content_filter": state.content_filter.is_some() && enabled("content_filter"),
+            "difficulty": settings.difficulty.is_some() && enabled("difficulty"),
+            "labeling": settings.labeling.is_some() && enabled("labeling"),
+            "judge": settings.quality_judge.is_some() && enabled("judge"),
+            "local_models": enabled("local_models"),
+            "contamination": state.contamination.is_some() && enabled("contamination"),
+            "corpus_dedup": state.corpus.is_some() && enabled("corpus_dedup"),
+            "diversity": settings.diversity.is_some() && enabled("diversity"),
+            "batch_dedup": state.batch_dedup.is_some() && enabled("batch_dedup"),
+            "completion_embeddings": state.embedder.is_some()
+                && settings.index_completion_embeddings
+                && enabled("completion_embeddings"),
+            "balance": state.balance.is_some() && enabled("balance"),
+        },
+    }));
+    task.trace = Some(trace);
+}
+
+/// Runs `future` under the task's trace, if it has one.
+async fn traced<F: Future>(task: &Task, future: F) -> F::Output {
+    match &task.trace {
+        Some(trace) => trace.scope(future).await,
+        None => future.await,
+    }
+}
+
+/// Looks up the task's event under `DEDUP_MODE` and acks the delivery when the event
+/// already completed or was cancelled. Returns `true` when the delivery was settled and
+/// the task must not be processed.
+pub async fn skip_completed(
+    settings: &Settings,
+    db_client: &dyn db::TaskStore,
+    task: &Task,
+) -> bool {
+    if settings.dedup_mode == DedupMode::Off {
+        return false;
+    }
+    let Some(delivery) = &task.delivery else {
+        return false;
+    };
+    match db_client.event_state(&task.message_id).await {
+        Ok(Some(state)) if state.skips_delivery() => {
+            if state.cancelled {
+                info!(
+                    "Message {} was cancelled, acknowledging it",
+                    task.message_id
+                );
+            } else {
+                info!(
+                    "Message {} already completed, acknowledging redelivery",
+                    task.message_id
+                );
+            }
+            if let Err(ack_err) = delivery.ack().await {
+                error!("Failed to acknowledge message: {}", ack_err);
+            }
+            true
+        }
+        Ok(_) => false,
+        Err(e) if settings.dedup_mode == DedupMode::Strict => {
+            error!(
+                "Failed to look up status of message {}: {}",
+                task.message_id, e
+            );
+            retry_or_dead_letter(
+                delivery,
+                settings.broker.max_delivery_attempts,
+                &format!("Failed to look up event status: {}", e),
+            )
+            .await;
+            true
+        }
+        Err(e) => {
+            warn!(
+                "Failed to look up status of message {}: {}",
+                task.message_id, e
+            );
+            false
+        }
+    }
+}
+
+/// Expiry of a task that went stale in the queue, under `TASK_STALE_POLICY=expire`.
+pub fn check_staleness(state: &AppState, task: &Task) -> Option<Outcome> {
+    let age = queue_aging::age(task.enqueued_at?, task.processing_started_at);
+    if !state.queue_aging.as_ref()?.expires(age) {
+        return None;
+    }
+    let error = format!("Task expired after {}s in the queue", age.as_secs());
+    warn!("Expiring message {}: {}", task.message_id, error);
+    Some(Outcome::Expired(error))
+}
+
+/// Renders the task's body from its `template`, if it has one, and merges in the
+/// parameter policies of its task type. Tasks whose payload is malformed are
+/// quarantined, and those whose template can't be rendered or whose body is over
+/// `MAX_REQUEST_BODY_BYTES` rejected.
+pub fn prepare_body(settings: &Settings, state: &AppState, task: &mut Task) -> Option<Outcome> {
+    if let Err(error) = TaskPayload::from_value(&task.payload) {
+        let error = format!("Malformed task payload: {}", error);
+        warn!("Quarantining message {}: {}", task.message_id, error);
+        return Some(Outcome::Quarantined(error));
+    }
+    if let Err(error) = state.templates.render_payload(&mut task.payload) {
+        let error = format!("Failed to render the prompt template: {}", error);
+        warn!("Rejecting message {}: {}", task.message_id, error);
+        return Some(Outcome::InvalidTemplate(error));
+    }
+    let task_type = task.payload["task_type"].as_str().map(str::to_string);
+    if let Some(body) = task.payload.get_mut("body") {
+        parameter_policy::apply(&settings.parameter_policies, task_type.as_deref(), body);
+    }
+    if let Err(error) =
+        payload::check_request_size(&task.payload["body"], settings.payloads.max_request_bytes)
+    {
+        warn!("Rejecting message {}: {}", task.message_id, error);
+        return Some(Outcome::Rejected(error));
+    }
+    None
+}
+
+/// Rejection of a task whose payload breaks the registered schema of its `task_type`.
+pub fn check_contract(state: &AppState, task: &Task) -> Option<Outcome> {
+    let errors = state.schema_registry.check(&task.payload).err()?;
+    let error = format!("Payload violates its schema: {}", errors.join("; "));
+    warn!("Rejecting message {}: {}", task.message_id, error);
+    Some(Outcome::Rejected(error))
+}
+
+/// Marks the task PROCESSING when progress is tracked and looks up the cache.
+/// Returns `Err(())` when the task must be abandoned; its delivery is then requeued.
+pub async fn check_cache(
+    settings: &Settings,
+    db_client: &dyn db::TaskStore,
+    task: &Task,
+) -> Result<Option<LLMResponse>, ()> {
+    let max_delivery_attempts = settings.broker.max_delivery_attempts;
+    let use_cache = task.payload["use_cache"].as_bool().unwrap_or(false);
+    let track_progress = task.payload["track_progress"].as_bool().unwrap_or(false);
+
+    // Update status to PROCESSING only if track_progress is true
+    if track_progress {
+        // Kept on the event so the task can be resumed if this consumer dies.
+        let message = task
+            .delivery
+            .as_ref()
+            .and_then(|delivery| serde_json::from_slice::<Value>(&delivery.data).ok());
+        if let Err(e) = db_client
+            .update_event_status(
+                &task.event_key(),
+                schemas::task_status::TaskStatus::Processing,
+                &LLMResponse {
+                    completions: Value::Null,
+                    cached: false,
+                    attempt: 0,
+                    started_at: task.processing_started_at,
+                    completed_at: task.processing_started_at,
+                    annotations: message
+                        .map(|message| (db::TASK_MESSAGE.to_string(), message))
+                        .into_iter()
+                        .collect(),
+                    usage: None,
+                    cost: None,
+                },
+                task.processing_started_at,
+            )
+            .instrument(info_span!("update_event_status", status = "PROCESSING"))
+            .await
+        {
+            error!("Failed to update status to PROCESSING: {}", e);
+            if let Some(delivery) = &task.delivery {
+                retry_or_dead_letter(
+                    delivery,
+                    max_delivery_attempts,
+                    &format!("Failed to mark task as processing: {}", e),
+                )
+                .await;
+            }
+            return Err(());
+        }
+    }
+
+    // Check cache only if use_cache is true
+    if !use_cache {
+        return Ok(None);
+    }
+    if cache_samples::is_stochastic(&task.payload["body"]) {
+        let limit = cache_samples::sample_limit(&task.payload, settings.stochastic_cache_samples);
+        if limit == 0 {
+            return Ok(None);
+        }
+        return match db_client.cached_samples(&task.body_hash, limit).await {
+            Ok(samples) => {
+                let collected = samples.len();
+                let sample = cache_samples::pick(samples, limit, &task.message_id);
+                if sample.is_some() {
+                    info!(
+                        "Using one of {} cached samples for message {}",
+                        limit, task.message_id
+                    );
+                } else {
+                    info!(
+                        "Generating sample {} of {} for message {}",
+                        collected + 1,
+                        limit,
+                        task.message_id
+                    );
+                }
+                Ok(sample)
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to load cached samples of message {}: {}",
+                    task.message_id, e
+                );
+                Ok(None)
+            }
+        };
+    }
+    if let Ok(Some(cached_response)) = db_client.get_cached_completion(&task.body_hash).await {
+        info!("Using cached response for message {}", task.message_id);
+        return Ok(Some(cached_response));
+    }
+    Ok(None)
+}
+
+/// Generation checkpointed by an earlier delivery of the task, which crashed or was
+/// interrupted before its final status was written.
+async fn resume_checkpoint(
+    settings: &Settings,
+    db_client: &dyn db::TaskStore,
+    task: &Task,
+) -> Option<LLMResponse> {
+    let redelivered = task
+        .delivery
+        .as_ref()
+        .is_some_and(|delivery| delivery.delivery_attempt() > 1);
+    if !settings.checkpoint_generations || !redelivered {
+        return None;
+    }
+    match db_client.load_checkpoint(&task.message_id).await {
+        Ok(Some(response)) => {
+            info!(
+                "Resuming message {} after its checkpointed generation",
+                task.message_id
+            );
+            Some(response)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!(
+                "Failed to load the checkpoint of message {}: {}",
+                task.message_id, e
+            );
+            None
+        }
+    }
+}
+
+async fn save_checkpoint(
+    settings: &Settings,
+    db_client: &dyn db::TaskStore,
+    task: &Task,
+    response: &LLMResponse,
+) {
+    if !settings.checkpoint_generations {
+        return;
+    }
+    if let Err(e) = db_client.save_checkpoint(&task.event_key(), response).await {
+        warn!(
+            "Failed to checkpoint the generation of message {}: {}",
+            task.message_id, e
+        );
+    }
+}
+
+pub async fn generate(
+    settings: &Settings,
+    state: &AppState,
+    db_client: &dyn db::TaskStore,
+    task: &Task,
+) -> Result<LLMResponse, Box<dyn std::error::Error + Send + Sync>> {
+    if embedding_tasks::is_embedding_task(&task.payload) {
+        return embed(settings, state, task).await;
+    }
+    let context = task.flag_context();
+    let enabled = |flag: &str| state.feature_flags.is_enabled(flag, &context);
+    let mut response = complete_task(settings, state, task).await?;
+    if let (Some(diversity_settings), true) = (&settings.diversity, enabled("diversity")) {
+        response = diversify(
+            settings,
+            diversity_settings,
+            state,
+            db_client,
+            task,
+            response,
+        )
+        .await;
+    }
+    if let (Some(dedup), true) = (&state.batch_dedup, enabled("batch_dedup")) {
+        response = dedupe(settings, dedup, state, db_client, task, response).await;
+    }
+    Ok(response)
+}
+
+/// Completion of the task, or of its whole dialogue for a `conversation` task.
+async fn complete_task(
+    settings: &Settings,
+    state: &AppState,
+    task: &Task,
+) -> Result<LLMResponse, Box<dyn std::error::Error + Send + Sync>> {
+    if conversations::is_conversation_task(&task.payload) {
+        return converse(settings, state, task).await;
+    }
+    complete(settings, state, task, &task.payload["body"]).await
+}
+
+/// Generates a `conversation` task's dialogue, one request per reply, each carrying the
+/// messages so far. The last reply is returned with the tokens of every request.
+async fn converse(
+    settings: &Settings,
+    state: &AppState,
+    task: &Task,
+) -> Result<LLMResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let mut conversation = conversations::Conversation::start(&task.payload)?;
+    let mut body = conversation.body();
+    let mut usage = None;
+    let mut started_at = None;
+    loop {
+        let mut response = complete(settings, state, task, &body).await?;
+        usage = combined_usage(usage, response.usage.take());
+        let started_at = *started_at.get_or_insert(response.started_at);
+        let reply = response.content().unwrap_or_default().into_owned();
+        let next = conversation.next_turn(&reply, &state.templates, &task.payload["variables"])?;
+        match next {
+            Some(next) => body = next,
+            None => {
+                response.started_at = started_at;
+                response.usage = usage;
+                response.annotations.extend(conversation.annotations());
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Completion of `body`, repaired until it conforms to the task's output schema.
+async fn complete(
+    settings: &Settings,
+    state: &AppState,
+    task: &Task,
+    body: &Value,
+) -> Result<LLMResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let providers = llm_wrapper::task_providers(&task.payload, &settings.fallback_providers);
+    let providers = state
+        .dispatch_windows
+        .wait(providers, releasable(settings, task))
+        .await?;
+    let providers = &providers;
+    let call = |body: Value| async move {
+        llm_wrapper::route(providers, &body, |provider, body| async move {
+            let legacy_completions::Adapted {
+                url,
+                body,
+                adaptation,
+            } = legacy_completions::adapt(provider, body)?;
+            let body = match provider.api {
+                ProviderApi::Anthropic => anthropic::to_messages(body),
+                ProviderApi::OpenAiCompatible => body,
+            };
+            let target = rate_limit::RateLimitTarget {
+                url: &url,
+                model: body["model"].as_str().unwrap_or_default(),
+                api_key: &provider.api_key,
+            };
+            let estimated_tokens = rate_limit::estimate_tokens(&body);
+            let _slots = state.concurrency.acquire(&target).await;
+            state
+                .rate_limiter
+                .acquire_for(&target, &task.attribution, estimated_tokens)
+                .await;
+            let mut response = llm_wrapper::call_llm(
+                &state.llm_client,
+                &url,
+                &body,
+                &provider.api_key,
+                provider.api,
+                &settings.site_url,
+                &settings.site_name,
+                settings.retry_attempts,
+                settings.base_delay_ms,
+                settings.max_delay_secs,
+                &settings.empty_completion,
+            )
+            .await?;
+            if let Some(usage) = &response.usage {
+                state
+                    .rate_limiter
+                    .record_tokens_for(
+                        &target,
+                        &task.attribution,
+                        estimated_tokens,
+                        usage.total_tokens,
+                    )
+                    .await;
+            }
+            if let Some(adaptation) = adaptation {
+                response
+                    .annotations
+                    .insert("legacy_payload".to_string(), Value::from(adaptation));
+            }
+            Ok(response)
+        })
+        .await
+    };
+
+    let mut response = call(body.clone()).await?;
+    let Some(schema) = task.payload.get("output_schema").filter(|s| !s.is_null()) else {
+        return Ok(response);
+    };
+
+    // Non-conforming completions are sent back with the violations, keeping the
+    // conversation so the model fixes its own answer.
+    let repair_attempts = if state
+        .feature_flags
+        .is_enabled("schema_repair", &task.flag_context())
+    {
+        settings.validation_repair_attempts
+    } else {
+        0
+    };
+    let mut repairs = 0;
+    let errors = loop {
+        let content = response.content().unwrap_or_default().into_owned();
+        let errors = match validation::validate(schema, &content) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors,
+        };
+        if errors.is_empty() || repairs >= repair_attempts {
+            break errors;
+        }
+        let Some(repair) = validation::repair_body(body, &content, schema, &errors) else {
+            break errors;
+        };
+        repairs += 1;
+        info!(
+            "Completion of message {} violates its output schema, repair {}/{}",
+            task.message_id, repairs, repair_attempts
+        );
+        let mut repaired = call(repair).await?;
+        repaired.started_at = response.started_at;
+        repaired.usage = combined_usage(response.usage.take(), repaired.usage);
+        response = repaired;
+    };
+
+    annotate_validation(&mut response, errors, repairs);
+    Ok(response)
+}
+
+fn combined_usage(a: Option<Usage>, b: Option<Usage>) -> Option<Usage> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(Usage {
+            prompt_tokens: a.prompt_tokens + b.prompt_tokens,
+            completion_tokens: a.completion_tokens + b.completion_tokens,
+            total_tokens: a.total_tokens + b.total_tokens,
+        }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Completes the task again while its samples are near duplicates of each other or of
+/// earlier samples of its prompt, up to `regenerate_attempts` times, and annotates the
+/// least similar completion with the diversity of its prompt group. Every attempt's
+/// tokens are counted.
+async fn diversify(
+    settings: &Settings,
+    diversity_settings: &DiversitySettings,
+    state: &AppState,
+    db_client: &dyn db::TaskStore,
+    task: &Task,
+    mut response: LLMResponse,
+) -> LLMResponse {
+    let earlier: Vec<String> = match db_client
+        .cached_samples(&task.body_hash, diversity_settings.group_samples)
+        .await
+    {
+        Ok(samples) => samples
+            .iter()
+            .flat_map(|sample| diversity::sample_texts(&sample.completions))
+            .collect(),
+        Err(e) => {
+            warn!(
+                "Failed to load earlier samples of message {}: {}",
+                task.message_id, e
+            );
+            Vec::new()
+        }
+    };
+    let assess = |response: &LLMResponse| {
+        let samples = diversity::sample_texts(&response.completions);
+        diversity::assess(diversity_settings, &earlier, &samples)
+    };
+
+    let mut best = assess(&response);
+    let mut regenerations = 0;
+    while best.near_duplicates > 0 && regenerations < diversity_settings.regenerate_attempts {
+        regenerations += 1;
+        info!(
+            "Samples of message {} are near duplicates, regeneration {}/{}",
+            task.message_id, regenerations, diversity_settings.regenerate_attempts
+        );
+        let mut regenerated = match complete_task(settings, state, task).await {
+            Ok(regenerated) => regenerated,
+            Err(e) => {
+                warn!(
+                    "Failed to regenerate message {}, keeping its samples: {}",
+                    task.message_id, e
+                );
+                break;
+            }
+        };
+        let usage = combined_usage(response.usage.take(), regenerated.usage.take());
+        let assessed = assess(&regenerated);
+        if assessed.max_similarity < best.max_similarity {
+            regenerated.started_at = response.started_at;
+            response = regenerated;
+            best = assessed;
+        }
+        response.usage = usage;
+    }
+
+    response.annotations.extend(best.annotations());
+    response.annotations.insert(
+        "diversity_regenerations".to_string(),
+        Value::from(regenerations),
+    );
+    response
+}
+
+/// Completes the task again while its completion is a near duplicate of an earlier one
+/// of its batch, up to `regenerate_attempts` times, and records the least similar
+/// completion in the batch's index with its cluster. Every attempt's tokens are counted.
+async fn dedupe(
+    settings: &Settings,
+    dedup: &BatchDedup,
+    state: &AppState,
+    db_client: &dyn db::TaskStore,
+    task: &Task,
+    mut response: LLMResponse,
+) -> LLMResponse {
+    dedup.seed(db_client, &task.batch_id).await;
+    let assess = |response: &LLMResponse| {
+        let completion = response.content().unwrap_or_default();
+        dedup.assess(&task.batch_id, &task.message_id, &completion)
+    };
+
+    let mut best = assess(&response);
+    let mut regenerations = 0;
+    while best.duplicate && regenerations < dedup.regenerate_attempts() {
+        regenerations += 1;
+        info!(
+            "Completion of message {} is a near duplicate in batch {}, regeneration {}/{}",
+            task.message_id,
+            task.batch_id,
+            regenerations,
+            dedup.regenerate_attempts()
+        );
+        let mut regenerated = match complete_task(settings, state, task).await {
+            Ok(regenerated) => regenerated,
+            Err(e) => {
+                warn!(
+                    "Failed to regenerate message {}, keeping its completion: {}",
+                    task.message_id, e
+                );
+                break;
+            }
+        };
+        let usage = combined_usage(response.usage.take(), regenerated.usage.take());
+        let assessed = assess(&regenerated);
+        if assessed.similarity < best.similarity {
+            regenerated.started_at = response.started_at;
+            response = regenerated;
+            best = assessed;
+        }
+        response.usage = usage;
+    }
+
+    dedup.record(&task.batch_id, &task.message_id, &best);
+    response.annotations.extend(best.annotations());
+    response.annotations.insert(
+        "batch_dedup_regenerations".to_string(),
+        Value::from(regenerations),
+    );
+    response
+}
+
+/// Embeds the inputs of an `embedding` task at the embeddings endpoint of its providers.
+async fn embed(
+    settings: &Settings,
+    state: &AppState,
+    task: &Task,
+) -> Result<LLMResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let providers = llm_wrapper::task_providers(&task.payload, &settings.fallback_providers);
+    let providers = state
+        .dispatch_windows
+        .wait(providers, releasable(settings, task))
+        .await?;
+    let body = &task.payload["body"];
+    llm_wrapper::route(&providers, body, |provider, body| async move {
+        if provider.api == ProviderApi::Anthropic {
+            return Err("The Messages API has no embeddings endpoint".into());
+        }
+        let url = embedding_tasks::embeddings_url(&provider.url);
+        let target = rate_limit::RateLimitTarget {
+            url: &url,
+            model: body["model"].as_str().unwrap_or_default(),
+            api_key: &provider.api_key,
+        };
+        let estimated_tokens = rate_limit::estimate_tokens(&body);
+        let _slots = state.concurrency.acquire(&target).await;
+        state
+            .rate_limiter
+            .acquire_for(&target, &task.attribution, estimated_tokens)
+            .await;
+        let mut response = llm_wrapper::call_llm(
+            &state.llm_client,
+            &url,
+            &body,
+            &provider.api_key,
+            provider.api,
+            &settings.site_url,
+            &settings.site_name,
+            settings.retry_attempts,
+            settings.base_delay_ms,
+            settings.max_delay_secs,
+            &settings.empty_completion,
+        )
+        .await?;
+        embedding_tasks::vectors(&response.completions)?;
+        response.usage = embedding_tasks::usage(&response.completions);
+        if let Some(usage) = &response.usage {
+            state
+                .rate_limiter
+                .record_tokens_for(
+                    &target,
+                    &task.attribution,
+                    estimated_tokens,
+                    usage.total_tokens,
+                )
+                .await;
+        }
+        Ok(response)
+    })
+    .await
+}
+
+fn annotate_validation(response: &mut LLMResponse, errors: Vec<String>, repairs: u32) {
+    response
+        .annotations
+        .insert("schema_valid".to_string(), Value::from(errors.is_empty()));
+    response
+        .annotations
+        .insert("validation_errors".to_string(), Value::from(errors));
+    response
+        .annotations
+        .insert("repair_attempts".to_string(), Value::from(repairs));
+}
+
+/// Runs the enabled annotation stages over a fresh completion.
+pub async fn postprocess(
+    settings: &Settings,
+    state: &AppState,
+    db_client: &dyn db::TaskStore,
+    llm_client: &llm_wrapper::LLMClient,
+    task: &Task,
+    response: &mut LLMResponse,
+) {
+    let message_id = &task.message_id;
+    let body = &task.payload["body"];
+    let context = task.flag_context();
+    let enabled = |flag: &str| state.feature_flags.is_enabled(flag, &context);
+
+    if let Some(usage) = &response.usage {
+        // The requested model is what prices and energy factors are configured for; the
+        // reported one can be a dated snapshot of it.
+        let models = [body["model"].as_str(), response.completions["model"].as_str()];
+        response.cost = state.prices.cost(models.into_iter().flatten(), usage);
+        if let Some(footprint) = state.energy.footprint(models.into_iter().flatten(), usage) {
+            response.annotations.extend(footprint.annotations());
+        }
+    }
+
+    // Vectors have no text for the other stages to look at.
+    if embedding_tasks::is_embedding_task(&task.payload) {
+        store_embeddings(settings, db_client, task, response).await;
+        return;
+    }
+
+    // Before the other stages, so they only see sanitized text.
+    if let (Some(filter), true) = (&state.content_filter, enabled("content_filter")) {
+        let annotations = filter.apply(llm_client, &mut response.completions).await;
+        response.annotations.extend(annotations);
+    }
+
+    let spec = &task.payload["evaluation"];
+    if !spec.is_null() {
+        let completion = response.content().unwrap_or_default().to_string();
+        let mut evaluation = evaluation::evaluate(
+            llm_client,
+            settings.evaluation_judge.as_ref(),
+            spec,
+            &text::prompt_text(body),
+            &completion,
+        )
+        .await;
+        // Ranked under the requested model, which the suite's runs are compared by.
+        if let Some(model) = body["model"]
+            .as_str()
+            .or(response.completions["model"].as_str())
+        {
+            evaluation["model"] = Value::from(model);
+        }
+        response
+            .annotations
+            .insert("evaluation".to_string(), evaluation);
+    }
+
+    if let (Some(difficulty_settings), true) = (&settings.difficulty, enabled("difficulty")) {
+        let completion = response.content().unwrap_or_default().to_string();
+        let annotations = difficulty::tag(llm_client, difficulty_settings, body, &completion).await;
+        response.annotations.extend(annotations);
+    }
+
+    if let (Some(labeling_settings), true) = (&settings.labeling, enabled("labeling")) {
+        let completion = response.content().unwrap_or_default().to_string();
+        match labeling::label(llm_client, labeling_settings, body, &completion).await {
+            Ok(labels) => {
+                response
+                    .annotations
+                    .insert("topic_labels".to_string(), serde_json::json!(labels));
+            }
+            Err(e) => error!("Topic labeling failed for message {}: {}", message_id, e),
+        }
+    }
+
+    if let (Some(judge_settings), true) = (&settings.quality_judge, enabled("judge")) {
+        let completion = response.content().unwrap_or_default().to_string();
+        let annotations = judge::score(llm_client, judge_settings, body, &completion).await;
+        response.annotations.extend(annotations);
+    }
+
+    #[cfg(feature = "candle")]
+    if let (Some(annotators), true) = (&state.local_annotators, enabled("local_models")) {
+        let completion = response.content().unwrap_or_default().to_string();
+        match annotators.annotate(&completion).await {
+            Ok(annotations) => response.annotations.extend(annotations),
+            Err(e) => error!(
+                "Local model stages failed for message {}: {}",
+                message_id, e
+            ),
+        }
+    }
+
+    if let (Some(checker), true) = (&state.contamination, enabled("contamination")) {
+        let annotations = checker.check(body, &response.content().unwrap_or_default());
+        response.annotations.extend(annotations);
+    }
+
+    if let (Some(corpus), true) = (&state.corpus, enabled("corpus_dedup")) {
+        let annotations = corpus.check(&response.content().unwrap_or_default());
+        response.annotations.extend(annotations);
+    }
+
+    if let (Some(embedder), true) = (
+        &state.embedder,
+        settings.index_completion_embeddings && enabled("completion_embeddings"),
+    ) {
+        let completion = response.content().unwrap_or_default().to_string();
+        match embedder.embed(&completion).await {
+            Ok(vector) => {
+                response.annotations.insert(
+                    "completion_embedding".to_string(),
+                    serde_json::json!(vector),
+                );
+            }
+            Err(e) => error!(
+                "Failed to embed completion for message {}: {}",
+                message_id, e
+            ),
+        }
+    }
+
+    if let (Some(tracker), true) = (&state.balance, enabled("balance")) {
+        let label = response
+            .annotations
+            .get("topic_labels")
+            .and_then(|labels| labels.get(0))
+            .or_else(|| task.payload.get("label"))
+            .and_then(|l| l.as_str())
+            .map(str::to_string);
+        if let Some(label) = label {
+            let admitted = tracker.admit(db_client, &task.batch_id, &label).await;
+            response
+                .annotations
+                .insert("balance_label".to_string(), serde_json::json!(label));
+            response
+                .annotations
+                .insert("balance_excess".to_string(), serde_json::json!(!admitted));
+        }
+    }
+
+    let rejections = quality_gates::rejection_reasons(&response.annotations, settings.min_quality_score);
+    response
+        .annotations
+        .insert("accepted".to_string(), Value::from(rejections.is_empty()));
+    response
+        .annotations
+        .insert("rejection_reasons".to_string(), serde_json::json!(rejections));
+
+    // Last, so every stage saw the whole completion.
+    let truncation = llm_client.truncation();
+    if let Some(truncated) = truncation.completions(&mut response.completions).await {
+        response
+            .annotations
+            .insert(truncation::TRUNCATED_ANNOTATION.to_string(), truncated);
+    }
+}
+
+/// Writes the vectors of an `embedding` task to the configured vector index, or keeps
+/// them on its event when there's none or the write fails.
+async fn store_embeddings(
+    settings: &Settings,
+    db_client: &dyn db::TaskStore,
+    task: &Task,
+    response: &mut LLMResponse,
+) {
+    let body = &task.payload["body"];
+    let mut index = settings.embedding_task_index.as_deref();
+    if let Some(name) = index {
+        let written = match embedding_tasks::vector_documents(
+            &task.message_id,
+            &task.batch_id,
+            body,
+            &response.completions,
+        ) {
+            Ok(documents) => db_client.index_embeddings(name, &documents).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = written {
+            error!(
+                "Failed to index the vectors of message {} in {}: {}",
+                task.message_id, name, e
+            );
+            index = None;
+        }
+    }
+    match embedding_tasks::annotations(body, &response.completions, index) {
+        Ok(annotations) => response.annotations.extend(annotations),
+        Err(e) => error!(
+            "Failed to read the vectors of message {}: {}",
+            task.message_id, e
+        ),
+    }
+}
+
+/// Ack policy of the task, from its `task_type` payload field.
+fn ack_policy(settings: &Settings, task: &Task) -> AckPolicy {
+    task.payload["task_type"]
+        .as_str()
+        .and_then(|task_type| settings.ack.by_task_type.get(task_type).copied())
+        .unwrap_or(settings.ack.default)
+}
+
+/// Acks the delivery of a cache miss under `AckPolicy::BeforeLlm`. The task then
+/// carries no delivery, so later stages only record its outcome.
+async fn ack_before_llm(settings: &Settings, task: &mut Task) {
+    if ack_policy(settings, task) != AckPolicy::BeforeLlm {
+        return;
+    }
+    if let Some(delivery) = task.delivery.take() {
+        if let Err(ack_err) = delivery.ack().await {
+            error!("Failed to acknowledge message: {}", ack_err);
+        }
+    }
+}
+
+/// Deadline of the task: `timeout_secs` from its payload, else `TASK_TIMEOUT_SECS`,
+/// counted from when it was decoded, or the run's `sla_deadline` (RFC 3339) when that
+/// comes first. `None` when timeouts are disabled and the run has no SLA.
+fn deadline(settings: &Settings, task: &Task) -> Option<Instant> {
+    let timeout_secs = task.payload["timeout_secs"]
+        .as_u64()
+        .unwrap_or(settings.task_timeout_secs);
+    let timeout = (timeout_secs > 0).then(|| {
+        let elapsed = (Utc::now() - task.processing_started_at)
+            .to_std()
+            .unwrap_or_default();
+        Instant::now() + Duration::from_secs(timeout_secs).saturating_sub(elapsed)
+    });
+    let sla = task.payload["sla_deadline"]
+        .as_str()
+        .and_then(|sla| DateTime::parse_from_rfc3339(sla).ok())
+        .map(|sla| {
+            let remaining = (sla.with_timezone(&Utc) - Utc::now())
+                .to_std()
+                .unwrap_or_default();
+            Instant::now() + remaining
+        });
+    match (timeout, sla) {
+        (Some(timeout), Some(sla)) => Some(timeout.min(sla)),
+        (timeout, sla) => timeout.or(sla),
+    }
+}
+
+/// Whether the task's delivery can be handed back to the broker to wait there rather
+/// than in a worker slot. Kafka offsets can't be: a released message stays unsettled
+/// until its partition is reassigned, which holds up the partition's commits.
+fn releasable(settings: &Settings, task: &Task) -> bool {
+    task.delivery.is_some() && settings.broker.kind == BrokerKind::RabbitMq
+}
+
+/// Runs `work` until the task's deadline, dropping it (and whatever request it has
+/// in flight) when the deadline passes first. LLM calls in `work` stop retrying once
+/// the next retry can't start before the deadline, and defer long rate limits to the
+/// broker when enabled for a delivered task.
+async fn before_deadline<F: Future>(
+    settings: &Settings,
+    task: &Task,
+    work: F,
+) -> Option<F::Output> {
+    let defer_after = settings
+        .broker
+        .defer_after_ms
+        .filter(|_| releasable(settings, task))
+        .map(Duration::from_millis);
+    let work = llm_wrapper::with_rate_limit_deferral(defer_after, work);
+    match deadline(settings, task) {
+        Some(deadline) => {
+            let work = llm_wrapper::with_deadline(deadline, work);
+            match tokio::time::timeout_at(deadline, work).await {
+                Ok(output) => Some(output),
+                Err(_) => {
+                    error!("Message {} timed out", task.message_id);
+                    None
+                }
+            }
+        }
+        None => Some(work.await),
+    }
+}
+
+/// Requeues a delivery whose processing failed for a retryable reason, or
+/// dead-letters it once it ran out of attempts.
+async fn retry_or_dead_letter(delivery: &BrokerMessage, max_delivery_attempts: u32, reason: &str) {
+    if delivery.delivery_attempt() >= max_delivery_attempts {
+        if let Err(dlq_err) = delivery.dead_letter(reason).await {
+            error!("Failed to dead-letter message: {}", dlq_err);
+        }
+    } else if let Err(reject_err) = delivery.requeue().await {
+        error!("Failed to requeue message: {}", reject_err);
+    }
+}
+
+/// Writes the final status and settles the delivery. Completed rows count towards
+/// their label's balance only once written.
+pub async fn persist(
+    db_client: &dyn db::TaskStore,
+    state: &AppState,
+    max_delivery_attempts: u32,
+    task: Task,
+    outcome: Outcome,
+) {
+    let Task {
+        delivery,
+        message_id,
+        batch_id,
+        body_hash,
+        payload,
+        processing_started_at,
+        trace,
+        ..
+    } = task;
+    let event = db::EventKey {
+        message_id: &message_id,
+        batch_id: &batch_id,
+        body_hash: &body_hash,
+    };
+
+    match outcome {
+        Outcome::Completed(mut response) => {
+            if let Some(trace) = trace.and_then(|trace| trace.finish(false)) {
+                response
+                    .annotations
+                    .insert("debug_trace".to_string(), trace);
+            }
+            match db_client
+                .update_event_status(
+                    &event,
+                    schemas::task_status::TaskStatus::Completed,
+                    &response,
+                    processing_started_at,
+                )
+                .instrument(info_span!("update_event_status", status = "COMPLETED"))
+                .await
+            {
+                Ok(_) => {
+                    if let (Some(tracker), Some(label)) = (
+                        &state.balance,
+                        response
+                            .annotations
+                            .get("balance_label")
+                            .and_then(Value::as_str),
+                    ) {
+                        if response.annotations.get("balance_excess") == Some(&Value::Bool(false)) {
+                            tracker.record(&batch_id, label);
+                        }
+                    }
+                    // Use the timing information from the LLMResponse
+                    let llm_duration_ms = response
+                        .completed_at
+                        .signed_duration_since(response.started_at)
+                        .num_milliseconds();
+
+                    let total_duration_ms = Utc::now()
+                        .signed_duration_since(processing_started_at)
+                        .num_milliseconds();
+
+                    info!(
+                        "Successfully completed message {} - LLM call: {}ms, Total processing: {}ms",
+                        message_id,
+                        llm_duration_ms,
+                        total_duration_ms
+                    );
+                    notify_callback(
+                        &state.webhooks,
+                        &payload,
+                        serde_json::json!({
+                            "event": "task.completed",
+                            "message_id": message_id,
+                            "batch_id": batch_id,
+                            "status": "COMPLETED",
+                            "cached": response.cached,
+                            "duration_ms": total_duration_ms,
+                            "usage": response.usage,
+                            "cost": response.cost,
+                        }),
+                    );
+
+                    // Acknowledge successful processing
+                    if let Some(delivery) = &delivery {
+                        if let Err(ack_err) = delivery.ack().await {
+                            error!("Failed to acknowledge message: {}", ack_err);
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to update status to COMPLETED: {}", e);
+                    // Requeue the message if database update fails, until it runs out
+                    // of attempts
+                    match &delivery {
+                        Some(delivery) => {
+                            retry_or_dead_letter(
+                                delivery,
+                                max_delivery_attempts,
+                                &format!("Failed to persist completion: {}", e),
+                            )
+                            .await
+                        }
+                        None => error!("Completion of message {} is lost", message_id),
+                    }
+                }
+            }
+        }
+        Outcome::Held(reason) => {
+            info!(
+                "Handing message {} back to the broker: {}",
+                message_id, reason
+            );
+            if let Some(delivery) = &delivery {
+                if let Err(release_err) = delivery.release().await {
+                    error!("Failed to release held message: {}", release_err);
+                }
+            }
+        }
+        Outcome::Deferred(reason, delay) => {
+            info!(
+                "Deferring message {} for {}s: {}",
+                message_id,
+                delay.as_secs(),
+                reason
+            );
+            if let Some(delivery) = &delivery {
+                if let Err(defer_err) = delivery.defer(delay).await {
+                    error!("Failed to defer message: {}", defer_err);
+                    retry_or_dead_letter(
+                        delivery,
+                        max_delivery_attempts,
+                        &format!("Failed to defer a rate-limited task: {}", defer_err),
+                    )
+                    .await;
+                }
+            }
+        }
+        // A hung call may not hang again, so a timed-out task is retried until it runs
+        // out of attempts, then fails like the others
+        Outcome::TimedOut
+            if delivery
+                .as_ref()
+                .is_some_and(|delivery| delivery.delivery_attempt() < max_delivery_attempts) =>
+        {
+            info!("Requeueing message {} after it timed out", message_id);
+            if let Some(delivery) = &delivery {
+                if let Err(reject_err) = delivery.requeue().await {
+                    error!("Failed to requeue message: {}", reject_err);
+                }
+            }
+        }
+        Outcome::Failed(_)
+        | Outcome::TimedOut
+        | Outcome::DeadlineUnreachable(_)
+        | Outcome::Rejected(_)
+        | Outcome::InvalidTemplate(_)
+        | Outcome::Quarantined(_)
+        | Outcome::Expired(_) => {
+            let (error, reason) = match outcome {
+                Outcome::Failed(error) => (error, "llm_error"),
+                Outcome::DeadlineUnreachable(error) => (error, "deadline_unreachable"),
+                Outcome::Rejected(error) => (error, "schema_violation"),
+                Outcome::InvalidTemplate(error) => (error, "template_error"),
+                Outcome::Quarantined(error) => (error, "quarantined"),
+                Outcome::Expired(error) => (error, "expired"),
+                _ => ("Task exceeded its deadline".to_string(), "timeout"),
+            };
+            error!("Message {} failed: {}", message_id, error);
+            let now = Utc::now();
+            let mut annotations: serde_json::Map<String, Value> =
+                [("failure_reason".to_string(), serde_json::json!(reason))]
+                    .into_iter()
+                    .collect();
+            if let Some(trace) = trace.and_then(|trace| trace.finish(true)) {
+                annotations.insert("debug_trace".to_string(), trace);
+            }
+            if let Err(db_err) = db_client
+                .update_event_status(
+                    &event,
+                    schemas::task_status::TaskStatus::Failed,
+                    &LLMResponse {
+                        completions: serde_json::json!({ "error": error }),
+                        cached: false,
+                        attempt: 0,
+                        started_at: processing_started_at,
+                        completed_at: now,
+                        annotations,
+                        usage: None,
+                        cost: None,
+                    },
+                    processing_started_at,
+                )
+                .instrument(info_span!("update_event_status", status = "FAILED"))
+                .await
+            {
+                error!("Failed to update status to FAILED: {}", db_err);
+            }
+            notify_callback(
+                &state.webhooks,
+                &payload,
+                serde_json::json!({
+                    "event": "task.failed",
+                    "message_id": message_id,
+                    "batch_id": batch_id,
+                    "status": "FAILED",
+                    "failure_reason": reason,
+                    "error": error,
+                    "duration_ms": now.signed_duration_since(processing_started_at).num_milliseconds(),
+                    "usage": null,
+                }),
+            );
+            // LLM failures that survived the in-process retries and rejected or
+            // quarantined payloads go to the dead-letter queue; a redelivery would only
+            // repeat them
+            if let Some(delivery) = &delivery {
+                if let Err(dlq_err) = delivery.dead_letter(&error).await {
+                    error!("Failed to dead-letter failed message: {}", dlq_err);
+                }
+            }
+        }
+    }
+}
+
+/// Posts how the task ended to its `callback_url`, if it has one.
+fn notify_callback(webhooks: &Webhooks, payload: &Value, mut event: Value) {
+    if let Some(url) = payload["callback_url"].as_str() {
+        event["custom_id"] = payload["custom_id"].clone();
+        webhooks.send(url, event);
+    }
+}
+
+/// Batch API key for the task, when batching is enabled for it.
+fn batch_key(settings: &Settings, task: &Task) -> Option<BatchKey> {
+    let batch_api = settings.batch_api.as_ref()?;
+    let payload = &task.payload;
+    // Batches are only submitted to chat completions endpoints.
+    if embedding_tasks::is_embedding_task(payload)
+        || !payload["use_batch_api"]
+            .as_bool()
+            .unwrap_or(batch_api.default_enabled)
+    {
+        return None;
+    }
+    BatchKey::for_task(
+        payload["url"].as_str()?,
+        payload["api_key"].as_str().unwrap_or_default(),
+        &payload["body"],
+    )
+}
+
+/// Hands the task to the batcher when it should go through the batch API; gives it back
+/// otherwise.
+async fn offer_to_batcher(
+    settings: &Settings,
+    batcher: Option<&mpsc::Sender<(BatchKey, Task)>>,
+    mut task: Task,
+) -> Option<Task> {
+    let (Some(batcher), Some(key)) = (batcher, batch_key(settings, &task)) else {
+        return Some(task);
+    };
+    // The delivery stays unacked until the provider accepts the batch, but it no
+    // longer occupies a pipeline slot.
+    task.permit = None;
+    match batcher.send((key, task)).await {
+        Ok(()) => None,
+        Err(mpsc::error::SendError((_, task))) => Some(task),
+    }
+}
+
+/// Collects batchable tasks per key and submits them once a group is full or has waited
+/// `max_wait_secs`. Each submitted batch is then polled by its own task.
+pub fn spawn_batcher(
+    settings: Arc<Settings>,
+    state: Arc<AppState>,
+    db_client: Arc<dyn db::TaskStore>,
+    batch_api: &BatchApiSettings,
+) -> mpsc::Sender<(BatchKey, Task)> {
+    let (sender, mut receiver) = mpsc::channel::<(BatchKey, Task)>(batch_api.max_batch_size.max(1));
+    let max_batch_size = batch_api.max_batch_size.max(1);
+    let max_wait = std::time::Duration::from_secs(batch_api.max_wait_secs);
+
+    tokio::spawn(async move {
+        let mut groups: HashMap<BatchKey, (Instant, Vec<Task>)> = HashMap::new();
+        let mut tick = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                item = receiver.recv() => {
+                    let Some((key, task)) = item else { break };
+                    let group = groups.entry(key.clone()).or_insert_with(|| (Instant::now(), Vec::new()));
+                    group.1.push(task);
+                    if group.1.len() >= max_batch_size {
+                        if let Some((_, tasks)) = groups.remove(&key) {
+                            tokio::spawn(run_batch(settings.clone(), state.clone(), db_client.clone(), key, tasks));
+                        }
+                    }
+                }
+                _ = tick.tick() => {
+                    let due: Vec<BatchKey> = groups
+                        .iter()
+                        .filter(|(_, (opened, _))| opened.elapsed() >= max_wait)
+                        .map(|(key, _)| key.clone())
+                        .collect();
+                    for key in due {
+                        if let Some((_, tasks)) = groups.remove(&key) {
+                            tokio::spawn(run_batch(settings.clone(), state.clone(), db_client.clone(), key, tasks));
+                        }
+                    }
+                }
+            }
+        }
+        // Tasks still grouped hold unacked deliveries, which the broker redelivers.
+    });
+
+    sender
+}
+
+/// Submits one batch, settles its deliveries, then polls until the provider finishes
+/// and fans the results out to the individual events documents.
+async fn run_batch(
+    settings: Arc<Settings>,
+    state: Arc<AppState>,
+    db_client: Arc<dyn db::TaskStore>,
+    key: BatchKey,
+    mut tasks: Vec<Task>,
+) {
+    let Some(batch_api) = settings.batch_api.as_ref() else {
+        return;
+    };
+    let max_delivery_attempts = settings.broker.max_delivery_attempts;
+    let client = BatchApiClient::new(state.llm_client.clone(), &key);
+
+    let mut input = Vec::new();
+    for task in &tasks {
+        if let Err(e) = batching::write_request_line(
+            &mut input,
+            &task.message_id,
+            &key.endpoint,
+            &task.payload["body"],
+        ) {
+            error!(
+                "Failed to serialize batch request for {}: {}",
+                task.message_id, e
+            );
+        }
+    }
+
+    let submitted = match client.upload(input).await {
+        Ok(file_id) => {
+            client
+                .create(&file_id, &key.endpoint, &batch_api.completion_window)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    let batch = match submitted {
+        Ok(batch) => batch,
+        Err(e) => {
+            error!(
+                "Failed to submit batch of {} tasks to {}: {}",
+                tasks.len(),
+                key.base_url,
+                e
+            );
+            let reason = format!("Batch submission failed: {}", e);
+            for task in &tasks {
+                if let Some(delivery) = &task.delivery {
+                    retry_or_dead_letter(delivery, max_delivery_attempts, &reason).await;
+                }
+            }
+            return;
+        }
+    };
+    let submitted_at = Utc::now();
+    info!(
+        "Submitted batch {} with {} tasks for model {}",
+        batch.id,
+        tasks.len(),
+        key.model
+    );
+
+    // The provider owns the requests now; record where each task went and settle it.
+    for task in &mut tasks {
+        if let Err(e) = db_client
+            .update_event_status(
+                &task.event_key(),
+                schemas::task_status::TaskStatus::Processing,
+                &LLMResponse {
+                    completions: Value::Null,
+                    cached: false,
+                    attempt: 0,
+                    started_at: task.processing_started_at,
+                    completed_at: task.processing_started_at,
+                    annotations: [(
+                        "provider_batch_id".to_string(),
+                        Value::from(batch.id.as_str()),
+                    )]
+                    .into_iter()
+                    .collect(),
+                    usage: None,
+                    cost: None,
+                },
+                task.processing_started_at,
+            )
+            .await
+        {
+            error!(
+                "Failed to record batch for message {}: {}",
+                task.message_id, e
+            );
+        }
+        if let Some(delivery) = task.delivery.take() {
+            if let Err(ack_err) = delivery.ack().await {
+                error!("Failed to acknowledge batched message: {}", ack_err);
+            }
+        }
+    }
+
+    let poll_interval = std::time::Duration::from_secs(batch_api.poll_interval_secs.max(1));
+    let batch = loop {
+        tokio::time::sleep(poll_interval).await;
+        match client.status(&batch.id).await {
+            Ok(status) if status.is_terminal() => break status,
+            Ok(status) => tracing::debug!(
+                "Batch {} is {} ({})",
+                status.id,
+                status.status,
+                status.request_counts
+            ),
+            Err(e) => error!("Failed to poll batch {}: {}", batch.id, e),
+        }
+    };
+    info!("Batch {} finished as {}", batch.id, batch.status);
+
+    let mut results = HashMap::new();
+    for file_id in [&batch.output_file_id, &batch.error_file_id]
+        .into_iter()
+        .flatten()
+    {
+        match client.download(file_id).await {
+            Ok(contents) => {
+                for result in batching::parse_results(&contents) {
+                    results.insert(result.custom_id, result.outcome);
+                }
+            }
+            Err(e) => error!(
+                "Failed to download file {} of batch {}: {}",
+                file_id, batch.id, e
+            ),
+        }
+    }
+
+    // Fan out with the same parallelism as the postprocess stage.
+    let completed_at = Utc::now();
+    let limit = Arc::new(Semaphore::new(settings.pipeline.postprocess_workers.max(1)));
+    for task in tasks {
+        let outcome = results.remove(&task.message_id).unwrap_or_else(|| {
+            Err(format!(
+                "Batch {} ended as {} without a result",
+                batch.id, batch.status
+            ))
+        });
+        let Ok(permit) = limit.clone().acquire_owned().await else {
+            break;
+        };
+        let settings = settings.clone();
+        let state = state.clone();
+        let db_client = db_client.clone();
+        let span = task.span.clone();
+        tokio::spawn(
+            async move {
+                let outcome = match outcome {
+                    Ok(completions) => {
+                        let mut response = LLMResponse {
+                            usage: llm_wrapper::parse_usage(&completions),
+                            completions,
+                            cached: false,
+                            attempt: 0,
+                            started_at: submitted_at,
+                            completed_at,
+                            annotations: Default::default(),
+                            cost: None,
+                        };
+                        // Batch results can't be repaired in place; violations are only
+                        // recorded.
+                        if let Some(schema) = task.payload.get("output_schema").filter(|s| !s.is_null()) {
+                            let content = response.content().unwrap_or_default().into_owned();
+                            let errors = validation::validate(schema, &content).err().unwrap_or_default();
+                            annotate_validation(&mut response, errors, 0);
+                        }
+                        postprocess(
+                            &settings,
+                            &state,
+                            db_client.as_ref(),
+                            &state.llm_client,
+                            &task,
+                            &mut response,
+                        )
+                        .await;
+                        Outcome::Completed(response)
+                    }
+                    Err(e) => Outcome::Failed(e),
+                };
+                persist(
+                    db_client.as_ref(),
+                    &state,
+                    max_delivery_attempts,
+                    task,
+                    outcome,
+                )
+                .await;
+                drop(permit);
+            }
+            .instrument(span),
+        );
+    }
+}
+
+pub async fn process_message(
+    settings: Arc<Settings>,
+    state: Arc<AppState>,
+    db_client: Arc<dyn db::TaskStore>,
+    batcher: Option<mpsc::Sender<(BatchKey, Task)>>,
+    delivery: BrokerMessage,
+) {
+    let Some(task) = decode(
+        delivery,
+        settings.broker.max_delivery_attempts,
+        &state.payload_keys,
+        &state.cache_keys,
+    )
+    .await
+    else {
+        return;
+    };
+    let span = task.span.clone();
+    process_task(settings, state, db_client, batcher, task)
+        .instrument(span)
+        .await;
+}
+
+/// Processes a decoded task sequentially, from the cache lookup to the final status.
+async fn process_task(
+    settings: Arc<Settings>,
+    state: Arc<AppState>,
+    db_client: Arc<dyn db::TaskStore>,
+    batcher: Option<mpsc::Sender<(BatchKey, Task)>>,
+    mut task: Task,
+) {
+    if skip_completed(&settings, db_client.as_ref(), &task).await {
+        return;
+    }
+    start_trace(&settings, &state, &mut task);
+
+    let max_delivery_attempts = settings.broker.max_delivery_attempts;
+    if let Some(rejection) = check_staleness(&state, &task)
+        .or_else(|| prepare_body(&settings, &state, &mut task))
+        .or_else(|| check_contract(&state, &task))
+    {
+        persist(
+            db_client.as_ref(),
+            &state,
+            max_delivery_attempts,
+            task,
+            rejection,
+        )
+        .await;
+        return;
+    }
+    match check_cache(&settings, db_client.as_ref(), &task).await {
+        Err(()) => return,
+        Ok(Some(cached_response)) => {
+            persist(
+                db_client.as_ref(),
+                &state,
+                max_delivery_attempts,
+                task,
+                Outcome::Completed(cached_response),
+            )
+            .await;
+            return;
+        }
+        Ok(None) => {}
+    }
+    let checkpoint = resume_checkpoint(&settings, db_client.as_ref(), &task).await;
+    ack_before_llm(&settings, &mut task).await;
+
+    let task = match checkpoint {
+        Some(_) => task,
+        None => match offer_to_batcher(&settings, batcher.as_ref(), task).await {
+            Some(task) => task,
+            None => return,
+        },
+    };
+    let llm_client = &state.llm_client;
+    let work = async {
+        let generated = match checkpoint {
+            Some(response) => Ok(response),
+            None => {
+                let generation = generate(&settings, &state, db_client.as_ref(), &task);
+                let generated = traced(
+                    &task,
+                    llm_wrapper::with_idempotency_key(&task.message_id, generation),
+                )
+                .await;
+                if let Ok(response) = &generated {
+                    save_checkpoint(&settings, db_client.as_ref(), &task, response).await;
+                }
+                generated
+            }
+        };
+        match generated {
+            Ok(mut response) => {
+                postprocess(
+                    &settings,
+                    &state,
+                    db_client.as_ref(),
+                    llm_client,
+                    &task,
+                    &mut response,
+                )
+                .await;
+                Outcome::Completed(response)
+            }
+            Err(e) => Outcome::from_error(e),
+        }
+    };
+    let outcome = before_deadline(&settings, &task, work)
+        .await
+        .unwrap_or(Outcome::TimedOut);
+
+    persist(
+        db_client.as_ref(),
+        &state,
+        max_delivery_attempts,
+        task,
+        outcome,
+    )
+    .await;
+}
+
+/// Pulls items from `receiver` and runs `handler` on each with at most `parallelism`
+/// in flight. Returns once the upstream channel is closed.
+async fn run_stage<I, F, Fut>(parallelism: usize, mut receiver: mpsc::Receiver<I>, handler: F)
+where
+    I: Send + 'static,
+    F: Fn(I) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(parallelism.max(1)));
+    while let Some(item) = receiver.recv().await {
+        let Ok(permit) = semaphore.clone().acquire_owned().await else {
+            break;
+        };
+        let work = handler(item);
+        tokio::spawn(async move {
+            work.await;
+            drop(permit);
+        });
+    }
+}
+
+/// Entry point of the staged pipeline: decoded tasks are pushed into the returned
+/// sender, which applies backpressure once the cache stage is saturated.
+pub fn spawn_staged(
+    settings: Arc<Settings>,
+    state: Arc<AppState>,
+    db_client: Arc<dyn db::TaskStore>,
+    batcher: Option<mpsc::Sender<(BatchKey, Task)>>,
+    pipeline: &PipelineSettings,
+) -> mpsc::Sender<Task> {
+    let capacity = pipeline.channel_capacity.max(1);
+    let (cache_tx, cache_rx) = mpsc::channel::<Task>(capacity);
+    let (llm_tx, llm_rx) = mpsc::channel::<Task>(capacity);
+    let (post_tx, post_rx) = mpsc::channel::<(Task, LLMResponse)>(capacity);
+    let (persist_tx, persist_rx) = mpsc::channel::<(Task, Outcome)>(capacity);
+    let llm_client = state.llm_client.clone();
+    let max_delivery_attempts = settings.broker.max_delivery_attempts;
+
+    {
+        let settings = settings.clone();
+        let state = state.clone();
+        let db_client = db_client.clone();
+        let post_tx = post_tx.clone();
+        let persist_tx = persist_tx.clone();
+        tokio::spawn(run_stage(
+            pipeline.cache_workers,
+            cache_rx,
+            move |mut task: Task| {
+                let settings = settings.clone();
+                let state = state.clone();
+                let db_client = db_client.clone();
+                let llm_tx = llm_tx.clone();
+                let post_tx = post_tx.clone();
+                let persist_tx = persist_tx.clone();
+                let span = task.span.clone();
+                async move {
+                    if skip_completed(&settings, db_client.as_ref(), &task).await {
+                        return;
+                    }
+                    start_trace(&settings, &state, &mut task);
+                    if let Some(rejection) = check_staleness(&state, &task)
+                        .or_else(|| prepare_body(&settings, &state, &mut task))
+                        .or_else(|| check_contract(&state, &task))
+                    {
+                        let _ = persist_tx.send((task, rejection)).await;
+                        return;
+                    }
+                    match check_cache(&settings, db_client.as_ref(), &task).await {
+                        Err(()) => {}
+                        Ok(Some(cached)) => {
+                            let _ = persist_tx.send((task, Outcome::Completed(cached))).await;
+                        }
+                        Ok(None) => {
+                            let checkpoint =
+                                resume_checkpoint(&settings, db_client.as_ref(), &task).await;
+                            ack_before_llm(&settings, &mut task).await;
+                            match checkpoint {
+                                Some(response) => {
+                                    let _ = post_tx.send((task, response)).await;
+                                }
+                                None => {
+                                    let _ = llm_tx.send(task).await;
+                                }
+                            }
+                        }
+                    }
+                }
+                .instrument(span)
+            },
+        ));
+    }
+
+    {
+        let settings = settings.clone();
+        let state = state.clone();
+        let db_client = db_client.clone();
+        let persist_tx = persist_tx.clone();
+        tokio::spawn(run_stage(
+            pipeline.llm_workers,
+            llm_rx,
+            move |task: Task| {
+                let settings = settings.clone();
+                let state = state.clone();
+                let db_client = db_client.clone();
+                let batcher = batcher.clone();
+                let post_tx = post_tx.clone();
+                let persist_tx = persist_tx.clone();
+                let span = task.span.clone();
+                async move {
+                    let Some(task) = offer_to_batcher(&settings, batcher.as_ref(), task).await
+                    else {
+                        return;
+                    };
+                    let generation = generate(&settings, &state, db_client.as_ref(), &task);
+                    let work = traced(
+                        &task,
+                        llm_wrapper::with_idempotency_key(&task.message_id, generation),
+                    );
+                    match before_deadline(&settings, &task, work).await {
+                        Some(Ok(response)) => {
+                            save_checkpoint(&settings, db_client.as_ref(), &task, &response).await;
+                            let _ = post_tx.send((task, response)).await;
+                        }
+                        Some(Err(e)) => {
+                            let _ = persist_tx.send((task, Outcome::from_error(e))).await;
+                        }
+                        None => {
+                            let _ = persist_tx.send((task, Outcome::TimedOut)).await;
+                        }
+                    }
+                }
+                .instrument(span)
+            },
+        ));
+    }
+
+    {
+        let state = state.clone();
+        let db_client = db_client.clone();
+        tokio::spawn(run_stage(
+            pipeline.postprocess_workers,
+            post_rx,
+            move |(task, mut response): (Task, LLMResponse)| {
+                let settings = settings.clone();
+                let state = state.clone();
+                let db_client = db_client.clone();
+                let llm_client = llm_client.clone();
+                let persist_tx = persist_tx.clone();
+                let span = task.span.clone();
+                async move {
+                    let work = postprocess(
+                        &settings,
+                        &state,
+                        db_client.as_ref(),
+                        &llm_client,
+                        &task,
+                        &mut response,
+                    );
+                    let outcome = match before_deadline(&settings, &task, work).await {
+                        Some(()) => Outcome::Completed(response),
+                        None => Outcome::TimedOut,
+                    };
+                    let _ = persist_tx.send((task, outcome)).await;
+                }
+                .instrument(span)
+            },
+        ));
+    }
+
+    tokio::spawn(run_stage(
+        pipeline.persist_workers,
+        persist_rx,
+        move |(task, outcome): (Task, Outcome)| {
+            let db_client = db_client.clone();
+            let state = state.clone();
+            let span = task.span.clone();
+            async move {
+                persist(
+                    db_client.as_ref(),
+                    &state,
+                    max_delivery_attempts,
+                    task,
+                    outcome,
+                )
+                .await
+            }
+            .instrument(span)
+        },
+    ));
+
+    cache_tx
+}
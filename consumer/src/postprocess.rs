@@ -0,0 +1,263 @@
+//! Content-safety filters run over completions before the other post-processing stages,
+//! so those, the events index and the exported dataset only see sanitized text.
+//!
+//! Matches of the enabled PII patterns are replaced by `[REDACTED:<name>]` in every
+//! text of the completion, and the unredacted text is kept in the `raw_completion`
+//! annotation. Blocked terms and a moderation endpoint flagging the sanitized text
+//! leave the completion as is, but make the verdict `flagged`, which the quality gates
+//! reject. The `moderation` annotation holds the verdict (`clean`, `redacted`,
+//! `flagged`, or `unchecked` when the moderation endpoint failed) and what led to it.
+
+use crate::llm_wrapper::LLMClient;
+use crate::schemas::provider_response;
+use crate::settings::{AuxModelSettings, ContentFilterSettings};
+use regex::{Captures, Regex};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// Built-in PII patterns, applied in this order before the configured ones.
+const PII_PATTERNS: &[(&str, &str)] = &[
+    ("credit_card", r"\b(?:\d[ -]?){12,18}\d\b"),
+    ("ssn", r"\b\d{3}-\d{2}-\d{4}\b"),
+    ("email", r"(?i)\b[a-z0-9._%+-]+@[a-z0-9.-]+\.[a-z]{2,}\b"),
+    (
+        "phone",
+        r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{3}\)|\b\d{3})[\s.-]\d{3}[\s.-]\d{4}\b",
+    ),
+    (
+        "ip_address",
+        r"\b(?:(?:25[0-5]|2[0-4]\d|1?\d?\d)\.){3}(?:25[0-5]|2[0-4]\d|1?\d?\d)\b",
+    ),
+];
+
+pub struct ContentFilter {
+    redactions: Vec<(String, Regex)>,
+    blocked_terms: Option<Regex>,
+    moderation: Option<AuxModelSettings>,
+}
+
+impl ContentFilter {
+    pub fn new(settings: &ContentFilterSettings) -> Result<Self, String> {
+        if let Some(kind) = settings
+            .pii
+            .iter()
+            .find(|kind| !PII_PATTERNS.iter().any(|(name, _)| name == kind))
+        {
+            return Err(format!("Unknown PII pattern `{}`", kind));
+        }
+        // Built-in patterns in their own order, then the configured ones by name.
+        let mut patterns: Vec<(&str, &str)> = PII_PATTERNS
+            .iter()
+            .filter(|(name, _)| settings.pii.iter().any(|kind| kind == name))
+            .copied()
+            .collect();
+        let mut configured: Vec<_> = settings.patterns.iter().collect();
+        configured.sort();
+        patterns.extend(
+            configured
+                .into_iter()
+                .map(|(n, p)| (n.as_str(), p.as_str())),
+        );
+        let redactions = patterns
+            .into_iter()
+            .map(|(name, pattern)| {
+                Regex::new(pattern)
+                    .map(|regex| (name.to_string(), regex))
+                    .map_err(|e| format!("Invalid pattern `{}`: {}", name, e))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let blocked_terms = if settings.blocked_terms.is_empty() {
+            None
+        } else {
+            let terms: Vec<String> = settings
+                .blocked_terms
+                .iter()
+                .map(|term| regex::escape(term))
+                .collect();
+            let pattern = format!(r"(?i)\b(?:{})\b", terms.join("|"));
+            Some(Regex::new(&pattern).map_err(|e| format!("Invalid blocked terms: {}", e))?)
+        };
+
+        Ok(Self {
+            redactions,
+            blocked_terms,
+            moderation: settings.moderation.clone(),
+        })
+    }
+
+    /// `text` with the PII it contains redacted, counting the redactions by pattern.
+    pub fn redact(&self, text: &str, counts: &mut BTreeMap<String, u64>) -> String {
+        let mut text = text.to_string();
+        for (name, regex) in &self.redactions {
+            let mut redacted = 0;
+            let replaced = regex.replace_all(&text, |captures: &Captures| {
+                let matched = &captures[0];
+                if name == "credit_card" && !luhn_valid(matched) {
+                    return matched.to_string();
+                }
+                redacted += 1;
+                format!("[REDACTED:{}]", name)
+            });
+            if redacted > 0 {
+                text = replaced.into_owned();
+                *counts.entry(name.clone()).or_default() += redacted;
+            }
+        }
+        text
+    }
+
+    /// Distinct blocked terms found in `text`, lowercased.
+    pub fn blocked_terms(&self, text: &str) -> Vec<String> {
+        let Some(regex) = &self.blocked_terms else {
+            return Vec::new();
+        };
+        let mut found: Vec<String> = regex
+            .find_iter(text)
+            .map(|term| term.as_str().to_lowercase())
+            .collect();
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    /// Redacts `completions` in place and returns the `moderation` annotation, with
+    /// `raw_completion` when anything was redacted.
+    pub async fn apply(&self, client: &LLMClient, completions: &mut Value) -> Map<String, Value> {
+        let raw = provider_response::content(completions).map(|c| c.into_owned());
+        let mut redactions = BTreeMap::new();
+        for_each_text(completions, |text| {
+            *text = self.redact(text, &mut redactions)
+        });
+        let blocked = raw
+            .as_deref()
+            .map(|raw| self.blocked_terms(raw))
+            .unwrap_or_default();
+
+        let mut moderation = json!({ "redactions": redactions, "blocked_terms": blocked });
+        let mut verdict = if !blocked.is_empty() {
+            "flagged"
+        } else if !redactions.is_empty() {
+            "redacted"
+        } else {
+            "clean"
+        };
+        let sanitized = provider_response::content(completions).map(|c| c.into_owned());
+        if let (Some(endpoint), Some(sanitized)) = (&self.moderation, sanitized) {
+            match moderate(client, endpoint, &sanitized).await {
+                Ok((flagged, categories)) => {
+                    if flagged {
+                        verdict = "flagged";
+                    }
+                    moderation["categories"] = json!(categories);
+                }
+                Err(e) => {
+                    tracing::warn!("Moderation request failed: {}", e);
+                    if verdict != "flagged" {
+                        verdict = "unchecked";
+                    }
+                    moderation["error"] = json!(e.to_string());
+                }
+            }
+        }
+        moderation["verdict"] = json!(verdict);
+
+        let mut annotations = Map::new();
+        if let (false, Some(raw)) = (redactions.is_empty(), raw) {
+            annotations.insert("raw_completion".to_string(), Value::from(raw));
+        }
+        annotations.insert("moderation".to_string(), moderation);
+        annotations
+    }
+}
+
+/// Calls `apply` on every text of a response: OpenAI-compatible message contents and
+/// legacy texts, Anthropic text blocks and Gemini text parts, of all choices.
+fn for_each_text(completions: &mut Value, mut apply: impl FnMut(&mut String)) {
+    let mut apply_text = |value: &mut Value, field: &str| {
+        if let Some(Value::String(text)) = value.get_mut(field) {
+            apply(text);
+        }
+    };
+    if let Some(Value::Array(choices)) = completions.get_mut("choices") {
+        for choice in choices {
+            apply_text(choice, "text");
+            if let Some(message) = choice.get_mut("message") {
+                apply_text(message, "content");
+            }
+        }
+    }
+    if let Some(Value::Array(blocks)) = completions.get_mut("content") {
+        for block in blocks.iter_mut().filter(|block| block["type"] == "text") {
+            apply_text(block, "text");
+        }
+    }
+    if let Some(Value::Array(candidates)) = completions.get_mut("candidates") {
+        for candidate in candidates {
+            if let Some(Value::Array(parts)) = candidate
+                .get_mut("content")
+                .and_then(|content| content.get_mut("parts"))
+            {
+                for part in parts {
+                    apply_text(part, "text");
+                }
+            }
+        }
+    }
+}
+
+/// Whether the digits of `number` pass the Luhn check of payment card numbers.
+fn luhn_valid(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2, d * 2) {
+            (0, _) => d,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Whether the moderation endpoint flags `text`, and the categories it flagged.
+async fn moderate(
+    client: &LLMClient,
+    endpoint: &AuxModelSettings,
+    text: &str,
+) -> Result<(bool, Vec<String>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut body = json!({ "input": text });
+    if !endpoint.model.is_empty() {
+        body["model"] = Value::from(endpoint.model.as_str());
+    }
+    let response = client
+        .for_url(&endpoint.url)
+        .post(&endpoint.url)
+        .header("Authorization", format!("Bearer {}", endpoint.api_key))
+        .json(&body)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let error_body = response.text().await.unwrap_or_default();
+        return Err(format!("Moderation request failed ({}): {}", status, error_body).into());
+    }
+    let response = response.json::<Value>().await?;
+    let result = &response["results"][0];
+    let flagged = result["flagged"]
+        .as_bool()
+        .ok_or("Moderation response has no `flagged` verdict")?;
+    let categories = result["categories"]
+        .as_object()
+        .map(|categories| {
+            categories
+                .iter()
+                .filter(|(_, flagged)| **flagged == true)
+                .map(|(category, _)| category.clone())
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((flagged, categories))
+}
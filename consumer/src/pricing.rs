@@ -0,0 +1,50 @@
+//! Per-model token prices, used to attribute a cost to each completion.
+
+use crate::schemas::provider_response::Usage;
+use crate::settings::ModelPrice;
+use std::collections::HashMap;
+
+/// Cost of `usage` at `price`.
+pub fn cost(price: &ModelPrice, usage: &Usage) -> f64 {
+    usage.prompt_tokens as f64 / 1_000_000.0 * price.input_per_1m
+        + usage.completion_tokens as f64 / 1_000_000.0 * price.output_per_1m
+}
+
+/// Entry of `model` in a per-model table: its own, else the longest entry it starts
+/// with, so that `gpt-4o-mini` also covers snapshots such as `gpt-4o-mini-2024-07-18`.
+pub fn model_entry<'a, T>(table: &'a HashMap<String, T>, model: &str) -> Option<&'a T> {
+    table.get(model).or_else(|| {
+        table
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, entry)| entry)
+    })
+}
+
+pub struct PriceTable {
+    prices: HashMap<String, ModelPrice>,
+}
+
+impl PriceTable {
+    pub fn new(prices: HashMap<String, ModelPrice>) -> Self {
+        Self { prices }
+    }
+
+    /// Price of `model`, see `model_entry`.
+    pub fn price(&self, model: &str) -> Option<&ModelPrice> {
+        model_entry(&self.prices, model)
+    }
+
+    /// Cost of `usage` under the first of `models` with a known price.
+    pub fn cost<'a>(
+        &self,
+        models: impl IntoIterator<Item = &'a str>,
+        usage: &Usage,
+    ) -> Option<f64> {
+        models
+            .into_iter()
+            .find_map(|model| self.price(model))
+            .map(|price| cost(price, usage))
+    }
+}
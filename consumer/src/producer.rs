@@ -0,0 +1,274 @@
+//! Task submission, shared by the `synthgen-api` binary: turns submitted tasks into
+//! PENDING event documents and task messages, the way the API's batch worker does.
+
+use crate::attribution::{self, Attribution};
+use crate::cache_key;
+use crate::hashing;
+use crate::payload_encryption;
+use crate::settings::HashAlgorithm;
+use age::x25519::Recipient;
+use chrono::{SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::fmt::Write;
+
+/// Fields of the events of encrypted tasks.
+const ENCRYPTED_EVENT_FIELDS: [&str; 7] = [
+    "message_id",
+    "batch_id",
+    "created_at",
+    "status",
+    "body_hash",
+    "attempt",
+    attribution::FIELD,
+];
+
+/// A validated task, ready to be recorded and published.
+pub struct PreparedTask {
+    pub message_id: String,
+    pub body_hash: String,
+    /// PENDING document of the task's event.
+    pub event: Value,
+    /// Task message, as the consumer decodes it.
+    pub message: Vec<u8>,
+    pub priority: Option<u8>,
+}
+
+/// Hash the cache and deduplication key tasks on: base64 of the SHA-256 of the body
+/// serialized like Python's `json.dumps(body, sort_keys=True, separators=(",", ":"))`,
+/// so hashes match the ones the API computes.
+pub fn body_hash(body: &Value) -> String {
+    body_hash_with(HashAlgorithm::Sha256, body)
+}
+
+/// `body_hash` with another algorithm, see `hashing`.
+pub fn body_hash_with(algorithm: HashAlgorithm, body: &Value) -> String {
+    hashing::key(algorithm, &canonical_json(body))
+}
+
+/// `value` serialized like Python's `json.dumps(value, sort_keys=True,
+/// separators=(",", ":"))`.
+pub fn canonical_json(value: &Value) -> String {
+    let mut canonical = String::new();
+    write_canonical(&mut canonical, value);
+    canonical
+}
+
+fn write_canonical(out: &mut String, value: &Value) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => match n.as_f64().filter(|_| n.is_f64()) {
+            Some(f) => write_python_float(out, f),
+            None => out.push_str(&n.to_string()),
+        },
+        Value::String(s) => write_ascii_string(out, s),
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(out, item);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_ascii_string(out, key);
+                out.push(':');
+                write_canonical(out, value);
+            }
+            out.push('}');
+        }
+    }
+}
+
+/// JSON string with everything outside printable ASCII escaped, as `ensure_ascii` does.
+fn write_ascii_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            ' '..='~' => out.push(c),
+            _ => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    let _ = write!(out, "\\u{:04x}", unit);
+                }
+            }
+        }
+    }
+    out.push('"');
+}
+
+/// Python's `repr` of a float: the shortest round-tripping digits, positional for
+/// decimal exponents in [-4, 16) and scientific with a signed two-digit exponent
+/// otherwise.
+fn write_python_float(out: &mut String, f: f64) {
+    if !f.is_finite() {
+        // Not valid JSON; Python writes these names.
+        out.push_str(if f.is_nan() {
+            "NaN"
+        } else if f > 0.0 {
+            "Infinity"
+        } else {
+            "-Infinity"
+        });
+        return;
+    }
+    // `{:e}` gives the shortest round-tripping digits, e.g. `1.5e-7`.
+    let formatted = format!("{:e}", f.abs());
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let digits = mantissa.replace('.', "");
+    if f.is_sign_negative() {
+        out.push('-');
+    }
+    if (-4..16).contains(&exponent) {
+        if exponent < 0 {
+            out.push_str("0.");
+            out.push_str(&"0".repeat((-exponent - 1) as usize));
+            out.push_str(&digits);
+        } else {
+            let int_len = exponent as usize + 1;
+            if digits.len() > int_len {
+                out.push_str(&digits[..int_len]);
+                out.push('.');
+                out.push_str(&digits[int_len..]);
+            } else {
+                out.push_str(&digits);
+                out.push_str(&"0".repeat(int_len - digits.len()));
+                out.push_str(".0");
+            }
+        }
+    } else {
+        out.push_str(&digits[..1]);
+        if digits.len() > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        let _ = write!(
+            out,
+            "e{}{:02}",
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        );
+    }
+}
+
+/// Queue priority of a task: its `priority` clamped to `max_priority`, if priorities
+/// are enabled.
+pub fn priority(task: &Value, max_priority: u8) -> Option<u8> {
+    task["priority"]
+        .as_i64()
+        .filter(|_| max_priority > 0)
+        .map(|p| p.clamp(0, max_priority as i64) as u8)
+}
+
+/// Validates a submitted task and builds its event and message. The task is passed
+/// through as the message payload, so fields the consumer reads (`use_cache`,
+/// `output_schema`, `providers`, ...) need no support here. Tasks give either a
+/// `body` or a `template` with its `variables`.
+pub fn prepare(batch_id: &str, task: Value, max_priority: u8) -> Result<PreparedTask, String> {
+    prepare_encrypted(batch_id, task, max_priority, &[])
+}
+
+/// Like `prepare`, with the payload encrypted to `recipients` when there are any. The
+/// event then only holds the task's routing fields and attribution, see
+/// `payload_encryption`.
+pub fn prepare_encrypted(
+    batch_id: &str,
+    mut task: Value,
+    max_priority: u8,
+    recipients: &[Recipient],
+) -> Result<PreparedTask, String> {
+    if !task.is_object() {
+        return Err("Task data must be a JSON object".to_string());
+    }
+    for field in ["method", "url"] {
+        if !task[field].is_string() {
+            return Err(format!("Task field `{}` must be a string", field));
+        }
+    }
+    // Templated tasks are rendered by the consumer and hashed on what they're
+    // rendered from.
+    if task["template"].is_null() {
+        if !task["body"].is_object() {
+            return Err("Task field `body` must be an object".to_string());
+        }
+    } else {
+        if !task["template"].is_string() && !task["template"].is_object() {
+            return Err("Task field `template` must be a template name or an object".to_string());
+        }
+        if !task["variables"].is_null() && !task["variables"].is_object() {
+            return Err("Task field `variables` must be an object".to_string());
+        }
+    }
+    let body_hash = body_hash(&cache_key::hashed_value(&task));
+    if !task["callback_url"].is_null() && !task["callback_url"].is_string() {
+        return Err("Task field `callback_url` must be a string".to_string());
+    }
+    // Moved from the task to the message, next to the payload.
+    let attribution = Attribution::from_task(&task)?;
+    if let Some(fields) = task.as_object_mut() {
+        fields.remove(attribution::FIELD);
+    }
+
+    let message_id = uuid::Uuid::new_v4().to_string();
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true);
+    let priority = priority(&task, max_priority);
+
+    let mut event = json!({
+        "message_id": message_id,
+        "batch_id": batch_id,
+        "created_at": timestamp,
+        "status": "PENDING",
+        "custom_id": task["custom_id"],
+        "method": task["method"],
+        "url": task["url"],
+        "body_hash": body_hash,
+        "body": task["body"],
+        "dataset": task["dataset"],
+        "source": task["source"],
+        "attempt": 0,
+        attribution::FIELD: attribution,
+    });
+    let payload = if recipients.is_empty() {
+        task
+    } else {
+        // The other fields come from the payload, which only the consumer decrypts.
+        if let Some(fields) = event.as_object_mut() {
+            fields.retain(|field, _| ENCRYPTED_EVENT_FIELDS.contains(&field.as_str()));
+        }
+        payload_encryption::encrypt(&task, recipients)?
+    };
+    let message = serde_json::to_vec(&json!({
+        "message_id": message_id,
+        "timestamp": timestamp,
+        "payload": payload,
+        "body_hash": body_hash,
+        "batch_id": batch_id,
+        attribution::FIELD: attribution,
+    }))
+    .map_err(|e| e.to_string())?;
+
+    Ok(PreparedTask {
+        message_id,
+        body_hash,
+        event,
+        message,
+        priority,
+    })
+}
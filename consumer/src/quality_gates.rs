@@ -0,0 +1,44 @@
+//! Acceptance of a completion as a usable example, from the annotations the pipeline
+//! stages produced. Combined with the per-task cost, this gives what a run spent per
+//! accepted example.
+
+use serde_json::{Map, Value};
+
+/// Gates the completion failed, in pipeline order; empty when it is accepted. Stages
+/// that didn't run leave no annotation and don't reject anything.
+pub fn rejection_reasons(
+    annotations: &Map<String, Value>,
+    min_quality_score: Option<f64>,
+) -> Vec<&'static str> {
+    let flag = |key: &str| annotations.get(key).and_then(Value::as_bool);
+    let mut reasons = Vec::new();
+    if flag("schema_valid") == Some(false) {
+        reasons.push("schema");
+    }
+    if annotations
+        .get("moderation")
+        .is_some_and(|moderation| moderation["verdict"] == "flagged")
+    {
+        reasons.push("moderation");
+    }
+    if let Some(min) = min_quality_score {
+        // Of the local quality model and of the judge.
+        if ["local_quality_score", "quality_score"]
+            .iter()
+            .filter_map(|key| annotations.get(*key).and_then(Value::as_f64))
+            .any(|score| score < min)
+        {
+            reasons.push("quality");
+        }
+    }
+    if flag("contaminated") == Some(true) {
+        reasons.push("contamination");
+    }
+    if flag("corpus_duplicate") == Some(true) || flag("batch_duplicate") == Some(true) {
+        reasons.push("duplicate");
+    }
+    if flag("balance_excess") == Some(true) {
+        reasons.push("balance");
+    }
+    reasons
+}
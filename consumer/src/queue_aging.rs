@@ -0,0 +1,144 @@
+//! Aging of tasks waiting in the priority task queue, so old tasks don't starve behind
+//! fresh work of a higher priority that keeps arriving.
+//!
+//! A task's age counts from the `timestamp` its producer gave it, which stays on the
+//! message through requeues, deferrals and resumes. From `TASK_BOOST_AFTER_SECS` on,
+//! by default half of `TASK_STALE_AFTER_SECS`, its priority is raised towards the
+//! highest one (`TASK_QUEUE_MAX_PRIORITY`), which it reaches once stale. RabbitMQ only
+//! delivers the head of a priority queue, so `synthgen-admin age-queue`, meant to run
+//! from a scheduler such as cron, walks the task queue and publishes the tasks whose
+//! priority rose since they were published again at it. The others are put back in the
+//! order they came, which keeps their order within each priority but for tasks
+//! published meanwhile. The walk holds one message at a time, and a lock keeps two
+//! walks from overlapping.
+//!
+//! With `TASK_STALE_POLICY=expire`, stale tasks are failed when delivered instead of
+//! being processed, with an `expired` failure reason.
+
+use crate::broker::amqp::AmqpBroker;
+use crate::broker::BrokerResult;
+use crate::producer;
+use crate::schemas::envelope::TaskMessage;
+use crate::settings::{QueueAgingSettings, StalePolicy};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+/// When the task of `message` was submitted, if its producer said.
+pub fn enqueued_at(message: &TaskMessage) -> Option<DateTime<Utc>> {
+    let timestamp = message.timestamp.as_deref()?;
+    DateTime::parse_from_rfc3339(timestamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Time since `enqueued_at`, zero when it's in the future.
+pub fn age(enqueued_at: DateTime<Utc>, now: DateTime<Utc>) -> Duration {
+    (now - enqueued_at).to_std().unwrap_or_default()
+}
+
+pub struct QueueAging {
+    boost_after: Duration,
+    stale_after: Duration,
+    policy: StalePolicy,
+}
+
+impl QueueAging {
+    /// `None` when tasks don't age.
+    pub fn new(settings: &QueueAgingSettings) -> Option<Self> {
+        let stale_after = settings.stale_after_secs?;
+        let boost_after = settings
+            .boost_after_secs
+            .unwrap_or(stale_after / 2)
+            .min(stale_after);
+        Some(Self {
+            boost_after: Duration::from_secs(boost_after),
+            stale_after: Duration::from_secs(stale_after),
+            policy: settings.policy,
+        })
+    }
+
+    pub fn is_stale(&self, age: Duration) -> bool {
+        age >= self.stale_after
+    }
+
+    /// Whether a task of `age` is failed rather than processed.
+    pub fn expires(&self, age: Duration) -> bool {
+        self.policy == StalePolicy::Expire && self.is_stale(age)
+    }
+
+    /// Priority of a task submitted at `priority` once it's `age` old: raised linearly
+    /// from `boost_after` on, up to `max_priority` when stale.
+    pub fn priority(&self, priority: u8, age: Duration, max_priority: u8) -> u8 {
+        if age < self.boost_after || priority >= max_priority {
+            return priority;
+        }
+        if self.is_stale(age) {
+            return max_priority;
+        }
+        let progress = (age - self.boost_after).as_secs_f64()
+            / (self.stale_after - self.boost_after).as_secs_f64();
+        let raised = (f64::from(max_priority - priority) * progress).ceil() as u8;
+        priority + raised.min(max_priority - priority)
+    }
+
+    /// Priority of the task message `data` now, if it's above `current`, the priority
+    /// its message has in the queue. Tasks are raised from the priority they were
+    /// submitted at, so one raised by an earlier walk only rises further.
+    pub fn raised_priority(
+        &self,
+        data: &[u8],
+        current: u8,
+        max_priority: u8,
+        now: DateTime<Utc>,
+    ) -> Option<u8> {
+        let message = TaskMessage::parse(data).ok()?;
+        let age = age(enqueued_at(&message)?, now);
+        let submitted = producer::priority(&message.payload, max_priority).unwrap_or(0);
+        Some(self.priority(submitted, age, max_priority)).filter(|raised| *raised > current)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AgingReport {
+    /// Tasks published again at a raised priority.
+    pub boosted: usize,
+    /// Tasks put back at their priority.
+    pub kept: usize,
+}
+
+/// Walks the task queue and publishes its aged tasks again at their raised priority.
+pub async fn age_queue(
+    broker: &Arc<AmqpBroker>,
+    aging: &QueueAging,
+    max_priority: u8,
+) -> BrokerResult<AgingReport> {
+    let mut report = AgingReport::default();
+    if max_priority == 0 {
+        return Ok(report);
+    }
+    let now = Utc::now();
+    let mut walk = broker.walk(broker.queue()).await?;
+    while let Some(message) = walk.next().await? {
+        let current = message.priority.unwrap_or_default();
+        match aging.raised_priority(&message.data, current, max_priority, now) {
+            Some(priority) => {
+                walk.put_back(&message, Some(priority)).await?;
+                report.boosted += 1;
+            }
+            None => {
+                walk.put_back(&message, message.priority).await?;
+                report.kept += 1;
+            }
+        }
+    }
+    if report.boosted > 0 {
+        info!(
+            "Raised the priority of {} aged tasks in {}",
+            report.boosted,
+            broker.queue()
+        );
+    }
+    Ok(report)
+}
@@ -0,0 +1,363 @@
+//! Client-side request and token rate limits per provider or model, so requests are
+//! spaced out before they are sent instead of being retried after a 429.
+//!
+//! Limits are enforced per process by default, in a token bucket per limit and API key.
+//! With `REDIS_URL` set they are shared by every replica through a GCRA schedule kept in
+//! Redis, under the same keys.
+//! Quotas of organizations and projects are enforced the same way, on the requests of
+//! the tasks attributed to them.
+
+use crate::attribution::Attribution;
+use crate::settings::{RateLimitSettings, RateLimitUnit};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+
+/// Parses a rate such as `60/min`, `5/s` or `1000/hour`.
+pub fn parse_rate(value: &str) -> Option<(u32, u64)> {
+    let (requests, unit) = value.trim().split_once('/')?;
+    let period_secs = match unit.trim() {
+        "s" | "sec" | "second" => 1,
+        "m" | "min" | "minute" => 60,
+        "h" | "hour" => 3600,
+        "d" | "day" => 86400,
+        _ => return None,
+    };
+    Some((requests.trim().parse().ok()?, period_secs))
+}
+
+/// Rough token count of a request before it is sent: about four characters per token
+/// for the body, plus the completion budget it asks for.
+pub fn estimate_tokens(body: &Value) -> u64 {
+    let prompt = serde_json::to_string(body).map_or(0, |s| s.len() as u64 / 4);
+    let completion = ["max_tokens", "max_completion_tokens", "max_output_tokens"]
+        .iter()
+        .find_map(|field| body[*field].as_u64())
+        .unwrap_or(0);
+    prompt + completion
+}
+
+/// Token bucket allowing bursts of up to `capacity` units and refilling at the
+/// configured rate. Callers that find it empty reserve future units and sleep until
+/// they are due, so waiters are served in arrival order.
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(requests: u32, period: Duration) -> Self {
+        let capacity = requests.max(1) as f64;
+        Self {
+            capacity,
+            refill_per_sec: capacity / period.as_secs_f64().max(f64::EPSILON),
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Time to wait before the caller may use `amount` units, when this process may
+    /// use `share` of the configured rate.
+    pub fn reserve(&self, amount: f64, share: f64) -> Duration {
+        let capacity = (self.capacity * share).max(1.0);
+        let refill_per_sec = self.refill_per_sec * share;
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens =
+            (*tokens + now.duration_since(*last).as_secs_f64() * refill_per_sec).min(capacity);
+        *last = now;
+        *tokens -= amount;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / refill_per_sec)
+        }
+    }
+
+    pub async fn acquire(&self) {
+        let wait = self.reserve(1.0, 1.0);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// Generic cell rate algorithm with reservations: the key holds the theoretical arrival
+// time (TAT) in milliseconds of Redis' clock, so replicas with skewed clocks agree.
+// Returns how long the caller has to wait for its `quantity`.
+const GCRA_SCRIPT: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local interval = tonumber(ARGV[1])
+local period = tonumber(ARGV[2])
+local quantity = tonumber(ARGV[3])
+local tat = tonumber(redis.call('GET', KEYS[1]) or now)
+if tat < now then
+  tat = now
+end
+local new_tat = tat + interval * quantity
+redis.call('SET', KEYS[1], tostring(new_tat), 'PX', math.ceil(new_tat - now) + 1000)
+local wait = new_tat - period - now
+if wait < 0 then
+  return 0
+end
+return math.ceil(wait)
+";
+
+/// Rate limits shared by all replicas through Redis.
+struct DistributedLimits {
+    client: redis::Client,
+    connection: OnceCell<redis::aio::ConnectionManager>,
+    script: redis::Script,
+}
+
+impl DistributedLimits {
+    /// Milliseconds to wait before `quantity` units of `limit` may be used.
+    async fn reserve(
+        &self,
+        key: &str,
+        limit: &RateLimitSettings,
+        quantity: u64,
+    ) -> redis::RedisResult<u64> {
+        let mut connection = self
+            .connection
+            .get_or_try_init(|| self.client.get_connection_manager())
+            .await?
+            .clone();
+        let period_ms = limit.period_secs as f64 * 1000.0;
+        self.script
+            .key(key)
+            .arg(period_ms / limit.limit.max(1) as f64)
+            .arg(period_ms)
+            .arg(quantity)
+            .invoke_async(&mut connection)
+            .await
+    }
+}
+
+/// Identifies the provider account a request is charged to.
+pub struct RateLimitTarget<'a> {
+    pub url: &'a str,
+    pub model: &'a str,
+    pub api_key: &'a str,
+}
+
+impl RateLimitTarget<'_> {
+    /// Whether a limit applies: `pattern` (lowercase) occurs in the URL's host or in
+    /// the model name.
+    pub fn matcher(&self) -> impl Fn(&str) -> bool {
+        let host = reqwest::Url::parse(self.url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_lowercase))
+            .unwrap_or_default();
+        let model = self.model.to_lowercase();
+        move |pattern| host.contains(pattern) || model.contains(pattern)
+    }
+}
+
+/// Token buckets of a limit, by the key it is enforced under.
+#[derive(Default)]
+struct Buckets(Mutex<HashMap<String, Arc<TokenBucket>>>);
+
+impl Buckets {
+    fn get(&self, key: &str, limit: &RateLimitSettings) -> Arc<TokenBucket> {
+        let mut buckets = self.0.lock().unwrap();
+        if let Some(bucket) = buckets.get(key) {
+            return bucket.clone();
+        }
+        let bucket = Arc::new(TokenBucket::new(
+            limit.limit,
+            Duration::from_secs(limit.period_secs),
+        ));
+        buckets.insert(key.to_string(), bucket.clone());
+        bucket
+    }
+}
+
+pub struct RateLimiter {
+    limits: Vec<(RateLimitSettings, Buckets)>,
+    /// Quotas, whose pattern is the scope of an organization or project.
+    quotas: Vec<(RateLimitSettings, Buckets)>,
+    distributed: Option<DistributedLimits>,
+    /// Replicas splitting the configured rates under work partitioning, when the
+    /// limits are enforced per process.
+    cluster_size: AtomicUsize,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &[RateLimitSettings], redis_url: Option<&str>) -> redis::RedisResult<Self> {
+        let distributed = match redis_url {
+            Some(url) => Some(DistributedLimits {
+                client: redis::Client::open(url)?,
+                connection: OnceCell::new(),
+                script: redis::Script::new(GCRA_SCRIPT),
+            }),
+            None => None,
+        };
+        Ok(Self {
+            limits: buckets(limits),
+            quotas: Vec::new(),
+            distributed,
+            cluster_size: AtomicUsize::new(1),
+        })
+    }
+
+    /// Also charges the requests of attributed tasks to `quotas`, see `acquire_for`.
+    pub fn with_quotas(mut self, quotas: &[RateLimitSettings]) -> Self {
+        self.quotas = buckets(quotas);
+        self
+    }
+
+    /// Limits each configured rate to this replica's share of `replicas`.
+    pub fn set_cluster_size(&self, replicas: usize) {
+        self.cluster_size.store(replicas.max(1), Ordering::Relaxed);
+    }
+
+    /// Waits until a request of about `estimated_tokens` fits every limit whose pattern
+    /// occurs in the URL's host or in the model name, so a provider-wide limit and a
+    /// model limit can both apply.
+    pub async fn acquire(&self, target: &RateLimitTarget<'_>, estimated_tokens: u64) {
+        self.acquire_for(target, &Attribution::default(), estimated_tokens)
+            .await
+    }
+
+    /// Like `acquire`, also waiting for the quotas of the organization and project
+    /// the request is attributed to.
+    pub async fn acquire_for(
+        &self,
+        target: &RateLimitTarget<'_>,
+        attribution: &Attribution,
+        estimated_tokens: u64,
+    ) {
+        let amount = |unit| match unit {
+            RateLimitUnit::Requests => 1,
+            RateLimitUnit::Tokens => estimated_tokens,
+        };
+        let mut wait = Duration::ZERO;
+        for limit in self.matching(target) {
+            let key = limit_key(target, &limit.0);
+            wait = wait.max(self.reserve(&key, limit, amount(limit.0.unit)).await);
+        }
+        for quota in self.quotas_of(attribution) {
+            let key = quota_key(&quota.0);
+            wait = wait.max(self.reserve(&key, quota, amount(quota.0.unit)).await);
+        }
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Charges token limits for what a response used beyond the estimate it was
+    /// admitted with; later requests wait for the difference.
+    pub async fn record_tokens(
+        &self,
+        target: &RateLimitTarget<'_>,
+        estimated_tokens: u64,
+        used_tokens: u64,
+    ) {
+        self.record_tokens_for(
+            target,
+            &Attribution::default(),
+            estimated_tokens,
+            used_tokens,
+        )
+        .await
+    }
+
+    /// Like `record_tokens`, also charging the quotas the request is attributed to.
+    pub async fn record_tokens_for(
+        &self,
+        target: &RateLimitTarget<'_>,
+        attribution: &Attribution,
+        estimated_tokens: u64,
+        used_tokens: u64,
+    ) {
+        let Some(extra) = used_tokens.checked_sub(estimated_tokens).filter(|n| *n > 0) else {
+            return;
+        };
+        for limit in self.matching(target) {
+            if limit.0.unit == RateLimitUnit::Tokens {
+                self.reserve(&limit_key(target, &limit.0), limit, extra)
+                    .await;
+            }
+        }
+        for quota in self.quotas_of(attribution) {
+            if quota.0.unit == RateLimitUnit::Tokens {
+                self.reserve(&quota_key(&quota.0), quota, extra).await;
+            }
+        }
+    }
+
+    fn matching<'s>(
+        &'s self,
+        target: &RateLimitTarget<'_>,
+    ) -> impl Iterator<Item = &'s (RateLimitSettings, Buckets)> {
+        let matches = target.matcher();
+        self.limits
+            .iter()
+            .filter(move |(limit, _)| matches(&limit.pattern))
+    }
+
+    fn quotas_of<'s>(
+        &'s self,
+        attribution: &Attribution,
+    ) -> impl Iterator<Item = &'s (RateLimitSettings, Buckets)> {
+        let scopes = attribution.quota_scopes();
+        self.quotas
+            .iter()
+            .filter(move |(quota, _)| scopes.contains(&quota.pattern))
+    }
+
+    async fn reserve(
+        &self,
+        key: &str,
+        (limit, buckets): &(RateLimitSettings, Buckets),
+        amount: u64,
+    ) -> Duration {
+        if let Some(distributed) = &self.distributed {
+            match distributed.reserve(key, limit, amount).await {
+                Ok(wait_ms) => return Duration::from_millis(wait_ms),
+                Err(e) => tracing::warn!(
+                    "Distributed rate limit unavailable, limiting locally: {}",
+                    e
+                ),
+            }
+        }
+        let share = 1.0 / self.cluster_size.load(Ordering::Relaxed) as f64;
+        buckets.get(key, limit).reserve(amount as f64, share)
+    }
+}
+
+fn buckets(limits: &[RateLimitSettings]) -> Vec<(RateLimitSettings, Buckets)> {
+    limits
+        .iter()
+        .map(|limit| {
+            let mut limit = limit.clone();
+            limit.pattern = limit.pattern.to_lowercase();
+            (limit, Buckets::default())
+        })
+        .collect()
+}
+
+/// Key of a provider limit, per account.
+fn limit_key(target: &RateLimitTarget<'_>, limit: &RateLimitSettings) -> String {
+    // Only a digest of the API key ends up in Redis or in memory.
+    let account = format!("{:x}", Sha256::digest(target.api_key.as_bytes()));
+    format!(
+        "synthgen:rate-limit:{}:{:?}:{}",
+        limit.pattern,
+        limit.unit,
+        &account[..16]
+    )
+}
+
+/// Key of a quota, shared by every account.
+fn quota_key(quota: &RateLimitSettings) -> String {
+    format!("synthgen:quota:{}:{:?}", quota.pattern, quota.unit)
+}
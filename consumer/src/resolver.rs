@@ -0,0 +1,76 @@
+//! Name resolution shared by the HTTP client and the broker connections, for
+//! restricted networks: a single address family, custom nameservers and static host
+//! overrides, on top of hickory's answer cache.
+
+use crate::settings::{IpFamily, NetworkSettings};
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct Resolver {
+    dns: TokioAsyncResolver,
+    overrides: Arc<HashMap<String, IpAddr>>,
+}
+
+impl Resolver {
+    /// Resolver following `network`, caching up to `cache_size` answers for at least
+    /// `min_ttl`.
+    pub fn new(network: &NetworkSettings, cache_size: usize, min_ttl: Duration) -> Self {
+        let (system_config, mut opts) = hickory_resolver::system_conf::read_system_conf()
+            .unwrap_or_else(|_| (ResolverConfig::default(), ResolverOpts::default()));
+        let config = if network.dns_servers.is_empty() {
+            system_config
+        } else {
+            let mut config = ResolverConfig::new();
+            for server in &network.dns_servers {
+                config.add_name_server(NameServerConfig::new(*server, Protocol::Udp));
+                config.add_name_server(NameServerConfig::new(*server, Protocol::Tcp));
+            }
+            config
+        };
+        opts.cache_size = cache_size;
+        opts.positive_min_ttl = Some(min_ttl);
+        opts.ip_strategy = match network.ip_family {
+            // Return both families so the connector can race them (happy eyeballs).
+            IpFamily::Any => LookupIpStrategy::Ipv4AndIpv6,
+            IpFamily::V4 => LookupIpStrategy::Ipv4Only,
+            IpFamily::V6 => LookupIpStrategy::Ipv6Only,
+        };
+        Self {
+            dns: TokioAsyncResolver::tokio(config, opts),
+            overrides: Arc::new(network.host_overrides.clone()),
+        }
+    }
+
+    /// Addresses of `host`: its override, the address itself for an IP literal, or
+    /// the DNS answer.
+    pub async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if let Some(ip) = self.overrides.get(host) {
+            return Ok(vec![*ip]);
+        }
+        if let Ok(ip) = host.trim_matches(['[', ']']).parse() {
+            return Ok(vec![ip]);
+        }
+        let lookup = self.dns.lookup_ip(host).await.map_err(io::Error::other)?;
+        Ok(lookup.into_iter().collect())
+    }
+
+    /// First address of `host`, written as the host part of a URI or `host:port` pair.
+    pub async fn uri_host(&self, host: &str) -> io::Result<String> {
+        match self.lookup(host).await?.first() {
+            Some(IpAddr::V4(ip)) => Ok(ip.to_string()),
+            Some(IpAddr::V6(ip)) => Ok(format!("[{}]", ip)),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no address found for {}", host),
+            )),
+        }
+    }
+}
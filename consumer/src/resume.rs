@@ -0,0 +1,93 @@
+//! Resuming of tasks stranded in PROCESSING, e.g. by a consumer that crashed after its
+//! delivery was settled, which no redelivery will ever pick up.
+//!
+//! Tasks tracking their progress keep their message on their event while PROCESSING
+//! (`db::TASK_MESSAGE`). Those PROCESSING for longer than `RESUME_STALE_AFTER_SECS` are
+//! set back to PENDING and published to the task queue again. The reset only applies to
+//! a task still stranded, so of consumers resuming at the same time a single one
+//! publishes it. Events written before messages were kept can't be resumed and are
+//! only reported.
+//!
+//! Runs when the consumer starts with `RESUME_ON_STARTUP=true`, or on demand with
+//! `consumer resume`.
+
+use crate::broker::MessageBroker;
+use crate::db::{DbResult, TaskStore};
+use crate::{producer, telemetry};
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tracing::{info, info_span, warn, Instrument};
+
+/// Stranded tasks looked up at a time.
+const PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ResumeReport {
+    /// Tasks set back to PENDING and published again.
+    pub resumed: usize,
+    /// Tasks that moved on before they could be reset, e.g. resumed by another consumer.
+    pub moved_on: usize,
+    /// Tasks whose event holds no message to publish again.
+    pub unresumable: usize,
+}
+
+/// Publishes the tasks PROCESSING for longer than `stale_after` again.
+pub async fn resume_stale_tasks(
+    store: &dyn TaskStore,
+    broker: &dyn MessageBroker,
+    stale_after: Duration,
+    max_priority: u8,
+) -> DbResult<ResumeReport> {
+    let cutoff = Utc::now() - chrono::Duration::from_std(stale_after)?;
+    let mut started_before = cutoff;
+    let mut report = ResumeReport::default();
+    loop {
+        let page = store.stale_tasks(started_before, PAGE_SIZE).await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        started_before = last.started_at;
+
+        for task in &page {
+            let Some(message) = &task.message else {
+                warn!(
+                    "Task {} of batch {} is stranded in PROCESSING since {} without a message to resume it from",
+                    task.message_id, task.batch_id, task.started_at
+                );
+                report.unresumable += 1;
+                continue;
+            };
+            if !store.reset_stale_task(&task.event_key(), cutoff).await? {
+                report.moved_on += 1;
+                continue;
+            }
+
+            let span =
+                info_span!("resume_task", message_id = %task.message_id, batch_id = %task.batch_id);
+            let mut headers = BTreeMap::new();
+            telemetry::inject(&span, &mut headers);
+            let priority = producer::priority(&message["payload"], max_priority);
+            let data = serde_json::to_vec(message)?;
+            // The task is PENDING by now, so a failure leaves it to be resubmitted.
+            broker
+                .publish_task(&data, priority, &headers)
+                .instrument(span)
+                .await
+                .map_err(|e| format!("Failed to publish task {} again: {}", task.message_id, e))?;
+            info!(
+                "Resumed task {} of batch {}, PROCESSING since {}",
+                task.message_id, task.batch_id, task.started_at
+            );
+            report.resumed += 1;
+        }
+        if page.len() < PAGE_SIZE {
+            break;
+        }
+    }
+    info!(
+        "Resumed {} stranded tasks ({} moved on, {} without a message)",
+        report.resumed, report.moved_on, report.unresumable
+    );
+    Ok(report)
+}
@@ -0,0 +1,150 @@
+//! Registry of the payload JSON Schemas of known task types, so producers fetch the
+//! contract of a `task_type` instead of guessing it, and tasks breaking it are
+//! rejected before they reach an LLM.
+//!
+//! Schemas come from `PAYLOAD_SCHEMAS` and, when `PAYLOAD_SCHEMAS_INDEX` is set, from an
+//! Elasticsearch document re-read periodically, whose schemas take precedence over the
+//! configured ones of the same task type.
+
+use crate::db::elastic::ElasticStore;
+use crate::db::DbResult;
+use crate::settings::{SchemaDocument, SchemaRegistrySettings};
+use crate::validation;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Deserialize)]
+struct StoredSchemas {
+    #[serde(default)]
+    schemas: HashMap<String, Value>,
+}
+
+/// A registered schema, compiled once.
+#[derive(Clone)]
+struct Contract {
+    schema: Value,
+    validator: Arc<jsonschema::Validator>,
+}
+
+pub struct SchemaRegistry {
+    configured: HashMap<String, Contract>,
+    /// Schemas of the Elasticsearch document, as of the last refresh.
+    stored: RwLock<HashMap<String, Contract>>,
+    /// Source of the stored schemas, to skip recompiling an unchanged document.
+    stored_source: RwLock<HashMap<String, Value>>,
+    strict: bool,
+}
+
+impl SchemaRegistry {
+    pub fn new(settings: &SchemaRegistrySettings) -> Self {
+        Self {
+            configured: compile(&settings.schemas),
+            stored: RwLock::new(HashMap::new()),
+            stored_source: RwLock::new(HashMap::new()),
+            strict: settings.strict,
+        }
+    }
+
+    /// Schema registered for `task_type`.
+    pub fn schema(&self, task_type: &str) -> Option<Value> {
+        self.contract(task_type).map(|contract| contract.schema)
+    }
+
+    /// Every registered schema by task type.
+    pub fn schemas(&self) -> BTreeMap<String, Value> {
+        let mut schemas: BTreeMap<String, Value> = self
+            .configured
+            .iter()
+            .map(|(task_type, contract)| (task_type.clone(), contract.schema.clone()))
+            .collect();
+        for (task_type, contract) in self.stored.read().unwrap().iter() {
+            schemas.insert(task_type.clone(), contract.schema.clone());
+        }
+        schemas
+    }
+
+    /// Checks a task payload against the schema of its `task_type`. Tasks without a
+    /// registered type pass unless the registry is strict.
+    pub fn check(&self, payload: &Value) -> Result<(), Vec<String>> {
+        let Some(task_type) = payload["task_type"].as_str() else {
+            return if self.strict {
+                Err(vec!["Task has no `task_type`".to_string()])
+            } else {
+                Ok(())
+            };
+        };
+        let Some(contract) = self.contract(task_type) else {
+            return if self.strict {
+                Err(vec![format!("Unknown task type `{}`", task_type)])
+            } else {
+                Ok(())
+            };
+        };
+        let errors = validation::violations(&contract.validator, payload);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn contract(&self, task_type: &str) -> Option<Contract> {
+        let stored = self.stored.read().unwrap();
+        stored
+            .get(task_type)
+            .or_else(|| self.configured.get(task_type))
+            .cloned()
+    }
+
+    /// Re-reads the schemas from the document until the process exits. A failed read
+    /// keeps the schemas of the last successful one.
+    pub async fn run(&self, store: ElasticStore, document: &SchemaDocument) {
+        let mut interval = tokio::time::interval(Duration::from_secs(document.refresh_secs.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.refresh(&store, document).await {
+                tracing::warn!(
+                    "Failed to refresh payload schemas from {}/{}: {}",
+                    document.index,
+                    document.id,
+                    e
+                );
+            }
+        }
+    }
+
+    async fn refresh(&self, store: &ElasticStore, document: &SchemaDocument) -> DbResult<()> {
+        let schemas = match store.get_document(&document.index, &document.id).await? {
+            Some(source) => serde_json::from_value::<StoredSchemas>(source)?.schemas,
+            None => HashMap::new(),
+        };
+        if *self.stored_source.read().unwrap() == schemas {
+            return Ok(());
+        }
+        tracing::info!("Payload schemas updated: {} stored schemas", schemas.len());
+        *self.stored.write().unwrap() = compile(&schemas);
+        *self.stored_source.write().unwrap() = schemas;
+        Ok(())
+    }
+}
+
+/// Compiles the schemas, leaving out the invalid ones.
+fn compile(schemas: &HashMap<String, Value>) -> HashMap<String, Contract> {
+    let mut contracts = HashMap::new();
+    for (task_type, schema) in schemas {
+        match jsonschema::validator_for(schema) {
+            Ok(validator) => {
+                let contract = Contract {
+                    schema: schema.clone(),
+                    validator: Arc::new(validator),
+                };
+                contracts.insert(task_type.clone(), contract);
+            }
+            Err(e) => tracing::warn!("Ignoring invalid payload schema of `{}`: {}", task_type, e),
+        }
+    }
+    contracts
+}
@@ -0,0 +1,229 @@
+use crate::attribution::Attribution;
+use crate::conversations::ConversationPlan;
+use crate::evaluation::EvaluationSpec;
+use crate::settings::ProviderApi;
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+
+/// Wire format of a task message. Identifiers borrow from the delivery buffer and the
+/// payload is parsed straight into place instead of being cloned out of a parent `Value`.
+#[derive(Debug, Deserialize)]
+pub struct TaskMessage<'a> {
+    #[serde(borrow)]
+    pub message_id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub batch_id: Cow<'a, str>,
+    #[serde(borrow)]
+    pub body_hash: Cow<'a, str>,
+    pub payload: Value,
+    /// When the producer submitted the task (RFC 3339), see `queue_aging`.
+    #[serde(default, borrow)]
+    pub timestamp: Option<Cow<'a, str>>,
+    /// Organization, project and user the task is run for.
+    #[serde(default)]
+    pub attribution: Option<Attribution>,
+}
+
+impl<'a> TaskMessage<'a> {
+    /// Parses a delivery, whose identifiers must all be set. The payload is checked
+    /// separately, by `TaskPayload::from_value`, so a task with a broken payload can
+    /// still be recorded on its event.
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        let message: Self =
+            serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(data))
+                .map_err(|e| e.to_string())?;
+        for (field, value) in [
+            ("message_id", &message.message_id),
+            ("batch_id", &message.batch_id),
+            ("body_hash", &message.body_hash),
+        ] {
+            if value.is_empty() {
+                return Err(format!("{}: must not be empty", field));
+            }
+        }
+        if let Some(attribution) = &message.attribution {
+            attribution
+                .check()
+                .map_err(|e| format!("attribution.{}", e))?;
+        }
+        Ok(message)
+    }
+}
+
+/// Kind of a task, see `embedding_tasks`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskKind {
+    #[default]
+    Completion,
+    Embedding,
+    Conversation,
+}
+
+/// Endpoint of a task's `providers`, as submitted.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderEndpoint {
+    pub url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub completions_url: Option<String>,
+    #[serde(default)]
+    pub provider: Option<ProviderApi>,
+}
+
+/// Fields of a task payload the consumer acts on, with their types. Payloads carry
+/// others (`dataset`, `source`, ...) which are kept as they are.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TaskPayload {
+    #[serde(default)]
+    pub custom_id: Option<String>,
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub body: Option<Map<String, Value>>,
+    /// Template name, or an inline template.
+    #[serde(default)]
+    pub template: Option<Value>,
+    #[serde(default)]
+    pub variables: Option<Map<String, Value>>,
+    #[serde(default)]
+    pub task_type: Option<String>,
+    #[serde(default)]
+    pub kind: Option<TaskKind>,
+    #[serde(default)]
+    pub provider: Option<ProviderApi>,
+    #[serde(default)]
+    pub providers: Option<Vec<ProviderEndpoint>>,
+    #[serde(default)]
+    pub priority: Option<i64>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub use_cache: Option<bool>,
+    /// Samples collected before a stochastic body is served from the cache, see
+    /// `cache_samples`.
+    #[serde(default)]
+    pub cache_samples: Option<u32>,
+    #[serde(default)]
+    pub track_progress: Option<bool>,
+    #[serde(default)]
+    pub use_batch_api: Option<bool>,
+    #[serde(default)]
+    pub debug: Option<bool>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub sla_deadline: Option<DateTime<FixedOffset>>,
+    #[serde(default)]
+    pub callback_url: Option<String>,
+    #[serde(default)]
+    pub output_schema: Option<Map<String, Value>>,
+    #[serde(default)]
+    pub evaluation: Option<EvaluationSpec>,
+    #[serde(default)]
+    pub conversation: Option<ConversationPlan>,
+}
+
+impl TaskPayload {
+    /// Checks `payload`, with the path of the offending field in the error.
+    pub fn from_value(payload: &Value) -> Result<Self, String> {
+        let payload: Self = serde_path_to_error::deserialize(payload).map_err(|e| e.to_string())?;
+        match &payload.template {
+            None if payload.body.is_none() => {
+                return Err("body: required unless a template is given".to_string())
+            }
+            Some(template) if !template.is_string() && !template.is_object() => {
+                return Err("template: must be a template name or an object".to_string())
+            }
+            _ => {}
+        }
+        match &payload.conversation {
+            None if payload.kind == Some(TaskKind::Conversation) => {
+                return Err("conversation: required for conversation tasks".to_string())
+            }
+            Some(plan) => plan.check()?,
+            None => {}
+        }
+        Ok(payload)
+    }
+}
+
+/// JSON Schema of the envelope, served to producers next to the payload schemas of the
+/// task types.
+pub fn json_schema() -> Value {
+    let string = json!({ "type": "string" });
+    let boolean = json!({ "type": "boolean" });
+    let count = json!({ "type": "integer", "minimum": 0 });
+    let payload_properties = json!({
+        "custom_id": string,
+        "task_type": string,
+        "kind": { "enum": ["completion", "embedding", "conversation"] },
+        "method": string,
+        "url": string,
+        "api_key": string,
+        "body": { "type": "object" },
+        "template": { "type": ["string", "object"] },
+        "variables": { "type": "object" },
+        "provider": { "enum": ["openai_compatible", "anthropic"] },
+        "providers": {
+            "type": "array",
+            "items": { "type": "object", "required": ["url"] }
+        },
+        "priority": { "type": "integer" },
+        "tenant": string,
+        "use_cache": boolean,
+        "cache_samples": count,
+        "track_progress": boolean,
+        "use_batch_api": boolean,
+        "debug": boolean,
+        "timeout_secs": count,
+        "sla_deadline": { "type": "string", "format": "date-time" },
+        "callback_url": string,
+        "output_schema": { "type": "object" },
+        "evaluation": { "type": "object", "required": ["method", "reference"] },
+        "conversation": {
+            "type": "object",
+            "properties": {
+                "turns": { "type": "array", "items": { "type": ["string", "object"] } },
+                "follow_up": { "type": ["string", "object"] },
+                "max_turns": { "type": "integer", "minimum": 1 }
+            }
+        }
+    });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Task message",
+        "type": "object",
+        "required": ["message_id", "batch_id", "body_hash", "payload"],
+        "properties": {
+            "message_id": { "type": "string", "minLength": 1 },
+            "batch_id": { "type": "string", "minLength": 1 },
+            "body_hash": {
+                "type": "string",
+                "minLength": 1,
+                "description": "Base64 SHA-256 of `payload.body` serialized with sorted keys and no whitespace"
+            },
+            "timestamp": { "type": "string", "format": "date-time" },
+            "attribution": {
+                "type": "object",
+                "properties": { "org": string, "project": string, "user": string },
+                "additionalProperties": false
+            },
+            "payload": {
+                "type": "object",
+                "required": ["method", "url"],
+                "anyOf": [{ "required": ["body"] }, { "required": ["template"] }],
+                "properties": payload_properties
+            }
+        }
+    })
+}
@@ -1,11 +1,27 @@
-use serde_json::Value;
+use super::provider_response::{self, Usage};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
 use chrono::{DateTime, Utc};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LLMResponse {
     pub completions: Value,
     pub cached: bool,
     pub attempt: u32,
     pub started_at: DateTime<Utc>,
     pub completed_at: DateTime<Utc>,
+    /// Extra fields produced by post-processing stages, merged into the events document.
+    pub annotations: Map<String, Value>,
+    /// Token usage reported by the provider.
+    pub usage: Option<Usage>,
+    /// Cost of `usage` under the configured model prices.
+    pub cost: Option<f64>,
+}
+
+impl LLMResponse {
+    /// Text of the first choice, for OpenAI chat and legacy completion shapes as well as
+    /// Anthropic and Gemini responses.
+    pub fn content(&self) -> Option<Cow<'_, str>> {
+        provider_response::content(&self.completions)
+    }
 }
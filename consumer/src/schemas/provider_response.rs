@@ -0,0 +1,193 @@
+//! Normalization of raw provider responses. Tasks are sent to OpenAI-compatible
+//! endpoints (OpenAI, OpenRouter), Anthropic's Messages API or Gemini's
+//! `generateContent`, and the raw body is stored as `completions`; this reads text,
+//! finish reason, usage and errors out of any of those shapes.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Provider {
+    /// OpenAI chat/legacy completions and the providers mirroring them (OpenRouter).
+    #[serde(rename = "openai_compatible")]
+    OpenAiCompatible,
+    Anthropic,
+    Gemini,
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NormalizedResponse {
+    pub provider: Provider,
+    pub model: Option<String>,
+    /// Text of the first choice/candidate; multiple text blocks are concatenated.
+    pub content: Option<String>,
+    pub finish_reason: Option<String>,
+    pub usage: Option<Usage>,
+}
+
+/// Error reported in a response body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProviderError {
+    /// Numeric code (OpenRouter, Gemini), usually an HTTP status.
+    pub code: Option<u64>,
+    /// Symbolic error type: OpenAI string `code`, Gemini `status`, else `type` (Anthropic).
+    pub kind: Option<String>,
+    pub message: String,
+}
+
+pub fn detect_provider(raw: &Value) -> Provider {
+    if raw.get("choices").is_some() {
+        Provider::OpenAiCompatible
+    } else if raw.get("candidates").is_some() || raw.get("promptFeedback").is_some() {
+        Provider::Gemini
+    } else if raw["type"] == "message" || raw["type"] == "error" || raw["content"].is_array() {
+        Provider::Anthropic
+    } else {
+        Provider::Unknown
+    }
+}
+
+/// Error carried by the body, at the top level or nested under `completions`.
+pub fn detect_error(raw: &Value) -> Option<ProviderError> {
+    let error = raw
+        .get("error")
+        .or_else(|| raw.get("completions").and_then(|c| c.get("error")))?;
+
+    if let Some(message) = error.as_str() {
+        return Some(ProviderError {
+            code: None,
+            kind: None,
+            message: message.to_string(),
+        });
+    }
+    if !error.is_object() {
+        return None;
+    }
+
+    let kind = error["code"]
+        .as_str()
+        .or_else(|| error["status"].as_str())
+        .or_else(|| error["type"].as_str())
+        .map(str::to_string);
+    Some(ProviderError {
+        code: error["code"].as_u64(),
+        kind,
+        message: error["message"]
+            .as_str()
+            .unwrap_or("Unknown error")
+            .to_string(),
+    })
+}
+
+/// Concatenated `text` of the blocks, borrowed when there is only one.
+pub fn text_blocks<'a>(blocks: impl Iterator<Item = &'a Value>) -> Option<Cow<'a, str>> {
+    let texts: Vec<&str> = blocks.filter_map(|b| b["text"].as_str()).collect();
+    match texts.as_slice() {
+        [] => None,
+        [text] => Some(Cow::Borrowed(text)),
+        _ => Some(Cow::Owned(texts.concat())),
+    }
+}
+
+/// Text of the first choice/candidate of a successful response, whatever the provider.
+pub fn content(raw: &Value) -> Option<Cow<'_, str>> {
+    if let Some(choice) = raw["choices"].get(0) {
+        return choice["message"]["content"]
+            .as_str()
+            .or_else(|| choice["text"].as_str())
+            .map(Cow::Borrowed);
+    }
+    if let Some(blocks) = raw["content"].as_array() {
+        return text_blocks(blocks.iter().filter(|b| b["type"] == "text"));
+    }
+    raw["candidates"][0]["content"]["parts"]
+        .as_array()
+        .and_then(|parts| text_blocks(parts.iter()))
+}
+
+fn usage(prompt: &Value, completion: &Value, total: &Value) -> Option<Usage> {
+    let prompt_tokens = prompt.as_u64();
+    let completion_tokens = completion.as_u64();
+    if prompt_tokens.is_none() && completion_tokens.is_none() {
+        return None;
+    }
+    let prompt_tokens = prompt_tokens.unwrap_or(0);
+    let completion_tokens = completion_tokens.unwrap_or(0);
+    Some(Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: total.as_u64().unwrap_or(prompt_tokens + completion_tokens),
+    })
+}
+
+/// Normalizes a successful response, or returns the error it carries.
+pub fn normalize(raw: &Value) -> Result<NormalizedResponse, ProviderError> {
+    if let Some(error) = detect_error(raw) {
+        return Err(error);
+    }
+
+    let provider = detect_provider(raw);
+    let model = |key: &str| raw[key].as_str().map(str::to_string);
+    Ok(match provider {
+        Provider::OpenAiCompatible => {
+            let choice = &raw["choices"][0];
+            NormalizedResponse {
+                provider,
+                model: model("model"),
+                content: content(raw).map(Cow::into_owned),
+                finish_reason: choice["finish_reason"].as_str().map(str::to_string),
+                usage: usage(
+                    &raw["usage"]["prompt_tokens"],
+                    &raw["usage"]["completion_tokens"],
+                    &raw["usage"]["total_tokens"],
+                ),
+            }
+        }
+        Provider::Anthropic => NormalizedResponse {
+            provider,
+            model: model("model"),
+            content: content(raw).map(Cow::into_owned),
+            finish_reason: raw["stop_reason"].as_str().map(str::to_string),
+            usage: usage(
+                &raw["usage"]["input_tokens"],
+                &raw["usage"]["output_tokens"],
+                &Value::Null,
+            ),
+        },
+        Provider::Gemini => {
+            let candidate = &raw["candidates"][0];
+            NormalizedResponse {
+                provider,
+                model: model("modelVersion"),
+                content: content(raw).map(Cow::into_owned),
+                // A blocked prompt has no candidates, only the block reason.
+                finish_reason: candidate["finishReason"]
+                    .as_str()
+                    .or_else(|| raw["promptFeedback"]["blockReason"].as_str())
+                    .map(str::to_string),
+                usage: usage(
+                    &raw["usageMetadata"]["promptTokenCount"],
+                    &raw["usageMetadata"]["candidatesTokenCount"],
+                    &raw["usageMetadata"]["totalTokenCount"],
+                ),
+            }
+        }
+        Provider::Unknown => NormalizedResponse {
+            provider,
+            model: model("model"),
+            content: None,
+            finish_reason: None,
+            usage: None,
+        },
+    })
+}
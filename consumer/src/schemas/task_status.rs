@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TaskStatus {
     Pending,
     Processing,
@@ -15,4 +15,14 @@ impl TaskStatus {
             TaskStatus::Failed => "FAILED",
         }
     }
-} 
\ No newline at end of file
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "PENDING" => Some(TaskStatus::Pending),
+            "PROCESSING" => Some(TaskStatus::Processing),
+            "COMPLETED" => Some(TaskStatus::Completed),
+            "FAILED" => Some(TaskStatus::Failed),
+            _ => None,
+        }
+    }
+}    
\ No newline at end of file
@@ -1,9 +1,1115 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 
-#[derive(Debug, Deserialize, Clone)]
+mod env;
+
+/// Connection to an Elasticsearch cluster, self-hosted or on Elastic Cloud.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DatabaseSettings {
+    /// `http` or `https`.
+    pub scheme: String,
     pub host: String,
     pub port: u16,
+    /// Basic auth credentials, used without an API key or bearer token.
     pub user: String,
     pub password: String,
-} 
\ No newline at end of file
+    /// Base64-encoded API key (`id:api_key`), as Kibana and Elastic Cloud show it.
+    pub api_key: Option<String>,
+    pub bearer_token: Option<String>,
+    /// Elastic Cloud deployment to connect to instead of `host` and `port`.
+    pub cloud_id: Option<String>,
+    /// PEM CA certificate trusted for the server's certificate, besides the system
+    /// roots.
+    pub ca_cert: Option<String>,
+    /// Skips the checks of the server's certificate when false, for clusters with
+    /// self-signed certificates.
+    pub verify_certs: bool,
+    /// Registered snapshot repository the indexes are snapshotted to before operations
+    /// rewriting events, see `admin`.
+    pub snapshot_repository: Option<String>,
+    /// Stores each distinct completion once in `indices.completions`, under its hash
+    /// with this algorithm, the events referencing it by `completions_ref`.
+    pub content_addressed_completions: Option<HashAlgorithm>,
+    pub indices: IndexSettings,
+}
+
+/// Names of the Elasticsearch indexes of the events, batch counts and leaderboards.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IndexSettings {
+    pub events: String,
+    pub batches: String,
+    pub leaderboard: String,
+    /// Records of the snapshots taken and restored.
+    pub audit: String,
+    /// Heartbeats of the consumer processes, see `workers`.
+    pub workers: String,
+    /// Completions stored content-addressably, see `content_addressed_completions`.
+    pub completions: String,
+    /// Writes new events to an index of the current period, `<events>-<date>`, and
+    /// searches them across `<events>-*`.
+    pub rollover: Option<IndexRollover>,
+    /// Creates the index templates and the missing indexes on connecting.
+    pub bootstrap: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum IndexRollover {
+    /// `<events>-YYYY.MM.DD`
+    Daily,
+    /// `<events>-YYYY.MM`
+    Monthly,
+}
+
+impl IndexRollover {
+    /// `chrono` format of the date suffix.
+    pub fn date_format(self) -> &'static str {
+        match self {
+            IndexRollover::Daily => "%Y.%m.%d",
+            IndexRollover::Monthly => "%Y.%m",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum RateLimitUnit {
+    Requests,
+    /// Prompt and completion tokens.
+    Tokens,
+}
+
+/// Request or token rate allowed to providers or models matching `pattern`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RateLimitSettings {
+    /// Matched against the request URL's host and the model name.
+    pub pattern: String,
+    pub unit: RateLimitUnit,
+    /// Requests or tokens allowed per period.
+    pub limit: u32,
+    pub period_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConcurrencyLimitSettings {
+    /// Matched against the request URL's host and the model name.
+    pub pattern: String,
+    /// Requests to the route in flight at once.
+    pub limit: usize,
+}
+
+/// What happens to a response larger than its model's budget.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum OversizedResponseAction {
+    /// Stop reading it and fail the attempt without retrying.
+    #[default]
+    Abort,
+    /// Keep it, annotated with its size in `oversized_response`.
+    Flag,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResponseBudgetSettings {
+    /// Matched against the model name.
+    pub pattern: String,
+    /// Largest response body accepted, in bytes.
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PayloadSettings {
+    /// Largest request body sent to a provider, serialized; larger tasks are rejected.
+    /// Zero lets any through.
+    pub max_request_bytes: usize,
+    pub response_budgets: Vec<ResponseBudgetSettings>,
+    pub oversized_response: OversizedResponseAction,
+    /// How often the request and response size histograms are logged; zero disables it.
+    pub report_interval_secs: u64,
+}
+
+/// Circuit breakers of the provider URLs; a zero `failure_threshold` disables them.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CircuitBreakerSettings {
+    /// Consecutive failed attempts opening the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit first stays open.
+    pub open_secs: u64,
+    /// Cap of the cooldown doubled by each failed probe.
+    pub max_open_secs: u64,
+}
+
+/// Size limits of the provider error bodies and completion texts kept in events and
+/// logs, see `truncation`. Zero keeps them whole.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StoredSizeSettings {
+    pub max_error_bytes: usize,
+    pub max_completion_bytes: usize,
+    pub completion_policy: StoredCompletionPolicy,
+    /// Object store the full bodies of truncated ones are uploaded to.
+    pub archive: Option<ArchiveSettings>,
+}
+
+/// What becomes of a completion over `max_completion_bytes`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum StoredCompletionPolicy {
+    /// Its texts are cut to the limit (default).
+    #[default]
+    Truncate,
+    /// It's moved to the object store whole, once its JSON is over the limit, the event
+    /// keeping its location. Truncated when it can't be uploaded.
+    Offload,
+}
+
+/// S3-compatible bucket (e.g. the API's MinIO) written with SigV4-signed requests.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ArchiveSettings {
+    /// Base URL of the object store; objects are addressed path-style under it.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Prepended to the keys of the archived objects.
+    pub prefix: String,
+}
+
+/// Resuming of tasks stranded in PROCESSING by a consumer that died, see `resume`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ResumeSettings {
+    /// How long a task has to be PROCESSING to be considered stranded.
+    pub stale_after_secs: u64,
+    /// Whether the consumer resumes stranded tasks when it starts.
+    pub on_startup: bool,
+}
+
+/// What becomes of tasks that went stale in the queue, see `queue_aging`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum StalePolicy {
+    /// Stale tasks are processed, at the highest priority (default).
+    #[default]
+    Boost,
+    /// Stale tasks are failed without being processed.
+    Expire,
+}
+
+/// Aging of the tasks waiting in the priority task queue, see `queue_aging`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct QueueAgingSettings {
+    /// Age at which a task is stale; tasks don't age when unset.
+    pub stale_after_secs: Option<u64>,
+    /// Age from which a task's priority is raised, by default half `stale_after_secs`.
+    pub boost_after_secs: Option<u64>,
+    pub policy: StalePolicy,
+}
+
+/// File format of batch exports, see `export`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// One JSON object per line (default).
+    #[default]
+    Jsonl,
+    /// Snappy-compressed Parquet with a UTF-8 string column per field.
+    Parquet,
+    /// Comma-separated values with a header row.
+    Csv,
+    /// SQLite database with a `results` table of a TEXT column per field.
+    Sqlite,
+}
+
+impl ExportFormat {
+    /// Format named `jsonl`, `parquet`, `csv` or `sqlite`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "jsonl" => Some(Self::Jsonl),
+            "parquet" => Some(Self::Parquet),
+            "csv" => Some(Self::Csv),
+            "sqlite" => Some(Self::Sqlite),
+            _ => None,
+        }
+    }
+}
+
+/// Order of the rows of batch exports.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportOrder {
+    /// By message id (default).
+    #[default]
+    MessageId,
+    /// Easiest first by `difficulty_score`, for curriculum training; rows without a
+    /// score go last.
+    Curriculum,
+}
+
+impl ExportOrder {
+    /// Order named `message_id` or `curriculum`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "message_id" => Some(Self::MessageId),
+            "curriculum" => Some(Self::Curriculum),
+            _ => None,
+        }
+    }
+}
+
+/// Column of a batch export and the event field it's read from.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ExportField {
+    pub name: String,
+    /// `prompt`, `messages` or `completion`, or the dotted path of an event field such
+    /// as `custom_id` or `body.model`.
+    pub source: String,
+}
+
+/// Exports of the prompt/completion pairs of completed batches, see `export`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ExportSettings {
+    /// Directory the export files are written to, or an `s3://bucket/prefix` URL of the
+    /// object store configured by the `ARCHIVE_S3_*` settings.
+    pub sink: String,
+    pub format: ExportFormat,
+    pub fields: Vec<ExportField>,
+    pub order: ExportOrder,
+}
+
+/// Header an LLM request's idempotency key is sent in.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdempotencyHeaderSettings {
+    /// Matched against the request URL's host.
+    pub pattern: String,
+    /// `None` to send no key to the matching hosts.
+    pub header: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct IdempotencySettings {
+    /// Header the key is sent in to hosts without their own, `None` to send none.
+    pub header: Option<String>,
+    pub hosts: Vec<IdempotencyHeaderSettings>,
+}
+
+/// Mock LLM backend answering generation calls, enabled by `LLM_MODE=mock`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MockLlmSettings {
+    /// Directory of the `<model>.json` responses returned instead of canned ones.
+    pub fixtures_dir: Option<String>,
+    /// Delay before each response.
+    pub latency_ms: u64,
+}
+
+/// Token prices of a model, in currency units per million tokens.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ModelPrice {
+    pub input_per_1m: f64,
+    pub output_per_1m: f64,
+}
+
+/// Estimated energy use of a model, in watt-hours per thousand tokens, and the carbon
+/// intensity of the power it runs on when it differs from the default.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ModelEnergy {
+    pub input_wh_per_1k: f64,
+    pub output_wh_per_1k: f64,
+    pub grams_co2_per_kwh: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EnergySettings {
+    pub models: HashMap<String, ModelEnergy>,
+    /// Grams of CO2-equivalent per kWh of models without their own intensity.
+    pub grams_co2_per_kwh: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum StorageBackend {
+    Elasticsearch,
+    Postgres,
+    /// Events kept in memory and written out as JSONL, for standalone runs.
+    Jsonl,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PostgresSettings {
+    pub url: String,
+    pub max_connections: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StorageSettings {
+    pub backend: StorageBackend,
+    pub elasticsearch: DatabaseSettings,
+    pub postgres: PostgresSettings,
+    /// File the events of finished tasks are written to by the `jsonl` backend.
+    pub jsonl_output: String,
+    /// Batches Elasticsearch status updates into `_bulk` requests when set.
+    pub bulk_writer: Option<BulkWriterSettings>,
+    /// Secondary Elasticsearch cluster every event write is replicated to.
+    pub mirror: Option<MirrorSettings>,
+    /// Per-batch task counts updated on every status change.
+    pub batch_progress: Option<BatchProgressSettings>,
+    /// Redis cache of completed responses consulted before the store.
+    pub response_cache: Option<ResponseCacheSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BulkWriterSettings {
+    /// Most updates sent in one `_bulk` request.
+    pub max_actions: usize,
+    /// Longest an update waits for others to join its request.
+    pub flush_interval_ms: u64,
+    /// Updates waiting to be flushed; writers wait while it's full.
+    pub queue_capacity: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MirrorSettings {
+    pub elasticsearch: DatabaseSettings,
+    /// Writes waiting for the secondary; further writes are dropped while it's full.
+    pub queue_capacity: usize,
+    /// Most writes sent in one `_bulk` request.
+    pub batch_size: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ResponseCacheSettings {
+    pub redis_url: String,
+    /// Prepended to the body hash to form the Redis key.
+    pub key_prefix: String,
+    /// How long a cached response is kept after it was last written.
+    pub ttl_secs: u64,
+    /// Longest a cache lookup or write may take before the store is used instead.
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchProgressSettings {
+    /// Receives a POST with a batch's counts once its last task finishes.
+    pub completion_webhook_url: Option<String>,
+}
+/// Endpoint of a cheap model used by auxiliary pipeline stages (tagging, labeling, ...).
+/// Delivery of the task and batch webhooks.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookSettings {
+    /// Key the request bodies are signed with; unsigned without one.
+    pub secret: Option<String>,
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuxModelSettings {
+    pub url: String,
+    pub api_key: String,
+    pub model: String,
+}
+
+/// API a provider endpoint speaks.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderApi {
+    /// OpenAI chat/legacy completions, authenticated with a bearer token.
+    #[default]
+    #[serde(rename = "openai_compatible")]
+    OpenAiCompatible,
+    /// Anthropic's Messages API, see `anthropic`.
+    Anthropic,
+}
+
+/// LLM endpoint a completion can be routed to. Tasks name theirs in `providers`;
+/// `LLM_PROVIDERS` lists the fallbacks tried after a task's own endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ProviderSettings {
+    /// Recorded on the event of the completions it serves; defaults to the URL's host.
+    #[serde(default)]
+    pub name: String,
+    pub url: String,
+    #[serde(default)]
+    pub api_key: String,
+    /// Model name at this provider, replacing the body's `model`.
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Legacy `/v1/completions` endpoint, if the provider still serves one; legacy
+    /// prompt bodies go there rather than being converted to chat.
+    #[serde(default)]
+    pub completions_url: Option<String>,
+    /// Named `provider` in tasks.
+    #[serde(default, alias = "provider")]
+    pub api: ProviderApi,
+}
+
+/// What becomes of a task whose provider is outside its dispatch window.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutsideWindow {
+    /// The task waits for the window to open.
+    #[default]
+    Hold,
+    /// The task goes on to its next provider.
+    Skip,
+}
+
+/// Daily hours a provider is sent requests in, e.g. an on-prem GPU cluster only used
+/// at night. `start` and `end` are `HH:MM` in `timezone`: `UTC` (the default),
+/// `local` for the consumer's time zone, or a fixed offset such as `+02:00`. A window
+/// ending before it starts spans midnight.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DispatchWindow {
+    pub start: String,
+    pub end: String,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub outside: OutsideWindow,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DispatchWindowSettings {
+    /// Windows by provider name (the URL's host for unnamed providers).
+    pub windows: HashMap<String, DispatchWindow>,
+    /// Longest a held task keeps its delivery; it's then handed back to the broker to
+    /// wait again, rather than outlast the broker's delivery timeout.
+    pub max_hold_secs: u64,
+}
+
+/// How requests to one upstream host are carried, for inference sidecars only reachable
+/// over a Unix domain socket or with a client certificate, and providers only reachable
+/// through an egress proxy. Only configured, never taken from task payloads.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TransportSettings {
+    /// Requests to this host (`host` or `host:port`) use the transport.
+    pub host: String,
+    /// Socket the requests go through instead of TCP; `https` URLs still use TLS over it.
+    pub unix_socket: Option<String>,
+    /// PEM certificate chain presented to the server.
+    pub client_cert: Option<String>,
+    /// PKCS#8 PEM key of `client_cert`.
+    pub client_key: Option<String>,
+    /// PEM CA bundle trusted for the server certificate, besides the system roots.
+    pub ca_cert: Option<String>,
+    /// `http://`, `https://`, `socks5://` or `socks5h://` proxy the requests go
+    /// through instead of `HTTP_PROXY_URL` and the environment's, or `none` to reach the
+    /// host directly.
+    pub proxy: Option<String>,
+    /// Credentials of `proxy`, unless given in its URL.
+    pub proxy_username: Option<String>,
+    pub proxy_password: Option<String>,
+}
+
+/// Retries of successful responses whose completion came back empty or truncated.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmptyCompletionSettings {
+    /// Completions with fewer characters are retried like transient errors; zero
+    /// accepts them.
+    pub min_chars: usize,
+    /// Re-send retries with a raised `temperature` and `max_tokens` rather than the
+    /// same body.
+    pub adjust_params: bool,
+    pub temperature_step: f64,
+    pub max_temperature: f64,
+    pub max_tokens_factor: f64,
+}
+
+impl EmptyCompletionSettings {
+    /// Retries empty completions with the same body.
+    pub const UNADJUSTED: Self = Self {
+        min_chars: 1,
+        adjust_params: false,
+        temperature_step: 0.0,
+        max_temperature: 0.0,
+        max_tokens_factor: 1.0,
+    };
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DifficultyMode {
+    Heuristic,
+    Model,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DifficultySettings {
+    pub mode: DifficultyMode,
+    pub model: Option<AuxModelSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JudgeSettings {
+    pub model: AuxModelSettings,
+    /// What the judge rates completions on.
+    pub rubric: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LabelingSettings {
+    pub labels: Vec<String>,
+    pub max_labels: usize,
+    pub model: AuxModelSettings,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BalanceSettings {
+    /// Target share of each label, e.g. `{"math": 0.3, "code": 0.7}`.
+    pub targets: std::collections::HashMap<String, f64>,
+    /// Allowed overshoot above a label's target share before rows are marked excess.
+    pub tolerance: f64,
+    /// Rows accepted unconditionally per batch before proportions are enforced.
+    pub min_samples: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ContaminationSettings {
+    /// Evaluation set files (JSONL or plain text, one example per line).
+    pub eval_sets: Vec<String>,
+    pub ngram_size: usize,
+    /// Share of an example's n-grams found in an eval set above which it is flagged.
+    pub threshold: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DiversitySettings {
+    /// Similarity of two samples of a prompt from which they're near duplicates.
+    pub threshold: f64,
+    pub ngram_size: usize,
+    /// Earlier samples of the prompt compared with a new generation.
+    pub group_samples: usize,
+    /// Generations made again while their samples are near duplicates; 0 only annotates.
+    pub regenerate_attempts: u32,
+}
+
+/// Near-duplicate detection of completions across a batch, see `batch_dedup`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchDedupSettings {
+    /// Estimated similarity to an earlier completion from which one is a near duplicate.
+    pub threshold: f64,
+    pub ngram_size: usize,
+    /// Generations made again while their completion is a near duplicate; 0 only
+    /// annotates.
+    pub regenerate_attempts: u32,
+}
+
+/// End-to-end encryption of task payloads, see `payload_encryption`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PayloadEncryptionSettings {
+    /// age identity file the consumer decrypts payloads with.
+    pub identity_file: Option<String>,
+    /// age X25519 recipients (`age1...`) producers encrypt payloads to.
+    pub recipients: Vec<String>,
+    /// Dead-letters tasks whose payload isn't encrypted.
+    pub required: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ContentFilterSettings {
+    /// Built-in PII patterns redacted from completions: `email`, `phone`,
+    /// `credit_card`, `ssn` and `ip_address`.
+    pub pii: Vec<String>,
+    /// Further redaction patterns (regular expressions) by name.
+    pub patterns: HashMap<String, String>,
+    /// Terms flagging a completion, matched as whole words regardless of case.
+    pub blocked_terms: Vec<String>,
+    /// OpenAI-compatible `/v1/moderations` endpoint the sanitized completion is sent to.
+    pub moderation: Option<AuxModelSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CorpusDedupSettings {
+    /// Files of hex SHA-256 digests (one per line) or `.bloom` filters of existing corpora.
+    pub hash_files: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum EmbeddingProviderKind {
+    OpenAi,
+    Cohere,
+    Local,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmbeddingSettings {
+    pub provider: EmbeddingProviderKind,
+    /// Embeddings endpoint for remote providers, or the model directory for `Local`.
+    pub url: String,
+    pub api_key: String,
+    pub model: String,
+    /// Maximum number of texts sent in one provider call.
+    pub batch_size: usize,
+    /// How long a partial batch waits for more texts before being sent.
+    pub batch_wait_ms: u64,
+    /// Number of embeddings kept in the in-memory cache; 0 disables caching.
+    pub cache_capacity: usize,
+}
+
+/// In-process auxiliary models, only used when built with the `candle` feature.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LocalModelSettings {
+    pub language_model_dir: Option<String>,
+    pub quality_model_dir: Option<String>,
+    /// Label whose probability is reported as the quality score; defaults to the last label.
+    pub quality_positive_label: Option<String>,
+}
+
+/// When a task's delivery is settled relative to the work it triggers.
+///
+/// Whatever the policy, malformed messages and messages past
+/// `MAX_DELIVERY_ATTEMPTS` are dead-lettered on receipt, and LLM failures that survive
+/// the in-process retries are recorded as FAILED and never requeued, since another
+/// delivery would only repeat the same calls.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AckPolicy {
+    /// Ack once the result is stored (default): at-least-once. A crash or a failed
+    /// status write requeues the task until it runs out of attempts, and LLM failures
+    /// go to the dead-letter queue.
+    AfterPersist,
+    /// Ack as soon as the cache missed, before calling the LLM: at-most-once. Nothing
+    /// is redelivered or dead-lettered afterwards, so a crash or failed write loses the
+    /// result. Meant for cheap, idempotent tasks where a gap is cheaper than a retry.
+    BeforeLlm,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AckSettings {
+    pub default: AckPolicy,
+    /// Overrides keyed by the task's `task_type` payload field.
+    pub by_task_type: std::collections::HashMap<String, AckPolicy>,
+}
+
+/// How a delivery is checked against its event before any work, so a redelivery of a
+/// task that already completed (say after a crash between the status write and the
+/// ack) doesn't pay for the LLM call again.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DedupMode {
+    /// No lookup; every delivery is processed.
+    Off,
+    /// Deliveries of COMPLETED events are acked without processing (default). When the
+    /// lookup fails the task is processed anyway.
+    Lenient,
+    /// Like `Lenient`, but a failed lookup requeues the delivery rather than risk a
+    /// duplicate call.
+    Strict,
+}
+
+/// How the consumer treats the `body_hash` producers send, see `cache_key`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum CacheKeyMode {
+    /// Tasks are keyed on the producer's hash (default).
+    #[default]
+    Trust,
+    /// Tasks whose hash doesn't match the recomputed key are dead-lettered.
+    Verify,
+    /// Tasks are keyed on the recomputed key, whatever the producer sent.
+    Replace,
+}
+
+/// Hash of task keys and stored completions, see `hashing`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct CacheKeySettings {
+    pub mode: CacheKeyMode,
+    /// Algorithm recomputed keys are hashed with.
+    pub hash: HashAlgorithm,
+    /// Fields of bodies and template variables left out of the key.
+    pub excluded_fields: Vec<String>,
+    /// Keys templated tasks on the semantic hash of their template.
+    pub semantic_templates: bool,
+}
+
+/// Which events get the `debug_trace` of their processing stored. Tasks with
+/// `"debug": true` are traced whatever the mode.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DebugTraceMode {
+    Off,
+    /// Only failed and timed-out tasks (default).
+    Failed,
+    All,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PipelineMode {
+    /// Each delivery runs all stages in a single task (default).
+    Sequential,
+    /// Stages are decoupled by bounded channels with per-stage parallelism.
+    Staged,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PipelineSettings {
+    pub mode: PipelineMode,
+    pub channel_capacity: usize,
+    pub cache_workers: usize,
+    pub llm_workers: usize,
+    pub postprocess_workers: usize,
+    pub persist_workers: usize,
+}
+
+/// HTTP API of the `synthgen-api` binary.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiSettings {
+    pub listen_addr: String,
+    /// Bearer token required on every request except the health check, when set.
+    pub secret_key: Option<String>,
+    /// Most tasks a single submission may contain.
+    pub max_batch_tasks: usize,
+}
+
+/// When a feature flag is on. All conditions that are set have to hold.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FeatureFlagRule {
+    #[serde(default = "default_flag_enabled")]
+    pub enabled: bool,
+    /// Runs (`batch_id`s) the flag is limited to, when not empty.
+    #[serde(default)]
+    pub batches: Vec<String>,
+    /// Tenants, from the task's `tenant` field, the flag is limited to, when not empty.
+    #[serde(default)]
+    pub tenants: Vec<String>,
+    /// Share of tasks, in percent, the flag is on for.
+    #[serde(default)]
+    pub percentage: Option<f64>,
+}
+
+fn default_flag_enabled() -> bool {
+    true
+}
+
+/// Elasticsearch document holding feature flag rules under `flags`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FeatureFlagDocument {
+    pub index: String,
+    pub id: String,
+    pub refresh_secs: u64,
+}
+
+/// Identity and heartbeats of this consumer process, see `workers`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WorkerSettings {
+    pub id: String,
+    pub hostname: String,
+    /// Seconds between heartbeats; 0 writes none.
+    pub heartbeat_secs: u64,
+    /// Silence after which a running worker is considered dead.
+    pub stale_after_secs: u64,
+}
+
+/// Tasks run in-process from a JSONL file, see `standalone`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StandaloneSettings {
+    /// File the tasks are read from; stdin when unset.
+    pub input: Option<String>,
+    /// Batch the tasks are submitted as.
+    pub batch_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FeatureFlagSettings {
+    pub rules: HashMap<String, FeatureFlagRule>,
+    pub document: Option<FeatureFlagDocument>,
+}
+
+/// Elasticsearch document holding payload schemas by task type under `schemas`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SchemaDocument {
+    pub index: String,
+    pub id: String,
+    pub refresh_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SchemaRegistrySettings {
+    /// Payload JSON Schemas by `task_type`.
+    pub schemas: HashMap<String, Value>,
+    /// Rejects tasks without a registered `task_type` instead of passing them unchecked.
+    pub strict: bool,
+    pub document: Option<SchemaDocument>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum BrokerKind {
+    RabbitMq,
+    Kafka,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AmqpSettings {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    /// Virtual host holding the queues.
+    pub vhost: String,
+    /// Connects over `amqps://` when set, as managed brokers require.
+    pub tls: Option<AmqpTlsSettings>,
+}
+
+/// TLS of the RabbitMQ connection.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AmqpTlsSettings {
+    /// PEM CA bundle trusted for the broker's certificate, besides the system roots.
+    pub ca_cert: Option<String>,
+    /// PKCS#12 bundle of the certificate chain and key presented to the broker.
+    pub client_identity: Option<String>,
+    /// Password of the `client_identity` bundle, empty when unset.
+    pub client_identity_password: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KafkaSettings {
+    /// Comma-separated `host:port` bootstrap servers.
+    pub brokers: String,
+    pub group_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BrokerSettings {
+    pub kind: BrokerKind,
+    /// RabbitMQ queue or Kafka topic carrying the tasks.
+    pub queue: String,
+    /// Maximum number of unacknowledged messages delivered to this consumer.
+    pub prefetch: u16,
+    /// Queue/topic receiving messages that can't be processed; dropped when unset.
+    pub dead_letter_queue: Option<String>,
+    /// Deliveries after which a message that keeps failing is dead-lettered.
+    pub max_delivery_attempts: u32,
+    /// Delay before a requeued message is delivered again, indexed by the attempt that
+    /// failed; the last entry applies to later attempts. Empty requeues immediately.
+    pub requeue_delays_ms: Vec<u64>,
+    /// Rate limits asking to wait at least this long hand the task back to the broker
+    /// through a delay queue instead of holding its worker slot; RabbitMQ only. Unset
+    /// waits them out.
+    pub defer_after_ms: Option<u64>,
+    /// Delays of the holding queues deferred tasks go through: the shortest covering
+    /// the rate limit, or else the longest.
+    pub defer_delays_ms: Vec<u64>,
+    /// Highest priority of the RabbitMQ task queue (`x-max-priority`); 0, the default,
+    /// declares it without priorities. Must match the API's declaration, and an existing
+    /// task queue has to be deleted (once drained) before priorities are turned on.
+    pub max_priority: u8,
+    /// Splits the work between replicas by task hash; RabbitMQ only.
+    pub partitioning: Option<PartitionSettings>,
+    pub amqp: AmqpSettings,
+    pub kafka: KafkaSettings,
+}
+
+/// Work partitioning between replicas, with membership tracked through Kubernetes
+/// Lease objects.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PartitionSettings {
+    /// Replicas of the same group split the work between them.
+    pub group: String,
+    /// Name of this replica within the group, usually the pod name.
+    pub identity: String,
+    pub namespace: String,
+    /// A member whose lease hasn't been renewed for this long is considered gone.
+    pub lease_duration_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum HttpProfile {
+    /// reqwest defaults.
+    #[default]
+    Default,
+    /// Large per-host pools, long-lived keep-alive and cached DNS, for thousands of
+    /// concurrent upstream connections.
+    HighConcurrency,
+}
+
+/// HTTP versions the upstream clients speak.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum HttpVersion {
+    /// HTTP/2 where TLS negotiates it, HTTP/1.1 otherwise.
+    #[default]
+    Auto,
+    Http1Only,
+    /// HTTP/2 without negotiation, also over plain TCP (h2c), e.g. to a local vLLM.
+    Http2PriorKnowledge,
+}
+
+/// Upstream HTTP client. The pool size and connect timeout default to the profile's;
+/// the other settings apply under any profile.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpSettings {
+    pub profile: HttpProfile,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout_secs: u64,
+    pub tcp_keepalive_secs: u64,
+    pub http2_keep_alive_interval_secs: u64,
+    pub connect_timeout_ms: Option<u64>,
+    /// Longest wait for the next bytes of a response; unset waits for as long as the
+    /// server takes.
+    pub read_timeout_secs: Option<u64>,
+    pub version: HttpVersion,
+    /// `http://`, `https://` or `socks5://` proxy all upstream requests go through,
+    /// instead of the one of the `HTTPS_PROXY`/`HTTP_PROXY` environment variables.
+    pub proxy_url: Option<String>,
+    /// Comma-separated hosts, domains and CIDR ranges reached without `proxy_url`.
+    pub no_proxy: Option<String>,
+    /// PEM CA bundle trusted for upstream servers and proxies, besides the system roots.
+    pub ca_bundle: Option<String>,
+    /// Number of cached DNS lookups; 0 keeps the system resolver.
+    pub dns_cache_size: usize,
+    /// Lower bound on how long a DNS answer is cached, whatever its TTL.
+    pub dns_min_ttl_secs: u64,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        Self {
+            profile: HttpProfile::Default,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout_secs: 90,
+            tcp_keepalive_secs: 60,
+            http2_keep_alive_interval_secs: 30,
+            connect_timeout_ms: None,
+            read_timeout_secs: None,
+            version: HttpVersion::Auto,
+            proxy_url: None,
+            no_proxy: None,
+            ca_bundle: None,
+            dns_cache_size: 4096,
+            dns_min_ttl_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum IpFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+/// Name resolution of the upstream HTTP and broker connections, for networks where the
+/// system resolver can't be used as is.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NetworkSettings {
+    /// Only connects over this address family.
+    pub ip_family: IpFamily,
+    /// Nameservers queried instead of the system ones.
+    pub dns_servers: Vec<SocketAddr>,
+    /// Addresses used for these hosts without any lookup.
+    pub host_overrides: HashMap<String, IpAddr>,
+}
+
+impl NetworkSettings {
+    /// Whether names resolve like the system resolver would.
+    pub fn is_system_default(&self) -> bool {
+        self.ip_family == IpFamily::Any
+            && self.dns_servers.is_empty()
+            && self.host_overrides.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchApiSettings {
+    /// Whether tasks use the batch API when their payload doesn't set `use_batch_api`.
+    pub default_enabled: bool,
+    pub max_batch_size: usize,
+    /// How long a partial batch waits for more tasks before being submitted.
+    pub max_wait_secs: u64,
+    pub poll_interval_secs: u64,
+    pub completion_window: String,
+}
+
+/// Settings of the consumer, loaded by `Settings::new` from the environment.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Settings {
+    pub site_url: String,
+    pub site_name: String,
+    pub retry_attempts: u32,
+    pub base_delay_ms: u64,
+    pub storage: StorageSettings,
+    pub broker: BrokerSettings,
+    pub max_parallel_tasks: usize,
+    pub max_delay_secs: u64,
+    pub difficulty: Option<DifficultySettings>,
+    pub labeling: Option<LabelingSettings>,
+    /// Model grading answers of tasks evaluated by `judge`.
+    pub evaluation_judge: Option<AuxModelSettings>,
+    /// Model scoring every completion against a rubric.
+    pub quality_judge: Option<JudgeSettings>,
+    pub balance: Option<BalanceSettings>,
+    pub contamination: Option<ContaminationSettings>,
+    pub content_filter: Option<ContentFilterSettings>,
+    pub corpus_dedup: Option<CorpusDedupSettings>,
+    pub diversity: Option<DiversitySettings>,
+    pub batch_dedup: Option<BatchDedupSettings>,
+    pub embedding: Option<EmbeddingSettings>,
+    pub index_completion_embeddings: bool,
+    /// Index the vectors of `embedding` tasks are written to instead of their events;
+    /// on PostgreSQL, their `index_name` in the `embeddings` table.
+    pub embedding_task_index: Option<String>,
+    /// Keeps each generation on the task's event until its final status, for
+    /// redeliveries to resume from.
+    pub checkpoint_generations: bool,
+    /// Generated responses collected per body sampled with a temperature above zero
+    /// before duplicates reuse them; 0 keeps such bodies out of the response cache.
+    pub stochastic_cache_samples: usize,
+    pub resume: ResumeSettings,
+    pub queue_aging: QueueAgingSettings,
+    pub export: ExportSettings,
+    pub local_models: LocalModelSettings,
+    pub pipeline: PipelineSettings,
+    pub feature_flags: FeatureFlagSettings,
+    pub worker: WorkerSettings,
+    /// Runs the tasks of a local file through the pipeline without a broker when set.
+    pub standalone: Option<StandaloneSettings>,
+    pub schema_registry: SchemaRegistrySettings,
+    pub payload_encryption: PayloadEncryptionSettings,
+    /// Request parameters merged into the bodies of each task type, `*` for all.
+    pub parameter_policies: HashMap<String, Value>,
+    /// Directory of the named templates task bodies can be rendered from.
+    pub prompt_templates_dir: Option<String>,
+    pub http: HttpSettings,
+    pub network: NetworkSettings,
+    pub webhooks: WebhookSettings,
+    pub worker_shards: usize,
+    pub pin_shards: bool,
+    pub shutdown_timeout_secs: u64,
+    /// Hand prefetched deliveries that no task started on back to the broker as soon
+    /// as consumption stops, instead of when the drain is over.
+    pub handoff_prefetched: bool,
+    /// Start without consuming; SIGUSR1 toggles it at runtime.
+    pub maintenance_mode: bool,
+    pub batch_api: Option<BatchApiSettings>,
+    pub ack: AckSettings,
+    pub dedup_mode: DedupMode,
+    pub cache_key: CacheKeySettings,
+    pub debug_trace: DebugTraceMode,
+    pub model_prices: HashMap<String, ModelPrice>,
+    pub energy: EnergySettings,
+    pub rate_limits: Vec<RateLimitSettings>,
+    /// Request and token rates per organization or project, whose scope (`acme`,
+    /// `acme/search`) is the `pattern`, see `attribution`.
+    pub quotas: Vec<RateLimitSettings>,
+    /// In-flight request caps per route, within `max_parallel_tasks`.
+    pub concurrency_limits: Vec<ConcurrencyLimitSettings>,
+    /// Response size budgets per model and reporting of payload sizes.
+    pub payloads: PayloadSettings,
+    pub stored_sizes: StoredSizeSettings,
+    pub circuit_breaker: CircuitBreakerSettings,
+    /// Headers of the idempotency keys sent with generation requests.
+    pub idempotency: IdempotencySettings,
+    /// Answers LLM calls without network calls when set.
+    pub mock_llm: Option<MockLlmSettings>,
+    /// Shares rate limits between replicas when set.
+    pub redis_url: Option<String>,
+    pub validation_repair_attempts: u32,
+    pub empty_completion: EmptyCompletionSettings,
+    pub min_quality_score: Option<f64>,
+    /// Deadline for generating and postprocessing a task; zero disables it.
+    pub task_timeout_secs: u64,
+    /// Providers a completion falls back to when the task's own endpoint fails.
+    pub fallback_providers: Vec<ProviderSettings>,
+    pub dispatch_windows: DispatchWindowSettings,
+    pub transports: Vec<TransportSettings>,
+    pub api: ApiSettings,
+}
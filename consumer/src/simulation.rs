@@ -0,0 +1,210 @@
+//! Offline replay of recorded request traces against retry and rate-limit policies,
+//! to estimate throughput, failure rate and cost before changing `RETRY_ATTEMPTS` or
+//! the backoff settings in production.
+//!
+//! A trace is a JSONL file with one observed request attempt per line:
+//!
+//! ```json
+//! {"latency_ms": 840, "status": 200, "prompt_tokens": 52, "completion_tokens": 310}
+//! {"latency_ms": 95, "status": 429, "retry_after": "4"}
+//! {"latency_ms": 30000, "status": 504}
+//! {"latency_ms": 610, "status": 200, "body": {"error": {"code": 502, "message": "Provider returned error"}}}
+//! ```
+//!
+//! Attempts consume trace records in order, wrapping around, so the replay keeps the
+//! trace's error mix and its bursts.
+
+use crate::llm_wrapper::{self, Failure};
+use crate::pricing;
+use crate::schemas::provider_response::Usage;
+use crate::settings::ModelPrice;
+use serde::Deserialize;
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TraceRecord {
+    pub latency_ms: u64,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    /// Raw `Retry-After` header of a 429.
+    #[serde(default)]
+    pub retry_after: Option<String>,
+    /// Response body, for errors reported inside a 200.
+    #[serde(default)]
+    pub body: Value,
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+pub fn load_trace(
+    path: &str,
+) -> Result<Vec<TraceRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut records = Vec::new();
+    for (line_number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(
+            serde_json::from_str(line)
+                .map_err(|e| format!("{}:{}: {}", path, line_number + 1, e))?,
+        );
+    }
+    if records.is_empty() {
+        return Err(format!("{} has no trace records", path).into());
+    }
+    Ok(records)
+}
+
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub retry_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_secs: u64,
+    /// Concurrent tasks, as `MAX_PARALLEL_TASKS`.
+    pub concurrency: usize,
+    /// Client-side cap on request starts per minute.
+    pub rate_limit_rpm: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub tasks: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub attempts: u64,
+    pub makespan: Duration,
+    pub p50_latency: Duration,
+    pub p95_latency: Duration,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+impl Report {
+    pub fn throughput(&self) -> f64 {
+        let secs = self.makespan.as_secs_f64();
+        if secs > 0.0 {
+            self.succeeded as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Outcome of one attempt under the consumer's classification.
+fn classify(record: &TraceRecord) -> Option<Failure> {
+    if (200..300).contains(&record.status) {
+        llm_wrapper::classify_body(&record.body, 0)
+    } else {
+        Some(llm_wrapper::classify_status(
+            record.status,
+            record.retry_after.as_deref(),
+            "",
+        ))
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[index]
+}
+
+/// Simulates `tasks` tasks under `policy`. Like the consumer, a task holds its slot
+/// through retries and backoff sleeps.
+pub fn simulate(trace: &[TraceRecord], tasks: usize, policy: &Policy, price: ModelPrice) -> Report {
+    let min_spacing = policy
+        .rate_limit_rpm
+        .filter(|rpm| *rpm > 0)
+        .map(|rpm| Duration::from_secs(60).div_f64(rpm as f64));
+
+    // Time at which each slot frees up.
+    let mut slots: BinaryHeap<Reverse<Duration>> = (0..policy.concurrency.max(1))
+        .map(|_| Reverse(Duration::ZERO))
+        .collect();
+    let mut next_request = Duration::ZERO;
+    let mut cursor = 0usize;
+
+    let mut latencies = Vec::with_capacity(tasks);
+    let mut report = Report {
+        tasks,
+        succeeded: 0,
+        failed: 0,
+        attempts: 0,
+        makespan: Duration::ZERO,
+        p50_latency: Duration::ZERO,
+        p95_latency: Duration::ZERO,
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        cost: 0.0,
+    };
+
+    for _ in 0..tasks {
+        let Reverse(started) = slots.pop().unwrap_or(Reverse(Duration::ZERO));
+        let mut now = started;
+        let mut delays = llm_wrapper::retry_strategy(
+            policy.base_delay_ms,
+            policy.max_delay_secs,
+            policy.retry_attempts,
+        );
+
+        loop {
+            if let Some(spacing) = min_spacing {
+                now = now.max(next_request);
+                next_request = now + spacing;
+            }
+            let record = &trace[cursor % trace.len()];
+            cursor += 1;
+            report.attempts += 1;
+            now += Duration::from_millis(record.latency_ms);
+
+            let failure = match classify(record) {
+                None => {
+                    report.succeeded += 1;
+                    report.prompt_tokens += record.prompt_tokens;
+                    report.completion_tokens += record.completion_tokens;
+                    break;
+                }
+                Some(failure) => failure,
+            };
+            let delay = match (&failure, delays.next()) {
+                (Failure::Permanent(_), _) | (_, None) => {
+                    report.failed += 1;
+                    break;
+                }
+                (Failure::RateLimited { retry_after, .. }, Some(_)) => *retry_after,
+                (Failure::Transient(_), Some(delay)) => delay,
+            };
+            now += delay;
+        }
+
+        latencies.push(now - started);
+        report.makespan = report.makespan.max(now);
+        slots.push(Reverse(now));
+    }
+
+    latencies.sort();
+    report.p50_latency = percentile(&latencies, 0.5);
+    report.p95_latency = percentile(&latencies, 0.95);
+    report.cost = pricing::cost(
+        &price,
+        &Usage {
+            prompt_tokens: report.prompt_tokens,
+            completion_tokens: report.completion_tokens,
+            total_tokens: report.prompt_tokens + report.completion_tokens,
+        },
+    );
+    report
+}
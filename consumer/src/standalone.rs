@@ -0,0 +1,98 @@
+//! Standalone mode (`STANDALONE=true`), for jobs too small to be worth standing up a
+//! broker for.
+//!
+//! Tasks are read as JSONL from `STANDALONE_INPUT`, or stdin, one task per line as
+//! submitted to the API, and submitted as the batch `STANDALONE_BATCH_ID`: their events
+//! are created in the configured store, then their messages are handed to an
+//! in-process broker (`broker::local`) and run through the same pipeline as queued
+//! tasks, `MAX_PARALLEL_TASKS` at a time. Results go to the store, which with
+//! `STORAGE_BACKEND=jsonl` writes them to a local JSONL file. The consumer exits once
+//! every task has finished. Lines that aren't valid tasks are logged and skipped.
+
+use crate::db::TaskStore;
+use crate::producer::{self, PreparedTask};
+use serde_json::Value;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+/// Tasks whose events are created in one request.
+const CHUNK_SIZE: usize = 500;
+
+pub type StandaloneResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FeedReport {
+    /// Tasks handed to the broker.
+    pub submitted: usize,
+    /// Lines skipped as invalid tasks.
+    pub rejected: usize,
+}
+
+/// Reader of the tasks file, or of stdin without one.
+pub async fn open(input: Option<&str>) -> StandaloneResult<Box<dyn AsyncBufRead + Send + Unpin>> {
+    Ok(match input {
+        Some(path) => Box::new(BufReader::new(tokio::fs::File::open(path).await?)),
+        None => Box::new(BufReader::new(tokio::io::stdin())),
+    })
+}
+
+/// Task of a line, or why it's rejected.
+fn parse(batch_id: &str, line: &str) -> Result<PreparedTask, String> {
+    let task: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    // Tasks are delivered in order, so priorities don't apply.
+    producer::prepare(batch_id, task, 0)
+}
+
+/// Creates the events of `chunk` and sends their messages, emptying it. Returns false
+/// once the broker no longer takes tasks.
+async fn submit(
+    store: &dyn TaskStore,
+    tasks: &mpsc::Sender<Vec<u8>>,
+    chunk: &mut Vec<PreparedTask>,
+    report: &mut FeedReport,
+) -> StandaloneResult<bool> {
+    let events: Vec<Value> = chunk.iter().map(|task| task.event.clone()).collect();
+    store.create_events(&events).await?;
+    for task in chunk.drain(..) {
+        if tasks.send(task.message).await.is_err() {
+            return Ok(false);
+        }
+        report.submitted += 1;
+    }
+    Ok(true)
+}
+
+/// Submits the tasks read from `input` as `batch_id` to the broker taking them on
+/// `tasks`, and closes its input once they're all sent.
+pub async fn feed(
+    input: impl AsyncBufRead + Unpin,
+    batch_id: &str,
+    store: &dyn TaskStore,
+    tasks: mpsc::Sender<Vec<u8>>,
+) -> StandaloneResult<FeedReport> {
+    let mut report = FeedReport::default();
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    let mut lines = input.lines();
+    let mut line_number = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_number += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse(batch_id, &line) {
+            Ok(task) => chunk.push(task),
+            Err(e) => {
+                tracing::error!("Skipping task on line {}: {}", line_number, e);
+                report.rejected += 1;
+                continue;
+            }
+        }
+        if chunk.len() == CHUNK_SIZE && !submit(store, &tasks, &mut chunk, &mut report).await? {
+            return Ok(report);
+        }
+    }
+    if !chunk.is_empty() {
+        submit(store, &tasks, &mut chunk, &mut report).await?;
+    }
+    Ok(report)
+}
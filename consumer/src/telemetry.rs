@@ -0,0 +1,163 @@
+//! Logging and tracing setup shared by the binaries, and trace context propagation.
+//!
+//! Logs always go to stdout. Built with the `otel` feature and with
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` set, spans are also exported over OTLP/HTTP. The W3C
+//! `traceparent` the producer puts in a message's headers makes the consumer's spans
+//! of that task children of the producer's, and is passed on to the LLM endpoint, so
+//! a single task can be followed from submission to its completion in Jaeger or Tempo.
+
+use std::collections::BTreeMap;
+use tracing::Span;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// Handle on the span exporter, if any, which flushes it when dropped; binaries hold
+/// it until they exit, whichever way they return.
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    /// Exports the spans still buffered.
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                tracing::warn!("Failed to flush exported spans: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber: logs filtered by `RUST_LOG` (INFO by default) and,
+/// when configured, span export under `service_name` unless `OTEL_SERVICE_NAME` is set.
+pub fn init(service_name: &str) -> Telemetry {
+    let filter = EnvFilter::from_default_env().add_directive(tracing::Level::INFO.into());
+    let logs = fmt::layer()
+        .with_target(true)
+        .with_thread_ids(true)
+        .with_line_number(true)
+        .with_file(true);
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry::trace::TracerProvider;
+
+        let (provider, error) = match otel::provider(service_name) {
+            Ok(provider) => (provider, None),
+            Err(e) => (None, Some(e)),
+        };
+        let spans = provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(provider.tracer("synthgen"))
+        });
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(logs)
+            .with(spans)
+            .init();
+        if let Some(e) = error {
+            tracing::warn!(
+                "Span export disabled, failed to build the OTLP exporter: {}",
+                e
+            );
+        }
+        Telemetry { provider }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = service_name;
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(logs)
+            .init();
+        Telemetry {}
+    }
+}
+
+/// Makes `span` a child of the trace context carried in `headers`, if any.
+pub fn set_parent(span: &Span, headers: &BTreeMap<String, String>) {
+    #[cfg(feature = "otel")]
+    {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&otel::Headers(headers))
+        });
+        // Fails only without the OpenTelemetry layer, when there is nothing to link.
+        let _ = span.set_parent(parent);
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, headers);
+}
+
+/// Adds the trace context of `span` to `headers`, so the receiver continues the trace.
+pub fn inject(span: &Span, headers: &mut BTreeMap<String, String>) {
+    #[cfg(feature = "otel")]
+    {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let context = span.context();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut otel::HeadersMut(headers))
+        });
+    }
+    #[cfg(not(feature = "otel"))]
+    let _ = (span, headers);
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::propagation::{Extractor, Injector};
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use std::collections::BTreeMap;
+    use std::env;
+
+    /// Tracer provider exporting to the OTLP endpoint, or `None` when none is set.
+    pub fn provider(
+        service_name: &str,
+    ) -> Result<Option<SdkTracerProvider>, opentelemetry_otlp::ExporterBuildError> {
+        if env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err()
+            && env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_err()
+        {
+            return Ok(None);
+        }
+        // The exporter reads the endpoint, headers and timeout from the OTEL_* variables.
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .build()?;
+        let service_name =
+            env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| service_name.to_string());
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name(service_name).build())
+            .build();
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        opentelemetry::global::set_tracer_provider(provider.clone());
+        Ok(Some(provider))
+    }
+
+    pub struct Headers<'a>(pub &'a BTreeMap<String, String>);
+
+    impl Extractor for Headers<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0.get(key).map(String::as_str)
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.keys().map(String::as_str).collect()
+        }
+    }
+
+    pub struct HeadersMut<'a>(pub &'a mut BTreeMap<String, String>);
+
+    impl Injector for HeadersMut<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            self.0.insert(key.to_string(), value);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Task bodies rendered from a `template` and its `variables`, so producers send
+//! compact rows and the prompts themselves are versioned with synthgen.
+//!
+//! A template is the request body with minijinja templates for strings, given inline
+//! or by the name of a `<name>.json` file of `PROMPT_TEMPLATES_DIR`. A string holding
+//! a single `{{ expression }}` is replaced by the expression's value, so arrays and
+//! objects such as few-shot messages can come from variables too. Undefined variables
+//! are errors. Tasks are hashed on their template and variables rather than on the
+//! rendered body, so a changed template needs a new name for its completions not to be
+//! served from the cache. With `CACHE_KEY_SEMANTIC_TEMPLATES`, the consumer keys them
+//! on the `semantic_hash` of the template they resolve to instead, see `cache_key`:
+//! edits change the key, cosmetic whitespace doesn't.
+
+use crate::producer;
+use minijinja::{Environment, UndefinedBehavior};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+pub struct PromptTemplates {
+    env: Environment<'static>,
+    named: HashMap<String, Value>,
+}
+
+impl PromptTemplates {
+    pub fn new(named: HashMap<String, Value>) -> Self {
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        Self { env, named }
+    }
+
+    /// Templates of the `*.json` files in `dir`, named after the files.
+    pub fn load(dir: Option<&str>) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let mut named = HashMap::new();
+        if let Some(dir) = dir {
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+                let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                    continue;
+                };
+                let template = serde_json::from_slice(&std::fs::read(&path)?)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                named.insert(name.to_string(), template);
+            }
+            tracing::info!("Loaded {} prompt templates from {}", named.len(), dir);
+        }
+        Ok(Self::new(named))
+    }
+
+    /// Renders the payload's `template`, if any, into its `body`.
+    pub fn render_payload(&self, payload: &mut Value) -> Result<(), String> {
+        let Some(template) = payload.get("template").filter(|t| !t.is_null()) else {
+            return Ok(());
+        };
+        let body = self.render(template, &payload["variables"])?;
+        payload["body"] = body;
+        Ok(())
+    }
+
+    /// Body rendered from `template`, a template name or an inline template.
+    pub fn render(&self, template: &Value, variables: &Value) -> Result<Value, String> {
+        let template = self.resolve(template)?;
+        let context = minijinja::Value::from_serialize(variables);
+        self.render_value(template, &context)
+    }
+
+    /// Hash of the template `template` resolves to, the same for templates differing
+    /// only in whitespace: runs of whitespace count as one space, and none is needed
+    /// at the ends of strings or inside `{{ }}` and `{% %}` delimiters.
+    pub fn semantic_hash(&self, template: &Value) -> Result<String, String> {
+        Ok(producer::body_hash(&normalize(self.resolve(template)?)))
+    }
+
+    fn resolve<'t>(&'t self, template: &'t Value) -> Result<&'t Value, String> {
+        match template {
+            Value::String(name) => self
+                .named
+                .get(name)
+                .ok_or_else(|| format!("Unknown prompt template `{}`", name)),
+            Value::Object(_) => Ok(template),
+            _ => Err("`template` must be a template name or an object".to_string()),
+        }
+    }
+
+    fn render_value(&self, value: &Value, context: &minijinja::Value) -> Result<Value, String> {
+        match value {
+            Value::String(source) => self.render_string(source, context),
+            Value::Array(items) => items
+                .iter()
+                .map(|item| self.render_value(item, context))
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+            Value::Object(fields) => fields
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), self.render_value(value, context)?)))
+                .collect::<Result<Map<_, _>, String>>()
+                .map(Value::Object),
+            other => Ok(other.clone()),
+        }
+    }
+
+    fn render_string(&self, source: &str, context: &minijinja::Value) -> Result<Value, String> {
+        let Some(expression) = single_expression(source) else {
+            return self
+                .env
+                .render_str(source, context)
+                .map(Value::String)
+                .map_err(|e| e.to_string());
+        };
+        let value = self
+            .env
+            .compile_expression(expression)
+            .and_then(|expression| expression.eval(context))
+            .map_err(|e| e.to_string())?;
+        if value.is_undefined() {
+            return Err(format!("`{}` is undefined", expression));
+        }
+        serde_json::to_value(&value).map_err(|e| e.to_string())
+    }
+}
+
+/// `template` with the whitespace of its strings normalized.
+fn normalize(template: &Value) -> Value {
+    match template {
+        Value::String(source) => {
+            let mut source = source.split_whitespace().collect::<Vec<_>>().join(" ");
+            for (spaced, tight) in [("{{ ", "{{"), (" }}", "}}"), ("{% ", "{%"), (" %}", "%}")] {
+                source = source.replace(spaced, tight);
+            }
+            Value::String(source)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(normalize).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), normalize(value)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+/// Expression of a string that is a single `{{ expression }}`.
+fn single_expression(source: &str) -> Option<&str> {
+    let inner = source.trim().strip_prefix("{{")?.strip_suffix("}}")?;
+    let inner = inner.trim_start_matches('-').trim_end_matches('-').trim();
+    let nested = ["{{", "}}", "{%", "%}", "{#"]
+        .iter()
+        .any(|delimiter| inner.contains(delimiter));
+    (!nested && !inner.is_empty()).then_some(inner)
+}
@@ -0,0 +1,574 @@
+//! The consumer loop and the pipeline run against the in-process broker of standalone
+//! mode, wrapped to record how each delivery is settled, with the mock LLM backend and
+//! the JSONL store.
+
+use super::*;
+use async_trait::async_trait;
+use chrono::Utc;
+use consumer::broker::{BrokerMessage, BrokerResult, MessageStream, Receipt, ATTEMPT_HEADER};
+use consumer::db::jsonl::JsonlStore;
+use consumer::db::TaskStore;
+use consumer::producer::{self, PreparedTask};
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::task_status::TaskStatus;
+use consumer::settings::{
+    DedupMode, DispatchWindow, DispatchWindowSettings, MockLlmSettings, OutsideWindow,
+};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+/// How a delivery was settled.
+#[derive(Debug, PartialEq)]
+enum Settled {
+    Acked,
+    Nacked,
+    Requeued,
+    /// With the headers of the dead-lettered copy.
+    DeadLettered(BTreeMap<String, String>),
+    Released,
+    Deferred,
+}
+
+/// Local broker recording the settlements of its deliveries with their data.
+struct Recording {
+    local: Arc<LocalBroker>,
+    queue: String,
+    settled: Mutex<Vec<(Vec<u8>, Settled)>>,
+}
+
+impl Recording {
+    fn new(settings: &Settings) -> (Arc<Self>, mpsc::Sender<Vec<u8>>) {
+        let (local, tasks) = LocalBroker::new(16, vec![]);
+        let broker = Arc::new(Self {
+            local: Arc::new(local),
+            queue: settings.broker.queue.clone(),
+            settled: Mutex::new(Vec::new()),
+        });
+        (broker, tasks)
+    }
+
+    fn record(&self, message: &BrokerMessage, settled: Settled) {
+        self.settled
+            .lock()
+            .unwrap()
+            .push((message.data.clone(), settled));
+    }
+
+    fn settled(&self) -> Vec<(Vec<u8>, Settled)> {
+        std::mem::take(&mut self.settled.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl MessageBroker for Recording {
+    async fn consume(self: Arc<Self>, consumer_tag: &str) -> BrokerResult<MessageStream> {
+        let deliveries = self.local.clone().consume(consumer_tag).await?;
+        Ok(Box::pin(deliveries.map(move |delivery| {
+            let delivery = delivery?;
+            Ok(BrokerMessage::new(
+                delivery.data,
+                delivery.redelivered,
+                delivery.headers,
+                delivery.priority,
+                Receipt::Local,
+                self.clone(),
+            ))
+        })))
+    }
+
+    async fn ack(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        self.record(message, Settled::Acked);
+        self.local.ack(message).await
+    }
+
+    async fn nack(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        self.record(message, Settled::Nacked);
+        self.local.nack(message).await
+    }
+
+    async fn requeue(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        self.record(message, Settled::Requeued);
+        self.local.requeue(message).await
+    }
+
+    async fn dead_letter(&self, message: &BrokerMessage, reason: &str) -> BrokerResult<()> {
+        let headers = message.failure_headers(&self.queue, reason);
+        self.record(message, Settled::DeadLettered(headers));
+        self.local.dead_letter(message, reason).await
+    }
+
+    async fn publish_task(
+        &self,
+        data: &[u8],
+        priority: Option<u8>,
+        headers: &BTreeMap<String, String>,
+    ) -> BrokerResult<()> {
+        self.local.publish_task(data, priority, headers).await
+    }
+
+    async fn forward(&self, message: &BrokerMessage, member: &str) -> BrokerResult<()> {
+        self.local.forward(message, member).await
+    }
+
+    async fn cancel(&self, consumer_tag: &str) -> BrokerResult<()> {
+        self.local.cancel(consumer_tag).await
+    }
+
+    async fn release(&self, message: &BrokerMessage) -> BrokerResult<()> {
+        self.record(message, Settled::Released);
+        self.local.release(message).await
+    }
+
+    async fn defer(&self, message: &BrokerMessage, delay: Duration) -> BrokerResult<()> {
+        self.record(message, Settled::Deferred);
+        self.local.defer(message, delay).await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.local.is_connected()
+    }
+
+    async fn shutdown(&self, consumer_tag: &str) -> BrokerResult<()> {
+        self.local.shutdown(consumer_tag).await
+    }
+}
+
+/// Default settings with the mock LLM backend answering after `latency_ms`.
+fn settings(latency_ms: u64) -> Settings {
+    let mut settings = Settings::new().unwrap();
+    settings.mock_llm = Some(MockLlmSettings {
+        fixtures_dir: None,
+        latency_ms,
+    });
+    settings
+}
+
+/// JSONL store whose output file is already unlinked; events are checked in the store.
+fn store() -> Arc<dyn TaskStore> {
+    let path = std::env::temp_dir().join(format!("consumer-test-{}.jsonl", uuid::Uuid::new_v4()));
+    let store = JsonlStore::create(&path.display().to_string()).unwrap();
+    let _ = std::fs::remove_file(&path);
+    Arc::new(store)
+}
+
+fn task(custom_id: &str) -> Value {
+    json!({
+        "custom_id": custom_id,
+        "method": "POST",
+        "url": "/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [{ "role": "user", "content": custom_id }] },
+    })
+}
+
+/// Creates the event of a task and sends its message to the broker.
+async fn submit(
+    store: &dyn TaskStore,
+    tasks: &mpsc::Sender<Vec<u8>>,
+    custom_id: &str,
+) -> PreparedTask {
+    let prepared = producer::prepare("batch", task(custom_id), 0).unwrap();
+    store
+        .create_events(std::slice::from_ref(&prepared.event))
+        .await
+        .unwrap();
+    tasks.send(prepared.message.clone()).await.unwrap();
+    prepared
+}
+
+/// Waits for `condition` to hold, for up to 5s.
+async fn until(condition: impl Fn() -> bool) {
+    let waited = tokio::time::timeout(Duration::from_secs(5), async {
+        while !condition() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await;
+    waited.expect("condition not met within 5s");
+}
+
+/// Runs `test` on a thread with room for the pipeline's futures, whose unoptimized
+/// polls need more than the 2 MiB of stack of a test thread.
+fn run(test: impl Future<Output = ()> + Send + 'static) {
+    std::thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(test)
+        })
+        .unwrap()
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+#[test]
+fn malformed_messages_are_dead_lettered_with_failure_headers() {
+    run(async {
+        let settings = Arc::new(settings(0));
+        let state = Arc::new(app_state(&settings));
+        let (broker, tasks) = Recording::new(&settings);
+        tasks.send(b"not json".to_vec()).await.unwrap();
+
+        let mut deliveries = broker.clone().consume("test").await.unwrap();
+        let delivery = deliveries.next().await.unwrap().unwrap();
+        pipeline::process_message(settings.clone(), state, store(), None, delivery).await;
+
+        let settled = broker.settled();
+        let [(data, Settled::DeadLettered(headers))] = &settled[..] else {
+            panic!("expected a dead letter, got {:?}", settled);
+        };
+        assert_eq!(data, b"not json");
+        assert!(headers["x-failure-reason"].starts_with("Malformed message: "));
+        assert_eq!(headers["x-original-queue"], settings.broker.queue);
+        assert_eq!(headers[ATTEMPT_HEADER], "1");
+        assert!(headers.contains_key("x-failed-at"));
+        assert_eq!(broker.local.unsettled(), 0);
+    })
+}
+
+#[test]
+fn messages_past_their_delivery_attempts_are_dead_lettered() {
+    run(async {
+        let settings = Arc::new(settings(0));
+        let state = Arc::new(app_state(&settings));
+        let store = store();
+        let (broker, _tasks) = Recording::new(&settings);
+        let prepared = producer::prepare("batch", task("a"), 0).unwrap();
+        let attempt = settings.broker.max_delivery_attempts + 1;
+        let headers = BTreeMap::from([(ATTEMPT_HEADER.to_string(), attempt.to_string())]);
+        broker
+            .publish_task(&prepared.message, None, &headers)
+            .await
+            .unwrap();
+
+        let mut deliveries = broker.clone().consume("test").await.unwrap();
+        let delivery = deliveries.next().await.unwrap().unwrap();
+        pipeline::process_message(settings.clone(), state, store, None, delivery).await;
+
+        let settled = broker.settled();
+        let [(data, Settled::DeadLettered(headers))] = &settled[..] else {
+            panic!("expected a dead letter, got {:?}", settled);
+        };
+        assert_eq!(data, &prepared.message);
+        assert_eq!(
+            headers["x-failure-reason"],
+            format!(
+                "Exceeded {} delivery attempts",
+                settings.broker.max_delivery_attempts
+            )
+        );
+        assert_eq!(headers[ATTEMPT_HEADER], attempt.to_string());
+    })
+}
+
+#[test]
+fn timed_out_tasks_are_requeued() {
+    run(async {
+        let mut settings = settings(60_000);
+        settings.task_timeout_secs = 1;
+        let settings = Arc::new(settings);
+        let state = Arc::new(app_state(&settings));
+        let store = store();
+        let (broker, tasks) = Recording::new(&settings);
+        let prepared = submit(store.as_ref(), &tasks, "a").await;
+
+        let mut deliveries = broker.clone().consume("test").await.unwrap();
+        let delivery = deliveries.next().await.unwrap().unwrap();
+        pipeline::process_message(settings.clone(), state, store.clone(), None, delivery).await;
+
+        assert_eq!(
+            broker.settled(),
+            [(prepared.message.clone(), Settled::Requeued)]
+        );
+        let retry = deliveries.next().await.unwrap().unwrap();
+        assert_eq!(retry.data, prepared.message);
+        assert_eq!(retry.delivery_attempt(), 2);
+        assert_ne!(
+            store.event_status(&prepared.message_id).await.unwrap(),
+            Some(TaskStatus::Failed)
+        );
+    })
+}
+
+#[test]
+fn tasks_timing_out_on_their_last_attempt_fail() {
+    run(async {
+        let mut settings = settings(60_000);
+        settings.task_timeout_secs = 1;
+        settings.broker.max_delivery_attempts = 1;
+        let settings = Arc::new(settings);
+        let state = Arc::new(app_state(&settings));
+        let store = store();
+        let (broker, tasks) = Recording::new(&settings);
+        let prepared = submit(store.as_ref(), &tasks, "a").await;
+
+        let mut deliveries = broker.clone().consume("test").await.unwrap();
+        let delivery = deliveries.next().await.unwrap().unwrap();
+        pipeline::process_message(settings.clone(), state, store.clone(), None, delivery).await;
+
+        let settled = broker.settled();
+        let [(data, Settled::DeadLettered(headers))] = &settled[..] else {
+            panic!("expected a dead letter, got {:?}", settled);
+        };
+        assert_eq!(data, &prepared.message);
+        assert_eq!(headers["x-failure-reason"], "Task exceeded its deadline");
+        assert_eq!(
+            store.event_status(&prepared.message_id).await.unwrap(),
+            Some(TaskStatus::Failed)
+        );
+    })
+}
+
+#[test]
+fn maintenance_stops_consumption() {
+    run(async {
+        let settings = Arc::new(settings(0));
+        let state = Arc::new(app_state(&settings));
+        let store = store();
+        let (broker, tasks) = Recording::new(&settings);
+        let first = submit(store.as_ref(), &tasks, "a").await;
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (maintenance_tx, maintenance) = watch::channel(false);
+        let consumer = {
+            let broker = broker.clone();
+            let store = store.clone();
+            tokio::spawn(async move {
+                run_consumer(
+                    &settings,
+                    &state,
+                    broker,
+                    store,
+                    shutdown,
+                    maintenance,
+                    "test",
+                )
+                .await
+            })
+        };
+        until(|| broker.settled.lock().unwrap().len() == 1).await;
+        // The consumer sees both when it next runs, and stops rather than take the
+        // task. The input stays open, so the stream doesn't end on its own.
+        maintenance_tx.send(true).unwrap();
+        let second = submit(store.as_ref(), &tasks, "b").await;
+        tokio::time::timeout(Duration::from_secs(5), consumer)
+            .await
+            .expect("consumer still running in maintenance")
+            .unwrap()
+            .unwrap();
+
+        // The hand-off of prefetched deliveries may still take the second task from the
+        // stream, only to hand it back.
+        let settled = broker.settled();
+        assert_eq!(settled[0], (first.message, Settled::Acked));
+        assert!(
+            settled[1..]
+                .iter()
+                .all(|delivery| *delivery == (second.message.clone(), Settled::Released)),
+            "{:?}",
+            settled
+        );
+        assert_eq!(broker.local.unsettled(), 0);
+    })
+}
+
+#[test]
+fn consumers_paused_for_maintenance_take_no_delivery() {
+    run(async {
+        let settings = Arc::new(settings(0));
+        let state = Arc::new(app_state(&settings));
+        let store = store();
+        let (broker, tasks) = Recording::new(&settings);
+        submit(store.as_ref(), &tasks, "a").await;
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (_maintenance_tx, maintenance) = watch::channel(true);
+        run_consumer(
+            &settings,
+            &state,
+            broker.clone(),
+            store,
+            shutdown,
+            maintenance,
+            "test",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(broker.settled(), []);
+        assert_eq!(broker.local.unsettled(), 0);
+    })
+}
+
+#[test]
+fn prefetched_deliveries_are_handed_back_on_shutdown() {
+    run(async {
+        let mut settings = settings(500);
+        settings.max_parallel_tasks = 1;
+        settings.handoff_prefetched = true;
+        let settings = Arc::new(settings);
+        let state = Arc::new(app_state(&settings));
+        let store = store();
+        let (broker, tasks) = Recording::new(&settings);
+        let started = submit(store.as_ref(), &tasks, "a").await;
+        let prefetched = submit(store.as_ref(), &tasks, "b").await;
+
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let (_maintenance_tx, maintenance) = watch::channel(false);
+        let consumer = {
+            let broker = broker.clone();
+            tokio::spawn(async move {
+                run_consumer(
+                    &settings,
+                    &state,
+                    broker,
+                    store,
+                    shutdown,
+                    maintenance,
+                    "test",
+                )
+                .await
+            })
+        };
+        // The second delivery waits for the slot of the first.
+        until(|| broker.local.unsettled() == 2).await;
+        shutdown_tx.send(true).unwrap();
+        consumer.await.unwrap().unwrap();
+
+        assert_eq!(
+            broker.settled(),
+            [
+                (prefetched.message, Settled::Released),
+                (started.message, Settled::Acked),
+            ]
+        );
+        assert_eq!(broker.local.unsettled(), 0);
+    })
+}
+
+/// Settlement of the redelivery of a task whose event already completed, under
+/// `dedup_mode`. Processing the task times it out, as the LLM never answers.
+async fn redelivery_of_completed_task(dedup_mode: DedupMode) -> Vec<(Vec<u8>, Settled)> {
+    let mut settings = settings(60_000);
+    settings.task_timeout_secs = 1;
+    settings.dedup_mode = dedup_mode;
+    let settings = Arc::new(settings);
+    let state = Arc::new(app_state(&settings));
+    let store = store();
+    let (broker, tasks) = Recording::new(&settings);
+    let prepared = submit(store.as_ref(), &tasks, "a").await;
+    let event = db::EventKey {
+        message_id: &prepared.message_id,
+        batch_id: "batch",
+        body_hash: &prepared.body_hash,
+    };
+    let response = LLMResponse {
+        completions: json!({ "choices": [{ "message": { "content": "a" } }] }),
+        cached: false,
+        attempt: 1,
+        started_at: Utc::now(),
+        completed_at: Utc::now(),
+        annotations: Default::default(),
+        usage: None,
+        cost: None,
+    };
+    store
+        .update_event_status(&event, TaskStatus::Completed, &response, Utc::now())
+        .await
+        .unwrap();
+
+    let mut deliveries = broker.clone().consume("test").await.unwrap();
+    let delivery = deliveries.next().await.unwrap().unwrap();
+    pipeline::process_message(settings.clone(), state, store, None, delivery).await;
+    broker.settled()
+}
+
+#[test]
+fn redeliveries_of_completed_tasks_are_acked_without_processing() {
+    run(async {
+        let settled = redelivery_of_completed_task(DedupMode::Lenient).await;
+        assert!(
+            matches!(settled[..], [(_, Settled::Acked)]),
+            "{:?}",
+            settled
+        );
+    })
+}
+
+#[test]
+fn redeliveries_are_processed_without_dedup() {
+    run(async {
+        let settled = redelivery_of_completed_task(DedupMode::Off).await;
+        assert!(
+            matches!(settled[..], [(_, Settled::Requeued)]),
+            "{:?}",
+            settled
+        );
+    })
+}
+
+/// Settlement of a task held for the dispatch window of its only provider, which
+/// opens in a couple of hours, under `kind` and a one-second `max_hold`. The task is
+/// given up on after three seconds.
+async fn held_for_dispatch_window(kind: BrokerKind) -> Vec<(Vec<u8>, Settled)> {
+    let opens_at = Utc::now() + chrono::Duration::hours(2);
+    let window = DispatchWindow {
+        start: opens_at.format("%H:%M").to_string(),
+        end: (opens_at + chrono::Duration::hours(1))
+            .format("%H:%M")
+            .to_string(),
+        timezone: Some("+00:00".to_string()),
+        outside: OutsideWindow::Hold,
+    };
+    let mut settings = settings(0);
+    settings.broker.kind = kind;
+    settings.task_timeout_secs = 0;
+    settings.dispatch_windows = DispatchWindowSettings {
+        windows: [("gpu.internal".to_string(), window)].into(),
+        max_hold_secs: 1,
+    };
+    let settings = Arc::new(settings);
+    let state = Arc::new(app_state(&settings));
+    let store = store();
+    let (broker, tasks) = Recording::new(&settings);
+    let mut task = task("a");
+    task["providers"] = json!([{ "url": "http://gpu.internal/v1/chat/completions" }]);
+    let prepared = producer::prepare("batch", task, 0).unwrap();
+    store
+        .create_events(std::slice::from_ref(&prepared.event))
+        .await
+        .unwrap();
+    tasks.send(prepared.message).await.unwrap();
+
+    let mut deliveries = broker.clone().consume("test").await.unwrap();
+    let delivery = deliveries.next().await.unwrap().unwrap();
+    let processing = pipeline::process_message(settings.clone(), state, store, None, delivery);
+    let _ = tokio::time::timeout(Duration::from_secs(3), processing).await;
+    broker.settled()
+}
+
+#[test]
+fn held_deliveries_are_handed_back_to_rabbitmq() {
+    run(async {
+        let settled = held_for_dispatch_window(BrokerKind::RabbitMq).await;
+        assert!(
+            matches!(settled[..], [(_, Settled::Released)]),
+            "{:?}",
+            settled
+        );
+    })
+}
+
+#[test]
+fn kafka_deliveries_are_held_in_their_slot() {
+    run(async {
+        // Releasing would leave the offset unsettled until the partition is reassigned.
+        let settled = held_for_dispatch_window(BrokerKind::Kafka).await;
+        assert_eq!(settled, []);
+    })
+}
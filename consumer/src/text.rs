@@ -0,0 +1,43 @@
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Concatenates the textual content of a request body, for chat (`messages`)
+/// and legacy completion (`prompt`) shapes.
+pub fn prompt_text(body: &Value) -> String {
+    if let Some(messages) = body["messages"].as_array() {
+        return messages
+            .iter()
+            .filter_map(|m| match &m["content"] {
+                Value::String(s) => Some(s.clone()),
+                Value::Array(parts) => Some(
+                    parts
+                        .iter()
+                        .filter_map(|p| p["text"].as_str())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+    body["prompt"].as_str().unwrap_or_default().to_string()
+}
+
+/// Lowercased alphanumeric words, used by the overlap and similarity checks.
+pub fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+/// Hashes of the `n`-word windows of `words`, used by the overlap and similarity checks.
+pub fn ngram_hashes(words: &[String], n: usize) -> impl Iterator<Item = u64> + '_ {
+    words.windows(n.max(1)).map(|window| {
+        let mut hasher = DefaultHasher::new();
+        window.hash(&mut hasher);
+        hasher.finish()
+    })
+}
@@ -0,0 +1,191 @@
+//! Size limits of what provider calls leave in events and logs.
+//!
+//! Provider error bodies, often whole HTML error pages, are cut to
+//! `STORED_ERROR_MAX_BYTES` before they make it into failure messages, and the texts of
+//! completed responses to `STORED_COMPLETION_MAX_BYTES` before the event is written.
+//! Both keep their head and tail around a marker of how many bytes were left out.
+//! With `ARCHIVE_S3_ENDPOINT` set, the full body is uploaded to the object store first
+//! and its location given after the error message, or in the `completion_truncated`
+//! annotation. Truncated completions aren't served from the response cache.
+//!
+//! With `STORED_COMPLETION_POLICY=offload`, a completion whose JSON is over
+//! `STORED_COMPLETION_MAX_BYTES` is uploaded whole instead, and replaced on the event by
+//! `{"archive_url": ...}`, its annotation saying `"offloaded": true`. That keeps
+//! completions whose size is in tool calls or logprobs rather than texts under the
+//! events index's document limits. Without an object store, or when the upload fails,
+//! it's truncated. The object store can be any S3-compatible one, GCS through its
+//! interoperability API included.
+
+use crate::archive::Archive;
+use crate::settings::{StoredCompletionPolicy, StoredSizeSettings};
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+
+/// Annotation of events whose completion texts were truncated.
+pub const TRUNCATED_ANNOTATION: &str = "completion_truncated";
+
+/// `text` cut to about `max_bytes`, keeping its head and tail on character boundaries.
+/// Zero keeps it whole.
+pub fn truncate(text: &str, max_bytes: usize) -> Cow<'_, str> {
+    if max_bytes == 0 || text.len() <= max_bytes {
+        return Cow::Borrowed(text);
+    }
+    let mut head = max_bytes / 2;
+    while !text.is_char_boundary(head) {
+        head -= 1;
+    }
+    let mut tail = text.len() - (max_bytes - max_bytes / 2);
+    while !text.is_char_boundary(tail) {
+        tail += 1;
+    }
+    Cow::Owned(format!(
+        "{}\n... [{} bytes truncated] ...\n{}",
+        &text[..head],
+        tail - head,
+        &text[tail..]
+    ))
+}
+
+#[derive(Clone, Default)]
+pub struct Truncation {
+    settings: StoredSizeSettings,
+    archive: Option<Archive>,
+}
+
+impl Truncation {
+    pub fn new(settings: &StoredSizeSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            archive: settings.archive.as_ref().map(Archive::new),
+        }
+    }
+
+    /// Provider error body as kept in failure messages.
+    pub async fn error_body(&self, body: String) -> String {
+        let max_bytes = self.settings.max_error_bytes;
+        if max_bytes == 0 || body.len() <= max_bytes {
+            return body;
+        }
+        let truncated = truncate(&body, max_bytes).into_owned();
+        match self
+            .archive("errors", "txt", "text/plain", body.into_bytes())
+            .await
+        {
+            Some(location) => format!("{} [full body archived to {}]", truncated, location),
+            None => truncated,
+        }
+    }
+
+    /// Truncates the texts of `completions` over the limit, or offloads them, and
+    /// returns the `completion_truncated` annotation if any was.
+    pub async fn completions(&self, completions: &mut Value) -> Option<Value> {
+        let max_bytes = self.settings.max_completion_bytes;
+        if max_bytes > 0 && self.settings.completion_policy == StoredCompletionPolicy::Offload {
+            if let Some(annotation) = self.offload(completions, max_bytes).await {
+                return Some(annotation);
+            }
+        }
+        if max_bytes == 0 || !texts(completions).any(|text| text.len() > max_bytes) {
+            return None;
+        }
+        let original_bytes: usize = texts(completions).map(str::len).sum();
+        let location = match serde_json::to_vec(completions) {
+            Ok(body) => {
+                self.archive("completions", "json", "application/json", body)
+                    .await
+            }
+            Err(_) => None,
+        };
+        for text in texts_mut(completions) {
+            if let Cow::Owned(truncated) = truncate(text, max_bytes) {
+                *text = truncated;
+            }
+        }
+        Some(json!({
+            "original_bytes": original_bytes,
+            "max_bytes": max_bytes,
+            "archive_url": location,
+        }))
+    }
+
+    /// Uploads `completions` when their JSON is over `max_bytes`, leaving their
+    /// location in their place.
+    async fn offload(&self, completions: &mut Value, max_bytes: usize) -> Option<Value> {
+        let body = serde_json::to_vec(completions).ok()?;
+        if body.len() <= max_bytes {
+            return None;
+        }
+        let original_bytes = body.len();
+        let location = self
+            .archive("completions", "json", "application/json", body)
+            .await?;
+        *completions = json!({ "archive_url": location });
+        Some(json!({
+            "original_bytes": original_bytes,
+            "max_bytes": max_bytes,
+            "archive_url": location,
+            "offloaded": true,
+        }))
+    }
+
+    async fn archive(
+        &self,
+        kind: &str,
+        extension: &str,
+        content_type: &str,
+        body: Vec<u8>,
+    ) -> Option<String> {
+        let archive = self.archive.as_ref()?;
+        let key = format!(
+            "{}/{}/{}.{}",
+            kind,
+            Utc::now().format("%Y-%m-%d"),
+            uuid::Uuid::new_v4(),
+            extension
+        );
+        match archive.put(&key, body, content_type).await {
+            Ok(location) => Some(location),
+            Err(e) => {
+                tracing::warn!("Failed to archive a truncated body: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Generated texts of a chat, legacy completions or Messages API response.
+fn texts(completions: &Value) -> impl Iterator<Item = &str> {
+    let choices = completions["choices"].as_array().into_iter().flatten();
+    let choice_texts = choices.flat_map(|choice| [&choice["message"]["content"], &choice["text"]]);
+    let blocks = completions["content"].as_array().into_iter().flatten();
+    choice_texts
+        .chain(blocks.map(|block| &block["text"]))
+        .filter_map(Value::as_str)
+}
+
+fn texts_mut(completions: &mut Value) -> Vec<&mut String> {
+    let mut texts = Vec::new();
+    let Value::Object(fields) = completions else {
+        return texts;
+    };
+    for (field, value) in fields.iter_mut() {
+        let Value::Array(items) = value else {
+            continue;
+        };
+        for item in items.iter_mut().filter_map(Value::as_object_mut) {
+            for (key, value) in item.iter_mut() {
+                match (field.as_str(), key.as_str(), value) {
+                    ("choices" | "content", "text", Value::String(text)) => texts.push(text),
+                    ("choices", "message", Value::Object(message)) => {
+                        if let Some(Value::String(text)) = message.get_mut("content") {
+                            texts.push(text);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    texts
+}
@@ -0,0 +1,84 @@
+//! Validation of completions against the JSON Schema a task declares in its
+//! `output_schema`, and the follow-up request asking the model to fix a completion
+//! that doesn't conform.
+
+use serde_json::{json, Value};
+
+/// Parses the completion as JSON, tolerating a surrounding Markdown code fence.
+pub fn parse_content(content: &str) -> Result<Value, String> {
+    let trimmed = content.trim();
+    let unfenced = trimmed
+        .strip_prefix("```")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .map(|inner| {
+            // Drop the info string of the fence, e.g. `json`
+            inner.split_once('\n').map_or(inner, |(_, code)| code)
+        })
+        .unwrap_or(trimmed);
+    serde_json::from_str(unfenced).map_err(|e| format!("Completion is not valid JSON: {}", e))
+}
+
+/// Checks the completion against `schema`. Returns the parsed value, or one message
+/// per violation, prefixed with the offending location.
+pub fn validate(schema: &Value, content: &str) -> Result<Value, Vec<String>> {
+    let validator = jsonschema::validator_for(schema)
+        .map_err(|e| vec![format!("Invalid output_schema: {}", e)])?;
+    let instance = parse_content(content).map_err(|e| vec![e])?;
+    let errors = violations(&validator, &instance);
+    if errors.is_empty() {
+        Ok(instance)
+    } else {
+        Err(errors)
+    }
+}
+
+/// One message per violation of `instance`, prefixed with the offending location.
+pub fn violations(validator: &jsonschema::Validator, instance: &Value) -> Vec<String> {
+    validator
+        .iter_errors(instance)
+        .map(|error| {
+            let path = error.instance_path.to_string();
+            if path.is_empty() {
+                error.to_string()
+            } else {
+                format!("{}: {}", path, error)
+            }
+        })
+        .collect()
+}
+
+fn repair_prompt(schema: &Value, errors: &[String]) -> String {
+    format!(
+        "Your previous answer does not conform to the required JSON Schema:\n- {}\n\n\
+         Schema:\n{}\n\nReply with the corrected JSON only, without explanations.",
+        errors.join("\n- "),
+        schema
+    )
+}
+
+/// Request body continuing the conversation with the non-conforming completion and a
+/// request to fix it. `None` when the body shape has no conversation to extend.
+pub fn repair_body(
+    body: &Value,
+    completion: &str,
+    schema: &Value,
+    errors: &[String],
+) -> Option<Value> {
+    let prompt = repair_prompt(schema, errors);
+    let mut body = body.clone();
+    if let Some(messages) = body["messages"].as_array_mut() {
+        // OpenAI-compatible chat and Anthropic Messages
+        messages.push(json!({ "role": "assistant", "content": completion }));
+        messages.push(json!({ "role": "user", "content": prompt }));
+    } else if let Some(contents) = body["contents"].as_array_mut() {
+        // Gemini generateContent
+        contents.push(json!({ "role": "model", "parts": [{ "text": completion }] }));
+        contents.push(json!({ "role": "user", "parts": [{ "text": prompt }] }));
+    } else if let Some(text) = body["prompt"].as_str() {
+        // Legacy completions
+        body["prompt"] = Value::from(format!("{}{}\n\n{}\n", text, completion, prompt));
+    } else {
+        return None;
+    }
+    Some(body)
+}
@@ -0,0 +1,114 @@
+//! Webhook deliveries of task and batch events, so downstream pipelines are told when
+//! work finishes instead of polling Elasticsearch for it.
+//!
+//! Each request carries the Unix time it was sent in `X-Synthgen-Timestamp` and, when
+//! `WEBHOOK_SECRET` is set, `X-Synthgen-Signature: sha256=<hex>`: the HMAC-SHA256 of
+//! `<timestamp>.<body>` under the secret. Receivers recompute it and reject stale
+//! timestamps. Deliveries run in the background and are retried with exponential
+//! backoff on network errors, 408, 429 and 5xx responses.
+
+use crate::settings::WebhookSettings;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use serde_json::Value;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const TIMESTAMP_HEADER: &str = "X-Synthgen-Timestamp";
+pub const SIGNATURE_HEADER: &str = "X-Synthgen-Signature";
+
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    settings: Arc<WebhookSettings>,
+}
+
+impl Webhooks {
+    pub fn new(settings: &WebhookSettings) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .build()
+            .unwrap_or_default();
+        Self {
+            client,
+            settings: Arc::new(settings.clone()),
+        }
+    }
+
+    /// Posts `event` to `url` without waiting for the delivery.
+    pub fn send(&self, url: &str, event: Value) {
+        let webhooks = self.clone();
+        let url = url.to_string();
+        tokio::spawn(async move { webhooks.deliver(&url, &event).await });
+    }
+
+    async fn deliver(&self, url: &str, event: &Value) {
+        let body = event.to_string();
+        let max_attempts = self.settings.max_attempts.max(1);
+        for attempt in 1..=max_attempts {
+            let error = match self.post(url, &body).await {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            let retryable = error.status().is_none_or(|status| {
+                status.is_server_error()
+                    || status == StatusCode::REQUEST_TIMEOUT
+                    || status == StatusCode::TOO_MANY_REQUESTS
+            });
+            if !retryable || attempt == max_attempts {
+                tracing::error!(
+                    "Failed to deliver {} webhook to {} after {} attempts: {}",
+                    event["event"],
+                    url,
+                    attempt,
+                    error
+                );
+                return;
+            }
+            let delay_ms = self
+                .settings
+                .base_delay_ms
+                .saturating_mul(1 << (attempt - 1).min(20))
+                .min(self.settings.max_delay_ms);
+            tracing::warn!(
+                "Webhook to {} failed (attempt {}/{}), retrying in {}ms: {}",
+                url,
+                attempt,
+                max_attempts,
+                delay_ms,
+                error
+            );
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        }
+    }
+
+    async fn post(&self, url: &str, body: &str) -> reqwest::Result<()> {
+        let timestamp = Utc::now().timestamp().to_string();
+        let mut request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, &timestamp);
+        if let Some(secret) = &self.settings.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, &timestamp, body));
+        }
+        request
+            .body(body.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of `<timestamp>.<body>` under `secret`.
+pub fn signature(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
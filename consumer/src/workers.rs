@@ -0,0 +1,129 @@
+//! Registry of the consumer processes in the `workers` index.
+//!
+//! Each process has a worker id, `WORKER_ID` or its hostname and pid, and writes a
+//! heartbeat document under it every `WORKER_HEARTBEAT_SECS`: its hostname, version,
+//! tasks in flight and processed so far, and uptime. A process shutting down cleanly
+//! writes a last one as `stopped`. A worker still `running` whose heartbeat is older
+//! than `WORKER_STALE_AFTER_SECS` is stale: it crashed or lost Elasticsearch, and the
+//! tasks it had in flight are left to redelivery or `consumer resume`.
+//! `synthgen-admin workers` and the API's `/admin/workers` list the fleet.
+
+use crate::db::elastic::ElasticStore;
+use crate::db::DbResult;
+use crate::settings::WorkerSettings;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const RUNNING: &str = "running";
+pub const STOPPED: &str = "stopped";
+
+/// This process, as it reports itself in its heartbeats.
+pub struct Worker {
+    settings: WorkerSettings,
+    started_at: DateTime<Utc>,
+    in_flight: AtomicUsize,
+    processed: AtomicU64,
+}
+
+impl Worker {
+    pub fn new(settings: &WorkerSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            started_at: Utc::now(),
+            in_flight: AtomicUsize::new(0),
+            processed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.settings.id
+    }
+
+    /// Counts a delivery in flight until the returned guard is dropped, then as
+    /// processed.
+    pub fn track(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn processed(&self) -> u64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// Heartbeat document of the worker at `now`, in `state`.
+    pub fn heartbeat(&self, state: &str, now: DateTime<Utc>) -> Value {
+        json!({
+            "worker_id": self.settings.id,
+            "hostname": self.settings.hostname,
+            "pid": std::process::id(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "state": state,
+            "in_flight": self.in_flight(),
+            "processed": self.processed(),
+            "started_at": self.started_at,
+            "uptime_secs": (now - self.started_at).num_seconds().max(0),
+            "last_heartbeat": now,
+        })
+    }
+
+    /// Writes a heartbeat every `WORKER_HEARTBEAT_SECS`.
+    pub async fn run(&self, store: ElasticStore) {
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.settings.heartbeat_secs.max(1)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.report(&store, RUNNING).await {
+                tracing::warn!(
+                    "Failed to write the heartbeat of worker {}: {}",
+                    self.id(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Writes the worker's heartbeat in `state`.
+    pub async fn report(&self, store: &ElasticStore, state: &str) -> DbResult<()> {
+        store
+            .put_document(
+                store.workers_index(),
+                self.id(),
+                &self.heartbeat(state, Utc::now()),
+            )
+            .await
+    }
+}
+
+/// A delivery of the worker in flight.
+pub struct InFlight(Arc<Worker>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.processed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether the worker of `heartbeat` is presumed dead at `now`: still running, but
+/// silent for `stale_after`.
+pub fn is_stale(heartbeat: &Value, now: DateTime<Utc>, stale_after: Duration) -> bool {
+    if heartbeat["state"] == STOPPED {
+        return false;
+    }
+    let Some(last_heartbeat) = heartbeat["last_heartbeat"]
+        .as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+    else {
+        return true;
+    };
+    (now - last_heartbeat.with_timezone(&Utc))
+        .to_std()
+        .is_ok_and(|silence| silence >= stale_after)
+}
@@ -0,0 +1,76 @@
+//! `synthgen-admin` picks the messages of a batch out of a queue, restarts the attempts
+//! of the failed tasks it requeues, has consumers drop the tasks it cancels and prints
+//! queue and event counts.
+
+use consumer::admin::{in_batch, requeue_headers, QueueStats, Stats, CANCELLED};
+use consumer::broker::ATTEMPT_HEADER;
+use consumer::db::EventState;
+use consumer::schemas::task_status::TaskStatus;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+#[test]
+fn messages_are_picked_by_batch() {
+    let message = json!({
+        "message_id": "m1",
+        "batch_id": "b1",
+        "body_hash": "h",
+        "payload": { "method": "POST", "url": "u", "body": {} },
+    })
+    .to_string();
+    assert!(in_batch(message.as_bytes(), "b1"));
+    assert!(!in_batch(message.as_bytes(), "b2"));
+    assert!(!in_batch(b"not json", "b1"));
+}
+
+#[test]
+fn requeued_tasks_lose_their_failure_headers() {
+    let headers: BTreeMap<String, String> = [
+        (ATTEMPT_HEADER, "3"),
+        ("x-failure-reason", "Rate limited"),
+        ("x-failed-at", "2024-01-01T00:00:00Z"),
+        ("x-original-queue", "synthgen_tasks"),
+        ("traceparent", "00-abc-def-01"),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect();
+    assert_eq!(
+        requeue_headers(&headers),
+        BTreeMap::from([("traceparent".to_string(), "00-abc-def-01".to_string())])
+    );
+}
+
+#[test]
+fn stats_are_printed_as_tables() {
+    let stats = Stats {
+        queues: vec![QueueStats {
+            name: "synthgen_tasks".to_string(),
+            messages: 12,
+            consumers: 3,
+        }],
+        events: BTreeMap::from([("COMPLETED".to_string(), 40), ("PENDING".to_string(), 12)]),
+    };
+    let printed = stats.to_string();
+    let lines: Vec<&str> = printed.lines().map(str::trim_end).collect();
+    assert_eq!(
+        lines[1].split_whitespace().collect::<Vec<_>>(),
+        ["synthgen_tasks", "12", "3"]
+    );
+    assert_eq!(
+        lines.last().unwrap().split_whitespace().collect::<Vec<_>>(),
+        ["total", "52"]
+    );
+}
+
+#[test]
+fn deliveries_of_cancelled_and_completed_events_are_skipped() {
+    let state = |event: serde_json::Value| EventState::of(&event).unwrap();
+    let cancelled = state(json!({ "status": "FAILED", CANCELLED: true }));
+    assert_eq!(cancelled.status, TaskStatus::Failed);
+    assert!(cancelled.skips_delivery());
+    assert!(state(json!({ "status": "COMPLETED" })).skips_delivery());
+    assert!(!state(json!({ "status": "FAILED" })).skips_delivery());
+    assert!(!state(json!({ "status": "PENDING", CANCELLED: false })).skips_delivery());
+    assert!(EventState::of(&json!({ "status": "UNKNOWN" })).is_none());
+}
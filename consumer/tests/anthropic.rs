@@ -0,0 +1,164 @@
+//! Providers speaking Anthropic's Messages API get converted bodies and its headers, and
+//! their responses and rate limits are read like any other provider's.
+
+use chrono::{TimeZone, Utc};
+use consumer::anthropic::{rate_limit_reset, should_retry, to_messages, DEFAULT_MAX_TOKENS};
+use consumer::llm_wrapper::{call_llm, task_providers, LLMClient};
+use consumer::settings::{EmptyCompletionSettings, ProviderApi};
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn chat_bodies_are_converted() {
+    let body = json!({
+        "model": "claude-sonnet-4-5",
+        "messages": [
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": "Hi" },
+            { "role": "developer", "content": [{ "type": "text", "text": "No emoji." }] },
+        ],
+        "max_completion_tokens": 256,
+        "stop": "END",
+        "seed": 7,
+        "n": 1,
+        "user": "tenant-1",
+    });
+    assert_eq!(
+        to_messages(body),
+        json!({
+            "model": "claude-sonnet-4-5",
+            "system": "Be brief.\n\nNo emoji.",
+            "messages": [{ "role": "user", "content": "Hi" }],
+            "max_tokens": 256,
+            "stop_sequences": ["END"],
+            "metadata": { "user_id": "tenant-1" },
+        })
+    );
+
+    let native = json!({
+        "model": "claude-sonnet-4-5",
+        "system": "Be brief.",
+        "messages": [{ "role": "user", "content": "Hi" }],
+        "max_tokens": 64,
+        "stop_sequences": ["END"],
+    });
+    assert_eq!(to_messages(native.clone()), native);
+
+    let legacy = to_messages(json!({ "model": "claude-sonnet-4-5", "prompt": "Hi" }));
+    assert_eq!(
+        legacy["messages"],
+        json!([{ "role": "user", "content": "Hi" }])
+    );
+    assert_eq!(legacy["max_tokens"], DEFAULT_MAX_TOKENS);
+}
+
+#[test]
+fn rate_limits_wait_for_the_latest_exhausted_reset() {
+    let now = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+    let mut headers = HeaderMap::new();
+    for (name, value) in [
+        ("anthropic-ratelimit-requests-remaining", "0"),
+        ("anthropic-ratelimit-requests-reset", "2026-01-01T00:00:05Z"),
+        ("anthropic-ratelimit-tokens-remaining", "0"),
+        ("anthropic-ratelimit-tokens-reset", "2026-01-01T00:00:20Z"),
+        // Not exhausted, so not waited for.
+        ("anthropic-ratelimit-output-tokens-remaining", "100"),
+        (
+            "anthropic-ratelimit-output-tokens-reset",
+            "2026-01-01T00:01:00Z",
+        ),
+        ("x-should-retry", "false"),
+    ] {
+        headers.insert(name, HeaderValue::from_static(value));
+    }
+    assert_eq!(
+        rate_limit_reset(&headers, now),
+        Some(Duration::from_secs(20))
+    );
+    assert_eq!(should_retry(&headers), Some(false));
+    assert_eq!(rate_limit_reset(&HeaderMap::new(), now), None);
+}
+
+#[test]
+fn tasks_name_their_provider_api() {
+    let payload = json!({
+        "url": "https://api.anthropic.com/v1/messages",
+        "api_key": "sk-ant",
+        "provider": "anthropic",
+    });
+    assert_eq!(task_providers(&payload, &[])[0].api, ProviderApi::Anthropic);
+
+    let payload = json!({
+        "providers": [
+            { "url": "https://api.anthropic.com/v1/messages", "provider": "anthropic" },
+            { "url": "https://api.openai.com/v1/chat/completions" },
+        ],
+    });
+    let providers = task_providers(&payload, &[]);
+    assert_eq!(providers[0].api, ProviderApi::Anthropic);
+    assert_eq!(providers[1].api, ProviderApi::OpenAiCompatible);
+}
+
+/// Answers the first request as overloaded and the others with a message, recording
+/// the request headers.
+async fn messages_endpoint(requests: Arc<Mutex<Vec<String>>>) -> String {
+    common::serve("/v1/messages", move |served, request| {
+        requests.lock().unwrap().push(request.to_lowercase());
+        if served == 0 {
+            let body = json!({ "type": "error", "error": { "type": "overloaded_error", "message": "Overloaded" } });
+            common::response(
+                "529 Overloaded",
+                &[("content-type", "application/json")],
+                &body.to_string(),
+            )
+        } else {
+            let body = json!({
+                "type": "message",
+                "content": [{ "type": "text", "text": "Hello" }],
+                "stop_reason": "end_turn",
+                "usage": { "input_tokens": 5, "output_tokens": 1 },
+            });
+            common::json_response(&body.to_string())
+        }
+    })
+    .await
+}
+
+#[tokio::test]
+async fn calls_send_anthropic_headers_and_retry_overloads() {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let url = messages_endpoint(requests.clone()).await;
+    let body = to_messages(json!({
+        "model": "claude-sonnet-4-5",
+        "messages": [{ "role": "user", "content": "Hi" }],
+    }));
+    let response = call_llm(
+        &LLMClient::new(),
+        &url,
+        &body,
+        "sk-ant",
+        ProviderApi::Anthropic,
+        "https://example.com",
+        "synthgen",
+        3,
+        1,
+        1,
+        &EmptyCompletionSettings::UNADJUSTED,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(response.content().as_deref(), Some("Hello"));
+    assert_eq!(response.usage.unwrap().total_tokens, 6);
+    assert_eq!(response.attempt, 1);
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].contains("x-api-key: sk-ant"));
+    assert!(requests[0].contains("anthropic-version: 2023-06-01"));
+    assert!(!requests[0].contains("authorization:"));
+    assert!(!requests[0].contains("http-referer:"));
+}
@@ -0,0 +1,82 @@
+//! Results are streamed as Arrow IPC messages that Arrow readers load as they arrive:
+//! the schema, a record batch per page of rows, then the end-of-stream marker.
+
+use arrow_array::cast::AsArray;
+use arrow_array::Array;
+use arrow_ipc::reader::StreamReader;
+use consumer::arrow_ipc::ResultStream;
+use consumer::settings::ExportField;
+use serde_json::{json, Map, Value};
+use std::io::Cursor;
+
+fn field(name: &str) -> ExportField {
+    ExportField {
+        name: name.to_string(),
+        source: name.to_string(),
+    }
+}
+
+#[test]
+fn messages_are_taken_as_they_are_written() {
+    let mut results = ResultStream::new(&[field("completion")]).unwrap();
+    let schema = results.take();
+    assert!(!schema.is_empty());
+    assert!(results.take().is_empty());
+
+    let row = Map::from_iter([("completion".to_string(), json!("a"))]);
+    results.write(&[row]).unwrap();
+    let batch = results.take();
+    assert!(!batch.is_empty());
+
+    results.finish().unwrap();
+    assert_eq!(results.take(), [0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]);
+
+    // Readers stop at the marker.
+    let stream = [schema, batch].concat();
+    let reader = StreamReader::try_new(Cursor::new(stream), None).unwrap();
+    assert_eq!(reader.count(), 1);
+}
+
+#[test]
+fn arrow_readers_load_the_stream() {
+    let fields = [field("custom_id"), field("completion")];
+    let row = |id: &str, completion: Value| {
+        Map::from_iter([
+            ("custom_id".to_string(), json!(id)),
+            ("completion".to_string(), completion),
+        ])
+    };
+    let mut results = ResultStream::new(&fields).unwrap();
+    results
+        .write(&[row("a", json!("Seven.")), row("b", Value::Null)])
+        .unwrap();
+    results.write(&[row("c", json!({ "n": 1 }))]).unwrap();
+    results.write(&[]).unwrap();
+    results.finish().unwrap();
+    let stream = results.take();
+
+    let reader = StreamReader::try_new(Cursor::new(stream), None).unwrap();
+    let read_schema = reader.schema();
+    let names: Vec<_> = read_schema
+        .fields()
+        .iter()
+        .map(|f| f.name().as_str())
+        .collect();
+    assert_eq!(names, ["custom_id", "completion"]);
+    assert!(read_schema.fields().iter().all(|f| f.is_nullable()));
+
+    let batches: Vec<_> = reader.map(Result::unwrap).collect();
+    assert_eq!(
+        batches.iter().map(|b| b.num_rows()).collect::<Vec<_>>(),
+        [2, 1, 0]
+    );
+    let ids = batches[0].column(0).as_string::<i32>();
+    let completions = batches[0].column(1).as_string::<i32>();
+    assert_eq!((ids.value(0), ids.value(1)), ("a", "b"));
+    assert_eq!(completions.value(0), "Seven.");
+    assert!(completions.is_null(1));
+    assert_eq!(
+        batches[1].column(1).as_string::<i32>().value(0),
+        r#"{"n":1}"#
+    );
+}
@@ -0,0 +1,82 @@
+//! Tasks carry their organization, project and user next to the payload, onto their
+//! event, and are held to the quotas of their organization and project.
+
+use consumer::attribution::Attribution;
+use consumer::producer;
+use consumer::rate_limit::{RateLimitTarget, RateLimiter};
+use consumer::schemas::envelope::TaskMessage;
+use consumer::settings::{RateLimitSettings, RateLimitUnit};
+use serde_json::json;
+use std::time::Duration;
+
+fn attribution(org: &str, project: Option<&str>) -> Attribution {
+    Attribution {
+        org: Some(org.to_string()),
+        project: project.map(str::to_string),
+        user: None,
+    }
+}
+
+#[test]
+fn attribution_moves_from_the_task_to_the_message_and_event() {
+    let task = json!({
+        "method": "POST",
+        "url": "/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [] },
+        "attribution": { "org": "acme", "project": "search", "user": "ada" },
+    });
+    let prepared = producer::prepare("batch", task, 0).unwrap();
+    assert_eq!(prepared.event["attribution"]["project"], "search");
+
+    let message = TaskMessage::parse(&prepared.message).unwrap();
+    assert!(message.payload.get("attribution").is_none());
+    let attribution = message.attribution.unwrap();
+    assert_eq!(attribution.user.as_deref(), Some("ada"));
+    assert_eq!(attribution.quota_scopes(), ["acme", "acme/search"]);
+}
+
+#[test]
+fn projects_and_users_need_their_organization() {
+    let task = json!({
+        "method": "POST",
+        "url": "/v1/chat/completions",
+        "body": {},
+        "attribution": { "project": "search" },
+    });
+    let error = producer::prepare("batch", task, 0).err().unwrap();
+    assert!(error.contains("org"), "{}", error);
+
+    let message = br#"{"message_id":"m","batch_id":"b","body_hash":"h","payload":{},"attribution":{"user":"ada"}}"#;
+    assert!(TaskMessage::parse(message).is_err());
+}
+
+#[tokio::test]
+async fn exhausted_quotas_only_hold_up_their_own_scope() {
+    let limiter = RateLimiter::new(&[], None)
+        .unwrap()
+        .with_quotas(&[RateLimitSettings {
+            pattern: "acme/search".to_string(),
+            unit: RateLimitUnit::Requests,
+            limit: 1,
+            period_secs: 3600,
+        }]);
+    let target = RateLimitTarget {
+        url: "https://api.openai.com/v1/chat/completions",
+        model: "gpt-4o",
+        api_key: "",
+    };
+    let search = attribution("acme", Some("search"));
+    limiter.acquire_for(&target, &search, 10).await;
+    let waiting = tokio::time::timeout(
+        Duration::from_millis(50),
+        limiter.acquire_for(&target, &search, 10),
+    );
+    assert!(waiting.await.is_err());
+
+    let ads = attribution("acme", Some("ads"));
+    let other = tokio::time::timeout(
+        Duration::from_millis(50),
+        limiter.acquire_for(&target, &ads, 10),
+    );
+    assert!(other.await.is_ok());
+}
@@ -0,0 +1,56 @@
+//! Rows over their label's target share are marked excess, counting only the rows
+//! that were written, so a failed write doesn't use up its label's share.
+
+use consumer::balance::BalanceTracker;
+use consumer::db::jsonl::JsonlStore;
+use consumer::settings::BalanceSettings;
+use std::collections::HashMap;
+
+fn tracker() -> BalanceTracker {
+    BalanceTracker::new(BalanceSettings {
+        targets: HashMap::from([("math".to_string(), 0.5), ("code".to_string(), 0.5)]),
+        tolerance: 0.0,
+        min_samples: 2,
+    })
+}
+
+fn store() -> JsonlStore {
+    let path = std::env::temp_dir().join(format!("balance-test-{}.jsonl", uuid::Uuid::new_v4()));
+    JsonlStore::create(&path.display().to_string()).unwrap()
+}
+
+#[tokio::test]
+async fn only_written_rows_count_towards_their_share() {
+    let store = store();
+    let tracker = tracker();
+    // Admitted rows whose write failed are never recorded.
+    for _ in 0..5 {
+        assert!(tracker.admit(&store, "batch", "math").await);
+    }
+
+    tracker.record("batch", "math");
+    tracker.record("batch", "math");
+    assert!(!tracker.admit(&store, "batch", "math").await);
+    assert!(tracker.admit(&store, "batch", "code").await);
+    // Other batches have their own counts.
+    assert!(tracker.admit(&store, "other", "math").await);
+
+    tracker.record("batch", "code");
+    tracker.record("batch", "code");
+    assert!(!tracker.admit(&store, "batch", "math").await);
+    tracker.record("batch", "code");
+    assert!(tracker.admit(&store, "batch", "math").await);
+}
+
+#[tokio::test]
+async fn labels_without_a_target_are_not_counted() {
+    let store = store();
+    let tracker = tracker();
+    for _ in 0..3 {
+        tracker.record("batch", "poetry");
+        assert!(tracker.admit(&store, "batch", "poetry").await);
+    }
+    // Two recorded rows are needed before any share is enforced.
+    tracker.record("batch", "math");
+    assert!(tracker.admit(&store, "batch", "math").await);
+}
@@ -0,0 +1,38 @@
+//! Batch counts follow tasks through their statuses, and a batch only counts as
+//! finished once none of its tasks are pending or processing.
+
+use consumer::db::BatchCounts;
+use consumer::schemas::task_status::TaskStatus;
+
+#[test]
+fn tasks_move_between_counts() {
+    let mut counts = BatchCounts::default();
+    counts.apply(None, TaskStatus::Pending, 3);
+    counts.apply(Some(TaskStatus::Pending), TaskStatus::Processing, 1);
+    counts.apply(Some(TaskStatus::Processing), TaskStatus::Completed, 1);
+    counts.apply(Some(TaskStatus::Pending), TaskStatus::Failed, 1);
+    assert_eq!(
+        counts,
+        BatchCounts {
+            total: 3,
+            pending: 1,
+            processing: 0,
+            completed: 1,
+            failed: 1,
+        }
+    );
+    assert!(!counts.is_finished());
+
+    counts.apply(Some(TaskStatus::Pending), TaskStatus::Completed, 1);
+    assert!(counts.is_finished());
+}
+
+#[test]
+fn counts_never_go_below_zero() {
+    let mut counts = BatchCounts::default();
+    counts.apply(Some(TaskStatus::Processing), TaskStatus::Completed, 1);
+    assert_eq!(counts.processing, 0);
+    assert_eq!(counts.completed, 1);
+    // Nothing was ever submitted to this batch.
+    assert!(!counts.is_finished());
+}
@@ -0,0 +1,61 @@
+//! Completions close to an earlier completion of their batch are flagged as near
+//! duplicates and clustered with it.
+
+use consumer::batch_dedup::{BatchDedup, Signature};
+use consumer::quality_gates::rejection_reasons;
+use consumer::settings::BatchDedupSettings;
+
+const SKY: &str = "The sky looks blue because air molecules scatter the short blue \
+    wavelengths of sunlight much more strongly than the longer red wavelengths.";
+const SKY_REWORDED: &str = "The sky looks blue because air molecules scatter the short blue \
+    wavelengths of sunlight much more strongly than the longer red ones.";
+const TIDES: &str = "Tides are caused by the gravitational pull of the moon and the sun \
+    on the oceans, which bulge on the sides of the earth facing and opposite the moon.";
+
+fn dedup() -> BatchDedup {
+    BatchDedup::new(BatchDedupSettings {
+        threshold: 0.6,
+        ngram_size: 3,
+        regenerate_attempts: 0,
+    })
+}
+
+#[test]
+fn signatures_estimate_the_similarity_of_texts() {
+    let sky = Signature::of(SKY, 3).unwrap();
+    assert_eq!(sky.similarity(&Signature::of(SKY, 3).unwrap()), 1.0);
+    assert!(sky.similarity(&Signature::of(SKY_REWORDED, 3).unwrap()) > 0.6);
+    assert!(sky.similarity(&Signature::of(TIDES, 3).unwrap()) < 0.2);
+    assert!(Signature::of(" ... ", 3).is_none());
+}
+
+#[test]
+fn near_duplicates_join_the_cluster_of_the_first_completion() {
+    let dedup = dedup();
+    let first = dedup.assess("batch", "m1", SKY);
+    assert!(!first.duplicate);
+    assert_eq!(first.cluster, "m1");
+    dedup.record("batch", "m1", &first);
+
+    let reworded = dedup.assess("batch", "m2", SKY_REWORDED);
+    assert!(reworded.duplicate);
+    assert_eq!(reworded.cluster, "m1");
+    dedup.record("batch", "m2", &reworded);
+    let annotations = reworded.annotations();
+    assert_eq!(annotations["dedup_cluster"], "m1");
+    assert_eq!(rejection_reasons(&annotations, None), ["duplicate"]);
+
+    let tides = dedup.assess("batch", "m3", TIDES);
+    assert!(!tides.duplicate);
+    assert_eq!(tides.cluster, "m3");
+}
+
+#[test]
+fn only_completions_of_other_tasks_in_the_batch_count() {
+    let dedup = dedup();
+    let first = dedup.assess("batch", "m1", SKY);
+    dedup.record("batch", "m1", &first);
+    // A redelivery of the same task, and the same text in another batch.
+    assert!(!dedup.assess("batch", "m1", SKY).duplicate);
+    assert!(!dedup.assess("other", "m2", SKY).duplicate);
+}
@@ -0,0 +1,62 @@
+//! `producer::body_hash` has to agree with the hashes the Python API computes, or cached
+//! completions of tasks submitted through either won't be found by the other.
+
+use consumer::producer::{body_hash, prepare};
+use serde_json::{json, Value};
+
+// Expected values computed with
+// base64.b64encode(sha256(json.dumps(body, sort_keys=True, separators=(",", ":")).encode()).digest())
+#[test]
+fn matches_python_for_non_ascii_strings() {
+    let body = json!({
+        "model": "gpt-4o",
+        "messages": [{ "role": "user", "content": "Héllo \"wörld\" 😀\n" }],
+        "temperature": 0.7,
+        "max_tokens": 256,
+    });
+    assert_eq!(
+        body_hash(&body),
+        "ANfiwHifaaG6WIOvRAyBcSoMjXax6kn6U1pM+cbtb2s="
+    );
+}
+
+#[test]
+fn matches_python_for_floats_and_nesting() {
+    let body: Value = serde_json::from_str(
+        r#"{"b":[1,2.5,1e-05,1e16,123456789.125,-0.0,null,true],"a":{"z":"\u0001","y":100.0}}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        body_hash(&body),
+        "9L9f8jxyRFJeNPuUMNeNjcE3SWTB9eL19qkfevuNzmI="
+    );
+}
+
+#[test]
+fn prepared_task_carries_the_hash_and_pending_event() {
+    let task = json!({
+        "custom_id": "row-1",
+        "method": "POST",
+        "url": "https://openrouter.ai/api/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [] },
+        "priority": 42,
+    });
+    let prepared = prepare("batch-1", task, 10).unwrap();
+    let message: Value = serde_json::from_slice(&prepared.message).unwrap();
+
+    assert_eq!(
+        prepared.body_hash,
+        body_hash(&json!({ "model": "gpt-4o", "messages": [] }))
+    );
+    assert_eq!(prepared.event["status"], "PENDING");
+    assert_eq!(prepared.event["body_hash"], prepared.body_hash);
+    assert_eq!(message["message_id"], prepared.message_id);
+    assert_eq!(message["payload"]["custom_id"], "row-1");
+    assert_eq!(prepared.priority, Some(10));
+}
+
+#[test]
+fn rejects_tasks_without_a_body() {
+    let task = json!({ "method": "POST", "url": "https://example.com" });
+    assert!(prepare("batch-1", task, 10).is_err());
+}
@@ -0,0 +1,34 @@
+//! Event selections of the bulk admin operations.
+
+use consumer::admin::selection;
+use serde_json::json;
+
+#[test]
+fn every_option_given_filters_the_selection() {
+    let message_ids = vec!["m1".to_string(), "m2".to_string()];
+    let query = selection(
+        Some(r#"{"term": {"model": "gpt-4o"}}"#),
+        Some("batch-1"),
+        &message_ids,
+    )
+    .unwrap();
+    assert_eq!(
+        query,
+        json!({ "bool": { "filter": [
+            { "term": { "model": "gpt-4o" }},
+            { "term": { "batch_id": "batch-1" }},
+            { "terms": { "message_id": ["m1", "m2"] }},
+        ]}})
+    );
+}
+
+#[test]
+fn invalid_queries_are_rejected() {
+    assert!(selection(Some("{\"term\":"), None, &[]).is_err());
+    assert!(selection(Some("[1, 2]"), None, &[]).is_err());
+}
+
+#[test]
+fn an_empty_selection_is_rejected() {
+    assert!(selection(None, None, &[]).is_err());
+}
@@ -0,0 +1,70 @@
+//! The consumer recomputes the keys tasks are cached on instead of trusting the
+//! producer's `body_hash`, when configured to.
+
+use consumer::cache_key::CacheKeys;
+use consumer::producer::{body_hash, prepare};
+use consumer::schemas::envelope::TaskMessage;
+use consumer::settings::{CacheKeyMode, CacheKeySettings};
+use serde_json::{json, Value};
+
+fn keys(mode: CacheKeyMode, excluded_fields: &[&str]) -> CacheKeys {
+    CacheKeys::new(&CacheKeySettings {
+        mode,
+        excluded_fields: excluded_fields.iter().map(|f| f.to_string()).collect(),
+        semantic_templates: false,
+        hash: Default::default(),
+    })
+}
+
+fn task(user: &str) -> Value {
+    json!({
+        "method": "POST",
+        "url": "/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [], "user": user },
+    })
+}
+
+#[test]
+fn verification_rejects_hashes_that_do_not_match_the_payload() {
+    let prepared = prepare("batch", task("ada"), 0).unwrap();
+    let message = TaskMessage::parse(&prepared.message).unwrap();
+    let verify = keys(CacheKeyMode::Verify, &[]);
+    assert_eq!(
+        verify.resolve(&message.payload, &message.body_hash),
+        Ok(prepared.body_hash.clone())
+    );
+
+    let forged = body_hash(&json!({ "model": "gpt-4o", "messages": [] }));
+    assert!(verify.resolve(&message.payload, &forged).is_err());
+    assert_eq!(
+        keys(CacheKeyMode::Trust, &[]).resolve(&message.payload, &forged),
+        Ok(forged)
+    );
+}
+
+#[test]
+fn excluded_fields_are_left_out_of_replaced_keys() {
+    let replace = keys(CacheKeyMode::Replace, &["user"]);
+    let first = replace.resolve(&task("ada"), "ignored").unwrap();
+    let second = replace.resolve(&task("grace"), "ignored").unwrap();
+    assert_eq!(first, second);
+    assert_eq!(
+        first,
+        body_hash(&json!({ "model": "gpt-4o", "messages": [] }))
+    );
+}
+
+#[test]
+fn conversation_plans_are_part_of_the_hash() {
+    let mut conversation = task("ada");
+    conversation["kind"] = json!("conversation");
+    conversation["conversation"] = json!({ "turns": ["Hi", "And then?"] });
+    let prepared = prepare("batch", conversation.clone(), 0).unwrap();
+    assert_eq!(
+        prepared.body_hash,
+        body_hash(&json!({
+            "body": conversation["body"],
+            "conversation": conversation["conversation"],
+        }))
+    );
+}
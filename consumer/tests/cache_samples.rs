@@ -0,0 +1,62 @@
+//! Bodies sampled with a temperature above zero collect several generated responses
+//! before duplicates are served from the cache, and spread over them.
+
+use chrono::Utc;
+use consumer::cache_samples::{is_stochastic, pick, sample_limit};
+use consumer::schemas::llm_response::LLMResponse;
+use serde_json::json;
+use std::collections::HashSet;
+
+fn sample(text: &str) -> LLMResponse {
+    LLMResponse {
+        completions: json!({ "choices": [{ "message": { "content": text } }] }),
+        cached: true,
+        attempt: 0,
+        started_at: Utc::now(),
+        completed_at: Utc::now(),
+        annotations: Default::default(),
+        usage: None,
+        cost: None,
+    }
+}
+
+#[test]
+fn only_positive_temperatures_are_stochastic() {
+    assert!(is_stochastic(
+        &json!({ "model": "gpt-4o", "temperature": 0.7 })
+    ));
+    assert!(!is_stochastic(
+        &json!({ "model": "gpt-4o", "temperature": 0 })
+    ));
+    assert!(!is_stochastic(&json!({ "model": "gpt-4o" })));
+
+    assert_eq!(sample_limit(&json!({ "cache_samples": 5 }), 3), 5);
+    assert_eq!(sample_limit(&json!({ "use_cache": true }), 3), 3);
+}
+
+#[test]
+fn samples_are_generated_until_enough_were_collected() {
+    assert!(pick(vec![sample("a"), sample("b")], 3, "msg-1").is_none());
+    assert!(pick(vec![sample("a")], 0, "msg-1").is_none());
+
+    let samples = || vec![sample("a"), sample("b"), sample("c")];
+    let first = pick(samples(), 3, "msg-1").unwrap();
+    assert!(first.cached);
+    // Redeliveries of a task get the same sample.
+    assert_eq!(
+        pick(samples(), 3, "msg-1").unwrap().completions,
+        first.completions
+    );
+}
+
+#[test]
+fn duplicates_spread_over_the_collected_samples() {
+    let samples = || vec![sample("a"), sample("b"), sample("c"), sample("d")];
+    let served: HashSet<String> = (0..64)
+        .map(|i| pick(samples(), 2, &format!("msg-{}", i)).unwrap())
+        .map(|response| response.completions.to_string())
+        .collect();
+    // Only the oldest `limit` samples are ever served.
+    assert_eq!(served.len(), 2);
+    assert!(served.iter().all(|s| s.contains('a') || s.contains('b')));
+}
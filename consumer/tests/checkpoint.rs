@@ -0,0 +1,61 @@
+//! A checkpointed generation reads back as the response it was saved from, and the
+//! task's final status clears it.
+
+use chrono::{TimeZone, Utc};
+use consumer::db::{checkpoint_document, event_update_fields, parse_checkpoint};
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::provider_response::Usage;
+use consumer::schemas::task_status::TaskStatus;
+use serde_json::{json, Value};
+
+fn generation() -> LLMResponse {
+    LLMResponse {
+        completions: json!({ "choices": [{ "message": { "content": "Hello" } }] }),
+        cached: false,
+        attempt: 2,
+        started_at: Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 5).unwrap(),
+        completed_at: Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 9).unwrap(),
+        annotations: json!({ "provider": "primary", "repair_attempts": 1 })
+            .as_object()
+            .cloned()
+            .unwrap(),
+        usage: Some(Usage {
+            prompt_tokens: 12,
+            completion_tokens: 3,
+            total_tokens: 15,
+        }),
+        cost: Some(0.5),
+    }
+}
+
+#[test]
+fn checkpoints_round_trip_without_the_cost() {
+    // Stored as JSON in the events document, so read it back from its serialized form.
+    let stored: Value =
+        serde_json::from_str(&checkpoint_document(&generation()).to_string()).unwrap();
+    let resumed = parse_checkpoint(&stored).unwrap();
+    let original = generation();
+
+    assert_eq!(resumed.completions, original.completions);
+    assert_eq!(resumed.annotations, original.annotations);
+    assert_eq!(resumed.usage, original.usage);
+    assert_eq!(resumed.attempt, 2);
+    assert_eq!(resumed.started_at, original.started_at);
+    assert_eq!(resumed.completed_at, original.completed_at);
+    // Priced again by the post-processing it resumes at.
+    assert_eq!(resumed.cost, None);
+    assert!(!resumed.cached);
+
+    assert!(parse_checkpoint(&Value::Null).is_none());
+}
+
+#[test]
+fn final_statuses_clear_the_checkpoint() {
+    let response = generation();
+    for status in [TaskStatus::Completed, TaskStatus::Failed] {
+        let fields = event_update_fields(status, &response, response.started_at);
+        assert_eq!(fields.get("checkpoint"), Some(&Value::Null));
+    }
+    let fields = event_update_fields(TaskStatus::Processing, &response, response.started_at);
+    assert!(fields.get("checkpoint").is_none());
+}
@@ -0,0 +1,79 @@
+//! A provider's circuit opens after consecutive failures, holds attempts back while
+//! open, and lets a single probe through once its cooldown is over.
+
+use consumer::circuit_breaker::CircuitBreakers;
+use consumer::settings::CircuitBreakerSettings;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const URL: &str = "http://127.0.0.1:1/v1/chat/completions";
+
+fn breakers() -> Arc<CircuitBreakers> {
+    Arc::new(CircuitBreakers::new(&CircuitBreakerSettings {
+        failure_threshold: 2,
+        open_secs: 1,
+        max_open_secs: 4,
+    }))
+}
+
+async fn fail(breakers: &CircuitBreakers, times: usize) {
+    for _ in 0..times {
+        breakers.admit(URL, None).await.unwrap().settle(false);
+    }
+}
+
+#[tokio::test]
+async fn opens_after_consecutive_failures_and_closes_after_a_probe() {
+    let breakers = breakers();
+    fail(&breakers, 1).await;
+    breakers.admit(URL, None).await.unwrap().settle(true);
+    fail(&breakers, 2).await;
+
+    // Open: attempts that couldn't start before their deadline give up right away.
+    let started = Instant::now();
+    let soon = Some(Instant::now() + Duration::from_millis(200));
+    assert!(breakers.admit(URL, soon).await.is_err());
+    assert!(breakers
+        .admit("http://127.0.0.1:1/v1/embeddings", soon)
+        .await
+        .is_ok());
+
+    let probe = breakers.admit(URL, None).await.unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(900));
+    let waiting = tokio::spawn({
+        let breakers = breakers.clone();
+        async move { breakers.admit(URL, None).await.is_ok() }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!waiting.is_finished());
+
+    probe.settle(true);
+    assert!(waiting.await.unwrap());
+    assert!(breakers.admit(URL, soon).await.is_ok());
+}
+
+#[tokio::test]
+async fn failed_or_abandoned_probes_reopen_the_circuit() {
+    let breakers = breakers();
+    fail(&breakers, 2).await;
+
+    // A failed probe doubles the cooldown.
+    breakers.admit(URL, None).await.unwrap().settle(false);
+    let in_1500ms = Some(Instant::now() + Duration::from_millis(1500));
+    assert!(breakers.admit(URL, in_1500ms).await.is_err());
+
+    // A probe dropped before settling lets the next attempt probe.
+    let probe = breakers.admit(URL, None).await.unwrap();
+    let next = tokio::spawn({
+        let breakers = breakers.clone();
+        async move {
+            breakers
+                .admit(URL, None)
+                .await
+                .map(|permit| permit.settle(true))
+        }
+    });
+    drop(probe);
+    assert!(next.await.unwrap().is_ok());
+}
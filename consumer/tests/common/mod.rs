@@ -0,0 +1,118 @@
+//! Stub servers and fixtures shared by the integration tests.
+
+// Each test crate uses its own subset.
+#![allow(dead_code)]
+
+use consumer::settings::HttpSettings;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Upstream client settings for talking to the stub servers: small pools, a short
+/// connect timeout and the system resolver.
+pub fn http_settings() -> HttpSettings {
+    HttpSettings {
+        pool_max_idle_per_host: Some(1),
+        pool_idle_timeout_secs: 1,
+        connect_timeout_ms: Some(1000),
+        dns_cache_size: 0,
+        dns_min_ttl_secs: 0,
+        ..HttpSettings::default()
+    }
+}
+
+/// An HTTP response closing its connection, with `headers` besides its length.
+pub fn response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    format!(
+        "HTTP/1.1 {}\r\n{}content-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body
+    )
+}
+
+/// A 200 response with a JSON `body`.
+pub fn json_response(body: &str) -> String {
+    response("200 OK", &[("content-type", "application/json")], body)
+}
+
+/// Reads one HTTP request, head and body, up to its `content-length` or the end of
+/// the stream.
+pub async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+    let mut received = Vec::new();
+    let mut buffer = vec![0; 8192];
+    loop {
+        let read = stream.read(&mut buffer).await.unwrap();
+        if read == 0 {
+            break;
+        }
+        received.extend_from_slice(&buffer[..read]);
+        let request = String::from_utf8_lossy(&received);
+        let Some((head, body)) = request.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let length: usize = head
+            .lines()
+            .find_map(|line| {
+                line.to_lowercase()
+                    .strip_prefix("content-length: ")?
+                    .parse()
+                    .ok()
+            })
+            .unwrap_or_default();
+        if body.len() >= length {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&received).into_owned()
+}
+
+/// Reads a request and answers it with `response`, returning the request. The client
+/// may hang up before reading all of the response.
+pub async fn answer<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, response: &str) -> String {
+    let request = read_request(stream).await;
+    let _ = stream.write_all(response.as_bytes()).await;
+    request
+}
+
+/// Accepts one connection and answers its request with `response`, returning the
+/// request.
+pub async fn serve_once(response: String) -> (SocketAddr, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let request = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        answer(&mut stream, &response).await
+    });
+    (address, request)
+}
+
+/// Answers the requests to `path` with `respond(served, request)`, `served` counting
+/// the requests answered before; returns the URL of `path`.
+pub async fn serve<F>(path: &str, mut respond: F) -> String
+where
+    F: FnMut(usize, String) -> String + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}{}", listener.local_addr().unwrap(), path);
+    tokio::spawn(async move {
+        for served in 0.. {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = read_request(&mut stream).await;
+            let _ = stream.write_all(respond(served, request).as_bytes()).await;
+        }
+    });
+    url
+}
+
+/// Accepts one upload, answers it and returns the request.
+pub async fn object_store() -> (String, JoinHandle<String>) {
+    let (address, upload) = serve_once(response("200 OK", &[], "")).await;
+    (format!("http://{}", address), upload)
+}
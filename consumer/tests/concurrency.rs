@@ -0,0 +1,54 @@
+//! Requests wait for a slot of every concurrency limit matching their route, and a
+//! saturated route doesn't hold up the others.
+
+use consumer::concurrency::ConcurrencyLimiter;
+use consumer::rate_limit::RateLimitTarget;
+use consumer::settings::ConcurrencyLimitSettings;
+use std::time::Duration;
+
+fn target<'a>(url: &'a str, model: &'a str) -> RateLimitTarget<'a> {
+    RateLimitTarget {
+        url,
+        model,
+        api_key: "",
+    }
+}
+
+#[tokio::test]
+async fn saturated_routes_only_block_their_own_requests() {
+    let limiter = ConcurrencyLimiter::new(
+        &[ConcurrencyLimitSettings {
+            pattern: "local-vllm".to_string(),
+            limit: 1,
+        }],
+        100,
+    );
+    let vllm = target("http://local-vllm:8000/v1/chat/completions", "llama");
+    let held = limiter.acquire(&vllm).await;
+    assert_eq!(held.len(), 1);
+
+    let waiting = tokio::time::timeout(Duration::from_millis(50), limiter.acquire(&vllm));
+    assert!(waiting.await.is_err());
+
+    let openai = target("https://api.openai.com/v1/chat/completions", "gpt-4o");
+    assert!(limiter.acquire(&openai).await.is_empty());
+
+    drop(held);
+    assert_eq!(limiter.acquire(&vllm).await.len(), 1);
+}
+
+#[tokio::test]
+async fn limits_are_capped_by_the_global_one() {
+    let limiter = ConcurrencyLimiter::new(
+        &[ConcurrencyLimitSettings {
+            pattern: "gpt".to_string(),
+            limit: 10,
+        }],
+        2,
+    );
+    let gpt = target("https://api.openai.com/v1/chat/completions", "gpt-4o");
+    let _first = limiter.acquire(&gpt).await;
+    let _second = limiter.acquire(&gpt).await;
+    let third = tokio::time::timeout(Duration::from_millis(50), limiter.acquire(&gpt));
+    assert!(third.await.is_err());
+}
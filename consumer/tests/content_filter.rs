@@ -0,0 +1,61 @@
+//! PII is redacted from every text of a completion, with the raw text kept aside, and
+//! blocked terms flag it for the quality gates.
+
+use consumer::llm_wrapper::LLMClient;
+use consumer::postprocess::ContentFilter;
+use consumer::quality_gates::rejection_reasons;
+use consumer::settings::ContentFilterSettings;
+use serde_json::json;
+use std::collections::HashMap;
+
+fn filter(blocked_terms: &[&str]) -> ContentFilter {
+    ContentFilter::new(&ContentFilterSettings {
+        pii: vec!["email".to_string(), "credit_card".to_string()],
+        patterns: HashMap::from([("ticket".to_string(), r"\bTCK-\d+\b".to_string())]),
+        blocked_terms: blocked_terms.iter().map(|t| t.to_string()).collect(),
+        moderation: None,
+    })
+    .unwrap()
+}
+
+#[tokio::test]
+async fn redacts_pii_and_keeps_the_raw_completion() {
+    let raw = "Mail jane.doe@example.com about TCK-42, card 4111 1111 1111 1111 (not 1234 5678 9012 3456).";
+    let mut completions = json!({
+        "choices": [{ "message": { "role": "assistant", "content": raw } }],
+    });
+    let annotations = filter(&[]).apply(&LLMClient::new(), &mut completions).await;
+
+    assert_eq!(
+        completions["choices"][0]["message"]["content"],
+        "Mail [REDACTED:email] about [REDACTED:ticket], card [REDACTED:credit_card] (not 1234 5678 9012 3456)."
+    );
+    assert_eq!(annotations["raw_completion"], raw);
+    assert_eq!(
+        annotations["moderation"],
+        json!({
+            "verdict": "redacted",
+            "redactions": { "credit_card": 1, "email": 1, "ticket": 1 },
+            "blocked_terms": [],
+        })
+    );
+    assert!(rejection_reasons(&annotations, None).is_empty());
+}
+
+#[tokio::test]
+async fn blocked_terms_flag_the_completion() {
+    let mut completions = json!({
+        "content": [{ "type": "text", "text": "Here is how to make Napalm at home." }],
+    });
+    let annotations = filter(&["napalm", "nerve agent"])
+        .apply(&LLMClient::new(), &mut completions)
+        .await;
+
+    assert_eq!(annotations["moderation"]["verdict"], "flagged");
+    assert_eq!(
+        annotations["moderation"]["blocked_terms"],
+        json!(["napalm"])
+    );
+    assert!(annotations.get("raw_completion").is_none());
+    assert_eq!(rejection_reasons(&annotations, None), ["moderation"]);
+}
@@ -0,0 +1,107 @@
+//! Conversation tasks are generated turn by turn, each request carrying the replies so
+//! far, following fixed turns or a follow-up template.
+
+use consumer::conversations::Conversation;
+use consumer::schemas::envelope::TaskPayload;
+use consumer::templates::PromptTemplates;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+fn payload(conversation: Value) -> Value {
+    json!({
+        "method": "POST",
+        "url": "u",
+        "kind": "conversation",
+        "body": {
+            "model": "gpt-4o",
+            "messages": [{ "role": "user", "content": "Hi, I need a recipe." }]
+        },
+        "variables": { "persona": "a picky eater" },
+        "conversation": conversation,
+    })
+}
+
+#[test]
+fn fixed_turns_follow_each_reply() {
+    let templates = PromptTemplates::new(HashMap::new());
+    let payload = payload(json!({ "turns": ["Without onions?", "Thanks!"] }));
+    let mut conversation = Conversation::start(&payload).unwrap();
+    assert_eq!(conversation.body()["messages"].as_array().unwrap().len(), 1);
+
+    let body = conversation
+        .next_turn("Try a risotto.", &templates, &payload["variables"])
+        .unwrap()
+        .unwrap();
+    assert_eq!(body["model"], "gpt-4o");
+    assert_eq!(
+        body["messages"][1],
+        json!({ "role": "assistant", "content": "Try a risotto." })
+    );
+    assert_eq!(
+        body["messages"][2],
+        json!({ "role": "user", "content": "Without onions?" })
+    );
+    assert!(conversation
+        .next_turn("Sure, leave them out.", &templates, &Value::Null)
+        .unwrap()
+        .is_some());
+    assert!(conversation
+        .next_turn("You're welcome!", &templates, &Value::Null)
+        .unwrap()
+        .is_none());
+
+    let annotations = conversation.annotations();
+    assert_eq!(annotations["conversation_turns"], 3);
+    let transcript = annotations["transcript"].as_array().unwrap();
+    assert_eq!(transcript.len(), 6);
+    assert_eq!(transcript[5]["content"], "You're welcome!");
+}
+
+#[test]
+fn follow_ups_are_rendered_from_the_transcript() {
+    let templates = PromptTemplates::new(HashMap::new());
+    let payload = payload(json!({
+        "follow_up": {
+            "role": "user",
+            "content": "{% if turn < 2 %}As {{ persona }}, reply to: {{ reply }} ({{ transcript | length }} messages){% endif %}"
+        },
+        "max_turns": 5,
+    }));
+    let variables = &payload["variables"];
+    let mut conversation = Conversation::start(&payload).unwrap();
+    let body = conversation
+        .next_turn("Try a risotto.", &templates, variables)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        body["messages"][2]["content"],
+        "As a picky eater, reply to: Try a risotto. (2 messages)"
+    );
+    // Rendered empty, so the conversation ends before `max_turns`.
+    assert!(conversation
+        .next_turn("Mushroom, then.", &templates, variables)
+        .unwrap()
+        .is_none());
+    assert_eq!(conversation.annotations()["conversation_turns"], 2);
+}
+
+#[test]
+fn plans_are_checked_with_the_payload() {
+    let error = |conversation: Value| TaskPayload::from_value(&payload(conversation)).unwrap_err();
+    assert_eq!(
+        error(json!({ "follow_up": "next" })),
+        "conversation.max_turns: required with follow_up"
+    );
+    assert_eq!(
+        error(json!({ "turns": ["a"], "follow_up": "next", "max_turns": 2 })),
+        "conversation: either turns or follow_up, not both"
+    );
+    assert!(error(json!({ "turns": "a" })).starts_with("conversation.turns: invalid type"));
+
+    let mut missing = payload(Value::Null);
+    missing.as_object_mut().unwrap().remove("conversation");
+    assert_eq!(
+        TaskPayload::from_value(&missing).unwrap_err(),
+        "conversation: required for conversation tasks"
+    );
+}
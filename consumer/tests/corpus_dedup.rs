@@ -0,0 +1,110 @@
+//! Completions whose normalized text is in an existing corpus, listed as digests or in a
+//! bloom filter, are tagged as duplicates; any other difference in the text is not one.
+
+use consumer::corpus_dedup::{digest, BloomFilter, CorpusIndex};
+use consumer::settings::CorpusDedupSettings;
+use sha2::{Digest, Sha256};
+
+const CORPUS_TEXT: &str = "The quick  brown fox\njumps over the lazy dog.\n";
+
+fn write(name: &str, contents: &[u8]) -> String {
+    let path = std::env::temp_dir().join(format!("corpus-{}-{}", uuid::Uuid::new_v4(), name));
+    std::fs::write(&path, contents).unwrap();
+    path.display().to_string()
+}
+
+/// Bloom filter holding `digests`, laid out as `corpus_dedup` documents it.
+fn bloom(digests: &[[u8; 32]]) -> Vec<u8> {
+    let (num_hashes, num_bits) = (3u32, 1u64 << 16);
+    let mut bits = vec![0u8; (num_bits / 8) as usize];
+    for digest in digests {
+        let h1 = u64::from_le_bytes(digest[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(digest[8..16].try_into().unwrap());
+        for i in 0..num_hashes as u64 {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % num_bits;
+            bits[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+    }
+    let mut bytes = b"SGBLOOM1".to_vec();
+    bytes.extend_from_slice(&num_hashes.to_le_bytes());
+    bytes.extend_from_slice(&num_bits.to_le_bytes());
+    bytes.extend_from_slice(&bits);
+    bytes
+}
+
+#[test]
+fn only_whitespace_differences_are_duplicates() {
+    let listed = hex::encode(digest(CORPUS_TEXT));
+    let hashes = write(
+        "hashes.txt",
+        format!("not a digest\n{}\n", listed).as_bytes(),
+    );
+    let index = CorpusIndex::load(&CorpusDedupSettings {
+        hash_files: vec![hashes],
+    })
+    .unwrap();
+
+    for exact in [
+        "The quick brown fox jumps over the lazy dog.",
+        "  The quick\tbrown fox\r\njumps over  the lazy dog.  ",
+    ] {
+        let annotations = index.check(exact);
+        assert_eq!(annotations["corpus_duplicate"], true, "{:?}", exact);
+        assert_eq!(annotations["completion_hash"], listed.as_str());
+    }
+    for near in [
+        "The quick brown fox jumps over the lazy dog",
+        "the quick brown fox jumps over the lazy dog.",
+        "The quick brown fox jumped over the lazy dog.",
+    ] {
+        assert_eq!(index.check(near)["corpus_duplicate"], false, "{:?}", near);
+    }
+}
+
+#[test]
+fn bloom_filters_hold_corpus_digests() {
+    let filter = write("corpus.bloom", &bloom(&[digest(CORPUS_TEXT)]));
+    let index = CorpusIndex::load(&CorpusDedupSettings {
+        hash_files: vec![filter],
+    })
+    .unwrap();
+    assert_eq!(
+        index.check("The quick brown fox jumps over the lazy dog.")["corpus_duplicate"],
+        true
+    );
+    assert_eq!(
+        index.check("A different sentence.")["corpus_duplicate"],
+        false
+    );
+    // Digests are of the normalized text.
+    let normalized = "The quick brown fox jumps over the lazy dog.";
+    assert_eq!(
+        digest(CORPUS_TEXT),
+        <[u8; 32]>::from(Sha256::digest(normalized.as_bytes()))
+    );
+
+    assert!(BloomFilter::from_bytes(b"SGBLOOM2").is_err());
+    let mut truncated = bloom(&[]);
+    truncated.truncate(100);
+    assert!(BloomFilter::from_bytes(&truncated).is_err());
+}
+
+#[test]
+fn bloom_filters_without_hashes_or_bits_are_rejected() {
+    let header = |num_hashes: u32, num_bits: u64| {
+        let mut bytes = b"SGBLOOM1".to_vec();
+        bytes.extend_from_slice(&num_hashes.to_le_bytes());
+        bytes.extend_from_slice(&num_bits.to_le_bytes());
+        bytes.extend_from_slice(&[0xff; 8]);
+        bytes
+    };
+    assert!(BloomFilter::from_bytes(&header(3, 64)).is_ok());
+    assert!(BloomFilter::from_bytes(&header(0, 64)).is_err());
+    assert!(BloomFilter::from_bytes(&header(3, 0)).is_err());
+
+    let filter = write("empty.bloom", &header(0, 64));
+    assert!(CorpusIndex::load(&CorpusDedupSettings {
+        hash_files: vec![filter],
+    })
+    .is_err());
+}
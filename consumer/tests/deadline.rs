@@ -0,0 +1,45 @@
+//! `call_llm` gives up on retries that can't start before the deadline it runs under.
+
+use consumer::llm_wrapper::{call_llm, with_deadline, DeadlineUnreachable, LLMClient};
+use consumer::settings::{EmptyCompletionSettings, ProviderApi};
+use serde_json::json;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Nothing listens on port 1, so every attempt fails at once with a transient
+// connection error.
+async fn call_unreachable_endpoint(base_delay_ms: u64) -> Box<dyn std::error::Error + Send + Sync> {
+    call_llm(
+        &LLMClient::new(),
+        "http://127.0.0.1:1/v1/chat/completions",
+        &json!({ "model": "m", "messages": [] }),
+        "",
+        ProviderApi::OpenAiCompatible,
+        "",
+        "",
+        5,
+        base_delay_ms,
+        60,
+        &EmptyCompletionSettings::UNADJUSTED,
+    )
+    .await
+    .unwrap_err()
+}
+
+#[tokio::test]
+async fn gives_up_when_the_next_retry_starts_past_the_deadline() {
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(2);
+    let error = with_deadline(deadline, call_unreachable_endpoint(30_000)).await;
+
+    assert!(error.is::<DeadlineUnreachable>(), "{}", error);
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn retries_that_fit_the_deadline_run_to_exhaustion() {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let error = with_deadline(deadline, call_unreachable_endpoint(1)).await;
+
+    assert!(!error.is::<DeadlineUnreachable>(), "{}", error);
+}
@@ -0,0 +1,60 @@
+//! What ends up in a task's `debug_trace`: secrets never, long strings cut short, and
+//! attempts only while the trace is current.
+
+use consumer::debug_trace::{self, Trace};
+use consumer::settings::DebugTraceMode;
+use serde_json::json;
+
+#[test]
+fn redacts_secrets_at_any_depth() {
+    let value = json!({
+        "url": "https://example.com",
+        "api_key": "sk-1",
+        "providers": [{ "name": "a", "API_KEY": "sk-2" }],
+        "headers": { "Authorization": "Bearer sk-3" },
+        "max_tokens": 10,
+    });
+    let redacted = debug_trace::redact(&value);
+    assert_eq!(redacted["api_key"], "[redacted]");
+    assert_eq!(redacted["providers"][0]["API_KEY"], "[redacted]");
+    assert_eq!(redacted["headers"]["Authorization"], "[redacted]");
+    assert_eq!(redacted["max_tokens"], 10);
+    assert!(!redacted.to_string().contains("sk-"));
+}
+
+#[test]
+fn snippets_truncate_long_strings() {
+    let long = "é".repeat(2000);
+    let snippet = debug_trace::snippet(&json!({ "messages": [{ "content": long }] }));
+    let content = snippet["messages"][0]["content"].as_str().unwrap();
+    assert!(content.chars().count() < 600);
+    assert!(content.ends_with("(2000 chars)"));
+}
+
+#[tokio::test]
+async fn records_attempts_only_within_the_scope() {
+    let message = json!({ "message_id": "m-1", "payload": { "api_key": "sk-1" } });
+    let trace = Trace::start(DebugTraceMode::Failed, &message).unwrap();
+
+    debug_trace::record_attempt(json!({ "attempt": 0 }));
+    trace
+        .scope(async {
+            assert!(debug_trace::is_recording());
+            debug_trace::record_attempt(json!({ "attempt": 1 }));
+        })
+        .await;
+    assert!(!debug_trace::is_recording());
+
+    assert_eq!(trace.finish(false), None);
+    let stored = trace.finish(true).unwrap();
+    assert_eq!(stored["attempts"], json!([{ "attempt": 1 }]));
+    assert_eq!(stored["message"]["payload"]["api_key"], "[redacted]");
+}
+
+#[test]
+fn debug_tasks_are_traced_whatever_the_mode() {
+    let message = json!({ "payload": { "debug": true } });
+    let trace = Trace::start(DebugTraceMode::Off, &message).unwrap();
+    assert!(trace.finish(false).is_some());
+    assert!(Trace::start(DebugTraceMode::Off, &json!({ "payload": {} })).is_none());
+}
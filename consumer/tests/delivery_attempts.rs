@@ -0,0 +1,39 @@
+//! Deliveries count an attempt each time a message is redelivered after a failure, but
+//! not when it is handed back without running.
+
+use consumer::broker::local::LocalBroker;
+use consumer::broker::{BrokerMessage, Receipt, ATTEMPT_HEADER};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+fn message(redelivered: bool, headers: BTreeMap<String, String>) -> BrokerMessage {
+    let (broker, _tasks) = LocalBroker::new(1, vec![]);
+    BrokerMessage::new(
+        b"task".to_vec(),
+        redelivered,
+        headers,
+        None,
+        Receipt::Local,
+        Arc::new(broker),
+    )
+}
+
+fn headers(name: &str, value: &str) -> BTreeMap<String, String> {
+    BTreeMap::from([(name.to_string(), value.to_string())])
+}
+
+#[test]
+fn released_copies_keep_the_delivery_attempt() {
+    for (redelivered, headers, attempt) in [
+        (false, BTreeMap::new(), 1),
+        (true, BTreeMap::new(), 2),
+        (false, headers(ATTEMPT_HEADER, "3"), 3),
+        (true, headers("x-delivery-count", "3"), 4),
+    ] {
+        let original = message(redelivered, headers);
+        assert_eq!(original.delivery_attempt(), attempt);
+        // Published back, the copy is delivered without the redelivered flag.
+        let copy = message(false, original.release_headers());
+        assert_eq!(copy.delivery_attempt(), attempt);
+    }
+}
@@ -0,0 +1,126 @@
+//! Providers outside their dispatch window are skipped or held for, in the window's
+//! own time zone.
+
+use chrono::{DateTime, Utc};
+use consumer::dispatch_windows::{Closed, DispatchWindows, WindowClosed};
+use consumer::llm_wrapper::{with_deadline, DeadlineUnreachable};
+use consumer::settings::{DispatchWindow, DispatchWindowSettings, OutsideWindow, ProviderSettings};
+use std::time::Duration;
+use tokio::time::Instant;
+
+fn provider(name: &str) -> ProviderSettings {
+    ProviderSettings {
+        name: name.to_string(),
+        url: format!("http://{}/v1/chat/completions", name),
+        api_key: String::new(),
+        model: None,
+        completions_url: None,
+        api: Default::default(),
+    }
+}
+
+fn windows(windows: &[(&str, &str, &str, OutsideWindow)], max_hold_secs: u64) -> DispatchWindows {
+    DispatchWindows::new(&DispatchWindowSettings {
+        windows: windows
+            .iter()
+            .map(|(name, start, end, outside)| {
+                let window = DispatchWindow {
+                    start: start.to_string(),
+                    end: end.to_string(),
+                    timezone: Some("+02:00".to_string()),
+                    outside: *outside,
+                };
+                (name.to_string(), window)
+            })
+            .collect(),
+        max_hold_secs,
+    })
+}
+
+fn at(time: &str) -> DateTime<Utc> {
+    format!("2026-03-02T{}Z", time).parse().unwrap()
+}
+
+fn names(providers: Vec<ProviderSettings>) -> Vec<String> {
+    providers.into_iter().map(|p| p.name).collect()
+}
+
+#[test]
+fn windows_spanning_midnight_follow_their_time_zone() {
+    let windows = windows(&[("gpu", "22:00", "06:00", OutsideWindow::Hold)], 600);
+    let providers = [provider("gpu"), provider("cloud")];
+
+    // 21:00 UTC is 23:00 at +02:00, and 03:30 UTC is 05:30.
+    for now in [at("21:00:00"), at("03:30:00")] {
+        assert_eq!(
+            names(windows.select(&providers, now).unwrap()),
+            ["gpu", "cloud"]
+        );
+    }
+    // At 10:00 local the first choice holds the task until 22:00.
+    assert_eq!(
+        windows.select(&providers, at("08:00:00")),
+        Err(Closed {
+            provider: "gpu".to_string(),
+            opens_in: Duration::from_secs(12 * 3600),
+        })
+    );
+    // Behind an open provider, it's only left out.
+    let providers = [provider("cloud"), provider("gpu")];
+    assert_eq!(
+        names(windows.select(&providers, at("08:00:00")).unwrap()),
+        ["cloud"]
+    );
+}
+
+#[test]
+fn skipped_providers_route_to_alternates() {
+    let windows = windows(
+        &[
+            ("gpu", "22:00", "06:00", OutsideWindow::Skip),
+            ("spare", "12:00", "14:00", OutsideWindow::Skip),
+        ],
+        600,
+    );
+    let providers = [provider("gpu"), provider("cloud")];
+    assert_eq!(
+        names(windows.select(&providers, at("08:00:00")).unwrap()),
+        ["cloud"]
+    );
+
+    // With nowhere else to go, the task waits for the earliest window.
+    let providers = [provider("gpu"), provider("spare")];
+    assert_eq!(
+        windows
+            .select(&providers, at("08:00:00"))
+            .unwrap_err()
+            .provider,
+        "spare"
+    );
+}
+
+#[tokio::test]
+async fn held_tasks_give_up_past_their_deadline_or_the_hold_limit() {
+    // Closed all day but for the minute before midnight, local time; skipped when
+    // that's too close to tell a hold from a deadline.
+    let windows = windows(&[("gpu", "23:59", "23:59:59", OutsideWindow::Hold)], 1);
+    let now = Utc::now().with_timezone(&chrono::FixedOffset::east_opt(7200).unwrap());
+    if matches!(now.format("%H:%M").to_string().as_str(), "23:58" | "23:59") {
+        return;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let error = with_deadline(deadline, windows.wait(vec![provider("gpu")], true))
+        .await
+        .unwrap_err();
+    assert!(error.is::<DeadlineUnreachable>());
+
+    let started = Instant::now();
+    let error = windows.wait(vec![provider("gpu")], true).await.unwrap_err();
+    assert!(error.is::<WindowClosed>());
+    assert!(started.elapsed() >= Duration::from_secs(1));
+
+    // Providers without a window are never held.
+    let providers = windows.wait(vec![provider("cloud")], true).await.unwrap();
+    assert_eq!(names(providers), ["cloud"]);
+}
@@ -0,0 +1,65 @@
+//! Samples of the same prompt, choices of one response or earlier generations, are
+//! compared so near duplicates can be flagged and the group scored.
+
+use consumer::diversity::{assess, sample_texts};
+use consumer::settings::DiversitySettings;
+use serde_json::json;
+
+fn settings() -> DiversitySettings {
+    DiversitySettings {
+        threshold: 0.8,
+        ngram_size: 2,
+        group_samples: 20,
+        regenerate_attempts: 1,
+    }
+}
+
+#[test]
+fn every_choice_is_a_sample() {
+    let completions = json!({
+        "choices": [
+            { "message": { "role": "assistant", "content": "Paris is the capital." } },
+            { "message": { "role": "assistant", "content": "It's Paris." } },
+        ]
+    });
+    assert_eq!(
+        sample_texts(&completions),
+        ["Paris is the capital.", "It's Paris."]
+    );
+
+    let anthropic = json!({ "type": "message", "content": [{ "type": "text", "text": "Paris" }] });
+    assert_eq!(sample_texts(&anthropic), ["Paris"]);
+}
+
+#[test]
+fn near_identical_choices_are_flagged() {
+    let samples = [
+        "The quick brown fox jumps over the lazy dog.".to_string(),
+        "The quick brown fox jumps over the lazy dog!".to_string(),
+        "A slow green turtle naps under a warm rock.".to_string(),
+    ];
+    let diversity = assess(&settings(), &[], &samples);
+    assert_eq!(diversity.near_duplicates, 2);
+    assert_eq!(diversity.max_similarity, 1.0);
+    assert_eq!(diversity.group_size, 3);
+    // One identical pair out of three, the others share nothing.
+    assert!((diversity.score - 2.0 / 3.0).abs() < 1e-9);
+    assert_eq!(diversity.annotations()["near_duplicate"], true);
+}
+
+#[test]
+fn new_samples_are_compared_with_earlier_ones() {
+    let earlier = ["Roses are red and violets are blue.".to_string()];
+    let repeated = assess(&settings(), &earlier, &earlier);
+    assert_eq!(repeated.near_duplicates, 1);
+    assert_eq!(repeated.score, 0.0);
+
+    let fresh = ["Sugar is sweet and so are you.".to_string()];
+    let diversity = assess(&settings(), &earlier, &fresh);
+    assert_eq!(diversity.near_duplicates, 0);
+    assert!(diversity.score > 0.9);
+    assert_eq!(diversity.annotations()["diversity_group_size"], 2);
+
+    let alone = assess(&settings(), &[], &fresh);
+    assert_eq!((alone.score, alone.max_similarity), (1.0, 0.0));
+}
@@ -0,0 +1,81 @@
+//! Tasks of kind `embedding` keep their vectors on the event, or as one document per
+//! input in the configured vector index.
+
+use consumer::embedding_tasks::{
+    annotations, embeddings_url, is_embedding_task, usage, vector_documents, vectors,
+};
+use serde_json::{json, Value};
+
+fn response(vectors: &[(u64, &[f32])]) -> Value {
+    json!({
+        "object": "list",
+        "data": vectors
+            .iter()
+            .map(|(index, vector)| json!({ "object": "embedding", "index": index, "embedding": vector }))
+            .collect::<Vec<_>>(),
+        "model": "text-embedding-3-small",
+        "usage": { "prompt_tokens": 8, "total_tokens": 8 },
+    })
+}
+
+#[test]
+fn embeddings_responses_are_read_in_input_order() {
+    assert!(is_embedding_task(&json!({ "kind": "embedding" })));
+    assert!(!is_embedding_task(&json!({ "body": { "messages": [] } })));
+    assert_eq!(
+        embeddings_url("https://api.openai.com/v1/chat/completions"),
+        "https://api.openai.com/v1/embeddings"
+    );
+    assert_eq!(
+        embeddings_url("http://tei:8080/v1/embeddings"),
+        "http://tei:8080/v1/embeddings"
+    );
+
+    let raw = response(&[(1, &[0.0, 1.0]), (0, &[1.0, 0.0])]);
+    assert_eq!(vectors(&raw).unwrap(), vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+    let usage = usage(&raw).unwrap();
+    assert_eq!(usage.prompt_tokens, 8);
+    assert_eq!(usage.completion_tokens, 0);
+    assert_eq!(usage.total_tokens, 8);
+
+    assert!(vectors(&json!({ "choices": [] })).is_err());
+    assert!(vectors(&response(&[(0, &[1.0]), (1, &[])])).is_err());
+}
+
+#[test]
+fn vectors_stay_on_the_event_without_an_index() {
+    let body = json!({ "model": "text-embedding-3-small", "input": "hello" });
+    let single = annotations(&body, &response(&[(0, &[0.6, 0.8])]), None).unwrap();
+    assert_eq!(single["embedding"], json!([0.6f32, 0.8f32]));
+    assert_eq!(single["embedding_dimensions"], 2);
+    assert_eq!(single["embedding_count"], 1);
+    assert_eq!(single["embedding_model"], "text-embedding-3-small");
+    assert!(single.get("embeddings").is_none());
+
+    let raw = response(&[(0, &[1.0, 0.0]), (1, &[0.0, 1.0])]);
+    let several = annotations(&body, &raw, None).unwrap();
+    assert_eq!(several["embeddings"].as_array().unwrap().len(), 2);
+    assert!(several.get("embedding").is_none());
+
+    let indexed = annotations(&body, &raw, Some("corpus-vectors")).unwrap();
+    assert_eq!(indexed["embedding_index"], "corpus-vectors");
+    assert!(indexed.get("embedding").is_none() && indexed.get("embeddings").is_none());
+}
+
+#[test]
+fn each_input_gets_its_own_document() {
+    let body = json!({ "model": "text-embedding-3-small", "input": ["first", "second"] });
+    let raw = response(&[(1, &[0.0, 1.0]), (0, &[1.0, 0.0])]);
+    let documents = vector_documents("msg-1", "batch-1", &body, &raw).unwrap();
+    assert_eq!(documents.len(), 2);
+    assert_eq!(documents[1].position, 1);
+    assert_eq!(documents[1].input, Some("second"));
+    assert_eq!(documents[1].vector, vec![0.0, 1.0]);
+    assert_eq!(documents[0].model, Some("text-embedding-3-small"));
+
+    // Token inputs have no text to keep.
+    let body = json!({ "model": "text-embedding-3-small", "input": [[101, 102]] });
+    let raw = response(&[(0, &[1.0])]);
+    let documents = vector_documents("msg-2", "batch-1", &body, &raw).unwrap();
+    assert_eq!(documents[0].input, None);
+}
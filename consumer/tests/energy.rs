@@ -0,0 +1,84 @@
+//! Completions are given an energy and carbon estimate from the factors of their model.
+
+use consumer::energy::{EnergyTable, Footprint};
+use consumer::schemas::provider_response::Usage;
+use consumer::settings::{EnergySettings, ModelEnergy};
+
+fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+    Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    }
+}
+
+fn table() -> EnergyTable {
+    EnergyTable::new(&EnergySettings {
+        models: [
+            (
+                "gpt-4o".to_string(),
+                ModelEnergy {
+                    input_wh_per_1k: 0.1,
+                    output_wh_per_1k: 1.0,
+                    grams_co2_per_kwh: None,
+                },
+            ),
+            (
+                "llama-3-70b".to_string(),
+                ModelEnergy {
+                    input_wh_per_1k: 0.2,
+                    output_wh_per_1k: 2.0,
+                    grams_co2_per_kwh: Some(50.0),
+                },
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        grams_co2_per_kwh: 400.0,
+    })
+}
+
+fn assert_close(actual: f64, expected: f64) {
+    assert!(
+        (actual - expected).abs() < 1e-9,
+        "{} != {}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn footprints_use_the_grid_intensity_unless_the_model_has_its_own() {
+    let table = table();
+    let Footprint {
+        energy_wh,
+        co2_grams,
+    } = table.footprint(["gpt-4o"], &usage(2000, 500)).unwrap();
+    assert_close(energy_wh, 0.7);
+    assert_close(co2_grams, 0.28);
+
+    // An on-prem cluster on a low-carbon supply.
+    let footprint = table
+        .footprint(["llama-3-70b"], &usage(1000, 1000))
+        .unwrap();
+    assert_close(footprint.energy_wh, 2.2);
+    assert_close(footprint.co2_grams, 0.11);
+}
+
+#[test]
+fn snapshots_match_their_model_and_unknown_models_have_no_estimate() {
+    let table = table();
+    let footprint = table
+        .footprint(
+            [None, Some("gpt-4o-2024-08-06")].into_iter().flatten(),
+            &usage(1000, 0),
+        )
+        .unwrap();
+    assert_close(footprint.energy_wh, 0.1);
+    assert!(table
+        .footprint(["claude-sonnet"], &usage(1000, 1000))
+        .is_none());
+
+    let annotations = footprint.annotations();
+    assert!(annotations["energy_wh"].is_f64() && annotations["co2_grams"].is_f64());
+}
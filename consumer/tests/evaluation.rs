@@ -0,0 +1,62 @@
+//! Completions of benchmark-style tasks are scored against their reference answer by
+//! the task's method.
+
+use consumer::evaluation::evaluate;
+use consumer::llm_wrapper::LLMClient;
+use serde_json::{json, Value};
+
+async fn score(spec: Value, completion: &str) -> Value {
+    evaluate(
+        &LLMClient::new(),
+        None,
+        &spec,
+        "What is the answer?",
+        completion,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn exact_matches_ignore_case_and_whitespace() {
+    let spec = json!({ "method": "exact_match", "reference": ["Paris", "Paris, France"] });
+    let evaluation = score(spec.clone(), "  paris,\n France. ").await;
+    assert_eq!(evaluation["correct"], true);
+    assert_eq!(evaluation["score"], 1.0);
+    assert_eq!(evaluation["method"], "exact_match");
+
+    assert_eq!(score(spec, "Lyon").await["correct"], false);
+
+    let spec = json!({ "method": "exact_match", "reference": "Paris", "case_sensitive": true });
+    assert_eq!(score(spec, "paris").await["correct"], false);
+}
+
+#[tokio::test]
+async fn answers_are_extracted_with_the_answer_pattern() {
+    let spec = json!({
+        "method": "numeric",
+        "reference": 1234.5,
+        "answer_pattern": r"(?s)Answer:(.*)",
+        "tolerance": 0.01,
+    });
+    let evaluation = score(spec, "Step 1: 12 * 100 = 1200\nAnswer: 1,234.499").await;
+    assert_eq!(evaluation["answer"], "1,234.499");
+    assert_eq!(evaluation["correct"], true);
+
+    let spec = json!({ "method": "regex", "reference": r"^\(?b\)?$" });
+    assert_eq!(score(spec.clone(), "(B)").await["correct"], true);
+    assert_eq!(score(spec, "(c)").await["correct"], false);
+}
+
+#[tokio::test]
+async fn unscorable_tasks_report_an_error() {
+    let evaluation = score(json!({ "method": "judge", "reference": "42" }), "42").await;
+    assert_eq!(evaluation["correct"], Value::Null);
+    assert!(evaluation["error"]
+        .as_str()
+        .unwrap()
+        .contains("EVAL_JUDGE_URL"));
+
+    let spec = json!({ "method": "numeric", "reference": "many" });
+    assert!(score(spec, "3").await["error"].is_string());
+    assert!(score(json!({ "method": "fuzzy" }), "3").await["error"].is_string());
+}
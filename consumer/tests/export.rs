@@ -0,0 +1,242 @@
+//! Completed events are exported as rows of the configured fields, in JSONL or Parquet,
+//! by message id or easiest first.
+
+use chrono::Utc;
+use consumer::db::jsonl::JsonlStore;
+use consumer::db::{EventKey, TaskStore};
+use consumer::export::{export_batch, jsonl, parquet, row, ParquetWriter, Sink};
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::task_status::TaskStatus;
+use consumer::settings::{ArchiveSettings, ExportField, ExportFormat, ExportOrder, ExportSettings};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use serde_json::{json, Value};
+
+fn fields(fields: &[(&str, &str)]) -> Vec<ExportField> {
+    fields
+        .iter()
+        .map(|(name, source)| ExportField {
+            name: name.to_string(),
+            source: source.to_string(),
+        })
+        .collect()
+}
+
+fn event(custom_id: &str, completion: Option<&str>) -> Value {
+    json!({
+        "message_id": format!("msg-{}", custom_id),
+        "custom_id": custom_id,
+        "body": {
+            "model": "gpt-4o",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Name a prime." }
+            ]
+        },
+        "completions": {
+            "choices": completion
+                .map(|text| vec![json!({ "message": { "role": "assistant", "content": text } })])
+                .unwrap_or_default()
+        },
+        "total_tokens": 12,
+    })
+}
+
+#[test]
+fn rows_map_prompts_completions_and_event_fields() {
+    let fields = fields(&[
+        ("id", "custom_id"),
+        ("prompt", "prompt"),
+        ("completion", "completion"),
+        ("model", "body.model"),
+        ("tokens", "total_tokens"),
+        ("missing", "annotations.label"),
+    ]);
+    let row = row(&event("a", Some("Seven.")), &fields);
+    assert_eq!(row["id"], "a");
+    assert_eq!(row["prompt"], "Be brief.\nName a prime.");
+    assert_eq!(row["completion"], "Seven.");
+    assert_eq!(row["model"], "gpt-4o");
+    assert_eq!(row["tokens"], 12);
+    assert_eq!(row["missing"], Value::Null);
+
+    let lines = jsonl(&[row.clone(), row]).unwrap();
+    let lines = String::from_utf8(lines).unwrap();
+    assert_eq!(lines.lines().count(), 2);
+    let first: Value = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+    assert_eq!(first["completion"], "Seven.");
+}
+
+#[test]
+fn parquet_readers_load_every_row() {
+    let fields = fields(&[
+        ("id", "custom_id"),
+        ("completion", "completion"),
+        ("tokens", "total_tokens"),
+    ]);
+    let rows: Vec<_> = [event("a", Some("Seven.")), event("b", None)]
+        .iter()
+        .map(|event| row(event, &fields))
+        .collect();
+    let path = std::env::temp_dir().join(format!("export-test-{}.parquet", uuid::Uuid::new_v4()));
+    std::fs::write(&path, parquet(&fields, &rows).unwrap()).unwrap();
+
+    let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+    let metadata = reader.metadata();
+    assert_eq!(metadata.file_metadata().num_rows(), 2);
+    let columns: Vec<_> = metadata
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|column| column.name().to_string())
+        .collect();
+    assert_eq!(columns, ["id", "completion", "tokens"]);
+
+    let read: Vec<Vec<Field>> = reader
+        .get_row_iter(None)
+        .unwrap()
+        .map(|row| {
+            row.unwrap()
+                .get_column_iter()
+                .map(|(_, value)| value.clone())
+                .collect()
+        })
+        .collect();
+    let text = |value: &str| Field::Str(value.to_string());
+    assert_eq!(
+        read,
+        [
+            vec![text("a"), text("Seven."), text("12")],
+            vec![text("b"), Field::Null, text("12")],
+        ]
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn parquet_files_have_a_compressed_row_group_per_write() {
+    let fields = fields(&[("id", "custom_id"), ("completion", "completion")]);
+    let page = |ids: &[&str]| -> Vec<_> {
+        ids.iter()
+            .map(|id| row(&event(id, Some("Seven.")), &fields))
+            .collect()
+    };
+    let mut writer = ParquetWriter::new(Vec::new(), &fields).unwrap();
+    writer.write(&page(&["a", "b"])).unwrap();
+    writer.write(&[]).unwrap();
+    writer.write(&page(&["c"])).unwrap();
+    let file = bytes::Bytes::from(writer.finish().unwrap());
+
+    let reader = SerializedFileReader::new(file).unwrap();
+    let metadata = reader.metadata();
+    assert_eq!(metadata.file_metadata().num_rows(), 3);
+    assert_eq!(metadata.num_row_groups(), 2);
+    let row_group = metadata.row_group(0);
+    assert_eq!(row_group.num_rows(), 2);
+    assert_eq!(
+        row_group.column(0).compression(),
+        parquet::basic::Compression::SNAPPY
+    );
+    assert!(row_group.column(0).statistics().is_some());
+
+    // An empty batch is a file without row groups.
+    let empty = bytes::Bytes::from(parquet(&fields, &[]).unwrap());
+    let reader = SerializedFileReader::new(empty).unwrap();
+    assert_eq!(reader.metadata().num_row_groups(), 0);
+    assert_eq!(reader.metadata().file_metadata().num_rows(), 0);
+}
+
+#[test]
+fn s3_sinks_need_the_object_store_settings() {
+    assert!(matches!(
+        Sink::parse("exports", None).unwrap(),
+        Sink::Directory(_)
+    ));
+    assert!(Sink::parse("s3://datasets/exports", None).is_err());
+    let archive = ArchiveSettings {
+        endpoint: "http://minio:9000".to_string(),
+        bucket: "synthetic-data-generator".to_string(),
+        region: "us-east-1".to_string(),
+        access_key: String::new(),
+        secret_key: String::new(),
+        prefix: "truncated/".to_string(),
+    };
+    assert!(matches!(
+        Sink::parse("s3://datasets/exports/", Some(&archive)).unwrap(),
+        Sink::Bucket(_)
+    ));
+    assert!(Sink::parse("s3:///exports", Some(&archive)).is_err());
+}
+
+#[tokio::test]
+async fn curriculum_exports_start_with_the_easiest_rows() {
+    let directory = std::env::temp_dir().join(format!("export-test-{}", uuid::Uuid::new_v4()));
+    let store =
+        JsonlStore::create(&directory.with_extension("jsonl").display().to_string()).unwrap();
+    for (message_id, score) in [
+        ("m1", Some(0.9)),
+        ("m2", None),
+        ("m3", Some(0.1)),
+        ("m4", Some(0.5)),
+        ("m5", Some(0.1)),
+    ] {
+        let mut annotations = serde_json::Map::new();
+        if let Some(score) = score {
+            annotations.insert("difficulty_score".to_string(), json!(score));
+        }
+        let response = LLMResponse {
+            completions: json!({ "choices": [{ "message": { "content": "ok" } }] }),
+            cached: false,
+            attempt: 1,
+            started_at: Utc::now(),
+            completed_at: Utc::now(),
+            annotations,
+            usage: None,
+            cost: None,
+        };
+        let event = EventKey {
+            message_id,
+            batch_id: "batch",
+            body_hash: message_id,
+        };
+        store
+            .update_event_status(&event, TaskStatus::Completed, &response, Utc::now())
+            .await
+            .unwrap();
+    }
+
+    let export = |order| {
+        let settings = ExportSettings {
+            sink: directory.display().to_string(),
+            format: ExportFormat::Jsonl,
+            fields: fields(&[("id", "message_id")]),
+            order,
+        };
+        let store = &store;
+        let directory = &directory;
+        async move {
+            let sink = Sink::parse(&settings.sink, None).unwrap();
+            let report = export_batch(store, "batch", &settings, &sink)
+                .await
+                .unwrap();
+            assert_eq!(report.rows, 5);
+            std::fs::read_to_string(directory.join("batch.jsonl"))
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap()["id"].clone())
+                .collect::<Vec<_>>()
+        }
+    };
+    assert_eq!(
+        export(ExportOrder::MessageId).await,
+        ["m1", "m2", "m3", "m4", "m5"]
+    );
+    // Ties keep their message id order, and unscored rows go last.
+    assert_eq!(
+        export(ExportOrder::Curriculum).await,
+        ["m3", "m5", "m4", "m1", "m2"]
+    );
+    let _ = std::fs::remove_dir_all(&directory);
+    let _ = std::fs::remove_file(directory.with_extension("jsonl"));
+}
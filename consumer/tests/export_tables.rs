@@ -0,0 +1,66 @@
+//! Exports also come as CSV files and SQLite databases, with the same columns as the
+//! JSONL and Parquet ones.
+
+use consumer::export::{csv, sqlite};
+use consumer::settings::ExportField;
+use serde_json::{json, Map, Value};
+use sqlx::sqlite::SqliteConnection;
+use sqlx::{Connection, Row};
+
+fn fields() -> Vec<ExportField> {
+    ["id", "completion", "tokens"]
+        .iter()
+        .map(|name| ExportField {
+            name: name.to_string(),
+            source: name.to_string(),
+        })
+        .collect()
+}
+
+fn rows() -> Vec<Map<String, Value>> {
+    [
+        json!({ "id": "a", "completion": "Seven, \"probably\".\nOr eleven.", "tokens": 12 }),
+        json!({ "id": "b", "completion": null, "tokens": 3 }),
+    ]
+    .into_iter()
+    .map(|row| row.as_object().unwrap().clone())
+    .collect()
+}
+
+#[test]
+fn csv_files_quote_what_needs_it() {
+    let file = String::from_utf8(csv(&fields(), &rows())).unwrap();
+    assert_eq!(
+        file,
+        "id,completion,tokens\r\n\
+         a,\"Seven, \"\"probably\"\".\nOr eleven.\",12\r\n\
+         b,,3\r\n"
+    );
+    assert_eq!(csv(&fields(), &[]), b"id,completion,tokens\r\n");
+}
+
+#[tokio::test]
+async fn sqlite_databases_have_a_results_table() {
+    let database = sqlite(&fields(), &rows()).await.unwrap();
+    assert!(database.starts_with(b"SQLite format 3\0"));
+
+    let path = std::env::temp_dir().join(format!("export-test-{}.sqlite", uuid::Uuid::new_v4()));
+    std::fs::write(&path, database).unwrap();
+    let mut connection = SqliteConnection::connect(&format!("sqlite://{}", path.display()))
+        .await
+        .unwrap();
+    let rows = sqlx::query("SELECT id, completion, tokens FROM results ORDER BY id")
+        .fetch_all(&mut connection)
+        .await
+        .unwrap();
+    connection.close().await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(rows.len(), 2);
+    assert_eq!(
+        rows[0].get::<String, _>("completion"),
+        "Seven, \"probably\".\nOr eleven."
+    );
+    assert_eq!(rows[0].get::<String, _>("tokens"), "12");
+    assert_eq!(rows[1].get::<Option<String>, _>("completion"), None);
+}
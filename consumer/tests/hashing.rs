@@ -0,0 +1,96 @@
+//! Task keys and completion addresses under each `HASH_ALGORITHM`.
+
+use consumer::cache_key::CacheKeys;
+use consumer::hashing;
+use consumer::producer::{body_hash, body_hash_with};
+use consumer::settings::{CacheKeyMode, CacheKeySettings, HashAlgorithm};
+use serde_json::json;
+
+fn keys(mode: CacheKeyMode) -> CacheKeys {
+    CacheKeys::new(&CacheKeySettings {
+        mode,
+        excluded_fields: Vec::new(),
+        semantic_templates: false,
+        hash: HashAlgorithm::Blake3,
+    })
+}
+
+#[test]
+fn blake3_keys_are_prefixed_and_sha256_keys_unchanged() {
+    let body = json!({ "model": "gpt-4o", "messages": [] });
+    assert_eq!(
+        body_hash_with(HashAlgorithm::Sha256, &body),
+        body_hash(&body)
+    );
+    let key = body_hash_with(HashAlgorithm::Blake3, &body);
+    assert!(key.starts_with("blake3:"));
+    assert_ne!(key["blake3:".len()..], body_hash(&body));
+}
+
+#[test]
+fn blake3_only_replaces_recomputed_keys() {
+    let payload = json!({ "body": { "model": "gpt-4o", "messages": [] } });
+    let producer_hash = body_hash(&payload["body"]);
+    assert_eq!(
+        keys(CacheKeyMode::Verify).resolve(&payload, &producer_hash),
+        Ok(producer_hash.clone())
+    );
+    assert_eq!(
+        keys(CacheKeyMode::Replace).resolve(&payload, &producer_hash),
+        Ok(body_hash_with(HashAlgorithm::Blake3, &payload["body"]))
+    );
+}
+
+#[test]
+fn equal_completions_share_an_address() {
+    let completions =
+        json!({ "choices": [{ "message": { "content": "hi", "role": "assistant" } }] });
+    let reordered: serde_json::Value =
+        serde_json::from_str(r#"{"choices":[{"message":{"role":"assistant","content":"hi"}}]}"#)
+            .unwrap();
+    for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+        let address = hashing::content_hash(algorithm, &completions);
+        assert_eq!(address, hashing::content_hash(algorithm, &reordered));
+        assert!(address.starts_with(&format!("{}:", algorithm.as_str())));
+        assert_eq!(address.len(), algorithm.as_str().len() + 1 + 64);
+    }
+    assert_ne!(
+        hashing::content_hash(HashAlgorithm::Sha256, &completions),
+        hashing::content_hash(HashAlgorithm::Sha256, &json!({ "choices": [] }))
+    );
+}
+
+#[test]
+fn responses_differing_only_in_id_and_created_share_an_address() {
+    let first = json!({
+        "id": "chatcmpl-1",
+        "created": 1_700_000_000,
+        "model": "gpt-4o",
+        "usage": { "total_tokens": 12 },
+        "choices": [{ "message": { "content": "hi", "role": "assistant" } }],
+    });
+    let mut second = first.clone();
+    second["id"] = json!("chatcmpl-2");
+    second["created"] = json!(1_700_000_042);
+    for algorithm in [HashAlgorithm::Sha256, HashAlgorithm::Blake3] {
+        assert_eq!(
+            hashing::content_hash(algorithm, &first),
+            hashing::content_hash(algorithm, &second)
+        );
+    }
+    let mut other_model = first.clone();
+    other_model["model"] = json!("gpt-4o-mini");
+    assert_ne!(
+        hashing::content_hash(HashAlgorithm::Sha256, &first),
+        hashing::content_hash(HashAlgorithm::Sha256, &other_model)
+    );
+
+    let (content, rest) = hashing::split_content(second.clone());
+    assert_eq!(
+        hashing::content_hash(HashAlgorithm::Sha256, &content),
+        hashing::content_hash(HashAlgorithm::Sha256, &first)
+    );
+    assert_eq!(rest["id"], "chatcmpl-2");
+    assert!(rest.get("choices").is_none());
+    assert_eq!(hashing::join_content(content, &rest), second);
+}
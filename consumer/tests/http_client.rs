@@ -0,0 +1,73 @@
+//! The upstream client honors the configured proxy, read timeout and CA bundle under
+//! any profile.
+
+use consumer::llm_wrapper::LLMClient;
+use consumer::settings::{HttpSettings, NetworkSettings};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+mod common;
+
+#[tokio::test]
+async fn requests_go_through_the_proxy() {
+    let (address, proxied) = common::serve_once(common::response("200 OK", &[], "proxied")).await;
+    let proxy_url = format!("http://{}", address);
+
+    let settings = HttpSettings {
+        proxy_url: Some(proxy_url),
+        ..common::http_settings()
+    };
+    let client = LLMClient::with_settings(&settings, &NetworkSettings::default(), &[]).unwrap();
+    let url = "http://vllm.internal:8000/v1/models";
+    let body = client
+        .inner()
+        .get(url)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(body, "proxied");
+    // Plain HTTP requests reach the proxy in absolute form.
+    assert!(proxied
+        .await
+        .unwrap()
+        .starts_with("GET http://vllm.internal:8000/v1/models"));
+}
+
+#[tokio::test]
+async fn slow_responses_hit_the_read_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/v1/models", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        common::read_request(&mut stream).await;
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    });
+
+    let settings = HttpSettings {
+        read_timeout_secs: Some(1),
+        ..common::http_settings()
+    };
+    let client = LLMClient::with_settings(&settings, &NetworkSettings::default(), &[]).unwrap();
+    let started = Instant::now();
+    let error = client.inner().get(&url).send().await.unwrap_err();
+    assert!(error.is_timeout());
+    assert!(started.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn missing_ca_bundles_and_invalid_proxies_are_rejected() {
+    let settings = HttpSettings {
+        ca_bundle: Some("/etc/synthgen/missing-ca.pem".to_string()),
+        ..common::http_settings()
+    };
+    assert!(LLMClient::with_settings(&settings, &NetworkSettings::default(), &[]).is_err());
+
+    let settings = HttpSettings {
+        proxy_url: Some("not a url".to_string()),
+        ..common::http_settings()
+    };
+    assert!(LLMClient::with_settings(&settings, &NetworkSettings::default(), &[]).is_err());
+}
@@ -0,0 +1,105 @@
+//! LLM calls made for a task send idempotency keys derived from its message id, the
+//! call and the attempts the provider answered, in the header configured for the host.
+
+use consumer::llm_wrapper::{call_llm, with_idempotency_key, LLMClient};
+use consumer::settings::{
+    EmptyCompletionSettings, IdempotencyHeaderSettings, IdempotencySettings, ProviderApi,
+};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+
+mod common;
+
+fn idempotency() -> IdempotencySettings {
+    IdempotencySettings {
+        header: Some("Idempotency-Key".to_string()),
+        hosts: vec![
+            IdempotencyHeaderSettings {
+                pattern: "example".to_string(),
+                header: Some("X-Request-Id".to_string()),
+            },
+            IdempotencyHeaderSettings {
+                pattern: "legacy".to_string(),
+                header: None,
+            },
+        ],
+    }
+}
+
+#[test]
+fn hosts_can_override_the_header() {
+    let client = LLMClient::new().with_idempotency(idempotency());
+    assert_eq!(
+        client.idempotency_header("https://api.openai.com/v1/chat/completions"),
+        Some("Idempotency-Key")
+    );
+    assert_eq!(
+        client.idempotency_header("https://llm.example.com/v1/chat/completions"),
+        Some("X-Request-Id")
+    );
+    assert_eq!(
+        client.idempotency_header("https://legacy.internal/v1/completions"),
+        None
+    );
+}
+
+/// Answers the first request with a 500 and the others with a completion, recording
+/// the idempotency key of each.
+async fn flaky_endpoint(keys: Arc<Mutex<Vec<String>>>) -> String {
+    common::serve("/v1/chat/completions", move |served, request| {
+        let request = request.to_lowercase();
+        let key = request
+            .lines()
+            .find_map(|line| line.strip_prefix("idempotency-key: "))
+            .unwrap_or_default();
+        keys.lock().unwrap().push(key.trim().to_string());
+        if served == 0 {
+            common::response(
+                "500 Internal Server Error",
+                &[("content-type", "application/json")],
+                &json!({ "error": "overloaded" }).to_string(),
+            )
+        } else {
+            common::json_response(
+                &json!({ "choices": [{ "message": { "content": "Hello" } }] }).to_string(),
+            )
+        }
+    })
+    .await
+}
+
+#[tokio::test]
+async fn keys_change_with_answered_attempts_and_calls() {
+    let keys = Arc::new(Mutex::new(Vec::new()));
+    let url = flaky_endpoint(keys.clone()).await;
+    let client = LLMClient::new().with_idempotency(idempotency());
+    let body = json!({ "model": "m", "messages": [] });
+    let call = || {
+        call_llm(
+            &client,
+            &url,
+            &body,
+            "",
+            ProviderApi::OpenAiCompatible,
+            "",
+            "",
+            3,
+            1,
+            1,
+            &EmptyCompletionSettings::UNADJUSTED,
+        )
+    };
+
+    let (first, second) = with_idempotency_key("msg-1", async {
+        (call().await.unwrap(), call().await.unwrap())
+    })
+    .await;
+    call().await.unwrap();
+
+    assert_eq!(
+        *keys.lock().unwrap(),
+        ["msg-1-0-0", "msg-1-0-1", "msg-1-1-0", ""]
+    );
+    assert_eq!(first.annotations["idempotency_key"], "msg-1-0-1");
+    assert_eq!(second.annotations["idempotency_key"], "msg-1-1-0");
+}
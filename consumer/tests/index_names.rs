@@ -0,0 +1,38 @@
+//! Events go to the configured index, or under rollover to the index of the day or
+//! month they are created in.
+
+use chrono::{TimeZone, Utc};
+use consumer::db::elastic::events_index;
+use consumer::settings::{IndexRollover, IndexSettings};
+
+fn indices(rollover: Option<IndexRollover>) -> IndexSettings {
+    IndexSettings {
+        events: "synthgen-events".to_string(),
+        batches: "synthgen-batches".to_string(),
+        leaderboard: "synthgen-leaderboard".to_string(),
+        audit: "synthgen-audit".to_string(),
+        workers: "synthgen-workers".to_string(),
+        completions: "synthgen-completions".to_string(),
+        rollover,
+        bootstrap: true,
+    }
+}
+
+#[test]
+fn events_go_to_the_configured_index() {
+    let now = Utc.with_ymd_and_hms(2026, 10, 18, 12, 0, 0).unwrap();
+    assert_eq!(events_index(&indices(None), now), "synthgen-events");
+}
+
+#[test]
+fn rollover_suffixes_the_date() {
+    let now = Utc.with_ymd_and_hms(2026, 3, 7, 23, 59, 0).unwrap();
+    assert_eq!(
+        events_index(&indices(Some(IndexRollover::Daily)), now),
+        "synthgen-events-2026.03.07"
+    );
+    assert_eq!(
+        events_index(&indices(Some(IndexRollover::Monthly)), now),
+        "synthgen-events-2026.03"
+    );
+}
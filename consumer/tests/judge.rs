@@ -0,0 +1,46 @@
+//! The quality judge's verdicts are parsed into a score and a rationale, and low scores
+//! reject the completion like the local quality model's.
+
+use consumer::judge::{parse_verdict, question, DEFAULT_RUBRIC};
+use consumer::quality_gates::rejection_reasons;
+use serde_json::{json, Map, Value};
+
+#[test]
+fn verdicts_are_read_as_json_or_plain_text() {
+    assert_eq!(
+        parse_verdict(r#"{"score": 8, "rationale": "Correct and concise."}"#).unwrap(),
+        (8.0, "Correct and concise.".to_string())
+    );
+    assert_eq!(
+        parse_verdict("Sure!\n```json\n{\"score\": \"3\", \"feedback\": \"Off topic.\"}\n```")
+            .unwrap(),
+        (3.0, "Off topic.".to_string())
+    );
+    assert_eq!(
+        parse_verdict("7/10 - Accurate but verbose.").unwrap(),
+        (7.0, "Accurate but verbose.".to_string())
+    );
+    assert_eq!(parse_verdict("Score: 12.").unwrap().0, 10.0);
+    assert!(parse_verdict("I can't rate this.").is_err());
+}
+
+#[test]
+fn questions_carry_the_rubric_and_the_example() {
+    let question = question(DEFAULT_RUBRIC, "Name a prime.", "Seven.");
+    assert!(question.starts_with(DEFAULT_RUBRIC));
+    assert!(question.contains("Request:\nName a prime."));
+    assert!(question.ends_with("Answer:\nSeven."));
+}
+
+#[test]
+fn low_judge_scores_reject_the_completion() {
+    let annotations = |score: f64| -> Map<String, Value> {
+        json!({ "quality_score": score, "judge_feedback": "..." })
+            .as_object()
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(rejection_reasons(&annotations(0.2), Some(0.5)), ["quality"]);
+    assert!(rejection_reasons(&annotations(0.8), Some(0.5)).is_empty());
+    assert!(rejection_reasons(&annotations(0.2), None).is_empty());
+}
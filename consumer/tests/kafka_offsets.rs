@@ -0,0 +1,42 @@
+//! Kafka offsets only advance past settled messages, to the next one to read, when
+//! tasks finish out of order.
+#![cfg(feature = "kafka")]
+
+use consumer::broker::kafka::PartitionOffsets;
+
+#[test]
+fn commits_the_offset_after_the_last_settled_message() {
+    let mut offsets = PartitionOffsets::default();
+    offsets.track(0);
+    assert_eq!(offsets.settle(0), 1);
+
+    offsets.track(1);
+    offsets.track(2);
+    assert_eq!(offsets.settle(1), 2);
+    assert_eq!(offsets.settle(2), 3);
+}
+
+#[test]
+fn holds_the_offset_at_the_lowest_message_in_flight() {
+    let mut offsets = PartitionOffsets::default();
+    for offset in 5..9 {
+        offsets.track(offset);
+    }
+
+    assert_eq!(offsets.settle(7), 5);
+    assert_eq!(offsets.settle(8), 5);
+    assert_eq!(offsets.settle(5), 6);
+    assert_eq!(offsets.settle(6), 9);
+}
+
+#[test]
+fn commits_past_messages_settled_out_of_order_once_the_gap_closes() {
+    let mut offsets = PartitionOffsets::default();
+    offsets.track(10);
+    offsets.track(11);
+    assert_eq!(offsets.settle(11), 10);
+
+    assert_eq!(offsets.settle(10), 12);
+    offsets.track(12);
+    assert_eq!(offsets.settle(12), 13);
+}
@@ -0,0 +1,83 @@
+//! Scored tasks of an eval suite count on its leaderboard under their model once they
+//! reach a final status.
+
+use chrono::Utc;
+use consumer::db::{scored_evaluation, ScoredEvaluation};
+use consumer::evaluation::evaluate;
+use consumer::llm_wrapper::LLMClient;
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::task_status::TaskStatus;
+use serde_json::{json, Value};
+
+fn evaluated(evaluation: Value) -> LLMResponse {
+    let now = Utc::now();
+    LLMResponse {
+        completions: json!({ "choices": [{ "message": { "content": "Paris" } }] }),
+        cached: false,
+        attempt: 1,
+        started_at: now,
+        completed_at: now,
+        annotations: [("evaluation".to_string(), evaluation)]
+            .into_iter()
+            .collect(),
+        usage: None,
+        cost: None,
+    }
+}
+
+#[tokio::test]
+async fn suites_are_carried_into_the_evaluation() {
+    let spec = json!({ "method": "exact_match", "reference": "Paris", "suite": "geo-v1" });
+    let evaluation = evaluate(
+        &LLMClient::new(),
+        None,
+        &spec,
+        "Capital of France?",
+        "Paris",
+    )
+    .await;
+    assert_eq!(evaluation["suite"], "geo-v1");
+
+    let spec = json!({ "method": "exact_match", "reference": "Paris" });
+    let evaluation = evaluate(
+        &LLMClient::new(),
+        None,
+        &spec,
+        "Capital of France?",
+        "Paris",
+    )
+    .await;
+    assert!(evaluation.get("suite").is_none());
+}
+
+#[test]
+fn only_scored_suite_tasks_count_on_final_statuses() {
+    let response = evaluated(json!({
+        "method": "exact_match",
+        "correct": true,
+        "score": 1.0,
+        "suite": "geo-v1",
+        "model": "gpt-4o",
+    }));
+    let expected = ScoredEvaluation {
+        suite: "geo-v1",
+        model: "gpt-4o",
+        correct: true,
+        score: 1.0,
+    };
+    for status in [TaskStatus::Completed, TaskStatus::Failed] {
+        assert_eq!(scored_evaluation(status, &response), Some(expected.clone()));
+    }
+    assert_eq!(scored_evaluation(TaskStatus::Processing, &response), None);
+
+    // Outside a suite, or left unscored by an evaluation error.
+    let response = evaluated(json!({ "correct": false, "score": 0.0, "model": "gpt-4o" }));
+    assert_eq!(scored_evaluation(TaskStatus::Completed, &response), None);
+    let response = evaluated(json!({
+        "correct": null,
+        "error": "Invalid answer pattern",
+        "suite": "geo-v1",
+        "model": "gpt-4o",
+    }));
+    assert_eq!(scored_evaluation(TaskStatus::Completed, &response), None);
+}
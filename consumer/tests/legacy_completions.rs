@@ -0,0 +1,61 @@
+//! Legacy prompt bodies go to a provider's completions endpoint when it has one, and
+//! are otherwise converted to chat.
+
+use consumer::legacy_completions::adapt;
+use consumer::settings::{ProviderApi, ProviderSettings};
+use serde_json::json;
+
+fn provider(completions_url: Option<&str>) -> ProviderSettings {
+    ProviderSettings {
+        name: "openai".to_string(),
+        url: "https://api.openai.com/v1/chat/completions".to_string(),
+        api_key: String::new(),
+        model: None,
+        completions_url: completions_url.map(str::to_string),
+        api: ProviderApi::OpenAiCompatible,
+    }
+}
+
+#[test]
+fn converts_prompts_to_a_user_message() {
+    let body = json!({
+        "model": "gpt-4o-mini",
+        "prompt": "Write a haiku about rust",
+        "max_tokens": 64,
+        "logprobs": 3,
+        "best_of": 2,
+    });
+    let adapted = adapt(&provider(None), body).unwrap();
+    assert_eq!(adapted.url, "https://api.openai.com/v1/chat/completions");
+    assert_eq!(adapted.adaptation, Some("converted_to_chat"));
+    assert_eq!(
+        adapted.body,
+        json!({
+            "model": "gpt-4o-mini",
+            "messages": [{ "role": "user", "content": "Write a haiku about rust" }],
+            "max_tokens": 64,
+            "logprobs": true,
+            "top_logprobs": 3,
+        })
+    );
+}
+
+#[test]
+fn prefers_the_legacy_endpoint_and_leaves_chat_bodies_alone() {
+    let legacy = json!({ "model": "gpt-3.5-turbo-instruct", "prompt": "2+2=", "suffix": "" });
+    let completions_url = "https://api.openai.com/v1/completions";
+    let adapted = adapt(&provider(Some(completions_url)), legacy.clone()).unwrap();
+    assert_eq!(adapted.url, completions_url);
+    assert_eq!(adapted.body, legacy);
+
+    let chat = json!({ "model": "gpt-4o", "messages": [{ "role": "user", "content": "hi" }] });
+    let adapted = adapt(&provider(None), chat.clone()).unwrap();
+    assert_eq!(adapted.body, chat);
+    assert_eq!(adapted.adaptation, None);
+}
+
+#[test]
+fn several_prompts_need_a_legacy_endpoint() {
+    let body = json!({ "model": "gpt-4o-mini", "prompt": ["a", "b"] });
+    assert!(adapt(&provider(None), body).is_err());
+}
@@ -0,0 +1,39 @@
+//! Field moves of an events migration and the name of the index it migrates to.
+
+use chrono::{TimeZone, Utc};
+use consumer::db::elastic::{migrated_index_name, FieldMove};
+use serde_json::json;
+
+#[test]
+fn field_moves_parse_into_script_params() {
+    let field_move = FieldMove::parse(" usage = response.usage ").unwrap();
+    assert_eq!(field_move.from, "usage");
+    assert_eq!(field_move.to, "response.usage");
+    assert_eq!(
+        field_move.script_param(),
+        json!({ "from": ["usage"], "to": ["response", "usage"] })
+    );
+}
+
+#[test]
+fn invalid_field_moves_are_rejected() {
+    for spec in [
+        "usage",
+        "=usage",
+        "usage=",
+        "a..b=c",
+        "usage=usage",
+        "usage=usage.total",
+    ] {
+        assert!(FieldMove::parse(spec).is_err(), "{}", spec);
+    }
+}
+
+#[test]
+fn migrated_index_is_named_after_the_source_and_time() {
+    let now = Utc.with_ymd_and_hms(2026, 10, 18, 9, 5, 30).unwrap();
+    assert_eq!(
+        migrated_index_name("events-2026.10", now),
+        "events-2026.10-migrated-20261018090530"
+    );
+}
@@ -0,0 +1,100 @@
+//! In mock mode `call_llm` answers from fixtures or with deterministic canned responses
+//! in the provider's shape, without reaching the endpoint.
+
+use consumer::llm_wrapper::{call_llm, LLMClient};
+use consumer::mock_llm::MockBackend;
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::settings::{EmptyCompletionSettings, ProviderApi};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Nothing listens on port 9 here, so only the mock can answer.
+const UNREACHABLE: &str = "http://127.0.0.1:9";
+
+fn client(fixtures: HashMap<String, Value>) -> LLMClient {
+    LLMClient::new().with_mock(Arc::new(MockBackend::new(fixtures, Duration::ZERO)))
+}
+
+async fn call(client: &LLMClient, path: &str, body: &Value) -> LLMResponse {
+    let url = format!("{}{}", UNREACHABLE, path);
+    call_llm(
+        client,
+        &url,
+        body,
+        "key",
+        ProviderApi::OpenAiCompatible,
+        "",
+        "",
+        1,
+        1,
+        1,
+        &EmptyCompletionSettings::UNADJUSTED,
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn canned_responses_are_deterministic_and_shaped_by_provider() {
+    let client = client(HashMap::new());
+    let body = json!({ "model": "gpt-4o-mini", "messages": [{ "role": "user", "content": "Hi" }] });
+    let first = call(&client, "/v1/chat/completions", &body).await;
+    let second = call(&client, "/v1/chat/completions", &body).await;
+    let content = first.content().unwrap().into_owned();
+    assert!(content.starts_with("Mock completion mock-"));
+    assert_eq!(second.content().unwrap(), content);
+    assert!(first.usage.is_some());
+    assert_eq!(first.annotations["mock_response"], true);
+
+    let other =
+        json!({ "model": "gpt-4o-mini", "messages": [{ "role": "user", "content": "Bye" }] });
+    assert_ne!(
+        call(&client, "/v1/chat/completions", &other)
+            .await
+            .content()
+            .unwrap(),
+        content
+    );
+
+    let anthropic = call(
+        &client,
+        "/v1/messages",
+        &json!({ "model": "claude", "max_tokens": 8 }),
+    )
+    .await;
+    assert_eq!(anthropic.completions["type"], "message");
+
+    let json_mode =
+        json!({ "model": "m", "messages": [], "response_format": { "type": "json_object" } });
+    let json_mode = call(&client, "/v1/chat/completions", &json_mode).await;
+    let parsed: Value = serde_json::from_str(&json_mode.content().unwrap()).unwrap();
+    assert!(parsed["mock"].is_string());
+}
+
+#[tokio::test]
+async fn fixtures_are_chosen_by_model_then_default() {
+    let fixture = |text: &str| json!({ "choices": [{ "message": { "content": text } }] });
+    let client = client(HashMap::from([
+        (
+            "openai_gpt-4o".to_string(),
+            fixture("from the model fixture"),
+        ),
+        ("default".to_string(), fixture("from the default fixture")),
+    ]));
+    let response = call(
+        &client,
+        "/v1/chat/completions",
+        &json!({ "model": "openai/gpt-4o" }),
+    )
+    .await;
+    assert_eq!(response.content().unwrap(), "from the model fixture");
+    let response = call(
+        &client,
+        "/v1/chat/completions",
+        &json!({ "model": "llama-3" }),
+    )
+    .await;
+    assert_eq!(response.content().unwrap(), "from the default fixture");
+}
@@ -0,0 +1,58 @@
+//! `synthgen-api openapi` prints the OpenAPI document of the API without connecting to
+//! anything, with submitted tasks described by the envelope's payload schema.
+
+use consumer::schemas::envelope;
+use serde_json::Value;
+use std::process::Command;
+
+fn document() -> Value {
+    let output = Command::new(env!("CARGO_BIN_EXE_synthgen-api"))
+        .arg("openapi")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn every_endpoint_is_documented() {
+    let document = document();
+    assert!(document["openapi"].as_str().unwrap().starts_with("3."));
+    let paths: Vec<&String> = document["paths"].as_object().unwrap().keys().collect();
+    assert_eq!(
+        paths,
+        [
+            "/api/v1/batches",
+            "/api/v1/batches/{batch_id}/arrow",
+            "/api/v1/schemas",
+            "/api/v1/schemas/{task_type}",
+            "/api/v1/tasks",
+            "/health"
+        ]
+    );
+    let submit_task = &document["paths"]["/api/v1/tasks"]["post"];
+    assert_eq!(
+        submit_task["responses"]["201"]["content"]["application/json"]["schema"]["$ref"],
+        "#/components/schemas/TaskSubmitted"
+    );
+    assert_eq!(submit_task["security"][1]["bearer"], Value::Array(vec![]));
+    assert_eq!(
+        document["components"]["securitySchemes"]["bearer"]["scheme"],
+        "bearer"
+    );
+}
+
+#[test]
+fn tasks_follow_the_envelope_schema() {
+    let document = document();
+    let batch_body = &document["paths"]["/api/v1/batches"]["post"]["requestBody"]["content"];
+    assert!(batch_body["application/x-ndjson"].is_object());
+    assert_eq!(
+        document["components"]["schemas"]["BatchRequest"]["properties"]["tasks"]["items"]["$ref"],
+        "#/components/schemas/Task"
+    );
+    assert_eq!(
+        document["components"]["schemas"]["Task"],
+        envelope::json_schema()["properties"]["payload"]
+    );
+}
@@ -0,0 +1,62 @@
+//! Parameter policies fill in the parameters a body leaves unset, and add their stop
+//! sequences to the body's own.
+
+use consumer::parameter_policy::apply;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+fn policies() -> HashMap<String, Value> {
+    HashMap::from([
+        (
+            "qa".to_string(),
+            json!({ "stop": ["\n\nQ:", "</answer>"], "frequency_penalty": 0.3 }),
+        ),
+        (
+            "*".to_string(),
+            json!({
+                "stop": ["<|end|>", "\n\n\n"],
+                "presence_penalty": 0.1,
+                "generationConfig": { "stopSequences": ["<|end|>"] },
+            }),
+        ),
+    ])
+}
+
+#[test]
+fn body_parameters_win_and_stop_sequences_are_combined() {
+    let mut body = json!({
+        "model": "gpt-4o-mini",
+        "stop": "###",
+        "frequency_penalty": 0.0,
+        "presence_penalty": null,
+    });
+    apply(&policies(), Some("qa"), &mut body);
+    assert_eq!(
+        body,
+        json!({
+            "model": "gpt-4o-mini",
+            "stop": ["###", "\n\nQ:", "</answer>", "<|end|>"],
+            "frequency_penalty": 0.0,
+            "presence_penalty": 0.1,
+            "generationConfig": { "stopSequences": ["<|end|>"] },
+        })
+    );
+}
+
+#[test]
+fn nested_objects_are_merged_and_other_task_types_get_the_default_policy() {
+    let mut body = json!({ "generationConfig": { "temperature": 0.7, "stopSequences": ["END"] } });
+    apply(&policies(), Some("summarize"), &mut body);
+    assert_eq!(
+        body,
+        json!({
+            "stop": ["<|end|>", "\n\n\n"],
+            "presence_penalty": 0.1,
+            "generationConfig": { "temperature": 0.7, "stopSequences": ["END", "<|end|>"] },
+        })
+    );
+
+    let mut untouched = json!("not an object");
+    apply(&policies(), None, &mut untouched);
+    assert_eq!(untouched, json!("not an object"));
+}
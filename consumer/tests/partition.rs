@@ -0,0 +1,47 @@
+//! Tasks are owned by the member with the highest rendezvous hash of their key, the
+//! same on every replica and across releases, and only the keys of a member that
+//! leaves or joins move.
+
+use consumer::partition::owner;
+
+fn members(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+#[test]
+fn owners_are_pinned_and_independent_of_member_order() {
+    let group = members(&["consumer-0", "consumer-1", "consumer-2"]);
+    // Replicas of different releases have to agree, so the hash can't change.
+    assert_eq!(owner(&group, "msg-1"), Some("consumer-2"));
+    assert_eq!(owner(&group, "msg-2"), Some("consumer-1"));
+    assert_eq!(owner(&group, "msg-3"), Some("consumer-0"));
+
+    let reversed: Vec<String> = group.iter().rev().cloned().collect();
+    for key in ["msg-1", "msg-2", "msg-3"] {
+        assert_eq!(owner(&group, key), owner(&reversed, key));
+    }
+    assert_eq!(owner(&[], "msg-1"), None);
+}
+
+#[test]
+fn only_the_keys_of_a_changed_member_move() {
+    let group = members(&["consumer-0", "consumer-1", "consumer-2"]);
+    let joined = members(&["consumer-0", "consumer-1", "consumer-2", "consumer-3"]);
+    let left = members(&["consumer-0", "consumer-2"]);
+    let keys: Vec<String> = (0..1000).map(|i| format!("msg-{}", i)).collect();
+
+    let mut moved = 0;
+    for key in &keys {
+        let before = owner(&group, key).unwrap();
+        let after = owner(&joined, key).unwrap();
+        if after != before {
+            assert_eq!(after, "consumer-3");
+            moved += 1;
+        }
+        if before != "consumer-1" {
+            assert_eq!(owner(&left, key), Some(before));
+        }
+    }
+    // The new member takes about a quarter of the keys.
+    assert!((150..350).contains(&moved), "{} keys moved", moved);
+}
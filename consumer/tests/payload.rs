@@ -0,0 +1,73 @@
+//! Payload sizes are recorded per model, and responses over their model's budget are
+//! given up on instead of read to the end.
+
+use consumer::llm_wrapper::{call_llm, LLMClient};
+use consumer::payload::Payloads;
+use consumer::settings::{
+    EmptyCompletionSettings, OversizedResponseAction, PayloadSettings, ProviderApi,
+    ResponseBudgetSettings,
+};
+use serde_json::json;
+use std::sync::Arc;
+
+mod common;
+
+fn budgets(budgets: &[(&str, u64)]) -> PayloadSettings {
+    PayloadSettings {
+        max_request_bytes: 0,
+        response_budgets: budgets
+            .iter()
+            .map(|(pattern, max_bytes)| ResponseBudgetSettings {
+                pattern: pattern.to_string(),
+                max_bytes: *max_bytes,
+            })
+            .collect(),
+        oversized_response: OversizedResponseAction::Abort,
+        report_interval_secs: 0,
+    }
+}
+
+#[test]
+fn the_smallest_matching_budget_applies() {
+    let payloads = Payloads::new(&budgets(&[("llama", 1 << 20), ("llama-3-8b", 1 << 16)]));
+    assert_eq!(
+        payloads.response_budget("Llama-3-8B-Instruct"),
+        Some(1 << 16)
+    );
+    assert_eq!(payloads.response_budget("llama-3-70b"), Some(1 << 20));
+    assert_eq!(payloads.response_budget("gpt-4o"), None);
+}
+
+#[tokio::test]
+async fn oversized_responses_are_aborted() {
+    let content = "a".repeat(64 * 1024);
+    let body = json!({ "choices": [{ "message": { "content": content } }] }).to_string();
+    let url = common::serve("/v1/chat/completions", move |_, _| {
+        common::json_response(&body)
+    })
+    .await;
+
+    let payloads = Arc::new(Payloads::new(&budgets(&[("runaway", 4096)])));
+    let client = LLMClient::new().with_payloads(payloads.clone());
+    let error = call_llm(
+        &client,
+        &url,
+        &json!({ "model": "runaway-model", "messages": [] }),
+        "",
+        ProviderApi::OpenAiCompatible,
+        "",
+        "",
+        3,
+        1,
+        1,
+        &EmptyCompletionSettings::UNADJUSTED,
+    )
+    .await
+    .unwrap_err();
+
+    assert!(error.to_string().contains("budget"), "{}", error);
+    let sizes = payloads.sizes("runaway-model").unwrap();
+    // Not retried, and read no further than the chunk crossing the budget.
+    assert_eq!(sizes.requests.count(), 1);
+    assert!(sizes.responses.max() < 64 * 1024);
+}
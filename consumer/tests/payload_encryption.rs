@@ -0,0 +1,47 @@
+//! Encrypted payloads travel as age ciphertext and are only readable by the consumer
+//! holding one of the recipients' identities.
+
+use age::x25519::Identity;
+use consumer::payload_encryption::{is_encrypted, PayloadKeys};
+use consumer::producer;
+use consumer::schemas::envelope::TaskMessage;
+use serde_json::{json, Value};
+
+fn task() -> Value {
+    json!({
+        "custom_id": "patient-17",
+        "method": "POST",
+        "url": "/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [{ "role": "user", "content": "..." }] },
+    })
+}
+
+#[test]
+fn only_routing_fields_are_left_in_the_clear() {
+    let identity = Identity::generate();
+    let prepared =
+        producer::prepare_encrypted("batch", task(), 0, &[identity.to_public()]).unwrap();
+    let plain = producer::prepare("batch", task(), 0).unwrap();
+    assert_eq!(prepared.body_hash, plain.body_hash);
+    assert!(prepared.event.get("body").is_none() && prepared.event.get("custom_id").is_none());
+
+    let message = TaskMessage::parse(&prepared.message).unwrap();
+    assert!(is_encrypted(&message.payload));
+    assert!(!String::from_utf8_lossy(&prepared.message).contains("patient-17"));
+
+    let keys = PayloadKeys::new(vec![identity], true);
+    assert_eq!(keys.decrypt(message.payload).unwrap(), task());
+}
+
+#[test]
+fn other_keys_and_plaintext_are_refused() {
+    let recipient = Identity::generate().to_public();
+    let prepared = producer::prepare_encrypted("batch", task(), 0, &[recipient]).unwrap();
+    let payload = TaskMessage::parse(&prepared.message).unwrap().payload;
+    let keys = PayloadKeys::new(vec![Identity::generate()], true);
+    assert!(keys.decrypt(payload).is_err());
+    assert!(keys.decrypt(task()).is_err());
+
+    let optional = PayloadKeys::new(Vec::new(), false);
+    assert_eq!(optional.decrypt(task()).unwrap(), task());
+}
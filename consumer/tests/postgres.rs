@@ -0,0 +1,79 @@
+//! Rows of the PostgreSQL store: submission fields and task messages kept apart from
+//! the columns, and exported events put back together.
+
+use consumer::db::postgres::{event_document, split_task_message, submission_annotations};
+use consumer::db::TASK_MESSAGE;
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+
+fn object(value: Value) -> Map<String, Value> {
+    value.as_object().unwrap().clone()
+}
+
+#[test]
+fn submission_fields_without_a_column_go_to_the_annotations() {
+    let document = json!({
+        "message_id": "msg-1",
+        "batch_id": "batch-1",
+        "body_hash": "abc",
+        "body": { "model": "gpt-4o-mini" },
+        "custom_id": "row-7",
+        "dataset": "train",
+        "source": null,
+    });
+    assert_eq!(
+        Value::Object(submission_annotations(&document)),
+        json!({ "custom_id": "row-7", "dataset": "train" })
+    );
+    assert!(submission_annotations(&json!({ "message_id": "msg-2" })).is_empty());
+}
+
+#[test]
+fn task_messages_are_split_from_the_annotations() {
+    let annotations = object(json!({
+        "attempt_model": "gpt-4o-mini",
+        TASK_MESSAGE: { "message_id": "msg-1", "body": {} },
+    }));
+    let (rest, task_message) = split_task_message(&annotations);
+    assert_eq!(
+        Value::Object(rest.into_owned()),
+        json!({ "attempt_model": "gpt-4o-mini" })
+    );
+    assert_eq!(
+        task_message,
+        Some(json!({ "message_id": "msg-1", "body": {} }))
+    );
+}
+
+#[test]
+fn annotations_without_a_task_message_are_not_copied() {
+    let annotations = object(json!({ "attempt_model": "gpt-4o-mini" }));
+    let (rest, task_message) = split_task_message(&annotations);
+    assert!(matches!(rest, Cow::Borrowed(_)));
+    assert_eq!(task_message, None);
+}
+
+#[test]
+fn exported_events_merge_columns_over_annotations() {
+    let annotations = json!({ "custom_id": "row-7", "status": "stale" });
+    let columns = object(json!({
+        "message_id": "msg-1",
+        "status": "COMPLETED",
+        "body": null,
+    }));
+    assert_eq!(
+        event_document(annotations, columns).unwrap(),
+        json!({
+            "custom_id": "row-7",
+            "message_id": "msg-1",
+            "status": "COMPLETED",
+            "body": null,
+        })
+    );
+}
+
+#[test]
+fn annotations_that_are_not_an_object_are_rejected() {
+    let error = event_document(json!(["custom_id"]), Map::new()).unwrap_err();
+    assert_eq!(error.to_string(), "Event annotations are not an object");
+}
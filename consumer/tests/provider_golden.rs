@@ -0,0 +1,112 @@
+//! Golden-file tests for provider response parsing.
+//!
+//! Every `tests/fixtures/providers/<name>.json` is an anonymized response from a
+//! provider. Its normalization, `LLMResponse::content` and retry classification are
+//! compared with `tests/fixtures/providers/golden/<name>.json`. After an intended change,
+//! regenerate the golden files with `UPDATE_GOLDEN=1 cargo test --test provider_golden`
+//! and review the diff.
+
+use chrono::Utc;
+use consumer::llm_wrapper::{classify_body, Failure};
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::provider_response::{detect_provider, normalize};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+
+/// Fixed clock, so rate-limit delays computed from reset timestamps are stable.
+const NOW_MS: u64 = 1_736_934_800_000;
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/providers")
+}
+
+fn classification(failure: Option<Failure>) -> Value {
+    match failure {
+        None => json!({ "kind": "success" }),
+        Some(Failure::Transient(message)) => json!({ "kind": "transient", "message": message }),
+        Some(Failure::Permanent(message)) => json!({ "kind": "permanent", "message": message }),
+        Some(Failure::RateLimited {
+            message,
+            retry_after,
+        }) => json!({
+            "kind": "rate_limited",
+            "message": message,
+            "retry_after_secs": retry_after.as_secs(),
+        }),
+    }
+}
+
+fn render(raw: &Value) -> Value {
+    let now = Utc::now();
+    let response = LLMResponse {
+        completions: raw.clone(),
+        cached: false,
+        attempt: 0,
+        started_at: now,
+        completed_at: now,
+        annotations: Default::default(),
+        usage: None,
+        cost: None,
+    };
+    json!({
+        "provider": detect_provider(raw),
+        "normalized": normalize(raw).map_err(|e| json!({ "error": e })).map(|n| json!(n))
+            .unwrap_or_else(|e| e),
+        "content": response.content(),
+        "classification": classification(classify_body(raw, NOW_MS)),
+    })
+}
+
+#[test]
+fn provider_responses_match_golden_files() {
+    let dir = fixtures_dir();
+    let golden_dir = dir.join("golden");
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+    let mut fixtures: Vec<PathBuf> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    fixtures.sort();
+    assert!(!fixtures.is_empty(), "no fixtures in {}", dir.display());
+
+    let mut mismatches = Vec::new();
+    for fixture in &fixtures {
+        let name = fixture.file_name().unwrap();
+        let raw: Value = serde_json::from_str(&std::fs::read_to_string(fixture).unwrap())
+            .unwrap_or_else(|e| panic!("{}: {}", fixture.display(), e));
+        let actual = render(&raw);
+        let golden_path = golden_dir.join(name);
+
+        if update {
+            std::fs::create_dir_all(&golden_dir).unwrap();
+            let mut rendered = serde_json::to_string_pretty(&actual).unwrap();
+            rendered.push('\n');
+            std::fs::write(&golden_path, rendered).unwrap();
+            continue;
+        }
+
+        let expected: Value = match std::fs::read_to_string(&golden_path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap(),
+            Err(_) => {
+                mismatches.push(format!("{}: missing golden file", name.to_string_lossy()));
+                continue;
+            }
+        };
+        if actual != expected {
+            mismatches.push(format!(
+                "{}:\nexpected {}\nactual   {}",
+                name.to_string_lossy(),
+                expected,
+                actual
+            ));
+        }
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "golden mismatches (rerun with UPDATE_GOLDEN=1 if intended):\n{}",
+        mismatches.join("\n\n")
+    );
+}
@@ -0,0 +1,139 @@
+//! Providers behind an egress proxy are reached through the proxy of their transport,
+//! HTTP or SOCKS5 and with credentials, while others can bypass every proxy.
+
+use consumer::llm_wrapper::LLMClient;
+use consumer::settings::{HttpSettings, NetworkSettings, TransportSettings};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+mod common;
+
+fn transport(host: &str, proxy: &str) -> TransportSettings {
+    TransportSettings {
+        host: host.to_string(),
+        proxy: Some(proxy.to_string()),
+        ..TransportSettings::default()
+    }
+}
+
+/// Answers a request with a JSON body, returning the request.
+async fn answer(stream: &mut TcpStream) -> String {
+    common::answer(stream, &common::json_response(r#"{"ok":true}"#)).await
+}
+
+async fn post(client: &LLMClient, url: &str) -> reqwest::Result<reqwest::Response> {
+    client.for_url(url).post(url).send().await
+}
+
+#[tokio::test]
+async fn http_proxies_get_the_transport_credentials() {
+    let (address, request) = common::serve_once(common::json_response(r#"{"ok":true}"#)).await;
+
+    let transports = [TransportSettings {
+        proxy_username: Some("egress".to_string()),
+        proxy_password: Some("p@ss".to_string()),
+        ..transport("api.provider.internal", &format!("http://{}", address))
+    }];
+    let client = LLMClient::with_settings(
+        &common::http_settings(),
+        &NetworkSettings::default(),
+        &transports,
+    )
+    .unwrap();
+    let response = post(&client, "http://api.provider.internal/v1/chat/completions")
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+
+    let request = request.await.unwrap().to_lowercase();
+    assert!(request.starts_with("post http://api.provider.internal/v1/chat/completions "));
+    assert!(request.contains("proxy-authorization: basic zwdyzxnzonbac3m="));
+}
+
+#[tokio::test]
+async fn socks5_proxies_authenticate_and_resolve_the_provider() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (seen, target) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        // Greeting, offering username/password authentication.
+        let mut greeting = [0; 2];
+        stream.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0; greeting[1] as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+        assert!(methods.contains(&2));
+        stream.write_all(&[5, 2]).await.unwrap();
+
+        let mut auth = [0; 2];
+        stream.read_exact(&mut auth).await.unwrap();
+        let mut username = vec![0; auth[1] as usize];
+        stream.read_exact(&mut username).await.unwrap();
+        let mut length = [0; 1];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut password = vec![0; length[0] as usize];
+        stream.read_exact(&mut password).await.unwrap();
+        assert_eq!(
+            (&username[..], &password[..]),
+            (&b"egress"[..], &b"p@ss"[..])
+        );
+        stream.write_all(&[1, 0]).await.unwrap();
+
+        // Connect request to a domain name.
+        let mut connect = [0; 5];
+        stream.read_exact(&mut connect).await.unwrap();
+        assert_eq!(connect[3], 3);
+        let mut host = vec![0; connect[4] as usize + 2];
+        stream.read_exact(&mut host).await.unwrap();
+        let port = u16::from_be_bytes([host[host.len() - 2], host[host.len() - 1]]);
+        host.truncate(host.len() - 2);
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let target = format!("{}:{}", String::from_utf8(host).unwrap(), port);
+        answer(&mut stream).await;
+        seen.send(target).unwrap();
+    });
+
+    let transports = [transport(
+        "api.provider.internal",
+        &format!("socks5h://egress:p%40ss@{}", address),
+    )];
+    let client = LLMClient::with_settings(
+        &common::http_settings(),
+        &NetworkSettings::default(),
+        &transports,
+    )
+    .unwrap();
+    let response = post(&client, "http://api.provider.internal/v1/chat/completions")
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(target.await.unwrap(), "api.provider.internal:80");
+}
+
+#[tokio::test]
+async fn local_servers_can_bypass_the_shared_proxy() {
+    let (address, _) = common::serve_once(common::json_response(r#"{"ok":true}"#)).await;
+    // Nothing listens on the shared proxy.
+    let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let settings = HttpSettings {
+        proxy_url: Some(format!("http://{}", dead.local_addr().unwrap())),
+        ..common::http_settings()
+    };
+    drop(dead);
+
+    let url = format!("http://{}/v1/chat/completions", address);
+    let client = LLMClient::with_settings(&settings, &NetworkSettings::default(), &[]).unwrap();
+    assert!(post(&client, &url).await.is_err());
+
+    let transports = [transport(&address.to_string(), "none")];
+    let client =
+        LLMClient::with_settings(&settings, &NetworkSettings::default(), &transports).unwrap();
+    assert!(post(&client, &url).await.unwrap().status().is_success());
+
+    let transports = [transport("api.provider.internal", "ftp://proxy.corp:21")];
+    assert!(LLMClient::with_settings(&settings, &NetworkSettings::default(), &transports).is_err());
+}
@@ -0,0 +1,85 @@
+//! Tasks waiting in the priority queue are raised towards the highest priority as they
+//! near staleness, and expired once stale when the policy says so.
+
+use chrono::{Duration as ChronoDuration, Utc};
+use consumer::producer;
+use consumer::queue_aging::{self, QueueAging};
+use consumer::schemas::envelope::TaskMessage;
+use consumer::settings::{QueueAgingSettings, StalePolicy};
+use serde_json::json;
+use std::time::Duration;
+
+fn aging(policy: StalePolicy) -> QueueAging {
+    QueueAging::new(&QueueAgingSettings {
+        stale_after_secs: Some(600),
+        boost_after_secs: Some(200),
+        policy,
+    })
+    .unwrap()
+}
+
+#[test]
+fn priorities_rise_until_tasks_are_stale() {
+    let aging = aging(StalePolicy::Boost);
+    let minutes = |m: u64| Duration::from_secs(m * 60);
+    assert_eq!(aging.priority(2, minutes(3), 10), 2);
+    assert_eq!(aging.priority(2, minutes(5), 10), 4);
+    assert_eq!(aging.priority(2, minutes(8), 10), 8);
+    assert_eq!(aging.priority(2, minutes(10), 10), 10);
+    assert!(!aging.expires(minutes(60)));
+    assert!(QueueAging::new(&QueueAgingSettings::default()).is_none());
+}
+
+#[test]
+fn stale_tasks_expire_under_the_expire_policy() {
+    let aging = aging(StalePolicy::Expire);
+    assert!(!aging.expires(Duration::from_secs(599)));
+    assert!(aging.expires(Duration::from_secs(600)));
+}
+
+#[test]
+fn age_counts_from_the_producer_timestamp() {
+    let task = json!({
+        "method": "POST",
+        "url": "/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [] },
+    });
+    let prepared = producer::prepare("batch", task, 0).unwrap();
+    let message = TaskMessage::parse(&prepared.message).unwrap();
+    let enqueued_at = queue_aging::enqueued_at(&message).unwrap();
+    let later = enqueued_at + ChronoDuration::seconds(90);
+    assert_eq!(
+        queue_aging::age(enqueued_at, later),
+        Duration::from_secs(90)
+    );
+    assert!(queue_aging::age(enqueued_at, Utc::now()) < Duration::from_secs(60));
+}
+
+#[test]
+fn tasks_are_only_published_again_when_their_priority_rises() {
+    let aging = aging(StalePolicy::Boost);
+    let task = json!({
+        "method": "POST",
+        "url": "/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [] },
+        "priority": 2,
+    });
+    let prepared = producer::prepare("batch", task, 10).unwrap();
+    let message = TaskMessage::parse(&prepared.message).unwrap();
+    let enqueued_at = queue_aging::enqueued_at(&message).unwrap();
+    let later = enqueued_at + ChronoDuration::minutes(8);
+    assert_eq!(
+        aging.raised_priority(&prepared.message, 2, 10, later),
+        Some(8)
+    );
+    // Raised to 8 by an earlier walk, it stays put until it rises further.
+    assert_eq!(aging.raised_priority(&prepared.message, 8, 10, later), None);
+    assert_eq!(
+        aging.raised_priority(&prepared.message, 8, 10, later + ChronoDuration::minutes(2)),
+        Some(10)
+    );
+    assert_eq!(
+        aging.raised_priority(&prepared.message, 2, 10, enqueued_at),
+        None
+    );
+}
@@ -0,0 +1,196 @@
+//! Token buckets allow bursts up to their capacity and then space requests out at their
+//! rate, and limits shared through Redis wait for what the GCRA script answers.
+
+use consumer::rate_limit::{RateLimitTarget, RateLimiter, TokenBucket};
+use consumer::settings::{RateLimitSettings, RateLimitUnit};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::time::{advance, Instant};
+
+/// Rounded to the millisecond, as the bucket works in floating point.
+fn millis(wait: Duration) -> u64 {
+    (wait.as_secs_f64() * 1000.0).round() as u64
+}
+
+fn limit(pattern: &str, limit: u32, period_secs: u64) -> RateLimitSettings {
+    RateLimitSettings {
+        pattern: pattern.to_string(),
+        unit: RateLimitUnit::Requests,
+        limit,
+        period_secs,
+    }
+}
+
+const TARGET: RateLimitTarget<'static> = RateLimitTarget {
+    url: "https://api.openai.com/v1/chat/completions",
+    model: "gpt-4o",
+    api_key: "sk-test",
+};
+
+#[tokio::test(start_paused = true)]
+async fn buckets_burst_to_capacity_then_wait_for_refills() {
+    let bucket = TokenBucket::new(10, Duration::from_secs(1));
+    for _ in 0..10 {
+        assert_eq!(bucket.reserve(1.0, 1.0), Duration::ZERO);
+    }
+    // Waiters reserve future units, so each waits for the one before.
+    assert_eq!(millis(bucket.reserve(1.0, 1.0)), 100);
+    assert_eq!(millis(bucket.reserve(1.0, 1.0)), 200);
+
+    advance(Duration::from_millis(700)).await;
+    assert_eq!(bucket.reserve(5.0, 1.0), Duration::ZERO);
+    assert_eq!(millis(bucket.reserve(1.0, 1.0)), 100);
+
+    // Idle time refills no further than the capacity.
+    advance(Duration::from_secs(60)).await;
+    assert_eq!(bucket.reserve(10.0, 1.0), Duration::ZERO);
+    assert_eq!(millis(bucket.reserve(15.0, 1.0)), 1500);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shares_scale_the_capacity_and_rate() {
+    let bucket = TokenBucket::new(10, Duration::from_secs(1));
+    for _ in 0..5 {
+        assert_eq!(bucket.reserve(1.0, 0.5), Duration::ZERO);
+    }
+    assert_eq!(millis(bucket.reserve(1.0, 0.5)), 200);
+
+    // A share never allows less than a unit at a time.
+    let bucket = TokenBucket::new(1, Duration::from_secs(10));
+    assert_eq!(bucket.reserve(1.0, 0.1), Duration::ZERO);
+    assert_eq!(millis(bucket.reserve(1.0, 0.1)), 100_000);
+}
+
+#[tokio::test(start_paused = true)]
+async fn local_limits_space_out_matching_requests() {
+    let limiter =
+        RateLimiter::new(&[limit("OpenAI", 2, 1), limit("anthropic", 1, 60)], None).unwrap();
+    let started = Instant::now();
+    for _ in 0..4 {
+        limiter.acquire(&TARGET, 0).await;
+    }
+    assert_eq!(started.elapsed(), Duration::from_secs(1));
+
+    // Two replicas each get half of the rate.
+    let limiter = RateLimiter::new(&[limit("openai", 2, 1)], None).unwrap();
+    limiter.set_cluster_size(2);
+    let started = Instant::now();
+    for _ in 0..3 {
+        limiter.acquire(&TARGET, 0).await;
+    }
+    assert_eq!(started.elapsed(), Duration::from_secs(2));
+}
+
+#[tokio::test(start_paused = true)]
+async fn local_limits_are_per_account() {
+    let limiter = RateLimiter::new(&[limit("openai", 1, 60)], None).unwrap();
+    let started = Instant::now();
+    limiter.acquire(&TARGET, 0).await;
+    let other_account = RateLimitTarget {
+        api_key: "sk-other",
+        ..TARGET
+    };
+    limiter.acquire(&other_account, 0).await;
+    assert_eq!(started.elapsed(), Duration::ZERO);
+
+    limiter.acquire(&TARGET, 0).await;
+    assert_eq!(started.elapsed(), Duration::from_secs(60));
+}
+
+/// Reads one RESP command, `None` once the client hangs up.
+async fn read_command<R: tokio::io::AsyncBufRead + Unpin>(reader: &mut R) -> Option<Vec<String>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await.unwrap() == 0 {
+        return None;
+    }
+    let count: usize = line.trim().strip_prefix('*')?.parse().ok()?;
+    let mut command = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        reader.read_line(&mut line).await.unwrap();
+        let length: usize = line.trim().strip_prefix('$')?.parse().ok()?;
+        let mut argument = vec![0; length + 2];
+        reader.read_exact(&mut argument).await.unwrap();
+        argument.truncate(length);
+        command.push(String::from_utf8(argument).unwrap());
+    }
+    Some(command)
+}
+
+/// Redis answering script calls with `reply`, as a script that isn't loaded yet the
+/// first time; records the calls and the loaded script.
+async fn redis(reply: &'static str, calls: Arc<Mutex<Vec<Vec<String>>>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("redis://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut loaded = false;
+        while let Some(command) = read_command(&mut reader).await {
+            let answer = match command[0].to_uppercase().as_str() {
+                "EVALSHA" if !loaded => "-NOSCRIPT No matching script.\r\n".to_string(),
+                "EVALSHA" => reply.to_string(),
+                "SCRIPT" => {
+                    loaded = true;
+                    format!("${}\r\n{}\r\n", command[1].len(), command[1])
+                }
+                _ => "+OK\r\n".to_string(),
+            };
+            if command[0].eq_ignore_ascii_case("EVALSHA")
+                || command[0].eq_ignore_ascii_case("SCRIPT")
+            {
+                calls.lock().unwrap().push(command);
+            }
+            writer.write_all(answer.as_bytes()).await.unwrap();
+        }
+    });
+    url
+}
+
+#[tokio::test]
+async fn shared_limits_pass_the_schedule_and_wait_for_the_script() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let url = redis(":300\r\n", calls.clone()).await;
+    let limiter = RateLimiter::new(
+        &[RateLimitSettings {
+            unit: RateLimitUnit::Tokens,
+            ..limit("openai", 1000, 60)
+        }],
+        Some(&url),
+    )
+    .unwrap();
+    let started = std::time::Instant::now();
+    limiter.acquire(&TARGET, 250).await;
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    let calls = calls.lock().unwrap();
+    let script = calls.iter().find(|call| call[0] == "SCRIPT").unwrap();
+    assert_eq!(script[1].to_uppercase(), "LOAD");
+    assert!(script[2].contains("redis.call('TIME')"));
+    let call = calls.last().unwrap();
+    assert_eq!(call[0], "EVALSHA");
+    // One key, the limit's per account, then the interval and period in milliseconds
+    // and the quantity.
+    assert_eq!(call[2], "1");
+    assert!(call[3].starts_with("synthgen:rate-limit:openai:Tokens:"));
+    assert!(!call[3].contains("sk-test"));
+    assert_eq!(call[4..], ["60.0", "60000.0", "250"]);
+}
+
+#[tokio::test]
+async fn script_errors_fall_back_to_the_local_bucket() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let url = redis("-ERR script killed\r\n", calls.clone()).await;
+    let limiter = RateLimiter::new(&[limit("openai", 1, 60)], Some(&url)).unwrap();
+    let started = std::time::Instant::now();
+    limiter.acquire(&TARGET, 0).await;
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(calls
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|call| call[0] == "EVALSHA"));
+}
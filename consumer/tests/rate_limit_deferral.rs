@@ -0,0 +1,62 @@
+//! Long rate limits end the call with `RateLimitDeferred`, so the task goes through a
+//! delay queue instead of holding its worker slot; the holding queue is the shortest
+//! one covering the wait.
+
+use consumer::broker::defer_step;
+use consumer::llm_wrapper::{call_llm, with_rate_limit_deferral, LLMClient, RateLimitDeferred};
+use consumer::settings::{EmptyCompletionSettings, ProviderApi};
+use serde_json::json;
+use std::time::Duration;
+use tokio::time::Instant;
+
+mod common;
+
+/// Answers every request with a 429 asking to retry after two minutes.
+async fn rate_limited_endpoint() -> String {
+    let body = json!({ "error": "slow down" }).to_string();
+    common::serve("/v1/chat/completions", move |_, _| {
+        common::response(
+            "429 Too Many Requests",
+            &[("retry-after", "120"), ("content-type", "application/json")],
+            &body,
+        )
+    })
+    .await
+}
+
+#[tokio::test]
+async fn long_rate_limits_are_deferred() {
+    let url = rate_limited_endpoint().await;
+    let client = LLMClient::new();
+    let body = json!({ "model": "m", "messages": [] });
+    let started = Instant::now();
+    let call = call_llm(
+        &client,
+        &url,
+        &body,
+        "",
+        ProviderApi::OpenAiCompatible,
+        "",
+        "",
+        5,
+        1,
+        600,
+        &EmptyCompletionSettings::UNADJUSTED,
+    );
+    let error = with_rate_limit_deferral(Some(Duration::from_secs(30)), call)
+        .await
+        .unwrap_err();
+
+    let deferred = error.downcast_ref::<RateLimitDeferred>().expect("deferred");
+    assert_eq!(deferred.retry_after, Duration::from_secs(120));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn deferred_messages_take_the_shortest_covering_queue() {
+    let steps = [5_000, 10_000, 60_000, 300_000];
+    assert_eq!(defer_step(&steps, Duration::from_secs(30)), Some(60_000));
+    assert_eq!(defer_step(&steps, Duration::from_secs(60)), Some(60_000));
+    assert_eq!(defer_step(&steps, Duration::from_secs(3600)), Some(300_000));
+    assert_eq!(defer_step(&[], Duration::from_secs(30)), None);
+}
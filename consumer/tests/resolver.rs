@@ -0,0 +1,41 @@
+//! Host overrides and address literals resolve without any DNS query, and broker
+//! URIs get IPv6 addresses in brackets.
+
+use consumer::resolver::Resolver;
+use consumer::settings::{IpFamily, NetworkSettings};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+fn resolver() -> Resolver {
+    let network = NetworkSettings {
+        ip_family: IpFamily::V6,
+        // Nothing listens there, so any query would fail.
+        dns_servers: vec!["127.0.0.1:9".parse().unwrap()],
+        host_overrides: HashMap::from([
+            ("llm.internal".to_string(), "fd00::10".parse().unwrap()),
+            ("rabbitmq".to_string(), "10.0.0.5".parse().unwrap()),
+        ]),
+    };
+    Resolver::new(&network, 16, Duration::ZERO)
+}
+
+#[tokio::test]
+async fn overrides_skip_the_lookup() {
+    let resolver = resolver();
+    let ip: IpAddr = "fd00::10".parse().unwrap();
+    assert_eq!(resolver.lookup("llm.internal").await.unwrap(), [ip]);
+    assert_eq!(
+        resolver.uri_host("llm.internal").await.unwrap(),
+        "[fd00::10]"
+    );
+    // Overrides win over the configured address family.
+    assert_eq!(resolver.uri_host("rabbitmq").await.unwrap(), "10.0.0.5");
+}
+
+#[tokio::test]
+async fn address_literals_resolve_to_themselves() {
+    let resolver = resolver();
+    assert_eq!(resolver.uri_host("[::1]").await.unwrap(), "[::1]");
+    assert_eq!(resolver.uri_host("192.0.2.1").await.unwrap(), "192.0.2.1");
+}
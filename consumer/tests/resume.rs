@@ -0,0 +1,50 @@
+//! Tasks stranded in PROCESSING keep their message until their final status, and are
+//! published again at the priority they were submitted with.
+
+use chrono::Utc;
+use consumer::db::{event_update_fields, TASK_MESSAGE};
+use consumer::producer::priority;
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::task_status::TaskStatus;
+use serde_json::{json, Value};
+
+fn processing(message: Value) -> LLMResponse {
+    let now = Utc::now();
+    LLMResponse {
+        completions: Value::Null,
+        cached: false,
+        attempt: 0,
+        started_at: now,
+        completed_at: now,
+        annotations: [(TASK_MESSAGE.to_string(), message)].into_iter().collect(),
+        usage: None,
+        cost: None,
+    }
+}
+
+#[test]
+fn final_statuses_clear_the_task_message() {
+    let message = json!({ "batch_id": "b1", "payload": { "custom_id": "t1" } });
+    let response = processing(message.clone());
+    let fields = event_update_fields(TaskStatus::Processing, &response, response.started_at);
+    assert_eq!(fields.get(TASK_MESSAGE), Some(&message));
+
+    let response = LLMResponse {
+        completions: json!({ "choices": [{ "message": { "content": "Hello" } }] }),
+        annotations: Default::default(),
+        ..response
+    };
+    for status in [TaskStatus::Completed, TaskStatus::Failed] {
+        let fields = event_update_fields(status, &response, response.started_at);
+        assert_eq!(fields.get(TASK_MESSAGE), Some(&Value::Null));
+    }
+}
+
+#[test]
+fn resumed_tasks_keep_their_priority() {
+    assert_eq!(priority(&json!({ "priority": 3 }), 5), Some(3));
+    assert_eq!(priority(&json!({ "priority": 9 }), 5), Some(5));
+    assert_eq!(priority(&json!({ "priority": -1 }), 5), Some(0));
+    assert_eq!(priority(&json!({ "priority": 3 }), 0), None);
+    assert_eq!(priority(&json!({}), 5), None);
+}
@@ -0,0 +1,219 @@
+//! Property tests for how `call_llm` classifies failures and spaces out retries.
+
+use consumer::llm_wrapper::{
+    adjust_for_retry, classify_body, classify_completion, classify_status, retry_strategy, Failure,
+};
+use consumer::settings::EmptyCompletionSettings;
+use proptest::prelude::*;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Arbitrary JSON, up to a few levels deep.
+fn json_value() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        "[a-z ]{0,12}".prop_map(Value::from),
+    ];
+    leaf.prop_recursive(3, 32, 4, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..4).prop_map(Value::from),
+            prop::collection::btree_map("[a-z_]{1,8}", inner, 0..4)
+                .prop_map(|m| Value::Object(m.into_iter().collect())),
+        ]
+    })
+}
+
+/// Where providers put the error object: top level, or nested under `completions`.
+fn with_error(error: Value, nested: bool) -> Value {
+    if nested {
+        json!({ "completions": { "error": error } })
+    } else {
+        json!({ "error": error })
+    }
+}
+
+proptest! {
+    #[test]
+    fn server_errors_are_transient(status in 500u16..600, body in ".*") {
+        prop_assert!(matches!(classify_status(status, None, &body), Failure::Transient(_)));
+    }
+
+    #[test]
+    fn client_errors_are_permanent(status in 400u16..500, body in ".*") {
+        prop_assume!(status != 429);
+        match classify_status(status, None, &body) {
+            Failure::Permanent(message) => prop_assert!(message.contains(&body)),
+            other => prop_assert!(false, "{} classified as {:?}", status, other),
+        }
+    }
+
+    #[test]
+    fn rate_limit_honors_retry_after(secs in 0u32..100_000) {
+        let failure = classify_status(429, Some(&secs.to_string()), "");
+        match failure {
+            Failure::RateLimited { retry_after, .. } => {
+                prop_assert_eq!(retry_after, Duration::from_secs(secs as u64))
+            }
+            other => prop_assert!(false, "classified as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rate_limit_defaults_on_unparseable_retry_after(header in "[^0-9]*") {
+        match classify_status(429, Some(&header), "") {
+            Failure::RateLimited { retry_after, .. } => {
+                prop_assert_eq!(retry_after, Duration::from_secs(2))
+            }
+            other => prop_assert!(false, "classified as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bodies_without_error_are_successes(mut value in json_value(), now_ms in any::<u64>()) {
+        if let Some(object) = value.as_object_mut() {
+            object.remove("error");
+            object.remove("completions");
+        }
+        prop_assert_eq!(classify_body(&value, now_ms), None);
+    }
+
+    #[test]
+    fn embedded_server_errors_are_transient(
+        code in 500u64..600,
+        message in "[a-z ]{0,20}",
+        nested in any::<bool>(),
+    ) {
+        let body = with_error(json!({ "code": code, "message": message }), nested);
+        prop_assert!(matches!(classify_body(&body, 0), Some(Failure::Transient(_))));
+    }
+
+    #[test]
+    fn embedded_other_errors_are_permanent(
+        code in prop_oneof![0u64..429, 430u64..500, 600u64..100_000],
+        nested in any::<bool>(),
+        extra in json_value(),
+    ) {
+        let body = with_error(json!({ "code": code, "metadata": extra }), nested);
+        prop_assert!(matches!(classify_body(&body, 0), Some(Failure::Permanent(_))));
+    }
+
+    #[test]
+    fn embedded_rate_limit_waits_until_reset(
+        now_ms in 0u64..4_000_000_000_000,
+        ahead_ms in 0u64..3_600_000,
+        nested in any::<bool>(),
+    ) {
+        let reset = now_ms + ahead_ms;
+        let body = with_error(
+            json!({
+                "code": 429,
+                "metadata": { "headers": { "X-RateLimit-Reset": reset.to_string() } }
+            }),
+            nested,
+        );
+        let expected = if ahead_ms > 0 { ahead_ms / 1000 + 1 } else { 2 };
+        match classify_body(&body, now_ms) {
+            Some(Failure::RateLimited { retry_after, .. }) => {
+                prop_assert!(retry_after >= Duration::from_secs(1));
+                prop_assert!(retry_after.as_millis() as u64 >= ahead_ms);
+                prop_assert_eq!(retry_after, Duration::from_secs(expected));
+            }
+            other => prop_assert!(false, "classified as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn embedded_rate_limit_defaults_without_reset(
+        metadata in json_value(),
+        now_ms in any::<u64>(),
+    ) {
+        let mut metadata = metadata;
+        if let Some(headers) = metadata.get_mut("headers").and_then(Value::as_object_mut) {
+            headers.remove("X-RateLimit-Reset");
+        }
+        let body = json!({ "error": { "code": 429, "metadata": metadata } });
+        match classify_body(&body, now_ms) {
+            Some(Failure::RateLimited { retry_after, .. }) => {
+                prop_assert_eq!(retry_after, Duration::from_secs(2))
+            }
+            other => prop_assert!(false, "classified as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn backoff_is_bounded(
+        base_delay_ms in 1u64..60_000,
+        max_delay_secs in 0u64..600,
+        retry_attempts in 0u32..40,
+    ) {
+        let delays: Vec<Duration> =
+            retry_strategy(base_delay_ms, max_delay_secs, retry_attempts).collect();
+        prop_assert_eq!(delays.len(), retry_attempts as usize);
+        for delay in delays {
+            prop_assert!(delay <= Duration::from_secs(max_delay_secs));
+        }
+    }
+
+    #[test]
+    fn short_completions_are_transient(content in "[a-z ]{0,12}", min_chars in 1usize..20) {
+        let body = json!({ "choices": [{ "message": { "role": "assistant", "content": content } }] });
+        let short = content.trim().chars().count() < min_chars;
+        match classify_completion(&body, min_chars) {
+            Some(Failure::Transient(_)) => prop_assert!(short),
+            None => prop_assert!(!short),
+            other => prop_assert!(false, "classified as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn adjusted_parameters_stay_bounded(
+        temperature in 0.0f64..2.0,
+        max_tokens in 1u64..100_000,
+        step in 0.0f64..1.0,
+        max_temperature in 0.0f64..2.0,
+        factor in 1.0f64..3.0,
+    ) {
+        let settings = EmptyCompletionSettings {
+            min_chars: 1,
+            adjust_params: true,
+            temperature_step: step,
+            max_temperature,
+            max_tokens_factor: factor,
+        };
+        let mut body = json!({ "temperature": temperature, "max_tokens": max_tokens });
+        let changed = adjust_for_retry(&mut body, &settings);
+        let adjusted = body["temperature"].as_f64().unwrap();
+        prop_assert!(adjusted >= temperature);
+        prop_assert!(adjusted == temperature || adjusted <= max_temperature + 0.001);
+        prop_assert!(body["max_tokens"].as_u64().unwrap() >= max_tokens);
+        for (key, value) in &changed {
+            prop_assert_eq!(&body[key], value);
+        }
+    }
+}
+
+#[test]
+fn tool_calls_are_not_empty_completions() {
+    let body = json!({
+        "choices": [{
+            "message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{ "id": "call_1", "type": "function" }]
+            },
+            "finish_reason": "tool_calls"
+        }]
+    });
+    assert_eq!(classify_completion(&body, 1), None);
+}
+
+#[test]
+fn missing_choices_are_empty_completions() {
+    let body = json!({ "id": "gen-1", "model": "gpt-4o", "choices": [] });
+    assert!(matches!(
+        classify_completion(&body, 1),
+        Some(Failure::Transient(_))
+    ));
+}
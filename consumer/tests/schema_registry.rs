@@ -0,0 +1,57 @@
+//! Tasks are checked against the payload schema of their `task_type`; tasks of
+//! unregistered types only pass when the registry isn't strict.
+
+use consumer::schema_registry::SchemaRegistry;
+use consumer::settings::SchemaRegistrySettings;
+use serde_json::json;
+
+fn registry(strict: bool) -> SchemaRegistry {
+    let schemas = json!({
+        "qa": {
+            "type": "object",
+            "required": ["body"],
+            "properties": {
+                "body": {
+                    "type": "object",
+                    "required": ["messages"],
+                }
+            }
+        },
+        "broken": { "type": 12 },
+    });
+    SchemaRegistry::new(&SchemaRegistrySettings {
+        schemas: serde_json::from_value(schemas).unwrap(),
+        strict,
+        document: None,
+    })
+}
+
+#[test]
+fn rejects_payloads_breaking_their_schema() {
+    let registry = registry(false);
+    assert_eq!(
+        registry.check(&json!({ "task_type": "qa", "body": { "messages": [] } })),
+        Ok(())
+    );
+    let errors = registry
+        .check(&json!({ "task_type": "qa", "body": {} }))
+        .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("/body: "), "{}", errors[0]);
+}
+
+#[test]
+fn unregistered_task_types_pass_unless_strict() {
+    let task = json!({ "task_type": "summary", "body": {} });
+    assert_eq!(registry(false).check(&task), Ok(()));
+    assert_eq!(registry(false).check(&json!({ "body": {} })), Ok(()));
+    assert!(registry(true).check(&task).is_err());
+    assert!(registry(true).check(&json!({ "body": {} })).is_err());
+}
+
+#[test]
+fn invalid_schemas_are_left_out() {
+    let registry = registry(false);
+    assert!(registry.schema("broken").is_none());
+    assert_eq!(registry.schemas().keys().collect::<Vec<_>>(), ["qa"]);
+}
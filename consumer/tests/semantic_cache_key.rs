@@ -0,0 +1,64 @@
+//! Templated tasks can be cached on the meaning of their template and their variables,
+//! so reformatting a template keeps its cached completions.
+
+use consumer::cache_key::CacheKeys;
+use consumer::settings::{CacheKeyMode, CacheKeySettings};
+use consumer::templates::PromptTemplates;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+fn keys(content: &str) -> CacheKeys {
+    let qa = json!({
+        "model": "gpt-4o-mini",
+        "messages": [{ "role": "user", "content": content }],
+    });
+    CacheKeys::with_templates(
+        &CacheKeySettings {
+            mode: CacheKeyMode::Replace,
+            excluded_fields: Vec::new(),
+            semantic_templates: true,
+            hash: Default::default(),
+        },
+        Arc::new(PromptTemplates::new(HashMap::from([(
+            "qa-v1".to_string(),
+            qa,
+        )]))),
+    )
+}
+
+fn task(variables: Value) -> Value {
+    json!({ "template": "qa-v1", "variables": variables })
+}
+
+#[test]
+fn whitespace_changes_to_a_template_keep_its_key() {
+    let variables = json!({ "question": "Why is the sky blue?", "lang": "en" });
+    let key = keys("Q: {{ question }}")
+        .resolve(&task(variables.clone()), "ignored")
+        .unwrap();
+    let reformatted = keys("  Q:   {{question}}\n")
+        .resolve(&task(variables.clone()), "ignored")
+        .unwrap();
+    assert_eq!(key, reformatted);
+
+    let edited = keys("Question: {{ question }}")
+        .resolve(&task(variables), "ignored")
+        .unwrap();
+    assert_ne!(key, edited);
+}
+
+#[test]
+fn variables_are_canonicalized() {
+    let keys = keys("Q: {{ question }}");
+    let first: Value = serde_json::from_str(r#"{"question": "Why?", "lang": "en"}"#).unwrap();
+    let second: Value = serde_json::from_str(r#"{"lang": "en", "question": "Why?"}"#).unwrap();
+    assert_eq!(
+        keys.resolve(&task(first), "ignored"),
+        keys.resolve(&task(second), "ignored")
+    );
+    assert_ne!(
+        keys.resolve(&task(json!({ "question": "Why?" })), "ignored"),
+        keys.resolve(&task(json!({ "question": "How?" })), "ignored")
+    );
+}
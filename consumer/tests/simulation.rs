@@ -0,0 +1,156 @@
+//! Trace replay classifies attempts like the consumer, retries under the policy and
+//! holds a concurrency slot through retries and rate-limit waits.
+
+use consumer::settings::ModelPrice;
+use consumer::simulation::{load_trace, simulate, Policy, TraceRecord};
+use std::time::Duration;
+
+fn record(line: &str) -> TraceRecord {
+    serde_json::from_str(line).unwrap()
+}
+
+fn policy(retry_attempts: u32, concurrency: usize) -> Policy {
+    Policy {
+        retry_attempts,
+        // No backoff, so that replays don't depend on the jitter.
+        base_delay_ms: 0,
+        max_delay_secs: 0,
+        concurrency,
+        rate_limit_rpm: None,
+    }
+}
+
+const PRICE: ModelPrice = ModelPrice {
+    input_per_1m: 1.0,
+    output_per_1m: 2.0,
+};
+
+#[test]
+fn successful_attempts_add_their_tokens_and_cost() {
+    let trace = [record(
+        r#"{"latency_ms": 500, "prompt_tokens": 1000, "completion_tokens": 250}"#,
+    )];
+    let report = simulate(&trace, 4, &policy(2, 2), PRICE);
+
+    assert_eq!(report.succeeded, 4);
+    assert_eq!(report.failed, 0);
+    assert_eq!(report.attempts, 4);
+    assert_eq!(report.prompt_tokens, 4000);
+    assert_eq!(report.completion_tokens, 1000);
+    assert!((report.cost - 0.006).abs() < 1e-12);
+    // Two rounds of two tasks.
+    assert_eq!(report.makespan, Duration::from_millis(1000));
+    assert_eq!(report.p50_latency, Duration::from_millis(500));
+    assert!((report.throughput() - 4.0).abs() < 1e-9);
+}
+
+#[test]
+fn transient_failures_are_retried_with_the_next_records() {
+    let trace = [
+        record(r#"{"latency_ms": 100, "status": 503}"#),
+        record(r#"{"latency_ms": 200}"#),
+    ];
+    let report = simulate(&trace, 1, &policy(1, 1), PRICE);
+
+    assert_eq!(report.succeeded, 1);
+    assert_eq!(report.attempts, 2);
+    assert_eq!(report.makespan, Duration::from_millis(300));
+}
+
+#[test]
+fn tasks_fail_once_their_retries_run_out() {
+    let trace = [record(r#"{"latency_ms": 100, "status": 502}"#)];
+    let report = simulate(&trace, 2, &policy(2, 1), PRICE);
+
+    assert_eq!(report.succeeded, 0);
+    assert_eq!(report.failed, 2);
+    assert_eq!(report.attempts, 6);
+    assert_eq!(report.cost, 0.0);
+    assert_eq!(report.throughput(), 0.0);
+}
+
+#[test]
+fn permanent_failures_are_not_retried() {
+    let trace = [
+        record(r#"{"latency_ms": 100, "status": 400}"#),
+        record(r#"{"latency_ms": 100}"#),
+    ];
+    let report = simulate(&trace, 1, &policy(3, 1), PRICE);
+
+    assert_eq!(report.failed, 1);
+    assert_eq!(report.attempts, 1);
+}
+
+#[test]
+fn errors_inside_a_200_are_failures() {
+    let trace = [record(
+        r#"{"latency_ms": 100, "body": {"error": {"code": 400, "message": "Invalid model"}}}"#,
+    )];
+    let report = simulate(&trace, 1, &policy(3, 1), PRICE);
+
+    assert_eq!(report.failed, 1);
+    assert_eq!(report.attempts, 1);
+}
+
+#[test]
+fn rate_limited_tasks_wait_out_retry_after_in_their_slot() {
+    let trace = [
+        record(r#"{"latency_ms": 100, "status": 429, "retry_after": "4"}"#),
+        record(r#"{"latency_ms": 100}"#),
+    ];
+    let report = simulate(&trace, 1, &policy(1, 1), PRICE);
+
+    assert_eq!(report.succeeded, 1);
+    assert_eq!(report.makespan, Duration::from_millis(4200));
+    assert_eq!(report.p95_latency, Duration::from_millis(4200));
+}
+
+#[test]
+fn the_rate_limit_spaces_out_request_starts() {
+    let trace = [record(r#"{"latency_ms": 100}"#)];
+    let report = simulate(
+        &trace,
+        3,
+        &Policy {
+            rate_limit_rpm: Some(60),
+            ..policy(0, 3)
+        },
+        PRICE,
+    );
+
+    // Starts at 0s, 1s and 2s despite three free slots.
+    assert_eq!(report.makespan, Duration::from_millis(2100));
+    assert_eq!(report.p50_latency, Duration::from_millis(1100));
+}
+
+#[test]
+fn traces_load_skipping_blank_lines() {
+    let path = std::env::temp_dir().join(format!("trace-{}.jsonl", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &path,
+        "{\"latency_ms\": 840, \"prompt_tokens\": 52}\n\n{\"latency_ms\": 95, \"status\": 429, \"retry_after\": \"4\"}\n",
+    )
+    .unwrap();
+    let trace = load_trace(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(trace.len(), 2);
+    assert_eq!(trace[0].status, 200);
+    assert_eq!(trace[0].prompt_tokens, 52);
+    assert_eq!(trace[1].retry_after.as_deref(), Some("4"));
+}
+
+#[test]
+fn malformed_and_empty_traces_are_rejected() {
+    let path = std::env::temp_dir().join(format!("trace-{}.jsonl", uuid::Uuid::new_v4()));
+    let path_name = path.to_str().unwrap().to_string();
+
+    std::fs::write(&path, "{\"latency_ms\": 1}\n{\"status\": 200}\n").unwrap();
+    let error = load_trace(&path_name).unwrap_err().to_string();
+    assert!(error.starts_with(&format!("{}:2:", path_name)), "{}", error);
+
+    std::fs::write(&path, "\n").unwrap();
+    let error = load_trace(&path_name).unwrap_err().to_string();
+    std::fs::remove_file(&path).unwrap();
+    assert!(error.contains("has no trace records"), "{}", error);
+}
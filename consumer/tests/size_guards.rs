@@ -0,0 +1,71 @@
+//! Request bodies over `MAX_REQUEST_BODY_BYTES` are refused, and completions over
+//! `STORED_COMPLETION_MAX_BYTES` offloaded to the object store under
+//! `STORED_COMPLETION_POLICY=offload`.
+
+use consumer::payload::check_request_size;
+use consumer::settings::{ArchiveSettings, StoredCompletionPolicy, StoredSizeSettings};
+use consumer::truncation::Truncation;
+use serde_json::json;
+
+mod common;
+
+fn offloading(archive: Option<ArchiveSettings>) -> Truncation {
+    Truncation::new(&StoredSizeSettings {
+        max_error_bytes: 0,
+        max_completion_bytes: 64,
+        completion_policy: StoredCompletionPolicy::Offload,
+        archive,
+    })
+}
+
+#[test]
+fn request_bodies_over_the_limit_are_refused() {
+    let body = json!({ "model": "gpt-4o", "messages": [{ "role": "user", "content": "hi" }] });
+    let bytes = body.to_string().len();
+    assert!(check_request_size(&body, 0).is_ok());
+    assert!(check_request_size(&body, bytes).is_ok());
+    let error = check_request_size(&body, bytes - 1).unwrap_err();
+    assert!(error.contains(&format!("{} bytes", bytes)));
+}
+
+#[tokio::test]
+async fn large_completions_are_offloaded_whole() {
+    let (endpoint, upload) = common::object_store().await;
+    let truncation = offloading(Some(ArchiveSettings {
+        endpoint,
+        bucket: "synthgen".to_string(),
+        region: "us-east-1".to_string(),
+        access_key: "minio".to_string(),
+        secret_key: "minio-secret".to_string(),
+        prefix: "truncated/".to_string(),
+    }));
+    // Short texts, but the tool calls make it large.
+    let original = json!({
+        "choices": [{ "message": { "content": "ok", "tool_calls": ["x".repeat(200)] } }],
+    });
+    let mut completions = original.clone();
+    let annotation = truncation.completions(&mut completions).await.unwrap();
+    assert_eq!(annotation["offloaded"], true);
+    let location = annotation["archive_url"].as_str().unwrap();
+    assert!(location.starts_with("s3://synthgen/truncated/completions/"));
+    assert_eq!(completions, json!({ "archive_url": location }));
+    let request = upload.await.unwrap();
+    let (_, body) = request.split_once("\r\n\r\n").unwrap();
+    let uploaded: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(uploaded, original);
+}
+
+#[tokio::test]
+async fn completions_are_truncated_without_an_object_store() {
+    let truncation = offloading(None);
+    let mut completions = json!({ "choices": [{ "message": { "content": "a".repeat(100) } }] });
+    let annotation = truncation.completions(&mut completions).await.unwrap();
+    assert!(annotation.get("offloaded").is_none());
+    assert!(completions["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap()
+        .contains("bytes truncated"));
+
+    let mut small = json!({ "choices": [{ "message": { "content": "fine" } }] });
+    assert!(truncation.completions(&mut small).await.is_none());
+}
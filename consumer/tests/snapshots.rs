@@ -0,0 +1,18 @@
+//! Operations rewriting events snapshot the indexes first, under names telling what
+//! they were taken before and when.
+
+use chrono::{TimeZone, Utc};
+use consumer::db::elastic::snapshot_name;
+
+#[test]
+fn snapshots_are_named_after_their_operation_and_time() {
+    let now = Utc.with_ymd_and_hms(2024, 3, 9, 14, 5, 7).unwrap();
+    assert_eq!(
+        snapshot_name("cancel-batch", now),
+        "synthgen-cancel-batch-2024.03.09-14.05.07"
+    );
+    assert_eq!(
+        snapshot_name("Requeue-Failed", now),
+        "synthgen-requeue-failed-2024.03.09-14.05.07"
+    );
+}
@@ -0,0 +1,124 @@
+//! Standalone mode submits the tasks of a JSONL input to an in-process broker, whose
+//! stream ends once every task is settled, and the JSONL store writes out the events of
+//! finished tasks.
+
+use chrono::Utc;
+use consumer::broker::local::LocalBroker;
+use consumer::broker::{MessageBroker, ATTEMPT_HEADER};
+use consumer::db::jsonl::JsonlStore;
+use consumer::db::{EventKey, TaskStore};
+use consumer::schemas::llm_response::LLMResponse;
+use consumer::schemas::task_status::TaskStatus;
+use consumer::standalone;
+use futures_lite::StreamExt;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+fn task(custom_id: &str) -> String {
+    json!({
+        "custom_id": custom_id,
+        "method": "POST",
+        "url": "/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [{ "role": "user", "content": custom_id }] },
+    })
+    .to_string()
+}
+
+fn output_path() -> String {
+    std::env::temp_dir()
+        .join(format!("standalone-test-{}.jsonl", uuid::Uuid::new_v4()))
+        .display()
+        .to_string()
+}
+
+#[tokio::test]
+async fn stream_ends_once_every_task_is_settled() {
+    let (broker, tasks) = LocalBroker::new(4, vec![]);
+    let broker = Arc::new(broker);
+    tasks.send(b"first".to_vec()).await.unwrap();
+    tasks.send(b"second".to_vec()).await.unwrap();
+    drop(tasks);
+
+    let mut stream = broker.clone().consume("standalone").await.unwrap();
+    let first = stream.next().await.unwrap().unwrap();
+    let second = stream.next().await.unwrap().unwrap();
+    first.ack().await.unwrap();
+    second.requeue().await.unwrap();
+
+    let retried = stream.next().await.unwrap().unwrap();
+    assert_eq!(retried.data, b"second");
+    assert_eq!(retried.headers[ATTEMPT_HEADER], "2");
+    assert_eq!(retried.delivery_attempt(), 2);
+    assert_eq!(broker.unsettled(), 1);
+    retried.dead_letter("failed").await.unwrap();
+    assert!(stream.next().await.is_none());
+    assert!(stream.next().await.is_none());
+}
+
+#[tokio::test]
+async fn feed_submits_valid_lines_and_skips_the_others() {
+    let path = output_path();
+    let store = JsonlStore::create(&path).unwrap();
+    let input = format!("{}\n\nnot json\n{}\n", task("a"), task("b"));
+    let (broker, tasks) = LocalBroker::new(8, vec![]);
+    let report = standalone::feed(input.as_bytes(), "batch", &store, tasks)
+        .await
+        .unwrap();
+    assert_eq!(report.submitted, 2);
+    assert_eq!(report.rejected, 1);
+
+    let mut stream = Arc::new(broker).consume("standalone").await.unwrap();
+    let message: Value =
+        serde_json::from_slice(&stream.next().await.unwrap().unwrap().data).unwrap();
+    assert_eq!(message["batch_id"], "batch");
+    assert_eq!(message["payload"]["custom_id"], "a");
+    let message_id = message["message_id"].as_str().unwrap();
+    assert_eq!(
+        store.event_status(message_id).await.unwrap(),
+        Some(TaskStatus::Pending)
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn jsonl_store_writes_finished_events_once() {
+    let path = output_path();
+    let store = JsonlStore::create(&path).unwrap();
+    let event = EventKey {
+        message_id: "m1",
+        batch_id: "batch",
+        body_hash: "hash",
+    };
+    let response = LLMResponse {
+        completions: json!({ "choices": [{ "message": { "content": "hi" } }] }),
+        cached: false,
+        attempt: 1,
+        started_at: Utc::now(),
+        completed_at: Utc::now(),
+        annotations: Default::default(),
+        usage: None,
+        cost: None,
+    };
+    for status in [
+        TaskStatus::Processing,
+        TaskStatus::Completed,
+        TaskStatus::Completed,
+    ] {
+        store
+            .update_event_status(&event, status, &response, Utc::now())
+            .await
+            .unwrap();
+    }
+
+    let output = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["message_id"], "m1");
+    assert_eq!(lines[0]["status"], "COMPLETED");
+    let cached = store.get_cached_completion("hash").await.unwrap().unwrap();
+    assert!(cached.cached);
+    let _ = std::fs::remove_file(&path);
+}
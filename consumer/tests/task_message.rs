@@ -0,0 +1,82 @@
+//! Task messages are checked against their types when decoded, naming the field at
+//! fault, rather than read with defaults.
+
+use consumer::schemas::envelope::{TaskKind, TaskMessage, TaskPayload};
+use serde_json::json;
+
+fn message(payload: serde_json::Value) -> Vec<u8> {
+    serde_json::to_vec(&json!({
+        "message_id": "msg-1",
+        "batch_id": "batch-1",
+        "body_hash": "hash",
+        "timestamp": "2026-03-02T10:00:00Z",
+        "payload": payload,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn well_formed_messages_keep_their_extra_fields() {
+    let data = message(json!({
+        "method": "POST",
+        "url": "https://api.openai.com/v1/chat/completions",
+        "body": { "model": "gpt-4o", "messages": [] },
+        "kind": "embedding",
+        "use_cache": true,
+        "timeout_secs": 30,
+        "sla_deadline": "2026-03-02T12:00:00+01:00",
+        "providers": [{ "url": "http://vllm:8000/v1/chat/completions", "api_key": null }],
+        "dataset": "geometry",
+    }));
+    let message = TaskMessage::parse(&data).unwrap();
+    assert_eq!(message.message_id, "msg-1");
+    assert_eq!(message.payload["dataset"], "geometry");
+
+    let payload = TaskPayload::from_value(&message.payload).unwrap();
+    assert_eq!(payload.kind, Some(TaskKind::Embedding));
+    assert_eq!(payload.use_cache, Some(true));
+    assert_eq!(payload.providers.unwrap()[0].api_key, None);
+
+    // Templated tasks have no body until they're rendered.
+    let templated = json!({ "method": "POST", "url": "u", "template": "qa", "variables": {} });
+    assert!(TaskPayload::from_value(&templated).is_ok());
+}
+
+#[test]
+fn messages_without_identifiers_are_malformed() {
+    let data = br#"{ "batch_id": "batch-1", "body_hash": "hash", "payload": {} }"#;
+    assert!(TaskMessage::parse(data).unwrap_err().contains("message_id"));
+
+    let mut message: serde_json::Value = serde_json::from_slice(&self::message(json!({}))).unwrap();
+    message["batch_id"] = json!("");
+    let data = serde_json::to_vec(&message).unwrap();
+    assert_eq!(
+        TaskMessage::parse(&data).unwrap_err(),
+        "batch_id: must not be empty"
+    );
+}
+
+#[test]
+fn malformed_payloads_name_the_field_at_fault() {
+    let payload = |fields: serde_json::Value| {
+        let mut payload = json!({ "method": "POST", "url": "u", "body": {} });
+        payload
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        TaskPayload::from_value(&payload).unwrap_err()
+    };
+    assert!(payload(json!({ "use_cache": "yes" })).starts_with("use_cache: invalid type"));
+    assert!(payload(json!({ "timeout_secs": -5 })).starts_with("timeout_secs: "));
+    assert!(payload(json!({ "providers": [{ "api_key": "k" }] }))
+        .starts_with("providers[0]: missing field `url`"));
+    assert!(payload(json!({ "kind": "image" })).starts_with("kind: unknown variant"));
+    assert_eq!(
+        payload(json!({ "body": null })),
+        "body: required unless a template is given"
+    );
+    assert_eq!(
+        payload(json!({ "template": 3 })),
+        "template: must be a template name or an object"
+    );
+}
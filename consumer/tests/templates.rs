@@ -0,0 +1,57 @@
+//! Templated payloads get their body rendered from the template and variables before
+//! anything reads it.
+
+use consumer::templates::PromptTemplates;
+use serde_json::json;
+use std::collections::HashMap;
+
+fn templates() -> PromptTemplates {
+    let qa = json!({
+        "model": "gpt-4o-mini",
+        "messages": [
+            { "role": "system", "content": "Answer in {{ language | default('English') }}." },
+            "{{ examples }}",
+            { "role": "user", "content": "Q: {{ question }}" },
+        ],
+        "max_tokens": 256,
+    });
+    PromptTemplates::new(HashMap::from([("qa-v1".to_string(), qa)]))
+}
+
+#[test]
+fn renders_named_templates_into_the_body() {
+    let mut payload = json!({
+        "template": "qa-v1",
+        "variables": {
+            "question": "Why is the sky blue?",
+            "examples": { "role": "assistant", "content": "Rayleigh scattering." },
+        },
+    });
+    templates().render_payload(&mut payload).unwrap();
+    assert_eq!(
+        payload["body"],
+        json!({
+            "model": "gpt-4o-mini",
+            "messages": [
+                { "role": "system", "content": "Answer in English." },
+                { "role": "assistant", "content": "Rayleigh scattering." },
+                { "role": "user", "content": "Q: Why is the sky blue?" },
+            ],
+            "max_tokens": 256,
+        })
+    );
+}
+
+#[test]
+fn undefined_variables_and_unknown_templates_are_errors() {
+    let templates = templates();
+    let mut missing_question = json!({ "template": "qa-v1", "variables": { "examples": [] } });
+    assert!(templates.render_payload(&mut missing_question).is_err());
+
+    let mut unknown = json!({ "template": "qa-v2", "variables": {} });
+    assert!(templates.render_payload(&mut unknown).is_err());
+
+    let mut plain = json!({ "body": { "model": "m" } });
+    templates.render_payload(&mut plain).unwrap();
+    assert_eq!(plain, json!({ "body": { "model": "m" } }));
+}
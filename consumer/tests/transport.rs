@@ -0,0 +1,64 @@
+//! Requests to a host with a configured transport go through it, e.g. to an inference
+//! sidecar only listening on a Unix domain socket.
+
+use consumer::llm_wrapper::LLMClient;
+use consumer::settings::{NetworkSettings, TransportSettings};
+use tokio::net::UnixListener;
+
+mod common;
+
+#[tokio::test]
+async fn requests_to_the_sidecar_go_through_its_socket() {
+    let dir = std::env::temp_dir().join(format!("synthgen-uds-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("vllm.sock");
+    let _ = std::fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket).unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        common::answer(
+            &mut stream,
+            &common::json_response(r#"{"served_by":"sidecar"}"#),
+        )
+        .await;
+    });
+
+    let transports = [TransportSettings {
+        unix_socket: Some(socket.to_string_lossy().into_owned()),
+        host: "vllm.sidecar".to_string(),
+        ..TransportSettings::default()
+    }];
+    let client = LLMClient::with_settings(
+        &common::http_settings(),
+        &NetworkSettings::default(),
+        &transports,
+    )
+    .unwrap();
+    let url = "http://vllm.sidecar/v1/chat/completions";
+    let body = client
+        .for_url(url)
+        .post(url)
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(body, r#"{"served_by":"sidecar"}"#);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn client_certificates_need_their_key() {
+    let transports = [TransportSettings {
+        client_cert: Some("/etc/synthgen/client.pem".to_string()),
+        host: "vllm.sidecar:8443".to_string(),
+        ..TransportSettings::default()
+    }];
+    assert!(LLMClient::with_settings(
+        &common::http_settings(),
+        &NetworkSettings::default(),
+        &transports
+    )
+    .is_err());
+}
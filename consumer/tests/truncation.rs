@@ -0,0 +1,83 @@
+//! Provider error bodies and completion texts over their limit are kept as their head
+//! and tail, the full body going to the object store when one is configured.
+
+use consumer::settings::{ArchiveSettings, StoredCompletionPolicy, StoredSizeSettings};
+use consumer::truncation::{truncate, Truncation};
+use serde_json::json;
+
+mod common;
+
+#[test]
+fn long_texts_keep_their_head_and_tail() {
+    assert_eq!(truncate("short", 10), "short");
+    assert_eq!(truncate("unlimited", 0), "unlimited");
+
+    let text = format!("<html>{}</html>", "x".repeat(1000));
+    let truncated = truncate(&text, 20);
+    assert!(truncated.starts_with("<html>xxxx"));
+    assert!(truncated.ends_with("xxx</html>"));
+    assert!(truncated.contains("[993 bytes truncated]"));
+
+    // Cuts fall on character boundaries.
+    let accented = "é".repeat(100);
+    let truncated = truncate(&accented, 5);
+    assert!(truncated.starts_with("é\n"));
+    assert!(truncated.ends_with("\né"));
+}
+
+#[tokio::test]
+async fn completions_over_the_limit_are_truncated_and_annotated() {
+    let truncation = Truncation::new(&StoredSizeSettings {
+        max_error_bytes: 0,
+        max_completion_bytes: 10,
+        completion_policy: StoredCompletionPolicy::Truncate,
+        archive: None,
+    });
+    let mut completions = json!({
+        "choices": [
+            { "message": { "role": "assistant", "content": "a".repeat(50) } },
+            { "message": { "role": "assistant", "content": "short" } },
+        ],
+    });
+    let annotation = truncation.completions(&mut completions).await.unwrap();
+    assert_eq!(annotation["original_bytes"], 55);
+    assert_eq!(annotation["archive_url"], serde_json::Value::Null);
+    let content = completions["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap();
+    assert!(content.contains("[40 bytes truncated]"));
+    assert_eq!(completions["choices"][1]["message"]["content"], "short");
+
+    let mut short = json!({ "content": [{ "type": "text", "text": "Hello" }] });
+    assert!(truncation.completions(&mut short).await.is_none());
+}
+
+#[tokio::test]
+async fn full_error_bodies_are_archived() {
+    let (endpoint, upload) = common::object_store().await;
+    let truncation = Truncation::new(&StoredSizeSettings {
+        max_error_bytes: 16,
+        max_completion_bytes: 0,
+        completion_policy: StoredCompletionPolicy::Truncate,
+        archive: Some(ArchiveSettings {
+            endpoint,
+            bucket: "synthgen".to_string(),
+            region: "us-east-1".to_string(),
+            access_key: "minio".to_string(),
+            secret_key: "minio-secret".to_string(),
+            prefix: "truncated/".to_string(),
+        }),
+    });
+
+    let body = format!("<html>{}</html>", "Bad Gateway ".repeat(100));
+    let stored = truncation.error_body(body.clone()).await;
+    assert!(stored.contains("bytes truncated"));
+    assert!(stored.contains("[full body archived to s3://synthgen/truncated/errors/"));
+
+    let request = upload.await.unwrap();
+    assert!(request.starts_with("PUT /synthgen/truncated/errors/"));
+    assert!(request
+        .to_lowercase()
+        .contains("authorization: aws4-hmac-sha256 credential=minio/"));
+    assert!(request.ends_with(&body));
+}
@@ -0,0 +1,18 @@
+//! Webhook signatures are the hex HMAC-SHA256 of `<timestamp>.<body>`, so receivers
+//! can check them with any HMAC implementation.
+
+use consumer::webhook::signature;
+
+#[test]
+fn signs_timestamp_and_body() {
+    let body = r#"{"event":"task.completed"}"#;
+    assert_eq!(
+        signature("whsec_test", "1760000000", body),
+        "sha256=50b51ef4745cdd1361704d13749c5259385a54af44fd07859b847ce0460bcb95"
+    );
+    // Replaying the body under another timestamp invalidates the signature.
+    assert_ne!(
+        signature("whsec_test", "1760000001", body),
+        signature("whsec_test", "1760000000", body)
+    );
+}
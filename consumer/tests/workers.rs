@@ -0,0 +1,49 @@
+//! Consumer processes report their deliveries in flight and processed in heartbeats,
+//! and those gone silent while running are flagged stale.
+
+use chrono::{Duration as ChronoDuration, Utc};
+use consumer::admin::WorkerStatus;
+use consumer::settings::WorkerSettings;
+use consumer::workers::{self, Worker};
+use std::sync::Arc;
+use std::time::Duration;
+
+fn worker() -> Arc<Worker> {
+    Arc::new(Worker::new(&WorkerSettings {
+        id: "consumer-0-42".to_string(),
+        hostname: "consumer-0".to_string(),
+        heartbeat_secs: 15,
+        stale_after_secs: 60,
+    }))
+}
+
+#[test]
+fn heartbeats_count_deliveries_in_flight_and_processed() {
+    let worker = worker();
+    let first = worker.track();
+    let second = worker.track();
+    drop(first);
+    let heartbeat = worker.heartbeat(workers::RUNNING, Utc::now());
+    assert_eq!(heartbeat["worker_id"], "consumer-0-42");
+    assert_eq!(heartbeat["in_flight"], 1);
+    assert_eq!(heartbeat["processed"], 1);
+    drop(second);
+    assert_eq!((worker.in_flight(), worker.processed()), (0, 2));
+}
+
+#[test]
+fn silent_running_workers_are_stale() {
+    let worker = worker();
+    let stale_after = Duration::from_secs(60);
+    let now = Utc::now();
+    let recent = worker.heartbeat(workers::RUNNING, now - ChronoDuration::seconds(30));
+    let silent = worker.heartbeat(workers::RUNNING, now - ChronoDuration::seconds(90));
+    let stopped = worker.heartbeat(workers::STOPPED, now - ChronoDuration::seconds(90));
+    assert!(!workers::is_stale(&recent, now, stale_after));
+    assert!(workers::is_stale(&silent, now, stale_after));
+    assert!(!workers::is_stale(&stopped, now, stale_after));
+
+    let status = WorkerStatus::from_heartbeat(&silent, stale_after);
+    assert!(status.stale);
+    assert_eq!(status.hostname, "consumer-0");
+}
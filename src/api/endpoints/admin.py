@@ -0,0 +1,113 @@
+from fastapi import APIRouter, HTTPException, Depends, Query
+from pydantic import BaseModel
+from typing import Dict, List, Optional
+from tenacity import retry, stop_after_attempt, wait_exponential
+from core.config import settings
+from database.elastic_session import ElasticsearchClient, get_elasticsearch_client
+from core.auth import get_current_user
+import logging
+
+logger = logging.getLogger(__name__)
+
+router = APIRouter()
+USE_API_PREFIX = True
+
+
+class LeaderboardEntry(BaseModel):
+    rank: int
+    model: str
+    scored_tasks: int
+    correct_tasks: int
+    accuracy: float
+    mean_score: float
+    updated_at: Optional[str] = None
+
+
+class LeaderboardResponse(BaseModel):
+    # Models ranked by accuracy, per eval suite
+    suites: Dict[str, List[LeaderboardEntry]]
+
+
+@retry(
+    stop=stop_after_attempt(settings.RETRY_ATTEMPTS),
+    wait=wait_exponential(multiplier=1, min=4, max=10),
+    reraise=True,
+)
+@router.get("/admin/leaderboard", response_model=LeaderboardResponse)
+async def get_leaderboard(
+    suite: Optional[str] = Query(None, description="Only rank models on this suite"),
+    min_tasks: int = Query(
+        1, ge=1, description="Leave out models with fewer scored tasks"
+    ),
+    es_client: ElasticsearchClient = Depends(get_elasticsearch_client),
+    current_user: str = Depends(get_current_user),
+):
+    """
+    Accuracy of each model on the eval suites its benchmark-style tasks belong to,
+    aggregated by the consumer across all evaluation runs.
+    """
+    logger.info(f"Fetching leaderboard{f' of suite {suite}' if suite else ''}")
+    try:
+        suites = await es_client.get_leaderboard(suite, min_tasks)
+        if suite and not suites:
+            raise HTTPException(
+                status_code=404, detail=f"No scored tasks found for suite {suite}"
+            )
+        return LeaderboardResponse(suites=suites)
+    except HTTPException:
+        raise
+    except Exception as e:
+        logger.error(f"Failed to fetch leaderboard: {str(e)}")
+        raise HTTPException(
+            status_code=500, detail=f"Failed to fetch leaderboard: {str(e)}"
+        )
+
+
+class WorkerStatus(BaseModel):
+    worker_id: str
+    hostname: str
+    version: str
+    # running, or stopped once shut down cleanly
+    state: str
+    in_flight: int
+    processed: int
+    uptime_secs: int
+    started_at: str
+    last_heartbeat: str
+    # Running but silent for longer than WORKER_STALE_AFTER_SECS
+    stale: bool
+
+
+class WorkersResponse(BaseModel):
+    workers: List[WorkerStatus]
+    stale: int
+
+
+@retry(
+    stop=stop_after_attempt(settings.RETRY_ATTEMPTS),
+    wait=wait_exponential(multiplier=1, min=4, max=10),
+    reraise=True,
+)
+@router.get("/admin/workers", response_model=WorkersResponse)
+async def get_workers(
+    stale: bool = Query(False, description="Only list the stale workers"),
+    es_client: ElasticsearchClient = Depends(get_elasticsearch_client),
+    current_user: str = Depends(get_current_user),
+):
+    """
+    Consumer processes and their latest heartbeat, with those presumed crashed
+    flagged stale.
+    """
+    logger.info("Fetching worker heartbeats")
+    try:
+        workers = await es_client.get_workers(settings.WORKER_STALE_AFTER_SECS)
+        if stale:
+            workers = [worker for worker in workers if worker["stale"]]
+        return WorkersResponse(
+            workers=workers, stale=sum(1 for worker in workers if worker["stale"])
+        )
+    except Exception as e:
+        logger.error(f"Failed to fetch workers: {str(e)}")
+        raise HTTPException(
+            status_code=500, detail=f"Failed to fetch workers: {str(e)}"
+        )
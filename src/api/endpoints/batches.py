@@ -7,11 +7,11 @@ from fastapi import (
     Query,
 )
 from fastapi.responses import StreamingResponse
-from pydantic import BaseModel, field_validator
+from pydantic import BaseModel, ConfigDict, Field, field_validator, model_validator
 from schemas.batch import Batch
 from schemas.task import Task
 from schemas.task_status import TaskStatus
-from typing import Any, Dict, List, Optional
+from typing import Any, Dict, List, Literal, Optional, Union
 from services.message_queue import RabbitMQHandler
 from services.storage import StorageHandler
 import uuid
@@ -68,18 +68,92 @@ class BatchTasksResponse(BaseModel):
     tasks: List[Task]
 
 
+class ProviderEndpoint(BaseModel):
+    url: str
+    api_key: Optional[str] = None
+    name: Optional[str] = None
+    # Model name at this provider, replacing the body's model
+    model: Optional[str] = None
+    # Legacy /v1/completions endpoint of this provider, for prompt-style bodies
+    completions_url: Optional[str] = None
+    # API the endpoint speaks
+    provider: Literal["openai_compatible", "anthropic"] = "openai_compatible"
+
+
+class EvaluationSpec(BaseModel):
+    method: Literal["exact_match", "regex", "numeric", "judge"]
+    # Expected answer, or a list of accepted ones
+    reference: Union[str, float, List[Union[str, float]]]
+    # Regular expression whose first group is the answer within the completion
+    answer_pattern: Optional[str] = None
+    case_sensitive: bool = False
+    tolerance: float = 1e-6
+    # Eval suite whose leaderboard the task counts on, per model
+    suite: Optional[str] = None
+
+
+class Attribution(BaseModel):
+    """Organization, project and user a task is run for."""
+
+    model_config = ConfigDict(extra="forbid")
+
+    org: Optional[str] = Field(default=None, min_length=1)
+    project: Optional[str] = Field(default=None, min_length=1)
+    user: Optional[str] = Field(default=None, min_length=1)
+
+    @model_validator(mode="after")
+    def check_org(self):
+        if self.org is None and (self.project is not None or self.user is not None):
+            raise ValueError("org is required with a project or a user")
+        return self
+
+
 class TaskSubmission(BaseModel):
     custom_id: str
     method: str
     url: str
     api_key: str
-    body: dict
+    body: Optional[dict] = None
+    # Rendered into the body by the consumer: a template name or an inline template
+    template: Optional[Union[str, Dict[str, Any]]] = None
+    variables: Optional[Dict[str, Any]] = None
     dataset: Optional[str] = None
     source: Optional[Dict[str, Any]] = None
+    # Higher is consumed first, up to TASK_QUEUE_MAX_PRIORITY
+    priority: Optional[int] = None
+    # Tried in order instead of url and api_key, each after the previous one failed
+    providers: Optional[List[ProviderEndpoint]] = None
+    # Deadline of the whole run; retries that can't finish before it are abandoned
+    sla_deadline: Optional[datetime.datetime] = None
+    # Payload contract checked by the consumer, see GET /api/v1/schemas
+    task_type: Optional[str] = None
+    # Told with a signed POST when the task completes or permanently fails
+    callback_url: Optional[str] = None
+    # API of url: "anthropic" for the Messages API, else OpenAI-compatible
+    provider: Literal["openai_compatible", "anthropic"] = "openai_compatible"
+    # Reference answer the consumer scores the completion against
+    evaluation: Optional[EvaluationSpec] = None
+    # "embedding" sends body (model, input) to the embeddings endpoint and stores the
+    # vectors instead of generating a completion; "conversation" generates a dialogue
+    # following the conversation plan
+    kind: Literal["completion", "embedding", "conversation"] = "completion"
+    # Fixed user turns, or a follow_up template and max_turns, of a conversation task
+    conversation: Optional[Dict[str, Any]] = None
+    # Organization, project and user the task is run for; sent next to the payload
+    # and recorded on the task's event
+    attribution: Optional[Attribution] = None
+
+    @model_validator(mode="after")
+    def check_body_or_template(self):
+        if self.body is None and self.template is None:
+            raise ValueError("Either body or template is required")
+        return self
 
 
 class TaskListSubmission(BaseModel):
     tasks: List[TaskSubmission]
+    # Told with a signed POST once every task of the batch completed or failed
+    callback_url: Optional[str] = None
 
 
 class TimeSeriesDataPoint(BaseModel):
@@ -117,6 +191,37 @@ class UsageStatsResponse(BaseModel):
     summary: StatsSummary
 
 
+class EfficiencyReport(BaseModel):
+    batch_id: str
+    total_tasks: int
+    completed_tasks: int
+    failed_tasks: int
+    accepted_examples: int
+    acceptance_rate: float
+    rejections: Dict[str, int]
+    total_cost: float
+    wasted_cost: float
+    cost_per_completed: Optional[float] = None
+    cost_per_accepted: Optional[float] = None
+    tokens_per_accepted: Optional[float] = None
+
+
+class MethodAccuracy(BaseModel):
+    scored_tasks: int
+    correct_tasks: int
+    accuracy: float
+
+
+class EvaluationReport(BaseModel):
+    batch_id: str
+    evaluated_tasks: int
+    scored_tasks: int
+    unscored_tasks: int
+    correct_tasks: int
+    accuracy: float
+    methods: Dict[str, MethodAccuracy]
+
+
 class CalendarInterval(str, Enum):
     """
     Valid Elasticsearch calendar intervals as described in the documentation.
@@ -233,6 +338,9 @@ async def get_batch(
             total_tokens=batch_stats["total_tokens"],
             prompt_tokens=batch_stats["prompt_tokens"],
             completion_tokens=batch_stats["completion_tokens"],
+            total_cost=batch_stats["total_cost"],
+            total_energy_wh=batch_stats["total_energy_wh"],
+            total_co2_grams=batch_stats["total_co2_grams"],
         )
 
         logger.info(f"Successfully retrieved status for batch {batch_id}")
@@ -252,6 +360,7 @@ async def get_batch(
 async def submit_bulk_tasks(
     file: UploadFile = File(...),
     batch_id: Optional[str] = Query(default=None),
+    callback_url: Optional[str] = Query(default=None),
     current_user: str = Depends(get_current_user),
 ):
     logger.info(f"Received bulk task submission: {file.filename}")
@@ -283,6 +392,7 @@ async def submit_bulk_tasks(
             "object_name": object_name,
             "upload_timestamp": timestamp,
             "bucket_name": settings.MINIO_BUCKET_NAME,
+            "callback_url": callback_url,
         }
         await rabbitmq_handler.publish_message(message, "data_generation_batch")
         logger.info(f"Sent metadata message to RabbitMQ for batch {batch_id}")
@@ -303,12 +413,15 @@ async def submit_bulk_tasks(
 )
 @router.get("/batches", response_model=BatchListResponse)
 async def list_batches(
+    org: Optional[str] = Query(None, description="Only count tasks of this organization"),
+    project: Optional[str] = Query(None, description="Only count tasks of this project"),
+    user: Optional[str] = Query(None, description="Only count tasks of this user"),
     es_client: ElasticsearchClient = Depends(get_elasticsearch_client),
     current_user: str = Depends(get_current_user),
 ):
     logger.info("Listing batches")
     try:
-        result = await es_client.list_batches()
+        result = await es_client.list_batches(org=org, project=project, user=user)
 
         batches = [Batch(**batch_data) for batch_data in result["batches"]]
 
@@ -333,6 +446,9 @@ async def list_batches(
 async def get_batch_tasks(
     batch_id: str,
     task_status: Optional[TaskStatus] = None,
+    org: Optional[str] = None,
+    project: Optional[str] = None,
+    user: Optional[str] = None,
     es_client: ElasticsearchClient = Depends(get_elasticsearch_client),
     current_user: str = Depends(get_current_user),
 ):
@@ -345,7 +461,9 @@ async def get_batch_tasks(
     logger.info(f"Streaming tasks for batch {batch_id}")
 
     async def task_streamer():
-        async for chunk in es_client.get_batch_tasks(batch_id, task_status):
+        async for chunk in es_client.get_batch_tasks(
+            batch_id, task_status, org=org, project=project, user=user
+        ):
             # Each yielded chunk is a dict containing {"tasks": [...], "total": ...}
             yield json.dumps(chunk) + "\n"
 
@@ -362,6 +480,9 @@ async def get_batch_tasks_with_pagination(
     task_status: TaskStatus = Query(TaskStatus.COMPLETED),
     page: int = Query(1, ge=1),
     page_size: int = Query(100, ge=1, le=10000),
+    org: Optional[str] = Query(None),
+    project: Optional[str] = Query(None),
+    user: Optional[str] = Query(None),
     es_client: ElasticsearchClient = Depends(get_elasticsearch_client),
     current_user: str = Depends(get_current_user),
 ):
@@ -372,6 +493,9 @@ async def get_batch_tasks_with_pagination(
             task_status=task_status,
             page=page,
             page_size=page_size,
+            org=org,
+            project=project,
+            user=user,
         )
         return tasks
     except Exception as e:
@@ -478,3 +602,69 @@ async def get_batch_usage_stats(
         raise HTTPException(
             status_code=500, detail=f"Failed to fetch usage statistics: {str(e)}"
         )
+
+
+@retry(
+    stop=stop_after_attempt(settings.RETRY_ATTEMPTS),
+    wait=wait_exponential(multiplier=1, min=4, max=10),
+    reraise=True,
+)
+@router.get("/batches/{batch_id}/efficiency", response_model=EfficiencyReport)
+async def get_batch_efficiency(
+    batch_id: str,
+    es_client: ElasticsearchClient = Depends(get_elasticsearch_client),
+    current_user: str = Depends(get_current_user),
+):
+    """
+    Cost of a batch against the examples it produced that passed the quality gates,
+    to compare generation strategies by cost per accepted example.
+    """
+    logger.info(f"Fetching efficiency report for batch {batch_id}")
+    try:
+        report = await es_client.get_batch_efficiency(batch_id)
+        if not report:
+            raise HTTPException(
+                status_code=404,
+                detail=f"No tasks found for batch {batch_id}",
+            )
+        return EfficiencyReport(**report)
+    except HTTPException:
+        raise
+    except Exception as e:
+        logger.error(f"Failed to fetch efficiency report for batch {batch_id}: {str(e)}")
+        raise HTTPException(
+            status_code=500, detail=f"Failed to fetch efficiency report: {str(e)}"
+        )
+
+
+@retry(
+    stop=stop_after_attempt(settings.RETRY_ATTEMPTS),
+    wait=wait_exponential(multiplier=1, min=4, max=10),
+    reraise=True,
+)
+@router.get("/batches/{batch_id}/evaluation", response_model=EvaluationReport)
+async def get_batch_evaluation(
+    batch_id: str,
+    es_client: ElasticsearchClient = Depends(get_elasticsearch_client),
+    current_user: str = Depends(get_current_user),
+):
+    """
+    Accuracy of a batch whose tasks carry reference answers, so the batch can be
+    used as an evaluation run.
+    """
+    logger.info(f"Fetching evaluation report for batch {batch_id}")
+    try:
+        report = await es_client.get_batch_evaluation(batch_id)
+        if not report:
+            raise HTTPException(
+                status_code=404,
+                detail=f"No evaluated tasks found for batch {batch_id}",
+            )
+        return EvaluationReport(**report)
+    except HTTPException:
+        raise
+    except Exception as e:
+        logger.error(f"Failed to fetch evaluation report for batch {batch_id}: {str(e)}")
+        raise HTTPException(
+            status_code=500, detail=f"Failed to fetch evaluation report: {str(e)}"
+        )
@@ -1,6 +1,6 @@
 from fastapi import APIRouter, HTTPException, Depends
 from pydantic import BaseModel
-from typing import List
+from typing import Any, Dict, List, Optional
 from schemas.task import Task
 from tenacity import retry, stop_after_attempt, wait_exponential
 from core.config import settings
@@ -30,6 +30,84 @@ class TaskStatsResponse(BaseModel):
     completion_tokens: int
 
 
+class TimelineEntry(BaseModel):
+    at: Optional[str]
+    event: str
+    detail: Dict[str, Any] = {}
+
+
+class TaskDebugResponse(BaseModel):
+    message_id: str
+    batch_id: Optional[str]
+    status: str
+    # Recorded by the consumer when the task was traced (DEBUG_TRACE, or "debug": true)
+    traced: bool
+    message: Optional[Dict[str, Any]]
+    settings: Optional[Dict[str, Any]]
+    attempts: List[Dict[str, Any]]
+    validation: Dict[str, Any]
+    timeline: List[TimelineEntry]
+    persistence: Dict[str, Any]
+
+
+VALIDATION_FIELDS = [
+    "schema_valid",
+    "validation_errors",
+    "repair_attempts",
+    "empty_completion_retries",
+    "retry_adjustments",
+    "accepted",
+    "rejection_reasons",
+    "failure_reason",
+]
+
+# Left out of the persistence record: the trace is returned on its own and the
+# embedding is only noise here
+OMITTED_FIELDS = {"debug_trace", "completion_embedding"}
+
+
+def build_timeline(event: Dict[str, Any], trace: Dict[str, Any]) -> List[TimelineEntry]:
+    """Steps of the task in the order they happened."""
+    timeline = [TimelineEntry(at=event.get("created_at"), event="submitted")]
+    message = trace.get("message") or {}
+    if message.get("received_at"):
+        timeline.append(
+            TimelineEntry(
+                at=message["received_at"],
+                event="received",
+                detail={"delivery_attempt": message.get("delivery_attempt")},
+            )
+        )
+    elif event.get("started_at"):
+        timeline.append(TimelineEntry(at=event["started_at"], event="received"))
+    for attempt in trace.get("attempts", []):
+        timeline.append(
+            TimelineEntry(
+                at=attempt.get("started_at"),
+                event="llm_attempt",
+                detail={
+                    key: attempt[key]
+                    for key in ["url", "attempt", "outcome", "duration_ms", "error"]
+                    if key in attempt
+                },
+            )
+        )
+    if event.get("status") in ("COMPLETED", "FAILED"):
+        detail = {"cached": event.get("cached", False)}
+        if event.get("copied_from"):
+            detail["copied_from"] = event["copied_from"]
+        if event.get("failure_reason"):
+            detail["failure_reason"] = event["failure_reason"]
+        timeline.append(
+            TimelineEntry(
+                at=event.get("completed_at"),
+                event=event["status"].lower(),
+                detail=detail,
+            )
+        )
+    return timeline
+
+
 @retry(
     stop=stop_after_attempt(settings.RETRY_ATTEMPTS),
     wait=wait_exponential(multiplier=1, min=4, max=10),
@@ -96,6 +174,7 @@ async def get_task(
             duration=event.get("duration"),
             dataset=event.get("dataset"),
             source=event.get("source"),
+            attribution=event.get("attribution"),
         )
     except HTTPException:
         raise
@@ -105,6 +184,54 @@ async def get_task(
         )
 
 
+@retry(
+    stop=stop_after_attempt(settings.RETRY_ATTEMPTS),
+    wait=wait_exponential(multiplier=1, min=4, max=10),
+    reraise=True,
+)
+@router.get("/tasks/{message_id}/debug", response_model=TaskDebugResponse)
+async def get_task_debug(
+    message_id: str,
+    current_user: str = Depends(get_current_user),
+    es_client: ElasticsearchClient = Depends(get_elasticsearch_client),
+):
+    """Reconstructed timeline of a single task: the message as received, the settings
+    it was processed with, every LLM attempt (redacted), validation results and the
+    final event."""
+    try:
+        event = await es_client.get_task_by_message_id(message_id)
+        if not event:
+            raise HTTPException(
+                status_code=404, detail=f"Task with message_id {message_id} not found"
+            )
+
+        trace = event.get("debug_trace") or {}
+        return TaskDebugResponse(
+            message_id=event["message_id"],
+            batch_id=event.get("batch_id"),
+            status=event["status"],
+            traced=bool(trace),
+            message=trace.get("message"),
+            settings=trace.get("settings"),
+            attempts=trace.get("attempts", []),
+            validation={
+                field: event[field] for field in VALIDATION_FIELDS if field in event
+            },
+            timeline=build_timeline(event, trace),
+            persistence={
+                key: value
+                for key, value in event.items()
+                if key not in OMITTED_FIELDS
+            },
+        )
+    except HTTPException:
+        raise
+    except Exception as e:
+        raise HTTPException(
+            status_code=500, detail=f"Failed to fetch task debug info: {str(e)}"
+        )
+
+
 @retry(
     stop=stop_after_attempt(settings.RETRY_ATTEMPTS),
     wait=wait_exponential(multiplier=1, min=4, max=10),
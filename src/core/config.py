@@ -16,6 +16,9 @@ class Settings:
     RABBITMQ_PASS: str = os.getenv("RABBITMQ_PASS", "guest")
     RABBITMQ_HOST: str = os.getenv("RABBITMQ_HOST", "localhost")
     RABBITMQ_PORT: int = int(os.getenv("RABBITMQ_PORT", 5672))
+    # Highest task priority; 0, the default, declares the task queue without priorities.
+    # An existing task queue has to be deleted (once drained) before turning them on.
+    TASK_QUEUE_MAX_PRIORITY: int = int(os.getenv("TASK_QUEUE_MAX_PRIORITY", 0))
 
     # Postgres Database Settings
     POSTGRES_USER: str = os.getenv("POSTGRES_USER", "postgres")
@@ -36,6 +39,10 @@ class Settings:
     # Chunk size for bulk inserts
     CHUNK_SIZE: int = int(os.getenv("CHUNK_SIZE", 1000))
 
+    # Largest task body accepted, as compact JSON; 0 accepts any. The consumer applies
+    # the same limit to the bodies it renders from templates.
+    MAX_REQUEST_BODY_BYTES: int = int(os.getenv("MAX_REQUEST_BODY_BYTES", 0))
+
     # MINIO Settings
     MINIO_HOST: str = os.getenv("MINIO_HOST", "localhost")
     MINIO_PORT: int = int(os.getenv("MINIO_PORT", 9000))
@@ -50,6 +57,9 @@ class Settings:
     ELASTICSEARCH_USER: str = os.getenv("ELASTICSEARCH_USER", "elastic")
     ELASTICSEARCH_PASSWORD: str = os.getenv("ELASTICSEARCH_PASSWORD", "changeme")
 
+    # Silence after which a running consumer is listed as stale
+    WORKER_STALE_AFTER_SECS: int = int(os.getenv("WORKER_STALE_AFTER_SECS", 60))
+
     # API Secret Key
     API_SECRET_KEY: str = os.getenv("API_SECRET_KEY")
 
@@ -3,11 +3,24 @@ from core.config import settings
 import logging
 from schemas.task_status import TaskStatus
 import datetime
-from typing import Dict, Any
+from typing import Dict, Any, List, Optional
 
 logger = logging.getLogger(__name__)
 
 
+def attribution_filters(
+    org: Optional[str] = None,
+    project: Optional[str] = None,
+    user: Optional[str] = None,
+) -> List[Dict[str, Any]]:
+    """Term filters on the attribution of events."""
+    return [
+        {"term": {f"attribution.{field}": value}}
+        for field, value in (("org", org), ("project", project), ("user", user))
+        if value is not None
+    ]
+
+
 class ElasticsearchClient:
     _instance = None
 
@@ -52,14 +65,164 @@ class ElasticsearchClient:
                             "duration": {"type": "integer"},
                             "cached": {"type": "boolean"},
                             "attempt": {"type": "integer"},
+                            "cancelled": {"type": "boolean"},
                             "dataset": {"type": "keyword"},
                             "source": {"type": "object"},
                             "completions": {"type": "object"},
+                            "difficulty": {"type": "keyword"},
+                            "difficulty_score": {"type": "float"},
+                            "difficulty_method": {"type": "keyword"},
+                            "topic_labels": {"type": "keyword"},
+                            "language": {"type": "keyword"},
+                            "language_confidence": {"type": "float"},
+                            "local_quality_score": {"type": "float"},
+                            "quality_score": {"type": "float"},
+                            "judge_feedback": {"type": "text"},
+                            "judge_model": {"type": "keyword"},
+                            "judge_error": {"type": "text"},
+                            "balance_label": {"type": "keyword"},
+                            "balance_excess": {"type": "boolean"},
+                            "contaminated": {"type": "boolean"},
+                            "contamination_score": {"type": "float"},
+                            "contamination_source": {"type": "keyword"},
+                            "completion_hash": {"type": "keyword"},
+                            "tags": {"type": "keyword"},
+                            "corpus_duplicate": {"type": "boolean"},
+                            "diversity_score": {"type": "float"},
+                            "diversity_max_similarity": {"type": "float"},
+                            "diversity_group_size": {"type": "integer"},
+                            "diversity_regenerations": {"type": "integer"},
+                            "near_duplicate": {"type": "boolean"},
+                            "dedup_cluster": {"type": "keyword"},
+                            "batch_duplicate": {"type": "boolean"},
+                            "batch_duplicate_similarity": {"type": "float"},
+                            "batch_dedup_regenerations": {"type": "integer"},
+                            "provider_batch_id": {"type": "keyword"},
+                            "prompt_tokens": {"type": "long"},
+                            "completion_tokens": {"type": "long"},
+                            "total_tokens": {"type": "long"},
+                            "cost": {"type": "double"},
+                            "energy_wh": {"type": "double"},
+                            "co2_grams": {"type": "double"},
+                            "raw_completion": {"type": "text", "index": False},
+                            "attribution": {
+                                "properties": {
+                                    "org": {"type": "keyword"},
+                                    "project": {"type": "keyword"},
+                                    "user": {"type": "keyword"},
+                                }
+                            },
+                            "checkpoint": {"type": "object", "enabled": False},
+                            "task_message": {"type": "object", "enabled": False},
+                            "transcript": {"type": "object", "enabled": False},
+                            "conversation_turns": {"type": "integer"},
+                            "completion_truncated": {
+                                "properties": {
+                                    "original_bytes": {"type": "long"},
+                                    "max_bytes": {"type": "long"},
+                                    "archive_url": {"type": "keyword", "index": False},
+                                }
+                            },
+                            "moderation": {
+                                "properties": {
+                                    "verdict": {"type": "keyword"},
+                                    "redactions": {"type": "object"},
+                                    "blocked_terms": {"type": "keyword"},
+                                    "categories": {"type": "keyword"},
+                                    "error": {"type": "text", "index": False},
+                                }
+                            },
+                            "evaluation": {
+                                "properties": {
+                                    "method": {"type": "keyword"},
+                                    "answer": {"type": "text", "index": False},
+                                    "correct": {"type": "boolean"},
+                                    "score": {"type": "double"},
+                                    "error": {"type": "text", "index": False},
+                                    "suite": {"type": "keyword"},
+                                    "model": {"type": "keyword"},
+                                }
+                            },
+                            "schema_valid": {"type": "boolean"},
+                            "validation_errors": {"type": "text"},
+                            "repair_attempts": {"type": "integer"},
+                            "empty_completion_retries": {"type": "integer"},
+                            "retry_adjustments": {"type": "object"},
+                            "accepted": {"type": "boolean"},
+                            "rejection_reasons": {"type": "keyword"},
+                            "failure_reason": {"type": "keyword"},
+                            "provider": {"type": "keyword"},
+                            "copied_from": {"type": "keyword"},
+                            "copied_from_batch_id": {"type": "keyword"},
+                            "debug_trace": {"type": "object", "enabled": False},
+                            "completion_embedding": {
+                                "type": "dense_vector",
+                                "index": True,
+                                "similarity": "cosine",
+                            },
+                            # Vectors of embedding tasks, unless written to
+                            # EMBEDDING_TASK_INDEX
+                            "embedding": {
+                                "type": "dense_vector",
+                                "index": True,
+                                "similarity": "cosine",
+                            },
+                            "embeddings": {"type": "object", "enabled": False},
+                            "embedding_model": {"type": "keyword"},
+                            "embedding_dimensions": {"type": "integer"},
+                            "embedding_count": {"type": "integer"},
+                            "embedding_index": {"type": "keyword"},
                         }
                     },
                 }
                 await self.client.indices.create(index=index_name, body=mapping)
                 logger.info(f"Created index {index_name}")
+            # Task counts per batch, maintained by the consumer as tasks change status
+            if not await self.client.indices.exists(index="batches"):
+                mapping = {
+                    "settings": {
+                        "number_of_replicas": 0,
+                        "number_of_shards": 1,
+                    },
+                    "mappings": {
+                        "properties": {
+                            "batch_id": {"type": "keyword"},
+                            "total": {"type": "long"},
+                            "pending": {"type": "long"},
+                            "processing": {"type": "long"},
+                            "completed": {"type": "long"},
+                            "failed": {"type": "long"},
+                            "updated_at": {"type": "date"},
+                            "finished_at": {"type": "date"},
+                            "callback_url": {"type": "keyword", "index": False},
+                        }
+                    },
+                }
+                await self.client.indices.create(index="batches", body=mapping)
+                logger.info("Created index batches")
+            # Accuracy per model on each eval suite, maintained by the consumer as
+            # scored tasks finish
+            if not await self.client.indices.exists(index="leaderboard"):
+                mapping = {
+                    "settings": {
+                        "number_of_replicas": 0,
+                        "number_of_shards": 1,
+                    },
+                    "mappings": {
+                        "properties": {
+                            "suite": {"type": "keyword"},
+                            "model": {"type": "keyword"},
+                            "scored_tasks": {"type": "long"},
+                            "correct_tasks": {"type": "long"},
+                            "score_sum": {"type": "double"},
+                            "accuracy": {"type": "double"},
+                            "mean_score": {"type": "double"},
+                            "updated_at": {"type": "date"},
+                        }
+                    },
+                }
+                await self.client.indices.create(index="leaderboard", body=mapping)
+                logger.info("Created index leaderboard")
         except Exception as e:
             logger.error(f"Error creating Elasticsearch index: {str(e)}")
             raise
@@ -93,6 +256,14 @@ class ElasticsearchClient:
                         }
                     },
                 },
+                "cost_stats": {
+                    "filter": {"term": {"cached": False}},
+                    "aggs": {
+                        "cost": {"sum": {"field": "cost"}},
+                        "energy_wh": {"sum": {"field": "energy_wh"}},
+                        "co2_grams": {"sum": {"field": "co2_grams"}},
+                    },
+                },
                 "status_counts": {"terms": {"field": "status"}},
                 "cached_count": {"filter": {"term": {"cached": True}}},
                 "time_stats": {"min": {"field": "created_at"}},
@@ -109,10 +280,17 @@ class ElasticsearchClient:
 
         return self._process_batch_stats(result, batch_id)
 
-    async def list_batches(self) -> Dict[str, Any]:
-        """Get paginated list of all batches with their statistics."""
+    async def list_batches(
+        self,
+        org: Optional[str] = None,
+        project: Optional[str] = None,
+        user: Optional[str] = None,
+    ) -> Dict[str, Any]:
+        """Get paginated list of all batches with their statistics, optionally only
+        of the tasks of an organization, project or user."""
         query = {
             "size": 0,
+            "query": {"bool": {"filter": attribution_filters(org, project, user)}},
             "aggs": {
                 "unique_batches": {
                     "terms": {
@@ -150,6 +328,14 @@ class ElasticsearchClient:
                                 }
                             },
                         },
+                        "cost_stats": {
+                            "filter": {"term": {"cached": False}},
+                            "aggs": {
+                                "cost": {"sum": {"field": "cost"}},
+                                "energy_wh": {"sum": {"field": "energy_wh"}},
+                                "co2_grams": {"sum": {"field": "co2_grams"}},
+                            },
+                        },
                         "status_counts": {"terms": {"field": "status"}},
                         "cached_count": {"filter": {"term": {"cached": True}}},
                         "time_stats": {"min": {"field": "created_at"}},
@@ -163,6 +349,35 @@ class ElasticsearchClient:
         result = await self.client.search(index="events", body=query)
         return self._process_batch_list(result)
 
+    async def _resolve_completions(self, hits: list) -> None:
+        """
+        Fill in the completions of the events referencing content stored by hash in
+        the completions index (CONTENT_ADDRESSED_COMPLETIONS): the model and choices
+        stored once, with the rest of the response kept on the event.
+        """
+        refs = {
+            hit["_source"]["completions_ref"]
+            for hit in hits
+            if hit["_source"].get("completions_ref")
+        }
+        if not refs:
+            return
+        result = await self.client.search(
+            index="completions",
+            body={"query": {"ids": {"values": list(refs)}}, "size": len(refs)},
+        )
+        stored = {
+            stored_hit["_id"]: stored_hit["_source"]["completions"]
+            for stored_hit in result["hits"]["hits"]
+        }
+        for hit in hits:
+            source = hit["_source"]
+            content = stored.get(source.get("completions_ref"))
+            if isinstance(content, dict):
+                source["completions"] = {**content, **(source.get("completions") or {})}
+            elif content is not None:
+                source["completions"] = content
+
     def _process_hits(self, hits: list) -> list:
         """Helper method to process Elasticsearch hits into task dicts."""
         tasks = []
@@ -183,11 +398,19 @@ class ElasticsearchClient:
                     "completions": source.get("completions", {}),
                     "dataset": source.get("dataset"),
                     "source": source.get("source"),
+                    "attribution": source.get("attribution"),
                 }
             )
         return tasks
 
-    async def get_batch_tasks(self, batch_id: str, task_status: TaskStatus = None):
+    async def get_batch_tasks(
+        self,
+        batch_id: str,
+        task_status: TaskStatus = None,
+        org: Optional[str] = None,
+        project: Optional[str] = None,
+        user: Optional[str] = None,
+    ):
         """
         Stream tasks for a specific batch using the scroll API.
         Yields each chunk (a dict containing a list of tasks and total count) as soon as it is received.
@@ -195,6 +418,7 @@ class ElasticsearchClient:
         conditions = [{"term": {"batch_id": batch_id}}]
         if task_status:
             conditions.append({"term": {"status": task_status.value}})
+        conditions.extend(attribution_filters(org, project, user))
 
         query = {
             "query": {
@@ -219,6 +443,7 @@ class ElasticsearchClient:
         scroll_id = result.get("_scroll_id")
 
         # Process and yield the first chunk of tasks
+        await self._resolve_completions(result["hits"]["hits"])
         tasks_chunk = self._process_hits(result["hits"]["hits"])
         yield {"tasks": tasks_chunk, "total": total_documents}
 
@@ -230,6 +455,7 @@ class ElasticsearchClient:
                 hits = result["hits"]["hits"]
                 if not hits:
                     break
+                await self._resolve_completions(hits)
                 tasks_chunk = self._process_hits(hits)
                 yield {"tasks": tasks_chunk, "total": total_documents}
         finally:
@@ -245,6 +471,9 @@ class ElasticsearchClient:
         task_status: TaskStatus,
         page: int = 1,
         page_size: int = 100,
+        org: Optional[str] = None,
+        project: Optional[str] = None,
+        user: Optional[str] = None,
     ):
         """
         Export tasks for a specific batch. if task_status is not provided, all tasks are exported.
@@ -261,6 +490,7 @@ class ElasticsearchClient:
                             if task_status
                             else None
                         ),
+                        *attribution_filters(org, project, user),
                     ]
                 }
             },
@@ -275,8 +505,9 @@ class ElasticsearchClient:
 
         total_documents = result["hits"]["total"]["value"]
 
-        tasks_chunk = self._process_hits(result["hits"]["hits"])
-        tasks_chunk = tasks_chunk[page_size * (page - 1) : page_size * page]
+        hits = result["hits"]["hits"][page_size * (page - 1) : page_size * page]
+        await self._resolve_completions(hits)
+        tasks_chunk = self._process_hits(hits)
         return {
             "total": total_documents,
             "page": page,
@@ -341,6 +572,9 @@ class ElasticsearchClient:
             "total_tokens": aggs["batch_stats"]["stats"]["sum"] or 0,
             "prompt_tokens": aggs["prompt_stats"]["stats"]["sum"] or 0,
             "completion_tokens": aggs["completion_stats"]["stats"]["sum"] or 0,
+            "total_cost": aggs["cost_stats"]["cost"]["value"] or 0,
+            "total_energy_wh": aggs["cost_stats"]["energy_wh"]["value"] or 0,
+            "total_co2_grams": aggs["cost_stats"]["co2_grams"]["value"] or 0,
         }
 
     def _process_batch_list(self, result: Dict[str, Any]) -> Dict[str, Any]:
@@ -416,6 +650,11 @@ class ElasticsearchClient:
                     "prompt_tokens": bucket["prompt_stats"]["tokens"]["sum"] or 0,
                     "completion_tokens": bucket["completion_stats"]["tokens"]["sum"]
                     or 0,
+                    "total_cost": bucket["cost_stats"]["cost"]["value"] or 0,
+                    "total_energy_wh": bucket["cost_stats"]["energy_wh"]["value"]
+                    or 0,
+                    "total_co2_grams": bucket["cost_stats"]["co2_grams"]["value"]
+                    or 0,
                 }
             )
 
@@ -438,7 +677,9 @@ class ElasticsearchClient:
         )
         if total_hits == 0:
             return None
-        return result["hits"]["hits"][0]["_source"]
+        hit = result["hits"]["hits"][0]
+        await self._resolve_completions([hit])
+        return hit["_source"]
 
     async def count_pending_tasks_before(self, created_at: str) -> int:
         """
@@ -665,6 +906,196 @@ class ElasticsearchClient:
             logger.error(f"Error retrieving usage statistics: {str(e)}")
             raise
 
+    async def get_batch_efficiency(self, batch_id: str) -> Dict[str, Any]:
+        """
+        Money spent by a batch against the examples that passed the consumer's
+        quality gates (schema validation, quality score, contamination, dedup,
+        class balance).
+        """
+        query = {
+            "size": 0,
+            "query": {"term": {"batch_id": batch_id}},
+            "aggs": {
+                "status_counts": {"terms": {"field": "status"}},
+                "total_cost": {"sum": {"field": "cost"}},
+                "total_tokens": {"sum": {"field": "total_tokens"}},
+                "accepted": {
+                    "filter": {"term": {"accepted": True}},
+                    "aggs": {"cost": {"sum": {"field": "cost"}}},
+                },
+                "rejection_reasons": {"terms": {"field": "rejection_reasons"}},
+            },
+        }
+
+        result = await self.client.search(index="events", body=query)
+
+        total_tasks = result["hits"]["total"]["value"]
+        if total_tasks == 0:
+            return None
+
+        aggs = result["aggregations"]
+        status_buckets = {
+            bucket["key"]: bucket["doc_count"]
+            for bucket in aggs["status_counts"]["buckets"]
+        }
+        completed = status_buckets.get("COMPLETED", 0)
+        accepted = aggs["accepted"]["doc_count"]
+        total_cost = aggs["total_cost"]["value"] or 0
+        total_tokens = aggs["total_tokens"]["value"] or 0
+
+        return {
+            "batch_id": batch_id,
+            "total_tasks": total_tasks,
+            "completed_tasks": completed,
+            "failed_tasks": status_buckets.get("FAILED", 0),
+            "accepted_examples": accepted,
+            "acceptance_rate": round(accepted / completed * 100, 2) if completed else 0,
+            "rejections": {
+                bucket["key"]: bucket["doc_count"]
+                for bucket in aggs["rejection_reasons"]["buckets"]
+            },
+            "total_cost": total_cost,
+            # Spent on failed tasks and on completions the gates rejected
+            "wasted_cost": total_cost - (aggs["accepted"]["cost"]["value"] or 0),
+            "cost_per_completed": total_cost / completed if completed else None,
+            "cost_per_accepted": total_cost / accepted if accepted else None,
+            "tokens_per_accepted": total_tokens / accepted if accepted else None,
+        }
+
+    async def get_batch_evaluation(self, batch_id: str) -> Dict[str, Any]:
+        """
+        Accuracy of a batch's completions against the reference answers of its
+        benchmark-style tasks, overall and per evaluation method.
+        """
+        query = {
+            "size": 0,
+            "query": {
+                "bool": {
+                    "filter": [
+                        {"term": {"batch_id": batch_id}},
+                        {"exists": {"field": "evaluation.method"}},
+                    ]
+                }
+            },
+            "aggs": {
+                "correct": {"filter": {"term": {"evaluation.correct": True}}},
+                "scored": {"filter": {"exists": {"field": "evaluation.correct"}}},
+                "methods": {
+                    "terms": {"field": "evaluation.method"},
+                    "aggs": {
+                        "correct": {"filter": {"term": {"evaluation.correct": True}}},
+                        "scored": {
+                            "filter": {"exists": {"field": "evaluation.correct"}}
+                        },
+                    },
+                },
+            },
+        }
+
+        result = await self.client.search(index="events", body=query)
+
+        evaluated = result["hits"]["total"]["value"]
+        if evaluated == 0:
+            return None
+
+        aggs = result["aggregations"]
+
+        def accuracy(correct: int, scored: int) -> float:
+            return round(correct / scored * 100, 2) if scored else 0
+
+        correct = aggs["correct"]["doc_count"]
+        scored = aggs["scored"]["doc_count"]
+        return {
+            "batch_id": batch_id,
+            "evaluated_tasks": evaluated,
+            "scored_tasks": scored,
+            # Tasks whose evaluation failed, e.g. an invalid pattern or judge error
+            "unscored_tasks": evaluated - scored,
+            "correct_tasks": correct,
+            "accuracy": accuracy(correct, scored),
+            "methods": {
+                bucket["key"]: {
+                    "scored_tasks": bucket["scored"]["doc_count"],
+                    "correct_tasks": bucket["correct"]["doc_count"],
+                    "accuracy": accuracy(
+                        bucket["correct"]["doc_count"], bucket["scored"]["doc_count"]
+                    ),
+                }
+                for bucket in aggs["methods"]["buckets"]
+            },
+        }
+
+    async def get_leaderboard(
+        self, suite: Optional[str] = None, min_tasks: int = 1
+    ) -> Dict[str, List[Dict[str, Any]]]:
+        """
+        Models ranked by accuracy on each eval suite, then by mean score and the
+        number of scored tasks.
+        """
+        filters = [{"range": {"scored_tasks": {"gte": min_tasks}}}]
+        if suite:
+            filters.append({"term": {"suite": suite}})
+        query = {
+            "size": 10000,
+            "query": {"bool": {"filter": filters}},
+            "sort": [
+                {"suite": "asc"},
+                {"accuracy": "desc"},
+                {"mean_score": "desc"},
+                {"scored_tasks": "desc"},
+            ],
+        }
+
+        if not await self.client.indices.exists(index="leaderboard"):
+            return {}
+        result = await self.client.search(index="leaderboard", body=query)
+
+        suites: Dict[str, List[Dict[str, Any]]] = {}
+        for hit in result["hits"]["hits"]:
+            entry = hit["_source"]
+            ranking = suites.setdefault(entry["suite"], [])
+            ranking.append(
+                {
+                    "rank": len(ranking) + 1,
+                    "model": entry["model"],
+                    "scored_tasks": entry["scored_tasks"],
+                    "correct_tasks": entry["correct_tasks"],
+                    "accuracy": round(entry["accuracy"], 2),
+                    "mean_score": round(entry["mean_score"], 4),
+                    "updated_at": entry.get("updated_at"),
+                }
+            )
+        return suites
+
+    async def get_workers(self, stale_after_secs: int) -> List[Dict[str, Any]]:
+        """
+        Latest heartbeat of every consumer process, flagged stale when it is still
+        running but hasn't written one for `stale_after_secs`.
+        """
+        if not await self.client.indices.exists(index="workers"):
+            return []
+        result = await self.client.search(
+            index="workers",
+            body={
+                "size": 10000,
+                "sort": [{"worker_id": "asc"}],
+                # Epoch millis rather than the consumer's nanosecond timestamps
+                "fields": [{"field": "last_heartbeat", "format": "epoch_millis"}],
+            },
+        )
+
+        now = datetime.datetime.now(datetime.timezone.utc).timestamp()
+        workers = []
+        for hit in result["hits"]["hits"]:
+            worker = hit["_source"]
+            last_heartbeat = float(hit["fields"]["last_heartbeat"][0]) / 1000
+            worker["stale"] = (
+                worker.get("state") != "stopped"
+                and now - last_heartbeat >= stale_after_secs
+            )
+            workers.append(worker)
+        return workers
+
     async def get_tasks_usage_stats(self) -> Dict[str, Any]:
         """
         Get usage statistics for all tasks.
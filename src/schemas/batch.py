@@ -20,6 +20,10 @@ class Batch(BaseModel):
     total_tokens: int = 0
     prompt_tokens: int = 0
     completion_tokens: int = 0
+    total_cost: float = 0.0
+    # Estimated from the consumer's per-model energy factors
+    total_energy_wh: float = 0.0
+    total_co2_grams: float = 0.0
 
     class Config:
         from_attributes = True
@@ -18,6 +18,7 @@ class Task(BaseModel):
     duration: Optional[int]
     dataset: Optional[str]
     source: Optional[dict]
+    attribution: Optional[dict] = None
 
     class Config:
         from_attributes = True
@@ -1,5 +1,5 @@
 import json
-from typing import Any, List
+from typing import Any, List, Optional
 from dotenv import load_dotenv
 from schemas.task_status import TaskStatus
 from aio_pika import connect_robust, Message, DeliveryMode
@@ -13,6 +13,22 @@ logger = logging.getLogger(__name__)
 load_dotenv()
 
 
+def task_queue_arguments() -> dict[str, Any]:
+    """Arguments of the task queue; the consumer declares it with the same ones."""
+    arguments = {'x-queue-type': 'classic'}
+    if settings.TASK_QUEUE_MAX_PRIORITY > 0:
+        arguments['x-max-priority'] = settings.TASK_QUEUE_MAX_PRIORITY
+    return arguments
+
+
+def task_priority(message: dict[str, Any]) -> Optional[int]:
+    """Priority of a task message from its payload's `priority` field, capped to the queue's."""
+    priority = (message.get("payload") or {}).get("priority")
+    if not isinstance(priority, int) or settings.TASK_QUEUE_MAX_PRIORITY <= 0:
+        return None
+    return max(0, min(priority, settings.TASK_QUEUE_MAX_PRIORITY))
+
+
 class RabbitMQHandler:
     _instance = None
 
@@ -56,9 +72,7 @@ class RabbitMQHandler:
                 name="data_generation_tasks",
                 durable=True,
                 auto_delete=False,
-                arguments={
-                    'x-queue-type': 'classic'
-                }
+                arguments=task_queue_arguments()
             )
             logger.info("Declared data_generation_tasks queue")
             await self.channel.declare_queue(
@@ -100,9 +114,7 @@ class RabbitMQHandler:
             queue="data_generation_tasks",
             durable=True,
             auto_delete=False,
-            arguments={
-                'x-queue-type': 'classic'
-            }
+            arguments=task_queue_arguments()
         )
         logger.info("Declared data_generation_tasks queue")
         channel.queue_declare(
@@ -138,6 +150,7 @@ class RabbitMQHandler:
                     delivery_mode=DeliveryMode.PERSISTENT,
                     message_id=message_data["message_id"],
                     headers={"status": TaskStatus.PENDING.value},
+                    priority=task_priority(message_data),
                 )
                 await self.channel.default_exchange.publish(
                     msg,
@@ -11,19 +11,91 @@ from core.config import settings
 from schemas.task_status import TaskStatus
 from services.message_queue import RabbitMQHandler
 from services.storage import StorageHandler
-from pydantic import BaseModel, ValidationError
-from typing import Any, Dict, Optional
+from pydantic import BaseModel, ConfigDict, Field, ValidationError, model_validator
+from typing import Any, Dict, List, Literal, Optional, Union
 from database.elastic_session import get_elasticsearch_client
 
 
+class ProviderEndpoint(BaseModel):
+    url: str
+    api_key: Optional[str] = None
+    name: Optional[str] = None
+    # Model name at this provider, replacing the body's model
+    model: Optional[str] = None
+    # Legacy /v1/completions endpoint of this provider, for prompt-style bodies
+    completions_url: Optional[str] = None
+    # API the endpoint speaks
+    provider: Literal["openai_compatible", "anthropic"] = "openai_compatible"
+
+
+class EvaluationSpec(BaseModel):
+    method: Literal["exact_match", "regex", "numeric", "judge"]
+    # Expected answer, or a list of accepted ones
+    reference: Union[str, float, List[Union[str, float]]]
+    # Regular expression whose first group is the answer within the completion
+    answer_pattern: Optional[str] = None
+    case_sensitive: bool = False
+    tolerance: float = 1e-6
+    # Eval suite whose leaderboard the task counts on, per model
+    suite: Optional[str] = None
+
+
+class Attribution(BaseModel):
+    """Organization, project and user a task is run for."""
+
+    model_config = ConfigDict(extra="forbid")
+
+    org: Optional[str] = Field(default=None, min_length=1)
+    project: Optional[str] = Field(default=None, min_length=1)
+    user: Optional[str] = Field(default=None, min_length=1)
+
+    @model_validator(mode="after")
+    def check_org(self):
+        if self.org is None and (self.project is not None or self.user is not None):
+            raise ValueError("org is required with a project or a user")
+        return self
+
+
 class TaskSubmission(BaseModel):
     custom_id: Optional[str] = None
     method: str
     url: str
     api_key: Optional[str] = None
-    body: dict
+    body: Optional[dict] = None
+    # Rendered into the body by the consumer: a template name or an inline template
+    template: Optional[Union[str, Dict[str, Any]]] = None
+    variables: Optional[Dict[str, Any]] = None
     dataset: Optional[str] = None
     source: Optional[Dict[str, Any]] = None
+    # Higher is consumed first, up to TASK_QUEUE_MAX_PRIORITY
+    priority: Optional[int] = None
+    # Tried in order instead of url and api_key, each after the previous one failed
+    providers: Optional[List[ProviderEndpoint]] = None
+    # Deadline of the whole run; retries that can't finish before it are abandoned
+    sla_deadline: Optional[datetime.datetime] = None
+    # Payload contract checked by the consumer, see GET /api/v1/schemas
+    task_type: Optional[str] = None
+    # Told with a signed POST when the task completes or permanently fails
+    callback_url: Optional[str] = None
+    # API of url: "anthropic" for the Messages API, else OpenAI-compatible
+    provider: Literal["openai_compatible", "anthropic"] = "openai_compatible"
+    # Reference answer the consumer scores the completion against
+    evaluation: Optional[EvaluationSpec] = None
+    # "embedding" sends body (model, input) to the embeddings endpoint and stores the
+    # vectors instead of generating a completion; "conversation" generates a dialogue
+    # following the conversation plan
+    kind: Literal["completion", "embedding", "conversation"] = "completion"
+    # Fixed user turns, or a follow_up template and max_turns, of a conversation task
+    conversation: Optional[Dict[str, Any]] = None
+    # Organization, project and user the task is run for; sent next to the payload
+    # and recorded on the task's event
+    attribution: Optional[Attribution] = None
+
+    @model_validator(mode="after")
+    def check_body_or_template(self):
+        if self.body is None and self.template is None:
+            raise ValueError("Either body or template is required")
+        return self
 
 
 class MetadataMessage(BaseModel):
@@ -31,6 +103,7 @@ class MetadataMessage(BaseModel):
     object_name: str
     upload_timestamp: str
     bucket_name: str
+    callback_url: Optional[str] = None
 
 
 class Worker:
@@ -72,17 +145,75 @@ class Worker:
                 "body": doc["body"],
                 "dataset": doc["dataset"],
                 "source": doc["source"],
-                "attempt": 0
+                "attempt": 0,
+                "attribution": doc.get("attribution"),
             })
 
-        if bulk_data:
-            await self.es_client.client.bulk(operations=bulk_data, refresh=True)
+        if not bulk_data:
+            return
+        response = await self.es_client.client.bulk(operations=bulk_data, refresh=True)
+
+        # Re-indexed events of a retried chunk are already counted
+        created = sum(
+            1 for item in response["items"] if item["index"].get("result") == "created"
+        )
+        if created:
+            try:
+                await self.count_batch_tasks(documents[0]["batch_id"], created)
+            except Exception as e:
+                self.logger.error(
+                    f"Failed to count {created} new tasks of batch {documents[0]['batch_id']}: {str(e)}"
+                )
+
+    async def count_batch_tasks(self, batch_id: str, created: int) -> None:
+        """Adds newly created tasks to the batch's counts in the `batches` index."""
+        await self.es_client.client.update(
+            index="batches",
+            id=batch_id,
+            script={
+                # The document may only hold the batch's callback_url so far
+                "source": (
+                    "for (String field : ['total', 'pending', 'processing', 'completed', 'failed'])"
+                    " { if (ctx._source[field] == null) { ctx._source[field] = 0 } }"
+                    " ctx._source.total += params.count;"
+                    " ctx._source.pending += params.count;"
+                    " ctx._source.finished_at = null"
+                ),
+                "params": {"count": created},
+            },
+            upsert={
+                "batch_id": batch_id,
+                "total": created,
+                "pending": created,
+                "processing": 0,
+                "completed": 0,
+                "failed": 0,
+            },
+            retry_on_conflict=10,
+        )
+
+    async def set_batch_callback_url(self, batch_id: str, callback_url: str) -> None:
+        """Records the webhook the consumer tells when the batch finishes."""
+        await self.es_client.client.update(
+            index="batches",
+            id=batch_id,
+            doc={"batch_id": batch_id, "callback_url": callback_url},
+            doc_as_upsert=True,
+            retry_on_conflict=10,
+        )
 
     async def process_message(self, message: bytes):
         """Processes a single message from the queue."""
         try:
             metadata = MetadataMessage.model_validate_json(message)
             self.logger.info(f"Received message for batch {metadata.batch_id}")
+            if metadata.callback_url:
+                try:
+                    await self.set_batch_callback_url(metadata.batch_id, metadata.callback_url)
+                except Exception as e:
+                    self.logger.error(
+                        f"Failed to record the callback of batch {metadata.batch_id}: {str(e)}"
+                    )
 
             # Download file from Storage
             file_content = await self.storage_handler.download_file(
@@ -121,10 +252,29 @@ class Worker:
 
                             # Validate task data
                             TaskSubmission.model_validate(task_data)
+                            if settings.MAX_REQUEST_BODY_BYTES and task_data.get("body") is not None:
+                                body_bytes = len(
+                                    json.dumps(task_data["body"], separators=(",", ":"), ensure_ascii=False).encode("utf-8")
+                                )
+                                if body_bytes > settings.MAX_REQUEST_BODY_BYTES:
+                                    self.logger.warning(
+                                        f"Skipping line {line_number}: Body of {body_bytes} bytes is over MAX_REQUEST_BODY_BYTES ({settings.MAX_REQUEST_BODY_BYTES})"
+                                    )
+                                    continue
+                            # Sent next to the payload rather than in it
+                            attribution = task_data.pop("attribution", None)
 
                             message_id = str(uuid.uuid4())
                             # Ensure consistent JSON serialization with compact format
-                            body_json = json.dumps(task_data["body"], sort_keys=True, separators=(",", ":"))
+                            # Templated tasks are hashed on what the consumer renders them from
+                            if task_data.get("template") is None:
+                                hashed = task_data["body"]
+                            else:
+                                hashed = {"template": task_data["template"], "variables": task_data.get("variables")}
+                            # Conversations with other plans give other transcripts
+                            if task_data.get("conversation") is not None:
+                                hashed = {"body": hashed, "conversation": task_data["conversation"]}
+                            body_json = json.dumps(hashed, sort_keys=True, separators=(",", ":"))
                             hasher = sha256()
                             hasher.update(body_json.encode('utf-8'))
                             body_hash = base64.b64encode(hasher.digest()).decode('utf-8')
@@ -139,9 +289,10 @@ class Worker:
                                 "method": task_data["method"],
                                 "url": task_data["url"],
                                 "body_hash": body_hash,
-                                "body": task_data["body"],
+                                "body": task_data.get("body"),
                                 "dataset": task_data.get("dataset", None),
-                                "source": task_data.get("source", None)
+                                "source": task_data.get("source", None),
+                                "attribution": attribution,
                             })
 
                             messages_to_publish.append(
@@ -151,6 +302,7 @@ class Worker:
                                     "payload": task_data,
                                     "body_hash": body_hash,
                                     "batch_id": metadata.batch_id,
+                                    "attribution": attribution,
                                 }
                             )
 